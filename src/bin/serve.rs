@@ -1,16 +1,50 @@
-use std::net::TcpListener;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
 
 use listenfd::ListenFd;
 use registry::{
+    client::{Client, UserExport},
+    confusion::ConfusionMonitor,
+    follow::UpstreamFollower,
+    migrations::{self, StampFile},
     policy::{
+        access_control, advisories,
         authenticators::OAuth,
-        storage::package::{ReadThrough, RemoteRegistry},
+        configurators::Env,
+        download_counts, geolocation, moderation,
+        storage::package::{
+            ChangeLog, GuardStorage, HotCache, PackageRules, ReadThrough, RemoteRegistry,
+            RewriteDependencies,
+        },
         storage::user,
         token_authorizers,
+        transform::TrimVersions,
+        webhooks,
     },
-    routes, Policy,
+    prune_previews, routes,
+    sync::RegistrySync,
+    tasks::TaskRegistry,
+    Authenticator, Configurator, PackageStorage, Policy, TokenAuthorizer,
 };
 
+const HOT_CACHE_CAPACITY: usize = 1024;
+const DOWNLOADS_DB: &str = "downloads.sqlite3";
+const CHANGES_DB: &str = "changes.sqlite3";
+const SYNC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+// How often token holders are checked against the identity provider.
+const TOKEN_REVALIDATE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+// How often packages published here are checked against the upstream registry.
+const CONFUSION_SCAN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+const CONFUSION_REPORT: &str = "confusion-report.json";
+// How often preview versions past their retention are deleted.
+const PREVIEW_PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+// How often the package cache is brought back under its size budget, when it has one.
+const CACHE_EVICT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+// How often tarballs no packument lists, and cache entries missing their content, are removed.
+const CACHE_GC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+// How long in-flight background work gets to finish once the server has stopped.
+const SHUTDOWN_GRACE: std::time::Duration = std::time::Duration::from_secs(10);
+
 fn setup_tracing() {
     use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
@@ -27,8 +61,324 @@ fn setup_tracing() {
     }
 }
 
+// Follow a primary registry's changes until shutdown, remembering our position across restarts.
+async fn follow_primary<S>(
+    sync: RegistrySync<S>,
+    seq_path: std::path::PathBuf,
+    tasks: TaskRegistry,
+) -> anyhow::Result<()>
+where
+    S: PackageStorage,
+    S::Error: std::error::Error,
+{
+    if let Err(e) = sync.check_primary().await {
+        tracing::error!(error = ?e, "not syncing from primary");
+        return Err(e);
+    }
+
+    let mut since: Option<serde_json::Value> = tokio::fs::read(&seq_path)
+        .await
+        .ok()
+        .and_then(|data| serde_json::from_slice(data.as_slice()).ok());
+
+    while !tasks.is_shutting_down() {
+        let pass = sync.sync_since(since.as_ref()).await;
+        let caught_up = match pass {
+            Ok(ref report) => {
+                for (what, reason) in &report.failures {
+                    tracing::warn!(what, reason, "could not sync from primary");
+                }
+                tracing::info!(
+                    packages = report.packages,
+                    versions = report.versions,
+                    "synced from primary"
+                );
+
+                if report.last_seq.is_some() && report.last_seq != since {
+                    since = report.last_seq.clone();
+                    if let Ok(data) = serde_json::to_vec(&since) {
+                        tokio::fs::write(&seq_path, data).await.ok();
+                    }
+                }
+                report.packages == 0 && report.failures.is_empty()
+            }
+            Err(ref e) => {
+                tracing::warn!(error = ?e, "sync from primary failed");
+                true
+            }
+        };
+        tasks.record_run("sync:primary:pass", &pass.map(|_| ()));
+
+        if !caught_up {
+            continue;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(SYNC_INTERVAL) => {}
+            _ = tasks.cancelled() => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Refresh cached packages as the upstream's replication feed reports changes to them, until
+// shutdown. A first run starts from the feed's present rather than replaying its history.
+async fn follow_upstream<S: PackageStorage>(
+    follower: UpstreamFollower<S>,
+    seq_path: std::path::PathBuf,
+    tasks: TaskRegistry,
+) -> anyhow::Result<()> {
+    let mut since: Option<serde_json::Value> = tokio::fs::read(&seq_path)
+        .await
+        .ok()
+        .and_then(|data| serde_json::from_slice(data.as_slice()).ok());
+
+    while !tasks.is_shutting_down() {
+        let pass = follower.follow_since(since.as_ref()).await;
+        let caught_up = match pass {
+            Ok(ref report) => {
+                for (package, reason) in &report.failures {
+                    tracing::warn!(package, reason, "could not refresh followed package");
+                }
+                tracing::info!(
+                    changes = report.changes,
+                    refreshed = report.refreshed,
+                    "followed upstream changes"
+                );
+
+                if report.last_seq.is_some() && report.last_seq != since {
+                    since = report.last_seq.clone();
+                    if let Ok(data) = serde_json::to_vec(&since) {
+                        tokio::fs::write(&seq_path, data).await.ok();
+                    }
+                }
+                report.changes == 0
+            }
+            Err(ref e) => {
+                tracing::warn!(error = ?e, "following upstream changes failed");
+                true
+            }
+        };
+        tasks.record_run("follow:upstream:pass", &pass.map(|_| ()));
+
+        if !caught_up {
+            continue;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(SYNC_INTERVAL) => {}
+            _ = tasks.cancelled() => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Revoke the tokens of users the identity provider has deactivated, until shutdown.
+async fn revalidate_tokens<T, A>(
+    token_authorizer: T,
+    authenticator: A,
+    tasks: TaskRegistry,
+) -> anyhow::Result<()>
+where
+    T: TokenAuthorizer + Send + Sync,
+    A: Authenticator,
+{
+    while !tasks.is_shutting_down() {
+        tokio::select! {
+            _ = tokio::time::sleep(TOKEN_REVALIDATE_INTERVAL) => {}
+            _ = tasks.cancelled() => break,
+        }
+
+        let pass = token_authorizer.revalidate_sessions(&authenticator).await;
+        if let Ok(ref revoked) = pass {
+            tracing::info!(users = revoked.len(), "revalidated token holders");
+        }
+        tasks.record_run("tokens:revalidate:pass", &pass.map(|_| ()));
+    }
+
+    Ok(())
+}
+
+// Evict the least recently used cache entries whenever the cache outgrows its budget, until
+// shutdown.
+async fn enforce_cache_budget(
+    cache: ReadThrough<RemoteRegistry>,
+    tasks: TaskRegistry,
+) -> anyhow::Result<()> {
+    while !tasks.is_shutting_down() {
+        tokio::select! {
+            _ = tokio::time::sleep(CACHE_EVICT_INTERVAL) => {}
+            _ = tasks.cancelled() => break,
+        }
+
+        let pass = cache.evict_to_budget().await;
+        if let Ok(ref eviction) = pass {
+            if eviction.evicted > 0 {
+                tracing::info!(
+                    size = eviction.size,
+                    evicted = eviction.evicted,
+                    freed = eviction.freed,
+                    "evicted least recently used cache entries"
+                );
+            }
+        }
+        tasks.record_run("cache:evict:pass", &pass.map(|_| ()));
+    }
+
+    Ok(())
+}
+
+// Remove orphaned tarballs and dangling cache entries, and report what deduplicating the rest
+// saves, until shutdown.
+async fn collect_garbage<S: PackageStorage>(storage: S, tasks: TaskRegistry) -> anyhow::Result<()> {
+    while !tasks.is_shutting_down() {
+        tokio::select! {
+            _ = tokio::time::sleep(CACHE_GC_INTERVAL) => {}
+            _ = tasks.cancelled() => break,
+        }
+
+        let pass = storage.collect_garbage().await;
+        if let Ok(ref collected) = pass {
+            tracing::info!(
+                orphaned_tarballs = collected.orphaned_tarballs,
+                dangling_entries = collected.dangling_entries,
+                reclaimed = collected.reclaimed,
+                "collected cache garbage"
+            );
+        }
+        tasks.record_run("cache:gc:pass", &pass.map(|_| ()));
+
+        match storage.tarball_stats().await {
+            Ok(stats) => tracing::info!(
+                tarballs = stats.tarballs,
+                blobs = stats.blobs,
+                stored = stats.stored,
+                saved = stats.saved(),
+                "counted stored tarballs"
+            ),
+            Err(e) => tracing::warn!(error = ?e, "could not count stored tarballs"),
+        }
+    }
+
+    Ok(())
+}
+
+// Check what's published here against the upstream until shutdown, raising an audit alert for
+// each collision and keeping the latest report next to the cache.
+async fn monitor_confusion<L, P>(
+    monitor: ConfusionMonitor<L, P>,
+    report_path: std::path::PathBuf,
+    tasks: TaskRegistry,
+) -> anyhow::Result<()>
+where
+    L: PackageStorage,
+    P: PackageStorage,
+{
+    while !tasks.is_shutting_down() {
+        tokio::select! {
+            _ = tokio::time::sleep(CONFUSION_SCAN_INTERVAL) => {}
+            _ = tasks.cancelled() => break,
+        }
+
+        let pass = monitor.scan().await;
+        if let Ok(ref report) = pass {
+            for collision in &report.collisions {
+                tracing::warn!(
+                    target: "audit",
+                    action = "package.confusion",
+                    package = collision.package,
+                    public = collision.public,
+                    kind = ?collision.kind
+                );
+            }
+            for (package, reason) in &report.failures {
+                tracing::warn!(package, reason, "could not check package against upstream");
+            }
+            tracing::info!(
+                local = report.local,
+                collisions = report.collisions.len(),
+                "checked local packages against upstream"
+            );
+            if let Ok(data) = serde_json::to_vec_pretty(report) {
+                tokio::fs::write(&report_path, data).await.ok();
+            }
+        }
+        tasks.record_run("monitor:confusion:pass", &pass.map(|_| ()));
+    }
+
+    Ok(())
+}
+
+// Bring every store under `cache_dir` to the schema this build expects. Stores also migrate
+// themselves when opened; doing it here first means a failure stops startup before serving.
+fn migrate_storage(cache_dir: &Path, dry_run: bool) -> anyhow::Result<()> {
+    std::fs::create_dir_all(cache_dir)?;
+
+    let reports = [
+        migrations::package_cache().run(&StampFile::in_dir(cache_dir), dry_run)?,
+        migrations::download_counts().run(
+            &rusqlite::Connection::open(cache_dir.join(DOWNLOADS_DB))?,
+            dry_run,
+        )?,
+        migrations::change_log().run(
+            &rusqlite::Connection::open(cache_dir.join(CHANGES_DB))?,
+            dry_run,
+        )?,
+    ];
+
+    for report in reports {
+        tracing::info!(%report, "checked storage schema");
+    }
+    Ok(())
+}
+
+// `serve users export <registry>` writes every user on a running registry to stdout, and
+// `serve users import <registry> <file>` loads such an export into one. Both authenticate
+// with an admin's token, from REGI_ADMIN_TOKEN.
+async fn users_command(mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let usage = "usage: serve users export <registry> | serve users import <registry> <file>";
+    let (Some(command), Some(registry)) = (args.next(), args.next()) else {
+        anyhow::bail!(usage);
+    };
+
+    let token = std::env::var("REGI_ADMIN_TOKEN")
+        .map_err(|_| anyhow::anyhow!("REGI_ADMIN_TOKEN must hold an admin's token"))?;
+    let client = Client::new(registry.trim_end_matches('/')).with_token(token);
+    match (command.as_str(), args.next()) {
+        ("export", None) => {
+            let export = client.export_users().await?;
+            println!("{}", serde_json::to_string_pretty(&export)?);
+        }
+        ("import", Some(file)) => {
+            let export: UserExport = serde_json::from_slice(std::fs::read(file)?.as_slice())?;
+            let imported = client.import_users(&export).await?;
+            tracing::info!(%imported, "imported users");
+        }
+        _ => anyhow::bail!(usage),
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    setup_tracing();
+
+    let mut pb = std::env::current_dir()?;
+    pb.push("cache");
+
+    // `serve migrate [--dry-run]` migrates storage (or reports what it would do) and exits.
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("migrate") => {
+            let dry_run = args.any(|arg| arg == "--dry-run");
+            return migrate_storage(&pb, dry_run);
+        }
+        Some("users") => return users_command(args).await,
+        _ => {}
+    }
+
     let mut listenfd = ListenFd::from_env();
 
     let bind = if let Some(listener) = listenfd.take_tcp_listener(0)? {
@@ -45,21 +395,206 @@ async fn main() -> anyhow::Result<()> {
         ))?
     };
 
-    setup_tracing();
+    let hot_index = pb.join("hot-packuments.json");
+    let sync_seq = pb.join("sync-seq.json");
+    let follow_seq = pb.join("follow-seq.json");
 
-    let mut pb = std::env::current_dir()?;
-    pb.push("cache");
+    migrate_storage(&pb, false)?;
+    let download_counts = download_counts::Sqlite::open(pb.join(DOWNLOADS_DB))?;
+
+    let config = Env::new();
+    tracing::info!(
+        deployment = config.deployment_id(),
+        user_agent = config.upstream_user_agent(),
+        "identifying to upstream registries"
+    );
+    tracing::info!(
+        preset = config.preset().name(),
+        require_auth = config.require_auth(),
+        upstream_enabled = config.upstream_enabled(),
+        "configured"
+    );
+    let upstream = RemoteRegistry::default()
+        .with_configurator(&config)
+        .offline(!config.upstream_enabled());
+    let change_log = pb.join(CHANGES_DB);
+    let confusion_report = pb.join(CONFUSION_REPORT);
+    let tasks = TaskRegistry::new();
+    // Offline, nothing could replace an expired packument, so cached ones never expire.
+    let max_age = config
+        .packument_max_age()
+        .filter(|_| config.upstream_enabled());
+    let mut read_through = ReadThrough::new(pb, upstream.clone())
+        .with_integrity_algorithm(config.integrity_algorithm())
+        .with_encodings(config.packument_encodings())
+        .with_max_age(max_age)
+        .with_max_size(config.cache_max_size());
+    if config.stale_while_revalidate() {
+        read_through = read_through.with_stale_while_revalidate(tasks.clone());
+    }
+    if config.cache_max_size().is_some() {
+        tasks.spawn(
+            "cache:evict",
+            enforce_cache_budget(read_through.clone(), tasks.clone()),
+        );
+    }
+    // Held in memory no longer than the cache holds them without revalidating, and dropped
+    // whenever it refills them, in the background or not.
+    let refills = read_through.subscribe_refills();
+    let package_storage = HotCache::new(
+        ChangeLog::open(read_through, change_log)?,
+        HOT_CACHE_CAPACITY,
+    )
+    .with_max_age(max_age);
+    tasks.spawn(
+        "cache:hot:refills",
+        package_storage
+            .clone()
+            .forget_refilled(refills, tasks.clone()),
+    );
+
+    match package_storage.prewarm(&hot_index).await {
+        Ok(warmed) => tracing::info!(warmed, "pre-warmed hot packument cache"),
+        Err(e) => tracing::warn!(error = ?e, "could not pre-warm hot packument cache"),
+    }
+
+    if let Ok(primary) = std::env::var("REGI_SYNC_PRIMARY") {
+        let client = registry::client::Client::from_remote(
+            RemoteRegistry::new(primary).with_configurator(&config),
+        );
+        let sync = RegistrySync::new(client, package_storage.clone());
+        tasks.spawn(
+            "sync:primary",
+            follow_primary(sync, sync_seq, tasks.clone()),
+        );
+    }
+
+    if !config.follow_packages().is_empty() && config.upstream_enabled() {
+        tracing::info!(
+            packages = ?config.follow_packages(),
+            feed = config.replication_url(),
+            "following upstream changes"
+        );
+        let feed = Client::from_remote(
+            RemoteRegistry::new(config.replication_url()).with_configurator(&config),
+        );
+        let follow = PackageRules::new(config.follow_packages(), &[]);
+        tasks.spawn(
+            "follow:upstream",
+            follow_upstream(
+                UpstreamFollower::new(feed, package_storage.clone(), follow),
+                follow_seq,
+                tasks.clone(),
+            ),
+        );
+    }
+
+    tasks.spawn(
+        "cache:gc",
+        collect_garbage(package_storage.clone(), tasks.clone()),
+    );
+
+    if config.confusion_monitor() {
+        tasks.spawn(
+            "monitor:confusion",
+            monitor_confusion(
+                ConfusionMonitor::new(package_storage.clone(), upstream),
+                confusion_report,
+                tasks.clone(),
+            ),
+        );
+    }
+
+    // Rewrites only apply to what's served; sync and the hot index see stored documents.
+    // Tarball URLs point back here so that tarballs, too, are fetched through the cache.
+    let served_storage = RewriteDependencies::new(
+        package_storage.clone(),
+        config.dependency_rewrites().to_vec(),
+    )
+    .with_tarball_base(config.fqdn());
+
+    // Allow and deny rules cover reads and publishes alike, but not sync: a blocked package
+    // stays stored, so that lifting the block doesn't need it fetched again.
+    let rules = PackageRules::from_configurator(&config);
+    tracing::info!(
+        allowlist = ?config.package_allowlist(),
+        blocklist = ?config.package_blocklist(),
+        "configured package rules"
+    );
+    let served_storage = GuardStorage::new(served_storage, move |name| rules.guard(name));
+
+    let geolocator = config
+        .geoip_database()
+        .map(geolocation::MaxMind::open)
+        .transpose()?;
+    tracing::info!(
+        geolocation = geolocator.is_some(),
+        publish_countries = ?config.publish_countries(),
+        "configured client geolocation"
+    );
+
+    let authenticator = OAuth::for_github();
+    let token_authorizer = token_authorizers::InMemory::new();
+    tasks.spawn(
+        "tokens:revalidate",
+        revalidate_tokens(
+            token_authorizer.clone(),
+            authenticator.clone(),
+            tasks.clone(),
+        ),
+    );
 
     let policy = Policy::new()
-        .with_package_storage(ReadThrough::new(pb, RemoteRegistry::default()))
-        .with_authenticator(OAuth::for_github())
-        .with_token_authorizer(token_authorizers::InMemory::new())
-        .with_user_storage(user::InMemory::new());
+        .with_package_storage(served_storage)
+        .with_authenticator(authenticator)
+        .with_token_authorizer(token_authorizer)
+        .with_user_storage(user::InMemory::new())
+        .with_advisories(advisories::Remote::default())
+        .with_access_control(access_control::InMemory::default())
+        .with_webhooks(webhooks::InMemory::default())
+        .with_download_counts(download_counts)
+        .with_geolocator(geolocator)
+        .with_moderation(moderation::InMemory::new())
+        .with_tasks(tasks.clone());
+    let policy = match config.max_served_versions() {
+        Some(max) => policy.with_packument_transform(TrimVersions::new(max)),
+        None => policy,
+    };
+
+    if config.preview_scope().is_some() {
+        let retention = policy.clone();
+        let pruner = tasks.clone();
+        tasks.spawn("previews:retention", async move {
+            while !pruner.is_shutting_down() {
+                tokio::select! {
+                    _ = tokio::time::sleep(PREVIEW_PRUNE_INTERVAL) => {}
+                    _ = pruner.cancelled() => break,
+                }
+
+                let pass = prune_previews(&retention, "retention".to_string()).await;
+                pruner.record_run("previews:retention:pass", &pass.map(|_| ()));
+            }
+            Ok(())
+        });
+    }
+
     let app = routes(policy);
 
     axum::Server::from_tcp(bind)?
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
         .await?;
 
+    tasks.shutdown(SHUTDOWN_GRACE).await;
+
+    if let Err(e) = package_storage
+        .save_index(&hot_index, HOT_CACHE_CAPACITY / 4)
+        .await
+    {
+        tracing::warn!(error = ?e, "could not persist hot packument index");
+    }
+
     Ok(())
 }
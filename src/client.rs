@@ -0,0 +1,234 @@
+//! A small client for talking to npm-compatible registries, built on the same models and
+//! upstream machinery the server uses to proxy packages.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+pub use crate::models::{
+    Attachment, Dist, DistTags, Maintainer, MaintainerObject, PackageIdentifier, Packument,
+    PackumentTime, PackumentVersion,
+};
+pub use crate::policies::package_storage::remote::RemoteRegistry;
+pub use crate::policies::user_storage::UserExport;
+use crate::policies::PackageStorage;
+
+#[derive(Clone, Debug)]
+pub struct Client {
+    remote: RemoteRegistry,
+    http: reqwest::Client,
+    token: Option<String>,
+}
+
+/// The pair of urls handed back by a registry when starting a web login.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LoginUrls {
+    #[serde(rename = "loginUrl")]
+    pub login_url: String,
+    #[serde(rename = "doneUrl")]
+    pub done_url: String,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::from_remote(RemoteRegistry::default())
+    }
+}
+
+impl Client {
+    pub fn new(registry: impl Into<String>) -> Self {
+        Self::from_remote(RemoteRegistry::new(registry))
+    }
+
+    pub fn from_remote(remote: RemoteRegistry) -> Self {
+        Self {
+            remote,
+            http: reqwest::Client::new(),
+            token: None,
+        }
+    }
+
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    pub fn registry(&self) -> &str {
+        self.remote.registry()
+    }
+
+    pub fn remote(&self) -> &RemoteRegistry {
+        &self.remote
+    }
+
+    pub(crate) fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .http
+            .request(method, format!("{}/{}", self.registry(), path));
+        if let Some(ref token) = self.token {
+            request.bearer_auth(token)
+        } else {
+            request
+        }
+    }
+
+    pub async fn packument(&self, name: &PackageIdentifier) -> anyhow::Result<Packument> {
+        self.remote.fetch_packument(name).await
+    }
+
+    pub async fn tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, reqwest::Error>>> {
+        self.remote.stream_tarball(name, version).await
+    }
+
+    pub async fn tarball_bytes(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<Vec<u8>> {
+        let chunks: Vec<Bytes> = self.tarball(name, version).await?.try_collect().await?;
+        Ok(chunks.concat())
+    }
+
+    /// Begin a web login, returning the url the user should visit and the url to poll.
+    pub async fn start_login(&self, hostname: &str) -> anyhow::Result<LoginUrls> {
+        Ok(self
+            .request(reqwest::Method::POST, "-/v1/login")
+            .json(&json!({ "hostname": hostname }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Poll the `doneUrl` of a web login once. Returns the session token when the login
+    /// completes, or the server-suggested delay before polling again.
+    pub async fn poll_login(&self, done_url: &str) -> anyhow::Result<Result<String, Duration>> {
+        let response = self.http.get(done_url).send().await?.error_for_status()?;
+
+        if response.status() == StatusCode::ACCEPTED {
+            let retry_after = response
+                .headers()
+                .get("retry-after")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(5);
+            return Ok(Err(Duration::from_secs(retry_after)));
+        }
+
+        let body: serde_json::Value = response.json().await?;
+        let token = body
+            .get("token")
+            .and_then(serde_json::Value::as_str)
+            .context("login response did not include a token")?;
+        Ok(Ok(token.to_string()))
+    }
+
+    /// Poll a web login until it completes, returning a client authenticated with the token.
+    pub async fn wait_for_login(self, done_url: &str) -> anyhow::Result<Self> {
+        loop {
+            match self.poll_login(done_url).await? {
+                Ok(token) => return Ok(self.with_token(token)),
+                Err(delay) => tokio::time::sleep(delay).await,
+            }
+        }
+    }
+
+    pub async fn whoami(&self) -> anyhow::Result<String> {
+        let body: serde_json::Value = self
+            .request(reqwest::Method::GET, "-/whoami")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        body.get("username")
+            .and_then(serde_json::Value::as_str)
+            .map(str::to_string)
+            .context("whoami response did not include a username")
+    }
+
+    /// Every user on the registry, with their org roles and team memberships. Needs an
+    /// admin's token.
+    pub async fn export_users(&self) -> anyhow::Result<UserExport> {
+        Ok(self
+            .request(reqwest::Method::GET, "-/admin/users/export")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Import users exported from another registry, returning what the registry reports
+    /// having imported. Needs an admin's token.
+    pub async fn import_users(&self, export: &UserExport) -> anyhow::Result<serde_json::Value> {
+        Ok(self
+            .request(reqwest::Method::POST, "-/admin/users/import")
+            .json(export)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Publish a single version. The manifest's `dist` should already describe `tarball`.
+    pub async fn publish(
+        &self,
+        manifest: PackumentVersion,
+        tarball: &[u8],
+        tag: &str,
+    ) -> anyhow::Result<()> {
+        use base64::Engine;
+
+        let name: PackageIdentifier = manifest
+            .meta
+            .get("name")
+            .and_then(serde_json::Value::as_str)
+            .context("manifest must have a name")?
+            .parse()?;
+        let version = manifest
+            .meta
+            .get("version")
+            .and_then(serde_json::Value::as_str)
+            .context("manifest must have a version")?
+            .to_string();
+
+        let body = json!({
+            "_id": name.to_string(),
+            "name": name.to_string(),
+            "dist-tags": { tag: version },
+            "versions": { version.as_str(): manifest },
+            "_attachments": {
+                format!("{}-{}.tgz", name.name, version): {
+                    "content_type": "application/octet-stream",
+                    "data": base64::engine::general_purpose::STANDARD.encode(tarball),
+                    "length": tarball.len()
+                }
+            }
+        });
+
+        self.request(
+            reqwest::Method::PUT,
+            urlencoding::encode(name.to_string().as_str()).as_ref(),
+        )
+        .json(&body)
+        .send()
+        .await?
+        .error_for_status()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+//! Dependency-confusion monitoring.
+//!
+//! A package published here under a name that also exists on the public registry can be
+//! swapped for the public one by any installer that looks there first, or falls back to it.
+//! [`ConfusionMonitor`] compares what's been published here against the public registry and
+//! reports names that now exist there, and public names a typo away from ours.
+//!
+//! Packages cached from the public registry are recognised by their contents: a package whose
+//! every version the public registry also has, with the same digests, is a copy rather than
+//! something published here.
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::Serialize;
+
+use crate::models::{PackageIdentifier, Packument};
+use crate::policies::package_storage::StorageError;
+use crate::policies::PackageStorage;
+
+const DEFAULT_CONCURRENCY: usize = 4;
+const SEPARATORS: [&str; 4] = ["-", "_", ".", ""];
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CollisionKind {
+    /// The public registry has a different package under the same name.
+    Exact,
+    /// The public registry has a package whose name differs only in punctuation.
+    Similar,
+}
+
+/// A package published here that the public registry could stand in for.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Collision {
+    pub package: String,
+    pub public: String,
+    pub kind: CollisionKind,
+}
+
+/// The outcome of one comparison against the public registry.
+#[derive(Clone, Debug, Serialize)]
+pub struct ConfusionReport {
+    pub time: DateTime<Utc>,
+    /// Packages found to be published here, rather than copied from the public registry.
+    pub local: usize,
+    pub collisions: Vec<Collision>,
+    /// Package paired with the reason it couldn't be checked.
+    pub failures: Vec<(String, String)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConfusionMonitor<L: PackageStorage, P: PackageStorage> {
+    local: L,
+    public: P,
+    concurrency: usize,
+}
+
+// Whether `local` could be a copy of `public`: nothing in it that the public registry lacks.
+fn is_copy_of(local: &Packument, public: &Packument) -> bool {
+    let public_versions = public.versions.as_ref();
+    local.versions.iter().flatten().all(|(number, version)| {
+        public_versions
+            .and_then(|versions| versions.get(number))
+            .is_some_and(|public| {
+                public.dist.shasum == version.dist.shasum
+                    && public.dist.integrity == version.dist.integrity
+            })
+    })
+}
+
+/// Names that differ from `name` only in their separators, e.g. `left_pad` and `leftpad` for
+/// `left-pad`. The scope is kept, since scopes are owned.
+pub fn similar_names(name: &PackageIdentifier) -> Vec<PackageIdentifier> {
+    let words: Vec<&str> = name
+        .name
+        .split(['-', '_', '.'])
+        .filter(|word| !word.is_empty())
+        .collect();
+    if words.len() < 2 {
+        return Vec::new();
+    }
+
+    SEPARATORS
+        .iter()
+        .map(|separator| words.join(separator))
+        .filter(|candidate| *candidate != name.name)
+        .filter_map(|candidate| {
+            let candidate = match name.scope {
+                Some(ref scope) => format!("@{}/{}", scope, candidate),
+                None => candidate,
+            };
+            candidate.parse().ok()
+        })
+        .collect()
+}
+
+impl<L, P> ConfusionMonitor<L, P>
+where
+    L: PackageStorage,
+    P: PackageStorage,
+{
+    pub fn new(local: L, public: P) -> Self {
+        Self {
+            local,
+            public,
+            concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
+
+    /// How many packages to check against the public registry at once.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Compare every package in local storage with the public registry.
+    pub async fn scan(&self) -> anyhow::Result<ConfusionReport> {
+        let names = self.local.list_packages().await?;
+        let checked: Vec<_> = futures::stream::iter(names)
+            .map(|name| async move {
+                let result = self.check_package(&name).await;
+                (name, result)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect()
+            .await;
+
+        let mut report = ConfusionReport {
+            time: Utc::now(),
+            local: 0,
+            collisions: Vec::new(),
+            failures: Vec::new(),
+        };
+        for (name, result) in checked {
+            match result {
+                Ok(Some(collisions)) => {
+                    report.local += 1;
+                    report.collisions.extend(collisions);
+                }
+                Ok(None) => {}
+                Err(e) => report.failures.push((name.to_string(), e.to_string())),
+            }
+        }
+
+        report
+            .collisions
+            .sort_by(|a, b| (&a.package, &a.public).cmp(&(&b.package, &b.public)));
+        Ok(report)
+    }
+
+    /// The collisions for `name`, or `None` if it's a copy of a public package.
+    pub async fn check_package(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<Option<Vec<Collision>>> {
+        let local = self.local.fetch_packument(name).await?;
+        let mut collisions = Vec::new();
+        match self.public.fetch_packument(name).await {
+            Ok(public) if is_copy_of(&local, &public) => return Ok(None),
+            Ok(_) => collisions.push(Collision {
+                package: name.to_string(),
+                public: name.to_string(),
+                kind: CollisionKind::Exact,
+            }),
+            Err(e) if matches!(StorageError::of(&e), Some(StorageError::NotFound)) => {}
+            Err(e) => return Err(e),
+        }
+
+        for similar in similar_names(name) {
+            match self.public.stream_abbreviated_packument(&similar).await {
+                Ok(_) => collisions.push(Collision {
+                    package: name.to_string(),
+                    public: similar.to_string(),
+                    kind: CollisionKind::Similar,
+                }),
+                Err(e) if matches!(StorageError::of(&e), Some(StorageError::NotFound)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Some(collisions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_similar_names() {
+        let similar = |name: &str| -> Vec<String> {
+            similar_names(&name.parse().unwrap())
+                .iter()
+                .map(ToString::to_string)
+                .collect()
+        };
+
+        assert_eq!(similar("left-pad"), ["left_pad", "left.pad", "leftpad"]);
+        assert_eq!(
+            similar("@corp/db.client"),
+            ["@corp/db-client", "@corp/db_client", "@corp/dbclient"]
+        );
+        assert!(similar("lodash").is_empty());
+    }
+}
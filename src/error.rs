@@ -0,0 +1,182 @@
+//! The error handlers return, rendered as the `{"error": "..."}` body the npm CLI prints
+//! alongside the status code.
+
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::time::Duration;
+
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegistryError {
+    status: StatusCode,
+    message: Cow<'static, str>,
+    retry_after: Option<Duration>,
+    details: serde_json::Map<String, serde_json::Value>,
+}
+
+impl RegistryError {
+    pub fn new(status: StatusCode, message: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            retry_after: None,
+            details: serde_json::Map::new(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    pub fn unauthorized(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, message)
+    }
+
+    pub fn forbidden(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, message)
+    }
+
+    pub fn not_found(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    pub fn conflict(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::CONFLICT, message)
+    }
+
+    pub fn not_implemented(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::NOT_IMPLEMENTED, message)
+    }
+
+    /// Log `error` and report a bare 500; the details are for operators, not clients.
+    pub fn internal(error: impl Into<anyhow::Error>) -> Self {
+        let error = error.into();
+        tracing::error!(error = ?error, "internal error while handling request");
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal server error")
+    }
+
+    /// Tell the client when to try again, with a `Retry-After` header in whole seconds.
+    pub fn with_retry_after(mut self, wait: Duration) -> Self {
+        self.retry_after = Some(wait);
+        self
+    }
+
+    /// Send `value` as `key` in the body, beside the message npm prints.
+    pub fn with_detail(mut self, key: &str, value: serde_json::Value) -> Self {
+        self.details.insert(key.to_string(), value);
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn message(&self) -> &str {
+        self.message.as_ref()
+    }
+}
+
+impl Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+/// A status on its own gets its reason phrase as the message.
+impl From<StatusCode> for RegistryError {
+    fn from(status: StatusCode) -> Self {
+        let reason = status.canonical_reason().unwrap_or("error");
+        Self::new(status, reason.to_ascii_lowercase())
+    }
+}
+
+impl From<anyhow::Error> for RegistryError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::internal(error)
+    }
+}
+
+// Sent with every 401. Seeing a bearer challenge, npm tells the user their token is invalid
+// and to run `npm login`, rather than only printing the status.
+const BEARER_CHALLENGE: &str = "Bearer";
+
+impl IntoResponse for RegistryError {
+    fn into_response(self) -> Response {
+        let mut body = self.details;
+        body.insert("error".to_string(), self.message.into());
+        let mut response = (self.status, Json(body)).into_response();
+        let headers = response.headers_mut();
+        if self.status == StatusCode::UNAUTHORIZED {
+            headers.insert(
+                header::WWW_AUTHENTICATE,
+                header::HeaderValue::from_static(BEARER_CHALLENGE),
+            );
+        }
+        // Rounded up, so a client that waits as long as it's told isn't early.
+        if let Some(wait) = self.retry_after {
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            headers.insert(header::RETRY_AFTER, header::HeaderValue::from(secs));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_renders_npm_error_body() {
+        let response = RegistryError::not_found("no such package: left-pad").into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let mut body = response.into_body();
+        let body = axum::body::HttpBody::data(&mut body)
+            .await
+            .unwrap()
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(body.as_ref()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "error": "no such package: left-pad" })
+        );
+
+        assert_eq!(
+            RegistryError::from(StatusCode::FORBIDDEN).message(),
+            "forbidden"
+        );
+
+        let response = RegistryError::unauthorized("you must be logged in").into_response();
+        assert_eq!(
+            response.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Bearer"
+        );
+
+        let response = RegistryError::new(StatusCode::SERVICE_UNAVAILABLE, "busy")
+            .with_retry_after(Duration::from_millis(1500))
+            .into_response();
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "2");
+
+        let response = RegistryError::forbidden("left-pad is quarantined")
+            .with_detail("quarantine", serde_json::json!({ "reason": "malware" }))
+            .into_response();
+        let mut body = response.into_body();
+        let body = axum::body::HttpBody::data(&mut body)
+            .await
+            .unwrap()
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(body.as_ref()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "error": "left-pad is quarantined",
+                "quarantine": { "reason": "malware" }
+            })
+        );
+    }
+}
@@ -0,0 +1,40 @@
+//! Package events as they happen, for clients watching a package at `/-/v1/watch/:pkg`.
+//!
+//! Every event that goes to webhooks is also published on the [`EventBus`]. Delivery is
+//! in-process and best-effort: a subscriber that falls too far behind loses the oldest events
+//! (and is told so), and events from other instances of the registry aren't seen at all.
+
+use tokio::sync::broadcast;
+
+use crate::policies::webhooks::HookEvent;
+
+const DEFAULT_CAPACITY: usize = 256;
+
+#[derive(Clone, Debug)]
+pub struct EventBus {
+    sender: broadcast::Sender<HookEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl EventBus {
+    /// A bus that holds up to `capacity` events for subscribers that haven't caught up.
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        Self { sender }
+    }
+
+    /// Send `event` to every current subscriber. With none, it's dropped.
+    pub fn publish(&self, event: HookEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<HookEvent> {
+        self.sender.subscribe()
+    }
+}
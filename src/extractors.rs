@@ -1,41 +1,428 @@
-use axum::{extract::FromRequestParts, http::request::Parts, http::StatusCode, Json};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    body::{Bytes, HttpBody},
+    extract::{ConnectInfo, FromRequest, FromRequestParts},
+    http::{request::Parts, HeaderMap, Method, Request, StatusCode, Uri},
+    BoxError,
+};
+use chrono::Utc;
+use serde::de::DeserializeOwned;
 
 use crate::{
+    error::RegistryError,
+    hashing::{self, Algorithm, Digest},
     models::User,
-    policies::{policy::PolicyHolder, TokenAuthorizer},
+    policies::{
+        configurator::AdminKey, policy::PolicyHolder, token_authorizer::bearer_token,
+        token_authorizer::TokenOptions, Configurator, TokenAuthorizer, UserStorage,
+    },
 };
 
+/// The user behind the request. Read-only tokens are turned away from anything but GET and
+/// HEAD.
+#[derive(Debug)]
 pub(crate) struct Authenticated(pub User);
 
+/// The user behind a request that only reads, whatever its method; read-only tokens are
+/// welcome here.
+#[derive(Debug)]
+pub(crate) struct Reader(pub User);
+
+/// Whoever is publishing: a user, or a preview token, which may only publish prereleases
+/// under the configured preview scope. Every other endpoint turns preview tokens away.
+#[derive(Debug)]
+pub(crate) struct Publisher {
+    pub user: User,
+    pub preview: bool,
+}
+
+// The user behind the request's token, and what that token is limited to. A token limited to
+// some addresses only works from them.
+pub(crate) async fn authenticate<S>(
+    parts: &Parts,
+    state: &S,
+) -> Result<(User, TokenOptions), RegistryError>
+where
+    S: Send + Sync + PolicyHolder,
+{
+    let internal = |e: anyhow::Error| {
+        tracing::error!(?parts, error = ?e, "encountered internal error while attempting to authenticate session");
+        RegistryError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "you must be logged in to use this endpoint",
+        )
+    };
+
+    let token_authorizer = state.as_token_authorizer();
+    let user = match token_authorizer.authenticate_session(parts).await {
+        Ok(Some(user)) => match state.as_user_storage().is_active(user.name.as_str()).await {
+            Ok(true) => user,
+            Ok(false) => {
+                return Err(RegistryError::unauthorized(
+                    "this account has been deactivated",
+                ))
+            }
+            Err(e) => {
+                tracing::error!(user = user.name, error = ?e, "could not check whether user is active");
+                return Err(RegistryError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "you must be logged in to use this endpoint",
+                ));
+            }
+        },
+        Ok(None) => {
+            return Err(RegistryError::unauthorized(
+                "you must be logged in to use this endpoint",
+            ))
+        }
+        Err(e) => return Err(internal(e)),
+    };
+
+    let options = match bearer_token(&parts.headers) {
+        Some(bearer) => token_authorizer
+            .token_options(&bearer)
+            .await
+            .map_err(internal)?
+            .unwrap_or_default(),
+        None => TokenOptions::default(),
+    };
+    if let Some(ref cidrs) = options.cidr_whitelist {
+        let ip = request_ip(parts).and_then(|ip| ip.parse::<IpAddr>().ok());
+        if !ip.is_some_and(|ip| cidrs.iter().any(|cidr| cidr_contains(cidr, ip))) {
+            return Err(RegistryError::unauthorized(
+                "this token may not be used from this address",
+            ));
+        }
+    }
+    Ok((user, options))
+}
+
+/// Whether `cidr`, a network or a single address, takes in `ip`.
+pub(crate) fn cidr_contains(cidr: &str, ip: IpAddr) -> bool {
+    match cidr.parse::<ipnet::IpNet>() {
+        Ok(net) => net.contains(&ip),
+        Err(_) => cidr.parse::<IpAddr>() == Ok(ip),
+    }
+}
+
+/// Whether `cidr` is a network or a single address that [`cidr_contains`] can match.
+pub(crate) fn is_valid_cidr(cidr: &str) -> bool {
+    cidr.parse::<ipnet::IpNet>().is_ok() || cidr.parse::<IpAddr>().is_ok()
+}
+
+fn read_only() -> RegistryError {
+    RegistryError::forbidden("read-only tokens may not make changes")
+}
+
 #[async_trait::async_trait]
 impl<S> FromRequestParts<S> for Authenticated
 where
     S: Send + Sync + PolicyHolder,
 {
-    type Rejection = (StatusCode, Json<serde_json::Value>);
+    type Rejection = RegistryError;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        match state
-            .as_token_authorizer()
-            .authenticate_session(parts)
-            .await
-        {
-            Ok(Some(user)) => Ok(Authenticated(user)),
-            Ok(None) => Err((
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "message": "you must be logged in to use this endpoint"
-                })),
+        let (user, options) = authenticate(parts, state).await?;
+        if options.preview {
+            return Err(RegistryError::forbidden(
+                "preview tokens may only publish preview versions",
+            ));
+        }
+        if options.readonly && !matches!(parts.method, Method::GET | Method::HEAD) {
+            return Err(read_only());
+        }
+        Ok(Authenticated(user))
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> FromRequestParts<S> for Reader
+where
+    S: Send + Sync + PolicyHolder,
+{
+    type Rejection = RegistryError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match authenticate(parts, state).await? {
+            (_, options) if options.preview => Err(RegistryError::forbidden(
+                "preview tokens may only publish preview versions",
             )),
-            Err(e) => {
-                tracing::error!(?parts, error = ?e, "encountered internal error while attempting to authenticate session");
-                Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(serde_json::json!({
-                        "message": "you must be logged in to use this endpoint"
-                    })),
-                ))
+            (user, _) => Ok(Reader(user)),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> FromRequestParts<S> for Publisher
+where
+    S: Send + Sync + PolicyHolder,
+{
+    type Rejection = RegistryError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let (user, options) = authenticate(parts, state).await?;
+        if options.readonly {
+            return Err(read_only());
+        }
+        Ok(Publisher {
+            user,
+            preview: options.preview,
+        })
+    }
+}
+
+/// The client's address as reported by a proxy in front of us, or else the peer's.
+pub(crate) fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<String> {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|ip| ip.trim().to_string())
+        .filter(|ip| !ip.is_empty())
+        .or_else(|| peer.map(|peer| peer.ip().to_string()))
+}
+
+/// Where a request came from, per [`client_ip`]; `None` when neither a proxy nor the
+/// connection says.
+#[derive(Debug)]
+pub(crate) struct ClientIp(pub Option<String>);
+
+#[async_trait::async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(ClientIp(request_ip(parts)))
+    }
+}
+
+fn request_ip(parts: &Parts) -> Option<String> {
+    let peer = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| *peer);
+    client_ip(&parts.headers, peer)
+}
+
+/// Requests to admin endpoints may be signed instead of carrying a bearer token:
+///
+/// ```text
+/// x-regi-key-id:    <key id from the configurator>
+/// x-regi-timestamp: <unix seconds>
+/// x-regi-nonce:     <unique per request>
+/// x-regi-signature: hex(hmac_sha256(secret, "{METHOD}\n{path?query}\n{timestamp}\n{nonce}\n{hex(sha256(body))}"))
+/// ```
+///
+/// Signatures are only accepted within [`SIGNATURE_WINDOW_SECS`] of the server's clock, and
+/// each nonce only once within that window.
+const SIGNATURE_WINDOW_SECS: i64 = 300;
+
+/// Nonces of signed requests seen within the signature window, keyed by "key id:nonce". Held
+/// by the policy, so clones of it share them but separate instances don't: deployments running
+/// several behind a balancer should pin signed callers to one instance or shorten the window.
+#[derive(Clone, Debug, Default)]
+pub struct SeenNonces(Arc<Mutex<HashMap<String, i64>>>);
+
+impl SeenNonces {
+    // Remember `nonce`, returning false if it was already used within the window.
+    fn record(&self, key_id: &str, nonce: &str, timestamp: i64, now: i64) -> bool {
+        let mut seen = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        seen.retain(|_, seen_at| (now - *seen_at).abs() <= SIGNATURE_WINDOW_SECS);
+        seen.insert(format!("{}:{}", key_id, nonce), timestamp)
+            .is_none()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum AdminPrincipal {
+    /// A user listed as an admin by the configurator; may do anything.
+    User(String),
+    /// A signing key, limited to its scopes.
+    Key(String, AdminKey),
+}
+
+impl AdminPrincipal {
+    pub(crate) fn allows(&self, scope: &str) -> bool {
+        match self {
+            AdminPrincipal::User(_) => true,
+            AdminPrincipal::Key(_, key) => key.allows(scope),
+        }
+    }
+
+    pub(crate) fn name(&self) -> String {
+        match self {
+            AdminPrincipal::User(name) => name.clone(),
+            AdminPrincipal::Key(id, _) => format!("key:{}", id),
+        }
+    }
+}
+
+/// An authenticated admin request along with its JSON body (`()` for bodiless requests).
+#[derive(Debug)]
+pub(crate) struct Admin<T = ()> {
+    pub principal: AdminPrincipal,
+    pub payload: T,
+}
+
+type Rejection = RegistryError;
+
+fn reject(status: StatusCode, message: &'static str) -> Rejection {
+    RegistryError::new(status, message)
+}
+
+impl<T> Admin<T> {
+    pub(crate) fn require_scope(&self, scope: &str) -> Result<(), Rejection> {
+        if self.principal.allows(scope) {
+            Ok(())
+        } else {
+            Err(reject(
+                StatusCode::FORBIDDEN,
+                "this key may not use this endpoint",
+            ))
+        }
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn verify_signature<S: PolicyHolder>(
+    state: &S,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    key_id: &str,
+    body: &[u8],
+) -> Result<AdminPrincipal, Rejection> {
+    let unauthorized = |message| reject(StatusCode::UNAUTHORIZED, message);
+
+    let Some(key) = state.as_configurator().admin_key(key_id) else {
+        return Err(unauthorized("unknown signing key"));
+    };
+
+    let (Some(timestamp), Some(nonce), Some(signature)) = (
+        header(headers, "x-regi-timestamp").and_then(|ts| ts.parse::<i64>().ok()),
+        header(headers, "x-regi-nonce").filter(|nonce| !nonce.is_empty()),
+        header(headers, "x-regi-signature").and_then(|signature| hex::decode(signature).ok()),
+    ) else {
+        return Err(unauthorized(
+            "signed requests need a timestamp, nonce and signature",
+        ));
+    };
+
+    let now = Utc::now().timestamp();
+    if (now - timestamp).abs() > SIGNATURE_WINDOW_SECS {
+        return Err(unauthorized(
+            "signature timestamp is outside the allowed window",
+        ));
+    }
+
+    let path = uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let body_digest = Digest::compute(Algorithm::Sha256, body)
+        .map_err(|_| reject(StatusCode::INTERNAL_SERVER_ERROR, "could not hash body"))?;
+    let signed = format!(
+        "{}\n{}\n{}\n{}\n{}",
+        method,
+        path,
+        timestamp,
+        nonce,
+        body_digest.to_hex()
+    );
+
+    let valid = hashing::verify_hmac_sha256(
+        key.secret.as_bytes(),
+        signed.as_bytes(),
+        signature.as_slice(),
+    )
+    .map_err(|_| {
+        reject(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not verify signature",
+        )
+    })?;
+    if !valid {
+        return Err(unauthorized("bad signature"));
+    }
+
+    // Only remember nonces of correctly signed requests, so junk can't fill the cache.
+    if !state.as_seen_nonces().record(key_id, nonce, timestamp, now) {
+        return Err(unauthorized("nonce has already been used"));
+    }
+
+    Ok(AdminPrincipal::Key(key_id.to_string(), key.clone()))
+}
+
+#[async_trait::async_trait]
+impl<S, B, T> FromRequest<S, B> for Admin<T>
+where
+    S: Send + Sync + PolicyHolder,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+    T: DeserializeOwned,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let (mut parts, body) = req.into_parts();
+
+        // Bearer callers are checked before we bother reading the body.
+        let key_id = header(&parts.headers, "x-regi-key-id").map(str::to_string);
+        let user = match key_id {
+            Some(_) => None,
+            None => {
+                let Authenticated(user) =
+                    Authenticated::from_request_parts(&mut parts, state).await?;
+                if !state.as_configurator().admin_users().contains(&user.name) {
+                    return Err(reject(
+                        StatusCode::FORBIDDEN,
+                        "you are not an administrator",
+                    ));
+                }
+                Some(user)
             }
+        };
+
+        let (method, uri, headers) = (
+            parts.method.clone(),
+            parts.uri.clone(),
+            parts.headers.clone(),
+        );
+        let body = Bytes::from_request(Request::from_parts(parts, body), state)
+            .await
+            .map_err(|_| reject(StatusCode::BAD_REQUEST, "could not read request body"))?;
+
+        let principal = match (user, key_id) {
+            (Some(user), _) => AdminPrincipal::User(user.name),
+            (None, Some(key_id)) => verify_signature(
+                state,
+                &method,
+                &uri,
+                &headers,
+                key_id.as_str(),
+                body.as_ref(),
+            )?,
+            (None, None) => unreachable!("either a user or a key id is present"),
+        };
+
+        let payload = if body.is_empty() {
+            serde_json::from_value(serde_json::Value::Null)
+        } else {
+            serde_json::from_slice(body.as_ref())
         }
+        .map_err(|_| reject(StatusCode::BAD_REQUEST, "malformed request body"))?;
+
+        Ok(Admin { principal, payload })
     }
 }
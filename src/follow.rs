@@ -0,0 +1,195 @@
+//! Keeping the packages a mirror cares about current, by following the upstream registry's
+//! replication feed.
+//!
+//! A read-through cache only learns of a new version once its copy of the packument
+//! expires. An [`UpstreamFollower`] reads the upstream's `_changes` feed instead, and for
+//! each changed package it follows, drops what's cached and reads it again: the packument,
+//! its abbreviated form and the tarball of its `latest` version, so the next install finds
+//! them all here. Packages published here are left alone, since the cache keeps them through
+//! an invalidation.
+
+use serde_json::Value;
+
+use crate::client::Client;
+use crate::models::PackageIdentifier;
+use crate::policies::package_rules::PackageRules;
+use crate::policies::package_storage::guard::Guard;
+use crate::policies::PackageStorage;
+use crate::sync::{self, Changes};
+
+// The public feed moves quickly; small batches would fall behind it.
+const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// The outcome of one pass over the upstream's changes.
+#[derive(Clone, Debug, Default)]
+pub struct FollowReport {
+    /// Where the next pass should resume from.
+    pub last_seq: Option<Value>,
+    /// Changes seen, followed or not.
+    pub changes: usize,
+    pub refreshed: usize,
+    /// Package paired with the reason it couldn't be refreshed.
+    pub failures: Vec<(String, String)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct UpstreamFollower<S: PackageStorage> {
+    upstream: Client,
+    cache: S,
+    follow: PackageRules,
+    batch_size: usize,
+}
+
+impl<S: PackageStorage> UpstreamFollower<S> {
+    /// Follow the packages `follow` allows, refreshing them in `cache`. Rules with no
+    /// allowlist follow nothing, rather than the whole registry.
+    pub fn new(upstream: Client, cache: S, follow: PackageRules) -> Self {
+        Self {
+            upstream,
+            cache,
+            follow,
+            batch_size: DEFAULT_BATCH_SIZE,
+        }
+    }
+
+    /// How many changes to request from the upstream per pass.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    pub fn follows(&self, name: &PackageIdentifier) -> bool {
+        !self.follow.is_empty() && self.follow.guard(name) == Guard::Allow
+    }
+
+    /// Refresh the followed packages among one batch of changes after `since`. With no
+    /// `since`, following starts from now: the upstream's history is of no use to a cache.
+    /// Call repeatedly with the returned `last_seq` to keep following.
+    pub async fn follow_since(&self, since: Option<&Value>) -> anyhow::Result<FollowReport> {
+        let now = Value::from("now");
+        let changes =
+            sync::changes(&self.upstream, Some(since.unwrap_or(&now)), self.batch_size).await?;
+        Ok(self.apply(changes).await)
+    }
+
+    async fn apply(&self, changes: Changes) -> FollowReport {
+        let mut report = FollowReport {
+            last_seq: Some(changes.last_seq),
+            changes: changes.results.len(),
+            ..Default::default()
+        };
+
+        for change in changes.results {
+            let Ok(name) = change.id.parse::<PackageIdentifier>() else {
+                continue;
+            };
+            if !self.follows(&name) {
+                continue;
+            }
+
+            // An unpublished package is only dropped; reading it again would find nothing.
+            let refreshed = match change.deleted {
+                true => self.cache.invalidate(&name).await,
+                false => self.refresh(&name).await,
+            };
+            match refreshed {
+                Ok(()) => report.refreshed += 1,
+                Err(e) => report.failures.push((change.id, e.to_string())),
+            }
+        }
+
+        report
+    }
+
+    async fn refresh(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        self.cache.invalidate(name).await?;
+        self.cache.packument_metadata(name).await?;
+        self.cache.abbreviated_packument_metadata(name).await?;
+        let latest = self.cache.resolve_version(name, "latest").await?;
+        let version = latest
+            .as_ref()
+            .and_then(|manifest| manifest.meta.get("version"))
+            .and_then(|version| version.as_str());
+        if let Some(version) = version {
+            self.cache.tarball_metadata(name, version).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Packument;
+    use crate::policies::package_storage::hot_cache::HotCache;
+    use crate::policies::package_storage::in_memory::InMemoryPackageStorage;
+    use axum::body::Bytes;
+
+    fn packument(name: &str, latest: &str) -> Packument {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "dist-tags": { "latest": latest },
+            "versions": {
+                latest: {
+                    "_id": format!("{}@{}", name, latest),
+                    "name": name,
+                    "version": latest,
+                    "dist": { "tarball": "", "shasum": "" }
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    fn latest(packument: &Packument) -> Option<&str> {
+        packument.dist_tags.as_ref()?.latest.as_deref()
+    }
+
+    #[tokio::test]
+    async fn test_follow_changes() {
+        let upstream = InMemoryPackageStorage::new();
+        let cache = HotCache::new(upstream.clone(), 16);
+        let follower = UpstreamFollower::new(
+            Client::default(),
+            cache.clone(),
+            PackageRules::new(&["@types/*", "react"], &[]),
+        );
+        for name in ["react", "left-pad"] {
+            let name: PackageIdentifier = name.parse().unwrap();
+            upstream
+                .put_packument(&name, &packument(name.to_string().as_str(), "1.0.0"))
+                .await
+                .unwrap();
+            cache.fetch_packument(&name).await.unwrap();
+            upstream
+                .put_packument(&name, &packument(name.to_string().as_str(), "2.0.0"))
+                .await
+                .unwrap();
+            upstream
+                .put_tarball(&name, "2.0.0", Bytes::from_static(b"tarball"))
+                .await
+                .unwrap();
+        }
+
+        let changes: Changes = serde_json::from_value(serde_json::json!({
+            "results": [
+                { "seq": 1, "id": "react" },
+                { "seq": 2, "id": "left-pad" }
+            ],
+            "last_seq": 2
+        }))
+        .unwrap();
+        let report = follower.apply(changes).await;
+        assert_eq!(report.last_seq, Some(serde_json::json!(2)));
+        assert_eq!((report.changes, report.refreshed), (2, 1));
+        assert!(report.failures.is_empty());
+
+        let react = cache.fetch_packument(&"react".parse().unwrap()).await;
+        assert_eq!(latest(&react.unwrap()), Some("2.0.0"));
+        let left_pad = cache.fetch_packument(&"left-pad".parse().unwrap()).await;
+        assert_eq!(latest(&left_pad.unwrap()), Some("1.0.0"));
+
+        let nothing = UpstreamFollower::new(Client::default(), cache, PackageRules::default());
+        assert!(!nothing.follows(&"react".parse().unwrap()));
+    }
+}
@@ -1 +1,5 @@
+pub mod openapi;
+pub mod scim;
 pub mod v1;
+#[cfg(feature = "web-ui")]
+pub mod web;
@@ -0,0 +1,342 @@
+//! The OpenAPI description of the HTTP API, served at `/-/openapi.json`.
+//!
+//! Routes are registered through [`ApiRouter`], with a summary given alongside each method's
+//! handler, so the document can't drift from what's actually routed. Path parameters are
+//! described from the route itself; bodies and responses are left to the summaries.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use axum::body::HttpBody;
+use axum::handler::Handler;
+use axum::routing::{self, MethodRouter};
+use axum::{Json, Router};
+use serde_json::json;
+
+// Summaries by lowercase method name, as OpenAPI keys operations.
+type Operations = BTreeMap<&'static str, &'static str>;
+
+/// A method router along with a summary of each method it serves.
+#[must_use]
+pub(crate) struct ApiMethod<S, B> {
+    router: MethodRouter<S, B>,
+    operations: Operations,
+}
+
+impl<S, B> ApiMethod<S, B> {
+    fn new(router: MethodRouter<S, B>, method: &'static str, summary: &'static str) -> Self {
+        Self {
+            router,
+            operations: BTreeMap::from([(method, summary)]),
+        }
+    }
+
+    /// Rework the methods routed so far, e.g. to wrap them in a layer.
+    pub(crate) fn map<B2>(
+        self,
+        f: impl FnOnce(MethodRouter<S, B>) -> MethodRouter<S, B2>,
+    ) -> ApiMethod<S, B2> {
+        ApiMethod {
+            router: f(self.router),
+            operations: self.operations,
+        }
+    }
+}
+
+pub(crate) fn get<H, T, S, B>(handler: H, summary: &'static str) -> ApiMethod<S, B>
+where
+    H: Handler<T, S, B>,
+    T: 'static,
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + 'static,
+{
+    ApiMethod::new(routing::get(handler), "get", summary)
+}
+
+pub(crate) fn post<H, T, S, B>(handler: H, summary: &'static str) -> ApiMethod<S, B>
+where
+    H: Handler<T, S, B>,
+    T: 'static,
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + 'static,
+{
+    ApiMethod::new(routing::post(handler), "post", summary)
+}
+
+pub(crate) fn put<H, T, S, B>(handler: H, summary: &'static str) -> ApiMethod<S, B>
+where
+    H: Handler<T, S, B>,
+    T: 'static,
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + 'static,
+{
+    ApiMethod::new(routing::put(handler), "put", summary)
+}
+
+pub(crate) fn patch<H, T, S, B>(handler: H, summary: &'static str) -> ApiMethod<S, B>
+where
+    H: Handler<T, S, B>,
+    T: 'static,
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + 'static,
+{
+    ApiMethod::new(routing::patch(handler), "patch", summary)
+}
+
+pub(crate) fn delete<H, T, S, B>(handler: H, summary: &'static str) -> ApiMethod<S, B>
+where
+    H: Handler<T, S, B>,
+    T: 'static,
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + 'static,
+{
+    ApiMethod::new(routing::delete(handler), "delete", summary)
+}
+
+/// Routes every method to `handler`. Only GET and POST are described, as the methods
+/// clients actually use on such routes.
+pub(crate) fn any<H, T, S, B>(handler: H, summary: &'static str) -> ApiMethod<S, B>
+where
+    H: Handler<T, S, B>,
+    T: 'static,
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + 'static,
+{
+    let mut method = ApiMethod::new(routing::any(handler), "get", summary);
+    method.operations.insert("post", summary);
+    method
+}
+
+impl<S, B> ApiMethod<S, B>
+where
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + 'static,
+{
+    pub(crate) fn head<H, T>(mut self, handler: H, summary: &'static str) -> Self
+    where
+        H: Handler<T, S, B>,
+        T: 'static,
+    {
+        self.router = self.router.head(handler);
+        self.operations.insert("head", summary);
+        self
+    }
+
+    pub(crate) fn post<H, T>(mut self, handler: H, summary: &'static str) -> Self
+    where
+        H: Handler<T, S, B>,
+        T: 'static,
+    {
+        self.router = self.router.post(handler);
+        self.operations.insert("post", summary);
+        self
+    }
+
+    pub(crate) fn put<H, T>(mut self, handler: H, summary: &'static str) -> Self
+    where
+        H: Handler<T, S, B>,
+        T: 'static,
+    {
+        self.router = self.router.put(handler);
+        self.operations.insert("put", summary);
+        self
+    }
+
+    pub(crate) fn patch<H, T>(mut self, handler: H, summary: &'static str) -> Self
+    where
+        H: Handler<T, S, B>,
+        T: 'static,
+    {
+        self.router = self.router.patch(handler);
+        self.operations.insert("patch", summary);
+        self
+    }
+
+    pub(crate) fn delete<H, T>(mut self, handler: H, summary: &'static str) -> Self
+    where
+        H: Handler<T, S, B>,
+        T: 'static,
+    {
+        self.router = self.router.delete(handler);
+        self.operations.insert("delete", summary);
+        self
+    }
+}
+
+/// A [`Router`] that keeps a description of every route added to it.
+#[must_use]
+pub(crate) struct ApiRouter<S, B> {
+    router: Router<S, B>,
+    paths: BTreeMap<String, Operations>,
+}
+
+impl<S, B> ApiRouter<S, B>
+where
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + 'static,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            router: Router::new(),
+            paths: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn route(mut self, path: &str, method: ApiMethod<S, B>) -> Self {
+        self.paths
+            .entry(path.to_string())
+            .or_default()
+            .extend(method.operations);
+        self.router = self.router.route(path, method.router);
+        self
+    }
+
+    pub(crate) fn merge(mut self, other: ApiRouter<S, B>) -> Self {
+        for (path, operations) in other.paths {
+            self.paths.entry(path).or_default().extend(operations);
+        }
+        self.router = self.router.merge(other.router);
+        self
+    }
+
+    /// The plain router, with the description of everything in it served at `path`.
+    pub(crate) fn finish(mut self, path: &str) -> Router<S, B> {
+        self.paths
+            .entry(path.to_string())
+            .or_default()
+            .insert("get", "This description of the API");
+        let document = Arc::new(document(&self.paths));
+        self.router.route(
+            path,
+            routing::get(move || async move { Json(document.as_ref().clone()) }),
+        )
+    }
+}
+
+// `:name` and `*name` segments (and `@:scope`) are parameters; OpenAPI writes them `{name}`.
+// A colon partway through a segment is left as it is.
+fn path_parameters(path: &str) -> (String, Vec<&str>) {
+    let mut names = Vec::new();
+    let segments: Vec<String> = path
+        .split('/')
+        .map(|segment| {
+            let (prefix, rest) = match segment.strip_prefix('@') {
+                Some(rest) => ("@", rest),
+                None => ("", segment),
+            };
+            match rest.strip_prefix([':', '*']) {
+                Some(name) if !name.is_empty() => {
+                    names.push(name);
+                    format!("{}{{{}}}", prefix, name)
+                }
+                _ => segment.to_string(),
+            }
+        })
+        .collect();
+    (segments.join("/"), names)
+}
+
+// Admin and SCIM routes take a signed admin request in place of (or as well as) a user token.
+fn is_admin_path(path: &str) -> bool {
+    ["/-/admin/", "/-/scim/"]
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+}
+
+fn document(paths: &BTreeMap<String, Operations>) -> serde_json::Value {
+    let paths: serde_json::Map<_, _> = paths
+        .iter()
+        .map(|(path, operations)| {
+            let (openapi_path, names) = path_parameters(path);
+            let parameters: Vec<_> = names
+                .iter()
+                .map(|name| {
+                    json!({
+                        "name": name,
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string" }
+                    })
+                })
+                .collect();
+
+            let operations: serde_json::Map<_, _> = operations
+                .iter()
+                .map(|(method, summary)| {
+                    let mut operation = json!({
+                        "summary": summary,
+                        "parameters": parameters,
+                        "responses": {
+                            "2XX": { "description": "Success" },
+                            "default": {
+                                "description": "An error",
+                                "content": {
+                                    "application/json": {
+                                        "schema": { "$ref": "#/components/schemas/Error" }
+                                    }
+                                }
+                            }
+                        }
+                    });
+                    if is_admin_path(path) {
+                        operation["security"] = json!([{ "bearer": [] }, { "adminSignature": [] }]);
+                    }
+                    (method.to_string(), operation)
+                })
+                .collect();
+            (openapi_path, serde_json::Value::Object(operations))
+        })
+        .collect();
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "bearer": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "A token from `npm login` or `/-/npm/v1/tokens`."
+                },
+                "adminSignature": {
+                    "type": "apiKey",
+                    "in": "header",
+                    "name": "x-regi-signature",
+                    "description": "An HMAC over the request by a configured admin key, sent with x-regi-key-id, x-regi-timestamp and x-regi-nonce."
+                }
+            },
+            "schemas": {
+                "Error": {
+                    "type": "object",
+                    "properties": { "error": { "type": "string" } },
+                    "required": ["error"]
+                }
+            }
+        },
+        "security": [{}, { "bearer": [] }]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_parameters() {
+        assert_eq!(
+            path_parameters("/@:scope/:pkg/-/*tarball"),
+            (
+                "/@{scope}/{pkg}/-/{tarball}".to_string(),
+                vec!["scope", "pkg", "tarball"]
+            )
+        );
+        assert_eq!(
+            path_parameters("/-/user/org.couchdb.user:user"),
+            ("/-/user/org.couchdb.user:user".to_string(), vec![])
+        );
+    }
+}
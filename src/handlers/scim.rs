@@ -0,0 +1,1063 @@
+//! SCIM 2.0 (RFC 7643/7644) provisioning, so an identity provider can create, update and
+//! offboard registry users and keep team membership in step with its groups.
+//!
+//! Users map onto [`UserStorage`] records, identified by username; Groups map onto teams,
+//! identified as `org:team`. Deactivating or deleting a user offboards them: they leave every
+//! org and team, lose their package grants, and have their tokens revoked.
+//!
+//! Every endpoint is an admin endpoint, needing the "scim" scope when called with a key.
+
+use std::collections::BTreeSet;
+
+use axum::body::{Body, HttpBody};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::instrument;
+
+use super::openapi::{get, ApiRouter};
+use crate::error::RegistryError;
+use crate::extractors::Admin;
+use crate::models::{OrgRole, PackageIdentifier, ProfileUpdate, User};
+use crate::policies::policy::PolicyHolder;
+use crate::policies::{AccessControl, TokenAuthorizer, UserStorage};
+
+const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+const GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+const LIST_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+const SERVICE_PROVIDER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig";
+
+const SCIM_CONTENT_TYPE: &str = "application/scim+json";
+const BASE_PATH: &str = "/-/scim/v2";
+
+/// The most resources returned in one page, whatever `count` asks for.
+const MAX_RESULTS: usize = 1000;
+
+#[derive(Debug)]
+struct ScimError {
+    status: StatusCode,
+    scim_type: Option<&'static str>,
+    detail: String,
+}
+
+impl ScimError {
+    fn new(status: StatusCode, detail: impl Into<String>) -> Self {
+        Self {
+            status,
+            scim_type: None,
+            detail: detail.into(),
+        }
+    }
+
+    fn invalid(scim_type: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            scim_type: Some(scim_type),
+            detail: detail.into(),
+        }
+    }
+
+    fn not_found(detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, detail)
+    }
+
+    fn internal(e: anyhow::Error) -> Self {
+        tracing::error!(error = ?e, "scim request failed");
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal error")
+    }
+}
+
+impl From<RegistryError> for ScimError {
+    fn from(error: RegistryError) -> Self {
+        Self::new(error.status(), error.message().to_string())
+    }
+}
+
+impl IntoResponse for ScimError {
+    fn into_response(self) -> Response {
+        let mut body = json!({
+            "schemas": [ERROR_SCHEMA],
+            "status": self.status.as_u16().to_string(),
+            "detail": self.detail
+        });
+        if let Some(scim_type) = self.scim_type {
+            body["scimType"] = json!(scim_type);
+        }
+        scim(self.status, body)
+    }
+}
+
+fn scim(status: StatusCode, body: Value) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, SCIM_CONTENT_TYPE)],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ScimName {
+    formatted: Option<String>,
+    given_name: Option<String>,
+    family_name: Option<String>,
+}
+
+impl ScimName {
+    fn full_name(&self) -> Option<String> {
+        if let Some(formatted) = &self.formatted {
+            return Some(formatted.clone());
+        }
+
+        let parts: Vec<_> = [&self.given_name, &self.family_name]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        (!parts.is_empty()).then(|| parts.join(" "))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ScimEmail {
+    value: String,
+    #[serde(default)]
+    primary: bool,
+}
+
+fn primary_email(emails: &[ScimEmail]) -> Option<String> {
+    emails
+        .iter()
+        .find(|email| email.primary)
+        .or_else(|| emails.first())
+        .map(|email| email.value.clone())
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ScimUser {
+    user_name: String,
+    display_name: Option<String>,
+    #[serde(default)]
+    name: ScimName,
+    #[serde(default)]
+    emails: Vec<ScimEmail>,
+    active: Option<bool>,
+}
+
+impl ScimUser {
+    fn full_name(&self) -> Option<String> {
+        self.display_name.clone().or_else(|| self.name.full_name())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ScimMember {
+    value: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ScimGroup {
+    display_name: String,
+    #[serde(default)]
+    members: Vec<ScimMember>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PatchOperation {
+    op: String,
+    path: Option<String>,
+    value: Option<Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PatchRequest {
+    #[serde(rename = "Operations")]
+    operations: Vec<PatchOperation>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ListQuery {
+    filter: Option<String>,
+    start_index: Option<usize>,
+    count: Option<usize>,
+}
+
+/// Parse the one filter form identity providers use to look a resource up before creating
+/// it: `<attribute> eq "<value>"`. The attribute is returned lowercased.
+fn parse_eq_filter(filter: &str) -> Result<(String, String), ScimError> {
+    let invalid = || {
+        ScimError::invalid(
+            "invalidFilter",
+            "only `<attribute> eq \"<value>\"` filters are supported",
+        )
+    };
+
+    let mut parts = filter.trim().splitn(3, ' ');
+    let (Some(attribute), Some(op), Some(value)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid());
+    };
+    if !op.eq_ignore_ascii_case("eq") {
+        return Err(invalid());
+    }
+
+    let value = value.trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+    Ok((attribute.to_ascii_lowercase(), value.to_string()))
+}
+
+fn list_response(resources: Vec<Value>, query: &ListQuery) -> Response {
+    let total = resources.len();
+    let start_index = query.start_index.unwrap_or(1).max(1);
+    let count = query.count.unwrap_or(MAX_RESULTS).min(MAX_RESULTS);
+    let page: Vec<_> = resources
+        .into_iter()
+        .skip(start_index - 1)
+        .take(count)
+        .collect();
+
+    scim(
+        StatusCode::OK,
+        json!({
+            "schemas": [LIST_SCHEMA],
+            "totalResults": total,
+            "startIndex": start_index,
+            "itemsPerPage": page.len(),
+            "Resources": page
+        }),
+    )
+}
+
+fn user_resource(user: &User, active: bool) -> Value {
+    let mut resource = json!({
+        "schemas": [USER_SCHEMA],
+        "id": user.name,
+        "userName": user.name,
+        "active": active,
+        "meta": {
+            "resourceType": "User",
+            "location": format!("{}/Users/{}", BASE_PATH, user.name)
+        }
+    });
+    if let Some(full_name) = &user.full_name {
+        resource["displayName"] = json!(full_name);
+        resource["name"] = json!({ "formatted": full_name });
+    }
+    if !user.email.is_empty() {
+        resource["emails"] = json!([{ "value": user.email, "primary": true }]);
+    }
+    resource
+}
+
+fn group_resource(org: &str, team: &str, members: &[String]) -> Value {
+    let id = format!("{}:{}", org, team);
+    json!({
+        "schemas": [GROUP_SCHEMA],
+        "id": id,
+        "displayName": id,
+        "members": members
+            .iter()
+            .map(|member| json!({ "value": member, "display": member }))
+            .collect::<Vec<_>>(),
+        "meta": {
+            "resourceType": "Group",
+            "location": format!("{}/Groups/{}", BASE_PATH, id)
+        }
+    })
+}
+
+async fn find_user<S: PolicyHolder>(state: &S, username: &str) -> Result<(User, bool), ScimError> {
+    let storage = state.as_user_storage();
+    let Ok(user) = storage.get_user(username).await else {
+        return Err(ScimError::not_found(format!("no such user: {}", username)));
+    };
+    let active = storage
+        .is_active(username)
+        .await
+        .map_err(ScimError::internal)?;
+    Ok((user, active))
+}
+
+/// Take away everything that lets `username` act on the registry: org and team memberships,
+/// package grants, and tokens. Their packuments still list them as a maintainer, but they can
+/// no longer authenticate to use that.
+async fn offboard<S: PolicyHolder>(state: &S, username: &str, by: &str) -> anyhow::Result<()> {
+    let user_storage = state.as_user_storage();
+    let orgs = user_storage.orgs_for_user(username).await?;
+    for org in &orgs {
+        user_storage.remove_org_member(org, username).await?;
+    }
+    // Storages needn't drop team memberships along with the org's.
+    for grantee in user_storage.teams_for_user(username).await? {
+        if let Some((org, team)) = grantee.split_once(':') {
+            user_storage.remove_team_member(org, team, username).await?;
+        }
+    }
+
+    let access_control = state.as_access_control();
+    let grants = access_control.list_packages(username).await?;
+    for package in grants.keys() {
+        let package: PackageIdentifier = package.parse()?;
+        access_control.revoke(&package, username).await?;
+    }
+
+    let tokens = state
+        .as_token_authorizer()
+        .revoke_user_tokens(username)
+        .await?;
+
+    tracing::info!(
+        target: "audit",
+        action = "user.offboard",
+        user = username,
+        orgs = orgs.len(),
+        grants = grants.len(),
+        tokens,
+        by
+    );
+    Ok(())
+}
+
+/// Field changes to a user, gathered from a PUT or PATCH.
+#[derive(Debug, Default)]
+struct UserChanges {
+    full_name: Option<String>,
+    email: Option<String>,
+    active: Option<bool>,
+}
+
+async fn apply_user_changes<S: PolicyHolder>(
+    state: &S,
+    username: &str,
+    changes: UserChanges,
+    by: &str,
+) -> Result<Value, ScimError> {
+    let (mut user, mut active) = find_user(state, username).await?;
+    let storage = state.as_user_storage();
+
+    if changes.full_name.is_some() || changes.email.is_some() {
+        let update = ProfileUpdate {
+            fullname: changes.full_name,
+            email: changes.email,
+            ..Default::default()
+        };
+        user = storage
+            .update_user(username, update)
+            .await
+            .map_err(ScimError::internal)?;
+    }
+
+    if let Some(requested) = changes.active.filter(|requested| *requested != active) {
+        storage
+            .set_active(username, requested)
+            .await
+            .map_err(ScimError::internal)?;
+        if !requested {
+            offboard(state, username, by)
+                .await
+                .map_err(ScimError::internal)?;
+        }
+        active = requested;
+    }
+
+    Ok(user_resource(&user, active))
+}
+
+fn group_id(id: &str) -> Result<(&str, &str), ScimError> {
+    id.split_once(':')
+        .ok_or_else(|| ScimError::not_found(format!("no such group: {}", id)))
+}
+
+async fn team_members<S: PolicyHolder>(
+    state: &S,
+    org: &str,
+    team: &str,
+) -> Result<Vec<String>, ScimError> {
+    state
+        .as_user_storage()
+        .list_team_members(org, team)
+        .await
+        .map_err(ScimError::internal)?
+        .ok_or_else(|| ScimError::not_found(format!("no such group: {}:{}", org, team)))
+}
+
+/// Teams are drawn from their org's membership, so provisioning someone onto a team makes
+/// them a developer in the org if they weren't already a member.
+async fn add_member<S: PolicyHolder>(
+    state: &S,
+    org: &str,
+    team: &str,
+    username: &str,
+) -> Result<(), ScimError> {
+    let storage = state.as_user_storage();
+    if storage.get_user(username).await.is_err() {
+        return Err(ScimError::invalid(
+            "invalidValue",
+            format!("no such user: {}", username),
+        ));
+    }
+
+    let members = storage
+        .list_org_members(org)
+        .await
+        .map_err(ScimError::internal)?;
+    if !members.contains_key(username) {
+        storage
+            .set_org_member(org, username, OrgRole::Developer)
+            .await
+            .map_err(ScimError::internal)?;
+    }
+
+    storage
+        .add_team_member(org, team, username)
+        .await
+        .map_err(ScimError::internal)?;
+    Ok(())
+}
+
+async fn remove_member<S: PolicyHolder>(
+    state: &S,
+    org: &str,
+    team: &str,
+    username: &str,
+) -> Result<(), ScimError> {
+    state
+        .as_user_storage()
+        .remove_team_member(org, team, username)
+        .await
+        .map_err(ScimError::internal)?;
+    Ok(())
+}
+
+/// Make `org:team`'s members exactly `wanted`.
+async fn replace_members<S: PolicyHolder>(
+    state: &S,
+    org: &str,
+    team: &str,
+    wanted: BTreeSet<String>,
+) -> Result<(), ScimError> {
+    let current: BTreeSet<_> = team_members(state, org, team).await?.into_iter().collect();
+    for username in current.difference(&wanted) {
+        remove_member(state, org, team, username).await?;
+    }
+    for username in wanted.difference(&current) {
+        add_member(state, org, team, username).await?;
+    }
+    Ok(())
+}
+
+#[instrument]
+async fn get_service_provider_config(admin: Admin) -> Result<Response, ScimError> {
+    admin.require_scope("scim")?;
+
+    Ok(scim(
+        StatusCode::OK,
+        json!({
+            "schemas": [SERVICE_PROVIDER_SCHEMA],
+            "patch": { "supported": true },
+            "bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+            "filter": { "supported": true, "maxResults": MAX_RESULTS },
+            "changePassword": { "supported": false },
+            "sort": { "supported": false },
+            "etag": { "supported": false },
+            "authenticationSchemes": [{
+                "type": "oauthbearertoken",
+                "name": "Bearer token",
+                "description": "A registry token belonging to an administrator"
+            }]
+        }),
+    ))
+}
+
+#[instrument(skip(state))]
+async fn get_users<S>(
+    State(state): State<S>,
+    Query(query): Query<ListQuery>,
+    admin: Admin,
+) -> Result<Response, ScimError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("scim")?;
+
+    let username = match query.filter.as_deref().map(parse_eq_filter).transpose()? {
+        Some((attribute, value)) if attribute == "username" => Some(value),
+        Some(_) => {
+            return Err(ScimError::invalid(
+                "invalidFilter",
+                "users may only be filtered by userName",
+            ))
+        }
+        None => None,
+    };
+
+    let storage = state.as_user_storage();
+    let users = match username {
+        Some(username) => storage
+            .get_user(username.as_str())
+            .await
+            .ok()
+            .into_iter()
+            .collect(),
+        None => storage.list_users().await.map_err(ScimError::internal)?,
+    };
+
+    let mut resources = Vec::with_capacity(users.len());
+    for user in users {
+        let active = storage
+            .is_active(user.name.as_str())
+            .await
+            .map_err(ScimError::internal)?;
+        resources.push(user_resource(&user, active));
+    }
+    Ok(list_response(resources, &query))
+}
+
+#[instrument(skip(state))]
+async fn post_user<S>(State(state): State<S>, admin: Admin<ScimUser>) -> Result<Response, ScimError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("scim")?;
+
+    let payload = &admin.payload;
+    let storage = state.as_user_storage();
+    if storage.get_user(payload.user_name.as_str()).await.is_ok() {
+        return Err(ScimError {
+            status: StatusCode::CONFLICT,
+            scim_type: Some("uniqueness"),
+            detail: format!("user already exists: {}", payload.user_name),
+        });
+    }
+
+    let user = User {
+        name: payload.user_name.clone(),
+        email: primary_email(&payload.emails).unwrap_or_default(),
+        full_name: payload.full_name(),
+        homepage: None,
+        freenode: None,
+        twitter: None,
+    };
+    let user = storage
+        .register_user(user)
+        .await
+        .map_err(ScimError::internal)?;
+
+    let active = payload.active.unwrap_or(true);
+    if !active {
+        storage
+            .set_active(user.name.as_str(), false)
+            .await
+            .map_err(ScimError::internal)?;
+    }
+
+    tracing::info!(
+        target: "audit",
+        action = "user.provision",
+        user = user.name,
+        active,
+        by = admin.principal.name()
+    );
+    Ok(scim(StatusCode::CREATED, user_resource(&user, active)))
+}
+
+#[instrument(skip(state))]
+async fn get_scim_user<S>(
+    State(state): State<S>,
+    Path(id): Path<String>,
+    admin: Admin,
+) -> Result<Response, ScimError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("scim")?;
+
+    let (user, active) = find_user(&state, id.as_str()).await?;
+    Ok(scim(StatusCode::OK, user_resource(&user, active)))
+}
+
+#[instrument(skip(state))]
+async fn put_scim_user<S>(
+    State(state): State<S>,
+    Path(id): Path<String>,
+    admin: Admin<ScimUser>,
+) -> Result<Response, ScimError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("scim")?;
+
+    let payload = &admin.payload;
+    if payload.user_name != id {
+        return Err(ScimError::invalid(
+            "mutability",
+            "userName may not be changed",
+        ));
+    }
+
+    // A PUT replaces the resource, so attributes it leaves out are cleared.
+    let changes = UserChanges {
+        full_name: Some(payload.full_name().unwrap_or_default()),
+        email: primary_email(&payload.emails),
+        active: payload.active,
+    };
+    let resource = apply_user_changes(
+        &state,
+        id.as_str(),
+        changes,
+        admin.principal.name().as_str(),
+    )
+    .await?;
+    Ok(scim(StatusCode::OK, resource))
+}
+
+fn as_bool(value: &Value) -> Option<bool> {
+    // Some providers send booleans as "True" or "False".
+    match value {
+        Value::Bool(value) => Some(*value),
+        Value::String(value) => value.to_ascii_lowercase().parse().ok(),
+        _ => None,
+    }
+}
+
+fn collect_user_change(
+    changes: &mut UserChanges,
+    path: &str,
+    value: &Value,
+) -> Result<(), ScimError> {
+    let invalid = || ScimError::invalid("invalidValue", format!("bad value for {}", path));
+
+    match path.to_ascii_lowercase().as_str() {
+        "active" => changes.active = Some(as_bool(value).ok_or_else(invalid)?),
+        "displayname" | "name.formatted" => {
+            changes.full_name = Some(value.as_str().ok_or_else(invalid)?.to_string())
+        }
+        "name" => {
+            let name: ScimName = serde_json::from_value(value.clone()).map_err(|_| invalid())?;
+            changes.full_name = name.full_name();
+        }
+        "emails" => {
+            let emails: Vec<ScimEmail> =
+                serde_json::from_value(value.clone()).map_err(|_| invalid())?;
+            changes.email = primary_email(&emails);
+        }
+        path if path.starts_with("emails[") && path.ends_with(".value") => {
+            changes.email = Some(value.as_str().ok_or_else(invalid)?.to_string())
+        }
+        // Attributes we don't keep (title, locale, enterprise extensions, ...) are ignored
+        // rather than failing the whole request.
+        _ => {}
+    }
+    Ok(())
+}
+
+#[instrument(skip(state))]
+async fn patch_scim_user<S>(
+    State(state): State<S>,
+    Path(id): Path<String>,
+    admin: Admin<PatchRequest>,
+) -> Result<Response, ScimError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("scim")?;
+
+    let mut changes = UserChanges::default();
+    for operation in &admin.payload.operations {
+        if !matches!(
+            operation.op.to_ascii_lowercase().as_str(),
+            "add" | "replace"
+        ) {
+            return Err(ScimError::invalid(
+                "invalidValue",
+                format!("unsupported operation on users: {}", operation.op),
+            ));
+        }
+
+        let value = operation.value.clone().unwrap_or(Value::Null);
+        match (&operation.path, value) {
+            (Some(path), value) => collect_user_change(&mut changes, path, &value)?,
+            (None, Value::Object(attributes)) => {
+                for (path, value) in attributes {
+                    collect_user_change(&mut changes, path.as_str(), &value)?;
+                }
+            }
+            (None, _) => {
+                return Err(ScimError::invalid(
+                    "invalidValue",
+                    "operations without a path need an object value",
+                ))
+            }
+        }
+    }
+
+    let resource = apply_user_changes(
+        &state,
+        id.as_str(),
+        changes,
+        admin.principal.name().as_str(),
+    )
+    .await?;
+    Ok(scim(StatusCode::OK, resource))
+}
+
+#[instrument(skip(state))]
+async fn delete_scim_user<S>(
+    State(state): State<S>,
+    Path(id): Path<String>,
+    admin: Admin,
+) -> Result<Response, ScimError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("scim")?;
+
+    find_user(&state, id.as_str()).await?;
+    let by = admin.principal.name();
+    offboard(&state, id.as_str(), by.as_str())
+        .await
+        .map_err(ScimError::internal)?;
+    state
+        .as_user_storage()
+        .remove_user(id.as_str())
+        .await
+        .map_err(ScimError::internal)?;
+
+    tracing::info!(target: "audit", action = "user.deprovision", user = id, by);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[instrument(skip(state))]
+async fn get_groups<S>(
+    State(state): State<S>,
+    Query(query): Query<ListQuery>,
+    admin: Admin,
+) -> Result<Response, ScimError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("scim")?;
+
+    let wanted = match query.filter.as_deref().map(parse_eq_filter).transpose()? {
+        Some((attribute, value)) if attribute == "displayname" || attribute == "id" => Some(value),
+        Some(_) => {
+            return Err(ScimError::invalid(
+                "invalidFilter",
+                "groups may only be filtered by displayName",
+            ))
+        }
+        None => None,
+    };
+
+    let storage = state.as_user_storage();
+    let mut resources = Vec::new();
+    for org in storage.list_orgs().await.map_err(ScimError::internal)? {
+        for id in storage
+            .list_teams(org.as_str())
+            .await
+            .map_err(ScimError::internal)?
+        {
+            if wanted.as_ref().is_some_and(|wanted| *wanted != id) {
+                continue;
+            }
+            let (org, team) = group_id(id.as_str())?;
+            let members = team_members(&state, org, team).await?;
+            resources.push(group_resource(org, team, members.as_slice()));
+        }
+    }
+    Ok(list_response(resources, &query))
+}
+
+#[instrument(skip(state))]
+async fn post_group<S>(
+    State(state): State<S>,
+    admin: Admin<ScimGroup>,
+) -> Result<Response, ScimError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("scim")?;
+
+    let payload = &admin.payload;
+    let Some((org, team)) = payload.display_name.split_once(':') else {
+        return Err(ScimError::invalid(
+            "invalidValue",
+            "group names must be of the form org:team",
+        ));
+    };
+
+    let created = state
+        .as_user_storage()
+        .create_team(org, team, None)
+        .await
+        .map_err(ScimError::internal)?;
+    if !created {
+        return Err(ScimError {
+            status: StatusCode::CONFLICT,
+            scim_type: Some("uniqueness"),
+            detail: format!("group already exists: {}", payload.display_name),
+        });
+    }
+
+    for member in &payload.members {
+        add_member(&state, org, team, member.value.as_str()).await?;
+    }
+
+    tracing::info!(
+        target: "audit",
+        action = "team.provision",
+        team = payload.display_name,
+        by = admin.principal.name()
+    );
+    let members = team_members(&state, org, team).await?;
+    Ok(scim(
+        StatusCode::CREATED,
+        group_resource(org, team, members.as_slice()),
+    ))
+}
+
+#[instrument(skip(state))]
+async fn get_group<S>(
+    State(state): State<S>,
+    Path(id): Path<String>,
+    admin: Admin,
+) -> Result<Response, ScimError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("scim")?;
+
+    let (org, team) = group_id(id.as_str())?;
+    let members = team_members(&state, org, team).await?;
+    Ok(scim(
+        StatusCode::OK,
+        group_resource(org, team, members.as_slice()),
+    ))
+}
+
+#[instrument(skip(state))]
+async fn put_group<S>(
+    State(state): State<S>,
+    Path(id): Path<String>,
+    admin: Admin<ScimGroup>,
+) -> Result<Response, ScimError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("scim")?;
+
+    let (org, team) = group_id(id.as_str())?;
+    if admin.payload.display_name != id {
+        return Err(ScimError::invalid(
+            "mutability",
+            "groups may not be renamed",
+        ));
+    }
+
+    let wanted = admin
+        .payload
+        .members
+        .iter()
+        .map(|member| member.value.clone())
+        .collect();
+    replace_members(&state, org, team, wanted).await?;
+
+    let members = team_members(&state, org, team).await?;
+    Ok(scim(
+        StatusCode::OK,
+        group_resource(org, team, members.as_slice()),
+    ))
+}
+
+fn member_values(value: Option<&Value>) -> Result<Vec<String>, ScimError> {
+    let Some(value) = value else {
+        return Ok(Vec::new());
+    };
+    let members: Vec<ScimMember> = serde_json::from_value(value.clone()).map_err(|_| {
+        ScimError::invalid("invalidValue", "members must be a list of {\"value\": ...}")
+    })?;
+    Ok(members.into_iter().map(|member| member.value).collect())
+}
+
+#[instrument(skip(state))]
+async fn patch_group<S>(
+    State(state): State<S>,
+    Path(id): Path<String>,
+    admin: Admin<PatchRequest>,
+) -> Result<Response, ScimError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("scim")?;
+
+    let (org, team) = group_id(id.as_str())?;
+    team_members(&state, org, team).await?;
+
+    for operation in &admin.payload.operations {
+        let op = operation.op.to_ascii_lowercase();
+        let path = operation.path.as_deref().unwrap_or_default();
+
+        // `members[value eq "x"]` names a single member.
+        if let Some(filter) = path
+            .strip_prefix("members[")
+            .and_then(|path| path.strip_suffix(']'))
+        {
+            let (attribute, username) = parse_eq_filter(filter)?;
+            match (op.as_str(), attribute.as_str()) {
+                ("remove", "value") => remove_member(&state, org, team, username.as_str()).await?,
+                _ => {
+                    return Err(ScimError::invalid(
+                        "invalidPath",
+                        format!("unsupported operation: {} {}", operation.op, path),
+                    ))
+                }
+            }
+            continue;
+        }
+
+        let members = match (path.to_ascii_lowercase().as_str(), &operation.value) {
+            ("members", value) => member_values(value.as_ref())?,
+            ("", Some(Value::Object(attributes))) => {
+                if let Some(name) = attributes.get("displayName") {
+                    if name.as_str() != Some(id.as_str()) {
+                        return Err(ScimError::invalid(
+                            "mutability",
+                            "groups may not be renamed",
+                        ));
+                    }
+                }
+                member_values(attributes.get("members"))?
+            }
+            ("displayname", Some(name)) if name.as_str() == Some(id.as_str()) => continue,
+            _ => {
+                return Err(ScimError::invalid(
+                    "invalidPath",
+                    format!("unsupported operation: {} {}", operation.op, path),
+                ))
+            }
+        };
+
+        match op.as_str() {
+            "add" => {
+                for username in &members {
+                    add_member(&state, org, team, username).await?;
+                }
+            }
+            "remove" => {
+                for username in &members {
+                    remove_member(&state, org, team, username).await?;
+                }
+            }
+            "replace" => {
+                replace_members(&state, org, team, members.into_iter().collect()).await?;
+            }
+            _ => {
+                return Err(ScimError::invalid(
+                    "invalidValue",
+                    format!("unsupported operation: {}", operation.op),
+                ))
+            }
+        }
+    }
+
+    let members = team_members(&state, org, team).await?;
+    Ok(scim(
+        StatusCode::OK,
+        group_resource(org, team, members.as_slice()),
+    ))
+}
+
+#[instrument(skip(state))]
+async fn delete_group<S>(
+    State(state): State<S>,
+    Path(id): Path<String>,
+    admin: Admin,
+) -> Result<Response, ScimError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("scim")?;
+
+    let (org, team) = group_id(id.as_str())?;
+    let deleted = state
+        .as_user_storage()
+        .delete_team(org, team)
+        .await
+        .map_err(ScimError::internal)?;
+    if !deleted {
+        return Err(ScimError::not_found(format!("no such group: {}", id)));
+    }
+
+    tracing::info!(
+        target: "audit",
+        action = "team.deprovision",
+        team = id,
+        by = admin.principal.name()
+    );
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+pub(crate) fn routes<S, B>() -> ApiRouter<S, B>
+where
+    S: PolicyHolder + Clone + Sync + Send + 'static + std::fmt::Debug,
+    B: Sync + Send + HttpBody + std::fmt::Debug + Into<Body> + 'static,
+    <B as HttpBody>::Data: 'static + Send + Sync,
+    <B as HttpBody>::Error: std::error::Error + 'static + Send + Sync,
+{
+    ApiRouter::new()
+        .route(
+            "/-/scim/v2/ServiceProviderConfig",
+            get(
+                get_service_provider_config,
+                "Describe the SCIM features served",
+            ),
+        )
+        .route(
+            "/-/scim/v2/Users",
+            get(get_users::<S>, "List provisioned users").post(post_user::<S>, "Provision a user"),
+        )
+        .route(
+            "/-/scim/v2/Users/:id",
+            get(get_scim_user::<S>, "Fetch a provisioned user")
+                .put(put_scim_user::<S>, "Replace a provisioned user")
+                .patch(patch_scim_user::<S>, "Update a provisioned user")
+                .delete(delete_scim_user::<S>, "Deprovision a user"),
+        )
+        .route(
+            "/-/scim/v2/Groups",
+            get(get_groups::<S>, "List provisioned teams")
+                .post(post_group::<S>, "Provision a team"),
+        )
+        .route(
+            "/-/scim/v2/Groups/:id",
+            get(get_group::<S>, "Fetch a provisioned team")
+                .put(put_group::<S>, "Replace a provisioned team")
+                .patch(patch_group::<S>, "Update a provisioned team")
+                .delete(delete_group::<S>, "Deprovision a team"),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_eq_filter() {
+        assert_eq!(
+            parse_eq_filter(r#"userName eq "jane doe""#).unwrap(),
+            ("username".to_string(), "jane doe".to_string())
+        );
+        assert!(parse_eq_filter(r#"userName sw "j""#).is_err());
+        assert!(parse_eq_filter("userName eq jane").is_err());
+    }
+}
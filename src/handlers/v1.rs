@@ -1,310 +1,6216 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
 use axum::body::{Body, HttpBody, StreamBody};
-use axum::extract::{Path, State};
-use axum::http::{Request, StatusCode};
-use axum::response::IntoResponse;
-use axum::routing::{any, get, post, put};
-use axum::{Json, Router};
+use axum::error_handling::HandleErrorLayer;
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::{BoxError, Json, Router};
 use tower::ServiceBuilder;
 use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::decompression::RequestDecompressionLayer;
 use tower_http::sensitive_headers::SetSensitiveRequestHeadersLayer;
 use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
 use tower_http::LatencyUnit;
 
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tracing::{instrument, Level};
 
-use crate::extractors::Authenticated;
-use crate::models::{PackageIdentifier, PackageModification, Packument};
+use super::openapi::{any, delete, get, patch, post, put, ApiRouter};
+use crate::error::RegistryError;
+use crate::extractors::{
+    authenticate, client_ip, is_valid_cidr, Admin, AdminPrincipal, Authenticated, ClientIp,
+    Publisher, Reader,
+};
+use crate::hashing::{Hasher, Integrity};
+use crate::models::{
+    parse_version, platform_variants, supports_platform, Attestations, AuditRequest, BulkAdvisory,
+    BulkAdvisoryRequest, DistTagPolicy, Maintainer, MaintainerObject, MetadataUpdate, OrgRole,
+    PackageIdentifier, PackageModification, Packument, PackumentVersion, ProfileUpdate, Provenance,
+    Signature, User, VersionRange,
+};
+use crate::policies::access_control::{Access, PackageEvent, Permission, Transfer};
+use crate::policies::authenticator::LoginSessionError;
+use crate::policies::download_counts::DownloadPeriod;
+use crate::policies::geolocation::Location;
+use crate::policies::moderation::Quarantine;
+use crate::policies::package_storage::{
+    collect_stream, guard, ByteRange, ContentEncoding, ContentMetadata, Offline, PackageChange,
+    SearchQuery, StorageError,
+};
 use crate::policies::policy::PolicyHolder;
-use crate::policies::{Authenticator, Configurator, PackageStorage, TokenAuthorizer, UserStorage};
+use crate::policies::token_authorizer::{bearer_token, LoginEvent, TokenOptions};
+use crate::policies::transform::ServedPackument;
+use crate::policies::user_storage::UserExport;
+use crate::policies::webhooks::{HookEvent, HookUpdate, NewHook};
+use crate::policies::{
+    AccessControl, Advisories, Authenticator, Configurator, DownloadCounts, Geolocator, Moderation,
+    PackageStorage, TokenAuthorizer, UserStorage, Webhooks,
+};
+use crate::signing::{active_key, PublicKey, SigningError, SigningKey};
+
+const ABBREVIATED_CONTENT_TYPE: &str = "application/vnd.npm.install-v1+json";
+// Packuments come in two forms at one URL, so caches in front of us must key on Accept.
+const VARY_ACCEPT: (header::HeaderName, &str) = (header::VARY, "accept");
+// ...and, when we pick the compression rather than the compression layer, on Accept-Encoding.
+const VARY_ACCEPT_ENCODING: (header::HeaderName, &str) = (header::VARY, "accept, accept-encoding");
+
+const CHANGES_LIMIT: usize = 1000;
+// The largest tarball accepted by a streamed publish.
+const PUBLISH_LIMIT: usize = 256 << 20;
+const VERIFY_LIMIT: usize = 1000;
+// How many lockfile entries are checked at once.
+const VERIFY_CONCURRENCY: usize = 8;
+const BATCH_LIMIT: usize = 1000;
+// How many packuments one batch request fetches at once.
+const BATCH_CONCURRENCY: usize = 8;
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+const BACKFILL_TASK: &str = "signatures:backfill";
+const PREVIEW_PRUNE_TASK: &str = "previews:prune";
+const CACHE_WARM_TASK: &str = "cache:warm";
+const WARM_LIMIT: usize = 10_000;
+// How many packages one warming fetches at once.
+const WARM_CONCURRENCY: usize = 8;
+// Who preview tokens publish as.
+const PREVIEW_PUBLISHER: &str = "preview";
+// How often longpoll and continuous `_changes` feeds look for new changes.
+const CHANGES_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+const CHANGES_DEFAULT_HEARTBEAT_MS: u64 = 30_000;
+const CHANGES_DEFAULT_TIMEOUT_MS: u64 = 60_000;
+const WATCH_DEFAULT_TIMEOUT_MS: u64 = 60_000;
+const EVENT_STREAM_CONTENT_TYPE: &str = "text/event-stream";
+const PREFETCH_TASK: &str = "search:prefetch";
+// How many packuments one prefetch fetches at once.
+const PREFETCH_CONCURRENCY: usize = 4;
+// Searches made while this many prefetches are still running don't start another, so a burst
+// of searches can't queue up unbounded upstream traffic.
+const PREFETCH_MAX_RUNNING: usize = 2;
+
+#[derive(Deserialize, Debug)]
+struct PackumentQuery {
+    /// The document as stored, with nothing trimmed from it.
+    #[serde(default)]
+    full: bool,
+}
 
-#[instrument(level = "info", fields(pkg))]
+#[instrument(level = "info", skip(headers), fields(pkg))]
 async fn get_packument<Storage>(
     State(state): State<Storage>,
+    user: Option<Authenticated>,
+    headers: HeaderMap,
     Path(pkg): Path<String>,
-) -> Result<impl IntoResponse, StatusCode>
+    Query(query): Query<PackumentQuery>,
+) -> Result<Response, RegistryError>
 where
     Storage: PolicyHolder + std::fmt::Debug,
 {
-    let Ok(pkg) = pkg.parse() else {
-        return Err(StatusCode::BAD_REQUEST)
+    let pkg = parse_package(pkg.as_str())?;
+
+    // Report restricted packages as missing rather than forbidden, as npm does.
+    if !can_install(&state, user.as_ref().map(|user| &user.0), &pkg).await? {
+        return Err(package_not_found(&pkg));
+    }
+    check_quarantine(&state, &pkg, None).await?;
+
+    let abbreviated = wants_abbreviated(&headers);
+    let content_type = if abbreviated {
+        ABBREVIATED_CONTENT_TYPE
+    } else {
+        "application/json"
     };
 
-    let stream = state
-        .as_package_storage()
-        .stream_packument(&pkg)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let user = user.map(|user| user.0);
+    if let Some(document) =
+        transformed_packument(&state, user.as_ref(), &pkg, abbreviated, query.full).await?
+    {
+        let etag = ContentMetadata::of(document.as_slice())
+            .map_err(RegistryError::internal)?
+            .etag();
+        if is_not_modified(&headers, etag.as_str()) {
+            return Ok((
+                StatusCode::NOT_MODIFIED,
+                [VARY_ACCEPT],
+                [(header::ETAG, etag)],
+            )
+                .into_response());
+        }
+        return Ok((
+            [VARY_ACCEPT, (header::CONTENT_TYPE, content_type)],
+            [(header::ETAG, etag)],
+            document,
+        )
+            .into_response());
+    }
 
-    Ok(StreamBody::new(stream))
-}
+    let storage = state.as_package_storage();
+    let metadata = if abbreviated {
+        storage.abbreviated_packument_metadata(&pkg).await
+    } else {
+        storage.packument_metadata(&pkg).await
+    };
 
-#[instrument(level = "info", fields(pkg))]
-async fn put_packument<Storage>(
-    State(state): State<Storage>,
-    Path(pkg): Path<String>,
-    Json(payload): Json<Packument>,
-) -> Result<impl IntoResponse, StatusCode>
-where
-    Storage: PolicyHolder + std::fmt::Debug,
-{
-    if payload.id.as_deref() != Some(pkg.as_str()) {
-        return Err(StatusCode::BAD_REQUEST);
+    // Without metadata we can still serve the document, just not revalidate it, unless the
+    // storage already knows there's nothing to serve.
+    let metadata = match metadata {
+        Ok(metadata) => Some(metadata),
+        Err(e) if StorageError::of(&e).is_some() => {
+            return Err(storage_error(e, || package_not_found(&pkg)))
+        }
+        Err(_) => None,
+    };
+
+    // A compressed copy is tagged apart from the document it was made from; a client holding
+    // either is up to date.
+    let accepted = accepted_encoding(&headers);
+    if let Some(ref metadata) = metadata {
+        let fresh = std::iter::once(metadata.etag())
+            .chain(accepted.map(|encoding| metadata.encoded_etag(encoding)))
+            .find(|etag| is_not_modified(&headers, etag));
+        if let Some(etag) = fresh {
+            return Ok((
+                StatusCode::NOT_MODIFIED,
+                [VARY_ACCEPT],
+                [(header::ETAG, etag)],
+            )
+                .into_response());
+        }
     }
 
-    let Ok(pkg) = pkg.parse() else {
-        return Err(StatusCode::BAD_REQUEST)
+    // A copy the storage keeps compressed is sent as it is; the compression layer leaves
+    // responses that already have a Content-Encoding alone.
+    let encoded = match accepted {
+        Some(encoding) => match storage
+            .stream_encoded_packument(&pkg, abbreviated, encoding)
+            .await
+        {
+            Ok(stream) => stream.map(|stream| (encoding, stream)),
+            Err(e) => {
+                tracing::warn!(error = ?e, "compressed packument unavailable; compressing it instead");
+                None
+            }
+        },
+        None => None,
     };
 
-    let old_packument = state
-        .as_package_storage()
-        .fetch_packument(&pkg)
-        .await
-        .ok()
-        .unwrap_or(Default::default());
+    let (mut response, etag) = match encoded {
+        Some((encoding, stream)) => (
+            (
+                [
+                    VARY_ACCEPT_ENCODING,
+                    (header::CONTENT_TYPE, content_type),
+                    (header::CONTENT_ENCODING, encoding.as_str()),
+                ],
+                StreamBody::new(stream),
+            )
+                .into_response(),
+            metadata.map(|metadata| metadata.encoded_etag(encoding)),
+        ),
+        None => {
+            let stream = if abbreviated {
+                storage.stream_abbreviated_packument(&pkg).await
+            } else {
+                storage.stream_packument(&pkg).await
+            };
+            let stream = stream.map_err(|e| storage_error(e, || package_not_found(&pkg)))?;
+            (
+                (
+                    [VARY_ACCEPT, (header::CONTENT_TYPE, content_type)],
+                    StreamBody::new(stream),
+                )
+                    .into_response(),
+                metadata.map(|metadata| metadata.etag()),
+            )
+        }
+    };
+    if let Some(etag) = etag.and_then(|etag| etag.try_into().ok()) {
+        response.headers_mut().insert(header::ETAG, etag);
+    }
+    Ok(response)
+}
 
-    let Ok(_modification) = PackageModification::from_diff(old_packument, payload) else {
-        return Err(StatusCode::BAD_REQUEST)
+// npm, pnpm and Yarn all ask with "application/vnd.npm.install-v1+json; q=1.0,
+// application/json; q=0.8, */*", and for plain JSON when they need the full document. Serve the
+// abbreviated form when it's preferred at least as much as plain JSON.
+fn wants_abbreviated(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+    else {
+        return false;
     };
+    let abbreviated = accept_quality(accept, ABBREVIATED_CONTENT_TYPE);
+    abbreviated > 0.0 && abbreviated >= accept_quality(accept, "application/json")
+}
 
-    Ok(StatusCode::NOT_FOUND)
+// The q-value `accept` gives `media_type` by name, or 0 when it doesn't list it.
+fn accept_quality(accept: &str, media_type: &str) -> f32 {
+    accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            if !params.next()?.trim().eq_ignore_ascii_case(media_type) {
+                return None;
+            }
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some(quality)
+        })
+        .fold(0.0, f32::max)
 }
 
-#[instrument(level = "info", fields(pkg))]
-async fn put_packument_at_rev<Storage>(
-    state: State<Storage>,
-    Path((pkg, rev)): Path<(String, String)>,
-    payload: Json<Packument>,
-) -> Result<impl IntoResponse, StatusCode>
+// The encoding packuments may be kept in that the client prefers, by its Accept-Encoding
+// q-values; ties go to the one that compresses best.
+fn accepted_encoding(headers: &HeaderMap) -> Option<ContentEncoding> {
+    let accept = headers.get(header::ACCEPT_ENCODING)?.to_str().ok()?;
+    ContentEncoding::ALL
+        .into_iter()
+        .map(|encoding| (encoding, accept_quality(accept, encoding.as_str())))
+        .filter(|(_, quality)| *quality > 0.0)
+        .reduce(|best, candidate| {
+            if candidate.1 > best.1 {
+                candidate
+            } else {
+                best
+            }
+        })
+        .map(|(encoding, _)| encoding)
+}
+
+fn is_not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| etag_matches(value, etag))
+}
+
+// The packument as this deployment's transforms would serve it, or `None` when none of them
+// apply and the stored document can be streamed as it is.
+async fn transformed_packument<S>(
+    state: &S,
+    user: Option<&User>,
+    pkg: &PackageIdentifier,
+    abbreviated: bool,
+    full: bool,
+) -> Result<Option<Vec<u8>>, RegistryError>
 where
-    Storage: PolicyHolder + std::fmt::Debug,
+    S: PolicyHolder,
 {
-    put_packument(state, Path(pkg), payload).await
+    let served = ServedPackument {
+        name: pkg,
+        abbreviated,
+        user,
+        full,
+    };
+    if !state
+        .as_packument_transforms()
+        .iter()
+        .any(|transform| transform.applies_to(&served))
+    {
+        return Ok(None);
+    }
+
+    let storage = state.as_package_storage();
+    let stream = if abbreviated {
+        storage.stream_abbreviated_packument(pkg).await
+    } else {
+        storage.stream_packument(pkg).await
+    };
+    let data = collect_stream(stream.map_err(|e| storage_error(e, || package_not_found(pkg)))?)
+        .await
+        .map_err(|e| storage_error(e, || package_not_found(pkg)))?;
+
+    let mut document = serde_json::from_slice(data.as_slice()).map_err(RegistryError::internal)?;
+    transform_packument(state, &served, &mut document).await?;
+    serde_json::to_vec(&document)
+        .map(Some)
+        .map_err(RegistryError::internal)
 }
 
-#[instrument(level = "info", fields(pkg))]
-async fn put_scoped_packument<Storage>(
-    state: State<Storage>,
-    Path((scope, pkg)): Path<(String, String)>,
-    payload: Json<Packument>,
-) -> Result<impl IntoResponse, StatusCode>
+async fn transform_packument<S>(
+    state: &S,
+    served: &ServedPackument<'_>,
+    document: &mut serde_json::Value,
+) -> Result<(), RegistryError>
 where
-    Storage: PolicyHolder + std::fmt::Debug,
+    S: PolicyHolder,
 {
-    let pkg = format!("@{}/{}", scope, pkg);
-    put_packument(state, Path(pkg), payload).await
+    for transform in state.as_packument_transforms() {
+        if transform.applies_to(served) {
+            transform
+                .transform(served, document)
+                .await
+                .map_err(RegistryError::internal)?;
+        }
+    }
+    Ok(())
 }
 
-async fn get_scoped_packument<Storage>(
+// If-None-Match holds a list of tags (or "*"); weak comparison is fine for GET.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+#[instrument(level = "info", skip(payload), fields(pkg))]
+async fn put_packument<Storage>(
     State(state): State<Storage>,
-    Path((scope, pkg)): Path<(String, String)>,
-) -> Result<impl IntoResponse, StatusCode>
+    publisher: Publisher,
+    ClientIp(ip): ClientIp,
+    Path(pkg): Path<String>,
+    Json(payload): Json<Packument>,
+) -> Result<impl IntoResponse, RegistryError>
 where
-    Storage: PolicyHolder + std::fmt::Debug,
+    Storage: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
 {
-    let pkg = format!("@{}/{}", scope, pkg);
-    get_packument(State(state), Path(pkg)).await
+    if payload.id.as_deref() != Some(pkg.as_str()) {
+        return Err(RegistryError::bad_request(
+            "the document's _id does not match the package being updated",
+        ));
+    }
+
+    let pkg = parse_package(pkg.as_str())?;
+    let packument = fetch_or_start_packument(&state, &pkg).await?;
+
+    // A client that read an older revision would undo whatever changed since. Publishes
+    // carry no `_rev`, and apply to the current one.
+    if payload.rev.is_some() && payload.rev != packument.rev {
+        return Err(update_conflict());
+    }
+
+    let modification = PackageModification::from_diff(&packument, payload)
+        .map_err(|e| RegistryError::bad_request(e.to_string()))?;
+
+    modify_package(&state, &publisher, ip, &pkg, packument, modification).await
 }
 
-#[instrument(level = "info", fields(pkg, tarball))]
-async fn get_tarball<Storage>(
-    State(state): State<Storage>,
-    Path((pkg, tarball)): Path<(String, String)>,
-) -> Result<impl IntoResponse, StatusCode>
+// A package that doesn't exist yet starts from an empty packument; any other failure must
+// not be mistaken for one, or the write would clobber the stored document.
+async fn fetch_or_start_packument<S>(
+    state: &S,
+    pkg: &PackageIdentifier,
+) -> Result<Packument, RegistryError>
 where
-    Storage: PolicyHolder + std::fmt::Debug,
+    S: PolicyHolder,
+{
+    match state.as_package_storage().fetch_packument(pkg).await {
+        Ok(packument) => Ok(packument),
+        Err(e) if matches!(StorageError::of(&e), Some(StorageError::NotFound)) => Ok(Packument {
+            id: Some(pkg.to_string()),
+            name: Some(pkg.to_string()),
+            ..Default::default()
+        }),
+        Err(e) => Err(storage_error(e, || package_not_found(pkg))),
+    }
+}
+
+// Check that `publisher` may make `modification`, then apply it and store the result.
+async fn modify_package<S>(
+    state: &S,
+    publisher: &Publisher,
+    ip: Option<String>,
+    pkg: &PackageIdentifier,
+    mut packument: Packument,
+    mut modification: PackageModification,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static,
 {
-    let pkg: PackageIdentifier = pkg.parse().unwrap();
-    if !tarball.starts_with(pkg.name.as_str())
-        || tarball.get(pkg.name.len()..pkg.name.len() + 1) != Some("-")
-        || !tarball.ends_with(".tgz")
+    let user = &publisher.user;
+    if publisher.preview {
+        check_preview_publish(state, pkg, &modification)?;
+    }
+
+    match modification {
+        PackageModification::AddStar(ref stargazer)
+        | PackageModification::RemoveStar(ref stargazer) => {
+            if *stargazer != user.name {
+                return Err(RegistryError::forbidden(
+                    "you may only star or unstar packages as yourself",
+                ));
+            }
+        }
+        PackageModification::Deprecate(_) | PackageModification::UpdateMetadata(_) => {
+            if !can_manage_access(state, user, pkg).await? {
+                return Err(cannot_modify(pkg));
+            }
+        }
+        // `npm owner add` and `npm owner rm`. Only a maintainer may change who maintains a
+        // package; a grant to write to it isn't enough.
+        PackageModification::AddMaintainer {
+            ref name,
+            ref mut email,
+        } => {
+            if !packument.is_maintainer(user.name.as_str()) {
+                return Err(cannot_change_owners(pkg));
+            }
+            let Ok(maintainer) = state.as_user_storage().get_user(name).await else {
+                return Err(user_not_found(name));
+            };
+            *email = Some(maintainer.email);
+        }
+        PackageModification::RemoveMaintainer(_) => {
+            if !packument.is_maintainer(user.name.as_str()) {
+                return Err(cannot_change_owners(pkg));
+            }
+        }
+        PackageModification::AddVersion {
+            ref tag,
+            ref version,
+            ..
+        } => {
+            // Anyone may publish a package that doesn't exist yet; after that, only those who
+            // may modify it. The preview scope is open to every preview token.
+            if packument.versions.is_some()
+                && !publisher.preview
+                && !can_manage_access(state, user, pkg).await?
+            {
+                return Err(cannot_modify(pkg));
+            }
+            if let Some(number) = version.meta.get("version").and_then(|v| v.as_str()) {
+                check_dist_tag_policy(state, pkg, tag, number).await?;
+                check_unpublish_window(state, pkg, &packument, number)?;
+            }
+            check_platform_variants(state, version).await?;
+            check_publish_location(state, pkg, ip.as_deref()).await?;
+            if packument.maintainers.is_none() {
+                packument.maintainers = Some(vec![Maintainer::Object(MaintainerObject {
+                    name: Some(user.name.clone()),
+                    email: Some(user.email.clone()),
+                    url: None,
+                })]);
+            }
+        }
+        PackageModification::AddTag {
+            ref tag,
+            ref version,
+        } => {
+            if !can_manage_access(state, user, pkg).await? {
+                return Err(cannot_modify(pkg));
+            }
+            check_dist_tag_policy(state, pkg, tag, version).await?;
+            let manifest = packument
+                .versions
+                .as_ref()
+                .and_then(|versions| versions.get(version));
+            if let Some(manifest) = manifest {
+                check_platform_variants(state, manifest).await?;
+            }
+        }
+        PackageModification::RemoveTag { .. } | PackageModification::RemoveVersions(_) => {
+            if !can_manage_access(state, user, pkg).await? {
+                return Err(cannot_modify(pkg));
+            }
+        }
+    }
+
+    if let PackageModification::AddVersion {
+        ref mut version,
+        ref provenance,
+        ..
+    } = modification
     {
-        return Err(StatusCode::BAD_REQUEST);
+        attach_provenance(state, pkg, version, provenance.as_ref());
+        sign_version(state, pkg, version)?;
+    }
+
+    let (tarball, provenance) = match modification {
+        PackageModification::AddVersion {
+            ref version,
+            ref mut tarball,
+            ref mut provenance,
+            ..
+        } => {
+            let number = version
+                .meta
+                .get("version")
+                .and_then(|v| v.as_str())
+                .map(str::to_string);
+            (
+                number.clone().zip(tarball.take()),
+                number.zip(provenance.take()),
+            )
+        }
+        _ => (None, None),
+    };
+
+    let unpublished = match modification {
+        PackageModification::RemoveVersions(ref versions) => versions.clone(),
+        _ => Vec::new(),
+    };
+
+    let event = hook_event(pkg, &modification);
+    let history = package_event(pkg, &modification, user, ip.clone());
+    if let Err(e) = packument.apply(modification) {
+        return Err(RegistryError::bad_request(e.to_string()));
     }
 
-    let version = tarball.get(pkg.name.len() + 1..tarball.len() - 4).unwrap();
+    // Stored once the document has accepted the version, and before the document itself,
+    // so that a version is never visible without its tarball.
+    if let Some((version, tarball)) = tarball {
+        state
+            .as_package_storage()
+            .put_tarball(pkg, version.as_str(), tarball.into())
+            .await
+            .map_err(RegistryError::internal)?;
+    }
+    if let Some((version, provenance)) = provenance {
+        state
+            .as_package_storage()
+            .put_attestations(pkg, version.as_str(), &provenance.document())
+            .await
+            .map_err(RegistryError::internal)?;
+    }
 
-    let stream = state
+    state
         .as_package_storage()
-        .stream_tarball(&pkg, version)
+        .update_packument(pkg, &mut packument)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|e| storage_error(e, || package_not_found(pkg)))?;
+
+    if let Some(history) = history {
+        record_package_event(state, history).await;
+    }
+
+    // Deleted after the document, which no longer lists them, so that no version is ever
+    // visible without its tarball.
+    for number in &unpublished {
+        if let Err(e) = state.as_package_storage().delete_tarball(pkg, number).await {
+            tracing::warn!(error = ?e, package = %pkg, version = number, "could not delete unpublished tarball");
+        }
+        record_package_event(
+            state,
+            PackageEvent {
+                package: pkg.to_string(),
+                action: "package.unpublish".to_string(),
+                actor: user.name.clone(),
+                version: Some(number.clone()),
+                tag: None,
+                ip: ip.clone(),
+                country: None,
+                time: Utc::now(),
+            },
+        )
+        .await;
+    }
 
-    Ok(StreamBody::new(stream))
+    announce(state, event, &packument);
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "ok": true,
+            "id": pkg.to_string(),
+            "rev": packument.rev
+        })),
+    ))
 }
 
-async fn get_scoped_tarball<Storage>(
-    State(state): State<Storage>,
-    Path((scope, pkg, tarball)): Path<(String, String, String)>,
-) -> Result<impl IntoResponse, StatusCode>
+// Like the signatures, `dist.attestations` is ours to set: only a bundle that checked out
+// against the tarball gets one.
+fn attach_provenance<S>(
+    state: &S,
+    pkg: &PackageIdentifier,
+    version: &mut PackumentVersion,
+    provenance: Option<&Provenance>,
+) where
+    S: PolicyHolder,
+{
+    let number = version.meta.get("version").and_then(|v| v.as_str());
+    version.dist.attestations = provenance
+        .zip(number)
+        .map(|(provenance, number)| Attestations {
+            url: pkg.attestations_url(state.as_configurator().fqdn(), number),
+            provenance: provenance.summary(),
+        });
+}
+
+// Whatever signatures the publisher sent are dropped: only this registry's keys vouch for
+// what it serves. Without an active key, or an integrity to sign, the version goes unsigned.
+fn sign_version<S>(
+    state: &S,
+    pkg: &PackageIdentifier,
+    version: &mut PackumentVersion,
+) -> Result<(), RegistryError>
 where
-    Storage: PolicyHolder + std::fmt::Debug,
+    S: PolicyHolder,
 {
-    let pkg = format!("@{}/{}", scope, pkg);
-    get_tarball(State(state), Path((pkg, tarball))).await
+    version.dist.signatures = None;
+    let Some(key) = active_key(state.as_configurator().signing_keys(), Utc::now()) else {
+        return Ok(());
+    };
+    version.dist.signatures = version_signature(key, pkg, version)
+        .map_err(RegistryError::internal)?
+        .map(|signature| vec![signature]);
+    Ok(())
 }
 
-#[instrument]
-async fn get_login_poll<Auth>(
-    State(state): State<Auth>,
-    Path(session): Path<String>,
-) -> impl IntoResponse
+fn version_signature(
+    key: &SigningKey,
+    pkg: &PackageIdentifier,
+    version: &PackumentVersion,
+) -> Result<Option<Signature>, SigningError> {
+    let number = version.meta.get("version").and_then(|v| v.as_str());
+    let (Some(number), Some(integrity)) = (number, version.dist.integrity.as_deref()) else {
+        return Ok(None);
+    };
+
+    let sig = key.sign_version(pkg.to_string().as_str(), number, integrity)?;
+    Ok(Some(Signature {
+        keyid: key.key_id(),
+        sig,
+    }))
+}
+
+// Tell watchers and the package's webhooks about `event`. Webhooks are sent in the background.
+fn announce<S>(state: &S, event: HookEvent, packument: &Packument)
 where
-    Auth: PolicyHolder + std::fmt::Debug,
+    S: PolicyHolder + Clone + Send + Sync + 'static,
 {
-    let Ok(session) = session.parse::<<Auth::Authenticator as Authenticator>::SessionId>() else {
-        todo!();
+    state.as_events().publish(event.clone());
+    let owners = packument.maintainer_names();
+    let dispatcher = state.clone();
+    state.as_tasks().spawn("webhooks:dispatch", async move {
+        dispatcher
+            .as_webhooks()
+            .dispatch(event, owners.as_slice())
+            .await
+            .map(|_| ())
+    });
+}
+
+fn hook_event(pkg: &PackageIdentifier, modification: &PackageModification) -> HookEvent {
+    let (event, change) = match modification {
+        PackageModification::AddStar(user) => ("package:star", json!({ "user": user })),
+        PackageModification::RemoveStar(user) => ("package:unstar", json!({ "user": user })),
+        PackageModification::AddTag { tag, version } => (
+            "package:dist-tag",
+            json!({ "dist-tag": tag, "version": version }),
+        ),
+        PackageModification::RemoveTag { tag } => {
+            ("package:dist-tag-rm", json!({ "dist-tag": tag }))
+        }
+        PackageModification::Deprecate(versions) => {
+            ("package:deprecate", json!({ "versions": versions }))
+        }
+        PackageModification::UpdateMetadata(update) => {
+            ("package:change", json!({ "fields": update.fields() }))
+        }
+        PackageModification::AddVersion { tag, version, .. } => (
+            "package:publish",
+            json!({ "dist-tag": tag, "version": version.meta.get("version") }),
+        ),
+        PackageModification::RemoveVersions(versions) => {
+            ("package:unpublish", json!({ "versions": versions }))
+        }
+        PackageModification::AddMaintainer { name, .. } => {
+            ("package:owner", json!({ "maintainer": name }))
+        }
+        PackageModification::RemoveMaintainer(name) => {
+            ("package:owner-rm", json!({ "maintainer": name }))
+        }
     };
 
-    let Ok(user) = state.as_authenticator().poll_login_session(session).await else {
-        todo!();
+    HookEvent::new(event, pkg).with_change(change)
+}
+
+// How a modification appears in the package's history. Stars and owner changes aren't part
+// of it.
+fn package_event(
+    pkg: &PackageIdentifier,
+    modification: &PackageModification,
+    actor: &User,
+    ip: Option<String>,
+) -> Option<PackageEvent> {
+    let (action, version, tag) = match modification {
+        PackageModification::AddVersion { tag, version, .. } => (
+            "package.publish",
+            version
+                .meta
+                .get("version")
+                .and_then(|v| v.as_str())
+                .map(str::to_string),
+            Some(tag.clone()),
+        ),
+        PackageModification::AddTag { tag, version } => (
+            "package.dist-tag.add",
+            Some(version.clone()),
+            Some(tag.clone()),
+        ),
+        PackageModification::RemoveTag { tag } => ("package.dist-tag.rm", None, Some(tag.clone())),
+        PackageModification::Deprecate(_) => ("package.deprecate", None, None),
+        PackageModification::UpdateMetadata(_) => ("package.metadata", None, None),
+        // Each unpublished version is recorded once its tarball is gone.
+        PackageModification::AddStar(_)
+        | PackageModification::RemoveStar(_)
+        | PackageModification::AddMaintainer { .. }
+        | PackageModification::RemoveMaintainer(_)
+        | PackageModification::RemoveVersions(_) => return None,
     };
 
-    if let Some(user) = user {
-        // TODO: this is the point at which we add them to UserStorage -- which is where
-        // we may wish to apply WASM-based filtering of incoming users.
-        let Ok(token) = state.as_token_authorizer().start_session(user.into()).await else {
-            todo!();
-        };
+    Some(PackageEvent {
+        package: pkg.to_string(),
+        action: action.to_string(),
+        actor: actor.name.clone(),
+        version,
+        tag,
+        ip,
+        country: None,
+        time: Utc::now(),
+    })
+}
 
-        (
-            StatusCode::OK,
-            [("x-ok", "x-ok")],
-            Json(serde_json::json!({
-                "message": "ok",
-                "token": token.to_string()
-            })),
-        )
-    } else {
-        (
-            StatusCode::ACCEPTED,
-            [("retry-after", "5")],
-            Json(serde_json::json!({
-                "message": "ok"
-            })),
-        )
+// A change that can't be added to the history still stands; the audit log has it either way.
+async fn record_package_event<S>(state: &S, mut event: PackageEvent)
+where
+    S: PolicyHolder,
+{
+    event.country = locate(state, event.ip.as_deref())
+        .await
+        .and_then(|location| location.country);
+    tracing::info!(
+        target: "audit",
+        action = event.action,
+        package = event.package,
+        by = event.actor,
+        version = event.version,
+        tag = event.tag,
+        ip = event.ip,
+        country = event.country
+    );
+    if let Err(e) = state.as_access_control().record_event(event).await {
+        tracing::warn!(error = ?e, "could not record package history");
     }
 }
 
-#[instrument]
-async fn post_login<Auth, B>(
-    State(state): State<Auth>,
-    req: Request<B>,
-) -> Result<impl IntoResponse, impl IntoResponse>
+// Geolocation only enriches: an address that can't be parsed or located goes without.
+async fn locate<S>(state: &S, ip: Option<&str>) -> Option<Location>
 where
-    Auth: PolicyHolder + std::fmt::Debug,
-    B: std::fmt::Debug + Into<axum::body::Body>,
+    S: PolicyHolder,
 {
-    let fqdn = state.as_configurator().fqdn();
+    let ip = ip?.parse().ok()?;
+    match state.as_geolocator().locate(ip).await {
+        Ok(location) => location,
+        Err(e) => {
+            tracing::warn!(error = ?e, %ip, "could not geolocate client");
+            None
+        }
+    }
+}
 
-    let (parts, body) = req.into_parts();
-    let req = Request::from_parts(parts, body.into());
-    let Ok(id) = state.as_authenticator().start_login_session(req).await else {
-        return Err(StatusCode::BAD_REQUEST)
+// Preview tokens publish prereleases under the preview scope, and nothing else: not to other
+// scopes, not to `latest`, and no changes besides publishes.
+fn check_preview_publish<S>(
+    state: &S,
+    pkg: &PackageIdentifier,
+    modification: &PackageModification,
+) -> Result<(), RegistryError>
+where
+    S: PolicyHolder,
+{
+    let Some(scope) = state.as_configurator().preview_scope() else {
+        return Err(RegistryError::forbidden(
+            "this registry does not accept preview publishes",
+        ));
     };
+    if pkg.scope.as_deref() != Some(scope) {
+        return Err(RegistryError::forbidden(format!(
+            "preview tokens may only publish to @{}",
+            scope
+        )));
+    }
 
-    Ok(Json(json!({
-        "doneUrl": format!("{}/-/v1/login/poll/{}", fqdn, id),
-        "loginUrl": format!("{}/-/v1/login/www/{}", fqdn, id)
-    })))
+    let PackageModification::AddVersion {
+        ref tag,
+        ref version,
+        ..
+    } = *modification
+    else {
+        return Err(RegistryError::forbidden(
+            "preview tokens may only publish new versions",
+        ));
+    };
+    let prerelease = version
+        .meta
+        .get("version")
+        .and_then(|number| number.as_str())
+        .and_then(|number| semver::Version::parse(number).ok())
+        .is_some_and(|number| !number.pre.is_empty());
+    if !prerelease {
+        return Err(RegistryError::forbidden(
+            "preview tokens may only publish prerelease versions",
+        ));
+    }
+    if tag == "latest" {
+        return Err(RegistryError::forbidden(
+            "preview versions must be published with a --tag other than latest",
+        ));
+    }
+    Ok(())
 }
 
-#[instrument]
-async fn www_login<Auth, B>(
-    State(state): State<Auth>,
-    session: Option<Path<String>>,
-    req: Request<B>,
-) -> impl IntoResponse
+// Private and unknown addresses can't be placed, so they're let through; the allowlist is
+// meant to catch stolen tokens used from abroad, not to stand in for network policy.
+async fn check_publish_location<S>(
+    state: &S,
+    pkg: &PackageIdentifier,
+    ip: Option<&str>,
+) -> Result<(), RegistryError>
 where
-    Auth: PolicyHolder + std::fmt::Debug,
-    B: std::fmt::Debug + Into<axum::body::Body>,
+    S: PolicyHolder,
 {
-    let (parts, body) = req.into_parts();
-    let req = Request::from_parts(parts, body.into());
+    let allowed = state.as_configurator().publish_countries();
+    if allowed.is_empty() {
+        return Ok(());
+    }
 
-    let session = if let Some(Path(session)) = session {
-        let Ok(session) = session.parse::<<Auth::Authenticator as Authenticator>::SessionId>() else {
-            todo!("invalid session id, bailing...");
-        };
-        Some(session)
-    } else {
-        None
+    let Some(country) = locate(state, ip)
+        .await
+        .and_then(|location| location.country)
+    else {
+        return Ok(());
+    };
+    if allowed.contains(&country) {
+        return Ok(());
+    }
+
+    tracing::warn!(
+        target: "audit",
+        action = "package.publish.blocked",
+        package = pkg.to_string(),
+        ip,
+        country
+    );
+    Err(RegistryError::forbidden(format!(
+        "publishes from {} are not accepted by this registry",
+        country
+    )))
+}
+
+// A name that was just unpublished is held for a while, so that nobody else can take it
+// over and publish the versions its dependents were installing. Until the window passes
+// nothing may be published to it, new version numbers included; after that only new ones
+// may be, since a version number once published always names the same tarball.
+fn check_unpublish_window<S>(
+    state: &S,
+    pkg: &PackageIdentifier,
+    packument: &Packument,
+    number: &str,
+) -> Result<(), RegistryError>
+where
+    S: PolicyHolder,
+{
+    if packument.was_unpublished(number) {
+        return Err(RegistryError::forbidden(format!(
+            "{}@{} was unpublished, and its version number cannot be used again",
+            pkg, number
+        )));
+    }
+
+    let Some(unpublished) = packument
+        .time
+        .as_ref()
+        .and_then(|time| time.unpublished.as_ref())
+    else {
+        return Ok(());
     };
 
-    let Ok(result) = state.as_authenticator().complete_login_session(
-        state.as_configurator(),
-        state.as_user_storage(),
-        req,
-        session
-    ).await.map_err(|e| dbg!(e)) else {
-        todo!("could not complete login session...");
+    let window = chrono::Duration::from_std(state.as_configurator().unpublish_window())
+        .map_err(RegistryError::internal)?;
+    let until = unpublished.time + window;
+    if Utc::now() >= until {
+        return Ok(());
+    }
+
+    Err(RegistryError::forbidden(format!(
+        "{} was unpublished at {} and cannot be published again until {}",
+        pkg,
+        unpublished.time.to_rfc3339(),
+        until.to_rfc3339()
+    ))
+    .with_detail("unpublished", json!(unpublished)))
+}
+
+// Scoped packages follow their org's dist-tag policy, if it has one.
+async fn check_dist_tag_policy<S>(
+    state: &S,
+    pkg: &PackageIdentifier,
+    tag: &str,
+    version: &str,
+) -> Result<(), RegistryError>
+where
+    S: PolicyHolder,
+{
+    let Some(ref org) = pkg.scope else {
+        return Ok(());
     };
 
-    result
+    let policy = state
+        .as_user_storage()
+        .org_dist_tag_policy(org.as_str())
+        .await
+        .map_err(RegistryError::internal)?;
+
+    match policy.map(|policy| policy.check(tag, version)) {
+        Some(Err(e)) => Err(RegistryError::bad_request(e.to_string())),
+        _ => Ok(()),
+    }
 }
 
-#[instrument]
-async fn get_user<Auth>(
-    State(state): State<Auth>,
-    Path(user): Path<String>,
-) -> Result<impl IntoResponse, StatusCode>
+// The manifest `variant` was published with at exactly `number`, if it has been.
+async fn published_variant<S>(
+    state: &S,
+    variant: &PackageIdentifier,
+    number: &str,
+) -> Result<Option<PackumentVersion>, RegistryError>
 where
-    Auth: PolicyHolder + std::fmt::Debug,
+    S: PolicyHolder,
 {
-    let Some(username) = user.strip_prefix(':') else {
-        return Err(StatusCode::NOT_FOUND);
+    match state
+        .as_package_storage()
+        .resolve_version(variant, number)
+        .await
+    {
+        Ok(manifest) => Ok(manifest.filter(|manifest| {
+            manifest.meta.get("version").and_then(|v| v.as_str()) == Some(number)
+        })),
+        Err(e) if matches!(StorageError::of(&e), Some(StorageError::NotFound)) => Ok(None),
+        Err(e) => Err(storage_error(e, || package_not_found(variant))),
+    }
+}
+
+// A version that declares platform variants can't be tagged (and so installed) until each
+// variant has been published at the same version; installs on the missing platforms would
+// fail to find their binaries.
+async fn check_platform_variants<S>(
+    state: &S,
+    version: &PackumentVersion,
+) -> Result<(), RegistryError>
+where
+    S: PolicyHolder,
+{
+    let variants =
+        platform_variants(version).map_err(|e| RegistryError::bad_request(e.to_string()))?;
+    let Some(number) = version.meta.get("version").and_then(|v| v.as_str()) else {
+        return Ok(());
     };
 
-    let Ok(user) = state.as_user_storage().get_user(username).await else {
-        return Err(StatusCode::NOT_FOUND);
+    let mut missing = Vec::new();
+    for (platform, variant) in variants {
+        if published_variant(state, &variant, number).await?.is_none() {
+            missing.push(format!("{} ({}@{})", platform, variant, number));
+        }
+    }
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(RegistryError::bad_request(format!(
+            "publish every platform variant before tagging {}; missing {}",
+            number,
+            missing.join(", ")
+        )))
+    }
+}
+
+fn dist_tags_json(packument: &Packument) -> BTreeMap<String, String> {
+    let Some(ref dist_tags) = packument.dist_tags else {
+        return BTreeMap::new();
     };
 
-    // TODO: "fetch user" capability
-    Ok(Json(json!({
-        "_id": format!("org.couchdb.user:{}", user.name),
-        "name": user.name,
-        "email": ""
-    })))
+    let mut tags = dist_tags.tags.clone();
+    if let Some(ref latest) = dist_tags.latest {
+        tags.insert("latest".to_string(), latest.clone());
+    }
+    tags
 }
 
 #[instrument]
-async fn whoami(Authenticated(user): Authenticated) -> impl IntoResponse {
-    Json(json!({
-        "username": user.name
-    }))
+async fn get_dist_tags<S>(
+    State(state): State<S>,
+    user: Option<Authenticated>,
+    Path(pkg): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+
+    if !can_install(&state, user.as_ref().map(|user| &user.0), &pkg).await? {
+        return Err(package_not_found(&pkg));
+    }
+
+    let packument = state
+        .as_package_storage()
+        .fetch_packument(&pkg)
+        .await
+        .map_err(|e| storage_error(e, || package_not_found(&pkg)))?;
+
+    Ok(Json(dist_tags_json(&packument)))
 }
 
-pub fn routes<S, B>(state: S) -> Router<(), B>
+// `npm dist-tag add` and `rm` both land here; they differ only in the modification.
+async fn modify_dist_tag<S>(
+    state: S,
+    user: User,
+    ip: Option<String>,
+    pkg: String,
+    modification: PackageModification,
+) -> Result<impl IntoResponse, RegistryError>
 where
-    S: PolicyHolder + Clone + Sync + Send + 'static + std::fmt::Debug,
-    B: Sync + Send + HttpBody + std::fmt::Debug + Into<Body> + 'static,
-    <B as HttpBody>::Data: 'static + Send + Sync,
-    <B as HttpBody>::Error: std::error::Error + 'static + Send + Sync,
+    S: PolicyHolder + Clone + Send + Sync + 'static,
 {
-    Router::new()
-        .route("/@:scope/:pkg/-/*tarball", get(get_scoped_tarball::<S>))
-        .route(
-            "/@:scope/:pkg",
-            get(get_scoped_packument::<S>)
-                .layer(ServiceBuilder::new().layer(CompressionLayer::new()))
-                .put(put_scoped_packument::<S>),
-        )
-        .route(
-            "/:pkg",
-            get(get_packument::<S>)
-                .layer(ServiceBuilder::new().layer(CompressionLayer::new()))
-                .put(put_packument::<S>),
-        )
-        .route("/:pkg/-rev/:rev", put(put_packument_at_rev::<S>))
-        .route("/:pkg/-/*tarball", get(get_tarball::<S>))
-        .route("/-/v1/login", post(post_login::<S, B>))
-        .route("/-/v1/login/poll/:session", get(get_login_poll::<S>))
-        .route("/-/v1/login/www/:session", any(www_login::<S, B>))
-        .route("/-/v1/login/www/", any(www_login::<S, B>))
-        // .route("/-/v1/npm/tokens", get(get_tokens::<S>))
-        .route("/-/user/org.couchdb.user:user", get(get_user::<S>))
-        .route("/-/whoami", get(whoami))
-        .with_state(state)
+    let pkg = parse_package(pkg.as_str())?;
+
+    if !can_manage_access(&state, &user, &pkg).await? {
+        return Err(cannot_modify(&pkg));
+    }
+
+    if let PackageModification::AddTag {
+        ref tag,
+        ref version,
+    } = modification
+    {
+        check_dist_tag_policy(&state, &pkg, tag, version).await?;
+    }
+
+    let mut packument = state
+        .as_package_storage()
+        .fetch_packument(&pkg)
+        .await
+        .map_err(|e| storage_error(e, || package_not_found(&pkg)))?;
+
+    if let PackageModification::AddTag { ref version, .. } = modification {
+        let manifest = packument
+            .versions
+            .as_ref()
+            .and_then(|versions| versions.get(version));
+        if let Some(manifest) = manifest {
+            check_platform_variants(&state, manifest).await?;
+        }
+    }
+
+    let event = hook_event(&pkg, &modification);
+    let history = package_event(&pkg, &modification, &user, ip);
+    if let Err(e) = packument.apply(modification) {
+        return Err(RegistryError::bad_request(e.to_string()));
+    }
+
+    state
+        .as_package_storage()
+        .update_packument(&pkg, &mut packument)
+        .await
+        .map_err(|e| storage_error(e, || package_not_found(&pkg)))?;
+
+    if let Some(history) = history {
+        record_package_event(&state, history).await;
+    }
+
+    announce(&state, event, &packument);
+
+    Ok(Json(dist_tags_json(&packument)))
+}
+
+#[instrument]
+async fn put_dist_tag<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    ClientIp(ip): ClientIp,
+    Path((pkg, tag)): Path<(String, String)>,
+    Json(version): Json<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    let modification = PackageModification::AddTag { tag, version };
+    modify_dist_tag(state, user, ip, pkg, modification).await
+}
+
+#[instrument]
+async fn delete_dist_tag<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    ClientIp(ip): ClientIp,
+    Path((pkg, tag)): Path<(String, String)>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    modify_dist_tag(state, user, ip, pkg, PackageModification::RemoveTag { tag }).await
+}
+
+#[derive(Deserialize, Debug)]
+struct MetadataPatch {
+    #[serde(rename = "_rev", default)]
+    rev: Option<String>,
+    #[serde(flatten)]
+    update: MetadataUpdate,
+}
+
+/// Correct a package's description, keywords, readme or homepage, or (un)deprecate some of
+/// its versions, without a republish. A `_rev`, if given, must be the current revision.
+#[instrument(skip(state, patch))]
+async fn patch_package_metadata<S>(
+    State(state): State<S>,
+    publisher: Publisher,
+    ClientIp(ip): ClientIp,
+    Path(pkg): Path<String>,
+    Json(patch): Json<MetadataPatch>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+    if patch.update.is_empty() {
+        return Err(RegistryError::bad_request("nothing to change"));
+    }
+
+    let packument = state
+        .as_package_storage()
+        .fetch_packument(&pkg)
+        .await
+        .map_err(|e| storage_error(e, || package_not_found(&pkg)))?;
+    if patch.rev.is_some() && patch.rev != packument.rev {
+        return Err(update_conflict());
+    }
+
+    let modification = PackageModification::UpdateMetadata(patch.update);
+    modify_package(&state, &publisher, ip, &pkg, packument, modification).await
+}
+
+#[derive(Deserialize, Debug)]
+struct PublishQuery {
+    tag: Option<String>,
+}
+
+/// Publish a bare tarball, sent as the request body, as the version its `package.json`
+/// names. Unlike `npm publish`'s document nothing is base64-encoded, so the tarball is the
+/// only copy held in memory.
+#[instrument(skip(request))]
+async fn put_package_tarball<S, B>(
+    State(state): State<S>,
+    publisher: Publisher,
+    ClientIp(ip): ClientIp,
+    Path(pkg): Path<String>,
+    Query(query): Query<PublishQuery>,
+    request: Request<B>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+    B: Into<Body>,
+{
+    let pkg = parse_package(pkg.as_str())?;
+    let tarball = read_upload(request.into_body().into()).await?;
+
+    let packument = fetch_or_start_packument(&state, &pkg).await?;
+    let modification = PackageModification::from_tarball(
+        &pkg,
+        query.tag.unwrap_or_else(|| "latest".to_string()),
+        state.as_configurator().fqdn(),
+        tarball,
+    )
+    .map_err(|e| RegistryError::bad_request(e.to_string()))?;
+
+    modify_package(&state, &publisher, ip, &pkg, packument, modification).await
+}
+
+// Refused as soon as it passes PUBLISH_LIMIT, rather than once it's all been read.
+async fn read_upload(mut body: Body) -> Result<Vec<u8>, RegistryError> {
+    let mut upload = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk =
+            chunk.map_err(|_| RegistryError::bad_request("could not read the request body"))?;
+        if upload.len() + chunk.len() > PUBLISH_LIMIT {
+            return Err(RegistryError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("tarballs may be at most {} bytes", PUBLISH_LIMIT),
+            ));
+        }
+        upload.extend_from_slice(chunk.as_ref());
+    }
+    Ok(upload)
+}
+
+#[instrument(level = "info", skip(payload), fields(pkg))]
+async fn put_packument_at_rev<Storage>(
+    state: State<Storage>,
+    publisher: Publisher,
+    ip: ClientIp,
+    Path((pkg, rev)): Path<(String, String)>,
+    Json(mut payload): Json<Packument>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Storage: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    payload.rev.get_or_insert(rev);
+    put_packument(state, publisher, ip, Path(pkg), Json(payload)).await
+}
+
+#[instrument(level = "info", skip(payload), fields(pkg))]
+async fn put_scoped_packument<Storage>(
+    state: State<Storage>,
+    publisher: Publisher,
+    ip: ClientIp,
+    Path((scope, pkg)): Path<(String, String)>,
+    payload: Json<Packument>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Storage: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    let pkg = format!("@{}/{}", scope, pkg);
+    put_packument(state, publisher, ip, Path(pkg), payload).await
+}
+
+#[instrument(level = "info", skip(payload), fields(pkg))]
+async fn put_scoped_packument_at_rev<Storage>(
+    state: State<Storage>,
+    publisher: Publisher,
+    ip: ClientIp,
+    Path((scope, pkg, rev)): Path<(String, String, String)>,
+    payload: Json<Packument>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Storage: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    let pkg = format!("@{}/{}", scope, pkg);
+    put_packument_at_rev(state, publisher, ip, Path((pkg, rev)), payload).await
+}
+
+/// `npm unpublish --force`: replace the package with a tombstone and delete its tarballs.
+/// The name can't be published to again until the unpublish window has passed.
+#[instrument(level = "info", skip(state), fields(pkg))]
+async fn delete_packument_at_rev<S>(
+    State(state): State<S>,
+    publisher: Publisher,
+    ClientIp(ip): ClientIp,
+    Path((pkg, rev)): Path<(String, String)>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+    if publisher.preview || !can_manage_access(&state, &publisher.user, &pkg).await? {
+        return Err(cannot_modify(&pkg));
+    }
+
+    let storage = state.as_package_storage();
+    let mut packument = storage
+        .fetch_packument(&pkg)
+        .await
+        .map_err(|e| storage_error(e, || package_not_found(&pkg)))?;
+    if packument.rev.as_deref() != Some(rev.as_str()) {
+        return Err(update_conflict());
+    }
+
+    // Kept for the webhooks, which are sent to the owners the tombstone no longer lists.
+    let owners = Packument {
+        maintainers: packument.maintainers.clone(),
+        ..Default::default()
+    };
+    let versions = packument
+        .unpublish()
+        .map_err(|e| RegistryError::bad_request(e.to_string()))?;
+    storage
+        .update_packument(&pkg, &mut packument)
+        .await
+        .map_err(|e| storage_error(e, || package_not_found(&pkg)))?;
+
+    for number in &versions {
+        if let Err(e) = storage.delete_tarball(&pkg, number).await {
+            tracing::warn!(error = ?e, package = %pkg, version = number, "could not delete unpublished tarball");
+        }
+    }
+
+    record_package_event(
+        &state,
+        PackageEvent {
+            package: pkg.to_string(),
+            action: "package.unpublish".to_string(),
+            actor: publisher.user.name.clone(),
+            version: None,
+            tag: None,
+            ip,
+            country: None,
+            time: Utc::now(),
+        },
+    )
+    .await;
+
+    let event =
+        HookEvent::new("package:unpublish", &pkg).with_change(json!({ "versions": versions }));
+    announce(&state, event, &owners);
+
+    Ok(Json(json!({
+        "ok": true,
+        "id": pkg.to_string(),
+        "rev": packument.rev
+    })))
+}
+
+#[instrument(level = "info", skip(state), fields(pkg))]
+async fn delete_scoped_packument_at_rev<S>(
+    state: State<S>,
+    publisher: Publisher,
+    ip: ClientIp,
+    Path((scope, pkg, rev)): Path<(String, String, String)>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    let pkg = format!("@{}/{}", scope, pkg);
+    delete_packument_at_rev(state, publisher, ip, Path((pkg, rev))).await
+}
+
+#[derive(Deserialize, Debug)]
+struct ViewQuery {
+    key: Option<String>,
+}
+
+#[instrument]
+async fn get_starred_by_user<Storage>(
+    State(state): State<Storage>,
+    Query(query): Query<ViewQuery>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Storage: PolicyHolder + std::fmt::Debug,
+{
+    let Some(key) = query.key else {
+        return Err(RegistryError::bad_request("a key is required"));
+    };
+
+    // CouchDB view keys are JSON-encoded; be lenient and accept a bare username too.
+    let username = serde_json::from_str::<String>(key.as_str()).unwrap_or(key);
+
+    let starred = state
+        .as_package_storage()
+        .starred_by(username.as_str())
+        .await
+        .map_err(RegistryError::internal)?;
+
+    Ok(Json(json!({
+        "rows": starred
+            .into_iter()
+            .map(|name| json!({ "key": username, "value": name }))
+            .collect::<Vec<_>>()
+    })))
+}
+
+async fn get_scoped_packument<Storage>(
+    State(state): State<Storage>,
+    user: Option<Authenticated>,
+    headers: HeaderMap,
+    Path((scope, pkg)): Path<(String, String)>,
+    query: Query<PackumentQuery>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Storage: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = format!("@{}/{}", scope, pkg);
+    get_packument(State(state), user, headers, Path(pkg), query).await
+}
+
+/// A single version manifest. `version` may be an exact version, a dist-tag, or a semver
+/// range, which resolves to the highest satisfying version.
+#[instrument(level = "info", fields(pkg, version))]
+async fn get_version_manifest<Storage>(
+    State(state): State<Storage>,
+    user: Option<Authenticated>,
+    Path((pkg, version)): Path<(String, String)>,
+) -> Result<Response, RegistryError>
+where
+    Storage: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+
+    if !can_install(&state, user.as_ref().map(|user| &user.0), &pkg).await? {
+        return Err(package_not_found(&pkg));
+    }
+
+    let Some(manifest) = state
+        .as_package_storage()
+        .resolve_version(&pkg, version.as_str())
+        .await
+        .map_err(|e| storage_error(e, || package_not_found(&pkg)))?
+    else {
+        return Err(version_not_found(&pkg, version.as_str()));
+    };
+
+    // Checked once resolved, so that a quarantined version can't be reached through a tag.
+    let number = manifest.meta.get("version").and_then(|v| v.as_str());
+    check_quarantine(&state, &pkg, Some(number.unwrap_or(version.as_str()))).await?;
+
+    Ok(Json(manifest).into_response())
+}
+
+async fn get_scoped_version_manifest<Storage>(
+    State(state): State<Storage>,
+    user: Option<Authenticated>,
+    Path((scope, pkg, version)): Path<(String, String, String)>,
+) -> Result<Response, RegistryError>
+where
+    Storage: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = format!("@{}/{}", scope, pkg);
+    get_version_manifest(State(state), user, Path((pkg, version))).await
+}
+
+#[instrument(level = "info", skip(headers), fields(pkg, tarball))]
+async fn get_tarball<Storage>(
+    State(state): State<Storage>,
+    user: Option<Authenticated>,
+    headers: HeaderMap,
+    Path((pkg, tarball)): Path<(String, String)>,
+) -> Result<Response, RegistryError>
+where
+    Storage: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+    let Some(version) = tarball_version(&pkg, tarball.as_str()) else {
+        return Err(invalid_tarball_name(&pkg, tarball.as_str()));
+    };
+
+    if !can_install(&state, user.as_ref().map(|user| &user.0), &pkg).await? {
+        return Err(package_not_found(&pkg));
+    }
+    check_quarantine(&state, &pkg, Some(version)).await?;
+
+    let storage = state.as_package_storage();
+    let range = match headers
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+    {
+        Some(range) => {
+            let size = storage
+                .tarball_metadata(&pkg, version)
+                .await
+                .map_err(|e| storage_error(e, || version_not_found(&pkg, version)))?
+                .size;
+            ByteRange::parse(range, size).map(|range| (range, size))
+        }
+        None => None,
+    };
+
+    // Resumed downloads would otherwise be counted once per resumption.
+    let counted = match range {
+        None => true,
+        Some((Ok(range), _)) => range.start == 0,
+        Some((Err(()), _)) => false,
+    };
+    if counted {
+        // A counter that's down shouldn't take installs with it.
+        if let Err(e) = state
+            .as_download_counts()
+            .record_download(&pkg, Utc::now().date_naive())
+            .await
+        {
+            tracing::warn!(error = ?e, "failed to record download");
+        }
+    }
+
+    let accept_ranges = (header::ACCEPT_RANGES, "bytes");
+    match range {
+        None => {
+            let stream = storage
+                .stream_tarball(&pkg, version)
+                .await
+                .map_err(|e| storage_error(e, || version_not_found(&pkg, version)))?;
+            Ok(([accept_ranges], StreamBody::new(stream)).into_response())
+        }
+        Some((Err(()), size)) => Ok((
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+        )
+            .into_response()),
+        Some((Ok(range), size)) => {
+            let stream = storage
+                .stream_tarball_range(&pkg, version, range)
+                .await
+                .map_err(|e| storage_error(e, || version_not_found(&pkg, version)))?;
+            Ok((
+                StatusCode::PARTIAL_CONTENT,
+                [
+                    (
+                        header::CONTENT_RANGE,
+                        format!("bytes {}-{}/{}", range.start, range.end, size),
+                    ),
+                    (header::CONTENT_LENGTH, range.len().to_string()),
+                ],
+                [accept_ranges],
+                StreamBody::new(stream),
+            )
+                .into_response())
+        }
+    }
+}
+
+// Tarballs are named "<name>-<version>.tgz"; returns the version.
+fn tarball_version<'a>(pkg: &PackageIdentifier, tarball: &'a str) -> Option<&'a str> {
+    let (named, version) = PackageIdentifier::parse_tarball_name(tarball)?;
+    pkg.is_named_by_tarball(&named).then_some(version)
+}
+
+fn metadata_headers(metadata: &ContentMetadata) -> Result<HeaderMap, RegistryError> {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_LENGTH, metadata.size.into());
+    headers.insert(
+        header::ETAG,
+        metadata
+            .etag()
+            .try_into()
+            .map_err(RegistryError::internal)?,
+    );
+    Ok(headers)
+}
+
+/// Answers HEAD from storage metadata, so existence and size checks don't stream the body.
+#[instrument(level = "info", skip(headers), fields(pkg))]
+async fn head_packument<Storage>(
+    State(state): State<Storage>,
+    user: Option<Authenticated>,
+    headers: HeaderMap,
+    Path(pkg): Path<String>,
+    Query(query): Query<PackumentQuery>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Storage: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+
+    if !can_install(&state, user.as_ref().map(|user| &user.0), &pkg).await? {
+        return Err(package_not_found(&pkg));
+    }
+    check_quarantine(&state, &pkg, None).await?;
+
+    let abbreviated = wants_abbreviated(&headers);
+
+    let storage = state.as_package_storage();
+    let (metadata, content_type) = if abbreviated {
+        (
+            storage.abbreviated_packument_metadata(&pkg).await,
+            ABBREVIATED_CONTENT_TYPE,
+        )
+    } else {
+        (storage.packument_metadata(&pkg).await, "application/json")
+    };
+    let metadata = match transformed_packument(
+        &state,
+        user.as_ref().map(|user| &user.0),
+        &pkg,
+        abbreviated,
+        query.full,
+    )
+    .await?
+    {
+        Some(document) => ContentMetadata::of(document.as_slice()).map_err(RegistryError::internal),
+        None => metadata.map_err(|e| storage_error(e, || package_not_found(&pkg))),
+    }?;
+
+    Ok((
+        [VARY_ACCEPT, (header::CONTENT_TYPE, content_type)],
+        metadata_headers(&metadata)?,
+    ))
+}
+
+async fn head_scoped_packument<Storage>(
+    State(state): State<Storage>,
+    user: Option<Authenticated>,
+    headers: HeaderMap,
+    Path((scope, pkg)): Path<(String, String)>,
+    query: Query<PackumentQuery>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Storage: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = format!("@{}/{}", scope, pkg);
+    head_packument(State(state), user, headers, Path(pkg), query).await
+}
+
+#[instrument]
+async fn head_tarball<Storage>(
+    State(state): State<Storage>,
+    user: Option<Authenticated>,
+    Path((pkg, tarball)): Path<(String, String)>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Storage: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+    let Some(version) = tarball_version(&pkg, tarball.as_str()) else {
+        return Err(invalid_tarball_name(&pkg, tarball.as_str()));
+    };
+
+    if !can_install(&state, user.as_ref().map(|user| &user.0), &pkg).await? {
+        return Err(package_not_found(&pkg));
+    }
+    check_quarantine(&state, &pkg, Some(version)).await?;
+
+    let metadata = state
+        .as_package_storage()
+        .tarball_metadata(&pkg, version)
+        .await
+        .map_err(|e| storage_error(e, || version_not_found(&pkg, version)))?;
+
+    Ok((
+        [(header::ACCEPT_RANGES, "bytes")],
+        metadata_headers(&metadata)?,
+    ))
+}
+
+async fn head_scoped_tarball<Storage>(
+    State(state): State<Storage>,
+    user: Option<Authenticated>,
+    Path((scope, pkg, tarball)): Path<(String, String, String)>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Storage: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = format!("@{}/{}", scope, pkg);
+    head_tarball(State(state), user, Path((pkg, tarball))).await
+}
+
+async fn get_scoped_tarball<Storage>(
+    State(state): State<Storage>,
+    user: Option<Authenticated>,
+    headers: HeaderMap,
+    Path((scope, pkg, tarball)): Path<(String, String, String)>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Storage: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = format!("@{}/{}", scope, pkg);
+    get_tarball(State(state), user, headers, Path((pkg, tarball))).await
+}
+
+#[instrument(skip(headers))]
+async fn get_login_poll<Auth>(
+    State(state): State<Auth>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Path(session): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Auth: PolicyHolder + std::fmt::Debug,
+{
+    let Ok(session) = session.parse::<<Auth::Authenticator as Authenticator>::SessionId>() else {
+        return Err(invalid_login_session());
+    };
+
+    let user = state
+        .as_authenticator()
+        .poll_login_session(state.as_configurator(), session)
+        .await
+        .map_err(|e| match LoginSessionError::of(&e) {
+            Some(LoginSessionError::Unrecognized) => {
+                RegistryError::not_found("no such login session")
+            }
+            // npm stops polling on any error; this one tells the user to start over.
+            Some(LoginSessionError::Expired) => RegistryError::new(
+                StatusCode::GONE,
+                "login session expired; run `npm login` again",
+            ),
+            Some(LoginSessionError::Failed) => {
+                RegistryError::unauthorized("login failed; run `npm login` again")
+            }
+            None => RegistryError::internal(e),
+        })?;
+
+    Ok(if let Some(user) = user {
+        // TODO: this is the point at which we add them to UserStorage -- which is where
+        // we may wish to apply WASM-based filtering of incoming users.
+        let user: User = user.into();
+        let username = user.name.clone();
+        let token = state
+            .as_token_authorizer()
+            .start_session(user)
+            .await
+            .map_err(RegistryError::internal)?;
+        let peer = peer.map(|ConnectInfo(peer)| peer);
+        record_login(&state, username, &token, &headers, peer).await;
+
+        (
+            StatusCode::OK,
+            [("x-ok", "x-ok")],
+            Json(serde_json::json!({
+                "message": "ok",
+                "token": token.to_string()
+            })),
+        )
+    } else {
+        (
+            StatusCode::ACCEPTED,
+            [("retry-after", "5")],
+            Json(serde_json::json!({
+                "message": "ok"
+            })),
+        )
+    })
+}
+
+// A login that can't be recorded still succeeds; the history is a convenience.
+async fn record_login<S>(
+    state: &S,
+    username: String,
+    token: &<S::TokenAuthorizer as TokenAuthorizer>::TokenSessionId,
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+) where
+    S: PolicyHolder,
+{
+    let token_authorizer = state.as_token_authorizer();
+    let key = match token_authorizer.token_key(token).await {
+        Ok(Some(key)) => key,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::warn!(error = ?e, "could not look up new session");
+            return;
+        }
+    };
+
+    let ip = client_ip(headers, peer);
+    let country = locate(state, ip.as_deref())
+        .await
+        .and_then(|location| location.country);
+    if let Some(ref country) = country {
+        check_login_location(state, username.as_str(), country).await;
+    }
+
+    let login = LoginEvent {
+        username,
+        key,
+        provider: state.as_authenticator().provider().to_string(),
+        ip,
+        country,
+        user_agent: headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        time: Utc::now(),
+    };
+    tracing::info!(
+        target: "audit",
+        action = "user.login",
+        user = login.username,
+        provider = login.provider,
+        ip = login.ip,
+        country = login.country,
+        session = login.key
+    );
+    if let Err(e) = token_authorizer.record_login(login).await {
+        tracing::warn!(error = ?e, "could not record login");
+    }
+}
+
+// A login from a country none of the user's recent logins came from is worth a look, though
+// not worth refusing: people travel, and the token is already issued.
+async fn check_login_location<S>(state: &S, username: &str, country: &str)
+where
+    S: PolicyHolder,
+{
+    let logins = match state.as_token_authorizer().list_logins(username).await {
+        Ok(logins) => logins,
+        Err(e) => {
+            tracing::warn!(error = ?e, "could not look up previous logins");
+            return;
+        }
+    };
+
+    let mut seen = logins
+        .iter()
+        .filter_map(|login| login.country.as_deref())
+        .peekable();
+    if seen.peek().is_none() || seen.any(|seen| seen == country) {
+        return;
+    }
+
+    tracing::warn!(
+        target: "audit",
+        action = "user.login.unusual_location",
+        user = username,
+        country
+    );
+}
+
+fn user_not_found(username: &str) -> RegistryError {
+    RegistryError::not_found(format!("no such user: {}", username))
+}
+
+// Also used for orgs the user isn't in, so as not to reveal who is.
+fn org_not_found(org: &str) -> RegistryError {
+    RegistryError::not_found(format!("no such org: {}", org))
+}
+
+fn not_org_member(org: &str, username: &str) -> RegistryError {
+    RegistryError::not_found(format!("{} is not a member of {}", username, org))
+}
+
+fn already_claimed(org: &str) -> RegistryError {
+    RegistryError::conflict(format!("{} has already been claimed", org))
+}
+
+fn last_owner(org: &str) -> RegistryError {
+    RegistryError::conflict(format!("{} must keep at least one owner", org))
+}
+
+fn cannot_manage_org(org: &str) -> RegistryError {
+    RegistryError::forbidden(format!("you must be an owner or admin of {}", org))
+}
+
+fn team_not_found(org: &str, team: &str) -> RegistryError {
+    RegistryError::not_found(format!("no such team: {}:{}", org, team))
+}
+
+fn no_pending_transfer(pkg: &PackageIdentifier) -> RegistryError {
+    RegistryError::not_found(format!("no pending transfer for {}", pkg))
+}
+
+fn invalid_login_session() -> RegistryError {
+    RegistryError::bad_request("invalid login session id")
+}
+
+#[instrument]
+async fn post_login<Auth, B>(
+    State(state): State<Auth>,
+    req: Request<B>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Auth: PolicyHolder + std::fmt::Debug,
+    B: std::fmt::Debug + Into<axum::body::Body>,
+{
+    let fqdn = state.as_configurator().fqdn();
+
+    let (parts, body) = req.into_parts();
+    let req = Request::from_parts(parts, body.into());
+    let Ok(id) = state.as_authenticator().start_login_session(req).await else {
+        return Err(RegistryError::bad_request(
+            "could not start a login session",
+        ));
+    };
+
+    Ok(Json(json!({
+        "doneUrl": format!("{}/-/v1/login/poll/{}", fqdn, id),
+        "loginUrl": format!("{}/-/v1/login/www/{}", fqdn, id)
+    })))
+}
+
+#[instrument]
+async fn www_login<Auth, B>(
+    State(state): State<Auth>,
+    session: Option<Path<String>>,
+    req: Request<B>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Auth: PolicyHolder + std::fmt::Debug,
+    B: std::fmt::Debug + Into<axum::body::Body>,
+{
+    let (parts, body) = req.into_parts();
+    let req = Request::from_parts(parts, body.into());
+
+    let session = if let Some(Path(session)) = session {
+        let Ok(session) = session.parse::<<Auth::Authenticator as Authenticator>::SessionId>()
+        else {
+            return Err(invalid_login_session());
+        };
+        Some(session)
+    } else {
+        None
+    };
+
+    state
+        .as_authenticator()
+        .complete_login_session(
+            state.as_configurator(),
+            state.as_user_storage(),
+            req,
+            session,
+        )
+        .await
+        .map_err(|e| {
+            tracing::warn!(error = ?e, "could not complete login session");
+            RegistryError::unauthorized("could not complete login")
+        })
+}
+
+#[instrument]
+async fn get_user<Auth>(
+    State(state): State<Auth>,
+    Path(user): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Auth: PolicyHolder + std::fmt::Debug,
+{
+    let Some(username) = user.strip_prefix(':') else {
+        return Err(RegistryError::not_found(format!("no such user: {}", user)));
+    };
+
+    let Ok(user) = state.as_user_storage().get_user(username).await else {
+        return Err(user_not_found(username));
+    };
+
+    // TODO: "fetch user" capability
+    Ok(Json(json!({
+        "_id": format!("org.couchdb.user:{}", user.name),
+        "name": user.name,
+        "email": ""
+    })))
+}
+
+fn profile(user: &User) -> serde_json::Value {
+    json!({
+        "name": user.name,
+        "email": user.email,
+        "tfa": false,
+        "fullname": user.full_name.as_deref().unwrap_or(""),
+        "homepage": user.homepage.as_deref().unwrap_or(""),
+        "freenode": user.freenode.as_deref().unwrap_or(""),
+        "twitter": user.twitter.as_deref().unwrap_or("")
+    })
+}
+
+#[instrument]
+async fn get_profile<Auth>(
+    State(state): State<Auth>,
+    Authenticated(user): Authenticated,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Auth: PolicyHolder + std::fmt::Debug,
+{
+    // Prefer the stored record; the token session may hold a stale copy of the user.
+    let user = state
+        .as_user_storage()
+        .get_user(user.name.as_str())
+        .await
+        .unwrap_or(user);
+
+    Ok(Json(profile(&user)))
+}
+
+#[instrument(skip(payload))]
+async fn post_profile<Auth>(
+    State(state): State<Auth>,
+    Authenticated(user): Authenticated,
+    Json(payload): Json<serde_json::Value>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Auth: PolicyHolder + std::fmt::Debug,
+{
+    // Passwords and 2fa belong to the upstream identity provider, not to us.
+    if payload.get("password").is_some() || payload.get("tfa").is_some() {
+        return Err(RegistryError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "passwords and two-factor auth are managed by your identity provider",
+        ));
+    }
+
+    let update = serde_json::from_value::<ProfileUpdate>(payload)
+        .map_err(|e| RegistryError::bad_request(format!("invalid profile: {}", e)))?;
+
+    let Ok(user) = state
+        .as_user_storage()
+        .update_user(user.name.as_str(), update)
+        .await
+    else {
+        return Err(user_not_found(user.name.as_str()));
+    };
+
+    Ok(Json(profile(&user)))
+}
+
+#[instrument]
+async fn get_tokens<Auth>(
+    State(state): State<Auth>,
+    Authenticated(user): Authenticated,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Auth: PolicyHolder + std::fmt::Debug,
+{
+    let tokens = state
+        .as_token_authorizer()
+        .list_tokens(user.name.as_str())
+        .await
+        .map_err(RegistryError::internal)?;
+
+    Ok(Json(json!({
+        "total": tokens.len(),
+        "objects": tokens,
+        "urls": {}
+    })))
+}
+
+#[derive(Deserialize, Debug)]
+struct CreateTokenRequest {
+    // npm always sends the account password; we authenticate via the bearer token instead.
+    #[serde(default)]
+    readonly: bool,
+    #[serde(default)]
+    cidr_whitelist: Option<Vec<String>>,
+}
+
+#[instrument(skip(payload))]
+async fn post_token<Auth>(
+    State(state): State<Auth>,
+    Authenticated(user): Authenticated,
+    Json(payload): Json<CreateTokenRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Auth: PolicyHolder + std::fmt::Debug,
+{
+    if let Some(cidr) = payload
+        .cidr_whitelist
+        .iter()
+        .flatten()
+        .find(|cidr| !is_valid_cidr(cidr))
+    {
+        return Err(RegistryError::bad_request(format!(
+            "{:?} is not an address or CIDR range",
+            cidr
+        )));
+    }
+
+    let options = TokenOptions {
+        readonly: payload.readonly,
+        cidr_whitelist: payload.cidr_whitelist,
+        ..Default::default()
+    };
+
+    let (token, metadata) = state
+        .as_token_authorizer()
+        .create_token(user, options)
+        .await
+        .map_err(RegistryError::internal)?;
+
+    Ok(Json(json!({
+        "token": token.to_string(),
+        "key": metadata.key,
+        "readonly": metadata.readonly,
+        "cidr_whitelist": metadata.cidr_whitelist,
+        "created": metadata.created,
+        "updated": metadata.updated
+    })))
+}
+
+#[instrument]
+async fn delete_token<Auth>(
+    State(state): State<Auth>,
+    Authenticated(user): Authenticated,
+    Path(key): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Auth: PolicyHolder + std::fmt::Debug,
+{
+    match state
+        .as_token_authorizer()
+        .revoke_token(user.name.as_str(), key.as_str())
+        .await
+    {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(RegistryError::not_found(format!("no such token: {}", key))),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct LoginHistoryEntry {
+    #[serde(flatten)]
+    login: LoginEvent,
+    /// Whether the session this login started can still be used.
+    active: bool,
+    /// Whether this request was made with that session.
+    current: bool,
+}
+
+async fn current_session_key<S>(state: &S, headers: &HeaderMap) -> Option<String>
+where
+    S: PolicyHolder,
+{
+    let bearer = bearer_token(headers)?;
+    state.as_token_authorizer().token_key(&bearer).await.ok()?
+}
+
+/// The caller's recent logins, with where they came from and whether their sessions are
+/// still live.
+#[instrument(skip(headers))]
+async fn get_logins<Auth>(
+    State(state): State<Auth>,
+    Authenticated(user): Authenticated,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Auth: PolicyHolder + std::fmt::Debug,
+{
+    let token_authorizer = state.as_token_authorizer();
+    let logins = token_authorizer
+        .list_logins(user.name.as_str())
+        .await
+        .map_err(RegistryError::internal)?;
+    let live: Vec<_> = token_authorizer
+        .list_tokens(user.name.as_str())
+        .await
+        .map_err(RegistryError::internal)?
+        .into_iter()
+        .map(|token| token.key)
+        .collect();
+    let current = current_session_key(&state, &headers).await;
+
+    let logins: Vec<_> = logins
+        .into_iter()
+        .map(|login| LoginHistoryEntry {
+            active: live.contains(&login.key),
+            current: current.as_ref() == Some(&login.key),
+            login,
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "total": logins.len(),
+        "objects": logins
+    })))
+}
+
+#[instrument]
+async fn delete_login<Auth>(
+    State(state): State<Auth>,
+    Authenticated(user): Authenticated,
+    Path(key): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Auth: PolicyHolder + std::fmt::Debug,
+{
+    match state
+        .as_token_authorizer()
+        .revoke_token(user.name.as_str(), key.as_str())
+        .await
+    {
+        Ok(true) => {
+            tracing::info!(
+                target: "audit",
+                action = "session.terminate",
+                user = user.name,
+                session = key
+            );
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err(RegistryError::not_found(format!(
+            "no such session: {}",
+            key
+        ))),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+/// Sign out everywhere but here.
+#[instrument(skip(headers))]
+async fn delete_other_logins<Auth>(
+    State(state): State<Auth>,
+    Authenticated(user): Authenticated,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Auth: PolicyHolder + std::fmt::Debug,
+{
+    let current = current_session_key(&state, &headers).await;
+    let token_authorizer = state.as_token_authorizer();
+    let tokens = token_authorizer
+        .list_tokens(user.name.as_str())
+        .await
+        .map_err(RegistryError::internal)?;
+
+    let mut terminated = 0;
+    for token in tokens {
+        if current.as_ref() == Some(&token.key) {
+            continue;
+        }
+        if token_authorizer
+            .revoke_token(user.name.as_str(), token.key.as_str())
+            .await
+            .map_err(RegistryError::internal)?
+        {
+            terminated += 1;
+        }
+    }
+
+    tracing::info!(
+        target: "audit",
+        action = "session.terminate_others",
+        user = user.name,
+        sessions = terminated
+    );
+    Ok(Json(json!({ "terminated": terminated })))
+}
+
+#[instrument(skip(request))]
+async fn post_audit<Storage>(
+    State(state): State<Storage>,
+    Json(request): Json<AuditRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Storage: PolicyHolder + std::fmt::Debug,
+{
+    match state.as_advisories().audit(request).await {
+        Ok(report) => Ok(Json(report)),
+        Err(e) => {
+            tracing::error!(error = ?e, "failed to run audit");
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+        }
+    }
+}
+
+#[instrument(skip(request))]
+async fn post_quick_audit<Storage>(
+    State(state): State<Storage>,
+    Json(request): Json<AuditRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Storage: PolicyHolder + std::fmt::Debug,
+{
+    match state.as_advisories().quick_audit(request).await {
+        Ok(report) => Ok(Json(report)),
+        Err(e) => {
+            tracing::error!(error = ?e, "failed to run quick audit");
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+        }
+    }
+}
+
+#[instrument(skip(request))]
+async fn post_bulk_advisories<Storage>(
+    State(state): State<Storage>,
+    Json(request): Json<BulkAdvisoryRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    Storage: PolicyHolder + std::fmt::Debug,
+{
+    match state.as_advisories().bulk_advisories(request).await {
+        Ok(advisories) => Ok(Json(advisories)),
+        Err(e) => {
+            tracing::error!(error = ?e, "failed to look up bulk advisories");
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct LockedPackage {
+    name: String,
+    version: String,
+    #[serde(default)]
+    integrity: Option<String>,
+}
+
+/// A lockfile entry as this registry sees it. `status` is `ok`, `mismatch` (a digest
+/// disagrees with the lockfile), `missing` (we can't serve it), `quarantined` (an admin is
+/// withholding it) or `error`.
+#[derive(Serialize, Debug)]
+struct VerifiedPackage {
+    name: String,
+    version: String,
+    status: &'static str,
+    /// What the packument advertises in `dist.integrity` (or `dist.shasum`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    advertised: Option<String>,
+    /// The digest of the tarball we would serve.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl VerifiedPackage {
+    fn failed(mut self, status: &'static str, reason: impl Into<String>) -> Self {
+        self.status = status;
+        self.reason = Some(reason.into());
+        self
+    }
+}
+
+// The stored tarball's digest, recomputed in an algorithm `want` lists if storage keeps a
+// different one.
+async fn tarball_integrity<S>(
+    state: &S,
+    pkg: &PackageIdentifier,
+    version: &str,
+    want: &Integrity,
+) -> anyhow::Result<Integrity>
+where
+    S: PolicyHolder,
+{
+    let storage = state.as_package_storage();
+    let stored = Integrity::from(storage.tarball_metadata(pkg, version).await?.digest);
+    let Some(algorithm) = want.strongest().map(|digest| digest.algorithm) else {
+        return Ok(stored);
+    };
+    if want.matches(&stored).is_some() {
+        return Ok(stored);
+    }
+
+    let mut stream = storage.stream_tarball(pkg, version).await?;
+    let mut hasher = Hasher::new(algorithm)?;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| {
+            let box_error: BoxError = e.into();
+            anyhow::anyhow!(box_error)
+        })?;
+        hasher.update(chunk.as_ref())?;
+    }
+    Ok(hasher.finish()?.into())
+}
+
+async fn verify_locked_package<S>(
+    state: &S,
+    user: Option<&User>,
+    locked: LockedPackage,
+) -> VerifiedPackage
+where
+    S: PolicyHolder,
+{
+    let verified = VerifiedPackage {
+        name: locked.name.clone(),
+        version: locked.version.clone(),
+        status: "ok",
+        advertised: None,
+        content: None,
+        reason: None,
+    };
+
+    let Ok(pkg) = locked.name.parse::<PackageIdentifier>() else {
+        return verified.failed("error", "invalid package name");
+    };
+    let expected = match locked.integrity.as_deref().map(str::parse::<Integrity>) {
+        None => None,
+        Some(Ok(expected)) => Some(expected),
+        Some(Err(e)) => return verified.failed("error", e.to_string()),
+    };
+
+    match can_install(state, user, &pkg).await {
+        Ok(true) => {}
+        Ok(false) => return verified.failed("missing", "package not found"),
+        Err(e) => return verified.failed("error", e.message().to_string()),
+    }
+    match check_quarantine(state, &pkg, Some(locked.version.as_str())).await {
+        Ok(()) => {}
+        Err(e) if e.status() == StatusCode::FORBIDDEN => {
+            return verified.failed("quarantined", e.message().to_string())
+        }
+        Err(e) => return verified.failed("error", e.message().to_string()),
+    }
+
+    let packument = match state.as_package_storage().fetch_packument(&pkg).await {
+        Ok(packument) => packument,
+        Err(e) => {
+            let e = storage_error(e, || package_not_found(&pkg));
+            let status = if e.status() == StatusCode::NOT_FOUND {
+                "missing"
+            } else {
+                "error"
+            };
+            return verified.failed(status, e.message().to_string());
+        }
+    };
+    let Some(dist) = packument
+        .versions
+        .as_ref()
+        .and_then(|versions| versions.get(locked.version.as_str()))
+        .map(|version| &version.dist)
+    else {
+        return verified.failed("missing", "version not found");
+    };
+
+    let advertised = dist.advertised_integrity();
+    let mut verified = VerifiedPackage {
+        advertised: advertised.as_ref().map(ToString::to_string),
+        ..verified
+    };
+
+    if let (Some(expected), Some(advertised)) = (&expected, &advertised) {
+        if expected.matches(advertised) == Some(false) {
+            return verified.failed("mismatch", "the packument advertises a different integrity");
+        }
+    }
+
+    // With no lockfile integrity, the packument's is the next best thing to check against.
+    let Some(reference) = expected.as_ref().or(advertised.as_ref()) else {
+        return verified.failed("error", "no integrity to verify against");
+    };
+    let content = match tarball_integrity(state, &pkg, locked.version.as_str(), reference).await {
+        Ok(content) => content,
+        Err(e) => {
+            let e = storage_error(e, || version_not_found(&pkg, locked.version.as_str()));
+            let status = if e.status() == StatusCode::NOT_FOUND {
+                "missing"
+            } else {
+                "error"
+            };
+            return verified.failed(status, e.message().to_string());
+        }
+    };
+    verified.content = Some(content.to_string());
+
+    match reference.matches(&content) {
+        Some(true) => verified,
+        Some(false) => verified.failed("mismatch", "the tarball does not match its integrity"),
+        None => verified.failed("error", "no digest algorithm in common with the tarball"),
+    }
+}
+
+/// Pre-flight a lockfile: for each `(name, version, integrity)`, whether this registry can
+/// serve it, and whether the packument and the tarball we'd serve agree with the lockfile.
+#[instrument(skip(state, locked))]
+async fn post_verify_lockfile<S>(
+    State(state): State<S>,
+    user: Option<Reader>,
+    Json(locked): Json<Vec<LockedPackage>>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    if locked.len() > VERIFY_LIMIT {
+        return Err(RegistryError::bad_request(format!(
+            "at most {} packages may be verified at once",
+            VERIFY_LIMIT
+        )));
+    }
+
+    let user = user.map(|Reader(user)| user);
+    let verified: Vec<_> = futures::stream::iter(locked)
+        .map(|locked| verify_locked_package(&state, user.as_ref(), locked))
+        .buffered(VERIFY_CONCURRENCY)
+        .collect()
+        .await;
+
+    Ok(Json(json!({
+        "ok": verified.iter().all(|verified| verified.status == "ok"),
+        "total": verified.len(),
+        "objects": verified
+    })))
+}
+
+#[derive(Deserialize, Debug)]
+struct InstallReportRequest {
+    /// Whatever the caller wants the report tied to (a repository, a commit, a build id),
+    /// carried into the signed report as given.
+    #[serde(default)]
+    build: serde_json::Value,
+    packages: Vec<LockedPackage>,
+}
+
+#[derive(Serialize, Debug)]
+struct InstalledPackage {
+    #[serde(flatten)]
+    verified: VerifiedPackage,
+    /// Known advisories affecting the installed version.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    advisories: Vec<BulkAdvisory>,
+}
+
+// The advisories affecting `version`, out of those listed for its package.
+fn advisories_affecting(
+    advisories: Option<&Vec<BulkAdvisory>>,
+    version: &str,
+) -> Vec<BulkAdvisory> {
+    let Some(version) = parse_version(version) else {
+        return Vec::new();
+    };
+    advisories
+        .into_iter()
+        .flatten()
+        .filter(|advisory| {
+            advisory
+                .vulnerable_versions
+                .parse::<VersionRange>()
+                .is_ok_and(|range| range.matches(&version))
+        })
+        .cloned()
+        .collect()
+}
+
+/// After an install, record what was installed: each `(name, version, integrity)` from the
+/// lockfile is checked as `/-/npm/v1/lockfile/verify` checks it, along with the advisories
+/// known against it, and the result is signed with the registry's active key. The
+/// signature is over the report's compact JSON, keys sorted, and checks against the key
+/// listed at `/-/npm/v1/keys` under its `keyid`.
+#[instrument(skip(state, request))]
+async fn post_install_report<S>(
+    State(state): State<S>,
+    user: Option<Reader>,
+    Json(request): Json<InstallReportRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    if request.packages.len() > VERIFY_LIMIT {
+        return Err(RegistryError::bad_request(format!(
+            "at most {} packages may be reported at once",
+            VERIFY_LIMIT
+        )));
+    }
+    let now = Utc::now();
+    let Some(key) = active_key(state.as_configurator().signing_keys(), now) else {
+        return Err(RegistryError::conflict("no signing key is configured"));
+    };
+
+    let mut installed = BulkAdvisoryRequest::new();
+    for package in &request.packages {
+        installed
+            .entry(package.name.clone())
+            .or_default()
+            .push(package.version.clone());
+    }
+    // A report that can't say whether advisories were checked would vouch for too much.
+    let advisories = match state.as_advisories().bulk_advisories(installed).await {
+        Ok(advisories) => Some(advisories),
+        Err(e) => {
+            tracing::warn!(error = ?e, "could not check installed packages for advisories");
+            None
+        }
+    };
+
+    let user = user.map(|Reader(user)| user);
+    let verified: Vec<_> = futures::stream::iter(request.packages)
+        .map(|locked| verify_locked_package(&state, user.as_ref(), locked))
+        .buffered(VERIFY_CONCURRENCY)
+        .collect()
+        .await;
+    let objects: Vec<_> = verified
+        .into_iter()
+        .map(|verified| InstalledPackage {
+            advisories: advisories_affecting(
+                advisories
+                    .as_ref()
+                    .and_then(|advisories| advisories.get(&verified.name)),
+                verified.version.as_str(),
+            ),
+            verified,
+        })
+        .collect();
+
+    let ok = objects.iter().all(|object| object.verified.status == "ok");
+    let vulnerable = objects
+        .iter()
+        .filter(|object| !object.advisories.is_empty())
+        .count();
+    let installer = user.map(|user| user.name);
+    tracing::info!(
+        target: "audit",
+        action = "install.report",
+        installer,
+        total = objects.len(),
+        ok,
+        vulnerable
+    );
+
+    let report = json!({
+        "registry": state.as_configurator().fqdn(),
+        "created": now,
+        "installer": installer,
+        "build": request.build,
+        "ok": ok,
+        "total": objects.len(),
+        "vulnerable": vulnerable,
+        "advisoriesChecked": advisories.is_some(),
+        "objects": objects
+    });
+    let message = serde_json::to_vec(&report).map_err(RegistryError::internal)?;
+    let sig = key
+        .sign(message.as_slice())
+        .map_err(RegistryError::internal)?;
+    let signature = Signature {
+        keyid: key.key_id(),
+        sig,
+    };
+
+    Ok(Json(json!({ "report": report, "signature": signature })))
+}
+
+#[derive(Deserialize, Debug)]
+struct BatchRequest {
+    names: Vec<String>,
+    /// Full packuments rather than the abbreviated install metadata.
+    #[serde(default)]
+    full: bool,
+}
+
+async fn batch_packument<S>(
+    state: &S,
+    user: Option<&User>,
+    name: &str,
+    full: bool,
+) -> Result<serde_json::Value, RegistryError>
+where
+    S: PolicyHolder,
+{
+    let pkg = parse_package(name)?;
+    if !can_install(state, user, &pkg).await? {
+        return Err(package_not_found(&pkg));
+    }
+    check_quarantine(state, &pkg, None).await?;
+
+    let storage = state.as_package_storage();
+    let stream = if full {
+        storage.stream_packument(&pkg).await
+    } else {
+        storage.stream_abbreviated_packument(&pkg).await
+    };
+    let data = collect_stream(stream.map_err(|e| storage_error(e, || package_not_found(&pkg)))?)
+        .await
+        .map_err(|e| storage_error(e, || package_not_found(&pkg)))?;
+
+    // Parsed rather than spliced in as stored, so that each stays on its one line.
+    let mut document = serde_json::from_slice(data.as_slice()).map_err(RegistryError::internal)?;
+    let served = ServedPackument {
+        name: &pkg,
+        abbreviated: !full,
+        user,
+        full: false,
+    };
+    transform_packument(state, &served, &mut document).await?;
+    Ok(document)
+}
+
+// One line of a batch response: the packument, or the error a GET for it would have had.
+fn batch_line(name: String, result: Result<serde_json::Value, RegistryError>) -> String {
+    let line = match result {
+        Ok(packument) => json!({ "name": name, "packument": packument }),
+        Err(e) => json!({
+            "name": name,
+            "status": e.status().as_u16(),
+            "error": e.message()
+        }),
+    };
+    format!("{}\n", line)
+}
+
+/// Fetch many packuments in one request, for resolvers that would otherwise make a GET per
+/// dependency. The response is newline-delimited JSON, one `{"name", "packument"}` or
+/// `{"name", "status", "error"}` line per name, in whatever order they finish.
+#[instrument(skip(state, batch))]
+async fn post_packuments_batch<S>(
+    State(state): State<S>,
+    user: Option<Reader>,
+    Json(batch): Json<BatchRequest>,
+) -> Result<Response, RegistryError>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    if batch.names.len() > BATCH_LIMIT {
+        return Err(RegistryError::bad_request(format!(
+            "at most {} packuments may be fetched at once",
+            BATCH_LIMIT
+        )));
+    }
+
+    let mut names = batch.names;
+    names.sort();
+    names.dedup();
+
+    let user = user.map(|Reader(user)| user);
+    let full = batch.full;
+    let lines = futures::stream::iter(names)
+        .map(move |name| {
+            let state = state.clone();
+            let user = user.clone();
+            async move {
+                let result = batch_packument(&state, user.as_ref(), name.as_str(), full).await;
+                Ok::<_, std::convert::Infallible>(batch_line(name, result))
+            }
+        })
+        .buffer_unordered(BATCH_CONCURRENCY);
+
+    Ok((
+        [(header::CONTENT_TYPE, NDJSON_CONTENT_TYPE)],
+        StreamBody::new(lines),
+    )
+        .into_response())
+}
+
+pub(super) fn parse_package(pkg: &str) -> Result<PackageIdentifier, RegistryError> {
+    pkg.parse()
+        .map_err(|_| RegistryError::bad_request(format!("invalid package name: {}", pkg)))
+}
+
+// Also used for packages the user may not see, so as not to reveal that they exist.
+pub(super) fn package_not_found(pkg: &PackageIdentifier) -> RegistryError {
+    RegistryError::not_found(format!("package not found: {}", pkg))
+}
+
+fn version_not_found(pkg: &PackageIdentifier, version: &str) -> RegistryError {
+    RegistryError::not_found(format!("version not found: {}@{}", pkg, version))
+}
+
+fn invalid_tarball_name(pkg: &PackageIdentifier, tarball: &str) -> RegistryError {
+    RegistryError::bad_request(format!("not a tarball of {}: {}", pkg, tarball))
+}
+
+fn cannot_modify(pkg: &PackageIdentifier) -> RegistryError {
+    RegistryError::forbidden(format!("you do not have permission to modify {}", pkg))
+}
+
+fn cannot_change_owners(pkg: &PackageIdentifier) -> RegistryError {
+    RegistryError::forbidden(format!("only maintainers of {} may change its owners", pkg))
+}
+
+fn update_conflict() -> RegistryError {
+    RegistryError::conflict("document update conflict: the package changed since it was read")
+}
+
+// Offline, a package that isn't here may still exist upstream; say so, rather than have
+// users conclude it was never published.
+fn offline_not_found(not_found: RegistryError) -> RegistryError {
+    RegistryError::new(
+        not_found.status(),
+        format!(
+            "{} (this registry is offline, and serves only packages it has cached or that were \
+             published to it)",
+            not_found.message()
+        ),
+    )
+    .with_detail("offline", true.into())
+}
+
+// Missing documents are the client's problem and a failing upstream is the upstream's;
+// anything else is ours.
+pub(super) fn storage_error(
+    error: anyhow::Error,
+    not_found: impl FnOnce() -> RegistryError,
+) -> RegistryError {
+    match StorageError::of(&error) {
+        Some(StorageError::NotFound) if error.downcast_ref::<Offline>().is_some() => {
+            offline_not_found(not_found())
+        }
+        Some(StorageError::NotFound) => not_found(),
+        Some(StorageError::Conflict) => update_conflict(),
+        Some(StorageError::Forbidden) => {
+            RegistryError::forbidden("you may not access this package")
+        }
+        Some(StorageError::Upstream(reason)) => {
+            tracing::warn!(reason, "upstream registry failed");
+            RegistryError::new(
+                StatusCode::BAD_GATEWAY,
+                "the upstream registry could not be reached",
+            )
+        }
+        // npm retries a 503 on its own, after backing off.
+        Some(StorageError::RateLimited { retry_after }) => {
+            tracing::warn!(?retry_after, "upstream registry is rate limiting us");
+            match retry_after {
+                Some(wait) => RegistryError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!(
+                        "the upstream registry is busy; try again in {}s",
+                        wait.as_secs_f64().ceil()
+                    ),
+                )
+                .with_retry_after(*wait),
+                None => RegistryError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "the upstream registry is busy; try again shortly",
+                ),
+            }
+        }
+        Some(StorageError::Io(_) | StorageError::Corrupt(_)) | None => {
+            RegistryError::internal(error)
+        }
+    }
+}
+
+// A user's effective permission on a package: maintainers listed in the packument have
+// read-write access, and anyone else gets the best of their own and their teams' grants.
+async fn package_permission<S>(
+    state: &S,
+    user: &User,
+    pkg: &PackageIdentifier,
+) -> Result<Option<Permission>, RegistryError>
+where
+    S: PolicyHolder,
+{
+    if let Ok(packument) = state.as_package_storage().fetch_packument(pkg).await {
+        if packument.is_maintainer(user.name.as_str()) {
+            return Ok(Some(Permission::ReadWrite));
+        }
+    }
+
+    let collaborators = state
+        .as_access_control()
+        .list_collaborators(pkg)
+        .await
+        .map_err(RegistryError::internal)?;
+
+    let teams = state
+        .as_user_storage()
+        .teams_for_user(user.name.as_str())
+        .await
+        .map_err(RegistryError::internal)?;
+
+    Ok(std::iter::once(&user.name)
+        .chain(teams.iter())
+        .filter_map(|grantee| collaborators.get(grantee.as_str()).copied())
+        .max())
+}
+
+async fn can_manage_access<S>(
+    state: &S,
+    user: &User,
+    pkg: &PackageIdentifier,
+) -> Result<bool, RegistryError>
+where
+    S: PolicyHolder,
+{
+    Ok(package_permission(state, user, pkg).await? == Some(Permission::ReadWrite))
+}
+
+// Restricted packages are only visible to users with some grant on them.
+pub(super) async fn can_install<S>(
+    state: &S,
+    user: Option<&User>,
+    pkg: &PackageIdentifier,
+) -> Result<bool, RegistryError>
+where
+    S: PolicyHolder,
+{
+    let access = state
+        .as_access_control()
+        .get_access(pkg)
+        .await
+        .map_err(RegistryError::internal)?;
+
+    match (access, user) {
+        (Access::Public, _) => Ok(true),
+        (Access::Restricted, None) => Ok(false),
+        (Access::Restricted, Some(user)) => {
+            Ok(package_permission(state, user, pkg).await?.is_some())
+        }
+    }
+}
+
+fn quarantined(quarantine: &Quarantine) -> RegistryError {
+    RegistryError::forbidden(format!(
+        "{} is quarantined: {}",
+        quarantine.subject(),
+        quarantine.reason
+    ))
+    .with_detail(
+        "quarantine",
+        json!({
+            "package": quarantine.package,
+            "version": quarantine.version,
+            "reason": quarantine.reason,
+            "created": quarantine.created
+        }),
+    )
+}
+
+/// Refuse to serve what an admin has quarantined, saying why. With no version, only a
+/// quarantine on the whole package refuses.
+pub(super) async fn check_quarantine<S>(
+    state: &S,
+    pkg: &PackageIdentifier,
+    version: Option<&str>,
+) -> Result<(), RegistryError>
+where
+    S: PolicyHolder,
+{
+    match state
+        .as_moderation()
+        .quarantined(pkg, version)
+        .await
+        .map_err(RegistryError::internal)?
+    {
+        Some(quarantine) => Err(quarantined(&quarantine)),
+        None => Ok(()),
+    }
+}
+
+#[instrument]
+async fn get_package_access<S>(
+    State(state): State<S>,
+    Path(pkg): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+
+    let access = state
+        .as_access_control()
+        .get_access(&pkg)
+        .await
+        .map_err(RegistryError::internal)?;
+
+    Ok(Json(json!({ "access": access })))
+}
+
+#[derive(Deserialize, Debug)]
+struct SetAccessRequest {
+    access: Access,
+}
+
+#[instrument]
+async fn post_package_access<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(pkg): Path<String>,
+    Json(payload): Json<SetAccessRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+
+    if !can_manage_access(&state, &user, &pkg).await? {
+        return Err(cannot_modify(&pkg));
+    }
+
+    state
+        .as_access_control()
+        .set_access(&pkg, payload.access)
+        .await
+        .map_err(RegistryError::internal)?;
+
+    Ok(Json(json!({ "access": payload.access })))
+}
+
+#[derive(Deserialize, Debug)]
+struct CollaboratorsQuery {
+    user: Option<String>,
+}
+
+// Usernames with access to a package: its maintainers (read-write), direct grants, and the
+// members of any team granted access. Users reachable more than one way get the best grant.
+async fn package_collaborators<S>(
+    state: &S,
+    pkg: &PackageIdentifier,
+) -> Result<BTreeMap<String, Permission>, RegistryError>
+where
+    S: PolicyHolder,
+{
+    let mut collaborators = BTreeMap::new();
+    if let Ok(packument) = state.as_package_storage().fetch_packument(pkg).await {
+        for maintainer in packument.maintainer_names() {
+            collaborators.insert(maintainer, Permission::ReadWrite);
+        }
+    }
+
+    let grants = state
+        .as_access_control()
+        .list_collaborators(pkg)
+        .await
+        .map_err(RegistryError::internal)?;
+
+    for (grantee, permission) in grants {
+        let users = match grantee.split_once(':') {
+            Some((org, team)) => state
+                .as_user_storage()
+                .list_team_members(org, team)
+                .await
+                .ok()
+                .flatten()
+                .unwrap_or_default(),
+            None => vec![grantee],
+        };
+
+        for user in users {
+            let entry = collaborators.entry(user).or_insert(permission);
+            *entry = (*entry).max(permission);
+        }
+    }
+
+    Ok(collaborators)
+}
+
+#[instrument]
+async fn get_package_collaborators<S>(
+    State(state): State<S>,
+    user: Option<Authenticated>,
+    Path(pkg): Path<String>,
+    Query(query): Query<CollaboratorsQuery>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+
+    if !can_install(&state, user.as_ref().map(|user| &user.0), &pkg).await? {
+        return Err(package_not_found(&pkg));
+    }
+
+    let mut collaborators = package_collaborators(&state, &pkg).await?;
+    if let Some(user) = query.user {
+        collaborators.retain(|name, _| *name == user);
+    }
+
+    Ok(Json(collaborators))
+}
+
+#[derive(Deserialize, Debug)]
+struct PlatformsQuery {
+    version: Option<String>,
+    os: Option<String>,
+    cpu: Option<String>,
+}
+
+/// The platform variants a version (by default `latest`) of a logical package declares, and
+/// which of them have been published. `?os=` and `?cpu=` narrow them to the published
+/// variants npm would install there.
+#[instrument]
+async fn get_package_platforms<S>(
+    State(state): State<S>,
+    user: Option<Authenticated>,
+    Path(pkg): Path<String>,
+    Query(query): Query<PlatformsQuery>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+    let user = user.as_ref().map(|user| &user.0);
+
+    if !can_install(&state, user, &pkg).await? {
+        return Err(package_not_found(&pkg));
+    }
+
+    let spec = query.version.as_deref().unwrap_or("latest");
+    let Some(manifest) = state
+        .as_package_storage()
+        .resolve_version(&pkg, spec)
+        .await
+        .map_err(|e| storage_error(e, || package_not_found(&pkg)))?
+    else {
+        return Err(version_not_found(&pkg, spec));
+    };
+    let number = manifest
+        .meta
+        .get("version")
+        .and_then(|v| v.as_str())
+        .unwrap_or(spec);
+    let variants = platform_variants(&manifest).map_err(RegistryError::internal)?;
+
+    let filtered = query.os.is_some() || query.cpu.is_some();
+    let mut complete = true;
+    let mut listed = serde_json::Map::new();
+    for (platform, variant) in variants {
+        // Variants the caller can't see are reported as unpublished.
+        let published = if can_install(&state, user, &variant).await? {
+            published_variant(&state, &variant, number).await?
+        } else {
+            None
+        };
+        complete &= published.is_some();
+
+        let supported = published.as_ref().is_some_and(|published| {
+            supports_platform(published, query.os.as_deref(), query.cpu.as_deref())
+        });
+        if filtered && !supported {
+            continue;
+        }
+
+        let field = |name: &str| {
+            published
+                .as_ref()
+                .and_then(|published| published.meta.get(name).cloned())
+                .unwrap_or(serde_json::Value::Null)
+        };
+        listed.insert(
+            platform,
+            json!({
+                "name": variant.to_string(),
+                "published": published.is_some(),
+                "os": field("os"),
+                "cpu": field("cpu"),
+            }),
+        );
+    }
+
+    Ok(Json(json!({
+        "name": pkg.to_string(),
+        "version": number,
+        "complete": complete,
+        "variants": listed,
+    })))
+}
+
+/// What has been published, tagged and deprecated, by whom and from where, for the
+/// package's maintainers to review.
+#[instrument]
+async fn get_package_history<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(pkg): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+
+    if !can_install(&state, Some(&user), &pkg).await? {
+        return Err(package_not_found(&pkg));
+    }
+    if !can_manage_access(&state, &user, &pkg).await? {
+        return Err(RegistryError::forbidden(format!(
+            "only maintainers of {} may view its history",
+            pkg
+        )));
+    }
+
+    let events = state
+        .as_access_control()
+        .package_history(&pkg)
+        .await
+        .map_err(RegistryError::internal)?;
+
+    Ok(Json(json!({
+        "total": events.len(),
+        "objects": events
+    })))
+}
+
+#[derive(Deserialize, Debug)]
+struct TransferRequest {
+    to: String,
+}
+
+// Users accept transfers addressed to them; any member of a team may accept for the team.
+async fn can_accept_transfer<S>(
+    state: &S,
+    user: &User,
+    transfer: &Transfer,
+) -> Result<bool, RegistryError>
+where
+    S: PolicyHolder,
+{
+    if transfer.to == user.name {
+        return Ok(true);
+    }
+
+    let teams = state
+        .as_user_storage()
+        .teams_for_user(user.name.as_str())
+        .await
+        .map_err(RegistryError::internal)?;
+    Ok(teams.contains(&transfer.to))
+}
+
+// The users who become maintainers when a package is transferred to `recipient`.
+async fn transfer_recipients<S>(state: &S, recipient: &str) -> Result<Vec<String>, RegistryError>
+where
+    S: PolicyHolder,
+{
+    match recipient.split_once(':') {
+        Some((org, team)) => match state.as_user_storage().list_team_members(org, team).await {
+            Ok(Some(members)) if !members.is_empty() => Ok(members),
+            Ok(Some(_)) => Err(RegistryError::conflict(format!(
+                "team {} has no members to transfer to",
+                recipient
+            ))),
+            Ok(None) => Err(team_not_found(org, team)),
+            Err(e) => Err(RegistryError::internal(e)),
+        },
+        None => match state.as_user_storage().get_user(recipient).await {
+            Ok(_) => Ok(vec![recipient.to_string()]),
+            Err(_) => Err(user_not_found(recipient)),
+        },
+    }
+}
+
+/// Offer a package to another user or team. Nothing changes until they accept.
+#[instrument]
+async fn put_package_transfer<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(pkg): Path<String>,
+    Json(payload): Json<TransferRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+
+    if !can_manage_access(&state, &user, &pkg).await? {
+        return Err(cannot_modify(&pkg));
+    }
+
+    transfer_recipients(&state, payload.to.as_str()).await?;
+
+    let transfer = Transfer {
+        package: pkg.to_string(),
+        from: user.name.clone(),
+        to: payload.to,
+        created: Utc::now(),
+    };
+
+    if let Err(e) = state
+        .as_access_control()
+        .offer_transfer(transfer.clone())
+        .await
+    {
+        tracing::error!(error = ?e, "failed to record transfer");
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+    }
+
+    tracing::info!(
+        target: "audit",
+        action = "package.transfer.offer",
+        package = transfer.package,
+        from = transfer.from,
+        to = transfer.to
+    );
+    Ok((StatusCode::CREATED, Json(transfer)))
+}
+
+#[instrument]
+async fn get_package_transfer<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(pkg): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+
+    let Ok(Some(transfer)) = state.as_access_control().pending_transfer(&pkg).await else {
+        return Err(no_pending_transfer(&pkg));
+    };
+
+    if !can_accept_transfer(&state, &user, &transfer).await?
+        && !can_manage_access(&state, &user, &pkg).await?
+    {
+        return Err(no_pending_transfer(&pkg));
+    }
+
+    Ok(Json(transfer))
+}
+
+/// Withdraw (as a package manager) or decline (as the recipient) a pending transfer.
+#[instrument]
+async fn delete_package_transfer<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(pkg): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+
+    let Ok(Some(transfer)) = state.as_access_control().pending_transfer(&pkg).await else {
+        return Err(no_pending_transfer(&pkg));
+    };
+
+    let action = if can_accept_transfer(&state, &user, &transfer).await? {
+        "package.transfer.decline"
+    } else if can_manage_access(&state, &user, &pkg).await? {
+        "package.transfer.withdraw"
+    } else {
+        return Err(RegistryError::forbidden(format!(
+            "you may not withdraw or decline the transfer of {}",
+            pkg
+        )));
+    };
+
+    state
+        .as_access_control()
+        .take_transfer(&pkg)
+        .await
+        .map_err(RegistryError::internal)?;
+
+    tracing::info!(
+        target: "audit",
+        action,
+        package = transfer.package,
+        from = transfer.from,
+        to = transfer.to,
+        by = user.name
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Accept a pending transfer: the recipient (or the team's members) replace the package's
+/// maintainers and get read-write access, and the previous owners' direct grants are revoked.
+#[instrument]
+async fn post_package_transfer_accept<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(pkg): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+
+    let access_control = state.as_access_control();
+    let Ok(Some(transfer)) = access_control.pending_transfer(&pkg).await else {
+        return Err(no_pending_transfer(&pkg));
+    };
+
+    if !can_accept_transfer(&state, &user, &transfer).await? {
+        return Err(RegistryError::forbidden(format!(
+            "the transfer of {} is addressed to {}",
+            pkg, transfer.to
+        )));
+    }
+
+    let mut packument = state
+        .as_package_storage()
+        .fetch_packument(&pkg)
+        .await
+        .map_err(|e| storage_error(e, || package_not_found(&pkg)))?;
+
+    let mut maintainers = Vec::new();
+    for name in transfer_recipients(&state, transfer.to.as_str()).await? {
+        let email = state
+            .as_user_storage()
+            .get_user(name.as_str())
+            .await
+            .ok()
+            .map(|user| user.email);
+        maintainers.push(Maintainer::Object(MaintainerObject {
+            name: Some(name),
+            email,
+            url: None,
+        }));
+    }
+
+    let mut previous = packument.maintainer_names();
+    previous.push(transfer.from.clone());
+    packument.maintainers = Some(maintainers);
+
+    state
+        .as_package_storage()
+        .update_packument(&pkg, &mut packument)
+        .await
+        .map_err(|e| storage_error(e, || package_not_found(&pkg)))?;
+
+    let regranted = async {
+        for grantee in previous.iter().filter(|grantee| **grantee != transfer.to) {
+            access_control.revoke(&pkg, grantee.as_str()).await?;
+        }
+        access_control
+            .grant(&pkg, transfer.to.as_str(), Permission::ReadWrite)
+            .await?;
+        access_control.take_transfer(&pkg).await
+    }
+    .await;
+
+    if let Err(e) = regranted {
+        tracing::error!(error = ?e, "failed to update grants after transfer");
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+    }
+
+    tracing::info!(
+        target: "audit",
+        action = "package.transfer.accept",
+        package = transfer.package,
+        from = transfer.from,
+        to = transfer.to,
+        by = user.name
+    );
+    Ok(Json(json!({
+        "ok": true,
+        "id": pkg.to_string(),
+        "maintainers": packument.maintainers
+    })))
+}
+
+/// Transfers awaiting the caller's acceptance, including those offered to their teams.
+#[instrument]
+async fn get_transfers<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let mut recipients = state
+        .as_user_storage()
+        .teams_for_user(user.name.as_str())
+        .await
+        .map_err(RegistryError::internal)?;
+    recipients.push(user.name);
+
+    match state
+        .as_access_control()
+        .transfers_to(recipients.as_slice())
+        .await
+    {
+        Ok(transfers) => Ok(Json(transfers)),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct PackageListQuery {
+    user: Option<String>,
+}
+
+#[instrument]
+async fn get_package_list<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Query(query): Query<PackageListQuery>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let grantee = query.user.unwrap_or(user.name);
+    list_granted_packages(&state, grantee.as_str()).await
+}
+
+#[instrument]
+async fn get_user_packages<S>(
+    State(state): State<S>,
+    Path(username): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    list_granted_packages(&state, username.as_str()).await
+}
+
+async fn list_granted_packages<S>(
+    state: &S,
+    grantee: &str,
+) -> Result<Json<BTreeMap<String, Permission>>, RegistryError>
+where
+    S: PolicyHolder,
+{
+    state
+        .as_access_control()
+        .list_packages(grantee)
+        .await
+        .map(Json)
+        .map_err(RegistryError::internal)
+}
+
+#[instrument]
+async fn get_team_packages<S>(
+    State(state): State<S>,
+    Path((scope, team)): Path<(String, String)>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    list_granted_packages(&state, format!("{}:{}", scope, team).as_str()).await
+}
+
+#[derive(Deserialize, Debug)]
+struct TeamGrantRequest {
+    package: String,
+    permissions: Option<Permission>,
+}
+
+#[instrument]
+async fn put_team_package<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path((scope, team)): Path<(String, String)>,
+    Json(payload): Json<TeamGrantRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(payload.package.as_str())?;
+
+    if !can_manage_access(&state, &user, &pkg).await? {
+        return Err(cannot_modify(&pkg));
+    }
+
+    let permission = payload.permissions.unwrap_or(Permission::ReadOnly);
+    let grantee = format!("{}:{}", scope, team);
+    state
+        .as_access_control()
+        .grant(&pkg, grantee.as_str(), permission)
+        .await
+        .map_err(RegistryError::internal)?;
+
+    Ok(StatusCode::CREATED)
+}
+
+#[instrument]
+async fn delete_team_package<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path((scope, team)): Path<(String, String)>,
+    Json(payload): Json<TeamGrantRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(payload.package.as_str())?;
+
+    if !can_manage_access(&state, &user, &pkg).await? {
+        return Err(cannot_modify(&pkg));
+    }
+
+    let grantee = format!("{}:{}", scope, team);
+    state
+        .as_access_control()
+        .revoke(&pkg, grantee.as_str())
+        .await
+        .map_err(RegistryError::internal)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Debug)]
+struct OrgMembershipRequest {
+    user: String,
+    #[serde(default)]
+    role: Option<OrgRole>,
+}
+
+async fn org_members<S>(state: &S, org: &str) -> Result<BTreeMap<String, OrgRole>, RegistryError>
+where
+    S: PolicyHolder,
+{
+    state
+        .as_user_storage()
+        .list_org_members(org)
+        .await
+        .map_err(RegistryError::internal)
+}
+
+fn owner_count(members: &BTreeMap<String, OrgRole>) -> usize {
+    members
+        .values()
+        .filter(|role| **role == OrgRole::Owner)
+        .count()
+}
+
+#[instrument]
+async fn put_org_user<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(org): Path<String>,
+    Json(payload): Json<OrgMembershipRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let role = payload.role.unwrap_or_default();
+    let members = org_members(&state, org.as_str()).await?;
+    let current = members.get(payload.user.as_str()).copied();
+
+    if members.is_empty() {
+        // Nobody owns this org yet; the only allowed change is claiming it.
+        if payload.user != user.name
+            || role != OrgRole::Owner
+            || !can_claim_org(&state, &user, org.as_str()).await?
+        {
+            return Err(org_not_found(org.as_str()));
+        }
+    } else {
+        // Admins manage developers and admins; only owners can touch owners.
+        let allowed = match members.get(user.name.as_str()) {
+            Some(OrgRole::Owner) => true,
+            Some(OrgRole::Admin) => role != OrgRole::Owner && current != Some(OrgRole::Owner),
+            _ => false,
+        };
+        if !allowed {
+            return Err(RegistryError::forbidden(format!(
+                "you may not change roles in {}",
+                org
+            )));
+        }
+
+        if current == Some(OrgRole::Owner) && role != OrgRole::Owner && owner_count(&members) == 1 {
+            return Err(last_owner(org.as_str()));
+        }
+    }
+
+    if state
+        .as_user_storage()
+        .get_user(payload.user.as_str())
+        .await
+        .is_err()
+    {
+        return Err(user_not_found(payload.user.as_str()));
+    }
+
+    let user_storage = state.as_user_storage();
+    if members.is_empty() {
+        let claimed = user_storage
+            .claim_org(org.as_str(), payload.user.as_str())
+            .await
+            .map_err(RegistryError::internal)?;
+        if !claimed {
+            return Err(already_claimed(org.as_str()));
+        }
+    } else {
+        user_storage
+            .set_org_member(org.as_str(), payload.user.as_str(), role)
+            .await
+            .map_err(RegistryError::internal)?;
+    }
+
+    let size = members.len() + usize::from(current.is_none());
+    Ok(Json(json!({
+        "org": { "name": org, "size": size },
+        "user": payload.user,
+        "role": role
+    })))
+}
+
+#[instrument]
+async fn delete_org_user<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(org): Path<String>,
+    Json(payload): Json<OrgMembershipRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let members = org_members(&state, org.as_str()).await?;
+    let Some(target) = members.get(payload.user.as_str()).copied() else {
+        return Err(not_org_member(org.as_str(), payload.user.as_str()));
+    };
+
+    // Anyone may leave; otherwise the same rules as changing a role apply.
+    let allowed = payload.user == user.name
+        || match members.get(user.name.as_str()) {
+            Some(OrgRole::Owner) => true,
+            Some(OrgRole::Admin) => target != OrgRole::Owner,
+            _ => false,
+        };
+    if !allowed {
+        return Err(RegistryError::forbidden(format!(
+            "you may not remove members from {}",
+            org
+        )));
+    }
+
+    if target == OrgRole::Owner && owner_count(&members) == 1 {
+        return Err(last_owner(org.as_str()));
+    }
+
+    match state
+        .as_user_storage()
+        .remove_org_member(org.as_str(), payload.user.as_str())
+        .await
+    {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(not_org_member(org.as_str(), payload.user.as_str())),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+#[instrument]
+async fn get_org_users<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(org): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let members = org_members(&state, org.as_str()).await?;
+    if !members.contains_key(user.name.as_str()) {
+        return Err(org_not_found(org.as_str()));
+    }
+
+    Ok(Json(members))
+}
+
+// A user may claim the scope matching their own name, or one their identity provider
+// vouched for at login.
+async fn can_claim_org<S>(state: &S, user: &User, org: &str) -> Result<bool, RegistryError>
+where
+    S: PolicyHolder,
+{
+    if user.name.eq_ignore_ascii_case(org) {
+        return Ok(true);
+    }
+
+    let verified = state
+        .as_user_storage()
+        .verified_scopes(user.name.as_str())
+        .await
+        .map_err(RegistryError::internal)?;
+
+    Ok(verified.iter().any(|scope| scope.eq_ignore_ascii_case(org)))
+}
+
+/// Claim an unowned scope, making the caller its owner and seeding the "developers" team
+/// that npm creates alongside every org.
+#[instrument]
+async fn post_org_claim<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(org): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    if !can_claim_org(&state, &user, org.as_str()).await? {
+        return Err(RegistryError::forbidden(format!(
+            "you may only claim your own scope or one your identity provider verified, not {}",
+            org
+        )));
+    }
+
+    // Taking ownership is the one step that must not race another claim; the team only
+    // follows for whoever won.
+    let user_storage = state.as_user_storage();
+    let claimed = user_storage
+        .claim_org(org.as_str(), user.name.as_str())
+        .await
+        .map_err(RegistryError::internal)?;
+    if !claimed {
+        return Err(already_claimed(org.as_str()));
+    }
+
+    let seeded = async {
+        user_storage
+            .create_team(org.as_str(), "developers", None)
+            .await?;
+        user_storage
+            .add_team_member(org.as_str(), "developers", user.name.as_str())
+            .await
+    }
+    .await;
+
+    if let Err(e) = seeded {
+        tracing::error!(error = ?e, "failed to claim org");
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "org": { "name": org, "size": 1 },
+            "user": user.name,
+            "role": OrgRole::Owner
+        })),
+    ))
+}
+
+// Team management requires being an owner or admin of the org.
+async fn can_manage_org<S>(state: &S, user: &User, org: &str) -> Result<bool, RegistryError>
+where
+    S: PolicyHolder,
+{
+    let members = org_members(state, org).await?;
+    Ok(matches!(
+        members.get(user.name.as_str()),
+        Some(OrgRole::Owner | OrgRole::Admin)
+    ))
+}
+
+#[instrument]
+async fn get_org_dist_tag_policy<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(org): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    if !org_members(&state, org.as_str())
+        .await?
+        .contains_key(user.name.as_str())
+    {
+        return Err(org_not_found(org.as_str()));
+    }
+
+    match state
+        .as_user_storage()
+        .org_dist_tag_policy(org.as_str())
+        .await
+    {
+        Ok(policy) => Ok(Json(policy.unwrap_or_default())),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+#[instrument]
+async fn put_org_dist_tag_policy<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(org): Path<String>,
+    Json(policy): Json<DistTagPolicy>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    if !can_manage_org(&state, &user, org.as_str()).await? {
+        return Err(cannot_manage_org(org.as_str()));
+    }
+
+    if let Err(e) = policy.validate() {
+        return Err(RegistryError::bad_request(e.to_string()));
+    }
+
+    state
+        .as_user_storage()
+        .set_org_dist_tag_policy(org.as_str(), policy.clone())
+        .await
+        .map_err(RegistryError::internal)?;
+
+    Ok(Json(policy))
+}
+
+#[derive(Deserialize, Debug)]
+struct CreateTeamRequest {
+    name: String,
+    description: Option<String>,
+}
+
+#[instrument]
+async fn put_org_team<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(org): Path<String>,
+    Json(payload): Json<CreateTeamRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    if !can_manage_org(&state, &user, org.as_str()).await? {
+        return Err(cannot_manage_org(org.as_str()));
+    }
+
+    match state
+        .as_user_storage()
+        .create_team(org.as_str(), payload.name.as_str(), payload.description)
+        .await
+    {
+        Ok(true) => Ok((
+            StatusCode::CREATED,
+            Json(json!({ "name": format!("{}:{}", org, payload.name) })),
+        )),
+        Ok(false) => Err(RegistryError::conflict(format!(
+            "team already exists: {}:{}",
+            org, payload.name
+        ))),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+#[instrument]
+async fn get_org_teams<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(org): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    if !org_members(&state, org.as_str())
+        .await?
+        .contains_key(user.name.as_str())
+    {
+        return Err(org_not_found(org.as_str()));
+    }
+
+    match state.as_user_storage().list_teams(org.as_str()).await {
+        Ok(teams) => Ok(Json(teams)),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+#[instrument]
+async fn delete_team<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path((org, team)): Path<(String, String)>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    if !can_manage_org(&state, &user, org.as_str()).await? {
+        return Err(cannot_manage_org(org.as_str()));
+    }
+
+    match state
+        .as_user_storage()
+        .delete_team(org.as_str(), team.as_str())
+        .await
+    {
+        Ok(true) => Ok(Json(json!({ "name": format!("{}:{}", org, team) }))),
+        Ok(false) => Err(team_not_found(org.as_str(), team.as_str())),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct TeamMemberRequest {
+    user: String,
+}
+
+#[instrument]
+async fn put_team_user<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path((org, team)): Path<(String, String)>,
+    Json(payload): Json<TeamMemberRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    if !can_manage_org(&state, &user, org.as_str()).await? {
+        return Err(cannot_manage_org(org.as_str()));
+    }
+
+    // Teams are drawn from the org's membership.
+    if !org_members(&state, org.as_str())
+        .await?
+        .contains_key(payload.user.as_str())
+    {
+        return Err(RegistryError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!(
+                "{} must join {} before joining its teams",
+                payload.user, org
+            ),
+        ));
+    }
+
+    match state
+        .as_user_storage()
+        .add_team_member(org.as_str(), team.as_str(), payload.user.as_str())
+        .await
+    {
+        Ok(true) => Ok(StatusCode::CREATED),
+        Ok(false) => Err(team_not_found(org.as_str(), team.as_str())),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+#[instrument]
+async fn delete_team_user<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path((org, team)): Path<(String, String)>,
+    Json(payload): Json<TeamMemberRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    if !can_manage_org(&state, &user, org.as_str()).await? {
+        return Err(cannot_manage_org(org.as_str()));
+    }
+
+    match state
+        .as_user_storage()
+        .remove_team_member(org.as_str(), team.as_str(), payload.user.as_str())
+        .await
+    {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(RegistryError::not_found(format!(
+            "{} is not on team {}:{}",
+            payload.user, org, team
+        ))),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+#[instrument]
+async fn get_team_users<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path((org, team)): Path<(String, String)>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    if !org_members(&state, org.as_str())
+        .await?
+        .contains_key(user.name.as_str())
+    {
+        return Err(org_not_found(org.as_str()));
+    }
+
+    match state
+        .as_user_storage()
+        .list_team_members(org.as_str(), team.as_str())
+        .await
+    {
+        Ok(Some(members)) => Ok(Json(members)),
+        Ok(None) => Err(team_not_found(org.as_str(), team.as_str())),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+fn is_valid_endpoint(endpoint: &str) -> bool {
+    reqwest::Url::parse(endpoint).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+fn invalid_endpoint() -> RegistryError {
+    RegistryError::bad_request("hook endpoints must be http or https urls")
+}
+
+fn hook_not_found(id: &str) -> RegistryError {
+    RegistryError::not_found(format!("no such hook: {}", id))
+}
+
+#[instrument(skip(payload))]
+async fn post_hook<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Json(payload): Json<NewHook>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    if !is_valid_endpoint(payload.endpoint.as_str()) {
+        return Err(invalid_endpoint());
+    }
+
+    match state
+        .as_webhooks()
+        .create_hook(user.name.as_str(), payload)
+        .await
+    {
+        Ok(hook) => Ok((StatusCode::CREATED, Json(hook))),
+        Err(e) => {
+            tracing::error!(error = ?e, "failed to create hook");
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct HooksQuery {
+    package: Option<String>,
+}
+
+#[instrument]
+async fn get_hooks<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Query(query): Query<HooksQuery>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let Ok(hooks) = state
+        .as_webhooks()
+        .list_hooks(user.name.as_str(), query.package.as_deref())
+        .await
+    else {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+    };
+
+    Ok(Json(json!({
+        "total": hooks.len(),
+        "objects": hooks,
+        "urls": {}
+    })))
+}
+
+#[instrument]
+async fn get_hook<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    match state
+        .as_webhooks()
+        .get_hook(user.name.as_str(), id.as_str())
+        .await
+    {
+        Ok(Some(hook)) => Ok(Json(hook)),
+        Ok(None) => Err(hook_not_found(id.as_str())),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+#[instrument(skip(payload))]
+async fn put_hook<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(id): Path<String>,
+    Json(payload): Json<HookUpdate>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    if payload
+        .endpoint
+        .as_deref()
+        .is_some_and(|endpoint| !is_valid_endpoint(endpoint))
+    {
+        return Err(invalid_endpoint());
+    }
+
+    match state
+        .as_webhooks()
+        .update_hook(user.name.as_str(), id.as_str(), payload)
+        .await
+    {
+        Ok(Some(hook)) => Ok(Json(hook)),
+        Ok(None) => Err(hook_not_found(id.as_str())),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+#[instrument]
+async fn delete_hook<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    match state
+        .as_webhooks()
+        .delete_hook(user.name.as_str(), id.as_str())
+        .await
+    {
+        Ok(Some(hook)) => Ok(Json(hook)),
+        Ok(None) => Err(hook_not_found(id.as_str())),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+#[instrument]
+async fn get_hook_deliveries<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    match state
+        .as_webhooks()
+        .list_deliveries(user.name.as_str(), id.as_str())
+        .await
+    {
+        Ok(Some(deliveries)) => Ok(Json(json!({
+            "total": deliveries.len(),
+            "objects": deliveries
+        }))),
+        Ok(None) => Err(hook_not_found(id.as_str())),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+#[instrument]
+async fn post_hook_delivery_replay<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    Path((id, delivery)): Path<(String, String)>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    match state
+        .as_webhooks()
+        .replay_delivery(user.name.as_str(), id.as_str(), delivery.as_str())
+        .await
+    {
+        Ok(Some(delivery)) => Ok(Json(delivery)),
+        Ok(None) => Err(RegistryError::not_found(format!(
+            "no such delivery: {}",
+            delivery
+        ))),
+        Err(e) => Err(RegistryError::internal(e)),
+    }
+}
+
+// Mirrors api.npmjs.org's `/downloads/point` response.
+#[instrument]
+async fn get_download_point<S>(
+    State(state): State<S>,
+    Path((period, pkg)): Path<(String, String)>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+    let Ok(range) = period.parse::<DownloadPeriod>() else {
+        return Err(RegistryError::bad_request(format!(
+            "invalid download period: {}",
+            period
+        )));
+    };
+
+    let downloads = state
+        .as_download_counts()
+        .total_downloads(&pkg, range)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = ?e, "failed to read download counts");
+            RegistryError::from(StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+    Ok(Json(json!({
+        "downloads": downloads,
+        "start": range.start.to_string(),
+        "end": range.end.to_string(),
+        "package": pkg.to_string()
+    })))
+}
+
+#[instrument]
+async fn get_scoped_download_point<S>(
+    State(state): State<S>,
+    Path((period, scope, pkg)): Path<(String, String, String)>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = format!("@{}/{}", scope, pkg);
+    get_download_point(State(state), Path((period, pkg))).await
+}
+
+// Mirrors api.npmjs.org's `/downloads/range` response: every day in the period is listed,
+// including the ones without downloads.
+#[instrument]
+async fn get_download_range<S>(
+    State(state): State<S>,
+    Path((period, pkg)): Path<(String, String)>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+    let Ok(range) = period.parse::<DownloadPeriod>() else {
+        return Err(RegistryError::bad_request(format!(
+            "invalid download period: {}",
+            period
+        )));
+    };
+
+    let counted: BTreeMap<_, _> = state
+        .as_download_counts()
+        .daily_downloads(&pkg, range)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = ?e, "failed to read download counts");
+            RegistryError::from(StatusCode::INTERNAL_SERVER_ERROR)
+        })?
+        .into_iter()
+        .collect();
+
+    let downloads: Vec<_> = range
+        .days()
+        .map(|day| {
+            json!({
+                "downloads": counted.get(&day).copied().unwrap_or(0),
+                "day": day.to_string()
+            })
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "start": range.start.to_string(),
+        "end": range.end.to_string(),
+        "package": pkg.to_string(),
+        "downloads": downloads
+    })))
+}
+
+#[instrument]
+async fn get_scoped_download_range<S>(
+    State(state): State<S>,
+    Path((period, scope, pkg)): Path<(String, String, String)>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = format!("@{}/{}", scope, pkg);
+    get_download_range(State(state), Path((period, pkg))).await
+}
+
+/// `npm search`, answered by the package storage (for a proxy, the upstream registry).
+#[instrument(skip(state))]
+async fn get_search<S>(
+    State(state): State<S>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<serde_json::Value>, RegistryError>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    let results = match state.as_package_storage().search(&query).await {
+        Ok(results) => results,
+        // Nothing to search, as when the upstream is switched off, is no results.
+        Err(e) if matches!(StorageError::of(&e), Some(StorageError::NotFound)) => json!({
+            "objects": [],
+            "total": 0,
+            "time": Utc::now().to_rfc3339()
+        }),
+        Err(e) => {
+            return Err(storage_error(e, || {
+                RegistryError::not_found("no search index")
+            }))
+        }
+    };
+
+    prefetch_search_results(&state, &results);
+    Ok(Json(results))
+}
+
+// Warm the cache with the top results in the background; failures only cost the warm-up.
+fn prefetch_search_results<S>(state: &S, results: &serde_json::Value)
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static,
+{
+    let limit = state.as_configurator().search_prefetch();
+    if limit == 0 {
+        return;
+    }
+
+    let tasks = state.as_tasks();
+    if tasks.running(PREFETCH_TASK) >= PREFETCH_MAX_RUNNING {
+        tracing::debug!("skipping search prefetch; earlier prefetches are still running");
+        return;
+    }
+
+    let names: Vec<PackageIdentifier> = results["objects"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|object| object["package"]["name"].as_str()?.parse().ok())
+        .take(limit)
+        .collect();
+    if names.is_empty() {
+        return;
+    }
+
+    let state = state.clone();
+    tasks.spawn(PREFETCH_TASK, async move {
+        futures::stream::iter(names)
+            .for_each_concurrent(PREFETCH_CONCURRENCY, |name| {
+                let state = &state;
+                async move {
+                    if let Err(e) = state.as_package_storage().fetch_packument(&name).await {
+                        tracing::debug!(error = ?e, package = %name, "could not prefetch");
+                    }
+                }
+            })
+            .await;
+        Ok(())
+    });
+}
+
+/// The attestations published with a version, as linked from its `dist.attestations`.
+#[instrument(skip(state))]
+async fn get_attestations<S>(
+    State(state): State<S>,
+    user: Option<Authenticated>,
+    Path(spec): Path<String>,
+) -> Result<Json<serde_json::Value>, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    // The version follows the last `@`; a scope's comes first.
+    let Some((name, version)) = spec.rsplit_once('@').filter(|(name, _)| !name.is_empty()) else {
+        return Err(RegistryError::bad_request("expected <package>@<version>"));
+    };
+    let pkg = parse_package(name)?;
+    if !can_install(&state, user.as_ref().map(|user| &user.0), &pkg).await? {
+        return Err(package_not_found(&pkg));
+    }
+
+    let attestations = state
+        .as_package_storage()
+        .fetch_attestations(&pkg, version)
+        .await
+        .map_err(|e| {
+            storage_error(e, || {
+                RegistryError::not_found(format!("no attestations for {}@{}", pkg, version))
+            })
+        })?;
+    Ok(Json(attestations))
+}
+
+/// The keys `npm audit signatures` checks `dist.signatures` with: this registry's, and the
+/// upstream's for the versions cached from it.
+#[instrument(skip(state))]
+async fn get_keys<S>(State(state): State<S>) -> Result<Json<serde_json::Value>, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let mut keys: Vec<PublicKey> = state
+        .as_configurator()
+        .signing_keys()
+        .iter()
+        .map(SigningKey::public_key)
+        .collect();
+
+    // Our own keys are still worth serving without the upstream's.
+    match state.as_package_storage().public_keys().await {
+        Ok(upstream) => {
+            for key in upstream {
+                if !keys.iter().any(|ours| ours.keyid == key.keyid) {
+                    keys.push(key);
+                }
+            }
+        }
+        Err(e) => tracing::warn!(error = ?e, "could not fetch the upstream's signing keys"),
+    }
+
+    if keys.is_empty() {
+        return Err(RegistryError::not_found(
+            "this registry does not sign packages",
+        ));
+    }
+    Ok(Json(json!({ "keys": keys })))
+}
+
+#[derive(Deserialize, Debug)]
+struct ChangesQuery {
+    since: Option<u64>,
+    limit: Option<usize>,
+    feed: Option<String>,
+    heartbeat: Option<u64>,
+    timeout: Option<u64>,
+}
+
+fn change_json(change: &PackageChange) -> serde_json::Value {
+    json!({
+        "seq": change.seq,
+        "id": change.id,
+        "changes": change.rev.iter().map(|rev| json!({ "rev": rev })).collect::<Vec<_>>()
+    })
+}
+
+// Drop changes to packages the caller couldn't install, so the feed doesn't leak their names.
+async fn visible_changes<S>(
+    state: &S,
+    user: Option<&User>,
+    changes: &[PackageChange],
+) -> Vec<serde_json::Value>
+where
+    S: PolicyHolder,
+{
+    let mut visible = Vec::with_capacity(changes.len());
+    for change in changes {
+        let Ok(pkg) = change.id.parse::<PackageIdentifier>() else {
+            continue;
+        };
+        if can_install(state, user, &pkg).await.unwrap_or(false) {
+            visible.push(change_json(change));
+        }
+    }
+    visible
+}
+
+// One line of JSON per change, polling until the server shuts down. A bare newline is sent
+// after `heartbeat` without changes so that proxies don't time the connection out.
+fn continuous_changes<S>(
+    state: S,
+    user: Option<User>,
+    since: u64,
+    limit: usize,
+    heartbeat: std::time::Duration,
+) -> impl futures::Stream<Item = Result<String, std::convert::Infallible>>
+where
+    S: PolicyHolder + Send + Sync + 'static,
+{
+    let now = tokio::time::Instant::now();
+    futures::stream::unfold(
+        (state, user, since, now),
+        move |(state, user, since, last_sent)| async move {
+            loop {
+                if state.as_tasks().is_shutting_down() {
+                    return None;
+                }
+
+                let changes = match state.as_package_storage().changes_since(since, limit).await {
+                    Ok(changes) => changes,
+                    Err(e) => {
+                        tracing::warn!(error = ?e, "ending continuous changes feed");
+                        return None;
+                    }
+                };
+
+                let now = tokio::time::Instant::now();
+                if let Some(last) = changes.last() {
+                    let next = last.seq;
+                    let mut lines = String::new();
+                    for change in visible_changes(&state, user.as_ref(), &changes).await {
+                        lines.push_str(change.to_string().as_str());
+                        lines.push('\n');
+                    }
+                    return Some((Ok(lines), (state, user, next, now)));
+                }
+
+                if now.duration_since(last_sent) >= heartbeat {
+                    return Some((Ok("\n".to_string()), (state, user, since, now)));
+                }
+
+                tokio::select! {
+                    _ = tokio::time::sleep(CHANGES_POLL_INTERVAL) => {}
+                    _ = state.as_tasks().cancelled() => {}
+                }
+            }
+        },
+    )
+}
+
+fn no_change_log() -> RegistryError {
+    RegistryError::not_implemented("this registry does not keep a change log")
+}
+
+/// A CouchDB-style changes feed, so mirrors and indexers can follow this registry the way
+/// they follow replicate.npmjs.com. Supports the `normal`, `longpoll` and `continuous` feeds.
+#[instrument(skip(state))]
+async fn get_changes<S>(
+    State(state): State<S>,
+    user: Option<Authenticated>,
+    Query(query): Query<ChangesQuery>,
+) -> Result<Response, RegistryError>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    let user = user.map(|user| user.0);
+    let since = query.since.unwrap_or(0);
+    let limit = query.limit.unwrap_or(CHANGES_LIMIT).clamp(1, CHANGES_LIMIT);
+    let feed = query.feed.as_deref().unwrap_or("normal");
+
+    let changes = match feed {
+        "normal" => state
+            .as_package_storage()
+            .changes_since(since, limit)
+            .await
+            .map_err(|_| no_change_log())?,
+
+        "longpoll" => {
+            let timeout = std::time::Duration::from_millis(
+                query.timeout.unwrap_or(CHANGES_DEFAULT_TIMEOUT_MS),
+            );
+            let deadline = tokio::time::Instant::now() + timeout;
+            loop {
+                let changes = state
+                    .as_package_storage()
+                    .changes_since(since, limit)
+                    .await
+                    .map_err(|_| no_change_log())?;
+                if !changes.is_empty()
+                    || tokio::time::Instant::now() >= deadline
+                    || state.as_tasks().is_shutting_down()
+                {
+                    break changes;
+                }
+
+                tokio::select! {
+                    _ = tokio::time::sleep(CHANGES_POLL_INTERVAL) => {}
+                    _ = state.as_tasks().cancelled() => {}
+                }
+            }
+        }
+
+        "continuous" => {
+            // Fail fast on storage that keeps no log, rather than streaming nothing forever.
+            state
+                .as_package_storage()
+                .changes_since(since, 1)
+                .await
+                .map_err(|_| no_change_log())?;
+
+            let heartbeat = std::time::Duration::from_millis(
+                query.heartbeat.unwrap_or(CHANGES_DEFAULT_HEARTBEAT_MS),
+            );
+            let stream = continuous_changes(state, user, since, limit, heartbeat);
+            return Ok((
+                [(header::CONTENT_TYPE, "application/json")],
+                StreamBody::new(stream),
+            )
+                .into_response());
+        }
+
+        _ => {
+            return Err(RegistryError::bad_request(format!(
+                "unsupported changes feed: {}",
+                feed
+            )))
+        }
+    };
+
+    // Report the last sequence number examined, even when its change was filtered out, so
+    // that consumers don't ask for it again.
+    let last_seq = changes.last().map_or(since, |change| change.seq);
+    let results = visible_changes(&state, user.as_ref(), &changes).await;
+    Ok(Json(json!({
+        "results": results,
+        "last_seq": last_seq
+    }))
+    .into_response())
+}
+
+#[derive(Deserialize, Debug)]
+struct WatchQuery {
+    /// The `_rev` the caller last saw. If the package has moved on since, we answer at once.
+    rev: Option<String>,
+    timeout: Option<u64>,
+}
+
+// Stars are kept in the packument, but nobody deploys on them.
+fn is_watched(event: &HookEvent, pkg: &PackageIdentifier) -> bool {
+    event.name == pkg.to_string()
+        && !matches!(event.event.as_str(), "package:star" | "package:unstar")
+}
+
+fn watch_json(
+    pkg: &PackageIdentifier,
+    packument: &Packument,
+    changed: bool,
+    event: Option<&HookEvent>,
+) -> serde_json::Value {
+    json!({
+        "name": pkg.to_string(),
+        "rev": packument.rev,
+        "dist-tags": dist_tags_json(packument),
+        "changed": changed,
+        "event": event
+    })
+}
+
+// Each watched event as it's published, until the server shuts down. A subscriber that fell
+// behind is sent a `resync` event, since it may have missed one of ours.
+fn watch_events<S>(
+    state: S,
+    pkg: PackageIdentifier,
+    events: tokio::sync::broadcast::Receiver<HookEvent>,
+) -> impl futures::Stream<Item = Result<Event, std::convert::Infallible>>
+where
+    S: PolicyHolder + Send + Sync + 'static,
+{
+    futures::stream::unfold(
+        (state, pkg, events),
+        |(state, pkg, mut events)| async move {
+            loop {
+                let received = tokio::select! {
+                    received = events.recv() => received,
+                    _ = state.as_tasks().cancelled() => return None,
+                };
+
+                let event = match received {
+                    Ok(event) if is_watched(&event, &pkg) => Event::default()
+                        .event(event.event.as_str())
+                        .json_data(&event)
+                        .ok(),
+                    Ok(_) => None,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                        Some(Event::default().event("resync").data(skipped.to_string()))
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+                };
+                if let Some(event) = event {
+                    return Some((Ok(event), (state, pkg, events)));
+                }
+            }
+        },
+    )
+}
+
+/// Wait for a package to change (a new version, a moved tag, ...), so that deployment
+/// automation can react to releases without polling.
+///
+/// By default this long-polls: it answers with the package's `_rev` and dist-tags once it
+/// changes, or with `"changed": false` after `timeout` milliseconds. Asking for
+/// `text/event-stream` instead streams every change as a server-sent event.
+///
+/// Only changes made through this instance of the registry are seen, and packages read
+/// through from the upstream never change here.
+#[instrument(skip(state, headers))]
+async fn watch_package<S>(
+    State(state): State<S>,
+    user: Option<Authenticated>,
+    headers: HeaderMap,
+    Path(pkg): Path<String>,
+    Query(query): Query<WatchQuery>,
+) -> Result<Response, RegistryError>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+    if !can_install(&state, user.as_ref().map(|user| &user.0), &pkg).await? {
+        return Err(package_not_found(&pkg));
+    }
+
+    // Subscribe before reading the packument, so a change between the two isn't missed.
+    let mut events = state.as_events().subscribe();
+    let packument = state
+        .as_package_storage()
+        .fetch_packument(&pkg)
+        .await
+        .map_err(|e| storage_error(e, || package_not_found(&pkg)))?;
+
+    let stream = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains(EVENT_STREAM_CONTENT_TYPE));
+    if stream {
+        return Ok(Sse::new(watch_events(state, pkg, events))
+            .keep_alive(KeepAlive::default())
+            .into_response());
+    }
+
+    if query.rev.is_some() && query.rev != packument.rev {
+        return Ok(Json(watch_json(&pkg, &packument, true, None)).into_response());
+    }
+
+    let timeout =
+        std::time::Duration::from_millis(query.timeout.unwrap_or(WATCH_DEFAULT_TIMEOUT_MS));
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    let event = loop {
+        let received = tokio::select! {
+            received = events.recv() => received,
+            _ = &mut deadline => break None,
+            _ = state.as_tasks().cancelled() => break None,
+        };
+        match received {
+            Ok(event) if is_watched(&event, &pkg) => break Some(event),
+            Ok(_) => {}
+            // Whatever we missed might have been ours; the packument will tell.
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {
+                let current = state
+                    .as_package_storage()
+                    .fetch_packument(&pkg)
+                    .await
+                    .map_err(|e| storage_error(e, || package_not_found(&pkg)))?;
+                if current.rev != packument.rev {
+                    return Ok(Json(watch_json(&pkg, &current, true, None)).into_response());
+                }
+            }
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break None,
+        }
+    };
+
+    let Some(event) = event else {
+        return Ok(Json(watch_json(&pkg, &packument, false, None)).into_response());
+    };
+    let current = state
+        .as_package_storage()
+        .fetch_packument(&pkg)
+        .await
+        .map_err(|e| storage_error(e, || package_not_found(&pkg)))?;
+    Ok(Json(watch_json(&pkg, &current, true, Some(&event))).into_response())
+}
+
+/// Lets peers (like a syncing secondary) discover which optional APIs we serve.
+async fn get_capabilities() -> impl IntoResponse {
+    Json(json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "features": [
+            "abbreviated-packuments",
+            "access",
+            "attestations",
+            "bulk-advisories",
+            "changes",
+            "downloads",
+            "hooks",
+            "install-report",
+            "lockfile-verify",
+            "logins",
+            "metadata-patch",
+            "openapi",
+            "orgs",
+            "package-history",
+            "packument-batch",
+            "platform-variants",
+            "preview-tokens",
+            "quarantine",
+            "search",
+            "signatures",
+            "tarball-publish",
+            "teams",
+            "tokens",
+            "unpublish",
+            "user-export",
+            "watch"
+        ],
+        // Clients whose installs, publishes and logins are known to work against us.
+        "clients": ["npm", "pnpm", "yarn"]
+    }))
+}
+
+#[derive(Deserialize, Debug)]
+struct AdminWhoamiQuery {
+    scope: Option<String>,
+}
+
+/// Lets automation check its credentials (and, with `?scope=`, a particular scope) before
+/// it relies on them.
+#[instrument]
+async fn admin_whoami(
+    Query(query): Query<AdminWhoamiQuery>,
+    admin: Admin,
+) -> Result<impl IntoResponse, RegistryError> {
+    if let Some(ref scope) = query.scope {
+        admin.require_scope(scope.as_str())?;
+    }
+
+    let Admin {
+        principal,
+        payload: (),
+    } = admin;
+    let scopes = match principal {
+        AdminPrincipal::User(_) => vec!["*".to_string()],
+        AdminPrincipal::Key(_, ref key) => key.scopes.clone(),
+    };
+
+    Ok(Json(json!({
+        "principal": principal.name(),
+        "scopes": scopes
+    })))
+}
+
+/// Background tasks with their health and last run, for operators.
+#[instrument(skip(state))]
+async fn get_admin_tasks<S>(
+    State(state): State<S>,
+    admin: Admin,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("tasks:read")?;
+
+    let tasks = state.as_tasks();
+    Ok(Json(json!({
+        "shuttingDown": tasks.is_shutting_down(),
+        "tasks": tasks.snapshot()
+    })))
+}
+
+#[derive(Deserialize, Debug)]
+struct QuarantineRequest {
+    reason: String,
+    /// Quarantine only this version, rather than the whole package.
+    version: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct QuarantineQuery {
+    version: Option<String>,
+}
+
+/// Everything currently quarantined.
+#[instrument(skip(state))]
+async fn get_admin_quarantines<S>(
+    State(state): State<S>,
+    admin: Admin,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("quarantine")?;
+
+    let quarantines = state
+        .as_moderation()
+        .list_quarantines()
+        .await
+        .map_err(RegistryError::internal)?;
+    Ok(Json(json!({ "quarantines": quarantines })))
+}
+
+/// Withhold a package, or one version of it, from installs while a report against it is
+/// looked into. Installs get a 403 saying why; nothing is deleted.
+#[instrument(skip(state))]
+async fn put_admin_quarantine<S>(
+    State(state): State<S>,
+    ClientIp(ip): ClientIp,
+    Path(pkg): Path<String>,
+    admin: Admin<QuarantineRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("quarantine")?;
+    let pkg = parse_package(pkg.as_str())?;
+    let QuarantineRequest { reason, version } = admin.payload;
+    if reason.trim().is_empty() {
+        return Err(RegistryError::bad_request("a quarantine needs a reason"));
+    }
+
+    let quarantine = Quarantine {
+        package: pkg.to_string(),
+        version,
+        reason,
+        by: admin.principal.name(),
+        created: Utc::now(),
+    };
+    state
+        .as_moderation()
+        .quarantine(quarantine.clone())
+        .await
+        .map_err(RegistryError::internal)?;
+
+    record_package_event(
+        &state,
+        PackageEvent {
+            package: quarantine.package.clone(),
+            action: "package.quarantine".to_string(),
+            actor: quarantine.by.clone(),
+            version: quarantine.version.clone(),
+            tag: None,
+            ip,
+            country: None,
+            time: quarantine.created,
+        },
+    )
+    .await;
+    Ok((StatusCode::CREATED, Json(quarantine)))
+}
+
+/// Lift a quarantine, on the whole package or with `?version=` on one version of it.
+#[instrument(skip(state))]
+async fn delete_admin_quarantine<S>(
+    State(state): State<S>,
+    ClientIp(ip): ClientIp,
+    Path(pkg): Path<String>,
+    Query(query): Query<QuarantineQuery>,
+    admin: Admin,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("quarantine")?;
+    let pkg = parse_package(pkg.as_str())?;
+
+    let Some(lifted) = state
+        .as_moderation()
+        .lift_quarantine(&pkg, query.version.as_deref())
+        .await
+        .map_err(RegistryError::internal)?
+    else {
+        return Err(RegistryError::not_found(match query.version {
+            Some(version) => format!("{}@{} is not quarantined", pkg, version),
+            None => format!("{} is not quarantined", pkg),
+        }));
+    };
+
+    record_package_event(
+        &state,
+        PackageEvent {
+            package: lifted.package.clone(),
+            action: "package.quarantine.lift".to_string(),
+            actor: admin.principal.name(),
+            version: lifted.version.clone(),
+            tag: None,
+            ip,
+            country: None,
+            time: Utc::now(),
+        },
+    )
+    .await;
+    Ok(Json(lifted))
+}
+
+#[derive(Debug, Deserialize)]
+struct CachePurgeQuery {
+    version: Option<String>,
+}
+
+/// Evict a package's cached packument, or with `?version=` one of its cached tarballs, so
+/// the next request fetches it afresh. Anything published here is kept.
+#[instrument(skip(state))]
+async fn delete_admin_cache<S>(
+    State(state): State<S>,
+    ClientIp(ip): ClientIp,
+    Path(pkg): Path<String>,
+    Query(query): Query<CachePurgeQuery>,
+    admin: Admin,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("purge")?;
+    let pkg = parse_package(pkg.as_str())?;
+
+    let storage = state.as_package_storage();
+    match query.version.as_deref() {
+        Some(version) => storage.invalidate_tarball(&pkg, version).await,
+        None => storage.invalidate(&pkg).await,
+    }
+    .map_err(RegistryError::internal)?;
+
+    record_package_event(
+        &state,
+        PackageEvent {
+            package: pkg.to_string(),
+            action: "package.cache.purge".to_string(),
+            actor: admin.principal.name(),
+            version: query.version,
+            tag: None,
+            ip,
+            country: None,
+            time: Utc::now(),
+        },
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct CacheWarmRequest {
+    /// Names, each optionally with a version: `left-pad`, `@types/node@20.11.0`.
+    packages: Vec<String>,
+    /// A `package-lock.json`, whose locked versions are all warmed.
+    lockfile: Option<serde_json::Value>,
+}
+
+// Package names, each with the versions whose tarballs to fetch. With none, the tarball of
+// `latest` is fetched.
+type WarmTargets = BTreeMap<String, std::collections::BTreeSet<String>>;
+
+// Only versions fetched from a registry; git, file and linked dependencies aren't ours.
+fn add_locked(targets: &mut WarmTargets, name: &str, entry: &serde_json::Value) {
+    if entry["link"].as_bool() == Some(true) {
+        return;
+    }
+    if let Some(resolved) = entry["resolved"].as_str() {
+        if !resolved.contains("/-/") {
+            return;
+        }
+    }
+    let Some(version) = entry["version"].as_str() else {
+        return;
+    };
+    // Aliases (`"foo": "npm:bar@^1"`) lock the real name, in `name` or in the version.
+    let (name, version) = match version.strip_prefix("npm:") {
+        Some(spec) => match spec.rsplit_once('@') {
+            Some((name, version)) if !name.is_empty() => (name, version),
+            _ => return,
+        },
+        None => (entry["name"].as_str().unwrap_or(name), version),
+    };
+    if semver::Version::parse(version).is_ok() {
+        targets
+            .entry(name.to_string())
+            .or_default()
+            .insert(version.to_string());
+    }
+}
+
+// Lockfile v1 nests `dependencies` within each other.
+fn add_locked_dependencies(targets: &mut WarmTargets, dependencies: &serde_json::Value) {
+    for (name, entry) in dependencies.as_object().into_iter().flatten() {
+        add_locked(targets, name, entry);
+        add_locked_dependencies(targets, &entry["dependencies"]);
+    }
+}
+
+fn warm_targets(request: CacheWarmRequest) -> Result<WarmTargets, RegistryError> {
+    let mut targets = WarmTargets::new();
+    for spec in &request.packages {
+        let (name, version) = match spec.rsplit_once('@') {
+            Some((name, version)) if !name.is_empty() => (name, Some(version)),
+            _ => (spec.as_str(), None),
+        };
+        parse_package(name)?;
+        let versions = targets.entry(name.to_string()).or_default();
+        versions.extend(version.map(str::to_string));
+    }
+
+    if let Some(lockfile) = request.lockfile {
+        // Lockfile v2 and v3 list every installed package by its path under node_modules.
+        let packages = lockfile["packages"].as_object().into_iter().flatten();
+        for (path, entry) in packages {
+            if let Some((_, name)) = path.rsplit_once("node_modules/") {
+                add_locked(&mut targets, name, entry);
+            }
+        }
+        if lockfile.get("packages").is_none() {
+            add_locked_dependencies(&mut targets, &lockfile["dependencies"]);
+        }
+    }
+
+    targets.retain(|name, _| name.parse::<PackageIdentifier>().is_ok());
+    if targets.len() > WARM_LIMIT {
+        return Err(RegistryError::bad_request(format!(
+            "at most {} packages may be warmed at once",
+            WARM_LIMIT
+        )));
+    }
+    Ok(targets)
+}
+
+// Fetch a package's packuments, and the tarballs of `versions` (or of `latest`), into the
+// cache.
+async fn warm_package<S>(
+    state: &S,
+    pkg: &PackageIdentifier,
+    versions: &std::collections::BTreeSet<String>,
+) -> anyhow::Result<()>
+where
+    S: PolicyHolder,
+{
+    let storage = state.as_package_storage();
+    storage.packument_metadata(pkg).await?;
+    storage.abbreviated_packument_metadata(pkg).await?;
+
+    let latest;
+    let versions: Vec<&str> = if versions.is_empty() {
+        latest = storage.resolve_version(pkg, "latest").await?;
+        latest
+            .as_ref()
+            .and_then(|manifest| manifest.meta.get("version"))
+            .and_then(|version| version.as_str())
+            .into_iter()
+            .collect()
+    } else {
+        versions.iter().map(String::as_str).collect()
+    };
+    for version in versions {
+        storage.tarball_metadata(pkg, version).await?;
+    }
+    Ok(())
+}
+
+async fn warm_cache<S>(state: &S, targets: WarmTargets, by: String) -> anyhow::Result<()>
+where
+    S: PolicyHolder,
+{
+    let packages = targets.len();
+    let failed = futures::stream::iter(targets)
+        .map(|(name, versions)| async move {
+            let Ok(pkg) = name.parse::<PackageIdentifier>() else {
+                return 1;
+            };
+            match warm_package(state, &pkg, &versions).await {
+                Ok(()) => 0,
+                Err(e) => {
+                    tracing::warn!(error = ?e, package = %pkg, "could not warm package");
+                    1
+                }
+            }
+        })
+        .buffer_unordered(WARM_CONCURRENCY)
+        .fold(0, |failed, one| async move { failed + one })
+        .await;
+
+    tracing::info!(
+        target: "audit",
+        action = "package.cache.warm",
+        by,
+        packages,
+        failed
+    );
+    Ok(())
+}
+
+/// Fetch packages into the cache ahead of their first install, so that a new mirror can be
+/// primed before it's put in rotation. Takes package names, a `package-lock.json` or both;
+/// runs in the background, and the audit log has how many couldn't be fetched.
+#[instrument(skip(state, admin))]
+async fn post_admin_cache_warm<S>(
+    State(state): State<S>,
+    admin: Admin<CacheWarmRequest>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    admin.require_scope("warm")?;
+    let by = admin.principal.name();
+    let targets = warm_targets(admin.payload)?;
+    let packages = targets.len();
+
+    // The admin asked for these; packages that require auth are theirs to warm.
+    let warm = state.clone();
+    state.as_tasks().spawn(
+        CACHE_WARM_TASK,
+        guard::authenticated(async move { warm_cache(&warm, targets, by).await }),
+    );
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "ok": true, "task": CACHE_WARM_TASK, "packages": packages })),
+    ))
+}
+
+/// Remove tarballs that no version of their package refers to, and cache entries left
+/// behind without their content (or content without its entry), reporting the bytes
+/// reclaimed.
+#[instrument(skip(state))]
+async fn post_admin_cache_gc<S>(
+    State(state): State<S>,
+    admin: Admin,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("purge")?;
+    let collected = state
+        .as_package_storage()
+        .collect_garbage()
+        .await
+        .map_err(RegistryError::internal)?;
+
+    tracing::info!(
+        target: "audit",
+        action = "cache.gc",
+        by = admin.principal.name(),
+        orphaned_tarballs = collected.orphaned_tarballs,
+        dangling_entries = collected.dangling_entries,
+        reclaimed = collected.reclaimed
+    );
+    Ok(Json(collected))
+}
+
+/// How many tarballs are stored, and how many bytes storing identical ones once saves.
+#[instrument(skip(state))]
+async fn get_admin_cache_stats<S>(
+    State(state): State<S>,
+    admin: Admin,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("stats")?;
+    let stats = state
+        .as_package_storage()
+        .tarball_stats()
+        .await
+        .map_err(RegistryError::internal)?;
+
+    Ok(Json(json!({
+        "tarballs": stats.tarballs,
+        "blobs": stats.blobs,
+        "size": stats.size,
+        "stored": stats.stored,
+        "saved": stats.saved()
+    })))
+}
+
+/// Every user, with their org roles and team memberships, for importing into another
+/// registry.
+#[instrument(skip(state))]
+async fn get_admin_users_export<S>(
+    State(state): State<S>,
+    admin: Admin,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("users:read")?;
+
+    let export = state
+        .as_user_storage()
+        .export_users()
+        .await
+        .map_err(RegistryError::internal)?;
+    tracing::info!(
+        target: "audit",
+        action = "users.export",
+        by = admin.principal.name(),
+        users = export.users.len()
+    );
+    Ok(Json(export))
+}
+
+/// Import users exported from another registry. What the export names replaces what's
+/// here; everything else is left alone.
+#[instrument(skip(state, admin))]
+async fn post_admin_users_import<S>(
+    State(state): State<S>,
+    admin: Admin<UserExport>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("users:write")?;
+    let export = admin.payload;
+    if let Some(key) = export.teams.keys().find(|key| !key.contains(':')) {
+        return Err(RegistryError::bad_request(format!(
+            "teams must be named org:team, not {}",
+            key
+        )));
+    }
+
+    let counts = json!({
+        "users": export.users.len(),
+        "orgs": export.orgs.len(),
+        "teams": export.teams.len()
+    });
+    state
+        .as_user_storage()
+        .import_users(export)
+        .await
+        .map_err(RegistryError::internal)?;
+    tracing::info!(
+        target: "audit",
+        action = "users.import",
+        by = admin.principal.name(),
+        %counts
+    );
+    Ok(Json(json!({ "ok": true, "imported": counts })))
+}
+
+// Sign every version of `pkg` that has no signatures at all. Versions signed by anyone,
+// including an upstream, are left as they are.
+async fn backfill_package_signatures<S>(
+    state: &S,
+    key: &SigningKey,
+    pkg: &PackageIdentifier,
+) -> anyhow::Result<usize>
+where
+    S: PolicyHolder,
+{
+    let storage = state.as_package_storage();
+    let mut packument = storage.fetch_packument(pkg).await?;
+    let mut signed = 0;
+    for version in packument
+        .versions
+        .iter_mut()
+        .flat_map(|versions| versions.values_mut())
+    {
+        if version
+            .dist
+            .signatures
+            .as_ref()
+            .is_some_and(|sigs| !sigs.is_empty())
+        {
+            continue;
+        }
+        if let Some(signature) = version_signature(key, pkg, version)? {
+            version.dist.signatures = Some(vec![signature]);
+            signed += 1;
+        }
+    }
+
+    if signed > 0 {
+        storage.update_packument(pkg, &mut packument).await?;
+    }
+    Ok(signed)
+}
+
+async fn backfill_signatures<S>(state: &S, by: String) -> anyhow::Result<()>
+where
+    S: PolicyHolder,
+{
+    let Some(key) = active_key(state.as_configurator().signing_keys(), Utc::now()) else {
+        return Ok(());
+    };
+
+    let (mut signed, mut failed) = (0, 0);
+    for pkg in state.as_package_storage().list_packages().await? {
+        match backfill_package_signatures(state, key, &pkg).await {
+            Ok(count) => signed += count,
+            Err(e) => {
+                tracing::warn!(error = ?e, package = %pkg, "could not backfill signatures");
+                failed += 1;
+            }
+        }
+    }
+
+    tracing::info!(
+        target: "audit",
+        action = "package.signatures.backfill",
+        by,
+        keyid = key.key_id(),
+        signed,
+        failed
+    );
+    Ok(())
+}
+
+/// Sign the versions published before a signing key was configured. Runs in the background;
+/// `/-/admin/tasks` shows when it's done and the audit log how many versions it signed.
+#[instrument(skip(state))]
+async fn post_admin_signatures_backfill<S>(
+    State(state): State<S>,
+    admin: Admin,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    admin.require_scope("signatures")?;
+    if active_key(state.as_configurator().signing_keys(), Utc::now()).is_none() {
+        return Err(RegistryError::conflict("no signing key is configured"));
+    }
+
+    let tasks = state.as_tasks();
+    if tasks.running(BACKFILL_TASK) > 0 {
+        return Err(RegistryError::conflict("a backfill is already running"));
+    }
+
+    let by = admin.principal.name();
+    let backfill = state.clone();
+    tasks.spawn(BACKFILL_TASK, async move {
+        backfill_signatures(&backfill, by).await
+    });
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "ok": true, "task": BACKFILL_TASK })),
+    ))
+}
+
+// Delete the prereleases of `pkg` published before `cutoff`, and then their tarballs. Returns
+// the versions deleted.
+async fn prune_package_previews<S>(
+    state: &S,
+    pkg: &PackageIdentifier,
+    cutoff: DateTime<Utc>,
+    by: &str,
+) -> anyhow::Result<Vec<String>>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static,
+{
+    let storage = state.as_package_storage();
+    let mut packument = storage.fetch_packument(pkg).await?;
+    let expired: Vec<String> = packument
+        .time
+        .iter()
+        .flat_map(|time| time.versions.iter())
+        .filter(|(number, published)| {
+            **published < cutoff
+                && packument
+                    .versions
+                    .as_ref()
+                    .is_some_and(|versions| versions.contains_key(*number))
+                && semver::Version::parse(number).is_ok_and(|number| !number.pre.is_empty())
+        })
+        .map(|(number, _)| number.clone())
+        .collect();
+    if expired.is_empty() {
+        return Ok(expired);
+    }
+
+    let modification = PackageModification::RemoveVersions(expired.clone());
+    let event = hook_event(pkg, &modification);
+    packument.apply(modification)?;
+    storage.update_packument(pkg, &mut packument).await?;
+
+    // The document no longer lists them, so a tarball left behind is only wasted space.
+    for number in &expired {
+        if let Err(e) = storage.delete_tarball(pkg, number).await {
+            tracing::warn!(error = ?e, package = %pkg, version = number, "could not delete preview tarball");
+        }
+        record_package_event(
+            state,
+            PackageEvent {
+                package: pkg.to_string(),
+                action: "package.unpublish".to_string(),
+                actor: by.to_string(),
+                version: Some(number.clone()),
+                tag: None,
+                ip: None,
+                country: None,
+                time: Utc::now(),
+            },
+        )
+        .await;
+    }
+
+    announce(state, event, &packument);
+    Ok(expired)
+}
+
+/// Delete the preview versions published longer ago than the configured retention, with
+/// their tarballs, so that the preview scope doesn't grow without bound. Releases published
+/// to the scope are kept. Returns how many versions were deleted.
+pub async fn prune_previews<S>(state: &S, by: String) -> anyhow::Result<usize>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static,
+{
+    let configurator = state.as_configurator();
+    let Some(scope) = configurator.preview_scope() else {
+        return Ok(0);
+    };
+    let cutoff = Utc::now() - chrono::Duration::from_std(configurator.preview_retention())?;
+
+    let (mut pruned, mut failed) = (0, 0);
+    for pkg in state.as_package_storage().list_packages().await? {
+        if pkg.scope.as_deref() != Some(scope) {
+            continue;
+        }
+        match prune_package_previews(state, &pkg, cutoff, by.as_str()).await {
+            Ok(versions) => pruned += versions.len(),
+            Err(e) => {
+                tracing::warn!(error = ?e, package = %pkg, "could not prune preview versions");
+                failed += 1;
+            }
+        }
+    }
+
+    tracing::info!(
+        target: "audit",
+        action = "package.previews.prune",
+        by,
+        scope,
+        cutoff = %cutoff,
+        pruned,
+        failed
+    );
+    Ok(pruned)
+}
+
+/// Delete expired preview versions now, rather than waiting for the next scheduled pass.
+/// Runs in the background; the audit log has what was deleted.
+#[instrument(skip(state))]
+async fn post_admin_previews_prune<S>(
+    State(state): State<S>,
+    admin: Admin,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    admin.require_scope("preview")?;
+    if state.as_configurator().preview_scope().is_none() {
+        return Err(RegistryError::conflict("no preview scope is configured"));
+    }
+
+    let tasks = state.as_tasks();
+    if tasks.running(PREVIEW_PRUNE_TASK) > 0 {
+        return Err(RegistryError::conflict("a prune is already running"));
+    }
+
+    let by = admin.principal.name();
+    let prune = state.clone();
+    tasks.spawn(PREVIEW_PRUNE_TASK, async move {
+        prune_previews(&prune, by).await.map(|_| ())
+    });
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "ok": true, "task": PREVIEW_PRUNE_TASK })),
+    ))
+}
+
+#[derive(Deserialize, Debug)]
+struct PreviewTokenRequest {
+    /// Seconds the token should last, up to the configured maximum.
+    ttl: Option<u64>,
+}
+
+/// Mint a token that may only publish prereleases under the preview scope, for CI to publish
+/// previews of pull requests with. It belongs to no one, and expires on its own.
+#[instrument(skip(state))]
+async fn post_admin_preview_token<S>(
+    State(state): State<S>,
+    admin: Admin<Option<PreviewTokenRequest>>,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    admin.require_scope("preview")?;
+    let configurator = state.as_configurator();
+    let Some(scope) = configurator.preview_scope() else {
+        return Err(RegistryError::conflict("no preview scope is configured"));
+    };
+
+    let longest = configurator.preview_token_ttl();
+    let ttl = admin
+        .payload
+        .and_then(|request| request.ttl)
+        .map_or(longest, |secs| {
+            std::time::Duration::from_secs(secs).min(longest)
+        });
+    let expires = Utc::now() + chrono::Duration::from_std(ttl).map_err(RegistryError::internal)?;
+
+    let user = User {
+        name: PREVIEW_PUBLISHER.to_string(),
+        email: String::new(),
+        full_name: None,
+        homepage: None,
+        freenode: None,
+        twitter: None,
+    };
+    let options = TokenOptions {
+        expires: Some(expires),
+        preview: true,
+        ..Default::default()
+    };
+    let (token, metadata) = state
+        .as_token_authorizer()
+        .create_token(user, options)
+        .await
+        .map_err(RegistryError::internal)?;
+
+    tracing::info!(
+        target: "audit",
+        action = "token.preview.create",
+        by = admin.principal.name(),
+        key = metadata.key,
+        expires = %expires
+    );
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "token": token.to_string(),
+            "key": metadata.key,
+            "scope": format!("@{}", scope),
+            "expires": expires
+        })),
+    ))
+}
+
+/// The caller's username and, when they sent a token, what that token is limited to, using
+/// the types `npm token list` shows. Each limit reported is one the extractors enforce.
+#[instrument(skip(headers))]
+async fn whoami<S>(
+    State(state): State<S>,
+    Authenticated(user): Authenticated,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, RegistryError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let mut body = json!({
+        "username": user.name
+    });
+
+    let options = match bearer_token(&headers) {
+        Some(bearer) => state
+            .as_token_authorizer()
+            .token_options(&bearer)
+            .await
+            .map_err(RegistryError::internal)?,
+        None => None,
+    };
+    if let Some(options) = options {
+        body["token"] = json!({
+            "key": current_session_key(&state, &headers).await,
+            "type": if options.readonly { "read-only" } else { "publish" },
+            "readonly": options.readonly,
+            "cidr_whitelist": options.cidr_whitelist,
+            "expires": options.expires
+        });
+    }
+    Ok(Json(body))
+}
+
+// Logging in has to work without a token, and the admin and SCIM endpoints accept signed
+// requests in place of one.
+fn exempt_from_auth(path: &str) -> bool {
+    [
+        "/-/v1/login",
+        "/-/admin/",
+        "/-/scim/",
+        "/-/capabilities",
+        "/-/openapi.json",
+    ]
+    .iter()
+    .any(|prefix| path.starts_with(prefix))
+}
+
+// Turns away anonymous requests when the configurator requires a token for everything, and
+// marks requests with a valid token as authenticated, for packages a `GuardStorage` keeps
+// from anonymous readers.
+async fn require_auth<S, B>(State(state): State<S>, request: Request<B>, next: Next<B>) -> Response
+where
+    S: PolicyHolder + Send + Sync,
+{
+    let required =
+        state.as_configurator().require_auth() && !exempt_from_auth(request.uri().path());
+    if !required && !request.headers().contains_key(header::AUTHORIZATION) {
+        return next.run(request).await;
+    }
+
+    // Any valid token will do here, preview and read-only tokens included; the handlers decide
+    // what it's for. A bad token on a route that doesn't need one is left for the handler to
+    // reject.
+    let (parts, body) = request.into_parts();
+    match authenticate(&parts, &state).await {
+        Ok(_) => guard::authenticated(next.run(Request::from_parts(parts, body))).await,
+        Err(rejection) if required => rejection.into_response(),
+        Err(_) => next.run(Request::from_parts(parts, body)).await,
+    }
+}
+
+// Browsers only get CORS headers from origins the configurator lists; with none listed,
+// there's no layer at all. Tokens go in `Authorization` rather than cookies, so credentials
+// are never allowed.
+fn cors_layer<C: Configurator>(configurator: &C) -> Option<CorsLayer> {
+    let origins = configurator.cors_origins();
+    if origins.is_empty() {
+        return None;
+    }
+
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().filter_map(|origin| {
+            origin
+                .parse()
+                .map_err(|_| tracing::warn!(origin, "ignoring invalid CORS origin"))
+                .ok()
+        }))
+    };
+
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods(configurator.cors_methods())
+            .allow_headers([
+                header::ACCEPT,
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                header::IF_NONE_MATCH,
+            ])
+            .expose_headers([header::ETAG])
+            .max_age(std::time::Duration::from_secs(3600)),
+    )
+}
+
+async fn handle_decompression_error(_err: BoxError) -> RegistryError {
+    RegistryError::bad_request("could not decompress request body")
+}
+
+pub fn routes<S, B>(state: S) -> Router<(), B>
+where
+    S: PolicyHolder + Clone + Sync + Send + 'static + std::fmt::Debug,
+    B: Sync + Send + HttpBody + std::fmt::Debug + Into<Body> + 'static,
+    <B as HttpBody>::Data: 'static + Send + Sync,
+    <B as HttpBody>::Error: std::error::Error + 'static + Send + Sync,
+{
+    let router = ApiRouter::new()
+        .route(
+            "/@:scope/:pkg/-/*tarball",
+            get(
+                get_scoped_tarball::<S>,
+                "Download a scoped package's tarball",
+            )
+            .head(head_scoped_tarball::<S>, "Check a scoped package's tarball"),
+        )
+        .route(
+            "/@:scope/:pkg",
+            get(get_scoped_packument::<S>, "Fetch a scoped packument")
+                .map(|router| router.layer(ServiceBuilder::new().layer(CompressionLayer::new())))
+                .head(head_scoped_packument::<S>, "Check a scoped packument")
+                .put(
+                    put_scoped_packument::<S>,
+                    "Publish or modify a scoped package",
+                ),
+        )
+        .route(
+            "/:pkg",
+            get(get_packument::<S>, "Fetch a packument")
+                .map(|router| router.layer(ServiceBuilder::new().layer(CompressionLayer::new())))
+                .head(head_packument::<S>, "Check a packument")
+                .put(put_packument::<S>, "Publish or modify a package"),
+        )
+        .route(
+            "/:pkg/-rev/:rev",
+            put(put_packument_at_rev::<S>, "Modify a package at a revision")
+                .delete(delete_packument_at_rev::<S>, "Unpublish a package"),
+        )
+        .route(
+            "/@:scope/:pkg/-rev/:rev",
+            put(
+                put_scoped_packument_at_rev::<S>,
+                "Modify a scoped package at a revision",
+            )
+            .delete(
+                delete_scoped_packument_at_rev::<S>,
+                "Unpublish a scoped package",
+            ),
+        )
+        .route(
+            "/:pkg/:version",
+            get(get_version_manifest::<S>, "Fetch a version manifest"),
+        )
+        .route(
+            "/@:scope/:pkg/:version",
+            get(
+                get_scoped_version_manifest::<S>,
+                "Fetch a scoped version manifest",
+            ),
+        )
+        .route(
+            "/:pkg/-/*tarball",
+            get(get_tarball::<S>, "Download a tarball").head(head_tarball::<S>, "Check a tarball"),
+        )
+        .route("/-/v1/login", post(post_login::<S, B>, "Start a web login"))
+        .route(
+            "/-/v1/login/poll/:session",
+            get(get_login_poll::<S>, "Poll a web login for its token"),
+        )
+        .route(
+            "/-/v1/login/www/:session",
+            any(www_login::<S, B>, "Complete a web login"),
+        )
+        .route(
+            "/-/v1/login/www/",
+            any(www_login::<S, B>, "Complete a web login"),
+        )
+        .route(
+            "/-/npm/v1/tokens",
+            get(get_tokens::<S>, "List the caller's tokens")
+                .post(post_token::<S>, "Create a token"),
+        )
+        .route(
+            "/-/npm/v1/tokens/token/:key",
+            delete(delete_token::<S>, "Revoke a token"),
+        )
+        .route(
+            "/-/npm/v1/user/logins",
+            get(get_logins::<S>, "List the caller's logins")
+                .delete(delete_other_logins::<S>, "Sign out everywhere else"),
+        )
+        .route(
+            "/-/npm/v1/user/logins/:key",
+            delete(delete_login::<S>, "End a login"),
+        )
+        .route(
+            "/-/npm/v1/user",
+            get(get_profile::<S>, "Fetch the caller's profile")
+                .post(post_profile::<S>, "Update the caller's profile"),
+        )
+        .route(
+            "/-/user/org.couchdb.user:user",
+            get(get_user::<S>, "Fetch a user"),
+        )
+        .route(
+            "/-/_view/starredByUser",
+            get(get_starred_by_user::<S>, "List the packages a user starred"),
+        )
+        // npm gzips audit bodies.
+        .route(
+            "/-/npm/v1/security/audits",
+            post(post_audit::<S>, "Audit a dependency tree").map(|router| {
+                router.layer(
+                    ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(handle_decompression_error))
+                        .layer(RequestDecompressionLayer::new()),
+                )
+            }),
+        )
+        .route(
+            "/-/npm/v1/security/advisories/bulk",
+            post(
+                post_bulk_advisories::<S>,
+                "Fetch advisories for package versions",
+            )
+            .map(|router| {
+                router.layer(
+                    ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(handle_decompression_error))
+                        .layer(RequestDecompressionLayer::new()),
+                )
+            }),
+        )
+        .route(
+            "/-/npm/v1/security/audits/quick",
+            post(post_quick_audit::<S>, "Audit a dependency tree quickly").map(|router| {
+                router.layer(
+                    ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(handle_decompression_error))
+                        .layer(RequestDecompressionLayer::new()),
+                )
+            }),
+        )
+        .route(
+            "/-/npm/v1/lockfile/verify",
+            post(
+                post_verify_lockfile::<S>,
+                "Check that a lockfile can be installed",
+            )
+            .map(|router| {
+                router.layer(
+                    ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(handle_decompression_error))
+                        .layer(RequestDecompressionLayer::new()),
+                )
+            }),
+        )
+        .route(
+            "/-/npm/v1/lockfile/report",
+            post(
+                post_install_report::<S>,
+                "Record and sign what an install fetched",
+            )
+            .map(|router| {
+                router.layer(
+                    ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(handle_decompression_error))
+                        .layer(RequestDecompressionLayer::new()),
+                )
+            }),
+        )
+        .route(
+            "/-/package/:pkg/access",
+            get(get_package_access::<S>, "Fetch a package's access settings").post(
+                post_package_access::<S>,
+                "Change a package's access settings",
+            ),
+        )
+        .route(
+            "/-/package/:pkg/collaborators",
+            get(
+                get_package_collaborators::<S>,
+                "List a package's collaborators",
+            ),
+        )
+        .route(
+            "/-/package/:pkg/transfer",
+            get(
+                get_package_transfer::<S>,
+                "Fetch a package's pending transfer",
+            )
+            .put(
+                put_package_transfer::<S>,
+                "Offer a package to another owner",
+            )
+            .delete(
+                delete_package_transfer::<S>,
+                "Withdraw or decline a transfer",
+            ),
+        )
+        .route(
+            "/-/package/:pkg/transfer/accept",
+            post(post_package_transfer_accept::<S>, "Accept a transfer"),
+        )
+        .route(
+            "/-/package/:pkg/dist-tags",
+            get(get_dist_tags::<S>, "List a package's dist-tags"),
+        )
+        .route(
+            "/-/package/:pkg/platforms",
+            get(
+                get_package_platforms::<S>,
+                "List a package's platform variants",
+            ),
+        )
+        .route(
+            "/-/package/:pkg/publish",
+            put(put_package_tarball::<S, B>, "Publish a tarball"),
+        )
+        .route(
+            "/-/package/:pkg/metadata",
+            patch(patch_package_metadata::<S>, "Edit a package's metadata"),
+        )
+        .route(
+            "/-/v1/packages/:pkg/history",
+            get(get_package_history::<S>, "Fetch a package's history"),
+        )
+        .route(
+            "/-/v1/packuments:batch",
+            post(
+                post_packuments_batch::<S>,
+                "Fetch many packuments as ndjson",
+            ),
+        )
+        .route(
+            "/-/package/:pkg/dist-tags/:tag",
+            put(put_dist_tag::<S>, "Set a dist-tag")
+                .delete(delete_dist_tag::<S>, "Remove a dist-tag"),
+        )
+        .route(
+            "/-/package/list",
+            get(get_package_list::<S>, "List packages"),
+        )
+        .route(
+            "/-/transfers",
+            get(get_transfers::<S>, "List transfers awaiting the caller"),
+        )
+        .route(
+            "/-/user/:user/package",
+            get(get_user_packages::<S>, "List a user's packages"),
+        )
+        .route(
+            "/-/team/:scope/:team/package",
+            get(get_team_packages::<S>, "List a team's packages")
+                .put(put_team_package::<S>, "Grant a team access to a package")
+                .delete(
+                    delete_team_package::<S>,
+                    "Revoke a team's access to a package",
+                ),
+        )
+        .route(
+            "/-/org/:org/user",
+            get(get_org_users::<S>, "List an org's members")
+                .put(put_org_user::<S>, "Add or update an org member")
+                .delete(delete_org_user::<S>, "Remove an org member"),
+        )
+        .route(
+            "/-/org/:org/team",
+            get(get_org_teams::<S>, "List an org's teams").put(put_org_team::<S>, "Create a team"),
+        )
+        .route(
+            "/-/org/:org/claim",
+            post(post_org_claim::<S>, "Claim a scope"),
+        )
+        .route(
+            "/-/org/:org/dist-tag-policy",
+            get(
+                get_org_dist_tag_policy::<S>,
+                "Fetch an org's dist-tag policy",
+            )
+            .put(put_org_dist_tag_policy::<S>, "Set an org's dist-tag policy"),
+        )
+        .route(
+            "/-/team/:scope/:team",
+            delete(delete_team::<S>, "Delete a team"),
+        )
+        .route(
+            "/-/team/:scope/:team/user",
+            get(get_team_users::<S>, "List a team's members")
+                .put(put_team_user::<S>, "Add a team member")
+                .delete(delete_team_user::<S>, "Remove a team member"),
+        )
+        .route(
+            "/-/npm/v1/keys",
+            get(get_keys::<S>, "List the keys versions are signed with"),
+        )
+        .route(
+            "/-/npm/v1/attestations/:spec",
+            get(get_attestations::<S>, "Fetch a version's attestations"),
+        )
+        .route(
+            "/-/npm/v1/hooks",
+            get(get_hooks::<S>, "List the caller's hooks"),
+        )
+        .route(
+            "/-/npm/v1/hooks/hook",
+            post(post_hook::<S>, "Create a hook"),
+        )
+        .route(
+            "/-/npm/v1/hooks/hook/:id",
+            get(get_hook::<S>, "Fetch a hook")
+                .put(put_hook::<S>, "Update a hook")
+                .delete(delete_hook::<S>, "Delete a hook"),
+        )
+        .route(
+            "/-/npm/v1/hooks/hook/:id/deliveries",
+            get(get_hook_deliveries::<S>, "List a hook's deliveries"),
+        )
+        .route(
+            "/-/npm/v1/hooks/hook/:id/deliveries/:delivery/replay",
+            post(post_hook_delivery_replay::<S>, "Deliver an event again"),
+        )
+        .route(
+            "/downloads/point/:period/:pkg",
+            get(get_download_point::<S>, "Count a package's downloads"),
+        )
+        .route(
+            "/downloads/point/:period/@:scope/:pkg",
+            get(
+                get_scoped_download_point::<S>,
+                "Count a scoped package's downloads",
+            ),
+        )
+        .route(
+            "/downloads/range/:period/:pkg",
+            get(
+                get_download_range::<S>,
+                "Count a package's downloads by day",
+            ),
+        )
+        .route(
+            "/downloads/range/:period/@:scope/:pkg",
+            get(
+                get_scoped_download_range::<S>,
+                "Count a scoped package's downloads by day",
+            ),
+        )
+        .route(
+            "/_changes",
+            get(get_changes::<S>, "Follow the changes feed"),
+        )
+        .route(
+            "/-/v1/watch/:pkg",
+            get(watch_package::<S>, "Wait for a package to change"),
+        )
+        .route("/-/v1/search", get(get_search::<S>, "Search packages"))
+        .route(
+            "/-/capabilities",
+            get(get_capabilities, "List the optional APIs served"),
+        )
+        .route("/-/whoami", get(whoami::<S>, "Identify the caller"))
+        .route(
+            "/-/admin/whoami",
+            get(admin_whoami, "Identify an admin caller"),
+        )
+        .route(
+            "/-/admin/tasks",
+            get(get_admin_tasks::<S>, "List background tasks"),
+        )
+        .route(
+            "/-/admin/quarantine",
+            get(get_admin_quarantines::<S>, "List quarantined packages"),
+        )
+        .route(
+            "/-/admin/quarantine/:pkg",
+            put(
+                put_admin_quarantine::<S>,
+                "Withhold a package or version from installs",
+            )
+            .delete(delete_admin_quarantine::<S>, "Lift a quarantine"),
+        )
+        .route(
+            "/-/admin/cache/:pkg",
+            delete(delete_admin_cache::<S>, "Evict a package from the cache"),
+        )
+        .route(
+            "/-/admin/cache/stats",
+            get(
+                get_admin_cache_stats::<S>,
+                "Count stored tarballs and what deduplication saves",
+            ),
+        )
+        .route(
+            "/-/admin/cache/gc",
+            post(
+                post_admin_cache_gc::<S>,
+                "Remove orphaned tarballs and dangling cache entries",
+            ),
+        )
+        .route(
+            "/-/admin/cache/warm",
+            post(
+                post_admin_cache_warm::<S>,
+                "Fetch packages into the cache ahead of installs",
+            ),
+        )
+        .route(
+            "/-/admin/users/export",
+            get(get_admin_users_export::<S>, "Export every user"),
+        )
+        .route(
+            "/-/admin/users/import",
+            post(
+                post_admin_users_import::<S>,
+                "Import users exported from another registry",
+            ),
+        )
+        .route(
+            "/-/admin/signatures/backfill",
+            post(
+                post_admin_signatures_backfill::<S>,
+                "Sign versions published before a key was configured",
+            ),
+        )
+        .route(
+            "/-/admin/preview-tokens",
+            post(
+                post_admin_preview_token::<S>,
+                "Mint a token that publishes preview versions",
+            ),
+        )
+        .route(
+            "/-/admin/previews/prune",
+            post(
+                post_admin_previews_prune::<S>,
+                "Delete preview versions past their retention",
+            ),
+        )
+        .merge(super::scim::routes::<S, B>());
+
+    #[cfg(feature = "web-ui")]
+    let router = router.merge(super::web::routes::<S, B>());
+
+    let cors = cors_layer(state.as_configurator());
+    let router = router
+        .finish("/-/openapi.json")
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_auth::<S, B>,
+        ))
+        .with_state(state)
         .layer(
             ServiceBuilder::new()
                 .layer(SetSensitiveRequestHeadersLayer::new(std::iter::once(
@@ -320,5 +6226,1846 @@ where
                                 .latency_unit(LatencyUnit::Micros),
                         ),
                 ),
+        );
+
+    match cors {
+        Some(cors) => router.layer(cors),
+        None => router,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashing::{Algorithm, Digest};
+    use crate::models::Advisory;
+    use crate::policies::access_control::in_memory::InMemoryAccessControl;
+    use crate::policies::advisories::in_memory::InMemoryAdvisories;
+    use crate::policies::configurator::AdminKey;
+    use crate::policies::geolocation::in_memory::InMemoryGeolocator;
+    use crate::policies::moderation::in_memory::InMemoryModeration;
+    use crate::policies::not_implemented::NotImplemented;
+    use crate::policies::package_storage::changes::ChangeLog;
+    use crate::policies::package_storage::hot_cache::HotCache;
+    use crate::policies::package_storage::in_memory::InMemoryPackageStorage;
+    use crate::policies::package_storage::read_through::ReadThrough;
+    use crate::policies::package_storage::TestDir;
+    use crate::policies::policy::Policy;
+    use crate::policies::token_authorizer::in_memory::InMemoryTokenAuthorizer;
+    use crate::policies::user_storage::in_memory::InMemoryUserStorage;
+    use axum::body::Bytes;
+    use axum::http::Method;
+    use axum_extra::extract::cookie::Key;
+    use base64::Engine;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    #[derive(Clone, Debug, Default)]
+    struct TestConfigurator {
+        admin_users: Vec<String>,
+        unpublish_window: Duration,
+        preview_scope: Option<String>,
+        admin_keys: HashMap<String, AdminKey>,
+    }
+
+    #[async_trait::async_trait]
+    impl Configurator for TestConfigurator {
+        fn fqdn(&self) -> &str {
+            "http://registry.test"
+        }
+
+        async fn oauth_config(&self) -> anyhow::Result<(String, String)> {
+            anyhow::bail!("these tests don't log in through oauth")
+        }
+
+        async fn cookie_key(&self) -> anyhow::Result<Key> {
+            Ok(Key::generate())
+        }
+
+        fn admin_users(&self) -> &[String] {
+            self.admin_users.as_slice()
+        }
+
+        fn unpublish_window(&self) -> Duration {
+            self.unpublish_window
+        }
+
+        fn preview_scope(&self) -> Option<&str> {
+            self.preview_scope.as_deref()
+        }
+
+        fn admin_key(&self, key_id: &str) -> Option<&AdminKey> {
+            self.admin_keys.get(key_id)
+        }
+    }
+
+    type TestPolicy<P = InMemoryPackageStorage> = Policy<
+        NotImplemented,
+        InMemoryTokenAuthorizer,
+        InMemoryUserStorage,
+        P,
+        TestConfigurator,
+        InMemoryAdvisories,
+        InMemoryAccessControl,
+        NotImplemented,
+        NotImplemented,
+        InMemoryGeolocator,
+        InMemoryModeration,
+    >;
+
+    // A registry on in-memory policies, driven a request at a time through `routes`.
+    struct TestRegistry<P: PackageStorage + Send + Sync = InMemoryPackageStorage> {
+        state: TestPolicy<P>,
+    }
+
+    impl TestRegistry {
+        fn new() -> Self {
+            Self::with_configurator(TestConfigurator::default())
+        }
+
+        fn with_configurator(configurator: TestConfigurator) -> Self {
+            let state = Policy::new()
+                .with_configurator(configurator)
+                .with_token_authorizer(InMemoryTokenAuthorizer::new())
+                .with_user_storage(InMemoryUserStorage::new())
+                .with_package_storage(InMemoryPackageStorage::new())
+                .with_advisories(InMemoryAdvisories::new())
+                .with_access_control(InMemoryAccessControl::new())
+                .with_geolocator(InMemoryGeolocator::new())
+                .with_moderation(InMemoryModeration::new());
+            Self { state }
+        }
+    }
+
+    impl<P> TestRegistry<P>
+    where
+        P: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static,
+    {
+        fn with_package_storage<P1>(self, storage: P1) -> TestRegistry<P1>
+        where
+            P1: PackageStorage + Send + Sync,
+        {
+            TestRegistry {
+                state: self.state.with_package_storage(storage),
+            }
+        }
+
+        // Register `name`, and return a token for them.
+        async fn login(&self, name: &str) -> String {
+            let user = User {
+                name: name.to_string(),
+                email: format!("{}@example.com", name),
+                full_name: None,
+                homepage: None,
+                freenode: None,
+                twitter: None,
+            };
+            self.state
+                .as_user_storage()
+                .register_user(user.clone())
+                .await
+                .unwrap();
+            let token = self.state.as_token_authorizer().start_session(user);
+            token.await.unwrap().to_string()
+        }
+
+        async fn send(&self, request: Request<Body>) -> Response {
+            routes::<_, Body>(self.state.clone())
+                .oneshot(request)
+                .await
+                .unwrap()
+        }
+
+        // Send `body` as JSON, and read the response as JSON; `null` if it isn't.
+        async fn request(
+            &self,
+            method: Method,
+            uri: &str,
+            token: Option<&str>,
+            body: Option<serde_json::Value>,
+        ) -> (StatusCode, serde_json::Value) {
+            let mut request = Request::builder().method(method).uri(uri);
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            let body = match body {
+                Some(body) => {
+                    request = request.header(header::CONTENT_TYPE, "application/json");
+                    Body::from(body.to_string())
+                }
+                None => Body::empty(),
+            };
+
+            let response = self.send(request.body(body).unwrap()).await;
+            let status = response.status();
+            let body = read_body(response).await;
+            (
+                status,
+                serde_json::from_slice(body.as_slice()).unwrap_or_default(),
+            )
+        }
+
+        async fn publish(&self, token: &str, name: &str, version: &str) -> StatusCode {
+            let uri = format!("/{}", name.replacen('/', "%2f", 1));
+            let document = publish_document(name, version);
+            let (status, _) = self
+                .request(Method::PUT, uri.as_str(), Some(token), Some(document))
+                .await;
+            status
+        }
+    }
+
+    async fn read_body(response: Response) -> Vec<u8> {
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(chunk.unwrap().as_ref());
+        }
+        bytes
+    }
+
+    // A gzipped tarball holding only the `package.json` of `name@version`. Stamped with no
+    // time, so that it's the same tarball whenever it's made.
+    fn tarball(name: &str, version: &str) -> Vec<u8> {
+        let manifest = json!({ "name": name, "version": version }).to_string();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut archive = tar::Builder::new(Vec::new());
+        archive
+            .append_data(&mut header, "package/package.json", manifest.as_bytes())
+            .unwrap();
+
+        let header = libflate::gzip::HeaderBuilder::new()
+            .modification_time(0)
+            .finish();
+        let options = libflate::gzip::EncodeOptions::new().header(header);
+        let mut gzipped = libflate::gzip::Encoder::with_options(Vec::new(), options).unwrap();
+        std::io::Write::write_all(&mut gzipped, archive.into_inner().unwrap().as_slice()).unwrap();
+        gzipped.finish().into_result().unwrap()
+    }
+
+    // The document `npm publish` sends for `name@version`.
+    fn publish_document(name: &str, version: &str) -> serde_json::Value {
+        let tarball = tarball(name, version);
+        let shasum = Digest::compute(Algorithm::Sha1, tarball.as_slice()).unwrap();
+        json!({
+            "_id": name,
+            "name": name,
+            "dist-tags": { "latest": version },
+            "versions": {
+                version: {
+                    "_id": format!("{}@{}", name, version),
+                    "name": name,
+                    "version": version,
+                    "dist": { "tarball": "", "shasum": shasum.to_hex() }
+                }
+            },
+            "_attachments": {
+                format!("{}-{}.tgz", name, version): {
+                    "content_type": "application/octet-stream",
+                    "data": base64::engine::general_purpose::STANDARD.encode(tarball.as_slice()),
+                    "length": tarball.len()
+                }
+            }
+        })
+    }
+
+    fn accepting(accept: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, accept.try_into().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_wants_abbreviated() {
+        // What npm, pnpm and Yarn send when installing.
+        assert!(wants_abbreviated(&accepting(
+            "application/vnd.npm.install-v1+json; q=1.0, application/json; q=0.8, */*"
+        )));
+        assert!(wants_abbreviated(&accepting(
+            "Application/Vnd.Npm.Install-V1+json"
+        )));
+
+        // And when they need the full document (`npm view`, `yarn npm info`, pnpm's
+        // time-based resolution.)
+        assert!(!wants_abbreviated(&accepting("application/json")));
+        assert!(!wants_abbreviated(&accepting("*/*")));
+        assert!(!wants_abbreviated(&HeaderMap::new()));
+
+        assert!(!wants_abbreviated(&accepting(
+            "application/vnd.npm.install-v1+json; q=0, application/json"
+        )));
+        assert!(!wants_abbreviated(&accepting(
+            "application/vnd.npm.install-v1+json; q=0.5, application/json; q=0.9"
+        )));
+    }
+
+    #[test]
+    fn test_offline_not_found() {
+        let not_found = || RegistryError::not_found("no such package: left-pad");
+        assert_eq!(
+            storage_error(StorageError::NotFound.into(), not_found),
+            not_found()
+        );
+
+        let offline = storage_error(
+            anyhow::Error::new(StorageError::NotFound).context(Offline),
+            not_found,
+        );
+        assert_eq!(offline.status(), StatusCode::NOT_FOUND);
+        assert!(offline.message().contains("offline"));
+    }
+
+    #[test]
+    fn test_warm_targets() {
+        let lockfile = json!({
+            "lockfileVersion": 3,
+            "packages": {
+                "": { "name": "app", "version": "1.0.0" },
+                "node_modules/react": {
+                    "version": "18.2.0",
+                    "resolved": "https://registry.npmjs.org/react/-/react-18.2.0.tgz"
+                },
+                "node_modules/a/node_modules/@types/node": {
+                    "version": "20.11.0",
+                    "resolved": "https://registry.npmjs.org/@types/node/-/node-20.11.0.tgz"
+                },
+                "node_modules/pad": {
+                    "name": "left-pad",
+                    "version": "1.3.0",
+                    "resolved": "https://registry.npmjs.org/left-pad/-/left-pad-1.3.0.tgz"
+                },
+                "node_modules/forked": {
+                    "version": "1.0.0",
+                    "resolved": "git+ssh://git@github.com/corp/forked.git#abc123"
+                },
+                "node_modules/local": { "resolved": "packages/local", "link": true }
+            }
+        });
+        let targets = warm_targets(CacheWarmRequest {
+            packages: vec!["react".to_string(), "@types/node@18.0.0".to_string()],
+            lockfile: Some(lockfile),
+        })
+        .unwrap();
+        let listed: Vec<(&str, Vec<&str>)> = targets
+            .iter()
+            .map(|(name, versions)| (name.as_str(), versions.iter().map(String::as_str).collect()))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                ("@types/node", vec!["18.0.0", "20.11.0"]),
+                ("left-pad", vec!["1.3.0"]),
+                ("react", vec!["18.2.0"]),
+            ]
+        );
+
+        let lockfile = json!({
+            "lockfileVersion": 1,
+            "dependencies": {
+                "pad": {
+                    "version": "npm:left-pad@1.3.0",
+                    "dependencies": { "tap": { "version": "16.0.0" } }
+                }
+            }
+        });
+        let targets = warm_targets(CacheWarmRequest {
+            packages: vec![],
+            lockfile: Some(lockfile),
+        })
+        .unwrap();
+        assert_eq!(targets.keys().collect::<Vec<_>>(), vec!["left-pad", "tap"]);
+
+        assert!(warm_targets(CacheWarmRequest {
+            packages: vec!["@types@20.11.0".to_string()],
+            lockfile: None,
+        })
+        .is_err());
+    }
+
+    // `npm unpublish pkg@version` and a document edit moving a dist-tag, both sent to
+    // `/<path>/-rev/<rev>`.
+    async fn check_modify_at_rev(name: &str, path: &str) {
+        let registry = TestRegistry::new();
+        let alice = registry.login("alice").await;
+        let mallory = registry.login("mallory").await;
+        assert_eq!(
+            registry.publish(alice.as_str(), name, "1.0.0").await,
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            registry.publish(alice.as_str(), name, "1.1.0").await,
+            StatusCode::CREATED
+        );
+        let mut events = registry.state.as_events().subscribe();
+
+        let uri = format!("/{}", path);
+        let (_, mut document) = registry
+            .request(Method::GET, uri.as_str(), None, None)
+            .await;
+        let rev = document["_rev"].as_str().unwrap().to_string();
+        document["versions"]
+            .as_object_mut()
+            .unwrap()
+            .remove("1.1.0");
+        document["dist-tags"]["latest"] = json!("1.0.0");
+
+        let at_rev = format!("/{}/-rev/{}", path, rev);
+        let (status, _) = registry
+            .request(
+                Method::PUT,
+                at_rev.as_str(),
+                Some(mallory.as_str()),
+                Some(document.clone()),
+            )
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, body) = registry
+            .request(
+                Method::PUT,
+                at_rev.as_str(),
+                Some(alice.as_str()),
+                Some(document),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let event = events.recv().await.unwrap();
+        assert_eq!(event.event, "package:unpublish");
+        assert_eq!(event.change["versions"], json!(["1.1.0"]));
+
+        let (_, mut document) = registry
+            .request(Method::GET, uri.as_str(), None, None)
+            .await;
+        let versions: Vec<_> = document["versions"].as_object().unwrap().keys().collect();
+        assert_eq!(versions, vec!["1.0.0"]);
+        let pkg: PackageIdentifier = name.parse().unwrap();
+        let tarball = registry
+            .state
+            .as_package_storage()
+            .tarball_metadata(&pkg, "1.1.0")
+            .await;
+        assert!(tarball.is_err());
+
+        // `latest` goes with the version it pointed at, for the client to move.
+        assert_eq!(document["dist-tags"]["latest"], json!(null));
+        assert_eq!(document["_rev"], body["rev"]);
+        document["dist-tags"]["latest"] = json!("1.0.0");
+        let at_rev = format!("/{}/-rev/{}", path, body["rev"].as_str().unwrap());
+        let (status, _) = registry
+            .request(
+                Method::PUT,
+                at_rev.as_str(),
+                Some(alice.as_str()),
+                Some(document.clone()),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (_, moved) = registry
+            .request(Method::GET, uri.as_str(), None, None)
+            .await;
+        assert_eq!(moved["dist-tags"], json!({ "latest": "1.0.0" }));
+
+        // Stale revisions are turned away.
+        let (status, _) = registry
+            .request(
+                Method::PUT,
+                at_rev.as_str(),
+                Some(alice.as_str()),
+                Some(document),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let registry = TestRegistry::new();
+        let alice = registry.login("alice").await;
+        let status = registry.publish("not-a-token", "@corp/app", "1.0.0").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let status = registry.publish(alice.as_str(), "@corp/app", "1.0.0").await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, document) = registry
+            .request(Method::GET, "/@corp%2fapp", None, None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(document["dist-tags"], json!({ "latest": "1.0.0" }));
+        let shasum = Digest::compute(Algorithm::Sha1, tarball("@corp/app", "1.0.0").as_slice());
+        assert_eq!(
+            document["versions"]["1.0.0"]["dist"]["shasum"],
+            shasum.unwrap().to_hex()
+        );
+        let download = registry
+            .send(
+                Request::get("/@corp/app/-/app-1.0.0.tgz")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(download.status(), StatusCode::OK);
+        assert_eq!(read_body(download).await, tarball("@corp/app", "1.0.0"));
+
+        // A version is only ever published once.
+        let (status, body) = registry
+            .request(
+                Method::PUT,
+                "/@corp%2fapp",
+                Some(alice.as_str()),
+                Some(publish_document("@corp/app", "1.0.0")),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .contains("previously published"));
+    }
+
+    #[tokio::test]
+    async fn test_publish_tarball() {
+        let registry = TestRegistry::new();
+        let alice = registry.login("alice").await;
+        let upload = |uri: &str, body: Vec<u8>| {
+            let request = Request::put(uri)
+                .header(header::AUTHORIZATION, format!("Bearer {}", alice))
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .body(Body::from(body))
+                .unwrap();
+            registry.send(request)
+        };
+
+        let published = upload(
+            "/-/package/left-pad/publish?tag=beta",
+            tarball("left-pad", "1.0.0"),
         )
+        .await;
+        assert_eq!(published.status(), StatusCode::CREATED);
+        let (_, document) = registry.request(Method::GET, "/left-pad", None, None).await;
+        assert_eq!(document["dist-tags"]["beta"], "1.0.0");
+        let download = registry
+            .send(
+                Request::get("/left-pad/-/left-pad-1.0.0.tgz")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(read_body(download).await, tarball("left-pad", "1.0.0"));
+
+        // The tarball's package.json has to name the package it's published to.
+        let mismatched = upload("/-/package/left-pad/publish", tarball("right-pad", "1.0.0")).await;
+        assert_eq!(mismatched.status(), StatusCode::BAD_REQUEST);
+        let garbage = upload("/-/package/left-pad/publish", b"not a tarball".to_vec()).await;
+        assert_eq!(garbage.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_modification() {
+        let registry = TestRegistry::new();
+        let alice = registry.login("alice").await;
+        registry.publish(alice.as_str(), "left-pad", "1.0.0").await;
+        registry.publish(alice.as_str(), "left-pad", "1.1.0").await;
+        let (_, document) = registry.request(Method::GET, "/left-pad", None, None).await;
+        let at_rev = format!("/left-pad/-rev/{}", document["_rev"].as_str().unwrap());
+
+        // Two tags moved at once, and a document with nothing this registry can apply.
+        let mut tags = document.clone();
+        tags["dist-tags"] = json!({ "latest": "1.0.0", "next": "1.1.0" });
+        let mut nothing = document.clone();
+        nothing["description"] = json!("Pads strings");
+        for edited in [tags, nothing] {
+            let (status, _) = registry
+                .request(
+                    Method::PUT,
+                    at_rev.as_str(),
+                    Some(alice.as_str()),
+                    Some(edited),
+                )
+                .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+
+        let (_, unchanged) = registry.request(Method::GET, "/left-pad", None, None).await;
+        assert_eq!(unchanged["_rev"], document["_rev"]);
+    }
+
+    #[tokio::test]
+    async fn test_put_packument_at_rev() {
+        check_modify_at_rev("left-pad", "left-pad").await;
+    }
+
+    #[tokio::test]
+    async fn test_put_scoped_packument_at_rev() {
+        check_modify_at_rev("@corp/left-pad", "@corp/left-pad").await;
+    }
+
+    // `npm unpublish --force`, as whoever `token` belongs to.
+    async fn unpublish(registry: &TestRegistry, token: &str, name: &str) {
+        let uri = format!("/{}", name);
+        let (_, document) = registry
+            .request(Method::GET, uri.as_str(), None, None)
+            .await;
+        let at_rev = format!("{}/-rev/{}", uri, document["_rev"].as_str().unwrap());
+        let (status, _) = registry
+            .request(Method::DELETE, at_rev.as_str(), Some(token), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_unpublish_window() {
+        let registry = TestRegistry::with_configurator(TestConfigurator {
+            unpublish_window: Duration::from_secs(60 * 60),
+            ..Default::default()
+        });
+        let alice = registry.login("alice").await;
+        let bob = registry.login("bob").await;
+        registry.publish(alice.as_str(), "left-pad", "1.0.0").await;
+        unpublish(&registry, alice.as_str(), "left-pad").await;
+
+        // Held from everyone, its publisher included, whatever the version.
+        for (token, version) in [(&bob, "2.0.0"), (&alice, "2.0.0"), (&alice, "1.0.0")] {
+            let status = registry.publish(token.as_str(), "left-pad", version).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unpublished_versions_stay_unpublished() {
+        let registry = TestRegistry::new();
+        let alice = registry.login("alice").await;
+        registry.publish(alice.as_str(), "left-pad", "1.0.0").await;
+        unpublish(&registry, alice.as_str(), "left-pad").await;
+
+        // The window has passed: new versions may be published, old ones never.
+        let status = registry.publish(alice.as_str(), "left-pad", "1.0.0").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let status = registry.publish(alice.as_str(), "left-pad", "2.0.0").await;
+        assert_eq!(status, StatusCode::CREATED);
+        let status = registry.publish(alice.as_str(), "left-pad", "1.0.0").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_advertised_clients() {
+        let install = "application/vnd.npm.install-v1+json; q=1.0, application/json; q=0.8, */*";
+        // Each client's User-Agent, and the Accept it sends when installing and when it needs
+        // the full document (`npm view`, pnpm's time-based resolution, `yarn npm info`).
+        let clients = [
+            (
+                "npm",
+                "npm/10.2.4 node/v20.11.0 linux x64 workspaces/false",
+                install,
+                "application/json",
+            ),
+            (
+                "pnpm",
+                "pnpm/8.15.1 npm/? node/v20.11.0 linux x64",
+                install,
+                "application/json",
+            ),
+            (
+                "yarn",
+                "yarn/4.1.0 npm/? node/v20.11.0 linux x64",
+                install,
+                "application/json",
+            ),
+        ];
+
+        let registry = TestRegistry::new();
+        let alice = registry.login("alice").await;
+        for version in ["1.0.0", "1.1.0", "2.0.0-rc.1"] {
+            registry.publish(alice.as_str(), "left-pad", version).await;
+        }
+
+        let (_, capabilities) = registry
+            .request(Method::GET, "/-/capabilities", None, None)
+            .await;
+        let names: Vec<_> = clients.iter().map(|(name, ..)| *name).collect();
+        assert_eq!(capabilities["clients"], json!(names));
+
+        for (name, user_agent, install, info) in clients {
+            let get = |uri: &str, accept: &str, etag: Option<&str>| {
+                let mut request = Request::get(uri)
+                    .header(header::USER_AGENT, user_agent)
+                    .header(header::ACCEPT, accept);
+                if let Some(etag) = etag {
+                    request = request.header(header::IF_NONE_MATCH, etag);
+                }
+                registry.send(request.body(Body::empty()).unwrap())
+            };
+
+            let installing = get("/left-pad", install, None).await;
+            assert_eq!(installing.status(), StatusCode::OK, "{}", name);
+            assert_eq!(
+                installing.headers()[header::CONTENT_TYPE],
+                ABBREVIATED_CONTENT_TYPE,
+                "{}",
+                name
+            );
+            let etag = installing.headers()[header::ETAG]
+                .to_str()
+                .unwrap()
+                .to_string();
+            let document: serde_json::Value =
+                serde_json::from_slice(read_body(installing).await.as_slice()).unwrap();
+            assert_eq!(document["dist-tags"]["latest"], "2.0.0-rc.1", "{}", name);
+            let cached = get("/left-pad", install, Some(etag.as_str())).await;
+            assert_eq!(cached.status(), StatusCode::NOT_MODIFIED, "{}", name);
+
+            let viewing = get("/left-pad", info, None).await;
+            assert_eq!(viewing.status(), StatusCode::OK, "{}", name);
+            assert_eq!(
+                viewing.headers()[header::CONTENT_TYPE],
+                "application/json",
+                "{}",
+                name
+            );
+            let document: serde_json::Value =
+                serde_json::from_slice(read_body(viewing).await.as_slice()).unwrap();
+            assert!(document["time"]["1.1.0"].is_string(), "{}", name);
+
+            // `npm view left-pad@^1`, `pnpm view`, `yarn npm info`: a range resolves to the
+            // highest version satisfying it, a tag to its version.
+            for (spec, expected) in [("%5E1.0.0", "1.1.0"), ("latest", "2.0.0-rc.1")] {
+                let uri = format!("/left-pad/{}", spec);
+                let resolved = get(uri.as_str(), info, None).await;
+                assert_eq!(resolved.status(), StatusCode::OK, "{} {}", name, spec);
+                let manifest: serde_json::Value =
+                    serde_json::from_slice(read_body(resolved).await.as_slice()).unwrap();
+                assert_eq!(manifest["version"], expected, "{} {}", name, spec);
+            }
+            for uri in ["/left-pad/%5E3.0.0", "/right-pad", "/right-pad/1.0.0"] {
+                let missing = get(uri, install, None).await;
+                assert_eq!(missing.status(), StatusCode::NOT_FOUND, "{} {}", name, uri);
+            }
+
+            let download = get("/left-pad/-/left-pad-1.1.0.tgz", "*/*", None).await;
+            assert_eq!(download.status(), StatusCode::OK, "{}", name);
+            assert_eq!(
+                read_body(download).await,
+                tarball("left-pad", "1.1.0"),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_package_access() {
+        let registry = TestRegistry::new();
+        let alice = registry.login("alice").await;
+        let mallory = registry.login("mallory").await;
+        registry.publish(alice.as_str(), "@corp/app", "1.0.0").await;
+
+        let access = "/-/package/@corp%2fapp/access";
+        let (status, body) = registry.request(Method::GET, access, None, None).await;
+        assert_eq!(
+            (status, body),
+            (StatusCode::OK, json!({ "access": "public" }))
+        );
+        let restrict = json!({ "access": "restricted" });
+        let (status, _) = registry
+            .request(Method::POST, access, None, Some(restrict.clone()))
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = registry
+            .request(
+                Method::POST,
+                access,
+                Some(mallory.as_str()),
+                Some(restrict.clone()),
+            )
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = registry
+            .request(
+                Method::POST,
+                access,
+                Some(alice.as_str()),
+                Some(restrict.clone()),
+            )
+            .await;
+        assert_eq!((status, body), (StatusCode::OK, restrict));
+
+        // Restricted, the package is only there for those it's shared with.
+        let collaborators = "/-/package/@corp%2fapp/collaborators";
+        let (status, _) = registry
+            .request(Method::GET, collaborators, Some(mallory.as_str()), None)
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, body) = registry
+            .request(Method::GET, collaborators, Some(alice.as_str()), None)
+            .await;
+        assert_eq!(
+            (status, body),
+            (StatusCode::OK, json!({ "alice": "read-write" }))
+        );
+
+        let team = "/-/team/corp/devs/package";
+        let grant = json!({ "package": "@corp/app", "permissions": "read-only" });
+        let (status, _) = registry
+            .request(
+                Method::PUT,
+                team,
+                Some(mallory.as_str()),
+                Some(grant.clone()),
+            )
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = registry
+            .request(Method::PUT, team, Some(alice.as_str()), Some(grant.clone()))
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (_, body) = registry.request(Method::GET, team, None, None).await;
+        assert_eq!(body, json!({ "@corp/app": "read-only" }));
+
+        let (status, _) = registry
+            .request(Method::GET, "/-/package/list", None, None)
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, body) = registry
+            .request(
+                Method::GET,
+                "/-/package/list?user=corp:devs",
+                Some(alice.as_str()),
+                None,
+            )
+            .await;
+        assert_eq!(
+            (status, body),
+            (StatusCode::OK, json!({ "@corp/app": "read-only" }))
+        );
+        let (status, body) = registry
+            .request(Method::GET, "/-/user/mallory/package", None, None)
+            .await;
+        assert_eq!((status, body), (StatusCode::OK, json!({})));
+
+        let (status, _) = registry
+            .request(Method::DELETE, team, Some(alice.as_str()), Some(grant))
+            .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (_, body) = registry.request(Method::GET, team, None, None).await;
+        assert_eq!(body, json!({}));
+    }
+
+    #[tokio::test]
+    async fn test_package_transfer() {
+        let registry = TestRegistry::new();
+        let alice = registry.login("alice").await;
+        let bob = registry.login("bob").await;
+        let mallory = registry.login("mallory").await;
+        registry.publish(alice.as_str(), "left-pad", "1.0.0").await;
+
+        let transfer = "/-/package/left-pad/transfer";
+        let offer = |to: &str| Some(json!({ "to": to }));
+        let (status, _) = registry
+            .request(Method::PUT, transfer, Some(alice.as_str()), offer("nobody"))
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = registry
+            .request(
+                Method::PUT,
+                transfer,
+                Some(mallory.as_str()),
+                offer("mallory"),
+            )
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = registry
+            .request(Method::PUT, transfer, Some(alice.as_str()), offer("bob"))
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(
+            (&body["from"], &body["to"]),
+            (&json!("alice"), &json!("bob"))
+        );
+
+        // Only the two sides of a transfer can see it.
+        let (status, _) = registry
+            .request(Method::GET, transfer, Some(mallory.as_str()), None)
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, body) = registry
+            .request(Method::GET, transfer, Some(bob.as_str()), None)
+            .await;
+        assert_eq!(
+            (status, &body["package"]),
+            (StatusCode::OK, &json!("left-pad"))
+        );
+        let (_, body) = registry
+            .request(Method::GET, "/-/transfers", Some(bob.as_str()), None)
+            .await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+
+        let accept = "/-/package/left-pad/transfer/accept";
+        let (status, _) = registry
+            .request(Method::POST, accept, Some(mallory.as_str()), None)
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = registry
+            .request(Method::POST, accept, Some(bob.as_str()), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["maintainers"][0]["name"], "bob");
+        let status = registry.publish(alice.as_str(), "left-pad", "1.1.0").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let status = registry.publish(bob.as_str(), "left-pad", "1.1.0").await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        // Offered back, and declined.
+        registry
+            .request(Method::PUT, transfer, Some(bob.as_str()), offer("alice"))
+            .await;
+        let (status, _) = registry
+            .request(Method::DELETE, transfer, Some(mallory.as_str()), None)
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = registry
+            .request(Method::DELETE, transfer, Some(alice.as_str()), None)
+            .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = registry
+            .request(Method::POST, accept, Some(alice.as_str()), None)
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_changes_feed() {
+        let (status, _) = TestRegistry::new()
+            .request(Method::GET, "/_changes", None, None)
+            .await;
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+
+        let storage = ChangeLog::in_memory(InMemoryPackageStorage::new()).unwrap();
+        let registry = TestRegistry::new().with_package_storage(storage);
+        let alice = registry.login("alice").await;
+        registry.publish(alice.as_str(), "left-pad", "1.0.0").await;
+        registry
+            .publish(alice.as_str(), "@corp/secret", "1.0.0")
+            .await;
+        let restrict = Some(json!({ "access": "restricted" }));
+        registry
+            .request(
+                Method::POST,
+                "/-/package/@corp%2fsecret/access",
+                Some(alice.as_str()),
+                restrict,
+            )
+            .await;
+
+        let ids = |body: &serde_json::Value| -> Vec<String> {
+            let results = body["results"].as_array().unwrap();
+            results
+                .iter()
+                .map(|change| change["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        // A restricted package is only listed for those who could install it, but its
+        // sequence number is still passed over.
+        let (status, body) = registry.request(Method::GET, "/_changes", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            (ids(&body), &body["last_seq"]),
+            (vec!["left-pad".to_string()], &json!(2))
+        );
+        assert!(body["results"][0]["changes"][0]["rev"].is_string());
+        let (_, body) = registry
+            .request(Method::GET, "/_changes?since=1", Some(alice.as_str()), None)
+            .await;
+        assert_eq!(ids(&body), ["@corp/secret"]);
+
+        let (_, body) = registry
+            .request(
+                Method::GET,
+                "/_changes?feed=longpoll&since=2&timeout=10",
+                None,
+                None,
+            )
+            .await;
+        assert_eq!(body, json!({ "results": [], "last_seq": 2 }));
+        let (status, _) = registry
+            .request(Method::GET, "/_changes?feed=eventsource", None, None)
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_preview_tokens() {
+        let unconfigured = TestRegistry::with_configurator(TestConfigurator {
+            admin_users: vec!["root".to_string()],
+            ..Default::default()
+        });
+        let root = unconfigured.login("root").await;
+        let (status, _) = unconfigured
+            .request(
+                Method::POST,
+                "/-/admin/preview-tokens",
+                Some(root.as_str()),
+                None,
+            )
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let registry = TestRegistry::with_configurator(TestConfigurator {
+            admin_users: vec!["root".to_string()],
+            preview_scope: Some("preview".to_string()),
+            ..Default::default()
+        });
+        let root = registry.login("root").await;
+        let alice = registry.login("alice").await;
+        let mint = Some(json!({ "ttl": 60 }));
+        let (status, _) = registry
+            .request(
+                Method::POST,
+                "/-/admin/preview-tokens",
+                Some(alice.as_str()),
+                mint.clone(),
+            )
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = registry
+            .request(
+                Method::POST,
+                "/-/admin/preview-tokens",
+                Some(root.as_str()),
+                mint,
+            )
+            .await;
+        assert_eq!(
+            (status, &body["scope"]),
+            (StatusCode::CREATED, &json!("@preview"))
+        );
+        let preview = body["token"].as_str().unwrap();
+
+        // `npm publish --tag <tag>` of `name@version`.
+        let registry = &registry;
+        let publish = |name: &str, version: &str, tag: &str| {
+            let mut document = publish_document(name, version);
+            document["dist-tags"] = json!({ tag: version });
+            let uri = format!("/{}", name.replacen('/', "%2f", 1));
+            async move {
+                let (status, _) = registry
+                    .request(Method::PUT, uri.as_str(), Some(preview), Some(document))
+                    .await;
+                status
+            }
+        };
+        assert_eq!(
+            publish("@preview/app", "1.0.0-pr.1", "pr-1").await,
+            StatusCode::CREATED
+        );
+        for (name, version, tag) in [
+            ("@preview/app", "1.0.0", "pr-1"),
+            ("@preview/app", "1.0.0-pr.2", "latest"),
+            ("@corp/app", "1.0.0-pr.1", "pr-1"),
+        ] {
+            let status = publish(name, version, tag).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{}@{}", name, version);
+        }
+
+        // Good for publishing, and nothing else.
+        let (status, _) = registry
+            .request(Method::GET, "/-/npm/v1/tokens", Some(preview), None)
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_admin_quarantine() {
+        let registry = TestRegistry::with_configurator(TestConfigurator {
+            admin_users: vec!["root".to_string()],
+            ..Default::default()
+        });
+        let root = registry.login("root").await;
+        let alice = registry.login("alice").await;
+        for version in ["1.0.0", "1.1.0"] {
+            registry.publish(alice.as_str(), "left-pad", version).await;
+        }
+        let download = |version: &str| {
+            let uri = format!("/left-pad/-/left-pad-{}.tgz", version);
+            registry.send(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        let quarantine = "/-/admin/quarantine/left-pad";
+        let reported = Some(json!({ "reason": "reported as malware", "version": "1.1.0" }));
+        let (status, _) = registry
+            .request(
+                Method::PUT,
+                quarantine,
+                Some(alice.as_str()),
+                reported.clone(),
+            )
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = registry
+            .request(
+                Method::PUT,
+                quarantine,
+                Some(root.as_str()),
+                Some(json!({ "reason": " " })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, body) = registry
+            .request(Method::PUT, quarantine, Some(root.as_str()), reported)
+            .await;
+        assert_eq!((status, &body["by"]), (StatusCode::CREATED, &json!("root")));
+
+        assert_eq!(download("1.1.0").await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(download("1.0.0").await.status(), StatusCode::OK);
+        let (_, body) = registry
+            .request(
+                Method::GET,
+                "/-/admin/quarantine",
+                Some(root.as_str()),
+                None,
+            )
+            .await;
+        assert_eq!(body["quarantines"][0]["version"], "1.1.0");
+
+        // Lifted only where it was put.
+        let (status, _) = registry
+            .request(Method::DELETE, quarantine, Some(root.as_str()), None)
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let lift = format!("{}?version=1.1.0", quarantine);
+        let (status, _) = registry
+            .request(Method::DELETE, lift.as_str(), Some(root.as_str()), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(download("1.1.0").await.status(), StatusCode::OK);
+
+        let whole = Some(json!({ "reason": "name dispute" }));
+        registry
+            .request(Method::PUT, quarantine, Some(root.as_str()), whole)
+            .await;
+        let (status, body) = registry.request(Method::GET, "/left-pad", None, None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(body["error"].as_str().unwrap().contains("name dispute"));
+    }
+
+    #[tokio::test]
+    async fn test_read_through_tarballs() {
+        let cache_dir = TestDir::new("registry-read-through");
+        let upstream = InMemoryPackageStorage::new();
+        let registry = TestRegistry::new()
+            .with_package_storage(ReadThrough::new(&cache_dir, upstream.clone()));
+        let name: PackageIdentifier = "left-pad".parse().unwrap();
+        let published = tarball("left-pad", "1.0.0");
+        let integrity = Digest::compute(Algorithm::Sha512, published.as_slice()).unwrap();
+        let document = json!({
+            "name": "left-pad",
+            "versions": {
+                "1.0.0": {
+                    "_id": "left-pad@1.0.0",
+                    "name": "left-pad",
+                    "version": "1.0.0",
+                    "dist": { "tarball": "", "shasum": "", "integrity": integrity.to_string() }
+                }
+            }
+        });
+        upstream
+            .put_packument(&name, &serde_json::from_value(document).unwrap())
+            .await
+            .unwrap();
+        let download = || {
+            let request = Request::get("/left-pad/-/left-pad-1.0.0.tgz");
+            registry.send(request.body(Body::empty()).unwrap())
+        };
+
+        // A tarball that doesn't match its integrity is cut off before its end, so that no
+        // client installs it.
+        upstream
+            .put_tarball(&name, "1.0.0", Bytes::from_static(b"tampered"))
+            .await
+            .unwrap();
+        let tampered = download().await;
+        assert_eq!(tampered.status(), StatusCode::OK);
+        let mut body = tampered.into_body();
+        let mut failed = false;
+        while let Some(chunk) = body.data().await {
+            failed |= chunk.is_err();
+        }
+        assert!(failed);
+
+        upstream
+            .put_tarball(&name, "1.0.0", Bytes::from(published.clone()))
+            .await
+            .unwrap();
+        let filled = download().await;
+        assert_eq!(filled.status(), StatusCode::OK);
+        assert_eq!(read_body(filled).await, published);
+
+        // Once filled, it's served without the upstream.
+        upstream.delete_tarball(&name, "1.0.0").await.unwrap();
+        let cached = download().await;
+        assert_eq!(cached.status(), StatusCode::OK);
+        assert_eq!(read_body(cached).await, published);
+        let missing = Request::get("/left-pad/-/left-pad-2.0.0.tgz");
+        let missing = registry.send(missing.body(Body::empty()).unwrap()).await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_encoded_packument_etags() {
+        let cache_dir = TestDir::new("registry-read-through");
+        let registry = TestRegistry::new()
+            .with_package_storage(ReadThrough::new(&cache_dir, InMemoryPackageStorage::new()));
+        let token = registry.login("alice").await;
+        assert_eq!(
+            registry.publish(token.as_str(), "left-pad", "1.0.0").await,
+            StatusCode::CREATED
+        );
+        let get = |encoding: Option<&str>, etag: Option<&str>| {
+            let mut request = Request::get("/left-pad");
+            if let Some(encoding) = encoding {
+                request = request.header(header::ACCEPT_ENCODING, encoding);
+            }
+            if let Some(etag) = etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            registry.send(request.body(Body::empty()).unwrap())
+        };
+        let etag = |response: &Response| {
+            response.headers()[header::ETAG]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let plain = get(None, None).await;
+        assert_eq!(plain.status(), StatusCode::OK);
+        assert!(plain.headers().get(header::CONTENT_ENCODING).is_none());
+        let gzipped = get(Some("gzip"), None).await;
+        assert_eq!(gzipped.status(), StatusCode::OK);
+        assert_eq!(gzipped.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_ne!(etag(&plain), etag(&gzipped));
+        assert!(etag(&gzipped).ends_with("-gzip\""));
+
+        // Either tag revalidates, and the 304 carries the one the client sent.
+        for (encoding, tag) in [(None, etag(&plain)), (Some("gzip"), etag(&gzipped))] {
+            let cached = get(encoding, Some(tag.as_str())).await;
+            assert_eq!(cached.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(etag(&cached), tag);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_token_restrictions() {
+        let registry = TestRegistry::new();
+        let token = registry.login("alice").await;
+        assert_eq!(
+            registry.publish(token.as_str(), "left-pad", "1.0.0").await,
+            StatusCode::CREATED
+        );
+        let create = |options: serde_json::Value| {
+            registry.request(
+                Method::POST,
+                "/-/npm/v1/tokens",
+                Some(token.as_str()),
+                Some(options),
+            )
+        };
+
+        // A read-only token reads, but can neither publish nor mint a token that could.
+        let (status, created) = create(json!({ "readonly": true })).await;
+        assert_eq!(status, StatusCode::OK);
+        let readonly = created["token"].as_str().unwrap();
+        let (status, _) = registry
+            .request(Method::GET, "/left-pad", Some(readonly), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            registry.publish(readonly, "left-pad", "1.0.1").await,
+            StatusCode::FORBIDDEN
+        );
+        let (status, _) = registry
+            .request(
+                Method::POST,
+                "/-/npm/v1/tokens",
+                Some(readonly),
+                Some(json!({})),
+            )
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, _) = create(json!({ "cidr_whitelist": ["10.0.0.0/33"] })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // A token limited to some addresses only works from them.
+        let (status, created) = create(json!({ "cidr_whitelist": ["10.0.0.0/8"] })).await;
+        assert_eq!(status, StatusCode::OK);
+        let limited = created["token"].as_str().unwrap();
+        let whoami = |from: Option<&str>| {
+            let mut request = Request::get("/-/whoami")
+                .header(header::AUTHORIZATION, format!("Bearer {}", limited));
+            if let Some(from) = from {
+                request = request.header("x-forwarded-for", from);
+            }
+            registry.send(request.body(Body::empty()).unwrap())
+        };
+        assert_eq!(whoami(Some("10.1.2.3")).await.status(), StatusCode::OK);
+        assert_eq!(
+            whoami(Some("192.168.0.1")).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(whoami(None).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_whoami() {
+        let registry = TestRegistry::new();
+        let token = registry.login("alice").await;
+        let (status, body) = registry
+            .request(Method::GET, "/-/whoami", Some(token.as_str()), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["username"], "alice");
+        assert_eq!(body["token"]["type"], "publish");
+
+        let (_, created) = registry
+            .request(
+                Method::POST,
+                "/-/npm/v1/tokens",
+                Some(token.as_str()),
+                Some(json!({ "readonly": true, "cidr_whitelist": ["10.0.0.0/8"] })),
+            )
+            .await;
+        let limited = created["token"].as_str().unwrap();
+        let request = Request::get("/-/whoami")
+            .header(header::AUTHORIZATION, format!("Bearer {}", limited))
+            .header("x-forwarded-for", "10.0.0.1");
+        let response = registry.send(request.body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_slice(read_body(response).await.as_slice()).unwrap();
+        assert_eq!(body["token"]["type"], "read-only");
+        assert_eq!(body["token"]["cidr_whitelist"], json!(["10.0.0.0/8"]));
+    }
+
+    // Upstream packuments cached as `serve` layers its storage: held in memory in front of the
+    // change log and the on-disk cache.
+    fn upstream_packument(versions: &[&str]) -> Packument {
+        let listed: serde_json::Map<_, _> = versions
+            .iter()
+            .map(|version| {
+                let manifest = json!({
+                    "_id": format!("left-pad@{}", version),
+                    "name": "left-pad",
+                    "version": version,
+                    "dist": { "tarball": "", "shasum": "" }
+                });
+                (version.to_string(), manifest)
+            })
+            .collect();
+        serde_json::from_value(json!({
+            "_id": "left-pad",
+            "name": "left-pad",
+            "dist-tags": { "latest": versions.last() },
+            "versions": listed
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_hot_packuments_expire() {
+        let cache_dir = TestDir::new("registry-read-through");
+        let upstream = InMemoryPackageStorage::new();
+        let max_age = Some(Duration::from_millis(200));
+        let read_through = ReadThrough::new(&cache_dir, upstream.clone()).with_max_age(max_age);
+        let registry = TestRegistry::new().with_package_storage(
+            HotCache::new(ChangeLog::in_memory(read_through).unwrap(), 16).with_max_age(max_age),
+        );
+        let name: PackageIdentifier = "left-pad".parse().unwrap();
+        let get = |etag: Option<String>| {
+            let mut request = Request::get("/left-pad");
+            if let Some(etag) = etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            registry.send(request.body(Body::empty()).unwrap())
+        };
+        let etag = |response: &Response| {
+            response.headers()[header::ETAG]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        upstream
+            .put_packument(&name, &upstream_packument(&["1.0.0"]))
+            .await
+            .unwrap();
+        let first = get(None).await;
+        let tag = etag(&first);
+        upstream
+            .put_packument(&name, &upstream_packument(&["1.0.0", "1.1.0"]))
+            .await
+            .unwrap();
+
+        // Until it expires, what's held is served, under its own tag.
+        let held = get(None).await;
+        assert_eq!(etag(&held), tag);
+        let body: serde_json::Value =
+            serde_json::from_slice(read_body(held).await.as_slice()).unwrap();
+        assert_eq!(body["dist-tags"]["latest"], "1.0.0");
+        assert_eq!(
+            get(Some(tag.clone())).await.status(),
+            StatusCode::NOT_MODIFIED
+        );
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let refreshed = get(Some(tag.clone())).await;
+        assert_eq!(refreshed.status(), StatusCode::OK);
+        assert_ne!(etag(&refreshed), tag);
+        let body: serde_json::Value =
+            serde_json::from_slice(read_body(refreshed).await.as_slice()).unwrap();
+        assert_eq!(body["dist-tags"]["latest"], "1.1.0");
+    }
+
+    #[tokio::test]
+    async fn test_hot_packuments_stale_while_revalidate() {
+        let cache_dir = TestDir::new("registry-read-through");
+        let upstream = InMemoryPackageStorage::new();
+        let tasks = crate::tasks::TaskRegistry::new();
+        let read_through = ReadThrough::new(&cache_dir, upstream.clone())
+            .with_max_age(Some(Duration::from_millis(100)))
+            .with_stale_while_revalidate(tasks.clone());
+        let refills = read_through.subscribe_refills();
+        // Held far longer than the cache's copy, so that only a refill can replace it.
+        let hot = HotCache::new(ChangeLog::in_memory(read_through.clone()).unwrap(), 16)
+            .with_max_age(Some(Duration::from_secs(60)));
+        tasks.spawn(
+            "cache:hot:refills",
+            hot.clone().forget_refilled(refills, tasks.clone()),
+        );
+        let registry = TestRegistry::new().with_package_storage(hot);
+        let name: PackageIdentifier = "left-pad".parse().unwrap();
+        let latest = || async {
+            let (_, body) = registry.request(Method::GET, "/left-pad", None, None).await;
+            body["dist-tags"]["latest"].as_str().unwrap().to_string()
+        };
+
+        upstream
+            .put_packument(&name, &upstream_packument(&["1.0.0"]))
+            .await
+            .unwrap();
+        assert_eq!(latest().await, "1.0.0");
+        upstream
+            .put_packument(&name, &upstream_packument(&["1.0.0", "1.1.0"]))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        // The cache serves its stale copy and refills it in the background; once it has, the
+        // copy held in memory goes too.
+        let mut refilled = false;
+        for _ in 0..50 {
+            let packument = read_through.fetch_packument(&name).await.unwrap();
+            if packument.versions.iter().flatten().count() == 2 {
+                refilled = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(refilled);
+        let mut forgotten = false;
+        for _ in 0..10 {
+            if latest().await == "1.1.0" {
+                forgotten = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(forgotten);
+        tasks.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn test_org_claim() {
+        let registry = TestRegistry::new();
+        let alice = registry.login("alice").await;
+        let bob = registry.login("bob").await;
+        let user_storage = registry.state.as_user_storage();
+        for name in ["alice", "bob"] {
+            user_storage
+                .set_verified_scopes(name, vec!["acme".to_string(), "globex".to_string()])
+                .await
+                .unwrap();
+        }
+
+        async fn claim(registry: &TestRegistry, org: &str, token: &str) -> StatusCode {
+            let uri = format!("/-/org/{}/claim", org);
+            registry
+                .request(Method::POST, uri.as_str(), Some(token), None)
+                .await
+                .0
+        }
+
+        let (status, body) = registry
+            .request(
+                Method::POST,
+                "/-/org/alice/claim",
+                Some(alice.as_str()),
+                None,
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["role"], "owner");
+        assert_eq!(
+            claim(&registry, "acme", alice.as_str()).await,
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            user_storage
+                .list_team_members("acme", "developers")
+                .await
+                .unwrap(),
+            Some(vec!["alice".to_string()])
+        );
+
+        assert_eq!(
+            claim(&registry, "initech", alice.as_str()).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            claim(&registry, "acme", bob.as_str()).await,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            claim(&registry, "alice", alice.as_str()).await,
+            StatusCode::CONFLICT
+        );
+
+        // Of two claims racing for the same scope, exactly one wins.
+        let (first, second) = tokio::join!(
+            claim(&registry, "globex", alice.as_str()),
+            claim(&registry, "globex", bob.as_str())
+        );
+        let mut statuses = vec![first, second];
+        statuses.sort();
+        assert_eq!(statuses, vec![StatusCode::CREATED, StatusCode::CONFLICT]);
+        assert_eq!(
+            user_storage.list_org_members("globex").await.unwrap().len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bulk_advisories() {
+        let registry = TestRegistry::new();
+        let advisory = |id, module_name: &str, vulnerable_versions: &str, cwe: &[&str]| {
+            serde_json::from_value::<Advisory>(json!({
+                "id": id,
+                "title": format!("advisory {}", id),
+                "module_name": module_name,
+                "severity": "high",
+                "vulnerable_versions": vulnerable_versions,
+                "url": format!("https://example.com/advisories/{}", id),
+                "cves": ["CVE-2024-0001"],
+                "cwe": cwe
+            }))
+            .unwrap()
+        };
+        let advisories = registry.state.as_advisories();
+        advisories
+            .add_advisory(advisory(1, "left-pad", "<1.3.0", &["CWE-400"]))
+            .await;
+        advisories
+            .add_advisory(advisory(2, "left-pad", ">=2.0.0", &[]))
+            .await;
+
+        let (status, body) = registry
+            .request(
+                Method::POST,
+                "/-/npm/v1/security/advisories/bulk",
+                None,
+                Some(json!({ "left-pad": ["1.2.0"], "right-pad": ["1.0.0"] })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({
+                "left-pad": [{
+                    "id": 1,
+                    "url": "https://example.com/advisories/1",
+                    "title": "advisory 1",
+                    "severity": "high",
+                    "vulnerable_versions": "<1.3.0",
+                    "cwe": ["CWE-400"]
+                }]
+            })
+        );
+
+        let (_, body) = registry
+            .request(
+                Method::POST,
+                "/-/npm/v1/security/advisories/bulk",
+                None,
+                Some(json!({ "left-pad": ["2.1.0"] })),
+            )
+            .await;
+        assert_eq!(body["left-pad"][0]["id"], 2);
+        assert!(body["left-pad"][0].get("cwe").is_none());
+    }
+
+    // A request to `uri` signed as the admin key `key_id`, as automation would sign it.
+    fn signed_request(
+        method: Method,
+        uri: &str,
+        key_id: &str,
+        secret: &str,
+        timestamp: i64,
+        nonce: &str,
+        body: &[u8],
+    ) -> Request<Body> {
+        let body_digest = Digest::compute(Algorithm::Sha256, body).unwrap();
+        let signed = format!(
+            "{}\n{}\n{}\n{}\n{}",
+            method,
+            uri,
+            timestamp,
+            nonce,
+            body_digest.to_hex()
+        );
+        let signature = crate::hashing::hmac_sha256(secret.as_bytes(), signed.as_bytes()).unwrap();
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("x-regi-key-id", key_id)
+            .header("x-regi-timestamp", timestamp.to_string())
+            .header("x-regi-nonce", nonce)
+            .header("x-regi-signature", hex::encode(signature))
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_vec()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_signed_admin_requests() {
+        let key = |scopes: &[&str]| AdminKey {
+            secret: "s3cret".to_string(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        };
+        let configurator = TestConfigurator {
+            admin_keys: HashMap::from([
+                ("ops".to_string(), key(&["tasks:read"])),
+                ("moderator".to_string(), key(&["quarantine"])),
+            ]),
+            ..Default::default()
+        };
+        let registry = TestRegistry::with_configurator(configurator.clone());
+        let now = Utc::now().timestamp();
+        let send = |key_id: &str, secret: &str, timestamp: i64, nonce: &str| {
+            registry.send(signed_request(
+                Method::GET,
+                "/-/admin/tasks",
+                key_id,
+                secret,
+                timestamp,
+                nonce,
+                b"",
+            ))
+        };
+
+        assert_eq!(
+            send("ops", "s3cret", now, "a").await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            send("ops", "s3cret", now, "a").await.status(),
+            StatusCode::UNAUTHORIZED,
+            "a replayed nonce is refused"
+        );
+        assert_eq!(
+            send("ops", "guessed", now, "b").await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            send("ops", "s3cret", now - 600, "c").await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            send("unknown", "s3cret", now, "d").await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            send("moderator", "s3cret", now, "e").await.status(),
+            StatusCode::FORBIDDEN
+        );
+
+        // The signature covers the body.
+        let mut tampered = signed_request(
+            Method::PUT,
+            "/-/admin/quarantine/left-pad",
+            "moderator",
+            "s3cret",
+            now,
+            "f",
+            br#"{"reason":"typosquat"}"#,
+        );
+        *tampered.body_mut() = Body::from(r#"{"reason":"nothing"}"#);
+        assert_eq!(
+            registry.send(tampered).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        // Nonces belong to the registry's policy, not the process.
+        let other = TestRegistry::with_configurator(configurator);
+        let response = other
+            .send(signed_request(
+                Method::GET,
+                "/-/admin/tasks",
+                "ops",
+                "s3cret",
+                now,
+                "a",
+                b"",
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_scim_provisioning() {
+        let registry = TestRegistry::with_configurator(TestConfigurator {
+            admin_users: vec!["root".to_string()],
+            admin_keys: HashMap::from([
+                (
+                    "idp".to_string(),
+                    AdminKey {
+                        secret: "s3cret".to_string(),
+                        scopes: vec!["scim".to_string()],
+                    },
+                ),
+                (
+                    "ops".to_string(),
+                    AdminKey {
+                        secret: "s3cret".to_string(),
+                        scopes: vec!["tasks:read".to_string()],
+                    },
+                ),
+            ]),
+            ..Default::default()
+        });
+        let root = registry.login("root").await;
+        let bob = registry.login("bob").await;
+        let root = Some(root.as_str());
+
+        let jane = json!({
+            "schemas": ["urn:ietf:params:scim:schemas:core:2.0:User"],
+            "userName": "jane",
+            "name": { "givenName": "Jane", "familyName": "Doe" },
+            "emails": [{ "value": "jane@corp.test", "primary": true }]
+        });
+        let (status, body) = registry
+            .request(Method::POST, "/-/scim/v2/Users", root, Some(jane.clone()))
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["userName"], "jane");
+        assert_eq!(body["displayName"], "Jane Doe");
+        assert_eq!(body["active"], true);
+        let (status, body) = registry
+            .request(Method::POST, "/-/scim/v2/Users", root, Some(jane))
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["scimType"], "uniqueness");
+
+        let (status, body) = registry
+            .request(
+                Method::POST,
+                "/-/scim/v2/Groups",
+                root,
+                Some(json!({ "displayName": "corp:devs", "members": [{ "value": "jane" }] })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["members"][0]["value"], "jane");
+        let user_storage = registry.state.as_user_storage();
+        assert_eq!(
+            user_storage.list_org_members("corp").await.unwrap()["jane"],
+            OrgRole::Developer
+        );
+
+        let (status, body) = registry
+            .request(
+                Method::PATCH,
+                "/-/scim/v2/Groups/corp:devs",
+                root,
+                Some(json!({
+                    "Operations": [
+                        { "op": "remove", "path": "members[value eq \"jane\"]" },
+                        { "op": "add", "path": "members", "value": [{ "value": "bob" }] }
+                    ]
+                })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body["members"],
+            json!([{ "value": "bob", "display": "bob" }])
+        );
+
+        // Deactivating a user ends every token they hold.
+        let user = user_storage.get_user("jane").await.unwrap();
+        let token = registry.state.as_token_authorizer().start_session(user);
+        let token = token.await.unwrap().to_string();
+        let (status, _) = registry
+            .request(Method::GET, "/-/whoami", Some(token.as_str()), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = registry
+            .request(
+                Method::PATCH,
+                "/-/scim/v2/Users/jane",
+                root,
+                Some(json!({
+                    "Operations": [{ "op": "replace", "path": "active", "value": "False" }]
+                })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["active"], false);
+        let (status, _) = registry
+            .request(Method::GET, "/-/whoami", Some(token.as_str()), None)
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(registry
+            .state
+            .as_token_authorizer()
+            .list_tokens("jane")
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(user_storage.orgs_for_user("jane").await.unwrap().is_empty());
+
+        // Keys need the scim scope, and users need to be admins.
+        let now = Utc::now().timestamp();
+        let signed = |key_id: &str, nonce: &str| {
+            signed_request(
+                Method::GET,
+                "/-/scim/v2/Users",
+                key_id,
+                "s3cret",
+                now,
+                nonce,
+                b"",
+            )
+        };
+        let response = registry.send(signed("idp", "a")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = registry.send(signed("ops", "b")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let (status, _) = registry
+            .request(Method::GET, "/-/scim/v2/Users", Some(bob.as_str()), None)
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
 }
@@ -0,0 +1,306 @@
+//! HTML pages for browsing packages, so people can read a package's readme and history
+//! without the npm CLI. Enabled by the `web-ui` feature.
+//!
+//! Pages follow the same visibility rules as the JSON API: a restricted package is missing
+//! to anyone who couldn't install it.
+
+use axum::body::{Body, HttpBody};
+use axum::extract::{Path, State};
+use axum::response::{Html, IntoResponse, Response};
+use maud::{html, Markup, PreEscaped, DOCTYPE};
+use pulldown_cmark::{CowStr, Event, Options, Parser, Tag};
+use tracing::instrument;
+
+use super::openapi::{get, ApiRouter};
+use super::v1::{can_install, package_not_found, parse_package, storage_error};
+use crate::error::RegistryError;
+use crate::extractors::Authenticated;
+use crate::models::{Maintainer, PackageIdentifier, Packument, PackumentVersion};
+use crate::policies::policy::PolicyHolder;
+use crate::policies::PackageStorage;
+
+const STYLE: &str = "
+body { font-family: system-ui, sans-serif; margin: 0; color: #222; }
+main { display: flex; flex-wrap: wrap; gap: 2rem; max-width: 70rem; margin: 0 auto; padding: 1rem; }
+article { flex: 3 1 30rem; min-width: 0; }
+aside { flex: 1 1 15rem; }
+pre, code { background: #f4f4f4; border-radius: 3px; }
+pre { padding: 0.75rem; overflow-x: auto; }
+table { border-collapse: collapse; }
+td { padding: 0.15rem 0.75rem 0.15rem 0; vertical-align: top; }
+.deprecated { color: #a33; }
+.muted { color: #777; }
+";
+
+/// An error rendered as a page rather than as the npm CLI's JSON.
+struct PageError(RegistryError);
+
+impl From<RegistryError> for PageError {
+    fn from(error: RegistryError) -> Self {
+        Self(error)
+    }
+}
+
+impl IntoResponse for PageError {
+    fn into_response(self) -> Response {
+        let status = self.0.status();
+        let title = status.canonical_reason().unwrap_or("Error");
+        let body = html! {
+            article {
+                h1 { (title) }
+                p { (self.0.message()) }
+            }
+        };
+        (status, Html(layout(title, body).into_string())).into_response()
+    }
+}
+
+fn layout(title: &str, body: Markup) -> Markup {
+    html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                title { (title) }
+                style { (PreEscaped(STYLE)) }
+            }
+            body {
+                main { (body) }
+            }
+        }
+    }
+}
+
+// Browsers run `javascript:` and friends, so links from package authors only keep the
+// schemes that merely navigate.
+fn is_safe_url(url: &str) -> bool {
+    let url = url.trim_start().to_ascii_lowercase();
+    match url.split_once(':') {
+        Some((scheme, _)) if !scheme.contains('/') => {
+            matches!(scheme, "http" | "https" | "mailto")
+        }
+        _ => true,
+    }
+}
+
+fn safe_url(url: CowStr<'_>) -> CowStr<'_> {
+    if is_safe_url(url.as_ref()) {
+        url
+    } else {
+        CowStr::Borrowed("#")
+    }
+}
+
+/// Render a readme's markdown. Raw HTML is shown as written rather than trusted, since
+/// anyone who can publish controls it.
+fn render_readme(readme: &str) -> String {
+    let options =
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let events = Parser::new_ext(readme, options).map(|event| match event {
+        Event::Html(html) => Event::Text(html),
+        Event::Start(Tag::Link(kind, url, title)) => {
+            Event::Start(Tag::Link(kind, safe_url(url), title))
+        }
+        Event::Start(Tag::Image(kind, url, title)) => {
+            Event::Start(Tag::Image(kind, safe_url(url), title))
+        }
+        event => event,
+    });
+
+    let mut rendered = String::new();
+    pulldown_cmark::html::push_html(&mut rendered, events);
+    rendered
+}
+
+fn maintainer_name(maintainer: &Maintainer) -> Option<String> {
+    match maintainer {
+        Maintainer::Object(maintainer) => maintainer.name.clone(),
+        Maintainer::Byline(_) => maintainer.clone().into_object().name,
+    }
+}
+
+// Newest first, by semver; anything that doesn't parse sorts after the rest.
+fn versions_newest_first(packument: &Packument) -> Vec<(&String, &PackumentVersion)> {
+    let mut versions: Vec<_> = packument.versions.iter().flatten().collect();
+    versions.sort_by_cached_key(|(number, _)| {
+        std::cmp::Reverse(semver::Version::parse(number.as_str()).ok())
+    });
+    versions
+}
+
+fn package_page(pkg: &PackageIdentifier, packument: &Packument) -> Markup {
+    let name = pkg.to_string();
+    let latest = packument
+        .dist_tags
+        .as_ref()
+        .and_then(|tags| tags.latest.as_deref());
+    let latest_version = latest.and_then(|latest| packument.versions.as_ref()?.get(latest));
+
+    // Older publishes only carry the readme on each version.
+    let readme = packument
+        .readme
+        .as_deref()
+        .filter(|readme| !readme.is_empty())
+        .or_else(|| latest_version?.meta.get("readme")?.as_str());
+    let description = packument
+        .description
+        .as_deref()
+        .or_else(|| latest_version?.meta.get("description")?.as_str());
+    let homepage = packument.homepage.as_deref().filter(|url| is_safe_url(url));
+    let published = |number: &str| {
+        packument
+            .time
+            .as_ref()
+            .and_then(|time| time.versions.get(number))
+            .map(|time| time.format("%Y-%m-%d").to_string())
+    };
+
+    let body = html! {
+        article {
+            h1 { (name) }
+            @if let Some(description) = description {
+                p { (description) }
+            }
+            @if let Some(latest) = latest {
+                p.muted { "latest " code { (latest) } }
+            }
+            pre { code { "npm install " (name) } }
+            @match readme {
+                Some(readme) => (PreEscaped(render_readme(readme))),
+                None => p.muted { "This package has no readme." },
+            }
+        }
+        aside {
+            @if let Some(homepage) = homepage {
+                h2 { "Homepage" }
+                p { a href=(homepage) rel="nofollow noopener" { (homepage) } }
+            }
+            @if let Some(ref tags) = packument.dist_tags {
+                h2 { "Tags" }
+                table {
+                    @for (tag, version) in tags.latest.iter().map(|v| ("latest", v)).chain(
+                        tags.tags.iter().map(|(tag, version)| (tag.as_str(), version))
+                    ) {
+                        tr { td { (tag) } td { code { (version) } } }
+                    }
+                }
+            }
+            @if let Some(ref maintainers) = packument.maintainers {
+                h2 { "Maintainers" }
+                ul {
+                    @for name in maintainers.iter().filter_map(maintainer_name) {
+                        li { (name) }
+                    }
+                }
+            }
+            h2 { "Versions" }
+            table {
+                @for (number, version) in versions_newest_first(packument) {
+                    tr {
+                        td {
+                            a href=(safe_url(version.dist.tarball.as_str().into())) {
+                                code { (number) }
+                            }
+                            @if version.deprecated.is_some() {
+                                " " span.deprecated title=[version.deprecated.as_deref()] {
+                                    "deprecated"
+                                }
+                            }
+                        }
+                        td.muted { (published(number).unwrap_or_default()) }
+                    }
+                }
+            }
+        }
+    };
+
+    layout(name.as_str(), body)
+}
+
+#[instrument(skip(state))]
+async fn get_package_page<S>(
+    State(state): State<S>,
+    user: Option<Authenticated>,
+    Path(pkg): Path<String>,
+) -> Result<Html<String>, PageError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(pkg.as_str())?;
+    render_package(&state, user, pkg).await
+}
+
+#[instrument(skip(state))]
+async fn get_scoped_package_page<S>(
+    State(state): State<S>,
+    user: Option<Authenticated>,
+    Path((scope, pkg)): Path<(String, String)>,
+) -> Result<Html<String>, PageError>
+where
+    S: PolicyHolder + std::fmt::Debug,
+{
+    let pkg = parse_package(format!("@{}/{}", scope, pkg).as_str())?;
+    render_package(&state, user, pkg).await
+}
+
+async fn render_package<S>(
+    state: &S,
+    user: Option<Authenticated>,
+    pkg: PackageIdentifier,
+) -> Result<Html<String>, PageError>
+where
+    S: PolicyHolder,
+{
+    if !can_install(state, user.as_ref().map(|user| &user.0), &pkg).await? {
+        return Err(package_not_found(&pkg).into());
+    }
+
+    let packument = state
+        .as_package_storage()
+        .fetch_packument(&pkg)
+        .await
+        .map_err(|e| storage_error(e, || package_not_found(&pkg)))?;
+
+    Ok(Html(package_page(&pkg, &packument).into_string()))
+}
+
+pub(crate) fn routes<S, B>() -> ApiRouter<S, B>
+where
+    S: PolicyHolder + Clone + Sync + Send + 'static + std::fmt::Debug,
+    B: Sync + Send + HttpBody + std::fmt::Debug + Into<Body> + 'static,
+    <B as HttpBody>::Data: 'static + Send + Sync,
+    <B as HttpBody>::Error: std::error::Error + 'static + Send + Sync,
+{
+    ApiRouter::new()
+        .route(
+            "/package/:pkg",
+            get(get_package_page::<S>, "A package's page, as HTML"),
+        )
+        .route(
+            "/package/@:scope/:pkg",
+            get(
+                get_scoped_package_page::<S>,
+                "A scoped package's page, as HTML",
+            ),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readme_html_is_not_trusted() {
+        let rendered = render_readme(
+            "# hi\n\n<script>alert(1)</script>\n\n[ok](https://example.com) [bad](javascript:alert(1))",
+        );
+        assert!(rendered.contains("<h1>hi</h1>"));
+        assert!(!rendered.contains("<script>"));
+        assert!(rendered.contains("&lt;script&gt;"));
+        assert!(rendered.contains(r#"href="https://example.com""#));
+        assert!(!rendered.contains("javascript:"));
+        assert!(is_safe_url("/relative/path"));
+        assert!(!is_safe_url(" JavaScript:alert(1)"));
+    }
+}
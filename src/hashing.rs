@@ -0,0 +1,397 @@
+//! Integrity hashing for tarballs and cached content.
+//!
+//! The digest backend is chosen at compile time: `ring` by default, or OpenSSL when built
+//! with the `fips` feature, so that hashing is performed by whatever (FIPS-validated)
+//! provider the system OpenSSL is configured with.
+//!
+//! That doesn't reach the read-through cache: cacache hashes the content it stores itself,
+//! with its own implementation, whichever backend this module uses. [`Algorithm`] only
+//! chooses which algorithm it uses.
+
+use std::{fmt::Display, str::FromStr};
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[cfg(not(any(feature = "ring", feature = "fips")))]
+compile_error!("one of the `ring` or `fips` features must be enabled to provide a hash backend");
+
+/// True when hashing is delegated to OpenSSL for FIPS compliance.
+pub const FIPS: bool = cfg!(feature = "fips");
+
+#[derive(Debug, Error)]
+pub enum HashError {
+    #[error("Unsupported hash algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    #[error("Malformed integrity string: {0}")]
+    MalformedIntegrity(String),
+    #[error("Hash backend failure: {0}")]
+    Backend(String),
+}
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    /// Only used for the legacy `dist.shasum` field.
+    Sha1,
+    Sha256,
+    #[default]
+    Sha512,
+}
+
+impl Algorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+        }
+    }
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = HashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha1" => Ok(Algorithm::Sha1),
+            "sha256" => Ok(Algorithm::Sha256),
+            "sha512" => Ok(Algorithm::Sha512),
+            other => Err(HashError::UnsupportedAlgorithm(other.to_string())),
+        }
+    }
+}
+
+#[cfg(feature = "tokio-backends")]
+impl From<Algorithm> for cacache::Algorithm {
+    fn from(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha1 => cacache::Algorithm::Sha1,
+            Algorithm::Sha256 => cacache::Algorithm::Sha256,
+            Algorithm::Sha512 => cacache::Algorithm::Sha512,
+        }
+    }
+}
+
+#[cfg(feature = "fips")]
+mod backend {
+    use super::{Algorithm, HashError};
+    use openssl::hash::{Hasher, MessageDigest};
+
+    pub(super) struct Context(Hasher);
+
+    fn backend_error(e: openssl::error::ErrorStack) -> HashError {
+        HashError::Backend(e.to_string())
+    }
+
+    impl Context {
+        pub(super) fn new(algorithm: Algorithm) -> Result<Self, HashError> {
+            let digest = match algorithm {
+                Algorithm::Sha1 => MessageDigest::sha1(),
+                Algorithm::Sha256 => MessageDigest::sha256(),
+                Algorithm::Sha512 => MessageDigest::sha512(),
+            };
+            Ok(Context(Hasher::new(digest).map_err(backend_error)?))
+        }
+
+        pub(super) fn update(&mut self, data: &[u8]) -> Result<(), HashError> {
+            self.0.update(data).map_err(backend_error)
+        }
+
+        pub(super) fn finish(mut self) -> Result<Vec<u8>, HashError> {
+            Ok(self.0.finish().map_err(backend_error)?.to_vec())
+        }
+    }
+
+    pub(super) fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, HashError> {
+        use openssl::{pkey::PKey, sign::Signer};
+
+        let key = PKey::hmac(key).map_err(backend_error)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(backend_error)?;
+        signer.update(data).map_err(backend_error)?;
+        signer.sign_to_vec().map_err(backend_error)
+    }
+
+    pub(super) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && openssl::memcmp::eq(a, b)
+    }
+}
+
+#[cfg(all(feature = "ring", not(feature = "fips")))]
+mod backend {
+    use super::{Algorithm, HashError};
+    use ring::digest;
+
+    pub(super) struct Context(digest::Context);
+
+    impl Context {
+        pub(super) fn new(algorithm: Algorithm) -> Result<Self, HashError> {
+            let algorithm = match algorithm {
+                Algorithm::Sha1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+                Algorithm::Sha256 => &digest::SHA256,
+                Algorithm::Sha512 => &digest::SHA512,
+            };
+            Ok(Context(digest::Context::new(algorithm)))
+        }
+
+        pub(super) fn update(&mut self, data: &[u8]) -> Result<(), HashError> {
+            self.0.update(data);
+            Ok(())
+        }
+
+        pub(super) fn finish(self) -> Result<Vec<u8>, HashError> {
+            Ok(self.0.finish().as_ref().to_vec())
+        }
+    }
+
+    pub(super) fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, HashError> {
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key);
+        Ok(ring::hmac::sign(&key, data).as_ref().to_vec())
+    }
+
+    pub(super) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        ring::constant_time::verify_slices_are_equal(a, b).is_ok()
+    }
+}
+
+/// An incremental hasher over a single algorithm.
+pub struct Hasher {
+    algorithm: Algorithm,
+    context: backend::Context,
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Result<Self, HashError> {
+        Ok(Self {
+            algorithm,
+            context: backend::Context::new(algorithm)?,
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> Result<(), HashError> {
+        self.context.update(data)
+    }
+
+    pub fn finish(self) -> Result<Digest, HashError> {
+        Ok(Digest {
+            algorithm: self.algorithm,
+            bytes: self.context.finish()?,
+        })
+    }
+}
+
+/// HMAC-SHA256 of `data` under `key`, computed by the active backend.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, HashError> {
+    backend::hmac_sha256(key, data)
+}
+
+/// Check an HMAC-SHA256 without leaking how much of it matched.
+pub fn verify_hmac_sha256(key: &[u8], data: &[u8], expected: &[u8]) -> Result<bool, HashError> {
+    Ok(backend::constant_time_eq(
+        hmac_sha256(key, data)?.as_slice(),
+        expected,
+    ))
+}
+
+/// A single digest, displayed in Subresource Integrity form (`sha512-<base64>`).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Digest {
+    pub algorithm: Algorithm,
+    pub bytes: Vec<u8>,
+}
+
+impl Digest {
+    pub fn compute(algorithm: Algorithm, data: &[u8]) -> Result<Self, HashError> {
+        let mut hasher = Hasher::new(algorithm)?;
+        hasher.update(data)?;
+        hasher.finish()
+    }
+
+    /// Parse the hex form used by `dist.shasum`.
+    pub fn from_hex(algorithm: Algorithm, hex: &str) -> Result<Self, HashError> {
+        let bytes =
+            hex::decode(hex.trim()).map_err(|_| HashError::MalformedIntegrity(hex.to_string()))?;
+        Ok(Self { algorithm, bytes })
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.bytes.as_slice())
+    }
+}
+
+impl Display for Digest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.algorithm,
+            base64::engine::general_purpose::STANDARD.encode(self.bytes.as_slice())
+        )
+    }
+}
+
+impl FromStr for Digest {
+    type Err = HashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((algorithm, encoded)) = s.trim().split_once('-') else {
+            return Err(HashError::MalformedIntegrity(s.to_string()));
+        };
+
+        // SRI allows trailing "?options"; we don't use any.
+        let encoded = encoded.split('?').next().unwrap_or(encoded);
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|_| HashError::MalformedIntegrity(s.to_string()))?;
+
+        Ok(Self {
+            algorithm: algorithm.parse()?,
+            bytes,
+        })
+    }
+}
+
+/// A parsed SRI string, which may list several digests separated by whitespace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Integrity {
+    digests: Vec<Digest>,
+}
+
+impl Integrity {
+    /// The strongest digest we know how to compute.
+    pub fn strongest(&self) -> Option<&Digest> {
+        self.digests.iter().max_by_key(|digest| digest.algorithm)
+    }
+
+    pub fn verify(&self, data: &[u8]) -> Result<bool, HashError> {
+        let Some(expected) = self.strongest() else {
+            return Ok(false);
+        };
+
+        Ok(Digest::compute(expected.algorithm, data)? == *expected)
+    }
+
+    /// Compare on the strongest algorithm both list, or `None` if they have none in common.
+    pub fn matches(&self, other: &Integrity) -> Option<bool> {
+        let algorithm = self
+            .digests
+            .iter()
+            .map(|digest| digest.algorithm)
+            .filter(|algorithm| {
+                other
+                    .digests
+                    .iter()
+                    .any(|digest| digest.algorithm == *algorithm)
+            })
+            .max()?;
+
+        Some(
+            self.digests
+                .iter()
+                .filter(|digest| digest.algorithm == algorithm)
+                .any(|digest| other.digests.contains(digest)),
+        )
+    }
+}
+
+impl From<Digest> for Integrity {
+    fn from(digest: Digest) -> Self {
+        Self {
+            digests: vec![digest],
+        }
+    }
+}
+
+impl FromStr for Integrity {
+    type Err = HashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Unknown algorithms are skipped rather than rejected, per the SRI spec.
+        let digests: Vec<Digest> = s
+            .split_whitespace()
+            .filter_map(|entry| entry.parse().ok())
+            .collect();
+
+        if digests.is_empty() {
+            return Err(HashError::MalformedIntegrity(s.to_string()));
+        }
+
+        Ok(Self { digests })
+    }
+}
+
+impl Display for Integrity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut first = true;
+        for digest in &self.digests {
+            if !first {
+                f.write_str(" ")?;
+            }
+            first = false;
+            Display::fmt(digest, f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_roundtrip() {
+        let digest = Digest::compute(Algorithm::Sha512, b"abc").unwrap();
+        let sri = "sha512-3a81oZNherrMQXNJriBBMRLm+k6JqX6iCp7u5ktV05ohkpkqJ0/BqDa6PCOj/uu9RU1EI2Q86A4qmslPpUyknw==";
+        assert_eq!(digest.to_string(), sri);
+        assert_eq!(sri.parse::<Digest>().unwrap(), digest);
+
+        let shasum = Digest::compute(Algorithm::Sha1, b"abc").unwrap();
+        assert_eq!(shasum.to_hex(), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(
+            Digest::from_hex(Algorithm::Sha1, "a9993e364706816aba3e25717850c26c9cd0d89d").unwrap(),
+            shasum
+        );
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test case 2.
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?").unwrap();
+        assert_eq!(
+            hex::encode(mac.as_slice()),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert!(verify_hmac_sha256(b"Jefe", b"what do ya want for nothing?", &mac).unwrap());
+        assert!(!verify_hmac_sha256(b"Jeff", b"what do ya want for nothing?", &mac).unwrap());
+    }
+
+    #[test]
+    fn test_integrity_prefers_strongest() {
+        let integrity: Integrity = format!(
+            "sha1-qZk+NkcGgWq6PiVxeFDCbJzQ2J0= md5-deadbeef {}",
+            Digest::compute(Algorithm::Sha512, b"abc").unwrap()
+        )
+        .parse()
+        .unwrap();
+
+        assert_eq!(integrity.strongest().unwrap().algorithm, Algorithm::Sha512);
+        assert!(integrity.verify(b"abc").unwrap());
+        assert!(!integrity.verify(b"abd").unwrap());
+
+        let sha512 = Integrity::from(Digest::compute(Algorithm::Sha512, b"abc").unwrap());
+        let sha256 = Integrity::from(Digest::compute(Algorithm::Sha256, b"abc").unwrap());
+        let tampered = Integrity::from(Digest::compute(Algorithm::Sha512, b"abd").unwrap());
+        assert_eq!(integrity.matches(&sha512), Some(true));
+        assert_eq!(integrity.matches(&tampered), Some(false));
+        assert_eq!(integrity.matches(&sha256), None);
+    }
+}
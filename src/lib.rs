@@ -1,35 +1,116 @@
+pub mod client;
+pub mod confusion;
+pub mod error;
+pub mod events;
 mod extractors;
+pub mod follow;
 mod handlers;
+pub mod hashing;
 mod layers;
+#[cfg(feature = "tokio-backends")]
+pub mod migrations;
 mod models;
 mod policies;
+pub mod signing;
+pub mod sync;
+pub mod tasks;
 
-pub use handlers::v1::routes;
+pub use handlers::v1::{prune_previews, routes};
 pub use policies::policy::Policy;
 
-pub use policies::{Authenticator, Configurator, PackageStorage, TokenAuthorizer};
+pub use policies::{
+    AccessControl, Advisories, Authenticator, Configurator, DownloadCounts, Geolocator, Moderation,
+    PackageStorage, PackumentTransform, TokenAuthorizer, Webhooks,
+};
 
 pub mod policy {
+    pub mod access_control {
+        pub use crate::policies::access_control::in_memory::InMemoryAccessControl as InMemory;
+        pub use crate::policies::access_control::{Access, PackageEvent, Permission, Transfer};
+    }
+
+    pub mod advisories {
+        pub use crate::policies::advisories::in_memory::InMemoryAdvisories as InMemory;
+        pub use crate::policies::advisories::remote::RemoteAdvisories as Remote;
+    }
+
     pub mod token_authorizers {
         pub use crate::policies::token_authorizer::in_memory::InMemoryTokenAuthorizer as InMemory;
     }
 
     pub mod authenticators {
         pub use crate::policies::authenticator::oauth::OAuthAuthenticator as OAuth;
+        pub use crate::policies::authenticator::{IdentityStatus, LoginSessionError};
     }
 
     pub mod configurators {
         pub use crate::policies::configurator::env::EnvConfigurator as Env;
+        pub use crate::policies::configurator::{LoginOutcome, LoginPage, Preset};
+    }
+
+    pub mod download_counts {
+        #[cfg(feature = "tokio-backends")]
+        pub use crate::policies::download_counts::sqlite::SqliteDownloadCounts as Sqlite;
+        pub use crate::policies::download_counts::DownloadPeriod;
+    }
+
+    pub mod geolocation {
+        pub use crate::policies::geolocation::in_memory::InMemoryGeolocator as InMemory;
+        pub use crate::policies::geolocation::maxmind::MaxMindGeolocator as MaxMind;
+        pub use crate::policies::geolocation::Location;
+    }
+
+    pub mod moderation {
+        pub use crate::policies::moderation::in_memory::InMemoryModeration as InMemory;
+        pub use crate::policies::moderation::Quarantine;
+    }
+
+    pub mod transform {
+        pub use crate::policies::transform::{ServedPackument, TrimVersions};
+    }
+
+    pub mod webhooks {
+        pub use crate::policies::webhooks::in_memory::InMemoryWebhooks as InMemory;
+        pub use crate::policies::webhooks::{
+            delivery_signature, Delivery, DeliveryStatus, Hook, HookEvent, HookType, HookUpdate,
+            NewHook, SIGNATURE_HEADER,
+        };
     }
 
     pub mod storage {
         pub mod package {
-            pub use crate::policies::package_storage::read_through::ReadThrough;
+            pub use crate::policies::package_rules::PackageRules;
+            #[cfg(feature = "tokio-backends")]
+            pub use crate::policies::package_storage::changes::ChangeLog;
+            pub use crate::policies::package_storage::couchdb::CouchDb;
+            #[cfg(feature = "tokio-backends")]
+            pub use crate::policies::package_storage::directory::Directory;
+            pub use crate::policies::package_storage::fallback::FallbackStorage as Fallback;
+            pub use crate::policies::package_storage::guard::{Guard, GuardStorage};
+            pub use crate::policies::package_storage::hot_cache::HotCache;
+            pub use crate::policies::package_storage::in_memory::InMemoryPackageStorage as InMemory;
+            #[cfg(feature = "postgres")]
+            pub use crate::policies::package_storage::postgres::Postgres;
+            #[cfg(feature = "tokio-backends")]
+            pub use crate::policies::package_storage::read_through::{CacheEviction, ReadThrough};
+            #[cfg(feature = "redis")]
+            pub use crate::policies::package_storage::redis_cache::RedisCache;
             pub use crate::policies::package_storage::remote::RemoteRegistry;
+            pub use crate::policies::package_storage::rewrite::{
+                DependencyRewrite, RewriteDependencies, RewriteRule,
+            };
+            #[cfg(feature = "tokio-backends")]
+            pub use crate::policies::package_storage::sqlite::SqlitePackageStorage as Sqlite;
+            pub use crate::policies::package_storage::{
+                ContentEncoding, GarbageCollection, Offline, StorageError, TarballStats,
+            };
         }
 
         pub mod user {
             pub use crate::policies::user_storage::in_memory::InMemoryUserStorage as InMemory;
+            #[cfg(feature = "tokio-backends")]
+            pub use crate::policies::user_storage::sqlite::SqliteUserStorage as Sqlite;
+            pub use crate::policies::user_storage::{ExportedUser, UserExport};
         }
     }
 }
@@ -0,0 +1,257 @@
+//! Versioned on-disk schemas for storage backends.
+//!
+//! Each store keeps a schema version stamp next to its data and lists its [`Migration`]s in
+//! order. Opening a store brings it up to date; `serve migrate [--dry-run]` does the same
+//! ahead of time (or only reports what it would do), so operators can upgrade deliberately.
+
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use rusqlite::Connection;
+
+pub use crate::policies::download_counts::sqlite::migrations as download_counts;
+pub use crate::policies::package_storage::changes::migrations as change_log;
+#[cfg(feature = "postgres")]
+pub use crate::policies::package_storage::postgres::migrations as package_database;
+pub use crate::policies::package_storage::read_through::migrations as package_cache;
+pub use crate::policies::package_storage::sqlite::migrations as sqlite_packages;
+pub use crate::policies::user_storage::sqlite::migrations as sqlite_users;
+
+/// Somewhere a store records which schema version its data is in.
+pub trait SchemaStamp {
+    /// The recorded version; 0 for a store that has never been stamped.
+    fn schema_version(&self) -> anyhow::Result<u32>;
+
+    fn set_schema_version(&self, version: u32) -> anyhow::Result<()>;
+
+    /// Run `f` so that a failure leaves neither its changes nor a new stamp behind, where the
+    /// store supports that.
+    fn atomically(&self, f: &dyn Fn() -> anyhow::Result<()>) -> anyhow::Result<()> {
+        f()
+    }
+}
+
+impl SchemaStamp for Connection {
+    fn schema_version(&self) -> anyhow::Result<u32> {
+        Ok(self.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+    }
+
+    fn set_schema_version(&self, version: u32) -> anyhow::Result<()> {
+        // PRAGMA arguments can't be bound as parameters.
+        self.execute_batch(format!("PRAGMA user_version = {}", version).as_str())?;
+        Ok(())
+    }
+
+    fn atomically(&self, f: &dyn Fn() -> anyhow::Result<()>) -> anyhow::Result<()> {
+        let transaction = self.unchecked_transaction()?;
+        f()?;
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+/// A version stamp kept in a file, for stores that are plain directories.
+#[derive(Clone, Debug)]
+pub struct StampFile {
+    path: PathBuf,
+}
+
+impl StampFile {
+    pub const FILE_NAME: &'static str = "schema-version";
+
+    pub fn in_dir(dir: impl AsRef<Path>) -> Self {
+        Self {
+            path: dir.as_ref().join(Self::FILE_NAME),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or(Path::new("."))
+    }
+}
+
+impl SchemaStamp for StampFile {
+    fn schema_version(&self) -> anyhow::Result<u32> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(version) => Ok(version.trim().parse()?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set_schema_version(&self, version: u32) -> anyhow::Result<()> {
+        std::fs::create_dir_all(self.dir())?;
+        std::fs::write(&self.path, format!("{}\n", version))?;
+        Ok(())
+    }
+}
+
+/// One step from schema version `version - 1` to `version`.
+pub struct Migration<T> {
+    pub version: u32,
+    pub description: &'static str,
+    pub apply: fn(&T) -> anyhow::Result<()>,
+}
+
+impl<T> std::fmt::Debug for Migration<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Migration")
+            .field("version", &self.version)
+            .field("description", &self.description)
+            .finish()
+    }
+}
+
+/// The ordered migrations for one store.
+#[derive(Debug)]
+pub struct Migrator<T> {
+    store: &'static str,
+    migrations: Vec<Migration<T>>,
+}
+
+/// What a [`Migrator`] did, or with `dry_run`, would do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub store: &'static str,
+    pub from: u32,
+    pub to: u32,
+    pub applied: Vec<(u32, &'static str)>,
+    pub dry_run: bool,
+}
+
+impl Display for MigrationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.applied.is_empty() {
+            return write!(
+                f,
+                "{}: up to date at schema version {}",
+                self.store, self.from
+            );
+        }
+
+        let verb = if self.dry_run {
+            "would migrate"
+        } else {
+            "migrated"
+        };
+        write!(
+            f,
+            "{}: {} from {} to {}",
+            self.store, verb, self.from, self.to
+        )?;
+        for (version, description) in &self.applied {
+            write!(f, "\n  {}: {}", version, description)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: SchemaStamp> Migrator<T> {
+    pub fn new(store: &'static str) -> Self {
+        Self {
+            store,
+            migrations: Vec::new(),
+        }
+    }
+
+    /// Add the next migration. Versions must count up from 1 without gaps.
+    pub fn migration(
+        mut self,
+        description: &'static str,
+        apply: fn(&T) -> anyhow::Result<()>,
+    ) -> Self {
+        let version = self.migrations.len() as u32 + 1;
+        self.migrations.push(Migration {
+            version,
+            description,
+            apply,
+        });
+        self
+    }
+
+    /// The version this build writes.
+    pub fn latest(&self) -> u32 {
+        self.migrations.len() as u32
+    }
+
+    /// Bring `target` up to the latest version, or with `dry_run`, report what that would
+    /// take. Refuses to touch data stamped by a newer build.
+    pub fn run(&self, target: &T, dry_run: bool) -> anyhow::Result<MigrationReport> {
+        let from = target.schema_version()?;
+        if from > self.latest() {
+            anyhow::bail!(
+                "{} is at schema version {}, but this build only knows up to {}",
+                self.store,
+                from,
+                self.latest()
+            );
+        }
+
+        let mut report = MigrationReport {
+            store: self.store,
+            from,
+            to: from,
+            applied: Vec::new(),
+            dry_run,
+        };
+
+        for migration in self.migrations.iter().filter(|m| m.version > from) {
+            if !dry_run {
+                target.atomically(&|| {
+                    (migration.apply)(target)?;
+                    target.set_schema_version(migration.version)
+                })?;
+                tracing::info!(
+                    store = self.store,
+                    version = migration.version,
+                    description = migration.description,
+                    "applied storage migration"
+                );
+            }
+            report.to = migration.version;
+            report
+                .applied
+                .push((migration.version, migration.description));
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migrator() -> Migrator<Connection> {
+        Migrator::new("test")
+            .migration("create widgets", |connection: &Connection| {
+                connection.execute_batch("CREATE TABLE widgets (id INTEGER PRIMARY KEY);")?;
+                Ok(())
+            })
+            .migration("name widgets", |connection: &Connection| {
+                connection.execute_batch("ALTER TABLE widgets ADD COLUMN name TEXT;")?;
+                Ok(())
+            })
+    }
+
+    #[test]
+    fn test_migrations_run_in_order_once() {
+        let connection = Connection::open_in_memory().unwrap();
+
+        let report = migrator().run(&connection, true).unwrap();
+        assert_eq!((report.from, report.to, report.applied.len()), (0, 2, 2));
+        assert_eq!(connection.schema_version().unwrap(), 0);
+
+        let report = migrator().run(&connection, false).unwrap();
+        assert_eq!((report.from, report.to), (0, 2));
+        connection
+            .execute("INSERT INTO widgets (name) VALUES ('sprocket')", [])
+            .unwrap();
+
+        let report = migrator().run(&connection, false).unwrap();
+        assert!(report.applied.is_empty());
+
+        connection.set_schema_version(3).unwrap();
+        assert!(migrator().run(&connection, false).is_err());
+    }
+}
@@ -1,8 +1,18 @@
+mod audit;
+mod dist_tag_policy;
 mod package_version;
 mod packument;
+mod platform;
+mod provenance;
+mod version_range;
 use serde::{Deserialize, Serialize};
 
+pub use audit::*;
+pub use dist_tag_policy::*;
 pub use packument::*;
+pub use platform::*;
+pub use provenance::*;
+pub use version_range::*;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct User {
@@ -10,4 +20,64 @@ pub struct User {
     pub(crate) email: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) full_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) homepage: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) freenode: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) twitter: Option<String>,
+}
+
+/// A member's role within an organization, as managed by `npm org set`.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OrgRole {
+    #[default]
+    Developer,
+    Admin,
+    Owner,
+}
+
+/// The subset of profile fields `npm profile set` may change. Setting a field to the empty
+/// string clears it.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ProfileUpdate {
+    #[serde(default)]
+    pub fullname: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub homepage: Option<String>,
+    #[serde(default)]
+    pub freenode: Option<String>,
+    #[serde(default)]
+    pub twitter: Option<String>,
+}
+
+impl User {
+    pub(crate) fn apply_profile_update(&mut self, update: ProfileUpdate) {
+        fn clearable(value: String) -> Option<String> {
+            if value.is_empty() {
+                None
+            } else {
+                Some(value)
+            }
+        }
+
+        if let Some(fullname) = update.fullname {
+            self.full_name = clearable(fullname);
+        }
+        if let Some(email) = update.email {
+            self.email = email;
+        }
+        if let Some(homepage) = update.homepage {
+            self.homepage = clearable(homepage);
+        }
+        if let Some(freenode) = update.freenode {
+            self.freenode = clearable(freenode);
+        }
+        if let Some(twitter) = update.twitter {
+            self.twitter = clearable(twitter);
+        }
+    }
 }
@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// The body npm sends to `/-/npm/v1/security/audits`: a lockfile-shaped dependency tree.
+/// Fields we don't interpret are kept so the request can be forwarded verbatim.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AuditRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
+    #[serde(default)]
+    pub requires: BTreeMap<String, String>,
+
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, AuditDependency>,
+
+    #[serde(flatten)]
+    pub meta: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AuditDependency {
+    pub version: String,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dev: bool,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub optional: bool,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub requires: BTreeMap<String, String>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, AuditDependency>,
+
+    #[serde(flatten)]
+    pub meta: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Low,
+    Moderate,
+    High,
+    Critical,
+}
+
+impl Severity {
+    pub const ALL: [Severity; 5] = [
+        Severity::Info,
+        Severity::Low,
+        Severity::Moderate,
+        Severity::High,
+        Severity::Critical,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Low => "low",
+            Severity::Moderate => "moderate",
+            Severity::High => "high",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// A security advisory in the shape npm's audit endpoints report them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Advisory {
+    pub id: u64,
+    pub title: String,
+    pub module_name: String,
+    pub severity: Severity,
+    pub vulnerable_versions: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patched_versions: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overview: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recommendation: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cves: Vec<String>,
+
+    /// Weakness identifiers, e.g. `CWE-400`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cwe: Vec<String>,
+}
+
+/// The body of `/-/npm/v1/security/advisories/bulk`: package names to installed versions.
+pub type BulkAdvisoryRequest = BTreeMap<String, Vec<String>>;
+
+/// Advisories affecting the requested versions, keyed by package name.
+pub type BulkAdvisoryResponse = BTreeMap<String, Vec<BulkAdvisory>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BulkAdvisory {
+    pub id: u64,
+    pub url: String,
+    pub title: String,
+    pub severity: Severity,
+    pub vulnerable_versions: String,
+
+    #[serde(flatten)]
+    pub meta: serde_json::Value,
+}
+
+impl From<&Advisory> for BulkAdvisory {
+    fn from(advisory: &Advisory) -> Self {
+        Self {
+            id: advisory.id,
+            url: advisory.url.clone().unwrap_or_default(),
+            title: advisory.title.clone(),
+            severity: advisory.severity,
+            vulnerable_versions: advisory.vulnerable_versions.clone(),
+            meta: if advisory.cwe.is_empty() {
+                serde_json::json!({})
+            } else {
+                serde_json::json!({ "cwe": advisory.cwe })
+            },
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::parse_version;
+
+#[derive(Debug, Error)]
+pub enum DistTagPolicyError {
+    #[error("rule noPrereleaseLatest: {0} is a prerelease and may not be tagged latest")]
+    PrereleaseLatest(String),
+    #[error("rule tagPattern: tag {0:?} does not match {1:?}")]
+    TagPattern(String, String),
+    #[error("rule tagPattern: {0:?} is not a valid regular expression")]
+    InvalidPattern(String),
+}
+
+/// Rules an org applies to every dist-tag set on its packages, whether by `npm dist-tag` or
+/// at publish.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DistTagPolicy {
+    /// Never point `latest` at a prerelease.
+    #[serde(default)]
+    pub no_prerelease_latest: bool,
+
+    /// A regular expression every tag name must match, e.g. `^(latest|next|canary)$`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag_pattern: Option<String>,
+}
+
+impl DistTagPolicy {
+    fn pattern(&self) -> Result<Option<Regex>, DistTagPolicyError> {
+        self.tag_pattern
+            .as_deref()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .map_err(|_| DistTagPolicyError::InvalidPattern(pattern.to_string()))
+            })
+            .transpose()
+    }
+
+    /// Reject policies that could never be checked.
+    pub fn validate(&self) -> Result<(), DistTagPolicyError> {
+        self.pattern().map(|_| ())
+    }
+
+    pub fn check(&self, tag: &str, version: &str) -> Result<(), DistTagPolicyError> {
+        if let Some(pattern) = self.pattern()? {
+            if !pattern.is_match(tag) {
+                return Err(DistTagPolicyError::TagPattern(
+                    tag.to_string(),
+                    pattern.to_string(),
+                ));
+            }
+        }
+
+        let prerelease = parse_version(version).is_some_and(|version| !version.pre.is_empty());
+        if self.no_prerelease_latest && tag == "latest" && prerelease {
+            return Err(DistTagPolicyError::PrereleaseLatest(version.to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dist_tag_policy() {
+        let policy = DistTagPolicy {
+            no_prerelease_latest: true,
+            tag_pattern: Some("^(latest|next|canary)$".to_string()),
+        };
+
+        assert!(policy.check("latest", "1.0.0").is_ok());
+        assert!(policy.check("next", "2.0.0-beta.1").is_ok());
+        assert!(matches!(
+            policy.check("latest", "2.0.0-beta.1"),
+            Err(DistTagPolicyError::PrereleaseLatest(_))
+        ));
+        assert!(matches!(
+            policy.check("experimental", "1.0.0"),
+            Err(DistTagPolicyError::TagPattern(..))
+        ));
+        assert!(DistTagPolicy::default()
+            .check("anything", "0.0.1-0")
+            .is_ok());
+
+        let broken = DistTagPolicy {
+            tag_pattern: Some("(".to_string()),
+            ..Default::default()
+        };
+        assert!(broken.validate().is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashSet},
     fmt::{Debug, Display},
     str::FromStr,
     string::FromUtf8Error,
@@ -10,9 +10,38 @@ use serde::{Deserialize, Serialize};
 use std::io::Cursor;
 use tar::Archive;
 
+use base64::Engine;
 use chrono::{DateTime, Utc};
 use thiserror::Error;
 
+use crate::hashing::{Algorithm, Digest, Integrity};
+
+use super::{Attestations, Provenance, VersionRange};
+
+// What a version keeps in the abbreviated form: the fields installs read.
+#[cfg_attr(not(feature = "tokio-backends"), allow(dead_code))]
+const ABBREVIATED_VERSION_FIELDS: [&str; 19] = [
+    "name",
+    "version",
+    "deprecated",
+    "dependencies",
+    "optionalDependencies",
+    "devDependencies",
+    "bundleDependencies",
+    "peerDependencies",
+    "peerDependenciesMeta",
+    "acceptDependencies",
+    "bin",
+    "directories",
+    "dist",
+    "engines",
+    "_hasShrinkwrap",
+    "hasInstallScript",
+    "cpu",
+    "os",
+    "libc",
+];
+
 // Chosen at random.
 const MAX_FILE_COUNT: usize = 16000;
 
@@ -27,6 +56,7 @@ pub enum PackumentError {
     PackageIdentifierMustBeUtf8(#[from] FromUtf8Error),
 }
 
+#[derive(Clone)]
 pub struct PackageIdentifier {
     pub scope: Option<String>,
     pub name: String,
@@ -52,6 +82,62 @@ impl Debug for PackageIdentifier {
     }
 }
 
+impl PackageIdentifier {
+    /// Where a registry at `base` serves this package's tarball for `version`, in the layout
+    /// npm uses.
+    pub(crate) fn tarball_url(&self, base: &str, version: &str) -> String {
+        format!(
+            "{}/{}/-/{}-{}.tgz",
+            base.trim_end_matches('/'),
+            self,
+            self.name,
+            version
+        )
+    }
+
+    /// Where a registry at `base` serves the attestations for `version`.
+    pub(crate) fn attestations_url(&self, base: &str, version: &str) -> String {
+        let base = base.trim_end_matches('/');
+        match self.scope {
+            Some(ref scope) => {
+                format!(
+                    "{}/-/npm/v1/attestations/@{}%2f{}@{}",
+                    base, scope, self.name, version
+                )
+            }
+            None => format!("{}/-/npm/v1/attestations/{}@{}", base, self.name, version),
+        }
+    }
+
+    /// Split a tarball's file name into the package and version it is for. Names may contain
+    /// hyphens and digits, and versions prereleases and build metadata, so the name ends at
+    /// the first hyphen after which a whole version follows. npm leaves a scoped package's
+    /// scope out of the name; some registries serve `@{scope}/{name}-{version}.tgz`.
+    pub fn parse_tarball_name(tarball: &str) -> Option<(PackageIdentifier, &str)> {
+        let stem = tarball.strip_suffix(".tgz")?;
+        stem.match_indices('-').find_map(|(idx, _)| {
+            let version = &stem[idx + 1..];
+            semver::Version::parse(version).ok()?;
+            Some((stem[..idx].parse().ok()?, version))
+        })
+    }
+
+    /// Whether a package read from a tarball name by [`PackageIdentifier::parse_tarball_name`]
+    /// is this one. `npm pack` names a scoped package's tarball `{scope}-{name}-{version}.tgz`.
+    pub fn is_named_by_tarball(&self, named: &PackageIdentifier) -> bool {
+        match named.scope {
+            Some(_) => named.scope == self.scope && named.name == self.name,
+            None => {
+                named.name == self.name
+                    || self
+                        .scope
+                        .as_ref()
+                        .is_some_and(|scope| named.name == format!("{}-{}", scope, self.name))
+            }
+        }
+    }
+}
+
 impl FromStr for PackageIdentifier {
     type Err = PackumentError;
 
@@ -88,6 +174,9 @@ impl FromStr for PackageIdentifier {
     }
 }
 
+// Maps in these types are BTreeMaps so that serializing the same document always produces
+// the same bytes (and therefore the same etag.)
+
 // Many thanks to Ryan Day for putting together type definitions [1]
 // for common NPM objects.
 // [1]: https://github.com/npm/types/blob/7f357f45e2b4205cd8474339a95092a5e6e77917/index.d.ts
@@ -95,11 +184,11 @@ impl FromStr for PackageIdentifier {
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
 pub struct MaintainerObject {
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) name: Option<String>,
+    pub name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) email: Option<String>,
+    pub email: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) url: Option<String>,
+    pub url: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -136,83 +225,244 @@ impl Maintainer {
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Signature {
-    pub(crate) keyid: String,
-    pub(crate) sig: String,
+    pub keyid: String,
+    pub sig: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Attachment {
-    pub(crate) content_type: String,
-    pub(crate) data: String,
+    pub content_type: String,
+    pub data: String,
+    /// The decoded size, as npm sends it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+}
+
+// What each of a publish's attachments is for, judged by its name.
+#[derive(Debug, PartialEq)]
+enum AttachmentKind {
+    Tarball,
+    Provenance,
+    // A tarball or bundle for a version other than the one being published.
+    OtherVersion,
+    // Anything else (a readme image, say); accepted and dropped, as nothing here serves it.
+    Extraneous,
+}
+
+// Content types clients send tarballs with.
+const TARBALL_CONTENT_TYPES: &[&str] = &[
+    "application/octet-stream",
+    "application/gzip",
+    "application/x-gzip",
+];
+
+// npm names attachments after the full package name; older clients used the bare name for
+// scoped packages.
+fn attachment_kind(pkg: &PackageIdentifier, version: &str, attachment: &str) -> AttachmentKind {
+    let Some((stem, extension)) = attachment.rsplit_once('.') else {
+        return AttachmentKind::Extraneous;
+    };
+    let kind = match extension {
+        "tgz" => AttachmentKind::Tarball,
+        "sigstore" => AttachmentKind::Provenance,
+        _ => return AttachmentKind::Extraneous,
+    };
+
+    let this_version = [
+        format!("{}-{}", pkg, version),
+        format!("{}-{}", pkg.name, version),
+    ];
+    if this_version.iter().any(|expected| expected == stem) {
+        return kind;
+    }
+
+    let other_version = [format!("{}-", pkg), format!("{}-", pkg.name)]
+        .iter()
+        .filter_map(|prefix| stem.strip_prefix(prefix.as_str()))
+        .any(|rest| semver::Version::parse(rest).is_ok());
+    if other_version {
+        AttachmentKind::OtherVersion
+    } else {
+        AttachmentKind::Extraneous
+    }
+}
+
+impl Attachment {
+    fn decode(&self) -> anyhow::Result<Vec<u8>> {
+        let Ok(data) = base64::engine::general_purpose::STANDARD.decode(self.data.as_str()) else {
+            anyhow::bail!("Attachment was not valid base64")
+        };
+        if self.length.is_some_and(|length| length != data.len()) {
+            anyhow::bail!("Attachment length did not match its data")
+        }
+        Ok(data)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Dist {
-    pub(crate) tarball: String,
-    pub(crate) shasum: String,
+    pub tarball: String,
+    pub shasum: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) integrity: Option<String>,
+    pub integrity: Option<String>,
 
     #[serde(rename = "fileCount", skip_serializing_if = "Option::is_none")]
-    pub(crate) file_count: Option<usize>,
+    pub file_count: Option<usize>,
 
     #[serde(rename = "unpackedSize", skip_serializing_if = "Option::is_none")]
-    pub(crate) unpacked_size: Option<usize>,
+    pub unpacked_size: Option<usize>,
 
-    pub(crate) signatures: Option<Vec<Signature>>,
+    pub signatures: Option<Vec<Signature>>,
 
     #[serde(rename = "npm-signature", skip_serializing_if = "Option::is_none")]
-    pub(crate) npm_signature: Option<String>,
+    pub npm_signature: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attestations: Option<Attestations>,
+}
+
+impl Dist {
+    /// The advertised digests: `integrity`, or failing that, the legacy `shasum`.
+    pub(crate) fn advertised_integrity(&self) -> Option<Integrity> {
+        if let Some(integrity) = self
+            .integrity
+            .as_deref()
+            .and_then(|integrity| integrity.parse().ok())
+        {
+            return Some(integrity);
+        }
+
+        if self.shasum.is_empty() {
+            return None;
+        }
+        Digest::from_hex(Algorithm::Sha1, self.shasum.as_str())
+            .ok()
+            .map(Integrity::from)
+    }
+
+    /// Check tarball bytes against the advertised `integrity` and legacy `shasum`.
+    pub(crate) fn verify(&self, tarball: &[u8]) -> anyhow::Result<()> {
+        if let Some(ref integrity) = self.integrity {
+            let integrity: Integrity = integrity.parse()?;
+            if !integrity.verify(tarball)? {
+                anyhow::bail!("Tarball did not match dist.integrity")
+            }
+        }
+
+        if !self.shasum.is_empty() {
+            let expected = Digest::from_hex(Algorithm::Sha1, self.shasum.as_str())?;
+            if Digest::compute(Algorithm::Sha1, tarball)? != expected {
+                anyhow::bail!("Tarball did not match dist.shasum")
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct PackumentVersion {
     #[serde(rename = "gitHead", skip_serializing_if = "Option::is_none")]
-    pub(crate) git_head: Option<String>,
+    pub git_head: Option<String>,
 
     #[serde(rename = "_id")]
-    pub(crate) id: String,
+    pub id: String,
 
     #[serde(rename = "_rev")]
-    pub(crate) rev: Option<String>,
+    pub rev: Option<String>,
 
     #[serde(rename = "npmVersion", skip_serializing_if = "Option::is_none")]
-    pub(crate) npm_version: Option<String>,
+    pub npm_version: Option<String>,
 
     #[serde(rename = "nodeVersion", skip_serializing_if = "Option::is_none")]
-    pub(crate) node_version: Option<String>,
+    pub node_version: Option<String>,
 
     #[serde(rename = "_npmUser", skip_serializing_if = "Option::is_none")]
-    pub(crate) npm_user: Option<Maintainer>,
+    pub npm_user: Option<Maintainer>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) maintainers: Option<Vec<Maintainer>>,
+    pub maintainers: Option<Vec<Maintainer>>,
 
-    pub(crate) dist: Dist,
+    pub dist: Dist,
 
     #[serde(rename = "_hasShrinkwrap")]
-    pub(crate) has_shrinkwrap: Option<bool>,
+    pub has_shrinkwrap: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub types: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) types: Option<String>,
+    pub deprecated: Option<String>,
 
     #[serde(flatten)]
-    pub(crate) meta: serde_json::Value,
+    pub meta: serde_json::Value,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct PackumentTime {
-    pub(crate) created: DateTime<Utc>,
-    pub(crate) modified: DateTime<Utc>,
+    pub created: DateTime<Utc>,
+    pub modified: DateTime<Utc>,
+    /// Set on the tombstone a full unpublish leaves behind.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unpublished: Option<Unpublished>,
     #[serde(flatten)]
-    pub(crate) versions: HashMap<String, DateTime<Utc>>,
+    pub versions: BTreeMap<String, DateTime<Utc>>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// When a package was unpublished, and the versions it had; `time.unpublished` in npm's
+/// tombstones.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Unpublished {
+    pub time: DateTime<Utc>,
+    pub versions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct DistTags {
-    pub(crate) latest: Option<String>,
+    pub latest: Option<String>,
     #[serde(flatten)]
-    pub(crate) tags: HashMap<String, String>,
+    pub tags: BTreeMap<String, String>,
+}
+
+impl DistTags {
+    /// Every tag with the version it points at, `latest` included.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.latest
+            .iter()
+            .map(|latest| ("latest", latest.as_str()))
+            .chain(
+                self.tags
+                    .iter()
+                    .map(|(tag, version)| (tag.as_str(), version.as_str())),
+            )
+    }
+}
+
+/// The entry of `versions` that `spec` refers to, as [`Packument::resolve_version`] resolves
+/// it; for anything that keys by version number alongside a packument's dist-tags.
+pub(crate) fn resolve_version_in<'a, T>(
+    versions: &'a BTreeMap<String, T>,
+    dist_tags: Option<&DistTags>,
+    spec: &str,
+) -> Option<&'a T> {
+    if let Some(version) = versions.get(spec) {
+        return Some(version);
+    }
+
+    if let Some(dist_tags) = dist_tags {
+        let tagged = if spec == "latest" {
+            dist_tags.latest.as_ref()
+        } else {
+            dist_tags.tags.get(spec)
+        };
+        if let Some(tagged) = tagged {
+            return versions.get(tagged);
+        }
+    }
+
+    let range: VersionRange = spec.parse().ok()?;
+    let resolved = range.max_satisfying(versions.keys().map(String::as_str))?;
+    versions.get(resolved)
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -239,64 +489,64 @@ pub enum License {
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Bugs {
-    pub(crate) url: Option<String>,
+    pub url: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
 pub struct Packument {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
-    pub(crate) id: Option<String>,
+    pub id: Option<String>,
 
     #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
-    pub(crate) rev: Option<String>,
+    pub rev: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) name: Option<String>,
+    pub name: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) readme: Option<String>,
+    pub readme: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) description: Option<String>,
+    pub description: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none", rename = "dist-tags")]
-    pub(crate) dist_tags: Option<DistTags>,
+    pub dist_tags: Option<DistTags>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) versions: Option<HashMap<String, PackumentVersion>>,
+    pub versions: Option<BTreeMap<String, PackumentVersion>>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) maintainers: Option<Vec<Maintainer>>,
+    pub maintainers: Option<Vec<Maintainer>>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) time: Option<PackumentTime>,
+    pub time: Option<PackumentTime>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) homepage: Option<String>,
+    pub homepage: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) keywords: Option<Vec<String>>,
+    pub keywords: Option<Vec<String>>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) repository: Option<Repository>,
+    pub repository: Option<Repository>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) author: Option<Maintainer>,
+    pub author: Option<Maintainer>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) bugs: Option<Bugs>,
+    pub bugs: Option<Bugs>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) license: Option<License>,
+    pub license: Option<License>,
 
     #[serde(rename = "users", skip_serializing_if = "Option::is_none")]
-    pub(crate) stargazers: Option<HashMap<String, bool>>,
+    pub stargazers: Option<BTreeMap<String, bool>>,
 
     #[serde(rename = "readmeFilename", skip_serializing_if = "Option::is_none")]
-    pub(crate) readme_filename: Option<String>,
+    pub readme_filename: Option<String>,
 
     #[serde(rename = "_attachments", skip_serializing_if = "Option::is_none")]
-    pub(crate) attachments: Option<HashMap<String, Attachment>>,
+    pub attachments: Option<BTreeMap<String, Attachment>>,
 }
 
 #[derive(Clone, Debug)]
@@ -313,21 +563,197 @@ pub enum PackageModification {
         tag: String,
     },
 
-    AddMaintainer(String),
+    /// The email is the new maintainer's own, from their account; whatever the client sent
+    /// is ignored.
+    AddMaintainer {
+        name: String,
+        email: Option<String>,
+    },
     RemoveMaintainer(String),
 
     AddVersion {
         tag: String,
         version: Box<PackumentVersion>,
         tarball: Option<Vec<u8>>,
+        provenance: Option<Provenance>,
     },
+
+    /// Maps version numbers to their new deprecation message; `None` un-deprecates.
+    Deprecate(BTreeMap<String, Option<String>>),
+
+    /// Curated changes to the package's metadata, made without a republish.
+    UpdateMetadata(MetadataUpdate),
+
+    /// Versions to drop, along with their publish times and any dist-tags pointing at them.
+    RemoveVersions(Vec<String>),
+}
+
+/// Package-level fields a maintainer may correct in place. Absent fields are left alone, and
+/// `deprecated` works as [`PackageModification::Deprecate`] does.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MetadataUpdate {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keywords: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readme: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub homepage: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub deprecated: BTreeMap<String, Option<String>>,
+}
+
+impl MetadataUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.description.is_none()
+            && self.keywords.is_none()
+            && self.readme.is_none()
+            && self.homepage.is_none()
+            && self.deprecated.is_empty()
+    }
+
+    /// The names of the fields this changes, for audit events.
+    pub fn fields(&self) -> Vec<&'static str> {
+        [
+            ("description", self.description.is_some()),
+            ("keywords", self.keywords.is_some()),
+            ("readme", self.readme.is_some()),
+            ("homepage", self.homepage.is_some()),
+            ("deprecated", !self.deprecated.is_empty()),
+        ]
+        .into_iter()
+        .filter_map(|(field, changed)| changed.then_some(field))
+        .collect()
+    }
+}
+
+/// What a publish learns from reading its tarball.
+pub(crate) struct TarballContents {
+    /// The tarball's `package.json`.
+    pub(crate) manifest: serde_json::Value,
+    pub(crate) file_count: usize,
+    pub(crate) unpacked_size: usize,
+}
+
+/// Check that a tarball is a plausible npm package, within our size limits, and read its
+/// `package.json`.
+pub(crate) fn inspect_tarball(tarball: &[u8]) -> anyhow::Result<TarballContents> {
+    use std::io::Read;
+
+    let mut gunzipped = Decoder::new(Cursor::new(tarball))?;
+    let mut tarball = Archive::new(&mut gunzipped);
+
+    let mut unpacked_size = 0usize;
+    let mut file_count = 0usize;
+    let mut manifest = None;
+    for entry in tarball.entries()? {
+        let Ok(mut entry) = entry else {
+            anyhow::bail!("Encountered bad tarball entry")
+        };
+
+        unpacked_size += entry.size() as usize;
+        file_count += 1;
+
+        if file_count > MAX_FILE_COUNT {
+            anyhow::bail!("Tarball exceeded maximum file count")
+        }
+
+        if unpacked_size > MAX_UNPACKED_SIZE {
+            anyhow::bail!("Tarball exceeded maximum unpacked size")
+        }
+
+        let Ok(path) = entry.path() else {
+            anyhow::bail!("Malformed unicode path")
+        };
+
+        let Ok(path) = path.strip_prefix("package/") else {
+            anyhow::bail!("Tarball entry didn't start with 'package/'")
+        };
+
+        let is_manifest = path.display().to_string() == "package.json";
+        if is_manifest {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            let Ok(parsed) = serde_json::from_slice(data.as_slice()) else {
+                anyhow::bail!("Tarball's package.json was not valid JSON")
+            };
+            manifest = Some(parsed);
+        }
+    }
+
+    let Some(manifest) = manifest else {
+        anyhow::bail!("Tarball did not contain package.json")
+    };
+
+    Ok(TarballContents {
+        manifest,
+        file_count,
+        unpacked_size,
+    })
 }
 
 impl PackageModification {
-    pub(crate) fn from_diff(old: Packument, new: Packument) -> anyhow::Result<Self> {
-        if let Some((old_stargazers, new_stargazers)) = old.stargazers.zip(new.stargazers) {
-            let old_stargazers: HashSet<_> = old_stargazers.keys().map(String::as_str).collect();
-            let new_stargazers: HashSet<_> = new_stargazers.keys().map(String::as_str).collect();
+    /// A publish of a bare tarball, described by its own `package.json`. The version's `dist`
+    /// is computed here rather than trusted from the client.
+    pub(crate) fn from_tarball(
+        pkg: &PackageIdentifier,
+        tag: String,
+        registry: &str,
+        tarball: Vec<u8>,
+    ) -> anyhow::Result<Self> {
+        let contents = inspect_tarball(tarball.as_slice())?;
+        let serde_json::Value::Object(mut manifest) = contents.manifest else {
+            anyhow::bail!("Tarball's package.json was not an object")
+        };
+
+        if manifest.get("name").and_then(|name| name.as_str()) != Some(pkg.to_string().as_str()) {
+            anyhow::bail!("Tarball's package.json does not name {}", pkg)
+        }
+
+        let Some(number) = manifest.get("version").and_then(|version| version.as_str()) else {
+            anyhow::bail!("Tarball's package.json has no version")
+        };
+        let number = semver::Version::parse(number)?.to_string();
+
+        let dist = Dist {
+            tarball: pkg.tarball_url(registry, number.as_str()),
+            shasum: Digest::compute(Algorithm::Sha1, tarball.as_slice())?.to_hex(),
+            integrity: Some(Digest::compute(Algorithm::Sha512, tarball.as_slice())?.to_string()),
+            file_count: Some(contents.file_count),
+            unpacked_size: Some(contents.unpacked_size),
+            signatures: None,
+            npm_signature: None,
+            attestations: None,
+        };
+        manifest.insert("_id".to_string(), format!("{}@{}", pkg, number).into());
+        manifest.insert("version".to_string(), number.into());
+        manifest.insert("dist".to_string(), serde_json::to_value(dist)?);
+
+        Ok(PackageModification::AddVersion {
+            tag,
+            version: Box::new(serde_json::from_value(manifest.into())?),
+            tarball: Some(tarball),
+            provenance: None,
+        })
+    }
+
+    pub(crate) fn from_diff(old: &Packument, new: Packument) -> anyhow::Result<Self> {
+        // `npm star` sends only `_id`, `_rev` and `users`; a package that has never been
+        // starred has no `users` at all.
+        if let Some(ref new_stargazers) = new.stargazers {
+            let old_stargazers: HashSet<_> = old
+                .stargazers
+                .iter()
+                .flat_map(|stargazers| stargazers.iter())
+                .filter(|(_, starred)| **starred)
+                .map(|(name, _)| name.as_str())
+                .collect();
+            let new_stargazers: HashSet<_> = new_stargazers
+                .iter()
+                .filter(|(_, starred)| **starred)
+                .map(|(name, _)| name.as_str())
+                .collect();
 
             if old_stargazers != new_stargazers {
                 let mut removed: Vec<&str> = old_stargazers
@@ -358,20 +784,28 @@ impl PackageModification {
             }
         }
 
-        if let Some(((dist_tags, versions), attachments)) =
-            new.dist_tags.zip(new.versions).zip(new.attachments)
+        if let Some(((dist_tags, versions), attachments)) = new
+            .dist_tags
+            .as_ref()
+            .zip(new.versions.as_ref())
+            .zip(new.attachments.as_ref())
         {
             if (dist_tags.tags.len() == 1 && dist_tags.latest.is_none())
                 || (dist_tags.latest.is_some() && dist_tags.tags.is_empty())
             {
-                let Some(version_name) = dist_tags.latest.as_ref().or(dist_tags.tags.values().next()) else {
+                let Some(version_name) =
+                    dist_tags.latest.as_ref().or(dist_tags.tags.values().next())
+                else {
                     anyhow::bail!("Could not find tag for publish")
                 };
 
                 // TODO: validate the tag name!
-                let Some(tag_name) = dist_tags.latest.as_ref()
+                let Some(tag_name) = dist_tags
+                    .latest
+                    .as_ref()
                     .map(|_| "latest".to_string())
-                    .or(dist_tags.tags.keys().next().cloned()) else {
+                    .or(dist_tags.tags.keys().next().cloned())
+                else {
                     anyhow::bail!("Could not find new tag name")
                 };
 
@@ -379,18 +813,33 @@ impl PackageModification {
                     anyhow::bail!("Attempted tag publish failed: did not refer to new version")
                 };
 
-                let Some(pkg_name) = new.name.or(new.id) else {
+                let Some(pkg_name) = new.name.as_ref().or(new.id.as_ref()) else {
                     anyhow::bail!("Package name not present")
                 };
 
                 let pkg_name: PackageIdentifier = pkg_name.parse()?;
 
-                let attachment_name = format!("{}-{}.tgz", pkg_name.name, version_name);
-                let Some(attachment) = attachments.get(attachment_name.as_str()) else {
+                let mut tarball = None;
+                let mut bundle = None;
+                for (name, attachment) in attachments {
+                    match attachment_kind(&pkg_name, version_name, name) {
+                        AttachmentKind::Tarball if tarball.is_none() => tarball = Some(attachment),
+                        AttachmentKind::Provenance if bundle.is_none() => bundle = Some(attachment),
+                        AttachmentKind::Tarball | AttachmentKind::Provenance => {
+                            anyhow::bail!("Found more than one attachment like {}", name)
+                        }
+                        AttachmentKind::OtherVersion => {
+                            anyhow::bail!("Can only publish one version at a time, found {}", name)
+                        }
+                        AttachmentKind::Extraneous => {}
+                    }
+                }
+
+                let Some(attachment) = tarball else {
                     anyhow::bail!("Expected attachment not found")
                 };
 
-                if attachment.content_type != "application/octet-stream" {
+                if !TARBALL_CONTENT_TYPES.contains(&attachment.content_type.as_str()) {
                     anyhow::bail!(
                         "Expected attachment to have application/octet-stream content-type"
                     )
@@ -399,63 +848,117 @@ impl PackageModification {
                 // TODO: check times on old packument, make sure we aren't overwriting an old,
                 // deleted packument version
 
-                let mut r = Cursor::new(attachment.data.as_bytes());
-                let mut decoded = base64::read::DecoderReader::new(
-                    &mut r,
-                    &base64::engine::general_purpose::STANDARD,
-                );
-
-                let mut debase64d: Vec<u8> = Vec::with_capacity(attachment.data.as_bytes().len());
+                let debase64d = attachment.decode()?;
 
-                let mut decoded = io_tee::TeeReader::new(&mut decoded, &mut debase64d);
+                version.dist.verify(debase64d.as_slice())?;
 
-                let mut gunzipped = Decoder::new(&mut decoded)?;
-                let mut tarball = Archive::new(&mut gunzipped);
+                inspect_tarball(debase64d.as_slice())?;
 
-                let mut unpacked_size = 0usize;
-                let mut file_count = 0usize;
-                let mut saw_package_json = false;
-                for entry in tarball.entries()? {
-                    let Ok(entry) = entry else {
-                        anyhow::bail!("Encountered bad tarball entry")
-                    };
+                // `npm publish --provenance` attaches the bundle as JSON, not base64.
+                let provenance = match bundle {
+                    Some(bundle) if Provenance::is_bundle(bundle.content_type.as_str()) => {
+                        Some(Provenance::verify(
+                            bundle.data.as_str(),
+                            &pkg_name,
+                            version_name,
+                            debase64d.as_slice(),
+                        )?)
+                    }
+                    Some(_) => anyhow::bail!("Expected a sigstore bundle for provenance"),
+                    None => None,
+                };
 
-                    unpacked_size += entry.size() as usize;
-                    file_count += 1;
+                return Ok(PackageModification::AddVersion {
+                    tag: tag_name,
+                    version: Box::new(version.clone()),
+                    tarball: Some(debase64d),
+                    provenance,
+                });
+            }
+        }
 
-                    if file_count > MAX_FILE_COUNT {
-                        anyhow::bail!("Tarball exceeded maximum file count")
-                    }
+        // `npm unpublish pkg@version` re-sends the document without the version, and deletes
+        // its tarball separately.
+        if let Some((old_versions, new_versions)) = old.versions.as_ref().zip(new.versions.as_ref())
+        {
+            let removed: Vec<String> = old_versions
+                .keys()
+                .filter(|version| !new_versions.contains_key(*version))
+                .cloned()
+                .collect();
 
-                    if unpacked_size > MAX_UNPACKED_SIZE {
-                        anyhow::bail!("Tarball exceeded maximum unpacked size")
-                    }
+            if !removed.is_empty() {
+                if new_versions
+                    .keys()
+                    .any(|version| !old_versions.contains_key(version))
+                {
+                    anyhow::bail!("Cannot add and remove versions at once")
+                }
 
-                    let Ok(path) = entry.path() else {
-                        anyhow::bail!("Malformed unicode path")
-                    };
+                return Ok(Self::RemoveVersions(removed));
+            }
+        }
 
-                    let Ok(path) = path.strip_prefix("package/") else {
-                        anyhow::bail!("Tarball entry didn't start with 'package/'")
-                    };
+        // `npm dist-tag` has endpoints of its own; a client editing the document instead may
+        // still move or drop one tag at a time.
+        if let Some((old_tags, new_tags)) = old.dist_tags.as_ref().zip(new.dist_tags.as_ref()) {
+            let old_tags: BTreeMap<_, _> = old_tags.iter().collect();
+            let new_tags: BTreeMap<_, _> = new_tags.iter().collect();
+            if old_tags != new_tags {
+                let mut removed: Vec<_> = old_tags
+                    .keys()
+                    .filter(|tag| !new_tags.contains_key(*tag))
+                    .collect();
+                let mut moved: Vec<_> = new_tags
+                    .iter()
+                    .filter(|(tag, version)| old_tags.get(*tag) != Some(*version))
+                    .collect();
 
-                    saw_package_json =
-                        saw_package_json || path.display().to_string() == "package.json";
+                match (removed.pop(), moved.pop()) {
+                    (Some(tag), None) if removed.is_empty() => {
+                        return Ok(Self::RemoveTag {
+                            tag: tag.to_string(),
+                        })
+                    }
+                    (None, Some((tag, version))) if moved.is_empty() => {
+                        return Ok(Self::AddTag {
+                            tag: tag.to_string(),
+                            version: version.to_string(),
+                        })
+                    }
+                    _ => anyhow::bail!("Can only change a single dist-tag at a time"),
                 }
+            }
+        }
 
-                if !saw_package_json {
-                    anyhow::bail!("Tarball did not contain package.json")
-                }
+        // `npm deprecate` re-sends every version, with "deprecated" set (or emptied) on the
+        // versions matching the requested range.
+        if let Some((old_versions, new_versions)) = old.versions.as_ref().zip(new.versions.as_ref())
+        {
+            let deprecations: BTreeMap<_, _> = new_versions
+                .iter()
+                .filter_map(|(version, new_version)| {
+                    let old_version = old_versions.get(version)?;
+                    let message = new_version
+                        .deprecated
+                        .as_ref()
+                        .filter(|message| !message.is_empty());
+                    if old_version.deprecated.as_ref() == message {
+                        return None;
+                    }
 
-                return Ok(PackageModification::AddVersion {
-                    tag: tag_name,
-                    version: Box::new(version.clone()),
-                    tarball: Some(debase64d),
-                });
+                    Some((version.clone(), message.cloned()))
+                })
+                .collect();
+
+            if !deprecations.is_empty() {
+                return Ok(Self::Deprecate(deprecations));
             }
         }
 
-        if let Some((old_maintainers, new_maintainers)) = old.maintainers.zip(new.maintainers) {
+        if let Some((old_maintainers, new_maintainers)) =
+            old.maintainers.as_ref().zip(new.maintainers)
+        {
             let old_maintainers: HashSet<_> = old_maintainers
                 .iter()
                 .filter_map(|maint| maint.clone().into_object().name)
@@ -482,12 +985,312 @@ impl PackageModification {
                         anyhow::bail!("Can only add a single maintainer at a time")
                     }
 
-                    return Ok(Self::AddMaintainer(added.pop().unwrap().to_string()));
+                    return Ok(Self::AddMaintainer {
+                        name: added.pop().unwrap().to_string(),
+                        email: None,
+                    });
+                }
+            }
+        }
+
+        anyhow::bail!("Found no change to the package this registry supports")
+    }
+}
+
+impl Packument {
+    /// Move `_rev` on to the next CouchDB-style revision, `<generation>-<digest>`, where the
+    /// digest covers the rest of the document.
+    pub(crate) fn advance_rev(&mut self) -> anyhow::Result<()> {
+        let generation = self
+            .rev
+            .as_deref()
+            .and_then(|rev| rev.split_once('-'))
+            .and_then(|(generation, _)| generation.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        self.rev = None;
+        let digest = Digest::compute(Algorithm::Sha256, serde_json::to_vec(self)?.as_slice())?;
+        self.rev = Some(format!("{}-{}", generation + 1, &digest.to_hex()[..32]));
+        Ok(())
+    }
+
+    pub(crate) fn is_maintainer(&self, username: &str) -> bool {
+        self.maintainers
+            .iter()
+            .flatten()
+            .any(|maintainer| maintainer.clone().into_object().name.as_deref() == Some(username))
+    }
+
+    /// The abbreviated document npm asks for with `Accept: application/vnd.npm.install-v1+json`,
+    /// for packuments no upstream abbreviates for us.
+    #[cfg_attr(not(feature = "tokio-backends"), allow(dead_code))]
+    pub(crate) fn abbreviated(&self) -> serde_json::Result<serde_json::Value> {
+        let mut versions = serde_json::Map::new();
+        for (number, version) in self.versions.iter().flatten() {
+            let serde_json::Value::Object(fields) = serde_json::to_value(version)? else {
+                continue;
+            };
+            let fields = fields
+                .into_iter()
+                .filter(|(field, value)| {
+                    !value.is_null() && ABBREVIATED_VERSION_FIELDS.contains(&field.as_str())
+                })
+                .collect();
+            versions.insert(number.clone(), serde_json::Value::Object(fields));
+        }
+
+        Ok(serde_json::json!({
+            "name": self.name,
+            "modified": self.time.as_ref().map(|time| time.modified),
+            "dist-tags": self.dist_tags,
+            "versions": versions
+        }))
+    }
+
+    pub(crate) fn maintainer_names(&self) -> Vec<String> {
+        self.maintainers
+            .iter()
+            .flatten()
+            .filter_map(|maintainer| maintainer.clone().into_object().name)
+            .collect()
+    }
+
+    /// Find the version `spec` refers to: an exact version, then a dist-tag, then the
+    /// highest version satisfying a semver range.
+    pub fn resolve_version(&self, spec: &str) -> Option<&PackumentVersion> {
+        let versions = self.versions.as_ref()?;
+        resolve_version_in(versions, self.dist_tags.as_ref(), spec)
+    }
+
+    /// Turn this packument into the tombstone of a full unpublish: the versions, dist-tags
+    /// and maintainers go, and `time` records what was unpublished and when. Returns the
+    /// versions unpublished.
+    pub(crate) fn unpublish(&mut self) -> anyhow::Result<Vec<String>> {
+        let Some(versions) = self.versions.take() else {
+            anyhow::bail!("Cannot unpublish a package with no versions")
+        };
+        let versions: Vec<String> = versions.into_keys().collect();
+
+        let now = Utc::now();
+        let time = self.time.get_or_insert_with(|| PackumentTime {
+            created: now,
+            modified: now,
+            unpublished: None,
+            versions: BTreeMap::new(),
+        });
+        time.modified = now;
+        time.unpublished = Some(Unpublished {
+            time: now,
+            versions: versions.clone(),
+        });
+
+        *self = Packument {
+            id: self.id.take(),
+            rev: self.rev.take(),
+            name: self.name.take(),
+            time: self.time.take(),
+            ..Default::default()
+        };
+        Ok(versions)
+    }
+
+    /// Whether `number` was published here and has since been unpublished: the tombstone
+    /// lists it or, once the package has been published to again, `time` still records it.
+    pub(crate) fn was_unpublished(&self, number: &str) -> bool {
+        let Some(ref time) = self.time else {
+            return false;
+        };
+        let tombstoned = time
+            .unpublished
+            .as_ref()
+            .is_some_and(|unpublished| unpublished.versions.iter().any(|v| v == number));
+        let listed = self
+            .versions
+            .as_ref()
+            .is_some_and(|versions| versions.contains_key(number));
+
+        tombstoned || (!listed && time.versions.contains_key(number))
+    }
+
+    fn set_tag(&mut self, tag: String, version: String) {
+        let dist_tags = self.dist_tags.get_or_insert_with(|| DistTags {
+            latest: None,
+            tags: BTreeMap::new(),
+        });
+        if tag == "latest" {
+            dist_tags.latest = Some(version);
+        } else {
+            dist_tags.tags.insert(tag, version);
+        }
+    }
+
+    fn deprecate(&mut self, deprecations: BTreeMap<String, Option<String>>) -> anyhow::Result<()> {
+        let Some(ref mut versions) = self.versions else {
+            anyhow::bail!("Cannot deprecate versions of a package with no versions")
+        };
+
+        for (version, message) in deprecations {
+            let Some(version) = versions.get_mut(&version) else {
+                anyhow::bail!("Cannot deprecate unknown version {}", version)
+            };
+            version.deprecated = message;
+        }
+        Ok(())
+    }
+
+    /// Apply a modification to this (stored) packument.
+    pub(crate) fn apply(&mut self, modification: PackageModification) -> anyhow::Result<()> {
+        match modification {
+            PackageModification::AddStar(user) => {
+                self.stargazers
+                    .get_or_insert_with(Default::default)
+                    .insert(user, true);
+            }
+
+            PackageModification::RemoveStar(user) => {
+                if let Some(ref mut stargazers) = self.stargazers {
+                    stargazers.remove(&user);
+                }
+            }
+
+            PackageModification::Deprecate(deprecations) => {
+                self.deprecate(deprecations)?;
+            }
+
+            PackageModification::UpdateMetadata(update) => {
+                if !update.deprecated.is_empty() {
+                    self.deprecate(update.deprecated)?;
+                }
+                if let Some(description) = update.description {
+                    self.description = Some(description);
+                }
+                if let Some(keywords) = update.keywords {
+                    self.keywords = Some(keywords);
+                }
+                if let Some(readme) = update.readme {
+                    self.readme = Some(readme);
+                }
+                if let Some(homepage) = update.homepage {
+                    self.homepage = Some(homepage);
+                }
+                if let Some(ref mut time) = self.time {
+                    time.modified = Utc::now();
+                }
+            }
+
+            PackageModification::AddTag { tag, version } => {
+                if !self
+                    .versions
+                    .as_ref()
+                    .is_some_and(|versions| versions.contains_key(&version))
+                {
+                    anyhow::bail!("Cannot tag unknown version {}", version)
+                }
+
+                self.set_tag(tag, version);
+            }
+
+            // The tarball is the caller's to store; only the document changes here.
+            PackageModification::AddVersion { tag, version, .. } => {
+                let Some(number) = version.meta.get("version").and_then(|v| v.as_str()) else {
+                    anyhow::bail!("Published version has no version number")
+                };
+                let number = number.to_string();
+
+                let versions = self.versions.get_or_insert_with(BTreeMap::new);
+                if versions.contains_key(&number) {
+                    anyhow::bail!(
+                        "Cannot publish over previously published version {}",
+                        number
+                    )
+                }
+                versions.insert(number.clone(), *version);
+
+                let now = Utc::now();
+                let time = self.time.get_or_insert_with(|| PackumentTime {
+                    created: now,
+                    modified: now,
+                    unpublished: None,
+                    versions: BTreeMap::new(),
+                });
+                time.modified = now;
+                time.unpublished = None;
+                time.versions.insert(number.clone(), now);
+
+                self.set_tag(tag, number);
+            }
+
+            PackageModification::AddMaintainer { name, email } => {
+                if self.is_maintainer(name.as_str()) {
+                    anyhow::bail!("{} is already a maintainer", name)
+                }
+                self.maintainers
+                    .get_or_insert_with(Vec::new)
+                    .push(Maintainer::Object(MaintainerObject {
+                        name: Some(name),
+                        email,
+                        url: None,
+                    }));
+            }
+
+            PackageModification::RemoveMaintainer(name) => {
+                if !self.is_maintainer(name.as_str()) {
+                    anyhow::bail!("{} is not a maintainer", name)
+                }
+                if self.maintainer_names().len() == 1 {
+                    anyhow::bail!("A package must keep at least one maintainer")
+                }
+                if let Some(ref mut maintainers) = self.maintainers {
+                    maintainers.retain(|maintainer| {
+                        maintainer.clone().into_object().name.as_deref() != Some(name.as_str())
+                    });
+                }
+            }
+
+            // As with publishing, the tarballs are the caller's to delete.
+            PackageModification::RemoveVersions(numbers) => {
+                let Some(ref mut versions) = self.versions else {
+                    anyhow::bail!("Cannot remove versions of a package with no versions")
+                };
+                if let Some(unknown) = numbers.iter().find(|n| !versions.contains_key(*n)) {
+                    anyhow::bail!("Cannot remove unknown version {}", unknown)
+                }
+                for number in &numbers {
+                    versions.remove(number);
+                }
+
+                if let Some(ref mut time) = self.time {
+                    for number in &numbers {
+                        time.versions.remove(number);
+                    }
+                    time.modified = Utc::now();
+                }
+                if let Some(ref mut dist_tags) = self.dist_tags {
+                    if dist_tags
+                        .latest
+                        .as_ref()
+                        .is_some_and(|v| numbers.contains(v))
+                    {
+                        dist_tags.latest = None;
+                    }
+                    dist_tags
+                        .tags
+                        .retain(|_, version| !numbers.contains(version));
+                }
+            }
+
+            PackageModification::RemoveTag { tag } => {
+                if tag == "latest" {
+                    anyhow::bail!("The latest tag cannot be removed")
+                }
+
+                if let Some(ref mut dist_tags) = self.dist_tags {
+                    dist_tags.tags.remove(&tag);
                 }
             }
         }
 
-        anyhow::bail!("wip")
+        Ok(())
     }
 }
 
@@ -539,4 +1342,345 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_star_diff_applies() {
+        let mut stored = Packument {
+            id: Some("left-pad".to_string()),
+            ..Default::default()
+        };
+
+        let starred: Packument = serde_json::from_value(serde_json::json!({
+            "_id": "left-pad",
+            "users": { "gary": true }
+        }))
+        .unwrap();
+
+        let modification = PackageModification::from_diff(&stored, starred).unwrap();
+        assert!(matches!(modification, PackageModification::AddStar(ref user) if user == "gary"));
+        stored.apply(modification).unwrap();
+        assert_eq!(stored.stargazers.as_ref().unwrap().get("gary"), Some(&true));
+
+        let unstarred: Packument = serde_json::from_value(serde_json::json!({
+            "_id": "left-pad",
+            "users": {}
+        }))
+        .unwrap();
+
+        let modification = PackageModification::from_diff(&stored, unstarred).unwrap();
+        assert!(
+            matches!(modification, PackageModification::RemoveStar(ref user) if user == "gary")
+        );
+        stored.apply(modification).unwrap();
+        assert!(stored.stargazers.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_maintainer_diff_applies() {
+        let mut stored: Packument = serde_json::from_value(serde_json::json!({
+            "_id": "left-pad",
+            "_rev": "1-abc",
+            "maintainers": [{ "name": "gary", "email": "gary@example.com" }]
+        }))
+        .unwrap();
+
+        // `npm owner add` sends the whole list back, with whatever email it could find.
+        let added: Packument = serde_json::from_value(serde_json::json!({
+            "_id": "left-pad",
+            "_rev": "1-abc",
+            "maintainers": [
+                { "name": "gary", "email": "gary@example.com" },
+                { "name": "tina", "email": "" }
+            ]
+        }))
+        .unwrap();
+        let modification = PackageModification::from_diff(&stored, added).unwrap();
+        assert!(matches!(
+            modification,
+            PackageModification::AddMaintainer { ref name, email: None } if name == "tina"
+        ));
+        stored
+            .apply(PackageModification::AddMaintainer {
+                name: "tina".to_string(),
+                email: Some("tina@example.com".to_string()),
+            })
+            .unwrap();
+        assert_eq!(stored.maintainer_names(), vec!["gary", "tina"]);
+        assert_eq!(
+            serde_json::to_value(&stored.maintainers).unwrap()[1]["email"],
+            "tina@example.com"
+        );
+
+        let removed: Packument = serde_json::from_value(serde_json::json!({
+            "_id": "left-pad",
+            "maintainers": [{ "name": "tina", "email": "tina@example.com" }]
+        }))
+        .unwrap();
+        let modification = PackageModification::from_diff(&stored, removed).unwrap();
+        assert!(matches!(
+            modification,
+            PackageModification::RemoveMaintainer(ref name) if name == "gary"
+        ));
+        stored.apply(modification).unwrap();
+        assert_eq!(stored.maintainer_names(), vec!["tina"]);
+
+        let last = PackageModification::RemoveMaintainer("tina".to_string());
+        assert!(stored.apply(last).is_err());
+    }
+
+    #[test]
+    fn test_remove_versions() {
+        let version = |number: &str| {
+            serde_json::json!({
+                "_id": format!("x@{}", number),
+                "version": number,
+                "dist": { "tarball": "", "shasum": "" }
+            })
+        };
+        let mut packument: Packument = serde_json::from_value(serde_json::json!({
+            "_id": "x",
+            "dist-tags": { "latest": "1.0.0-pr.2", "pr-1": "1.0.0-pr.1", "next": "1.0.0" },
+            "versions": {
+                "1.0.0": version("1.0.0"),
+                "1.0.0-pr.1": version("1.0.0-pr.1"),
+                "1.0.0-pr.2": version("1.0.0-pr.2")
+            },
+            "time": {
+                "created": "2020-01-01T00:00:00Z",
+                "modified": "2020-01-01T00:00:00Z",
+                "1.0.0": "2020-01-01T00:00:00Z",
+                "1.0.0-pr.1": "2020-01-01T00:00:00Z",
+                "1.0.0-pr.2": "2020-01-01T00:00:00Z"
+            }
+        }))
+        .unwrap();
+
+        let unknown = vec!["1.0.0-pr.1".to_string(), "9.9.9".to_string()];
+        assert!(packument
+            .apply(PackageModification::RemoveVersions(unknown))
+            .is_err());
+        assert_eq!(packument.versions.as_ref().unwrap().len(), 3);
+
+        let previews = vec!["1.0.0-pr.1".to_string(), "1.0.0-pr.2".to_string()];
+        packument
+            .apply(PackageModification::RemoveVersions(previews))
+            .unwrap();
+        let versions: Vec<_> = packument.versions.unwrap().into_keys().collect();
+        assert_eq!(versions, vec!["1.0.0"]);
+        let times: Vec<_> = packument.time.unwrap().versions.into_keys().collect();
+        assert_eq!(times, vec!["1.0.0"]);
+        let dist_tags = packument.dist_tags.unwrap();
+        assert_eq!(dist_tags.latest, None);
+        assert_eq!(dist_tags.tags.into_keys().collect::<Vec<_>>(), vec!["next"]);
+    }
+
+    #[test]
+    fn test_diff_versions_and_tags() {
+        let document = |versions: &[&str], tags: serde_json::Value| {
+            let versions: serde_json::Map<_, _> = versions
+                .iter()
+                .map(|number| {
+                    let version = serde_json::json!({
+                        "_id": format!("x@{}", number),
+                        "version": number,
+                        "dist": { "tarball": "", "shasum": "" }
+                    });
+                    (number.to_string(), version)
+                })
+                .collect();
+            serde_json::from_value::<Packument>(serde_json::json!({
+                "_id": "x",
+                "dist-tags": tags,
+                "versions": versions
+            }))
+            .unwrap()
+        };
+        let stored = document(
+            &["1.0.0", "1.1.0"],
+            serde_json::json!({ "latest": "1.1.0", "next": "1.1.0" }),
+        );
+        let diff = |versions: &[&str], tags| {
+            PackageModification::from_diff(&stored, document(versions, tags))
+        };
+
+        let removed = diff(&["1.0.0"], serde_json::json!({ "latest": "1.0.0" })).unwrap();
+        assert!(matches!(
+            removed,
+            PackageModification::RemoveVersions(ref versions) if versions == &["1.1.0"]
+        ));
+        assert!(diff(
+            &["1.0.0", "2.0.0"],
+            serde_json::json!({ "latest": "1.0.0" })
+        )
+        .is_err());
+
+        let tagged = diff(
+            &["1.0.0", "1.1.0"],
+            serde_json::json!({ "latest": "1.0.0", "next": "1.1.0" }),
+        )
+        .unwrap();
+        assert!(matches!(
+            tagged,
+            PackageModification::AddTag { ref tag, ref version } if tag == "latest" && version == "1.0.0"
+        ));
+        let untagged = diff(
+            &["1.0.0", "1.1.0"],
+            serde_json::json!({ "latest": "1.1.0" }),
+        )
+        .unwrap();
+        assert!(matches!(
+            untagged,
+            PackageModification::RemoveTag { ref tag } if tag == "next"
+        ));
+        assert!(diff(
+            &["1.0.0", "1.1.0"],
+            serde_json::json!({ "latest": "1.0.0" })
+        )
+        .is_err());
+        assert!(diff(
+            &["1.0.0", "1.1.0"],
+            serde_json::json!({ "latest": "1.1.0", "next": "1.1.0" })
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_unpublish() {
+        let mut packument: Packument = serde_json::from_value(serde_json::json!({
+            "_id": "x",
+            "_rev": "2-abc",
+            "name": "x",
+            "description": "gone soon",
+            "dist-tags": { "latest": "1.1.0" },
+            "maintainers": [{ "name": "alice" }],
+            "versions": {
+                "1.0.0": { "_id": "x@1.0.0", "version": "1.0.0", "dist": { "tarball": "", "shasum": "" } },
+                "1.1.0": { "_id": "x@1.1.0", "version": "1.1.0", "dist": { "tarball": "", "shasum": "" } }
+            },
+            "time": {
+                "created": "2020-01-01T00:00:00Z",
+                "modified": "2020-01-02T00:00:00Z",
+                "1.0.0": "2020-01-01T00:00:00Z",
+                "1.1.0": "2020-01-02T00:00:00Z"
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(packument.unpublish().unwrap(), vec!["1.0.0", "1.1.0"]);
+        assert!(packument.unpublish().is_err());
+        assert_eq!(packument.rev.as_deref(), Some("2-abc"));
+        assert_eq!(packument.description, None);
+        assert_eq!(packument.maintainers, None);
+        assert_eq!(packument.dist_tags, None);
+
+        let document = serde_json::to_value(&packument).unwrap();
+        assert_eq!(
+            document["time"]["unpublished"]["versions"],
+            serde_json::json!(["1.0.0", "1.1.0"])
+        );
+        assert_eq!(document["time"]["1.0.0"], "2020-01-01T00:00:00Z");
+
+        // The tombstone reads back as it was written, and a publish clears it.
+        let mut packument: Packument = serde_json::from_value(document).unwrap();
+        assert!(packument.time.as_ref().unwrap().unpublished.is_some());
+        assert!(packument.was_unpublished("1.1.0"));
+        assert!(!packument.was_unpublished("2.0.0"));
+        packument
+            .apply(PackageModification::AddVersion {
+                tag: "latest".to_string(),
+                version: serde_json::from_value(serde_json::json!({
+                    "_id": "x@2.0.0",
+                    "version": "2.0.0",
+                    "dist": { "tarball": "", "shasum": "" }
+                }))
+                .unwrap(),
+                tarball: None,
+                provenance: None,
+            })
+            .unwrap();
+        assert!(packument.was_unpublished("1.0.0"));
+        assert!(!packument.was_unpublished("2.0.0"));
+        assert_eq!(packument.time.unwrap().unpublished, None);
+    }
+
+    #[test]
+    fn test_attachment_kind() {
+        let pkg: PackageIdentifier = "@scope/pkg".parse().unwrap();
+        let kind = |name: &str| attachment_kind(&pkg, "1.2.0", name);
+        assert_eq!(kind("@scope/pkg-1.2.0.tgz"), AttachmentKind::Tarball);
+        assert_eq!(kind("pkg-1.2.0.tgz"), AttachmentKind::Tarball);
+        assert_eq!(
+            kind("@scope/pkg-1.2.0.sigstore"),
+            AttachmentKind::Provenance
+        );
+        assert_eq!(kind("@scope/pkg-1.3.0.tgz"), AttachmentKind::OtherVersion);
+        assert_eq!(
+            kind("pkg-1.2.0-beta.1.sigstore"),
+            AttachmentKind::OtherVersion
+        );
+        assert_eq!(kind("other-1.2.0.tgz"), AttachmentKind::Extraneous);
+        assert_eq!(kind("logo.png"), AttachmentKind::Extraneous);
+        assert_eq!(kind("README"), AttachmentKind::Extraneous);
+    }
+
+    #[test]
+    fn test_parse_tarball_name() {
+        fn version<'a>(pkg: &str, tarball: &'a str) -> Option<&'a str> {
+            let pkg: PackageIdentifier = pkg.parse().unwrap();
+            let (named, version) = PackageIdentifier::parse_tarball_name(tarball)?;
+            pkg.is_named_by_tarball(&named).then_some(version)
+        }
+
+        assert_eq!(version("left-pad", "left-pad-1.3.0.tgz"), Some("1.3.0"));
+        assert_eq!(
+            version("left-pad", "left-pad-2.0.0-rc.1+build.5.tgz"),
+            Some("2.0.0-rc.1+build.5")
+        );
+        assert_eq!(version("es-2015", "es-2015-1.0.0.tgz"), Some("1.0.0"));
+        assert_eq!(version("@acme/tool", "tool-1.0.0.tgz"), Some("1.0.0"));
+        assert_eq!(version("@acme/tool", "acme-tool-1.0.0.tgz"), Some("1.0.0"));
+        assert_eq!(version("@acme/tool", "@acme/tool-1.0.0.tgz"), Some("1.0.0"));
+        assert_eq!(version("@acme/tool", "@other/tool-1.0.0.tgz"), None);
+        assert_eq!(version("left-pad", "left-1.3.0.tgz"), None);
+        assert_eq!(version("left-pad", "left-pad-latest.tgz"), None);
+        assert_eq!(version("left-pad", "left-pad-1.3.0.tar"), None);
+    }
+
+    #[test]
+    fn test_advance_rev() {
+        let mut packument = Packument {
+            id: Some("left-pad".to_string()),
+            ..Default::default()
+        };
+
+        packument.advance_rev().unwrap();
+        let first = packument.rev.clone().unwrap();
+        assert!(first.starts_with("1-"));
+        assert_eq!(first.len(), "1-".len() + 32);
+
+        packument.advance_rev().unwrap();
+        assert!(packument.rev.as_deref().unwrap().starts_with("2-"));
+
+        // Upstream revisions carry on from their generation.
+        packument.rev = Some("41-abc".to_string());
+        packument.advance_rev().unwrap();
+        assert!(packument.rev.unwrap().starts_with("42-"));
+    }
+
+    #[test]
+    fn test_serialization_is_stable() {
+        let a: Packument = serde_json::from_str(
+            r#"{"_id": "x", "dist-tags": {"latest": "1.0.0", "next": "2.0.0", "beta": "2.0.0-0"}, "users": {"zed": true, "amy": true}}"#,
+        )
+        .unwrap();
+        let b: Packument = serde_json::from_str(
+            r#"{"users": {"amy": true, "zed": true}, "dist-tags": {"beta": "2.0.0-0", "next": "2.0.0", "latest": "1.0.0"}, "_id": "x"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            serde_json::to_vec(&a).unwrap(),
+            serde_json::to_vec(&b).unwrap()
+        );
+    }
 }
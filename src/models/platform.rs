@@ -0,0 +1,115 @@
+//! Packages split into one package per platform, as esbuild and swc ship their binaries: a
+//! logical package, and a variant for each os and cpu, each published at the logical
+//! package's version and declaring where it runs with `os` and `cpu`.
+//!
+//! The logical package names its variants in its manifest, keyed by platform:
+//!
+//! ```json
+//! "platformVariants": {
+//!   "linux-x64": "@acme/tool-linux-x64",
+//!   "darwin-arm64": "@acme/tool-darwin-arm64"
+//! }
+//! ```
+//!
+//! (usually alongside `optionalDependencies` on the same packages, which is what npm
+//! installs from.)
+
+use std::collections::BTreeMap;
+
+use super::{PackageIdentifier, PackumentVersion};
+
+/// The variants `version` declares, by platform. Versions that declare none have no entries.
+pub fn platform_variants(
+    version: &PackumentVersion,
+) -> anyhow::Result<BTreeMap<String, PackageIdentifier>> {
+    let Some(declared) = version.meta.get("platformVariants") else {
+        return Ok(BTreeMap::new());
+    };
+    let Some(declared) = declared.as_object() else {
+        anyhow::bail!("platformVariants must map each platform to a package name")
+    };
+
+    declared
+        .iter()
+        .map(|(platform, name)| {
+            let Some(name) = name.as_str() else {
+                anyhow::bail!("platformVariants.{} must be a package name", platform)
+            };
+            let name: PackageIdentifier = name.parse().map_err(|_| {
+                anyhow::anyhow!("platformVariants.{} is not a valid name", platform)
+            })?;
+            Ok((platform.clone(), name))
+        })
+        .collect()
+}
+
+// npm's `os` and `cpu` fields: a list of values to allow, or of `!`-prefixed values to deny.
+fn allows(version: &PackumentVersion, field: &str, value: &str) -> bool {
+    let Some(listed) = version.meta.get(field).and_then(|listed| listed.as_array()) else {
+        return true;
+    };
+    let listed: Vec<_> = listed.iter().filter_map(|entry| entry.as_str()).collect();
+    if listed
+        .iter()
+        .any(|entry| entry.strip_prefix('!') == Some(value))
+    {
+        return false;
+    }
+
+    let mut allowed = listed
+        .iter()
+        .filter(|entry| !entry.starts_with('!'))
+        .peekable();
+    allowed.peek().is_none() || allowed.any(|entry| *entry == value)
+}
+
+/// Whether npm would install `version` on `os` and `cpu`; either may be left unchecked.
+pub fn supports_platform(version: &PackumentVersion, os: Option<&str>, cpu: Option<&str>) -> bool {
+    os.is_none_or(|os| allows(version, "os", os))
+        && cpu.is_none_or(|cpu| allows(version, "cpu", cpu))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(meta: serde_json::Value) -> PackumentVersion {
+        let mut version = serde_json::json!({
+            "_id": "tool@1.0.0",
+            "version": "1.0.0",
+            "dist": { "tarball": "", "shasum": "" }
+        });
+        version
+            .as_object_mut()
+            .unwrap()
+            .extend(meta.as_object().unwrap().clone());
+        serde_json::from_value(version).unwrap()
+    }
+
+    #[test]
+    fn test_platform_variants() {
+        let logical = manifest(serde_json::json!({
+            "platformVariants": {
+                "linux-x64": "@acme/tool-linux-x64",
+                "win32-x64": "tool-win32-x64"
+            }
+        }));
+        let variants = platform_variants(&logical).unwrap();
+        assert_eq!(variants["linux-x64"].to_string(), "@acme/tool-linux-x64");
+        assert_eq!(variants["win32-x64"].to_string(), "tool-win32-x64");
+        assert!(platform_variants(&manifest(serde_json::json!({})))
+            .unwrap()
+            .is_empty());
+        assert!(
+            platform_variants(&manifest(serde_json::json!({ "platformVariants": ["x"] }))).is_err()
+        );
+
+        let linux = manifest(serde_json::json!({ "os": ["linux"], "cpu": ["x64", "arm64"] }));
+        assert!(supports_platform(&linux, Some("linux"), Some("arm64")));
+        assert!(!supports_platform(&linux, Some("darwin"), None));
+        assert!(!supports_platform(&linux, None, Some("ia32")));
+        let not_windows = manifest(serde_json::json!({ "os": ["!win32"] }));
+        assert!(supports_platform(&not_windows, Some("linux"), Some("x64")));
+        assert!(!supports_platform(&not_windows, Some("win32"), None));
+    }
+}
@@ -0,0 +1,183 @@
+//! Provenance attestations, as `npm publish --provenance` attaches them: a sigstore bundle
+//! whose DSSE envelope carries an in-toto statement about the published tarball.
+//!
+//! What we check is that the statement is about the tarball being published, under the
+//! name and version it's published as. The signing certificate and transparency log entry
+//! are left to consumers, who check them against sigstore's trust root (as
+//! `npm audit signatures` does); a registry has no better vantage point for that than they do.
+
+use std::collections::BTreeMap;
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use super::PackageIdentifier;
+use crate::hashing::{Algorithm, Digest};
+
+const BUNDLE_MEDIA_TYPE: &str = "application/vnd.dev.sigstore.bundle";
+const IN_TOTO_PAYLOAD_TYPE: &str = "application/vnd.in-toto+json";
+
+/// `dist.attestations`: where a version's attestations are served, and what they claim.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Attestations {
+    pub url: String,
+    pub provenance: AttestationSummary,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct AttestationSummary {
+    #[serde(rename = "predicateType")]
+    pub predicate_type: String,
+}
+
+#[derive(Deserialize)]
+struct Bundle {
+    #[serde(rename = "mediaType")]
+    media_type: String,
+    #[serde(rename = "verificationMaterial")]
+    verification_material: Option<serde_json::Value>,
+    #[serde(rename = "dsseEnvelope")]
+    dsse_envelope: Option<Envelope>,
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    payload: String,
+    #[serde(rename = "payloadType")]
+    payload_type: String,
+    #[serde(default)]
+    signatures: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct Statement {
+    #[serde(default)]
+    subject: Vec<Subject>,
+    #[serde(rename = "predicateType")]
+    predicate_type: String,
+}
+
+#[derive(Deserialize)]
+struct Subject {
+    name: String,
+    #[serde(default)]
+    digest: BTreeMap<String, String>,
+}
+
+/// A provenance bundle that attests to the tarball it was published with.
+#[derive(Debug, Clone)]
+pub struct Provenance {
+    pub predicate_type: String,
+    pub bundle: serde_json::Value,
+}
+
+/// The package URL provenance statements name their subject by.
+pub fn purl(pkg: &PackageIdentifier, version: &str) -> String {
+    match pkg.scope {
+        Some(ref scope) => format!("pkg:npm/%40{}/{}@{}", scope, pkg.name, version),
+        None => format!("pkg:npm/{}@{}", pkg.name, version),
+    }
+}
+
+impl Provenance {
+    pub fn is_bundle(content_type: &str) -> bool {
+        content_type.starts_with(BUNDLE_MEDIA_TYPE)
+    }
+
+    /// Check that `bundle` attests to `tarball` as `pkg@version`.
+    pub fn verify(
+        bundle: &str,
+        pkg: &PackageIdentifier,
+        version: &str,
+        tarball: &[u8],
+    ) -> anyhow::Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(bundle)?;
+        let parsed: Bundle = serde_json::from_value(value.clone())?;
+        if !Self::is_bundle(parsed.media_type.as_str()) {
+            anyhow::bail!("Provenance is not a sigstore bundle")
+        }
+        if parsed.verification_material.is_none() {
+            anyhow::bail!("Provenance bundle has no verification material")
+        }
+        let Some(envelope) = parsed.dsse_envelope else {
+            anyhow::bail!("Provenance bundle has no DSSE envelope")
+        };
+        if envelope.payload_type != IN_TOTO_PAYLOAD_TYPE || envelope.signatures.is_empty() {
+            anyhow::bail!("Provenance envelope is not a signed in-toto statement")
+        }
+
+        let payload = base64::engine::general_purpose::STANDARD
+            .decode(envelope.payload.as_str())
+            .map_err(|_| anyhow::anyhow!("Provenance payload was not valid base64"))?;
+        let statement: Statement = serde_json::from_slice(payload.as_slice())?;
+
+        let purl = purl(pkg, version);
+        let Some(subject) = statement
+            .subject
+            .iter()
+            .find(|subject| subject.name == purl)
+        else {
+            anyhow::bail!("Provenance does not attest to {}", purl)
+        };
+        let sha512 = Digest::compute(Algorithm::Sha512, tarball)?.to_hex();
+        if subject.digest.get("sha512") != Some(&sha512) {
+            anyhow::bail!("Provenance does not match the published tarball")
+        }
+
+        Ok(Self {
+            predicate_type: statement.predicate_type,
+            bundle: value,
+        })
+    }
+
+    pub fn summary(&self) -> AttestationSummary {
+        AttestationSummary {
+            predicate_type: self.predicate_type.clone(),
+        }
+    }
+
+    /// The document served from `dist.attestations.url`.
+    pub fn document(&self) -> serde_json::Value {
+        serde_json::json!({
+            "attestations": [{
+                "predicateType": self.predicate_type,
+                "bundle": self.bundle
+            }]
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provenance_must_match_tarball() {
+        let pkg: PackageIdentifier = "@acme/widget".parse().unwrap();
+        let tarball = b"tarball bytes";
+        let statement = serde_json::json!({
+            "_type": "https://in-toto.io/Statement/v1",
+            "subject": [{
+                "name": "pkg:npm/%40acme/widget@1.2.0",
+                "digest": { "sha512": Digest::compute(Algorithm::Sha512, tarball).unwrap().to_hex() }
+            }],
+            "predicateType": "https://slsa.dev/provenance/v1",
+            "predicate": {}
+        });
+        let bundle = serde_json::json!({
+            "mediaType": "application/vnd.dev.sigstore.bundle+json;version=0.2",
+            "verificationMaterial": { "tlogEntries": [] },
+            "dsseEnvelope": {
+                "payload": base64::engine::general_purpose::STANDARD.encode(statement.to_string()),
+                "payloadType": "application/vnd.in-toto+json",
+                "signatures": [{ "sig": "c2ln", "keyid": "" }]
+            }
+        })
+        .to_string();
+
+        let provenance = Provenance::verify(bundle.as_str(), &pkg, "1.2.0", tarball).unwrap();
+        assert_eq!(provenance.predicate_type, "https://slsa.dev/provenance/v1");
+        assert!(Provenance::verify(bundle.as_str(), &pkg, "1.2.1", tarball).is_err());
+        assert!(Provenance::verify(bundle.as_str(), &pkg, "1.2.0", b"other bytes").is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use std::str::FromStr;
+
+use semver::{Version, VersionReq};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum VersionRangeError {
+    #[error("Invalid version range {0:?}: {1}")]
+    InvalidRange(String, semver::Error),
+}
+
+/// An npm-flavored semver range (`^1.2.3 || >= 2.0.0 < 3`, `1.x`, `1.2.3 - 1.4`).
+///
+/// The `semver` crate implements cargo's dialect, so ranges are translated: comparator sets
+/// are separated by whitespace instead of commas, bare versions mean "exactly" instead of
+/// "caret", and `||` unions several sets.
+#[derive(Clone, Debug)]
+pub struct VersionRange {
+    alternatives: Vec<VersionReq>,
+}
+
+impl VersionRange {
+    pub fn matches(&self, version: &Version) -> bool {
+        self.alternatives.iter().any(|req| req.matches(version))
+    }
+
+    /// The highest of `versions` in this range. Unparseable versions are skipped.
+    pub fn max_satisfying<'a>(
+        &self,
+        versions: impl IntoIterator<Item = &'a str>,
+    ) -> Option<&'a str> {
+        versions
+            .into_iter()
+            .filter_map(|raw| Some((parse_version(raw)?, raw)))
+            .filter(|(version, _)| self.matches(version))
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, raw)| raw)
+    }
+}
+
+/// Parse a version leniently, accepting a leading `v` or `=` as npm does.
+pub fn parse_version(version: &str) -> Option<Version> {
+    let version = version.trim();
+    let version = version.strip_prefix('=').unwrap_or(version);
+    let version = version.strip_prefix('v').unwrap_or(version);
+    Version::parse(version).ok()
+}
+
+fn is_wildcard(part: &str) -> bool {
+    matches!(part, "x" | "X" | "*")
+}
+
+// Drop wildcard components ("1.x.x" -> "1") so that they read as cargo partial versions.
+fn strip_wildcards(version: &str) -> String {
+    let version = version.strip_prefix('v').unwrap_or(version);
+    version
+        .split('.')
+        .take_while(|part| !is_wildcard(part))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn translate_comparator(comparator: &str) -> Option<String> {
+    let split_at = comparator
+        .find(|c: char| !matches!(c, '<' | '>' | '=' | '^' | '~'))
+        .unwrap_or(comparator.len());
+    let (op, version) = comparator.split_at(split_at);
+    let version = strip_wildcards(version);
+
+    if version.is_empty() {
+        // "*", "x", ">=*" and friends match everything.
+        return None;
+    }
+
+    let op = match op {
+        "" | "=" => "=",
+        "~>" => "~",
+        op => op,
+    };
+
+    Some(format!("{}{}", op, version))
+}
+
+fn translate_alternative(alternative: &str) -> String {
+    let tokens: Vec<&str> = alternative.split_whitespace().collect();
+
+    if let [lower, "-", upper] = tokens.as_slice() {
+        let comparators: Vec<_> = [
+            translate_comparator(format!(">={}", lower).as_str()),
+            translate_comparator(format!("<={}", upper).as_str()),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        return comparators.join(", ");
+    }
+
+    // Re-attach operators written with a space before the version (">= 1.2.3").
+    let mut comparators = Vec::new();
+    let mut pending_op = String::new();
+    for token in tokens {
+        if token
+            .chars()
+            .all(|c| matches!(c, '<' | '>' | '=' | '^' | '~'))
+        {
+            pending_op.push_str(token);
+            continue;
+        }
+
+        let comparator = format!("{}{}", pending_op, token);
+        pending_op.clear();
+        comparators.extend(translate_comparator(comparator.as_str()));
+    }
+
+    comparators.join(", ")
+}
+
+impl FromStr for VersionRange {
+    type Err = VersionRangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let alternatives = s
+            .split("||")
+            .map(|alternative| {
+                let translated = translate_alternative(alternative);
+                if translated.is_empty() {
+                    Ok(VersionReq::STAR)
+                } else {
+                    VersionReq::parse(translated.as_str())
+                        .map_err(|e| VersionRangeError::InvalidRange(s.to_string(), e))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { alternatives })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(range: &str, version: &str) -> bool {
+        range
+            .parse::<VersionRange>()
+            .unwrap()
+            .matches(&Version::parse(version).unwrap())
+    }
+
+    #[test]
+    fn test_npm_ranges() {
+        assert!(matches("1.2.3", "1.2.3"));
+        assert!(!matches("1.2.3", "1.2.4"));
+        assert!(matches("^1.2.3", "1.9.0"));
+        assert!(!matches("^1.2.3", "2.0.0"));
+        assert!(matches(">= 1.2.3 < 2", "1.5.0"));
+        assert!(!matches(">= 1.2.3 < 2", "2.0.0"));
+        assert!(matches("1.x", "1.4.2"));
+        assert!(matches("*", "9.9.9"));
+        assert!(matches("", "0.0.1"));
+        assert!(matches("<1.0.0 || >=3.0.0", "3.1.0"));
+        assert!(!matches("<1.0.0 || >=3.0.0", "2.0.0"));
+        assert!(matches("1.2.3 - 1.4", "1.4.9"));
+        assert!(!matches("1.2.3 - 1.4", "1.5.0"));
+    }
+
+    #[test]
+    fn test_max_satisfying() {
+        let versions = ["1.0.0", "1.2.0", "1.10.0", "2.0.0", "2.1.0-beta.1", "x"];
+        let max = |range: &str| {
+            range
+                .parse::<VersionRange>()
+                .unwrap()
+                .max_satisfying(versions)
+        };
+
+        assert_eq!(max("^1"), Some("1.10.0"));
+        assert_eq!(max("*"), Some("2.0.0"));
+        assert_eq!(max(">=2.1.0-beta.0"), Some("2.1.0-beta.1"));
+        assert_eq!(max("^3"), None);
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
+
+use crate::models::PackageIdentifier;
+use crate::policies::AccessControl;
+
+use super::{Access, PackageEvent, Permission, Transfer};
+
+const HISTORY_LIMIT: usize = 200;
+
+#[derive(Clone, Debug, Default)]
+struct PackageAccess {
+    access: Access,
+    grants: BTreeMap<String, Permission>,
+}
+
+#[derive(Clone)]
+pub struct InMemoryAccessControl {
+    packages: Arc<RwLock<HashMap<String, PackageAccess>>>,
+    transfers: Arc<RwLock<HashMap<String, Transfer>>>,
+    history: Arc<RwLock<HashMap<String, VecDeque<PackageEvent>>>>,
+}
+
+impl std::fmt::Debug for InMemoryAccessControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut formatter = f.debug_struct("InMemoryAccessControl");
+        if let Ok(packages) = self.packages.try_read() {
+            formatter.field("packages", &packages);
+        }
+        if let Ok(transfers) = self.transfers.try_read() {
+            formatter.field("transfers", &transfers.len());
+        }
+        formatter.finish()
+    }
+}
+
+impl InMemoryAccessControl {
+    pub fn new() -> Self {
+        Self {
+            packages: Arc::new(RwLock::new(HashMap::new())),
+            transfers: Arc::new(RwLock::new(HashMap::new())),
+            history: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+}
+
+impl Default for InMemoryAccessControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait::async_trait]
+impl AccessControl for InMemoryAccessControl {
+    async fn get_access(&self, package: &PackageIdentifier) -> anyhow::Result<Access> {
+        Ok(self
+            .packages
+            .read()
+            .unwrap()
+            .get(&package.to_string())
+            .map(|package| package.access)
+            .unwrap_or_default())
+    }
+
+    async fn set_access(&self, package: &PackageIdentifier, access: Access) -> anyhow::Result<()> {
+        self.packages
+            .write()
+            .unwrap()
+            .entry(package.to_string())
+            .or_default()
+            .access = access;
+        Ok(())
+    }
+
+    async fn grant(
+        &self,
+        package: &PackageIdentifier,
+        grantee: &str,
+        permission: Permission,
+    ) -> anyhow::Result<()> {
+        self.packages
+            .write()
+            .unwrap()
+            .entry(package.to_string())
+            .or_default()
+            .grants
+            .insert(grantee.to_string(), permission);
+        Ok(())
+    }
+
+    async fn revoke(&self, package: &PackageIdentifier, grantee: &str) -> anyhow::Result<()> {
+        if let Some(package) = self.packages.write().unwrap().get_mut(&package.to_string()) {
+            package.grants.remove(grantee);
+        }
+        Ok(())
+    }
+
+    async fn list_packages(&self, grantee: &str) -> anyhow::Result<BTreeMap<String, Permission>> {
+        Ok(self
+            .packages
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|(name, package)| {
+                package
+                    .grants
+                    .get(grantee)
+                    .map(|permission| (name.clone(), *permission))
+            })
+            .collect())
+    }
+
+    async fn list_collaborators(
+        &self,
+        package: &PackageIdentifier,
+    ) -> anyhow::Result<BTreeMap<String, Permission>> {
+        Ok(self
+            .packages
+            .read()
+            .unwrap()
+            .get(&package.to_string())
+            .map(|package| package.grants.clone())
+            .unwrap_or_default())
+    }
+
+    async fn offer_transfer(&self, transfer: Transfer) -> anyhow::Result<()> {
+        self.transfers
+            .write()
+            .unwrap()
+            .insert(transfer.package.clone(), transfer);
+        Ok(())
+    }
+
+    async fn pending_transfer(
+        &self,
+        package: &PackageIdentifier,
+    ) -> anyhow::Result<Option<Transfer>> {
+        Ok(self
+            .transfers
+            .read()
+            .unwrap()
+            .get(&package.to_string())
+            .cloned())
+    }
+
+    async fn transfers_to(&self, recipients: &[String]) -> anyhow::Result<Vec<Transfer>> {
+        let mut transfers: Vec<_> = self
+            .transfers
+            .read()
+            .unwrap()
+            .values()
+            .filter(|transfer| recipients.contains(&transfer.to))
+            .cloned()
+            .collect();
+        transfers.sort_by_key(|transfer| transfer.created);
+        Ok(transfers)
+    }
+
+    async fn take_transfer(&self, package: &PackageIdentifier) -> anyhow::Result<Option<Transfer>> {
+        Ok(self.transfers.write().unwrap().remove(&package.to_string()))
+    }
+
+    async fn record_event(&self, event: PackageEvent) -> anyhow::Result<()> {
+        let mut history = self.history.write().unwrap();
+        let events = history.entry(event.package.clone()).or_default();
+        events.push_front(event);
+        events.truncate(HISTORY_LIMIT);
+        Ok(())
+    }
+
+    async fn package_history(
+        &self,
+        package: &PackageIdentifier,
+    ) -> anyhow::Result<Vec<PackageEvent>> {
+        Ok(self
+            .history
+            .read()
+            .unwrap()
+            .get(&package.to_string())
+            .map(|events| events.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::models::PackageIdentifier;
+
+pub(crate) mod in_memory;
+
+/// Whether anyone may install a package, or only its collaborators.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    #[default]
+    Public,
+    Restricted,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Permission {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// An offer to hand a package to another user or `scope:team`, pending the recipient's
+/// acceptance.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transfer {
+    pub package: String,
+    pub from: String,
+    pub to: String,
+    pub created: DateTime<Utc>,
+}
+
+/// Something done to a package, as listed in its history. `version` and `tag` are set for the
+/// actions they apply to.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageEvent {
+    pub package: String,
+    pub action: String,
+    pub actor: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    pub ip: Option<String>,
+    /// Where `ip` is, when a geolocator is configured and knows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    pub time: DateTime<Utc>,
+}
+
+/// Package visibility and collaborator grants, as driven by `npm access`.
+///
+/// Grantees are either usernames or `scope:team` pairs; implementations don't need to
+/// distinguish between them.
+#[async_trait::async_trait]
+pub trait AccessControl: Send + Sync {
+    async fn get_access(&self, package: &PackageIdentifier) -> anyhow::Result<Access>;
+    async fn set_access(&self, package: &PackageIdentifier, access: Access) -> anyhow::Result<()>;
+
+    async fn grant(
+        &self,
+        package: &PackageIdentifier,
+        grantee: &str,
+        permission: Permission,
+    ) -> anyhow::Result<()>;
+    async fn revoke(&self, package: &PackageIdentifier, grantee: &str) -> anyhow::Result<()>;
+
+    /// Packages `grantee` has been granted access to, by package name.
+    async fn list_packages(&self, grantee: &str) -> anyhow::Result<BTreeMap<String, Permission>>;
+
+    /// Grantees with access to `package`.
+    async fn list_collaborators(
+        &self,
+        package: &PackageIdentifier,
+    ) -> anyhow::Result<BTreeMap<String, Permission>>;
+
+    /// Record a pending transfer, replacing any earlier offer for the same package.
+    async fn offer_transfer(&self, _transfer: Transfer) -> anyhow::Result<()> {
+        anyhow::bail!("this access control does not support transfers")
+    }
+
+    async fn pending_transfer(
+        &self,
+        _package: &PackageIdentifier,
+    ) -> anyhow::Result<Option<Transfer>> {
+        Ok(None)
+    }
+
+    /// Pending transfers addressed to any of `recipients`.
+    async fn transfers_to(&self, _recipients: &[String]) -> anyhow::Result<Vec<Transfer>> {
+        Ok(Vec::new())
+    }
+
+    /// Remove and return the pending transfer for `package`, whether it's being accepted,
+    /// declined or withdrawn.
+    async fn take_transfer(
+        &self,
+        _package: &PackageIdentifier,
+    ) -> anyhow::Result<Option<Transfer>> {
+        Ok(None)
+    }
+
+    async fn record_event(&self, _event: PackageEvent) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// `package`'s recent history, newest first.
+    async fn package_history(
+        &self,
+        _package: &PackageIdentifier,
+    ) -> anyhow::Result<Vec<PackageEvent>> {
+        Ok(Vec::new())
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use serde_json::json;
+
+use crate::models::{
+    parse_version, Advisory, AuditDependency, AuditRequest, BulkAdvisoryRequest,
+    BulkAdvisoryResponse, Severity, VersionRange,
+};
+use crate::policies::Advisories;
+
+/// Evaluates audits locally against a set of advisories held in memory, for registries
+/// that curate their own advisory data (or can't reach an upstream.)
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryAdvisories {
+    advisories: Arc<RwLock<Vec<Advisory>>>,
+}
+
+impl InMemoryAdvisories {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_advisories(advisories: Vec<Advisory>) -> Self {
+        Self {
+            advisories: Arc::new(RwLock::new(advisories)),
+        }
+    }
+
+    pub async fn add_advisory(&self, advisory: Advisory) {
+        self.advisories.write().unwrap().push(advisory);
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    dependencies: usize,
+    dev_dependencies: usize,
+    optional_dependencies: usize,
+}
+
+// Walks the lockfile tree, collecting "advisory id -> version -> paths".
+fn walk<'a>(
+    dependencies: &'a BTreeMap<String, AuditDependency>,
+    parent: &str,
+    advisories: &[(&'a Advisory, VersionRange)],
+    findings: &mut BTreeMap<u64, BTreeMap<String, Vec<String>>>,
+    counts: &mut Counts,
+) {
+    for (name, dependency) in dependencies {
+        let path = if parent.is_empty() {
+            name.clone()
+        } else {
+            format!("{}>{}", parent, name)
+        };
+
+        if dependency.dev {
+            counts.dev_dependencies += 1;
+        } else if dependency.optional {
+            counts.optional_dependencies += 1;
+        } else {
+            counts.dependencies += 1;
+        }
+
+        if let Some(version) = parse_version(dependency.version.as_str()) {
+            for (advisory, range) in advisories {
+                if advisory.module_name == *name && range.matches(&version) {
+                    findings
+                        .entry(advisory.id)
+                        .or_default()
+                        .entry(dependency.version.clone())
+                        .or_default()
+                        .push(path.clone());
+                }
+            }
+        }
+
+        walk(
+            &dependency.dependencies,
+            path.as_str(),
+            advisories,
+            findings,
+            counts,
+        );
+    }
+}
+
+#[async_trait::async_trait]
+impl Advisories for InMemoryAdvisories {
+    async fn audit(&self, request: AuditRequest) -> anyhow::Result<serde_json::Value> {
+        let advisories = self.advisories.read().unwrap();
+        let ranges: Vec<_> = advisories
+            .iter()
+            .filter_map(|advisory| {
+                let range = advisory.vulnerable_versions.parse().ok()?;
+                Some((advisory, range))
+            })
+            .collect();
+
+        let mut findings = BTreeMap::new();
+        let mut counts = Counts::default();
+        walk(
+            &request.dependencies,
+            "",
+            &ranges,
+            &mut findings,
+            &mut counts,
+        );
+
+        let mut vulnerabilities: BTreeMap<&str, usize> = Severity::ALL
+            .iter()
+            .map(|severity| (severity.name(), 0))
+            .collect();
+
+        let mut report = serde_json::Map::new();
+        for (advisory, _) in ranges.iter() {
+            let Some(versions) = findings.get(&advisory.id) else {
+                continue;
+            };
+
+            *vulnerabilities.entry(advisory.severity.name()).or_default() +=
+                versions.values().map(Vec::len).sum::<usize>();
+
+            let mut entry = serde_json::to_value(advisory)?;
+            entry["findings"] = versions
+                .iter()
+                .map(|(version, paths)| json!({ "version": version, "paths": paths }))
+                .collect();
+            report.insert(advisory.id.to_string(), entry);
+        }
+
+        Ok(json!({
+            "actions": [],
+            "advisories": report,
+            "muted": [],
+            "metadata": {
+                "vulnerabilities": vulnerabilities,
+                "dependencies": counts.dependencies,
+                "devDependencies": counts.dev_dependencies,
+                "optionalDependencies": counts.optional_dependencies,
+                "totalDependencies": counts.dependencies
+                    + counts.dev_dependencies
+                    + counts.optional_dependencies
+            }
+        }))
+    }
+
+    async fn bulk_advisories(
+        &self,
+        request: BulkAdvisoryRequest,
+    ) -> anyhow::Result<BulkAdvisoryResponse> {
+        let advisories = self.advisories.read().unwrap();
+        let mut response = BulkAdvisoryResponse::new();
+
+        for (name, versions) in request {
+            let versions: Vec<_> = versions
+                .iter()
+                .filter_map(|version| parse_version(version))
+                .collect();
+
+            let matching: Vec<_> = advisories
+                .iter()
+                .filter(|advisory| advisory.module_name == name)
+                .filter(|advisory| {
+                    let Ok(range) = advisory.vulnerable_versions.parse::<VersionRange>() else {
+                        return false;
+                    };
+                    versions.iter().any(|version| range.matches(version))
+                })
+                .map(Into::into)
+                .collect();
+
+            if !matching.is_empty() {
+                response.insert(name, matching);
+            }
+        }
+
+        Ok(response)
+    }
+}
@@ -0,0 +1,21 @@
+use crate::models::{AuditRequest, BulkAdvisoryRequest, BulkAdvisoryResponse};
+
+pub(crate) mod in_memory;
+pub(crate) mod remote;
+
+#[async_trait::async_trait]
+pub trait Advisories: Send + Sync {
+    /// Evaluate a full `npm audit` request, returning npm's audit report document.
+    async fn audit(&self, request: AuditRequest) -> anyhow::Result<serde_json::Value>;
+
+    /// The cheaper "quick" audit npm runs after installs. Same shape as a full audit.
+    async fn quick_audit(&self, request: AuditRequest) -> anyhow::Result<serde_json::Value> {
+        self.audit(request).await
+    }
+
+    /// Look up advisories for many packages at once, as npm 7+ does.
+    async fn bulk_advisories(
+        &self,
+        request: BulkAdvisoryRequest,
+    ) -> anyhow::Result<BulkAdvisoryResponse>;
+}
@@ -0,0 +1,151 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hashlink::LruCache;
+use tokio::sync::Mutex;
+
+use crate::models::{AuditRequest, BulkAdvisory, BulkAdvisoryRequest, BulkAdvisoryResponse};
+use crate::policies::Advisories;
+
+const DEFAULT_BULK_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_BULK_CACHE_CAPACITY: usize = 10_000;
+
+// Bounded, dropping the least recently asked about once full; package and version sets
+// come from clients, so there's no limit to how many there are.
+type BulkCache = LruCache<String, (Instant, Vec<BulkAdvisory>)>;
+
+/// Forwards audits to an upstream registry's security endpoints.
+///
+/// Bulk advisory lookups are cached per package and version set, since every `npm install`
+/// and `npm audit` in a CI fleet tends to ask about the same dependency trees.
+#[derive(Clone)]
+pub struct RemoteAdvisories {
+    registry: String,
+    client: reqwest::Client,
+    bulk_cache_ttl: Duration,
+    bulk_cache: Arc<Mutex<BulkCache>>,
+}
+
+impl std::fmt::Debug for RemoteAdvisories {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut formatter = f.debug_struct("RemoteAdvisories");
+        formatter.field("registry", &self.registry);
+        if let Ok(cache) = self.bulk_cache.try_lock() {
+            formatter.field("bulk_cache", &cache.len());
+        }
+        formatter.finish()
+    }
+}
+
+fn bulk_cache_key(name: &str, versions: &[String]) -> String {
+    let mut versions = versions.to_vec();
+    versions.sort();
+    versions.dedup();
+    format!("{}@{}", name, versions.join(","))
+}
+
+impl RemoteAdvisories {
+    pub fn new(registry: impl Into<String>) -> Self {
+        Self {
+            registry: registry.into().trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+            bulk_cache_ttl: DEFAULT_BULK_CACHE_TTL,
+            bulk_cache: Arc::new(Mutex::new(LruCache::new(DEFAULT_BULK_CACHE_CAPACITY))),
+        }
+    }
+
+    pub fn with_bulk_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.bulk_cache_ttl = ttl;
+        self
+    }
+
+    /// How many package and version sets to remember answers for; 10,000 by default.
+    pub fn with_bulk_cache_capacity(self, capacity: usize) -> Self {
+        Self {
+            bulk_cache: Arc::new(Mutex::new(LruCache::new(capacity))),
+            ..self
+        }
+    }
+
+    async fn post<Req: serde::Serialize + Sync, Res: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+        request: &Req,
+    ) -> anyhow::Result<Res> {
+        Ok(self
+            .client
+            .post(format!("{}/-/npm/v1/security/{}", self.registry, path))
+            .json(request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+}
+
+impl Default for RemoteAdvisories {
+    fn default() -> Self {
+        Self::new("https://registry.npmjs.org")
+    }
+}
+
+#[async_trait::async_trait]
+impl Advisories for RemoteAdvisories {
+    async fn audit(&self, request: AuditRequest) -> anyhow::Result<serde_json::Value> {
+        self.post("audits", &request).await
+    }
+
+    async fn quick_audit(&self, request: AuditRequest) -> anyhow::Result<serde_json::Value> {
+        self.post("audits/quick", &request).await
+    }
+
+    async fn bulk_advisories(
+        &self,
+        request: BulkAdvisoryRequest,
+    ) -> anyhow::Result<BulkAdvisoryResponse> {
+        let mut response = BulkAdvisoryResponse::new();
+        let mut misses = BulkAdvisoryRequest::new();
+
+        {
+            let mut cache = self.bulk_cache.lock().await;
+            for (name, versions) in request {
+                let key = bulk_cache_key(name.as_str(), versions.as_slice());
+                match cache.get(&key) {
+                    Some((fetched_at, advisories))
+                        if fetched_at.elapsed() < self.bulk_cache_ttl =>
+                    {
+                        if !advisories.is_empty() {
+                            response.insert(name, advisories.clone());
+                        }
+                    }
+                    _ => {
+                        misses.insert(name, versions);
+                    }
+                }
+            }
+        }
+
+        if misses.is_empty() {
+            return Ok(response);
+        }
+
+        let mut fetched: BulkAdvisoryResponse = self.post("advisories/bulk", &misses).await?;
+
+        let now = Instant::now();
+        let mut cache = self.bulk_cache.lock().await;
+        for (name, versions) in misses {
+            // Cache negative results too; most packages have no advisories.
+            let advisories = fetched.remove(&name).unwrap_or_default();
+            cache.insert(
+                bulk_cache_key(name.as_str(), versions.as_slice()),
+                (now, advisories.clone()),
+            );
+            if !advisories.is_empty() {
+                response.insert(name, advisories);
+            }
+        }
+
+        Ok(response)
+    }
+}
@@ -1,8 +1,9 @@
-use std::{fmt::Display, hash::Hash, str::FromStr};
+use std::{fmt::Display, hash::Hash, str::FromStr, time::Duration};
 
 use axum::{body::Body, http::Request, response::IntoResponse};
 use chrono::{DateTime, Utc};
 use serde::Serialize;
+use thiserror::Error;
 
 use crate::models::User;
 use crate::policies::Configurator;
@@ -17,6 +18,48 @@ pub(crate) struct LoginSession {
     user: Option<User>,
     hostname: Option<String>,
     csrftoken: Option<String>,
+    // Set when the browser side of the login fails, so the CLI stops waiting on it.
+    failed: bool,
+}
+
+impl LoginSession {
+    pub(crate) fn is_expired(&self, timeout: Duration) -> bool {
+        (Utc::now() - self.initialized_at)
+            .to_std()
+            .is_ok_and(|age| age > timeout)
+    }
+}
+
+/// Why a login session can't be polled, for handlers to tell apart. Anything else an
+/// authenticator returns is treated as an internal error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Error)]
+pub enum LoginSessionError {
+    #[error("unrecognized login session")]
+    Unrecognized,
+    /// Started longer ago than [`Configurator::login_timeout`].
+    #[error("login session expired")]
+    Expired,
+    /// The user was turned away signing in, or signing in couldn't be completed.
+    #[error("login failed")]
+    Failed,
+}
+
+impl LoginSessionError {
+    /// The login session failure behind `error`, if there is one.
+    pub fn of(error: &anyhow::Error) -> Option<&Self> {
+        error.downcast_ref()
+    }
+}
+
+/// What an identity provider says about someone holding registry tokens.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdentityStatus {
+    Active,
+    /// Deleted or suspended, as the provider itself confirms; never merely a credential that
+    /// stopped working.
+    Deactivated,
+    /// The provider couldn't be asked, or can't tell.
+    Unknown,
 }
 
 #[async_trait::async_trait]
@@ -27,8 +70,10 @@ pub trait Authenticator: Send + Sync {
 
     async fn start_login_session(&self, req: Request<Body>) -> anyhow::Result<Self::SessionId>;
 
-    async fn poll_login_session(
+    /// The user a login session ended with, or `None` while it's still underway.
+    async fn poll_login_session<C: Configurator + Send + Sync>(
         &self,
+        config: &C,
         session: Self::SessionId,
     ) -> anyhow::Result<Option<Self::User>>;
 
@@ -40,7 +85,18 @@ pub trait Authenticator: Send + Sync {
         session: Option<Self::SessionId>,
     ) -> anyhow::Result<Self::Response>;
 
+    /// A short name for the identity provider, shown in users' login history.
+    fn provider(&self) -> &str {
+        "unknown"
+    }
+
     async fn get_user(&self, _username: &str) -> anyhow::Result<Option<User>> {
         Ok(None)
     }
+
+    /// Ask the identity provider whether `username` may still sign in. Providers that can't
+    /// tell report `Unknown`, which never costs anyone their tokens.
+    async fn identity_status(&self, _username: &str) -> anyhow::Result<IdentityStatus> {
+        Ok(IdentityStatus::Unknown)
+    }
 }
@@ -1,7 +1,9 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
+use super::{IdentityStatus, LoginSessionError};
 use crate::models::User;
+use crate::policies::configurator::LoginOutcome;
 use crate::policies::{Authenticator, Configurator, UserStorage};
 use axum::body::Body;
 use axum::http::{HeaderMap, Request, StatusCode};
@@ -22,6 +24,8 @@ use uuid::Uuid;
 
 use super::LoginSession;
 
+const GITHUB_USER_AGENT: &str = "regi/v1.0.0 (https://github.com/chrisdickinson/registry)";
+
 #[derive(Clone)]
 pub struct OAuthAuthenticator {
     login_sessions: Arc<RwLock<HashMap<Uuid, LoginSession>>>,
@@ -38,12 +42,41 @@ struct GitHubUser {
     name: Option<String>,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+struct GitHubOrg {
+    login: String,
+}
+
+// All of a public profile we need; its email may be hidden.
+#[derive(Deserialize)]
+struct GitHubAccount {
+    login: String,
+}
+
+// What the provider redirects back with: a code to exchange, or an error when the user
+// declined (`access_denied`) or the request was refused.
+#[derive(Deserialize)]
+struct Callback {
+    code: Option<AuthorizationCode>,
+    state: Option<CsrfToken>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+// The provider's own explanation of why it sent the user back without a code.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct Denied(String);
+
 impl From<GitHubUser> for User {
     fn from(userdata: GitHubUser) -> Self {
         Self {
             name: userdata.login,
             email: userdata.email,
             full_name: userdata.name,
+            homepage: None,
+            freenode: None,
+            twitter: None,
         }
     }
 }
@@ -108,6 +141,86 @@ impl OAuthAuthenticator {
         )
         .set_redirect_uri(RedirectUrl::from_url(redirect_url))
     }
+
+    // The provider redirecting back with a code (or with why there isn't one): exchange it
+    // for the user's identity and hand them to the login session waiting on it.
+    async fn finish_login<C: Configurator + Send + Sync, U: UserStorage + Send + Sync>(
+        &self,
+        config: &C,
+        user_storage: &U,
+        query: &str,
+        bearer: Uuid,
+    ) -> anyhow::Result<User> {
+        let callback: Callback = serde_urlencoded::from_str(query)?;
+        if let Some(error) = callback.error {
+            return Err(Denied(callback.error_description.unwrap_or(error)).into());
+        }
+        let (Some(code), Some(state)) = (callback.code, callback.state) else {
+            anyhow::bail!("expected an authorization code");
+        };
+
+        let mut sessions = self.login_sessions.write().await;
+        let Some(session) = sessions.get_mut(&bearer) else {
+            return Err(LoginSessionError::Unrecognized.into());
+        };
+
+        if session.is_expired(config.login_timeout()) {
+            return Err(LoginSessionError::Expired.into());
+        }
+
+        if Some(state.secret()) != session.csrftoken.as_ref() {
+            anyhow::bail!("csrf token mismatch");
+        }
+
+        let fqdn = Url::parse(config.fqdn())?;
+        let (client_id, client_secret) = config.oauth_config().await?;
+        let client = self.get_oauth_client(&fqdn, client_id.as_str(), client_secret.as_str());
+        let token = client
+            .exchange_code(code)
+            .request_async(async_http_client)
+            .await?;
+
+        let client = reqwest::Client::new();
+        let auth_header = format!("Bearer {}", token.access_token().secret());
+
+        let userdata = client
+            .get("https://api.github.com/user")
+            .header("Authorization", auth_header.as_str())
+            .header("Content-Type", "application/vnd.github+json")
+            .header("User-Agent", GITHUB_USER_AGENT)
+            .send()
+            .await?
+            .json::<GitHubUser>()
+            .await?;
+
+        // Requires the "read:org" scope. Failing to list orgs shouldn't fail the
+        // login; the user just can't claim any org scopes this time around.
+        let orgs = async {
+            client
+                .get("https://api.github.com/user/orgs")
+                .header("Authorization", auth_header.as_str())
+                .header("Content-Type", "application/vnd.github+json")
+                .header("User-Agent", GITHUB_USER_AGENT)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<Vec<GitHubOrg>>()
+                .await
+        }
+        .await
+        .unwrap_or_default();
+
+        let user = user_storage.register_user(userdata).await?;
+        user_storage
+            .set_verified_scopes(
+                user.name.as_str(),
+                orgs.into_iter().map(|org| org.login).collect(),
+            )
+            .await?;
+
+        session.user = Some(user.clone());
+        Ok(user)
+    }
 }
 
 #[async_trait::async_trait]
@@ -136,6 +249,7 @@ impl Authenticator for OAuthAuthenticator {
             LoginSession {
                 initialized_at: Utc::now(),
                 csrftoken: None,
+                failed: false,
                 user: None,
                 hostname,
             },
@@ -144,17 +258,41 @@ impl Authenticator for OAuthAuthenticator {
         Ok(id)
     }
 
-    async fn poll_login_session(&self, bearer: Self::SessionId) -> anyhow::Result<Option<User>> {
-        let has_user = {
+    // Named for the host users are sent to sign in at, e.g. "github.com".
+    fn provider(&self) -> &str {
+        self.auth_url.url().host_str().unwrap_or("oauth")
+    }
+
+    async fn poll_login_session<C: Configurator + Send + Sync>(
+        &self,
+        config: &C,
+        bearer: Self::SessionId,
+    ) -> anyhow::Result<Option<User>> {
+        let (has_user, expired, failed) = {
             let sessions = self.login_sessions.read().await;
 
             let Some(session) = sessions.get(&bearer) else {
-                return Err(anyhow::anyhow!("unrecognized login session"))
+                return Err(LoginSessionError::Unrecognized.into());
             };
 
-            session.user.is_some()
+            (
+                session.user.is_some(),
+                session.is_expired(config.login_timeout()),
+                session.failed,
+            )
         };
 
+        if failed {
+            self.login_sessions.write().await.remove(&bearer);
+            return Err(LoginSessionError::Failed.into());
+        }
+
+        // A login finished in time may still be collected late.
+        if expired && !has_user {
+            self.login_sessions.write().await.remove(&bearer);
+            return Err(LoginSessionError::Expired.into());
+        }
+
         if !has_user {
             return Ok(None);
         }
@@ -163,6 +301,35 @@ impl Authenticator for OAuthAuthenticator {
         Ok(session.and_then(|sess| sess.user))
     }
 
+    // Asked of the public profile, which GitHub stops serving once an account is deleted or
+    // suspended, or renamed, which makes it a different registry user. A login's access token
+    // would prove nothing: it expires, or is revoked, while the account stays in good standing.
+    // Rate limits, outages and anything else are Unknown.
+    async fn identity_status(&self, username: &str) -> anyhow::Result<IdentityStatus> {
+        let response = reqwest::Client::new()
+            .get(format!(
+                "https://api.github.com/users/{}",
+                urlencoding::encode(username)
+            ))
+            .header("Accept", "application/vnd.github+json")
+            .header("User-Agent", GITHUB_USER_AGENT)
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(IdentityStatus::Deactivated),
+            status if status.is_success() => {
+                let account: GitHubAccount = response.json().await?;
+                if account.login.eq_ignore_ascii_case(username) {
+                    Ok(IdentityStatus::Active)
+                } else {
+                    Ok(IdentityStatus::Unknown)
+                }
+            }
+            _ => Ok(IdentityStatus::Unknown),
+        }
+    }
+
     // TODO: oh my god this is such slop. It really needs to be revisited when:
     // - we add more oauth providers (google, auth0, okta; oidc in general)
     // - we start to tighten our error handling
@@ -179,6 +346,10 @@ impl Authenticator for OAuthAuthenticator {
 
         if let Some(bearer) = bearer {
             if let Some(session) = self.login_sessions.write().await.get_mut(&bearer) {
+                if session.is_expired(config.login_timeout()) {
+                    return Err(LoginSessionError::Expired.into());
+                }
+
                 let (client_id, client_secret) = config.oauth_config().await?;
                 let (auth_url, csrftoken) =
                     self.get_oauth_authorize_url(&fqdn, client_id.as_str(), client_secret.as_str());
@@ -202,57 +373,13 @@ impl Authenticator for OAuthAuthenticator {
                 Err(anyhow::anyhow!("unrecognized login session"))
             }
         } else {
-            #[derive(Deserialize)]
-            pub struct ReceivedCode {
-                pub code: AuthorizationCode,
-                pub state: CsrfToken,
-            }
-
-            if let Ok(received) =
-                serde_urlencoded::from_str::<ReceivedCode>(req.uri().query().unwrap_or(""))
-            {
-                let Some(cookie) = jar.get("sid") else {
-                    anyhow::bail!("expected session id cookie");
-                };
-                let Some(bearer) = cookie.value().parse().ok() else {
-                    anyhow::bail!("stored invalid login session id");
-                };
-                let mut sessions = self.login_sessions.write().await;
-                let Some(session) = sessions.get_mut(&bearer) else {
-                    anyhow::bail!("unrecognized login session");
-                };
-
-                if Some(received.state.secret()) != session.csrftoken.as_ref() {
-                    anyhow::bail!("csrf token mismatch");
-                }
-
-                let (client_id, client_secret) = config.oauth_config().await?;
-                let client =
-                    self.get_oauth_client(&fqdn, client_id.as_str(), client_secret.as_str());
-                let token = client
-                    .exchange_code(received.code)
-                    .request_async(async_http_client)
-                    .await?;
-
-                let client = reqwest::Client::new();
-                let auth_header = format!("Bearer {}", token.access_token().secret());
-
-                let userdata = client
-                    .get("https://api.github.com/user")
-                    .header("Authorization", auth_header)
-                    .header("Content-Type", "application/vnd.github+json")
-                    .header(
-                        "User-Agent",
-                        "regi/v1.0.0 (https://github.com/chrisdickinson/registry)",
-                    )
-                    .send()
-                    .await?
-                    .json::<GitHubUser>()
-                    .await?;
-
-                let user = user_storage.register_user(userdata).await?;
-
-                session.user = Some(user);
+            let bearer = jar
+                .get("sid")
+                .and_then(|cookie| cookie.value().parse().ok());
+            let query = req.uri().query().unwrap_or("");
+            let outcome = match bearer {
+                Some(bearer) => self.finish_login(config, user_storage, query, bearer).await,
+                None => Err(anyhow::anyhow!("expected session id cookie")),
             };
 
             let mut headers = HeaderMap::new();
@@ -261,23 +388,37 @@ impl Authenticator for OAuthAuthenticator {
                 "text/html; charset=utf-8".try_into().unwrap(),
             );
 
-            Ok((
-                StatusCode::OK,
-                jar,
-                headers,
-                r#"
-                <!doctype html>
-                <html>
-                <head></head>
-                <body>
-                    <h1>
-                        ♕
-                    </h1>
-                </body>
-                </html>
-            "#
-                .to_string(),
-            ))
+            let (status, page) = match outcome {
+                Ok(user) => (
+                    StatusCode::OK,
+                    config.login_page(LoginOutcome::SignedIn {
+                        username: user.name.as_str(),
+                    }),
+                ),
+                Err(e) => {
+                    tracing::warn!(error = ?e, "could not complete login session");
+                    if let Some(bearer) = bearer {
+                        if let Some(session) = self.login_sessions.write().await.get_mut(&bearer) {
+                            // Reloading the page after a successful login shouldn't undo it.
+                            session.failed = session.user.is_none();
+                        }
+                    }
+
+                    let reason = match (LoginSessionError::of(&e), e.downcast_ref::<Denied>()) {
+                        (Some(e), _) => e.to_string(),
+                        (None, Some(Denied(description))) => description.clone(),
+                        (None, None) => "the sign-in could not be completed".to_string(),
+                    };
+                    (
+                        StatusCode::UNAUTHORIZED,
+                        config.login_page(LoginOutcome::Failed {
+                            reason: reason.as_str(),
+                        }),
+                    )
+                }
+            };
+
+            Ok((status, jar, headers, page))
         }
     }
 }
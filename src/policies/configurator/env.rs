@@ -1,10 +1,150 @@
+use axum::http::Method;
 use axum_extra::extract::cookie::Key;
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 
-use super::Configurator;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::{
+    default_user_agent, AdminKey, Configurator, LoginOutcome, LoginPage, Preset,
+    DEFAULT_LOGIN_TIMEOUT, DEFAULT_PREVIEW_RETENTION, DEFAULT_PREVIEW_TOKEN_TTL,
+    DEFAULT_REPLICATION_URL, DEFAULT_UNPUBLISH_WINDOW, DEFAULT_UPSTREAM_RETRIES,
+};
+use crate::hashing::{self, Algorithm};
+use crate::policies::package_storage::rewrite::DependencyRewrite;
+use crate::policies::package_storage::ContentEncoding;
+use crate::signing::SigningKey;
 
 #[derive(Debug, Clone)]
 pub struct EnvConfigurator {
     fqdn: String,
+    integrity_algorithm: Algorithm,
+    deployment_id: Option<String>,
+    user_agent: Option<String>,
+    upstream_headers: HeaderMap,
+    upstream_retries: u32,
+    admin_users: Vec<String>,
+    admin_keys: HashMap<String, AdminKey>,
+    dependency_rewrites: Vec<DependencyRewrite>,
+    package_allowlist: Vec<String>,
+    package_blocklist: Vec<String>,
+    follow_packages: Vec<String>,
+    replication_url: String,
+    preset: Preset,
+    require_auth: Option<bool>,
+    upstream_enabled: Option<bool>,
+    geoip_database: Option<PathBuf>,
+    publish_countries: Vec<String>,
+    search_prefetch: usize,
+    confusion_monitor: Option<bool>,
+    signing_keys: Vec<SigningKey>,
+    cors_origins: Vec<String>,
+    cors_methods: Vec<Method>,
+    login_timeout: Duration,
+    login_page: LoginPage,
+    preview_scope: Option<String>,
+    preview_token_ttl: Duration,
+    preview_retention: Duration,
+    unpublish_window: Duration,
+    max_served_versions: Option<usize>,
+    packument_encodings: Vec<ContentEncoding>,
+    packument_max_age: Option<Duration>,
+    stale_while_revalidate: bool,
+    cache_max_size: Option<u64>,
+}
+
+const UPSTREAM_HEADER_PREFIX: &str = "REGI_UPSTREAM_HEADER_";
+
+// `REGI_UPSTREAM_HEADER_X_CONTACT=ops@example.com` sends `x-contact: ops@example.com`.
+fn upstream_headers_from_env() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (key, value) in std::env::vars() {
+        let Some(name) = key.strip_prefix(UPSTREAM_HEADER_PREFIX) else {
+            continue;
+        };
+
+        let name = name.replace('_', "-").to_ascii_lowercase();
+        match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value.as_str()),
+        ) {
+            (Ok(name), Ok(value)) => {
+                headers.insert(name, value);
+            }
+            _ => tracing::warn!(key, "ignoring invalid upstream header"),
+        }
+    }
+    headers
+}
+
+// `REGI_USER_AGENT`, unless it can't be sent as a header; the default stands in for it then.
+fn user_agent_from_env() -> Option<String> {
+    let user_agent = std::env::var("REGI_USER_AGENT").ok()?;
+    if HeaderValue::from_str(user_agent.as_str()).is_err() {
+        tracing::warn!(user_agent, "ignoring invalid user agent");
+        return None;
+    }
+    Some(user_agent)
+}
+
+// `REGI_REQUIRE_AUTH=true`; anything unrecognised is ignored, leaving the preset's choice.
+fn flag_from_env(key: &str) -> Option<bool> {
+    let value = std::env::var(key).ok()?;
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => {
+            tracing::warn!(key, value, "ignoring invalid flag");
+            None
+        }
+    }
+}
+
+// `REGI_ADMIN_USERS=alice,bob`
+fn list_from_env(key: &str) -> Vec<String> {
+    std::env::var(key)
+        .map(|items| {
+            items
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// `REGI_CORS_METHODS=GET,HEAD,PUT`; unset keeps to reads.
+fn cors_methods_from_env() -> Vec<Method> {
+    let methods = list_from_env("REGI_CORS_METHODS");
+    if methods.is_empty() {
+        return vec![Method::GET, Method::HEAD];
+    }
+
+    methods
+        .iter()
+        .filter_map(|method| {
+            method
+                .to_ascii_uppercase()
+                .parse()
+                .map_err(|_| tracing::warn!(method, "ignoring invalid REGI_CORS_METHODS entry"))
+                .ok()
+        })
+        .collect()
+}
+
+// `REGI_PRESET=enterprise-strict`
+fn preset_from_env() -> Preset {
+    let Ok(preset) = std::env::var("REGI_PRESET") else {
+        return Preset::default();
+    };
+
+    preset.parse().unwrap_or_else(|e| {
+        tracing::warn!(error = ?e, "ignoring REGI_PRESET");
+        Preset::default()
+    })
 }
 
 impl EnvConfigurator {
@@ -18,10 +158,186 @@ impl EnvConfigurator {
                     .map(|(host, port)| format!("http://{}:{}", host, port))
             })
             .unwrap_or_else(|| "http://localhost:8000".to_string());
-        Self { fqdn }
+
+        let integrity_algorithm = std::env::var("REGI_INTEGRITY_ALGORITHM")
+            .ok()
+            .and_then(|algorithm| algorithm.parse().ok())
+            .filter(|algorithm| {
+                if hashing::FIPS && *algorithm == Algorithm::Sha1 {
+                    tracing::warn!("sha1 is not an acceptable integrity algorithm in FIPS mode");
+                    return false;
+                }
+                true
+            })
+            .unwrap_or_default();
+
+        Self {
+            fqdn,
+            integrity_algorithm,
+            deployment_id: std::env::var("REGI_DEPLOYMENT_ID").ok(),
+            user_agent: user_agent_from_env(),
+            upstream_headers: upstream_headers_from_env(),
+            upstream_retries: std::env::var("REGI_UPSTREAM_RETRIES")
+                .ok()
+                .and_then(|retries| retries.parse().ok())
+                .unwrap_or(DEFAULT_UPSTREAM_RETRIES),
+            admin_users: list_from_env("REGI_ADMIN_USERS"),
+            admin_keys: admin_keys_from_env(),
+            dependency_rewrites: dependency_rewrites_from_env(),
+            package_allowlist: list_from_env("REGI_PACKAGE_ALLOWLIST"),
+            package_blocklist: list_from_env("REGI_PACKAGE_BLOCKLIST"),
+            follow_packages: list_from_env("REGI_FOLLOW_PACKAGES"),
+            replication_url: std::env::var("REGI_REPLICATION_URL")
+                .unwrap_or_else(|_| DEFAULT_REPLICATION_URL.to_string()),
+            preset: preset_from_env(),
+            require_auth: flag_from_env("REGI_REQUIRE_AUTH"),
+            upstream_enabled: flag_from_env("REGI_UPSTREAM_ENABLED"),
+            geoip_database: std::env::var_os("REGI_GEOIP_DATABASE").map(PathBuf::from),
+            publish_countries: list_from_env("REGI_PUBLISH_COUNTRIES")
+                .into_iter()
+                .map(|country| country.to_ascii_uppercase())
+                .collect(),
+            search_prefetch: std::env::var("REGI_SEARCH_PREFETCH")
+                .ok()
+                .and_then(|count| count.parse().ok())
+                .unwrap_or_default(),
+            confusion_monitor: flag_from_env("REGI_CONFUSION_MONITOR"),
+            signing_keys: signing_keys_from_env(),
+            cors_origins: list_from_env("REGI_CORS_ORIGINS"),
+            cors_methods: cors_methods_from_env(),
+            login_timeout: std::env::var("REGI_LOGIN_TIMEOUT_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .map_or(DEFAULT_LOGIN_TIMEOUT, Duration::from_secs),
+            login_page: login_page_from_env(),
+            preview_scope: std::env::var("REGI_PREVIEW_SCOPE")
+                .ok()
+                .map(|scope| scope.trim_start_matches('@').to_string())
+                .filter(|scope| !scope.is_empty()),
+            preview_token_ttl: std::env::var("REGI_PREVIEW_TOKEN_TTL_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .map_or(DEFAULT_PREVIEW_TOKEN_TTL, Duration::from_secs),
+            preview_retention: std::env::var("REGI_PREVIEW_RETENTION_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .map_or(DEFAULT_PREVIEW_RETENTION, Duration::from_secs),
+            unpublish_window: std::env::var("REGI_UNPUBLISH_WINDOW_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .map_or(DEFAULT_UNPUBLISH_WINDOW, Duration::from_secs),
+            max_served_versions: std::env::var("REGI_MAX_SERVED_VERSIONS")
+                .ok()
+                .and_then(|count| count.parse().ok())
+                .filter(|count| *count > 0),
+            packument_encodings: packument_encodings_from_env(),
+            packument_max_age: std::env::var("REGI_PACKUMENT_MAX_AGE_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs),
+            stale_while_revalidate: flag_from_env("REGI_STALE_WHILE_REVALIDATE").unwrap_or(false),
+            cache_max_size: std::env::var("REGI_CACHE_MAX_BYTES")
+                .ok()
+                .and_then(|bytes| bytes.parse().ok()),
+        }
     }
 }
 
+// `REGI_LOGIN_PAGE=/etc/registry/login.html`; a page that can't be read is replaced by the
+// default rather than failing startup.
+fn login_page_from_env() -> LoginPage {
+    let Some(path) = std::env::var_os("REGI_LOGIN_PAGE") else {
+        return LoginPage::default();
+    };
+
+    std::fs::read_to_string(&path)
+        .map(LoginPage::new)
+        .unwrap_or_else(|e| {
+            tracing::warn!(error = ?e, path = ?path, "ignoring unreadable REGI_LOGIN_PAGE");
+            LoginPage::default()
+        })
+}
+
+// `REGI_PACKUMENT_ENCODINGS=gzip,br`; set but empty, no compressed copies are kept.
+fn packument_encodings_from_env() -> Vec<ContentEncoding> {
+    if std::env::var_os("REGI_PACKUMENT_ENCODINGS").is_none() {
+        return vec![ContentEncoding::Gzip];
+    }
+
+    list_from_env("REGI_PACKUMENT_ENCODINGS")
+        .iter()
+        .filter_map(|encoding| match encoding.parse() {
+            Ok(encoding) => Some(encoding),
+            Err(e) => {
+                tracing::warn!(error = ?e, "ignoring unknown packument encoding");
+                None
+            }
+        })
+        .collect()
+}
+
+// `REGI_ADMIN_KEYS='{"ci": {"secret": "...", "scopes": ["purge"]}}'`
+fn admin_keys_from_env() -> HashMap<String, AdminKey> {
+    let Ok(keys) = std::env::var("REGI_ADMIN_KEYS") else {
+        return HashMap::new();
+    };
+
+    serde_json::from_str(keys.as_str()).unwrap_or_else(|e| {
+        tracing::warn!(error = ?e, "ignoring malformed REGI_ADMIN_KEYS");
+        HashMap::new()
+    })
+}
+
+// `REGI_DEPENDENCY_REWRITES='[{"scope": "corp", "type": "prefix", "from": "git+https://github.com/", "to": "https://mirror.corp/git/"}]'`
+fn dependency_rewrites_from_env() -> Vec<DependencyRewrite> {
+    let Ok(rewrites) = std::env::var("REGI_DEPENDENCY_REWRITES") else {
+        return Vec::new();
+    };
+
+    serde_json::from_str(rewrites.as_str()).unwrap_or_else(|e| {
+        tracing::warn!(error = ?e, "ignoring malformed REGI_DEPENDENCY_REWRITES");
+        Vec::new()
+    })
+}
+
+#[derive(serde::Deserialize)]
+struct SigningKeyFile {
+    path: PathBuf,
+    #[serde(default)]
+    expires: Option<DateTime<Utc>>,
+}
+
+// `REGI_SIGNING_KEYS='[{"path": "/etc/registry/2026.pem"}, {"path": "/etc/registry/2025.pem", "expires": "2026-01-01T00:00:00Z"}]'`
+//
+// A key that can't be read is left out rather than failing startup; versions are then
+// signed with the next, or not at all.
+fn signing_keys_from_env() -> Vec<SigningKey> {
+    let Ok(keys) = std::env::var("REGI_SIGNING_KEYS") else {
+        return Vec::new();
+    };
+
+    let files: Vec<SigningKeyFile> = serde_json::from_str(keys.as_str()).unwrap_or_else(|e| {
+        tracing::warn!(error = ?e, "ignoring malformed REGI_SIGNING_KEYS");
+        Vec::new()
+    });
+
+    files
+        .into_iter()
+        .filter_map(|file| {
+            let key = std::fs::read_to_string(&file.path)
+                .map_err(anyhow::Error::from)
+                .and_then(|pem| Ok(SigningKey::from_pem(pem.as_str())?));
+            match key {
+                Ok(key) => Some(key.with_expiry(file.expires)),
+                Err(e) => {
+                    tracing::warn!(error = ?e, path = ?file.path, "ignoring signing key");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
 impl Default for EnvConfigurator {
     fn default() -> Self {
         EnvConfigurator::new()
@@ -44,4 +360,141 @@ impl Configurator for EnvConfigurator {
         let secret = std::env::var("REGI_COOKIE_SECRET")?;
         Ok(Key::from(secret.as_bytes()))
     }
+
+    fn integrity_algorithm(&self) -> Algorithm {
+        self.integrity_algorithm
+    }
+
+    fn deployment_id(&self) -> &str {
+        self.deployment_id.as_deref().unwrap_or(self.fqdn.as_str())
+    }
+
+    fn upstream_user_agent(&self) -> String {
+        match self.user_agent {
+            Some(ref user_agent) => user_agent.clone(),
+            None => default_user_agent(self.deployment_id()),
+        }
+    }
+
+    fn upstream_headers(&self) -> HeaderMap {
+        self.upstream_headers.clone()
+    }
+
+    fn upstream_retries(&self) -> u32 {
+        self.upstream_retries
+    }
+
+    fn admin_users(&self) -> &[String] {
+        self.admin_users.as_slice()
+    }
+
+    fn admin_key(&self, key_id: &str) -> Option<&AdminKey> {
+        self.admin_keys.get(key_id)
+    }
+
+    fn dependency_rewrites(&self) -> &[DependencyRewrite] {
+        self.dependency_rewrites.as_slice()
+    }
+
+    fn package_allowlist(&self) -> &[String] {
+        self.package_allowlist.as_slice()
+    }
+
+    fn package_blocklist(&self) -> &[String] {
+        self.package_blocklist.as_slice()
+    }
+
+    fn follow_packages(&self) -> &[String] {
+        self.follow_packages.as_slice()
+    }
+
+    fn replication_url(&self) -> &str {
+        self.replication_url.as_str()
+    }
+
+    fn preset(&self) -> Preset {
+        self.preset
+    }
+
+    fn require_auth(&self) -> bool {
+        self.require_auth
+            .unwrap_or_else(|| self.preset.require_auth())
+    }
+
+    fn upstream_enabled(&self) -> bool {
+        self.upstream_enabled
+            .unwrap_or_else(|| self.preset.upstream_enabled())
+    }
+
+    fn geoip_database(&self) -> Option<&Path> {
+        self.geoip_database.as_deref()
+    }
+
+    fn publish_countries(&self) -> &[String] {
+        self.publish_countries.as_slice()
+    }
+
+    fn search_prefetch(&self) -> usize {
+        self.search_prefetch
+    }
+
+    fn confusion_monitor(&self) -> bool {
+        self.upstream_enabled() && self.confusion_monitor.unwrap_or(true)
+    }
+
+    fn signing_keys(&self) -> &[SigningKey] {
+        self.signing_keys.as_slice()
+    }
+
+    fn cors_origins(&self) -> &[String] {
+        self.cors_origins.as_slice()
+    }
+
+    fn cors_methods(&self) -> Vec<Method> {
+        self.cors_methods.clone()
+    }
+
+    fn login_timeout(&self) -> Duration {
+        self.login_timeout
+    }
+
+    fn login_page(&self, outcome: LoginOutcome<'_>) -> String {
+        self.login_page.render(outcome)
+    }
+
+    fn preview_scope(&self) -> Option<&str> {
+        self.preview_scope.as_deref()
+    }
+
+    fn preview_token_ttl(&self) -> Duration {
+        self.preview_token_ttl
+    }
+
+    fn preview_retention(&self) -> Duration {
+        self.preview_retention
+    }
+
+    fn unpublish_window(&self) -> Duration {
+        self.unpublish_window
+    }
+
+    fn max_served_versions(&self) -> Option<usize> {
+        self.max_served_versions
+    }
+
+    fn packument_encodings(&self) -> Vec<ContentEncoding> {
+        self.packument_encodings.clone()
+    }
+
+    fn packument_max_age(&self) -> Option<Duration> {
+        self.packument_max_age
+    }
+
+    fn stale_while_revalidate(&self) -> bool {
+        self.stale_while_revalidate
+    }
+
+    fn cache_max_size(&self) -> Option<u64> {
+        self.cache_max_size
+    }
 }
@@ -1,11 +1,415 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use axum::http::Method;
 use axum_extra::extract::cookie::Key;
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+
+use crate::hashing::Algorithm;
+use crate::policies::package_storage::rewrite::DependencyRewrite;
+use crate::policies::package_storage::ContentEncoding;
+use crate::signing::SigningKey;
 
 pub(crate) mod env;
 
+/// A shared secret machine callers use to sign admin requests, limited to some scopes
+/// (`"purge"`, `"quarantine"`, ...; `"*"` grants all of them).
+#[derive(Clone, Deserialize)]
+pub struct AdminKey {
+    pub secret: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+impl std::fmt::Debug for AdminKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminKey")
+            .field("scopes", &self.scopes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AdminKey {
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scopes
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == scope)
+    }
+}
+
+/// A named bundle of defaults for one kind of deployment, so that the settings which only
+/// make sense together are chosen together. Anything set on its own overrides the preset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Preset {
+    /// Anyone may install; missing packages are fetched from the upstream registry.
+    #[default]
+    PublicProxy,
+    /// Every request but logging in needs a token; the upstream is still consulted.
+    EnterpriseStrict,
+    /// Tokens required, and nothing is fetched from an upstream: only packages already
+    /// cached, synced or published here are served.
+    Airgapped,
+}
+
+impl Preset {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Preset::PublicProxy => "public-proxy",
+            Preset::EnterpriseStrict => "enterprise-strict",
+            Preset::Airgapped => "airgapped",
+        }
+    }
+
+    pub fn require_auth(&self) -> bool {
+        !matches!(self, Preset::PublicProxy)
+    }
+
+    pub fn upstream_enabled(&self) -> bool {
+        !matches!(self, Preset::Airgapped)
+    }
+}
+
+impl FromStr for Preset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public-proxy" => Ok(Preset::PublicProxy),
+            "enterprise-strict" => Ok(Preset::EnterpriseStrict),
+            "airgapped" => Ok(Preset::Airgapped),
+            _ => anyhow::bail!("unknown preset {:?}", s),
+        }
+    }
+}
+
+/// How a web login ended, for the page the browser is left on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoginOutcome<'a> {
+    /// The waiting `npm login` collects its token on its next poll.
+    SignedIn { username: &'a str },
+    /// `reason` is shown to the user, so it never carries an internal error.
+    Failed { reason: &'a str },
+}
+
+const DEFAULT_LOGIN_PAGE: &str = r#"<!doctype html>
+<html>
+<head><meta charset="utf-8"><title>{{title}}</title></head>
+<body class="{{outcome}}">
+    <h1>&#9813; {{title}}</h1>
+    <p>{{message}}</p>
+</body>
+</html>
+"#;
+
+/// The HTML page shown at the end of a web login. `{{title}}`, `{{message}}` and
+/// `{{outcome}}` (`success` or `failure`) are replaced, escaped, with how the login went.
+#[derive(Clone, Debug)]
+pub struct LoginPage {
+    template: String,
+}
+
+impl Default for LoginPage {
+    fn default() -> Self {
+        Self::new(DEFAULT_LOGIN_PAGE)
+    }
+}
+
+impl LoginPage {
+    pub fn new(template: impl Into<String>) -> Self {
+        Self {
+            template: template.into(),
+        }
+    }
+
+    pub fn render(&self, outcome: LoginOutcome<'_>) -> String {
+        let (kind, title, message) = match outcome {
+            LoginOutcome::SignedIn { username } => (
+                "success",
+                "Logged in",
+                format!(
+                    "Logged in as {}. You can close this window and return to your terminal.",
+                    username
+                ),
+            ),
+            LoginOutcome::Failed { reason } => (
+                "failure",
+                "Login failed",
+                format!(
+                    "{}. Return to your terminal and run `npm login` again.",
+                    sentence_case(reason.trim_end_matches('.'))
+                ),
+            ),
+        };
+
+        self.template
+            .replace("{{outcome}}", kind)
+            .replace("{{title}}", title)
+            .replace("{{message}}", escape_html(message.as_str()).as_str())
+    }
+}
+
+// Reasons come from errors ("login session expired") as often as from providers.
+fn sentence_case(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub(crate) const DEFAULT_LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+pub(crate) const DEFAULT_PREVIEW_TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+pub(crate) const DEFAULT_PREVIEW_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+pub(crate) const DEFAULT_UNPUBLISH_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+pub(crate) const DEFAULT_UPSTREAM_RETRIES: u32 = 2;
+pub(crate) const DEFAULT_REPLICATION_URL: &str = "https://replicate.npmjs.com/registry";
+
+pub(crate) fn default_user_agent(deployment_id: &str) -> String {
+    format!(
+        "{}/{} (+{})",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        deployment_id
+    )
+}
+
 #[async_trait::async_trait]
 pub trait Configurator {
     fn fqdn(&self) -> &str;
 
     async fn oauth_config(&self) -> anyhow::Result<(String, String)>;
     async fn cookie_key(&self) -> anyhow::Result<Key>;
+
+    /// The algorithm used when we compute integrity ourselves (e.g. for cached content).
+    fn integrity_algorithm(&self) -> Algorithm {
+        Algorithm::default()
+    }
+
+    /// Identifies this deployment in logs and (by default) the upstream User-Agent.
+    fn deployment_id(&self) -> &str {
+        self.fqdn()
+    }
+
+    /// The User-Agent sent to upstream registries. Operators of public registries ask that
+    /// proxies identify themselves and give a way to contact whoever runs them.
+    fn upstream_user_agent(&self) -> String {
+        default_user_agent(self.deployment_id())
+    }
+
+    /// Extra headers sent with every upstream request.
+    fn upstream_headers(&self) -> HeaderMap {
+        HeaderMap::new()
+    }
+
+    /// How many times an upstream request that fails in a way that might not last is tried
+    /// again before giving up.
+    fn upstream_retries(&self) -> u32 {
+        DEFAULT_UPSTREAM_RETRIES
+    }
+
+    /// Users whose bearer tokens grant full access to admin endpoints.
+    fn admin_users(&self) -> &[String] {
+        &[]
+    }
+
+    /// The signing key with id `key_id`, for HMAC-authenticated admin requests.
+    fn admin_key(&self, _key_id: &str) -> Option<&AdminKey> {
+        None
+    }
+
+    /// Dependency specifier rewrites applied to served packuments, for mirrors that must
+    /// keep installs inside themselves.
+    fn dependency_rewrites(&self) -> &[DependencyRewrite] {
+        &[]
+    }
+
+    /// Patterns for the only packages that may be read or published: exact names
+    /// (`left-pad`), whole scopes (`@corp`) or globs (`@types/*`, `lodash.*`). Empty allows
+    /// every package the blocklist doesn't name.
+    fn package_allowlist(&self) -> &[String] {
+        &[]
+    }
+
+    /// Patterns, as for the allowlist, for packages that may never be read or published,
+    /// whether or not the allowlist names them.
+    fn package_blocklist(&self) -> &[String] {
+        &[]
+    }
+
+    /// Patterns, as for the allowlist, for the packages whose cached copies are refreshed as
+    /// soon as the upstream's replication feed reports a change to them. Empty follows none.
+    fn follow_packages(&self) -> &[String] {
+        &[]
+    }
+
+    /// The CouchDB database whose `_changes` feed reports changes to upstream packages.
+    fn replication_url(&self) -> &str {
+        DEFAULT_REPLICATION_URL
+    }
+
+    /// The preset the other settings default from.
+    fn preset(&self) -> Preset {
+        Preset::default()
+    }
+
+    /// Whether every request but logging in must carry a valid token. Admin and SCIM
+    /// endpoints check their own credentials either way.
+    fn require_auth(&self) -> bool {
+        self.preset().require_auth()
+    }
+
+    /// Whether packages we don't have are fetched from the upstream registry.
+    fn upstream_enabled(&self) -> bool {
+        self.preset().upstream_enabled()
+    }
+
+    /// A MaxMind database to geolocate clients with, if any.
+    fn geoip_database(&self) -> Option<&Path> {
+        None
+    }
+
+    /// ISO country codes publishes are accepted from. Empty accepts them from anywhere, as
+    /// does an address that can't be located.
+    fn publish_countries(&self) -> &[String] {
+        &[]
+    }
+
+    /// How many of a search's top results to fetch into the cache in the background, since
+    /// an install usually follows a search. 0 turns prefetching off.
+    fn search_prefetch(&self) -> usize {
+        0
+    }
+
+    /// Whether packages published here are regularly checked for names that have since
+    /// turned up on the upstream registry. Needs the upstream.
+    fn confusion_monitor(&self) -> bool {
+        self.upstream_enabled()
+    }
+
+    /// Keys published at `/-/npm/v1/keys`. New versions are signed with the first that hasn't
+    /// expired; none leaves them unsigned.
+    fn signing_keys(&self) -> &[SigningKey] {
+        &[]
+    }
+
+    /// Origins browsers may call the API from (`https://dashboard.corp`), or `"*"` for any.
+    /// Empty sends no CORS headers, leaving browsers to refuse cross-origin reads.
+    fn cors_origins(&self) -> &[String] {
+        &[]
+    }
+
+    /// Methods allowed from those origins. Only reads, unless configured otherwise.
+    fn cors_methods(&self) -> Vec<Method> {
+        vec![Method::GET, Method::HEAD]
+    }
+
+    /// How long a web login may take, from `npm login` starting it to the user signing in.
+    /// Polling a session older than this answers 410 Gone.
+    fn login_timeout(&self) -> Duration {
+        DEFAULT_LOGIN_TIMEOUT
+    }
+
+    /// The page a browser is left on when a web login finishes, telling the user how it went
+    /// and to return to their terminal.
+    fn login_page(&self, outcome: LoginOutcome<'_>) -> String {
+        LoginPage::default().render(outcome)
+    }
+
+    /// The scope (without its `@`) preview tokens publish to, e.g. `preview` for
+    /// `@preview/*`. `None` means preview tokens can't be minted.
+    fn preview_scope(&self) -> Option<&str> {
+        None
+    }
+
+    /// The longest a preview token may last.
+    fn preview_token_ttl(&self) -> Duration {
+        DEFAULT_PREVIEW_TOKEN_TTL
+    }
+
+    /// How long a preview version is kept after it's published, before it and its tarball
+    /// are deleted.
+    fn preview_retention(&self) -> Duration {
+        DEFAULT_PREVIEW_RETENTION
+    }
+
+    /// How long after a package is unpublished its name is held, before anyone may publish
+    /// to it again. The versions it had can't be published again even then.
+    fn unpublish_window(&self) -> Duration {
+        DEFAULT_UNPUBLISH_WINDOW
+    }
+
+    /// How many versions of each release line served packuments keep, trimming the rest
+    /// unless a client asks for `?full=true`. `None` serves every version.
+    fn max_served_versions(&self) -> Option<usize> {
+        None
+    }
+
+    /// The encodings cached packuments are also kept compressed with, and served in as they
+    /// are, rather than compressed on every request.
+    fn packument_encodings(&self) -> Vec<ContentEncoding> {
+        vec![ContentEncoding::Gzip]
+    }
+
+    /// How long a packument cached from the upstream is served before it's checked against
+    /// the upstream again. `None` keeps cached packuments until they're written over.
+    fn packument_max_age(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Whether an expired packument is served as it is while it's revalidated in the
+    /// background, rather than after.
+    fn stale_while_revalidate(&self) -> bool {
+        false
+    }
+
+    /// How many bytes of cached upstream documents and tarballs to keep before the least
+    /// recently used are evicted. `None` lets the cache grow without limit.
+    fn cache_max_size(&self) -> Option<u64> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_page() {
+        let page = LoginPage::new("<p class={{outcome}}>{{title}}: {{message}}</p>");
+        assert_eq!(
+            page.render(LoginOutcome::SignedIn { username: "alice" }),
+            "<p class=success>Logged in: Logged in as alice. You can close this window and \
+             return to your terminal.</p>"
+        );
+
+        assert_eq!(
+            page.render(LoginOutcome::Failed {
+                reason: "login session expired"
+            }),
+            "<p class=failure>Login failed: Login session expired. Return to your terminal and \
+             run `npm login` again.</p>"
+        );
+
+        let failed = page.render(LoginOutcome::Failed {
+            reason: "<script>.",
+        });
+        assert!(failed.contains("&lt;script&gt;. Return"));
+    }
 }
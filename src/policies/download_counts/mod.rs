@@ -0,0 +1,144 @@
+use std::str::FromStr;
+
+use chrono::{Duration, NaiveDate, Utc};
+use thiserror::Error;
+
+use crate::models::PackageIdentifier;
+
+#[cfg(feature = "tokio-backends")]
+pub(crate) mod sqlite;
+
+#[derive(Debug, Error)]
+pub enum DownloadPeriodError {
+    #[error("Invalid download period {0:?}")]
+    InvalidPeriod(String),
+}
+
+/// An inclusive range of days, as accepted by api.npmjs.org: `last-day`, `last-week`,
+/// `last-month`, `last-year`, a single `YYYY-MM-DD` or a `YYYY-MM-DD:YYYY-MM-DD` range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DownloadPeriod {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+}
+
+impl DownloadPeriod {
+    /// Resolve a period relative to `today`. The named periods end with the last full day.
+    pub fn parse_relative_to(period: &str, today: NaiveDate) -> Result<Self, DownloadPeriodError> {
+        let yesterday = today - Duration::days(1);
+        let trailing = |days: i64| DownloadPeriod {
+            start: yesterday - Duration::days(days - 1),
+            end: yesterday,
+        };
+
+        let invalid = || DownloadPeriodError::InvalidPeriod(period.to_string());
+        let parse_day =
+            |day: &str| NaiveDate::parse_from_str(day, "%Y-%m-%d").map_err(|_| invalid());
+
+        let period = match period {
+            "last-day" => trailing(1),
+            "last-week" => trailing(7),
+            "last-month" => trailing(30),
+            "last-year" => trailing(365),
+            range => match range.split_once(':') {
+                Some((start, end)) => DownloadPeriod {
+                    start: parse_day(start)?,
+                    end: parse_day(end)?,
+                },
+                None => {
+                    let day = parse_day(range)?;
+                    DownloadPeriod {
+                        start: day,
+                        end: day,
+                    }
+                }
+            },
+        };
+
+        if period.start > period.end {
+            return Err(invalid());
+        }
+        Ok(period)
+    }
+
+    pub fn days(&self) -> impl Iterator<Item = NaiveDate> {
+        let end = self.end;
+        self.start.iter_days().take_while(move |day| *day <= end)
+    }
+}
+
+impl FromStr for DownloadPeriod {
+    type Err = DownloadPeriodError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_relative_to(s, Utc::now().date_naive())
+    }
+}
+
+/// Per-package, per-day tarball download counters.
+#[async_trait::async_trait]
+pub trait DownloadCounts: Send + Sync {
+    async fn record_download(
+        &self,
+        package: &PackageIdentifier,
+        day: NaiveDate,
+    ) -> anyhow::Result<()>;
+
+    /// Daily counts for every day in `period` with at least one download.
+    async fn daily_downloads(
+        &self,
+        package: &PackageIdentifier,
+        period: DownloadPeriod,
+    ) -> anyhow::Result<Vec<(NaiveDate, u64)>>;
+
+    async fn total_downloads(
+        &self,
+        package: &PackageIdentifier,
+        period: DownloadPeriod,
+    ) -> anyhow::Result<u64> {
+        Ok(self
+            .daily_downloads(package, period)
+            .await?
+            .into_iter()
+            .map(|(_, count)| count)
+            .sum())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_download_periods() {
+        let today = NaiveDate::from_ymd_opt(2023, 3, 10).unwrap();
+        let day = |d| NaiveDate::from_ymd_opt(2023, 3, d).unwrap();
+        let parse = |period| DownloadPeriod::parse_relative_to(period, today);
+
+        assert_eq!(
+            parse("last-day").unwrap(),
+            DownloadPeriod {
+                start: day(9),
+                end: day(9)
+            }
+        );
+        assert_eq!(
+            parse("last-week").unwrap(),
+            DownloadPeriod {
+                start: day(3),
+                end: day(9)
+            }
+        );
+        assert_eq!(parse("last-week").unwrap().days().count(), 7);
+        assert_eq!(
+            parse("2023-03-01:2023-03-04").unwrap(),
+            DownloadPeriod {
+                start: day(1),
+                end: day(4)
+            }
+        );
+        assert_eq!(parse("2023-03-05").unwrap().days().count(), 1);
+        assert!(parse("2023-03-05:2023-03-01").is_err());
+        assert!(parse("last-decade").is_err());
+    }
+}
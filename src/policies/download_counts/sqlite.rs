@@ -0,0 +1,121 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDate;
+use rusqlite::{params, Connection};
+
+use crate::migrations::Migrator;
+use crate::models::PackageIdentifier;
+use crate::policies::DownloadCounts;
+
+use super::DownloadPeriod;
+
+const DAY_FORMAT: &str = "%Y-%m-%d";
+
+pub fn migrations() -> Migrator<Connection> {
+    Migrator::new("download counts").migration("create the downloads table", |connection| {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS downloads (
+                package TEXT NOT NULL,
+                day TEXT NOT NULL,
+                count INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (package, day)
+            );",
+        )?;
+        Ok(())
+    })
+}
+
+/// Download counters in a SQLite database, bucketed by UTC day.
+#[derive(Clone)]
+pub struct SqliteDownloadCounts {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl std::fmt::Debug for SqliteDownloadCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqliteDownloadCounts")
+            .finish_non_exhaustive()
+    }
+}
+
+impl SqliteDownloadCounts {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    pub fn in_memory() -> anyhow::Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(connection: Connection) -> anyhow::Result<Self> {
+        migrations().run(&connection, false)?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    async fn with_connection<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let connection = connection.lock().unwrap_or_else(|e| e.into_inner());
+            Ok(f(&connection)?)
+        })
+        .await?
+    }
+}
+
+#[async_trait::async_trait]
+impl DownloadCounts for SqliteDownloadCounts {
+    async fn record_download(
+        &self,
+        package: &PackageIdentifier,
+        day: NaiveDate,
+    ) -> anyhow::Result<()> {
+        let package = package.to_string();
+        let day = day.format(DAY_FORMAT).to_string();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO downloads (package, day, count) VALUES (?1, ?2, 1)
+                 ON CONFLICT (package, day) DO UPDATE SET count = count + 1",
+                params![package, day],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn daily_downloads(
+        &self,
+        package: &PackageIdentifier,
+        period: DownloadPeriod,
+    ) -> anyhow::Result<Vec<(NaiveDate, u64)>> {
+        let package = package.to_string();
+        let start = period.start.format(DAY_FORMAT).to_string();
+        let end = period.end.format(DAY_FORMAT).to_string();
+        let rows = self
+            .with_connection(move |connection| {
+                let mut statement = connection.prepare(
+                    "SELECT day, count FROM downloads
+                     WHERE package = ?1 AND day BETWEEN ?2 AND ?3
+                     ORDER BY day",
+                )?;
+                let rows = statement
+                    .query_map(params![package, start, end], |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok(rows)
+            })
+            .await?;
+
+        rows.into_iter()
+            .map(|(day, count)| Ok((NaiveDate::parse_from_str(day.as_str(), DAY_FORMAT)?, count)))
+            .collect()
+    }
+}
@@ -0,0 +1,28 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use super::{Geolocator, Location};
+
+/// A fixed table of addresses, for tests and small deployments that know their clients.
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryGeolocator {
+    locations: HashMap<IpAddr, Location>,
+}
+
+impl InMemoryGeolocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_location(mut self, ip: IpAddr, location: Location) -> Self {
+        self.locations.insert(ip, location);
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl Geolocator for InMemoryGeolocator {
+    async fn locate(&self, ip: IpAddr) -> anyhow::Result<Option<Location>> {
+        Ok(self.locations.get(&ip).cloned())
+    }
+}
@@ -0,0 +1,55 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+
+use super::{Geolocator, Location};
+
+/// Locates addresses with a MaxMind database (GeoLite2 or GeoIP2, City or Country), read
+/// into memory once at startup.
+#[derive(Clone)]
+pub struct MaxMindGeolocator {
+    reader: Arc<Reader<Vec<u8>>>,
+}
+
+impl std::fmt::Debug for MaxMindGeolocator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MaxMindGeolocator")
+            .field("database", &self.reader.metadata.database_type)
+            .finish()
+    }
+}
+
+impl MaxMindGeolocator {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let reader = Reader::open_readfile(path.as_ref())
+            .map_err(|e| anyhow::anyhow!("could not open {}: {}", path.as_ref().display(), e))?;
+        Ok(Self {
+            reader: Arc::new(reader),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Geolocator for MaxMindGeolocator {
+    // City databases answer Country lookups too, with the fields a Country record shares.
+    async fn locate(&self, ip: IpAddr) -> anyhow::Result<Option<Location>> {
+        let city: geoip2::City = match self.reader.lookup(ip) {
+            Ok(city) => city,
+            Err(MaxMindDBError::AddressNotFoundError(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Some(Location {
+            country: city
+                .country
+                .and_then(|country| country.iso_code)
+                .map(str::to_string),
+            city: city
+                .city
+                .and_then(|city| city.names)
+                .and_then(|names| names.get("en").map(|name| name.to_string())),
+        }))
+    }
+}
@@ -0,0 +1,34 @@
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+pub(crate) mod in_memory;
+pub(crate) mod maxmind;
+
+/// Where an address is, as far as a geolocation database knows.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Location {
+    /// The ISO 3166-1 alpha-2 code, e.g. `"NZ"`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
+}
+
+/// Looks up where client addresses are, to enrich audit events and drive geo-based policy.
+#[async_trait::async_trait]
+pub trait Geolocator: Send + Sync {
+    /// `None` for addresses the database doesn't cover, such as private ones.
+    async fn locate(&self, ip: IpAddr) -> anyhow::Result<Option<Location>>;
+}
+
+/// An optional geolocator locates nothing when absent, so it can follow configuration.
+#[async_trait::async_trait]
+impl<G: Geolocator> Geolocator for Option<G> {
+    async fn locate(&self, ip: IpAddr) -> anyhow::Result<Option<Location>> {
+        match self {
+            Some(geolocator) => geolocator.locate(ip).await,
+            None => Ok(None),
+        }
+    }
+}
@@ -4,18 +4,33 @@ use axum::{
 };
 use futures::stream::BoxStream;
 
-use crate::models::{PackageIdentifier, User};
+use crate::models::{AuditRequest, PackageIdentifier, User};
 
+pub(crate) mod access_control;
+pub(crate) mod advisories;
 pub(crate) mod authenticator;
 pub(crate) mod configurator;
+pub(crate) mod download_counts;
+pub(crate) mod geolocation;
+pub(crate) mod moderation;
 pub(crate) mod not_implemented;
+pub(crate) mod package_rules;
 pub(crate) mod package_storage;
 pub(crate) mod policy;
 pub(crate) mod token_authorizer;
+pub(crate) mod transform;
 pub(crate) mod user_storage;
+pub(crate) mod webhooks;
 
+pub use access_control::AccessControl;
+pub use advisories::Advisories;
 pub use authenticator::Authenticator;
 pub use configurator::Configurator;
+pub use download_counts::DownloadCounts;
+pub use geolocation::Geolocator;
+pub use moderation::Moderation;
 pub use package_storage::PackageStorage;
 pub use token_authorizer::TokenAuthorizer;
+pub use transform::PackumentTransform;
 pub use user_storage::UserStorage;
+pub use webhooks::Webhooks;
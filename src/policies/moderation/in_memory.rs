@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use crate::models::PackageIdentifier;
+use crate::policies::Moderation;
+
+use super::Quarantine;
+
+// Keyed by package and then version, `None` being the package as a whole.
+type Quarantines = BTreeMap<String, BTreeMap<Option<String>, Quarantine>>;
+
+/// Quarantines held in memory.
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryModeration {
+    quarantines: Arc<RwLock<Quarantines>>,
+}
+
+impl InMemoryModeration {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl Moderation for InMemoryModeration {
+    async fn quarantined(
+        &self,
+        package: &PackageIdentifier,
+        version: Option<&str>,
+    ) -> anyhow::Result<Option<Quarantine>> {
+        let quarantines = self.quarantines.read().unwrap();
+        let Some(package) = quarantines.get(&package.to_string()) else {
+            return Ok(None);
+        };
+
+        let on_version = version.and_then(|version| package.get(&Some(version.to_string())));
+        Ok(package.get(&None).or(on_version).cloned())
+    }
+
+    async fn quarantine(&self, quarantine: Quarantine) -> anyhow::Result<()> {
+        self.quarantines
+            .write()
+            .unwrap()
+            .entry(quarantine.package.clone())
+            .or_default()
+            .insert(quarantine.version.clone(), quarantine);
+        Ok(())
+    }
+
+    async fn lift_quarantine(
+        &self,
+        package: &PackageIdentifier,
+        version: Option<&str>,
+    ) -> anyhow::Result<Option<Quarantine>> {
+        let mut quarantines = self.quarantines.write().unwrap();
+        let key = package.to_string();
+        let Some(package) = quarantines.get_mut(&key) else {
+            return Ok(None);
+        };
+
+        let lifted = package.remove(&version.map(str::to_string));
+        if package.is_empty() {
+            quarantines.remove(&key);
+        }
+        Ok(lifted)
+    }
+
+    async fn list_quarantines(&self) -> anyhow::Result<Vec<Quarantine>> {
+        Ok(self
+            .quarantines
+            .read()
+            .unwrap()
+            .values()
+            .flat_map(|versions| versions.values().cloned())
+            .collect())
+    }
+}
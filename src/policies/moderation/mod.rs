@@ -0,0 +1,54 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::models::PackageIdentifier;
+
+pub(crate) mod in_memory;
+
+/// A package, or one version of it, withheld from installs while a report against it is
+/// looked into. Nothing is deleted; lifting the quarantine serves it again as it was.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quarantine {
+    pub package: String,
+    /// `None` when the whole package is quarantined.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub reason: String,
+    pub by: String,
+    pub created: DateTime<Utc>,
+}
+
+impl Quarantine {
+    /// The package, or `package@version`, for messages.
+    pub fn subject(&self) -> String {
+        match &self.version {
+            Some(version) => format!("{}@{}", self.package, version),
+            None => self.package.clone(),
+        }
+    }
+}
+
+/// Keeps the quarantines admins place on packages in response to malware reports.
+#[async_trait::async_trait]
+pub trait Moderation: Send + Sync {
+    /// The quarantine withholding `version` of `package`: one on the whole package, or on
+    /// that version. With no version, only a quarantine on the whole package counts.
+    async fn quarantined(
+        &self,
+        package: &PackageIdentifier,
+        version: Option<&str>,
+    ) -> anyhow::Result<Option<Quarantine>>;
+
+    /// Place `quarantine`, replacing any already on the same package or version.
+    async fn quarantine(&self, quarantine: Quarantine) -> anyhow::Result<()>;
+
+    /// Lift the quarantine on `package`, or on one version of it. Returns the quarantine
+    /// lifted, if there was one.
+    async fn lift_quarantine(
+        &self,
+        package: &PackageIdentifier,
+        version: Option<&str>,
+    ) -> anyhow::Result<Option<Quarantine>>;
+
+    async fn list_quarantines(&self) -> anyhow::Result<Vec<Quarantine>>;
+}
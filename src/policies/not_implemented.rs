@@ -1,6 +1,15 @@
+use std::collections::BTreeMap;
+
 use serde::Serialize;
 
+use super::access_control::{Access, Permission};
+use super::authenticator::LoginSessionError;
+use super::download_counts::DownloadPeriod;
+use super::geolocation::Location;
+use super::moderation::Quarantine;
+use super::webhooks::{Delivery, Hook, HookEvent, HookUpdate, NewHook};
 use super::*;
+use crate::models::{BulkAdvisoryRequest, BulkAdvisoryResponse, ProfileUpdate};
 
 trait Unimplemented: Send + Sync {}
 
@@ -19,8 +28,13 @@ impl<T: Unimplemented> Authenticator for T {
         Err(anyhow::anyhow!("not implemented"))
     }
 
-    async fn poll_login_session(&self, _id: Self::SessionId) -> anyhow::Result<Option<Self::User>> {
-        Err(anyhow::anyhow!("not implemented"))
+    async fn poll_login_session<C: Configurator + Send + Sync>(
+        &self,
+        _config: &C,
+        _id: Self::SessionId,
+    ) -> anyhow::Result<Option<Self::User>> {
+        // No session could have been started.
+        Err(LoginSessionError::Unrecognized.into())
     }
 
     async fn complete_login_session<C: Configurator + Send + Sync, U: UserStorage + Send + Sync>(
@@ -82,4 +96,172 @@ impl<T: Unimplemented> UserStorage for T {
     async fn list_users(&self) -> anyhow::Result<Vec<User>> {
         Err(anyhow::anyhow!("not implemented"))
     }
+
+    async fn update_user(&self, _username: &str, _update: ProfileUpdate) -> anyhow::Result<User> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Unimplemented> Advisories for T {
+    async fn audit(&self, _request: AuditRequest) -> anyhow::Result<serde_json::Value> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+
+    async fn bulk_advisories(
+        &self,
+        _request: BulkAdvisoryRequest,
+    ) -> anyhow::Result<BulkAdvisoryResponse> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Unimplemented> AccessControl for T {
+    // Without access control every package is public; installs must keep working.
+    async fn get_access(&self, _package: &PackageIdentifier) -> anyhow::Result<Access> {
+        Ok(Access::Public)
+    }
+
+    async fn set_access(
+        &self,
+        _package: &PackageIdentifier,
+        _access: Access,
+    ) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+
+    async fn grant(
+        &self,
+        _package: &PackageIdentifier,
+        _grantee: &str,
+        _permission: Permission,
+    ) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+
+    async fn revoke(&self, _package: &PackageIdentifier, _grantee: &str) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+
+    async fn list_packages(&self, _grantee: &str) -> anyhow::Result<BTreeMap<String, Permission>> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+
+    async fn list_collaborators(
+        &self,
+        _package: &PackageIdentifier,
+    ) -> anyhow::Result<BTreeMap<String, Permission>> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Unimplemented> Webhooks for T {
+    async fn create_hook(&self, _username: &str, _hook: NewHook) -> anyhow::Result<Hook> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+
+    async fn list_hooks(&self, _username: &str, _name: Option<&str>) -> anyhow::Result<Vec<Hook>> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+
+    async fn get_hook(&self, _username: &str, _id: &str) -> anyhow::Result<Option<Hook>> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+
+    async fn update_hook(
+        &self,
+        _username: &str,
+        _id: &str,
+        _update: HookUpdate,
+    ) -> anyhow::Result<Option<Hook>> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+
+    async fn delete_hook(&self, _username: &str, _id: &str) -> anyhow::Result<Option<Hook>> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+
+    async fn list_deliveries(
+        &self,
+        _username: &str,
+        _hook_id: &str,
+    ) -> anyhow::Result<Option<Vec<Delivery>>> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+
+    async fn replay_delivery(
+        &self,
+        _username: &str,
+        _hook_id: &str,
+        _delivery_id: &str,
+    ) -> anyhow::Result<Option<Delivery>> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+
+    // With no subscriptions there's nothing to deliver; this keeps the publish path working.
+    async fn dispatch(
+        &self,
+        _event: HookEvent,
+        _owners: &[String],
+    ) -> anyhow::Result<Vec<Delivery>> {
+        Ok(Vec::new())
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Unimplemented> DownloadCounts for T {
+    // Tarball downloads record unconditionally, so counting is a no-op when unconfigured.
+    async fn record_download(
+        &self,
+        _package: &PackageIdentifier,
+        _day: chrono::NaiveDate,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn daily_downloads(
+        &self,
+        _package: &PackageIdentifier,
+        _period: DownloadPeriod,
+    ) -> anyhow::Result<Vec<(chrono::NaiveDate, u64)>> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Unimplemented> Geolocator for T {
+    // Without a database there's nothing to enrich with, and geo policies let requests by.
+    async fn locate(&self, _ip: std::net::IpAddr) -> anyhow::Result<Option<Location>> {
+        Ok(None)
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Unimplemented> Moderation for T {
+    // Nothing can be quarantined without a store, so nothing is withheld from installs.
+    async fn quarantined(
+        &self,
+        _package: &PackageIdentifier,
+        _version: Option<&str>,
+    ) -> anyhow::Result<Option<Quarantine>> {
+        Ok(None)
+    }
+
+    async fn quarantine(&self, _quarantine: Quarantine) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+
+    async fn lift_quarantine(
+        &self,
+        _package: &PackageIdentifier,
+        _version: Option<&str>,
+    ) -> anyhow::Result<Option<Quarantine>> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
+
+    async fn list_quarantines(&self) -> anyhow::Result<Vec<Quarantine>> {
+        Err(anyhow::anyhow!("not implemented"))
+    }
 }
@@ -0,0 +1,126 @@
+//! Allow and deny rules for package names, for organizations that must keep known-malicious
+//! or unlicensed packages out of their installs. Rules come from
+//! [`Configurator::package_allowlist`] and [`Configurator::package_blocklist`] and are
+//! enforced by wrapping the served storage in a [`GuardStorage`], so that they apply alike to
+//! packuments, tarballs, searches and publishes.
+//!
+//! [`Configurator::package_allowlist`]: crate::policies::Configurator::package_allowlist
+//! [`Configurator::package_blocklist`]: crate::policies::Configurator::package_blocklist
+//! [`GuardStorage`]: crate::policies::package_storage::guard::GuardStorage
+
+use regex::Regex;
+
+use crate::models::PackageIdentifier;
+use crate::policies::package_storage::guard::Guard;
+use crate::policies::Configurator;
+
+/// One rule: an exact name (`left-pad`), a whole scope (`@corp`), or a glob in which `*`
+/// stands for any run of characters and `?` for any one (`@types/*`, `lodash.*`).
+#[derive(Clone, Debug)]
+struct Pattern {
+    pattern: String,
+    matcher: Regex,
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Self {
+        let pattern = pattern.trim().to_lowercase();
+        let glob = match pattern.starts_with('@') && !pattern.contains('/') {
+            true => format!("{}/*", pattern),
+            false => pattern.clone(),
+        };
+
+        let mut expression = String::from("^");
+        for c in glob.chars() {
+            match c {
+                '*' => expression.push_str(".*"),
+                '?' => expression.push('.'),
+                c => expression.push_str(regex::escape(c.encode_utf8(&mut [0; 4])).as_str()),
+            }
+        }
+        expression.push('$');
+
+        Self {
+            pattern,
+            // Everything but the wildcards is escaped, so this always compiles.
+            matcher: Regex::new(expression.as_str()).expect("escaped glob is a valid regex"),
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.matcher.is_match(name)
+    }
+}
+
+/// Which packages may be read and published. A package the blocklist matches is denied even
+/// if the allowlist matches it too; with an allowlist, anything it doesn't match is denied.
+#[derive(Clone, Debug, Default)]
+pub struct PackageRules {
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+}
+
+impl PackageRules {
+    pub fn new<S: AsRef<str>>(allow: &[S], deny: &[S]) -> Self {
+        Self {
+            allow: allow.iter().map(|p| Pattern::new(p.as_ref())).collect(),
+            deny: deny.iter().map(|p| Pattern::new(p.as_ref())).collect(),
+        }
+    }
+
+    pub fn from_configurator<C: Configurator>(configurator: &C) -> Self {
+        Self::new(
+            configurator.package_allowlist(),
+            configurator.package_blocklist(),
+        )
+    }
+
+    /// Whether there are no rules, and so every package is allowed.
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn guard(&self, name: &PackageIdentifier) -> Guard {
+        let name = name.to_string().to_lowercase();
+        if let Some(rule) = self.deny.iter().find(|rule| rule.matches(name.as_str())) {
+            tracing::debug!(name, rule = rule.pattern, "package is blocklisted");
+            return Guard::Deny;
+        }
+
+        if self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(name.as_str())) {
+            Guard::Allow
+        } else {
+            tracing::debug!(name, "package is not allowlisted");
+            Guard::Deny
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(rules: &PackageRules, name: &str) -> Guard {
+        rules.guard(&name.parse().unwrap())
+    }
+
+    #[test]
+    fn test_package_rules() {
+        let rules = PackageRules::new(&["@corp", "@types/*", "lodash.*", "react"], &["@corp/evil"]);
+        assert_eq!(guard(&rules, "react"), Guard::Allow);
+        assert_eq!(guard(&rules, "react-dom"), Guard::Deny);
+        assert_eq!(guard(&rules, "@corp/utils"), Guard::Allow);
+        assert_eq!(guard(&rules, "@corp/evil"), Guard::Deny);
+        assert_eq!(guard(&rules, "@corporate/utils"), Guard::Deny);
+        assert_eq!(guard(&rules, "@types/node"), Guard::Allow);
+        assert_eq!(guard(&rules, "lodash.merge"), Guard::Allow);
+        assert_eq!(guard(&rules, "lodashmerge"), Guard::Deny);
+
+        let rules = PackageRules::new(&[], &["event-stream", "colors?"]);
+        assert_eq!(guard(&rules, "event-stream"), Guard::Deny);
+        assert_eq!(guard(&rules, "colorsx"), Guard::Deny);
+        assert_eq!(guard(&rules, "colors"), Guard::Allow);
+        assert_eq!(guard(&rules, "left-pad"), Guard::Allow);
+        assert!(PackageRules::default().is_empty());
+    }
+}
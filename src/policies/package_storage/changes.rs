@@ -0,0 +1,318 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+use rusqlite::{params, Connection};
+
+use crate::migrations::Migrator;
+use crate::models::{PackageIdentifier, Packument, PackumentVersion};
+use crate::policies::PackageStorage;
+use crate::signing::PublicKey;
+
+use super::{
+    ByteRange, ContentEncoding, ContentMetadata, GarbageCollection, PackageChange, SearchQuery,
+    TarballStats,
+};
+
+pub fn migrations() -> Migrator<Connection> {
+    // AUTOINCREMENT, so that a sequence number is never handed out twice even after the row
+    // holding it is replaced.
+    Migrator::new("change log").migration("create the changes table", |connection| {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS changes (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                package TEXT NOT NULL UNIQUE,
+                rev TEXT
+            );",
+        )?;
+        Ok(())
+    })
+}
+
+/// Numbers every packument written through it, so that replication consumers can follow
+/// along with a `_changes` feed. The log lives in SQLite so sequence numbers survive
+/// restarts; packuments fetched from an upstream on a read aren't changes and aren't logged.
+#[derive(Clone)]
+pub struct ChangeLog<R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static> {
+    inner: R,
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl<R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static> std::fmt::Debug
+    for ChangeLog<R>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChangeLog")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R> ChangeLog<R>
+where
+    R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static,
+    <R as PackageStorage>::Error: std::error::Error + Send + Sync + 'static,
+{
+    pub fn open(inner: R, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::from_connection(inner, Connection::open(path)?)
+    }
+
+    pub fn in_memory(inner: R) -> anyhow::Result<Self> {
+        Self::from_connection(inner, Connection::open_in_memory()?)
+    }
+
+    fn from_connection(inner: R, connection: Connection) -> anyhow::Result<Self> {
+        migrations().run(&connection, false)?;
+
+        Ok(Self {
+            inner,
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    async fn record_change(
+        &self,
+        name: &PackageIdentifier,
+        packument: &Packument,
+    ) -> anyhow::Result<()> {
+        let package = name.to_string();
+        let rev = packument.rev.clone();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO changes (package, rev) VALUES (?1, ?2)",
+                params![package, rev],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn with_connection<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let connection = connection.lock().unwrap_or_else(|e| e.into_inner());
+            Ok(f(&connection)?)
+        })
+        .await?
+    }
+}
+
+#[async_trait::async_trait]
+impl<R> PackageStorage for ChangeLog<R>
+where
+    R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static,
+    <R as PackageStorage>::Error: std::error::Error + Send + Sync + 'static,
+{
+    type Error = R::Error;
+
+    async fn fetch_packument(&self, name: &PackageIdentifier) -> anyhow::Result<Packument> {
+        self.inner.fetch_packument(name).await
+    }
+
+    async fn stream_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.inner.stream_packument(name).await
+    }
+
+    async fn resolve_version(
+        &self,
+        name: &PackageIdentifier,
+        spec: &str,
+    ) -> anyhow::Result<Option<PackumentVersion>> {
+        self.inner.resolve_version(name, spec).await
+    }
+
+    async fn stream_encoded_packument(
+        &self,
+        name: &PackageIdentifier,
+        abbreviated: bool,
+        encoding: ContentEncoding,
+    ) -> anyhow::Result<Option<BoxStream<'static, Result<Bytes, Self::Error>>>> {
+        self.inner
+            .stream_encoded_packument(name, abbreviated, encoding)
+            .await
+    }
+
+    async fn stream_abbreviated_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.inner.stream_abbreviated_packument(name).await
+    }
+
+    async fn stream_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.inner.stream_tarball(name, version).await
+    }
+
+    async fn packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        self.inner.packument_metadata(name).await
+    }
+
+    async fn abbreviated_packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        self.inner.abbreviated_packument_metadata(name).await
+    }
+
+    async fn tarball_metadata(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<ContentMetadata> {
+        self.inner.tarball_metadata(name, version).await
+    }
+
+    async fn stream_tarball_range(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        range: ByteRange,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.inner.stream_tarball_range(name, version, range).await
+    }
+
+    async fn put_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &Packument,
+    ) -> anyhow::Result<()> {
+        self.inner.put_packument(name, packument).await?;
+
+        self.record_change(name, packument).await
+    }
+
+    async fn update_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &mut Packument,
+    ) -> anyhow::Result<()> {
+        self.inner.update_packument(name, packument).await?;
+
+        self.record_change(name, packument).await
+    }
+
+    async fn put_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: Bytes,
+    ) -> anyhow::Result<()> {
+        self.inner.put_tarball(name, version, tarball).await
+    }
+
+    async fn delete_tarball(&self, name: &PackageIdentifier, version: &str) -> anyhow::Result<()> {
+        self.inner.delete_tarball(name, version).await
+    }
+
+    async fn put_tarball_stream(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: BoxStream<'static, Result<Bytes, std::io::Error>>,
+    ) -> anyhow::Result<()> {
+        self.inner.put_tarball_stream(name, version, tarball).await
+    }
+
+    // Logged with no revision: there's no document left for a follower to fetch.
+    async fn delete_package(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        self.inner.delete_package(name).await?;
+        let package = name.to_string();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO changes (package, rev) VALUES (?1, NULL)",
+                params![package],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn invalidate(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        self.inner.invalidate(name).await
+    }
+
+    async fn invalidate_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<()> {
+        self.inner.invalidate_tarball(name, version).await
+    }
+
+    async fn collect_garbage(&self) -> anyhow::Result<GarbageCollection> {
+        self.inner.collect_garbage().await
+    }
+
+    async fn tarball_stats(&self) -> anyhow::Result<TarballStats> {
+        self.inner.tarball_stats().await
+    }
+
+    async fn fetch_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<serde_json::Value> {
+        self.inner.fetch_attestations(name, version).await
+    }
+
+    async fn put_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        attestations: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        self.inner
+            .put_attestations(name, version, attestations)
+            .await
+    }
+
+    async fn list_packages(&self) -> anyhow::Result<Vec<PackageIdentifier>> {
+        self.inner.list_packages().await
+    }
+
+    async fn changes_since(&self, since: u64, limit: usize) -> anyhow::Result<Vec<PackageChange>> {
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT seq, package, rev FROM changes WHERE seq > ?1 ORDER BY seq LIMIT ?2",
+            )?;
+            let changes = statement
+                .query_map(params![since, limit], |row| {
+                    Ok(PackageChange {
+                        seq: row.get(0)?,
+                        id: row.get(1)?,
+                        rev: row.get(2)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(changes)
+        })
+        .await
+    }
+
+    async fn starred_by(&self, username: &str) -> anyhow::Result<Vec<String>> {
+        self.inner.starred_by(username).await
+    }
+
+    async fn search(&self, query: &SearchQuery) -> anyhow::Result<serde_json::Value> {
+        self.inner.search(query).await
+    }
+
+    async fn public_keys(&self) -> anyhow::Result<Vec<PublicKey>> {
+        self.inner.public_keys().await
+    }
+}
@@ -0,0 +1,396 @@
+//! Packages kept as plain files, for small installs that would rather be able to read (and
+//! back up) their storage with ordinary tools than run cacache or an external service:
+//!
+//! ```text
+//! <root>/left-pad/packument.json
+//! <root>/left-pad/1.3.0.tgz
+//! <root>/left-pad/1.3.0.attestations.json
+//! <root>/@corp/app/packument.json
+//! ```
+//!
+//! Files are written to a temporary name and renamed into place, so a reader sees either the
+//! old document or the new one.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+use futures_util::StreamExt;
+use tokio::sync::Mutex;
+
+use super::{put_if_unchanged, StorageError};
+use crate::models::{PackageIdentifier, Packument};
+use crate::policies::PackageStorage;
+
+const PACKUMENT: &str = "packument.json";
+
+#[derive(Clone, Debug)]
+pub struct Directory {
+    root: PathBuf,
+    // Held across the check and write of an update, so two can't both pass the check.
+    updates: Arc<Mutex<()>>,
+}
+
+// Names and versions come from request paths; none of them may climb out of the root.
+fn component(part: &str) -> anyhow::Result<&str> {
+    if part.is_empty() || part.starts_with('.') || part.contains(['/', '\\', '\0']) {
+        return Err(StorageError::NotFound.into());
+    }
+    Ok(part)
+}
+
+fn not_found_or_io(error: std::io::Error) -> anyhow::Error {
+    if error.kind() == std::io::ErrorKind::NotFound {
+        StorageError::NotFound.into()
+    } else {
+        StorageError::Io(error).into()
+    }
+}
+
+impl Directory {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: PathBuf::from(root.as_ref()),
+            updates: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn package_dir(&self, name: &PackageIdentifier) -> anyhow::Result<PathBuf> {
+        let mut dir = self.root.clone();
+        if let Some(scope) = &name.scope {
+            dir.push(format!("@{}", component(scope)?));
+        }
+        dir.push(component(name.name.as_str())?);
+        Ok(dir)
+    }
+
+    fn version_file(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        extension: &str,
+    ) -> anyhow::Result<PathBuf> {
+        let file = format!("{}.{}", component(version)?, extension);
+        Ok(self.package_dir(name)?.join(file))
+    }
+
+    async fn stream_file(
+        &self,
+        path: PathBuf,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, std::io::Error>>> {
+        let file = tokio::fs::File::open(path).await.map_err(not_found_or_io)?;
+        Ok(tokio_util::io::ReaderStream::new(file).boxed())
+    }
+
+    async fn write_file(&self, path: PathBuf, data: &[u8]) -> anyhow::Result<()> {
+        let data = Bytes::copy_from_slice(data);
+        self.write_stream(path, futures::stream::once(async move { Ok(data) }).boxed())
+            .await
+    }
+
+    // A stream that fails partway leaves its partial file behind to be removed, not renamed.
+    async fn write_stream(
+        &self,
+        path: PathBuf,
+        mut data: BoxStream<'static, Result<Bytes, std::io::Error>>,
+    ) -> anyhow::Result<()> {
+        use tokio::io::AsyncWriteExt;
+        let dir = path.parent().unwrap_or(self.root.as_path());
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(StorageError::from)?;
+
+        let mut partial = path.clone().into_os_string();
+        partial.push(format!(".{}.partial", uuid::Uuid::new_v4()));
+        let written = async {
+            let mut file = tokio::fs::File::create(&partial).await?;
+            while let Some(chunk) = data.next().await {
+                file.write_all(chunk?.as_ref()).await?;
+            }
+            file.flush().await
+        }
+        .await;
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(StorageError::Io(e).into());
+        }
+        tokio::fs::rename(&partial, &path)
+            .await
+            .map_err(StorageError::from)?;
+        Ok(())
+    }
+
+    async fn remove_file(&self, path: PathBuf) -> anyhow::Result<()> {
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(StorageError::Io(e).into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PackageStorage for Directory {
+    type Error = std::io::Error;
+
+    async fn stream_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.stream_file(self.package_dir(name)?.join(PACKUMENT))
+            .await
+    }
+
+    async fn stream_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.stream_file(self.version_file(name, version, "tgz")?)
+            .await
+    }
+
+    async fn put_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &Packument,
+    ) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(packument)?;
+        self.write_file(self.package_dir(name)?.join(PACKUMENT), data.as_slice())
+            .await
+    }
+
+    async fn update_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &mut Packument,
+    ) -> anyhow::Result<()> {
+        let _update = self.updates.lock().await;
+        put_if_unchanged(self, name, packument).await
+    }
+
+    async fn put_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: Bytes,
+    ) -> anyhow::Result<()> {
+        self.write_file(self.version_file(name, version, "tgz")?, tarball.as_ref())
+            .await
+    }
+
+    async fn delete_tarball(&self, name: &PackageIdentifier, version: &str) -> anyhow::Result<()> {
+        self.remove_file(self.version_file(name, version, "tgz")?)
+            .await?;
+        self.remove_file(self.version_file(name, version, "attestations.json")?)
+            .await
+    }
+
+    async fn put_tarball_stream(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: BoxStream<'static, Result<Bytes, std::io::Error>>,
+    ) -> anyhow::Result<()> {
+        self.write_stream(self.version_file(name, version, "tgz")?, tarball)
+            .await
+    }
+
+    // Everything of a package is in its directory. Its scope's directory is left, even empty.
+    async fn delete_package(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        let _update = self.updates.lock().await;
+        match tokio::fs::remove_dir_all(self.package_dir(name)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(StorageError::Io(e).into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn fetch_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<serde_json::Value> {
+        let path = self.version_file(name, version, "attestations.json")?;
+        let data = tokio::fs::read(path).await.map_err(not_found_or_io)?;
+        Ok(serde_json::from_slice(data.as_slice())?)
+    }
+
+    async fn put_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        attestations: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(attestations)?;
+        let path = self.version_file(name, version, "attestations.json")?;
+        self.write_file(path, data.as_slice()).await
+    }
+
+    // Any directory holding a packument is a package; a scope is a directory of them.
+    async fn list_packages(&self) -> anyhow::Result<Vec<PackageIdentifier>> {
+        let root = self.root.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut names = Vec::new();
+            let entries = match std::fs::read_dir(&root) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(names),
+                Err(e) => return Err(e.into()),
+            };
+
+            for entry in entries {
+                let entry = entry?;
+                let file_name = entry.file_name();
+                let Some(dir_name) = file_name.to_str() else {
+                    continue;
+                };
+
+                if dir_name.starts_with('@') {
+                    for scoped in std::fs::read_dir(entry.path())? {
+                        let scoped = scoped?;
+                        if scoped.path().join(PACKUMENT).is_file() {
+                            if let Some(name) = scoped.file_name().to_str() {
+                                names.push(format!("{}/{}", dir_name, name).parse()?);
+                            }
+                        }
+                    }
+                } else if entry.path().join(PACKUMENT).is_file() {
+                    names.push(dir_name.parse()?);
+                }
+            }
+
+            names.sort_by_key(|name: &PackageIdentifier| name.to_string());
+            Ok(names)
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policies::package_storage::{collect_stream, TestDir};
+
+    #[tokio::test]
+    async fn test_directory_round_trip() {
+        let root = TestDir::new("registry-directory");
+        let storage = Directory::new(&root);
+        let name: PackageIdentifier = "@corp/app".parse().unwrap();
+        let mut packument: Packument =
+            serde_json::from_value(serde_json::json!({ "name": "@corp/app" })).unwrap();
+
+        storage
+            .update_packument(&name, &mut packument)
+            .await
+            .unwrap();
+        storage
+            .put_tarball(&name, "1.0.0", Bytes::from_static(b"tarball"))
+            .await
+            .unwrap();
+        assert!(root.join("@corp/app/packument.json").is_file());
+        assert_eq!(storage.fetch_packument(&name).await.unwrap(), packument);
+        assert_eq!(
+            collect_stream(storage.stream_tarball(&name, "1.0.0").await.unwrap())
+                .await
+                .unwrap(),
+            b"tarball"
+        );
+        let listed = storage.list_packages().await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].to_string(), "@corp/app");
+
+        let mut stale: Packument =
+            serde_json::from_value(serde_json::json!({ "name": "@corp/app" })).unwrap();
+        assert!(matches!(
+            StorageError::of(
+                &storage
+                    .update_packument(&name, &mut stale)
+                    .await
+                    .unwrap_err()
+            ),
+            Some(StorageError::Conflict)
+        ));
+
+        storage.delete_tarball(&name, "1.0.0").await.unwrap();
+        let missing = storage.stream_tarball(&name, "1.0.0").await.err().unwrap();
+        assert!(matches!(
+            StorageError::of(&missing),
+            Some(StorageError::NotFound)
+        ));
+
+        let escape: PackageIdentifier = "..".parse().unwrap();
+        assert!(storage.stream_packument(&escape).await.is_err());
+        assert!(storage.stream_tarball(&name, "../../x").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_directory_deletes() {
+        let root = TestDir::new("registry-directory");
+        let storage = Directory::new(&root);
+        let name: PackageIdentifier = "left-pad".parse().unwrap();
+        let mut packument: Packument = serde_json::from_value(serde_json::json!({
+            "name": "left-pad",
+            "dist-tags": { "latest": "1.0.1" },
+            "versions": {
+                "1.0.0": {
+                    "_id": "left-pad@1.0.0",
+                    "name": "left-pad",
+                    "version": "1.0.0",
+                    "dist": { "tarball": "", "shasum": "" }
+                },
+                "1.0.1": {
+                    "_id": "left-pad@1.0.1",
+                    "name": "left-pad",
+                    "version": "1.0.1",
+                    "dist": { "tarball": "", "shasum": "" }
+                }
+            }
+        }))
+        .unwrap();
+        storage
+            .update_packument(&name, &mut packument)
+            .await
+            .unwrap();
+        for version in ["1.0.0", "1.0.1"] {
+            let chunks = futures::stream::iter([
+                Ok(Bytes::from_static(b"tar")),
+                Ok(Bytes::from_static(b"ball")),
+            ]);
+            storage
+                .put_tarball_stream(&name, version, chunks.boxed())
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            collect_stream(storage.stream_tarball(&name, "1.0.1").await.unwrap())
+                .await
+                .unwrap(),
+            b"tarball"
+        );
+
+        // A body cut off partway stores nothing, and leaves nothing behind.
+        let broken = futures::stream::iter([
+            Ok(Bytes::from_static(b"tar")),
+            Err(std::io::Error::other("connection reset")),
+        ]);
+        assert!(storage
+            .put_tarball_stream(&name, "1.0.2", broken.boxed())
+            .await
+            .is_err());
+        assert!(storage.stream_tarball(&name, "1.0.2").await.is_err());
+        assert_eq!(std::fs::read_dir(root.join("left-pad")).unwrap().count(), 3);
+
+        storage.delete_version(&name, "1.0.0").await.unwrap();
+        let packument = storage.fetch_packument(&name).await.unwrap();
+        let versions = packument.versions.unwrap();
+        assert_eq!(versions.keys().collect::<Vec<_>>(), ["1.0.1"]);
+        assert!(storage.stream_tarball(&name, "1.0.0").await.is_err());
+
+        storage.delete_package(&name).await.unwrap();
+        assert!(!root.join("left-pad").exists());
+        assert!(matches!(
+            StorageError::of(&storage.fetch_packument(&name).await.unwrap_err()),
+            Some(StorageError::NotFound)
+        ));
+        storage.delete_package(&name).await.unwrap();
+    }
+}
@@ -0,0 +1,406 @@
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+use futures_util::{StreamExt, TryStreamExt};
+
+use crate::models::{PackageIdentifier, Packument, PackumentVersion};
+use crate::policies::PackageStorage;
+use crate::signing::PublicKey;
+
+use super::{
+    ByteRange, ContentEncoding, ContentMetadata, GarbageCollection, PackageChange, SearchQuery,
+    StorageError, TarballStats,
+};
+
+/// Serves packages from `first`, and those it doesn't have from `second`; nest them to layer
+/// more, as in internal publishes, then a corporate mirror, then the public registry.
+///
+/// Only a package `first` reports missing is looked for in `second`. Any other failure is
+/// returned as it is, so that an internal storage that's down never quietly hands out a
+/// public package of the same name. A package is served whole from whichever storage has
+/// it, down to its tarballs and attestations; the two are never merged. Writes all go to `first`, so a package held only by
+/// `second` can't be published to here.
+#[derive(Clone, Debug)]
+pub struct FallbackStorage<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: PackageStorage, B: PackageStorage> FallbackStorage<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+
+    // Versions are looked for only where their package is: a tarball missing from the first
+    // storage must not be filled in with the second's of the same name.
+    async fn first_has(&self, name: &PackageIdentifier) -> anyhow::Result<bool> {
+        match self.first.packument_metadata(name).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_missing(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn is_missing(error: &anyhow::Error) -> bool {
+    matches!(StorageError::of(error), Some(StorageError::NotFound))
+}
+
+async fn either<T>(
+    first: impl std::future::Future<Output = anyhow::Result<T>>,
+    second: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    match first.await {
+        Err(e) if is_missing(&e) => second.await,
+        result => result,
+    }
+}
+
+fn boxed<E>(
+    stream: BoxStream<'static, Result<Bytes, E>>,
+) -> BoxStream<'static, Result<Bytes, std::io::Error>>
+where
+    E: Into<axum::BoxError> + Send + 'static,
+{
+    stream.map_err(|e| std::io::Error::other(e.into())).boxed()
+}
+
+#[async_trait::async_trait]
+impl<A: PackageStorage, B: PackageStorage> PackageStorage for FallbackStorage<A, B> {
+    type Error = std::io::Error;
+
+    async fn stream_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        either(
+            async { Ok(boxed(self.first.stream_packument(name).await?)) },
+            async { Ok(boxed(self.second.stream_packument(name).await?)) },
+        )
+        .await
+    }
+
+    async fn resolve_version(
+        &self,
+        name: &PackageIdentifier,
+        spec: &str,
+    ) -> anyhow::Result<Option<PackumentVersion>> {
+        either(
+            self.first.resolve_version(name, spec),
+            self.second.resolve_version(name, spec),
+        )
+        .await
+    }
+
+    async fn stream_abbreviated_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        either(
+            async { Ok(boxed(self.first.stream_abbreviated_packument(name).await?)) },
+            async { Ok(boxed(self.second.stream_abbreviated_packument(name).await?)) },
+        )
+        .await
+    }
+
+    // A `None` from the storage that has the package stands; the other's copy would be a
+    // different document.
+    async fn stream_encoded_packument(
+        &self,
+        name: &PackageIdentifier,
+        abbreviated: bool,
+        encoding: ContentEncoding,
+    ) -> anyhow::Result<Option<BoxStream<'static, Result<Bytes, Self::Error>>>> {
+        either(
+            async {
+                let stream = self
+                    .first
+                    .stream_encoded_packument(name, abbreviated, encoding)
+                    .await?;
+                Ok(stream.map(boxed))
+            },
+            async {
+                let stream = self
+                    .second
+                    .stream_encoded_packument(name, abbreviated, encoding)
+                    .await?;
+                Ok(stream.map(boxed))
+            },
+        )
+        .await
+    }
+
+    async fn stream_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        if self.first_has(name).await? {
+            Ok(boxed(self.first.stream_tarball(name, version).await?))
+        } else {
+            Ok(boxed(self.second.stream_tarball(name, version).await?))
+        }
+    }
+
+    async fn packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        either(
+            self.first.packument_metadata(name),
+            self.second.packument_metadata(name),
+        )
+        .await
+    }
+
+    async fn abbreviated_packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        either(
+            self.first.abbreviated_packument_metadata(name),
+            self.second.abbreviated_packument_metadata(name),
+        )
+        .await
+    }
+
+    async fn tarball_metadata(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<ContentMetadata> {
+        if self.first_has(name).await? {
+            self.first.tarball_metadata(name, version).await
+        } else {
+            self.second.tarball_metadata(name, version).await
+        }
+    }
+
+    async fn stream_tarball_range(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        range: ByteRange,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        if self.first_has(name).await? {
+            let stream = self
+                .first
+                .stream_tarball_range(name, version, range)
+                .await?;
+            Ok(boxed(stream))
+        } else {
+            let stream = self
+                .second
+                .stream_tarball_range(name, version, range)
+                .await?;
+            Ok(boxed(stream))
+        }
+    }
+
+    async fn put_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &Packument,
+    ) -> anyhow::Result<()> {
+        self.first.put_packument(name, packument).await
+    }
+
+    async fn update_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &mut Packument,
+    ) -> anyhow::Result<()> {
+        self.first.update_packument(name, packument).await
+    }
+
+    async fn put_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: Bytes,
+    ) -> anyhow::Result<()> {
+        self.first.put_tarball(name, version, tarball).await
+    }
+
+    async fn delete_tarball(&self, name: &PackageIdentifier, version: &str) -> anyhow::Result<()> {
+        self.first.delete_tarball(name, version).await
+    }
+
+    async fn put_tarball_stream(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: BoxStream<'static, Result<Bytes, std::io::Error>>,
+    ) -> anyhow::Result<()> {
+        self.first.put_tarball_stream(name, version, tarball).await
+    }
+
+    // The second storage is a read-only upstream; only the first holds anything to delete.
+    async fn delete_package(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        self.first.delete_package(name).await?;
+        self.second.invalidate(name).await
+    }
+
+    async fn invalidate(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        self.first.invalidate(name).await?;
+        self.second.invalidate(name).await
+    }
+
+    async fn invalidate_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<()> {
+        self.first.invalidate_tarball(name, version).await?;
+        self.second.invalidate_tarball(name, version).await
+    }
+
+    async fn collect_garbage(&self) -> anyhow::Result<GarbageCollection> {
+        let mut collected = self.first.collect_garbage().await?;
+        collected += self.second.collect_garbage().await?;
+        Ok(collected)
+    }
+
+    // Content isn't shared between the two, so neither is what it saves.
+    async fn tarball_stats(&self) -> anyhow::Result<TarballStats> {
+        let mut stats = self.first.tarball_stats().await?;
+        stats += self.second.tarball_stats().await?;
+        Ok(stats)
+    }
+
+    async fn fetch_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<serde_json::Value> {
+        if self.first_has(name).await? {
+            self.first.fetch_attestations(name, version).await
+        } else {
+            self.second.fetch_attestations(name, version).await
+        }
+    }
+
+    async fn put_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        attestations: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        self.first
+            .put_attestations(name, version, attestations)
+            .await
+    }
+
+    // A storage that can't list its packages, as an upstream registry can't, adds none.
+    async fn list_packages(&self) -> anyhow::Result<Vec<PackageIdentifier>> {
+        let (first, second) =
+            futures::join!(self.first.list_packages(), self.second.list_packages());
+        if let (Err(e), Err(_)) = (&first, &second) {
+            return Err(anyhow::anyhow!("neither storage can list packages: {}", e));
+        }
+
+        let mut names = first.unwrap_or_default();
+        let listed: std::collections::HashSet<String> =
+            names.iter().map(|name| name.to_string()).collect();
+        names.extend(
+            second
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|name| !listed.contains(&name.to_string())),
+        );
+        Ok(names)
+    }
+
+    // Sequence numbers are only meaningful within one storage; writes all land in the first.
+    async fn changes_since(&self, since: u64, limit: usize) -> anyhow::Result<Vec<PackageChange>> {
+        self.first.changes_since(since, limit).await
+    }
+
+    // Results aren't merged: paging through two result sets at once can't be done faithfully.
+    async fn search(&self, query: &SearchQuery) -> anyhow::Result<serde_json::Value> {
+        match self.first.search(query).await {
+            Ok(results) if results["total"].as_u64().unwrap_or_default() > 0 => Ok(results),
+            _ => self.second.search(query).await,
+        }
+    }
+
+    async fn public_keys(&self) -> anyhow::Result<Vec<PublicKey>> {
+        let mut keys = self.first.public_keys().await?;
+        keys.extend(self.second.public_keys().await?);
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policies::package_storage::collect_stream;
+    use crate::policies::package_storage::in_memory::InMemoryPackageStorage;
+
+    fn packument(name: &str, description: &str) -> Packument {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "description": description
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_fallback_storage() {
+        let internal = InMemoryPackageStorage::new();
+        let public = InMemoryPackageStorage::new();
+        let storage = FallbackStorage::new(internal.clone(), public.clone());
+        let shadowed: PackageIdentifier = "utils".parse().unwrap();
+        let left_pad: PackageIdentifier = "left-pad".parse().unwrap();
+
+        internal
+            .put_packument(&shadowed, &packument("utils", "internal"))
+            .await
+            .unwrap();
+        public
+            .put_packument(&shadowed, &packument("utils", "public"))
+            .await
+            .unwrap();
+        public
+            .put_packument(&left_pad, &packument("left-pad", "public"))
+            .await
+            .unwrap();
+        public
+            .put_tarball(&left_pad, "1.0.0", Bytes::from_static(b"tarball"))
+            .await
+            .unwrap();
+        public
+            .put_tarball(&shadowed, "1.0.0", Bytes::from_static(b"public"))
+            .await
+            .unwrap();
+
+        let utils = storage.fetch_packument(&shadowed).await.unwrap();
+        assert_eq!(utils.description.as_deref(), Some("internal"));
+        let pad = storage.fetch_packument(&left_pad).await.unwrap();
+        assert_eq!(pad.description.as_deref(), Some("public"));
+        let tarball = storage.stream_tarball(&left_pad, "1.0.0").await.unwrap();
+        assert_eq!(collect_stream(tarball).await.unwrap(), b"tarball");
+
+        let shadowed_tarball = storage.stream_tarball(&shadowed, "1.0.0").await;
+        assert!(is_missing(&shadowed_tarball.err().unwrap()));
+
+        let missing: PackageIdentifier = "missing".parse().unwrap();
+        let e = storage.fetch_packument(&missing).await.unwrap_err();
+        assert!(is_missing(&e));
+
+        let names: Vec<String> = storage
+            .list_packages()
+            .await
+            .unwrap()
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        assert_eq!(names, vec!["utils", "left-pad"]);
+
+        storage
+            .put_tarball(&shadowed, "1.0.0", Bytes::from_static(b"internal"))
+            .await
+            .unwrap();
+        let tarball = storage.stream_tarball(&shadowed, "1.0.0").await.unwrap();
+        assert_eq!(collect_stream(tarball).await.unwrap(), b"internal");
+    }
+}
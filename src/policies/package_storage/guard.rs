@@ -0,0 +1,376 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+
+use crate::models::{PackageIdentifier, Packument, PackumentVersion};
+use crate::policies::PackageStorage;
+use crate::signing::PublicKey;
+
+use super::{
+    ByteRange, ContentEncoding, ContentMetadata, GarbageCollection, PackageChange, SearchQuery,
+    StorageError, TarballStats,
+};
+
+/// What a [`GuardStorage`] does with a package.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Guard {
+    Allow,
+    /// Reads answer as though the package doesn't exist; writes are forbidden.
+    Deny,
+    /// Only requests that present a valid token may read or write it.
+    RequireAuth,
+}
+
+tokio::task_local! {
+    static AUTHENTICATED: bool;
+}
+
+/// Run `future` as a request that presented a valid token, letting it through
+/// [`Guard::RequireAuth`] packages. The auth middleware wraps every such request in this.
+pub(crate) async fn authenticated<F: Future>(future: F) -> F::Output {
+    AUTHENTICATED.scope(true, future).await
+}
+
+fn is_authenticated() -> bool {
+    AUTHENTICATED
+        .try_with(|authenticated| *authenticated)
+        .unwrap_or(false)
+}
+
+type Predicate = dyn Fn(&PackageIdentifier) -> Guard + Send + Sync;
+
+/// Consults a predicate on the package name before handing a request to the storage it
+/// wraps: denied packages are missing to readers and refused to publishers, and packages
+/// that require auth are refused to anonymous requests with a 403.
+///
+/// Listings, search results and the changes feed leave out whatever the caller couldn't
+/// read. Invalidation is passed through unchecked, so an admin can still purge a package
+/// after denying it.
+#[derive(Clone)]
+pub struct GuardStorage<R> {
+    inner: R,
+    predicate: Arc<Predicate>,
+}
+
+impl<R: std::fmt::Debug> std::fmt::Debug for GuardStorage<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GuardStorage")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: PackageStorage> GuardStorage<R> {
+    pub fn new(
+        inner: R,
+        predicate: impl Fn(&PackageIdentifier) -> Guard + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner,
+            predicate: Arc::new(predicate),
+        }
+    }
+
+    fn check_read(&self, name: &PackageIdentifier) -> Result<(), StorageError> {
+        match (self.predicate)(name) {
+            Guard::Allow => Ok(()),
+            Guard::Deny => Err(StorageError::NotFound),
+            Guard::RequireAuth if is_authenticated() => Ok(()),
+            Guard::RequireAuth => Err(StorageError::Forbidden),
+        }
+    }
+
+    fn check_write(&self, name: &PackageIdentifier) -> Result<(), StorageError> {
+        match self.check_read(name) {
+            Err(StorageError::NotFound) => Err(StorageError::Forbidden),
+            checked => checked,
+        }
+    }
+
+    fn readable(&self, name: &str) -> bool {
+        name.parse::<PackageIdentifier>()
+            .map(|name| self.check_read(&name).is_ok())
+            .unwrap_or(false)
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: PackageStorage> PackageStorage for GuardStorage<R> {
+    type Error = R::Error;
+
+    async fn stream_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.check_read(name)?;
+        self.inner.stream_packument(name).await
+    }
+
+    async fn resolve_version(
+        &self,
+        name: &PackageIdentifier,
+        spec: &str,
+    ) -> anyhow::Result<Option<PackumentVersion>> {
+        self.check_read(name)?;
+        self.inner.resolve_version(name, spec).await
+    }
+
+    async fn stream_abbreviated_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.check_read(name)?;
+        self.inner.stream_abbreviated_packument(name).await
+    }
+
+    async fn stream_encoded_packument(
+        &self,
+        name: &PackageIdentifier,
+        abbreviated: bool,
+        encoding: ContentEncoding,
+    ) -> anyhow::Result<Option<BoxStream<'static, Result<Bytes, Self::Error>>>> {
+        self.check_read(name)?;
+        self.inner
+            .stream_encoded_packument(name, abbreviated, encoding)
+            .await
+    }
+
+    async fn stream_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.check_read(name)?;
+        self.inner.stream_tarball(name, version).await
+    }
+
+    async fn packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        self.check_read(name)?;
+        self.inner.packument_metadata(name).await
+    }
+
+    async fn abbreviated_packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        self.check_read(name)?;
+        self.inner.abbreviated_packument_metadata(name).await
+    }
+
+    async fn tarball_metadata(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<ContentMetadata> {
+        self.check_read(name)?;
+        self.inner.tarball_metadata(name, version).await
+    }
+
+    async fn stream_tarball_range(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        range: ByteRange,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.check_read(name)?;
+        self.inner.stream_tarball_range(name, version, range).await
+    }
+
+    async fn put_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &Packument,
+    ) -> anyhow::Result<()> {
+        self.check_write(name)?;
+        self.inner.put_packument(name, packument).await
+    }
+
+    async fn update_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &mut Packument,
+    ) -> anyhow::Result<()> {
+        self.check_write(name)?;
+        self.inner.update_packument(name, packument).await
+    }
+
+    async fn put_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: Bytes,
+    ) -> anyhow::Result<()> {
+        self.check_write(name)?;
+        self.inner.put_tarball(name, version, tarball).await
+    }
+
+    async fn delete_tarball(&self, name: &PackageIdentifier, version: &str) -> anyhow::Result<()> {
+        self.check_write(name)?;
+        self.inner.delete_tarball(name, version).await
+    }
+
+    async fn put_tarball_stream(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: BoxStream<'static, Result<Bytes, std::io::Error>>,
+    ) -> anyhow::Result<()> {
+        self.check_write(name)?;
+        self.inner.put_tarball_stream(name, version, tarball).await
+    }
+
+    async fn delete_package(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        self.check_write(name)?;
+        self.inner.delete_package(name).await
+    }
+
+    async fn invalidate(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        self.inner.invalidate(name).await
+    }
+
+    async fn invalidate_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<()> {
+        self.inner.invalidate_tarball(name, version).await
+    }
+
+    async fn collect_garbage(&self) -> anyhow::Result<GarbageCollection> {
+        self.inner.collect_garbage().await
+    }
+
+    async fn tarball_stats(&self) -> anyhow::Result<TarballStats> {
+        self.inner.tarball_stats().await
+    }
+
+    async fn fetch_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<serde_json::Value> {
+        self.check_read(name)?;
+        self.inner.fetch_attestations(name, version).await
+    }
+
+    async fn put_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        attestations: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        self.check_write(name)?;
+        self.inner
+            .put_attestations(name, version, attestations)
+            .await
+    }
+
+    async fn list_packages(&self) -> anyhow::Result<Vec<PackageIdentifier>> {
+        let mut names = self.inner.list_packages().await?;
+        names.retain(|name| self.check_read(name).is_ok());
+        Ok(names)
+    }
+
+    async fn changes_since(&self, since: u64, limit: usize) -> anyhow::Result<Vec<PackageChange>> {
+        let mut changes = self.inner.changes_since(since, limit).await?;
+        changes.retain(|change| self.readable(change.id.as_str()));
+        Ok(changes)
+    }
+
+    async fn starred_by(&self, username: &str) -> anyhow::Result<Vec<String>> {
+        let mut starred = self.inner.starred_by(username).await?;
+        starred.retain(|name| self.readable(name.as_str()));
+        Ok(starred)
+    }
+
+    // The total drops by however many were left out of this page; the pages after it may
+    // still hold more, so it can be an overcount.
+    async fn search(&self, query: &SearchQuery) -> anyhow::Result<serde_json::Value> {
+        let mut results = self.inner.search(query).await?;
+        if let Some(objects) = results["objects"].as_array_mut() {
+            let before = objects.len();
+            objects.retain(|object| {
+                self.readable(object["package"]["name"].as_str().unwrap_or_default())
+            });
+            let hidden = (before - objects.len()) as u64;
+            if let Some(total) = results["total"].as_u64() {
+                results["total"] = total.saturating_sub(hidden).into();
+            }
+        }
+        Ok(results)
+    }
+
+    async fn public_keys(&self) -> anyhow::Result<Vec<PublicKey>> {
+        self.inner.public_keys().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policies::package_storage::in_memory::InMemoryPackageStorage;
+
+    fn packument(name: &str) -> Packument {
+        serde_json::from_value(serde_json::json!({ "name": name })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_guard_storage() {
+        let inner = InMemoryPackageStorage::new();
+        let storage = GuardStorage::new(inner.clone(), |name| match name.to_string().as_str() {
+            "evil" => Guard::Deny,
+            "internal" => Guard::RequireAuth,
+            _ => Guard::Allow,
+        });
+        for name in ["evil", "internal", "left-pad"] {
+            inner
+                .put_packument(&name.parse().unwrap(), &packument(name))
+                .await
+                .unwrap();
+        }
+        let evil: PackageIdentifier = "evil".parse().unwrap();
+        let internal: PackageIdentifier = "internal".parse().unwrap();
+        let left_pad: PackageIdentifier = "left-pad".parse().unwrap();
+
+        storage.fetch_packument(&left_pad).await.unwrap();
+        let e = storage.fetch_packument(&evil).await.unwrap_err();
+        assert!(matches!(StorageError::of(&e), Some(StorageError::NotFound)));
+        let e = storage
+            .put_packument(&evil, &packument("evil"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            StorageError::of(&e),
+            Some(StorageError::Forbidden)
+        ));
+        let e = storage.fetch_packument(&internal).await.unwrap_err();
+        assert!(matches!(
+            StorageError::of(&e),
+            Some(StorageError::Forbidden)
+        ));
+
+        let listed = |names: Vec<PackageIdentifier>| -> Vec<String> {
+            names.iter().map(|name| name.to_string()).collect()
+        };
+        assert_eq!(
+            listed(storage.list_packages().await.unwrap()),
+            vec!["left-pad"]
+        );
+
+        authenticated(async {
+            storage.fetch_packument(&internal).await.unwrap();
+            assert_eq!(
+                listed(storage.list_packages().await.unwrap()),
+                vec!["internal", "left-pad"]
+            );
+            let e = storage.fetch_packument(&evil).await.unwrap_err();
+            assert!(matches!(StorageError::of(&e), Some(StorageError::NotFound)));
+        })
+        .await;
+    }
+}
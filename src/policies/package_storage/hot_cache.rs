@@ -0,0 +1,468 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::models::{PackageIdentifier, Packument, PackumentVersion};
+use crate::policies::PackageStorage;
+use crate::signing::PublicKey;
+use crate::tasks::TaskRegistry;
+
+use super::{
+    ByteRange, ContentEncoding, ContentMetadata, GarbageCollection, PackageChange, SearchQuery,
+    TarballStats,
+};
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+use futures_util::{StreamExt, TryStreamExt};
+use hashlink::LruCache;
+
+#[derive(Clone, Debug)]
+struct HotEntry {
+    packument: Bytes,
+    // Of `packument` itself, so that its tag always labels the body served with it.
+    metadata: ContentMetadata,
+    fetched: Instant,
+}
+
+/// Keeps the most recently requested packuments in memory in front of another storage,
+/// dropping the least recently requested to make room.
+///
+/// The hottest keys can be written out at shutdown with [`HotCache::save_index`] and loaded
+/// back with [`HotCache::prewarm`], so a restart doesn't send a burst of cold misses at the
+/// inner storage (and, through it, the upstream.)
+#[derive(Clone)]
+pub struct HotCache<R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static> {
+    inner: R,
+    capacity: usize,
+    max_age: Option<Duration>,
+    entries: Arc<Mutex<LruCache<String, HotEntry>>>,
+}
+
+impl<R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static> std::fmt::Debug
+    for HotCache<R>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut formatter = f.debug_struct("HotCache");
+        formatter.field("inner", &self.inner);
+        formatter.field("capacity", &self.capacity);
+        formatter.field("max_age", &self.max_age);
+        if let Ok(entries) = self.entries.try_lock() {
+            formatter.field("entries", &entries.len());
+        }
+        formatter.finish()
+    }
+}
+
+impl<R> HotCache<R>
+where
+    R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static,
+    <R as PackageStorage>::Error: std::error::Error + Send + Sync + 'static,
+{
+    pub fn new(inner: R, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            max_age: None,
+            entries: Arc::new(Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    /// Fetch packuments held longer than `max_age` from the inner storage again, so that one
+    /// which revalidates its own copies gets the chance to. `None`, the default, holds each
+    /// until it's written over or pushed out.
+    pub fn with_max_age(mut self, max_age: Option<Duration>) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    fn is_fresh(&self, entry: &HotEntry) -> bool {
+        self.max_age
+            .is_none_or(|max_age| entry.fetched.elapsed() <= max_age)
+    }
+
+    // A fresh entry, now the most recently used.
+    fn hit(&self, key: &str) -> Option<HotEntry> {
+        let mut entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|entry| self.is_fresh(entry))
+            .cloned()
+    }
+
+    fn peek(&self, key: &str) -> Option<ContentMetadata> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.peek(key).filter(|entry| self.is_fresh(entry))?;
+        Some(entry.metadata.clone())
+    }
+
+    fn insert(&self, key: String, packument: Bytes) -> anyhow::Result<HotEntry> {
+        let entry = HotEntry {
+            metadata: ContentMetadata::of(packument.as_ref())?,
+            packument,
+            fetched: Instant::now(),
+        };
+        self.entries.lock().unwrap().insert(key, entry.clone());
+        Ok(entry)
+    }
+
+    /// Drop `name`'s packument, so that it's next fetched from the inner storage.
+    pub fn forget(&self, name: &PackageIdentifier) {
+        self.entries.lock().unwrap().remove(&name.to_string());
+    }
+
+    /// Forget each package `refills` names until `tasks` shuts down: the inner storage has a
+    /// newer copy than the one held here. Having missed some, everything is forgotten.
+    pub async fn forget_refilled(
+        self,
+        mut refills: tokio::sync::broadcast::Receiver<PackageIdentifier>,
+        tasks: TaskRegistry,
+    ) -> anyhow::Result<()> {
+        use tokio::sync::broadcast::error::RecvError;
+        loop {
+            let refilled = tokio::select! {
+                refilled = refills.recv() => refilled,
+                _ = tasks.cancelled() => return Ok(()),
+            };
+            match refilled {
+                Ok(name) => self.forget(&name),
+                Err(RecvError::Lagged(_)) => self.entries.lock().unwrap().clear(),
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+
+    /// Write the names of the `limit` most recently used packuments to `path`, most recent
+    /// first.
+    pub async fn save_index(&self, path: impl AsRef<Path>, limit: usize) -> anyhow::Result<()> {
+        let keys: Vec<String> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .take(limit)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        // A list of names, written once at shutdown; not worth a trip to a blocking pool.
+        std::fs::write(path, serde_json::to_vec(&keys)?)?;
+        Ok(())
+    }
+
+    /// Load an index written by [`HotCache::save_index`], fetching each packument into
+    /// memory. Returns the number of packuments warmed.
+    pub async fn prewarm(&self, path: impl AsRef<Path>) -> anyhow::Result<usize> {
+        let keys: Vec<String> = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(data.as_slice())?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let fetched: Vec<_> = futures::stream::iter(keys)
+            .map(|key| async move {
+                let name: PackageIdentifier = key.parse().ok()?;
+                let packument = self.fetch_from_inner(&name).await.ok()?;
+                Some((key, packument))
+            })
+            .buffered(8)
+            .filter_map(|fetched| async move { fetched })
+            .collect()
+            .await;
+
+        // Least recent first, so the warmed entries keep their order.
+        let warmed = fetched.len();
+        for (key, packument) in fetched.into_iter().rev() {
+            self.insert(key, packument)?;
+        }
+        Ok(warmed)
+    }
+
+    async fn fetch_from_inner(&self, name: &PackageIdentifier) -> anyhow::Result<Bytes> {
+        let chunks: Vec<Bytes> = self
+            .inner
+            .stream_packument(name)
+            .await?
+            .try_collect()
+            .await?;
+        Ok(Bytes::from(chunks.concat()))
+    }
+}
+
+#[async_trait::async_trait]
+impl<R> PackageStorage for HotCache<R>
+where
+    R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static,
+    <R as PackageStorage>::Error: std::error::Error + Send + Sync + 'static,
+{
+    type Error = R::Error;
+
+    async fn stream_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        let key = name.to_string();
+        let entry = match self.hit(key.as_str()) {
+            Some(entry) => entry,
+            None => {
+                let packument = self.fetch_from_inner(name).await?;
+                self.insert(key, packument)?
+            }
+        };
+
+        let packument = entry.packument;
+        Ok(futures::stream::once(async move { Ok(packument) }).boxed())
+    }
+
+    async fn resolve_version(
+        &self,
+        name: &PackageIdentifier,
+        spec: &str,
+    ) -> anyhow::Result<Option<PackumentVersion>> {
+        self.inner.resolve_version(name, spec).await
+    }
+
+    // Only the uncompressed documents are held in memory; the inner storage's compressed
+    // copies are smaller to read than it is to compress a hot one again.
+    async fn stream_encoded_packument(
+        &self,
+        name: &PackageIdentifier,
+        abbreviated: bool,
+        encoding: ContentEncoding,
+    ) -> anyhow::Result<Option<BoxStream<'static, Result<Bytes, Self::Error>>>> {
+        self.inner
+            .stream_encoded_packument(name, abbreviated, encoding)
+            .await
+    }
+
+    async fn stream_abbreviated_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.inner.stream_abbreviated_packument(name).await
+    }
+
+    async fn stream_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.inner.stream_tarball(name, version).await
+    }
+
+    // Taken from the entry on a hit, so that the tag sent with a body held here is that body's.
+    async fn packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        match self.peek(name.to_string().as_str()) {
+            Some(metadata) => Ok(metadata),
+            None => self.inner.packument_metadata(name).await,
+        }
+    }
+
+    async fn abbreviated_packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        self.inner.abbreviated_packument_metadata(name).await
+    }
+
+    async fn tarball_metadata(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<ContentMetadata> {
+        self.inner.tarball_metadata(name, version).await
+    }
+
+    async fn stream_tarball_range(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        range: ByteRange,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.inner.stream_tarball_range(name, version, range).await
+    }
+
+    async fn put_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &Packument,
+    ) -> anyhow::Result<()> {
+        self.entries.lock().unwrap().remove(&name.to_string());
+        self.inner.put_packument(name, packument).await
+    }
+
+    async fn update_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &mut Packument,
+    ) -> anyhow::Result<()> {
+        self.entries.lock().unwrap().remove(&name.to_string());
+        self.inner.update_packument(name, packument).await
+    }
+
+    async fn put_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: Bytes,
+    ) -> anyhow::Result<()> {
+        self.inner.put_tarball(name, version, tarball).await
+    }
+
+    async fn delete_tarball(&self, name: &PackageIdentifier, version: &str) -> anyhow::Result<()> {
+        self.inner.delete_tarball(name, version).await
+    }
+
+    async fn put_tarball_stream(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: BoxStream<'static, Result<Bytes, std::io::Error>>,
+    ) -> anyhow::Result<()> {
+        self.inner.put_tarball_stream(name, version, tarball).await
+    }
+
+    async fn delete_package(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        self.inner.delete_package(name).await?;
+        self.entries.lock().unwrap().remove(&name.to_string());
+        Ok(())
+    }
+
+    async fn invalidate(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        self.inner.invalidate(name).await?;
+        self.entries.lock().unwrap().remove(&name.to_string());
+        Ok(())
+    }
+
+    async fn invalidate_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<()> {
+        self.inner.invalidate_tarball(name, version).await
+    }
+
+    async fn collect_garbage(&self) -> anyhow::Result<GarbageCollection> {
+        self.inner.collect_garbage().await
+    }
+
+    async fn tarball_stats(&self) -> anyhow::Result<TarballStats> {
+        self.inner.tarball_stats().await
+    }
+
+    async fn fetch_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<serde_json::Value> {
+        self.inner.fetch_attestations(name, version).await
+    }
+
+    async fn put_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        attestations: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        self.inner
+            .put_attestations(name, version, attestations)
+            .await
+    }
+
+    async fn list_packages(&self) -> anyhow::Result<Vec<PackageIdentifier>> {
+        self.inner.list_packages().await
+    }
+
+    async fn changes_since(&self, since: u64, limit: usize) -> anyhow::Result<Vec<PackageChange>> {
+        self.inner.changes_since(since, limit).await
+    }
+
+    async fn starred_by(&self, username: &str) -> anyhow::Result<Vec<String>> {
+        self.inner.starred_by(username).await
+    }
+
+    async fn search(&self, query: &SearchQuery) -> anyhow::Result<serde_json::Value> {
+        self.inner.search(query).await
+    }
+
+    async fn public_keys(&self) -> anyhow::Result<Vec<PublicKey>> {
+        self.inner.public_keys().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policies::package_storage::in_memory::InMemoryPackageStorage;
+    use crate::policies::package_storage::TestDir;
+
+    async fn storage(names: &[&str]) -> InMemoryPackageStorage {
+        let storage = InMemoryPackageStorage::new();
+        for name in names {
+            let packument: Packument =
+                serde_json::from_value(serde_json::json!({ "name": name })).unwrap();
+            storage
+                .put_packument(&name.parse().unwrap(), &packument)
+                .await
+                .unwrap();
+        }
+        storage
+    }
+
+    async fn read(cache: &HotCache<InMemoryPackageStorage>, name: &str) {
+        cache.fetch_packument(&name.parse().unwrap()).await.unwrap();
+    }
+
+    fn held(cache: &HotCache<InMemoryPackageStorage>) -> Vec<String> {
+        let entries = cache.entries.lock().unwrap();
+        let mut held: Vec<_> = entries.iter().map(|(key, _)| key.clone()).collect();
+        held.sort();
+        held
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used() {
+        let cache = HotCache::new(storage(&["a", "b", "c", "d"]).await, 2);
+
+        read(&cache, "a").await;
+        read(&cache, "b").await;
+        read(&cache, "a").await;
+        read(&cache, "c").await;
+        assert_eq!(held(&cache), vec!["a", "c"]);
+
+        // However often an old favourite was read, a new workload displaces it.
+        for _ in 0..100 {
+            read(&cache, "a").await;
+        }
+        read(&cache, "c").await;
+        read(&cache, "d").await;
+        assert_eq!(held(&cache), vec!["c", "d"]);
+
+        let nothing = HotCache::new(storage(&["a"]).await, 0);
+        read(&nothing, "a").await;
+        assert!(held(&nothing).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_prewarm_keeps_recency() {
+        let dir = TestDir::new("registry-hot-cache");
+        std::fs::create_dir_all(&dir).unwrap();
+        let index = dir.join("hot-packuments.json");
+        let inner = storage(&["a", "b", "c"]).await;
+        let cache = HotCache::new(inner.clone(), 3);
+        for name in ["a", "b", "c", "a"] {
+            read(&cache, name).await;
+        }
+        cache.save_index(&index, 2).await.unwrap();
+
+        let restarted = HotCache::new(inner, 2);
+        assert_eq!(restarted.prewarm(&index).await.unwrap(), 2);
+        assert_eq!(held(&restarted), vec!["a", "c"]);
+
+        // The most recent before the restart is still the last to go.
+        read(&restarted, "b").await;
+        assert_eq!(held(&restarted), vec!["a", "b"]);
+    }
+}
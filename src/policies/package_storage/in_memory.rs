@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+use futures_util::StreamExt;
+
+use crate::models::{PackageIdentifier, Packument};
+use crate::policies::PackageStorage;
+
+use super::{search_results, SearchQuery, StorageError};
+
+// Keyed by (package, version).
+type Versioned<T> = HashMap<(String, String), T>;
+
+/// Packuments, tarballs and attestations held in memory, for tests and registries that don't
+/// need to outlive the process. Packuments are kept serialized, as a storage on disk would
+/// keep them, so what's read back is what any other storage would serve.
+#[derive(Clone, Default)]
+pub struct InMemoryPackageStorage {
+    packuments: Arc<RwLock<HashMap<String, Bytes>>>,
+    tarballs: Arc<RwLock<Versioned<Bytes>>>,
+    attestations: Arc<RwLock<Versioned<serde_json::Value>>>,
+}
+
+impl InMemoryPackageStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Debug for InMemoryPackageStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut formatter = f.debug_struct("InMemoryPackageStorage");
+        if let Ok(packuments) = self.packuments.try_read() {
+            formatter.field("packuments", &packuments.len());
+        }
+        if let Ok(tarballs) = self.tarballs.try_read() {
+            formatter.field("tarballs", &tarballs.len());
+        }
+        formatter.finish()
+    }
+}
+
+fn once(data: Bytes) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
+    futures::stream::once(async move { Ok(data) }).boxed()
+}
+
+fn versioned(name: &PackageIdentifier, version: &str) -> (String, String) {
+    (name.to_string(), version.to_string())
+}
+
+#[async_trait::async_trait]
+impl PackageStorage for InMemoryPackageStorage {
+    type Error = std::io::Error;
+
+    async fn stream_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        let packuments = self.packuments.read().unwrap();
+        let data = packuments
+            .get(&name.to_string())
+            .cloned()
+            .ok_or(StorageError::NotFound)?;
+        Ok(once(data))
+    }
+
+    async fn stream_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        let tarballs = self.tarballs.read().unwrap();
+        let data = tarballs
+            .get(&versioned(name, version))
+            .cloned()
+            .ok_or(StorageError::NotFound)?;
+        Ok(once(data))
+    }
+
+    async fn put_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &Packument,
+    ) -> anyhow::Result<()> {
+        let data = Bytes::from(serde_json::to_vec(packument)?);
+        self.packuments
+            .write()
+            .unwrap()
+            .insert(name.to_string(), data);
+        Ok(())
+    }
+
+    // Checked and written under one lock.
+    async fn update_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &mut Packument,
+    ) -> anyhow::Result<()> {
+        let mut packuments = self.packuments.write().unwrap();
+        let stored = match packuments.get(&name.to_string()) {
+            Some(data) => serde_json::from_slice::<Packument>(data.as_ref())?.rev,
+            None => None,
+        };
+        if stored != packument.rev {
+            return Err(StorageError::Conflict.into());
+        }
+
+        packument.advance_rev()?;
+        packuments.insert(
+            name.to_string(),
+            Bytes::from(serde_json::to_vec(packument)?),
+        );
+        Ok(())
+    }
+
+    async fn put_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: Bytes,
+    ) -> anyhow::Result<()> {
+        self.tarballs
+            .write()
+            .unwrap()
+            .insert(versioned(name, version), tarball);
+        Ok(())
+    }
+
+    async fn delete_tarball(&self, name: &PackageIdentifier, version: &str) -> anyhow::Result<()> {
+        let key = versioned(name, version);
+        self.tarballs.write().unwrap().remove(&key);
+        self.attestations.write().unwrap().remove(&key);
+        Ok(())
+    }
+
+    async fn delete_package(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        let package = name.to_string();
+        self.packuments.write().unwrap().remove(&package);
+        self.tarballs
+            .write()
+            .unwrap()
+            .retain(|(name, _), _| name != &package);
+        self.attestations
+            .write()
+            .unwrap()
+            .retain(|(name, _), _| name != &package);
+        Ok(())
+    }
+
+    async fn fetch_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<serde_json::Value> {
+        let attestations = self.attestations.read().unwrap();
+        Ok(attestations
+            .get(&versioned(name, version))
+            .cloned()
+            .ok_or(StorageError::NotFound)?)
+    }
+
+    async fn put_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        attestations: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        self.attestations
+            .write()
+            .unwrap()
+            .insert(versioned(name, version), attestations.clone());
+        Ok(())
+    }
+
+    async fn list_packages(&self) -> anyhow::Result<Vec<PackageIdentifier>> {
+        let mut names: Vec<PackageIdentifier> = self
+            .packuments
+            .read()
+            .unwrap()
+            .keys()
+            .map(|name| name.parse())
+            .collect::<Result<_, _>>()?;
+        names.sort_by_key(|name| name.to_string());
+        Ok(names)
+    }
+
+    // Names and descriptions containing the text, ignoring case, and exact keywords; an exact
+    // name comes first, the rest by name.
+    async fn search(&self, query: &SearchQuery) -> anyhow::Result<serde_json::Value> {
+        let text = query.text.trim().to_lowercase();
+        let mut matches = Vec::new();
+        for (name, data) in self.packuments.read().unwrap().iter() {
+            let document: serde_json::Value = serde_json::from_slice(data.as_ref())?;
+            let description = document["description"].as_str().unwrap_or_default();
+            let keyword = document["keywords"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .any(|keyword| keyword.as_str() == Some(query.text.trim()));
+            if name.to_lowercase().contains(text.as_str())
+                || description.to_lowercase().contains(text.as_str())
+                || keyword
+            {
+                let latest = document["dist-tags"]["latest"].as_str().map(String::from);
+                matches.push((name.clone(), latest, document));
+            }
+        }
+
+        matches.sort_by(|(a, _, _), (b, _, _)| (*a != text, a).cmp(&(*b != text, b)));
+        let total = matches.len() as u64;
+        let (from, size) = query.page();
+        let page = matches
+            .into_iter()
+            .skip(from as usize)
+            .take(size as usize)
+            .collect();
+        Ok(search_results(page, total))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policies::package_storage::collect_stream;
+
+    #[tokio::test]
+    async fn test_in_memory_package_storage() {
+        let storage = InMemoryPackageStorage::new();
+        let name: PackageIdentifier = "left-pad".parse().unwrap();
+        let mut packument: Packument = serde_json::from_value(serde_json::json!({
+            "name": "left-pad",
+            "description": "Pads strings",
+            "dist-tags": { "latest": "1.0.0" }
+        }))
+        .unwrap();
+
+        storage
+            .update_packument(&name, &mut packument)
+            .await
+            .unwrap();
+        assert_eq!(storage.fetch_packument(&name).await.unwrap(), packument);
+
+        let mut stale: Packument =
+            serde_json::from_value(serde_json::json!({ "name": "left-pad" })).unwrap();
+        let conflict = storage
+            .update_packument(&name, &mut stale)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            StorageError::of(&conflict),
+            Some(StorageError::Conflict)
+        ));
+
+        storage
+            .put_tarball(&name, "1.0.0", Bytes::from_static(b"tarball"))
+            .await
+            .unwrap();
+        let tarball = storage.stream_tarball(&name, "1.0.0").await.unwrap();
+        assert_eq!(collect_stream(tarball).await.unwrap(), b"tarball");
+
+        let query = SearchQuery {
+            text: "pads".to_string(),
+            ..SearchQuery::default()
+        };
+        let results = storage.search(&query).await.unwrap();
+        assert_eq!(results["total"], 1);
+        assert_eq!(results["objects"][0]["package"]["name"], "left-pad");
+
+        storage.delete_tarball(&name, "1.0.0").await.unwrap();
+        let missing = storage.stream_tarball(&name, "1.0.0").await.err().unwrap();
+        assert!(matches!(
+            StorageError::of(&missing),
+            Some(StorageError::NotFound)
+        ));
+    }
+}
@@ -1,25 +1,449 @@
+use std::str::FromStr;
+use std::time::Duration;
+
 use axum::body::Bytes;
 use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-use crate::models::{PackageIdentifier, Packument};
+use crate::hashing::{Algorithm, Digest};
+use crate::models::{PackageIdentifier, PackageModification, Packument, PackumentVersion};
+use crate::signing::PublicKey;
 
+#[cfg(feature = "tokio-backends")]
+pub(crate) mod changes;
+pub(crate) mod couchdb;
+#[cfg(feature = "tokio-backends")]
+pub(crate) mod directory;
+pub(crate) mod fallback;
+pub(crate) mod guard;
+pub(crate) mod hot_cache;
+pub(crate) mod in_memory;
+#[cfg(feature = "tokio-backends")]
+pub(crate) mod indexed;
+#[cfg(feature = "postgres")]
+pub(crate) mod postgres;
+#[cfg(feature = "tokio-backends")]
 pub(crate) mod read_through;
+#[cfg(feature = "redis")]
+pub(crate) mod redis_cache;
 pub(crate) mod remote;
+pub(crate) mod rewrite;
+#[cfg(feature = "tokio-backends")]
+pub(crate) mod sqlite;
+
+/// Size and digest of a stored document, enough to answer a HEAD request without sending it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContentMetadata {
+    pub size: u64,
+    pub digest: Digest,
+}
+
+impl ContentMetadata {
+    pub fn of(data: &[u8]) -> anyhow::Result<Self> {
+        Ok(Self {
+            size: data.len() as u64,
+            digest: Digest::compute(Algorithm::default(), data)?,
+        })
+    }
+
+    /// A strong entity tag; the digest changes whenever the content does.
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.digest)
+    }
+
+    /// The tag of a copy kept in `encoding`: a different representation, so a different tag.
+    pub fn encoded_etag(&self, encoding: ContentEncoding) -> String {
+        format!("\"{}-{}\"", self.digest, encoding.as_str())
+    }
+}
+
+/// A compression packuments can be kept in at rest, and served in as they are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ContentEncoding {
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl ContentEncoding {
+    /// Every encoding, in the order we'd rather serve them when a client accepts several
+    /// equally.
+    pub const ALL: [ContentEncoding; 3] = [Self::Brotli, Self::Zstd, Self::Gzip];
+
+    /// Its token in `Accept-Encoding` and `Content-Encoding`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+impl FromStr for ContentEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|encoding| encoding.as_str().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| anyhow::anyhow!("unknown content encoding {:?}", s))
+    }
+}
+
+/// A packument that was created or modified, as listed by a `_changes` feed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PackageChange {
+    pub seq: u64,
+    pub id: String,
+    pub rev: Option<String>,
+}
+
+/// What a [`PackageStorage::collect_garbage`] pass removed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct GarbageCollection {
+    /// Tarballs no version of their package's packument refers to.
+    pub orphaned_tarballs: usize,
+    /// Entries whose content has gone missing, or content no entry refers to.
+    pub dangling_entries: usize,
+    /// Bytes freed on disk.
+    pub reclaimed: u64,
+}
+
+impl std::ops::AddAssign for GarbageCollection {
+    fn add_assign(&mut self, other: Self) {
+        self.orphaned_tarballs += other.orphaned_tarballs;
+        self.dangling_entries += other.dangling_entries;
+        self.reclaimed += other.reclaimed;
+    }
+}
+
+/// How many tarballs a storage holds, and how much storing identical ones once saves.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct TarballStats {
+    /// Tarballs held, one per package version.
+    pub tarballs: usize,
+    /// Distinct contents among them.
+    pub blobs: usize,
+    /// Bytes the tarballs would take were each stored separately.
+    pub size: u64,
+    /// Bytes they take stored once per distinct content.
+    pub stored: u64,
+}
+
+impl TarballStats {
+    pub fn saved(&self) -> u64 {
+        self.size.saturating_sub(self.stored)
+    }
+}
+
+impl std::ops::AddAssign for TarballStats {
+    fn add_assign(&mut self, other: Self) {
+        self.tarballs += other.tarballs;
+        self.blobs += other.blobs;
+        self.size += other.size;
+        self.stored += other.stored;
+    }
+}
+
+/// An `npm search` query, as sent to `/-/v1/search`. Storage that forwards searches passes
+/// it on unchanged.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SearchQuery {
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub popularity: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<f64>,
+}
+
+impl SearchQuery {
+    /// How many results to skip and how many to return. npm asks for 20 by default, and
+    /// never for more than 250.
+    pub fn page(&self) -> (u32, u32) {
+        (self.from.unwrap_or(0), self.size.unwrap_or(20).min(250))
+    }
+}
+
+// A `LIKE` pattern matching `text` anywhere, with its wildcards taken literally. SQLite only
+// honours the backslashes with `ESCAPE '\'`; PostgreSQL does by default.
+#[cfg(feature = "tokio-backends")]
+pub(crate) fn like_pattern(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+// Search results from storage that matches packages by query rather than ranking them, so
+// every score is 1. `matches` are each package's name, latest version and packument (or as
+// much of it as holds the description, keywords and times).
+pub(crate) fn search_results(
+    matches: Vec<(String, Option<String>, serde_json::Value)>,
+    total: u64,
+) -> serde_json::Value {
+    let objects: Vec<_> = matches
+        .into_iter()
+        .map(|(name, version, document)| {
+            serde_json::json!({
+                "package": {
+                    "name": name,
+                    "version": version,
+                    "description": document["description"],
+                    "keywords": document["keywords"],
+                    "date": document["time"]["modified"],
+                },
+                "score": {
+                    "final": 1.0,
+                    "detail": { "quality": 1.0, "popularity": 1.0, "maintenance": 1.0 }
+                },
+                "searchScore": 1.0
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "objects": objects,
+        "total": total,
+        "time": chrono::Utc::now().to_rfc3339()
+    })
+}
+
+/// Failures a storage can report for handlers, and the storage wrapping it, to tell apart.
+/// Anything else a storage returns is treated as an internal error.
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("no such document")]
+    NotFound,
+    /// The upstream registry turned the request away with a 429, asking us to wait
+    /// `retry_after` if it said how long.
+    #[error("the upstream registry is rate limiting requests")]
+    RateLimited { retry_after: Option<Duration> },
+    /// The upstream registry answered with an error other than a 404 or 429, or couldn't be
+    /// reached at all.
+    #[error("the upstream registry failed: {0}")]
+    Upstream(String),
+    /// Reading or writing local storage failed.
+    #[error("storage i/o failed: {0}")]
+    Io(#[from] std::io::Error),
+    /// A document was read whole but can't be used: it doesn't parse, or doesn't match the
+    /// digest it was stored with.
+    #[error("corrupt document: {0}")]
+    Corrupt(String),
+    /// The document changed since the revision a write was based on.
+    #[error("document update conflict")]
+    Conflict,
+    /// The document exists, but the caller may not read or write it.
+    #[error("access to the document is forbidden")]
+    Forbidden,
+}
+
+impl StorageError {
+    /// The storage failure behind `error`, if it's one worth telling apart.
+    pub fn of(error: &anyhow::Error) -> Option<&Self> {
+        error.downcast_ref()
+    }
+
+    /// Whether the same request might succeed if made again later. A missing or corrupt
+    /// document stays that way, and a conflict needs the document read again first.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            StorageError::RateLimited { .. } | StorageError::Upstream(_)
+        )
+    }
+
+    /// How long to wait before retrying, when the upstream said.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            StorageError::RateLimited { retry_after } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// Context on the [`StorageError::NotFound`] of a storage that would have asked the upstream
+/// registry, had it not been offline; the document may well exist, just not here.
+#[derive(Debug, Error)]
+#[error("the upstream registry is offline")]
+pub struct Offline;
+
+/// An inclusive span of bytes, as requested by a `Range: bytes=` header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// Resolve a `Range` header against a document of `size` bytes. Returns `None` when the
+    /// header should be ignored (it's malformed, or asks for several ranges) and `Some(Err)`
+    /// when the range can't be satisfied.
+    pub fn parse(header: &str, size: u64) -> Option<Result<Self, ()>> {
+        let spec = header.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+
+        let (start, end) = spec.split_once('-')?;
+        let (start, end) = match (start.trim(), end.trim()) {
+            ("", "") => return None,
+            ("", suffix) => {
+                let suffix: u64 = suffix.parse().ok()?;
+                if suffix == 0 {
+                    return Some(Err(()));
+                }
+                (size.saturating_sub(suffix), size.saturating_sub(1))
+            }
+            (start, "") => (start.parse().ok()?, size.saturating_sub(1)),
+            (start, end) => {
+                let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+                if end < start {
+                    return None;
+                }
+                (start, end.min(size.saturating_sub(1)))
+            }
+        };
+
+        if start >= size {
+            return Some(Err(()));
+        }
+        Some(Ok(Self { start, end }))
+    }
+
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+// Trim a stream down to `range`, stopping once it's been read.
+fn slice_stream<E: Send + 'static>(
+    stream: BoxStream<'static, Result<Bytes, E>>,
+    range: ByteRange,
+) -> BoxStream<'static, Result<Bytes, E>> {
+    stream
+        .scan(0u64, move |offset, chunk| {
+            let item = match chunk {
+                Err(e) => Some(Some(Err(e))),
+                Ok(_) if *offset > range.end => None,
+                Ok(chunk) => {
+                    let chunk_start = *offset;
+                    *offset += chunk.len() as u64;
+                    let start = range.start.max(chunk_start);
+                    let end = (range.end + 1).min(*offset);
+                    Some((start < end).then(|| {
+                        Ok(chunk
+                            .slice((start - chunk_start) as usize..(end - chunk_start) as usize))
+                    }))
+                }
+            };
+            futures::future::ready(item)
+        })
+        .filter_map(futures::future::ready)
+        .boxed()
+}
+
+// Check-then-write, for storage without anything better; see
+// [`PackageStorage::update_packument`].
+pub(crate) async fn put_if_unchanged<S>(
+    storage: &S,
+    name: &PackageIdentifier,
+    packument: &mut Packument,
+) -> anyhow::Result<()>
+where
+    S: PackageStorage + ?Sized,
+{
+    let stored = match storage.fetch_packument(name).await {
+        Ok(stored) => stored.rev,
+        Err(e) if matches!(StorageError::of(&e), Some(StorageError::NotFound)) => None,
+        Err(e) => return Err(e),
+    };
+    if stored != packument.rev {
+        return Err(StorageError::Conflict.into());
+    }
+
+    packument.advance_rev()?;
+    storage.put_packument(name, packument).await
+}
+
+pub(crate) async fn collect_stream<E: Into<axum::BoxError>>(
+    stream: BoxStream<'static, Result<Bytes, E>>,
+) -> anyhow::Result<Vec<u8>> {
+    let data: Vec<Bytes> = stream
+        .try_collect()
+        .await
+        .map_err(|e| stream_error(e.into()))?;
+    Ok(data.as_slice().concat())
+}
+
+// A storage failing partway through a document sends its `StorageError` down the stream as
+// it is, or wrapped in an i/o error; either is recovered, so that it's told apart like one
+// returned before the stream began.
+fn stream_error(error: axum::BoxError) -> anyhow::Error {
+    let error = match error.downcast::<std::io::Error>() {
+        Ok(io) if io.get_ref().is_some_and(|inner| inner.is::<StorageError>()) => {
+            io.into_inner().expect("checked for an inner error")
+        }
+        Ok(io) => return anyhow::Error::new(*io),
+        Err(error) => error,
+    };
+    match error.downcast::<StorageError>() {
+        Ok(storage) => anyhow::Error::new(*storage),
+        Err(error) => anyhow::anyhow!(error),
+    }
+}
+
+// A fresh path under the system's temporary directory for a test's storage to create, and
+// removed with whatever is in it once dropped, even by a failing assertion.
+#[cfg(test)]
+pub(crate) struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub(crate) fn new(prefix: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("{}-{}", prefix, uuid::Uuid::new_v4())))
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_path()
+    }
+}
+
+#[cfg(test)]
+impl AsRef<std::path::Path> for TestDir {
+    fn as_ref(&self) -> &std::path::Path {
+        self.0.as_path()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
 
 #[async_trait::async_trait]
 pub trait PackageStorage: Send + Sync {
     type Error: Into<axum::BoxError> + Send + Sync + 'static;
     async fn fetch_packument(&self, name: &PackageIdentifier) -> anyhow::Result<Packument> {
-        let stream = self.stream_packument(name).await?;
-        use futures::TryStreamExt;
-
-        let data: Vec<Bytes> = stream.try_collect().await.map_err(|e| {
-            let box_error: axum::BoxError = e.into();
-            anyhow::anyhow!(box_error)
-        })?;
-        let data = data.as_slice().concat();
-
-        Ok(serde_json::from_slice(data.as_slice())?)
+        let data = collect_stream(self.stream_packument(name).await?).await?;
+        serde_json::from_slice(data.as_slice())
+            .map_err(|e| StorageError::Corrupt(format!("packument for {}: {}", name, e)).into())
     }
 
     async fn stream_packument(
@@ -27,9 +451,304 @@ pub trait PackageStorage: Send + Sync {
         name: &PackageIdentifier,
     ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>>;
 
+    /// The manifest of the version `spec` refers to, as [`Packument::resolve_version`] finds
+    /// it, or `None` if the package has no such version. Storage that can read one version
+    /// without parsing the whole packument should override this.
+    async fn resolve_version(
+        &self,
+        name: &PackageIdentifier,
+        spec: &str,
+    ) -> anyhow::Result<Option<PackumentVersion>> {
+        Ok(self
+            .fetch_packument(name)
+            .await?
+            .resolve_version(spec)
+            .cloned())
+    }
+
+    /// The abbreviated ("corgi") packument, holding only what installers need. The full
+    /// packument is a valid superset, so that's what storage without a cheaper form returns.
+    async fn stream_abbreviated_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.stream_packument(name).await
+    }
+
+    /// The packument (or, with `abbreviated`, its abbreviated form) as kept compressed with
+    /// `encoding`, or `None` for storage that doesn't keep it that way; it's then compressed
+    /// as it's served. Either way it's the document [`PackageStorage::packument_metadata`]
+    /// describes, so it keeps that ETag.
+    async fn stream_encoded_packument(
+        &self,
+        _name: &PackageIdentifier,
+        _abbreviated: bool,
+        _encoding: ContentEncoding,
+    ) -> anyhow::Result<Option<BoxStream<'static, Result<Bytes, Self::Error>>>> {
+        Ok(None)
+    }
+
     async fn stream_tarball(
         &self,
         name: &PackageIdentifier,
         version: &str,
     ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>>;
+
+    /// Metadata for the full packument. Storage that records sizes and digests should
+    /// override this (and its siblings); the default reads the whole document.
+    async fn packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        ContentMetadata::of(
+            collect_stream(self.stream_packument(name).await?)
+                .await?
+                .as_slice(),
+        )
+    }
+
+    async fn abbreviated_packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        let stream = self.stream_abbreviated_packument(name).await?;
+        ContentMetadata::of(collect_stream(stream).await?.as_slice())
+    }
+
+    async fn tarball_metadata(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<ContentMetadata> {
+        let stream = self.stream_tarball(name, version).await?;
+        ContentMetadata::of(collect_stream(stream).await?.as_slice())
+    }
+
+    /// Part of a tarball, for resumed downloads. Storage that can seek should override this;
+    /// the default reads the tarball from the start and discards what wasn't asked for.
+    async fn stream_tarball_range(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        range: ByteRange,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        Ok(slice_stream(
+            self.stream_tarball(name, version).await?,
+            range,
+        ))
+    }
+
+    async fn put_packument(
+        &self,
+        _name: &PackageIdentifier,
+        _packument: &Packument,
+    ) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("this package storage is read-only"))
+    }
+
+    /// Store a modified packument, provided the stored document is still at the `_rev` it was
+    /// read at (none, for a new package), and give it the next revision. Fails with
+    /// [`StorageError::Conflict`] if another write got there first.
+    ///
+    /// The default checks and then writes; storage that can should do both at once.
+    async fn update_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &mut Packument,
+    ) -> anyhow::Result<()> {
+        put_if_unchanged(self, name, packument).await
+    }
+
+    async fn put_tarball(
+        &self,
+        _name: &PackageIdentifier,
+        _version: &str,
+        _tarball: Bytes,
+    ) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("this package storage is read-only"))
+    }
+
+    /// Remove a tarball stored with [`PackageStorage::put_tarball`], once its version has
+    /// been dropped from the packument.
+    async fn delete_tarball(
+        &self,
+        _name: &PackageIdentifier,
+        _version: &str,
+    ) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!(
+            "this package storage cannot delete tarballs"
+        ))
+    }
+
+    /// Store a tarball as it arrives, rather than once it's all in memory. Nothing is stored
+    /// if the stream ends in an error.
+    ///
+    /// The default collects the stream and hands it to [`PackageStorage::put_tarball`].
+    async fn put_tarball_stream(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: BoxStream<'static, Result<Bytes, std::io::Error>>,
+    ) -> anyhow::Result<()> {
+        let data = collect_stream(tarball).await?;
+        self.put_tarball(name, version, Bytes::from(data)).await
+    }
+
+    /// Drop one version from the packument, then its tarball. The tarball is only removed
+    /// once no packument refers to it, so a reader never finds a version it can't download.
+    async fn delete_version(&self, name: &PackageIdentifier, version: &str) -> anyhow::Result<()> {
+        let mut packument = self.fetch_packument(name).await?;
+        packument.apply(PackageModification::RemoveVersions(vec![
+            version.to_string()
+        ]))?;
+        self.update_packument(name, &mut packument).await?;
+        self.delete_tarball(name, version).await
+    }
+
+    /// Remove a package outright: its packument, tarballs and attestations. Unlike an
+    /// unpublish, no tombstone is left behind; this is for content that must not be kept.
+    async fn delete_package(&self, _name: &PackageIdentifier) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!(
+            "this package storage cannot delete packages"
+        ))
+    }
+
+    /// Drop whatever is cached of `name`'s packument, so that the next read goes to the
+    /// storage behind the cache. Documents published here are the only copy and are kept.
+    /// Storage that caches nothing has nothing to drop.
+    async fn invalidate(&self, _name: &PackageIdentifier) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Drop a cached tarball, as [`PackageStorage::invalidate`] does a packument.
+    async fn invalidate_tarball(
+        &self,
+        _name: &PackageIdentifier,
+        _version: &str,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Remove tarballs that no version in their package's packument refers to, and anything
+    /// else left behind that can no longer be served. Storage that keeps nothing of its own
+    /// has nothing to collect.
+    async fn collect_garbage(&self) -> anyhow::Result<GarbageCollection> {
+        Ok(GarbageCollection::default())
+    }
+
+    /// How many tarballs are held, and how many distinct contents they come to. Storage that
+    /// keeps no tarballs of its own reports none.
+    async fn tarball_stats(&self) -> anyhow::Result<TarballStats> {
+        Ok(TarballStats::default())
+    }
+
+    /// The attestations published with `version`, as served at `/-/npm/v1/attestations/`.
+    async fn fetch_attestations(
+        &self,
+        _name: &PackageIdentifier,
+        _version: &str,
+    ) -> anyhow::Result<serde_json::Value> {
+        Err(StorageError::NotFound.into())
+    }
+
+    async fn put_attestations(
+        &self,
+        _name: &PackageIdentifier,
+        _version: &str,
+        _attestations: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!(
+            "this package storage cannot store attestations"
+        ))
+    }
+
+    async fn list_packages(&self) -> anyhow::Result<Vec<PackageIdentifier>> {
+        Err(anyhow::anyhow!("this package storage cannot list packages"))
+    }
+
+    /// Packuments written after sequence number `since`, oldest first. Each package is listed
+    /// once, at its most recent change.
+    async fn changes_since(
+        &self,
+        _since: u64,
+        _limit: usize,
+    ) -> anyhow::Result<Vec<PackageChange>> {
+        Err(anyhow::anyhow!(
+            "this package storage does not record changes"
+        ))
+    }
+
+    /// Results for `query`, as the `{"objects": [...], "total": n, "time": ...}` document
+    /// npm expects.
+    async fn search(&self, _query: &SearchQuery) -> anyhow::Result<serde_json::Value> {
+        Err(anyhow::anyhow!("this package storage cannot search"))
+    }
+
+    /// Keys that signatures in the packuments it serves were made with, other than this
+    /// registry's own: an upstream's, for the versions cached from it.
+    async fn public_keys(&self) -> anyhow::Result<Vec<PublicKey>> {
+        Ok(Vec::new())
+    }
+
+    /// Names of packages starred by `username`. Storage with an index of stars should
+    /// override this; the default walks every listed packument.
+    async fn starred_by(&self, username: &str) -> anyhow::Result<Vec<String>> {
+        let mut starred = Vec::new();
+        for name in self.list_packages().await? {
+            let Ok(packument) = self.fetch_packument(&name).await else {
+                continue;
+            };
+
+            if packument
+                .stargazers
+                .as_ref()
+                .is_some_and(|stargazers| stargazers.get(username) == Some(&true))
+            {
+                starred.push(name.to_string());
+            }
+        }
+
+        starred.sort();
+        Ok(starred)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_ranges() {
+        let range = |start, end| Some(Ok(ByteRange { start, end }));
+        assert_eq!(ByteRange::parse("bytes=0-9", 100), range(0, 9));
+        assert_eq!(ByteRange::parse("bytes=90-", 100), range(90, 99));
+        assert_eq!(ByteRange::parse("bytes=-10", 100), range(90, 99));
+        assert_eq!(ByteRange::parse("bytes=-500", 100), range(0, 99));
+        assert_eq!(ByteRange::parse("bytes=50-500", 100), range(50, 99));
+        assert_eq!(ByteRange::parse("bytes=100-", 100), Some(Err(())));
+        assert_eq!(ByteRange::parse("bytes=0-1,5-6", 100), None);
+        assert_eq!(ByteRange::parse("items=0-1", 100), None);
+        assert_eq!(ByteRange::parse("bytes=9-1", 100), None);
+    }
+
+    #[cfg(feature = "tokio-backends")]
+    #[test]
+    fn test_like_pattern() {
+        assert_eq!(like_pattern("left-pad"), "%left-pad%");
+        assert_eq!(like_pattern("a_b%"), "%a\\_b\\%%");
+    }
+
+    #[test]
+    fn test_slice_stream() {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = ["abcd", "efgh", "ijkl"]
+            .into_iter()
+            .map(|chunk| Ok(Bytes::from(chunk)))
+            .collect();
+        let sliced = slice_stream(
+            futures::stream::iter(chunks).boxed(),
+            ByteRange { start: 2, end: 8 },
+        );
+        let sliced: Vec<Bytes> = futures::executor::block_on(sliced.try_collect()).unwrap();
+        assert_eq!(sliced.concat(), b"cdefghi");
+    }
 }
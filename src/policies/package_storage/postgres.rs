@@ -0,0 +1,690 @@
+//! Packages kept in PostgreSQL, for deployments that already run a database and want
+//! publishes to be transactional.
+//!
+//! A packument is stored taken apart: its versions, dist-tags and maintainers are rows of
+//! their own, and what's left of the document is kept as JSON alongside its `_rev`. That
+//! lets a publish check the revision and write every row in one transaction, lets one
+//! version be read without the rest, and lets packages be listed and searched by query.
+//! Tarballs and attestations live in tables of their own, keyed by package and version.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+use futures_util::StreamExt;
+use tokio::sync::Mutex;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::{Client, NoTls, Transaction};
+
+use super::{like_pattern, search_results, ByteRange, SearchQuery, StorageError};
+use crate::migrations::{Migrator, SchemaStamp};
+use crate::models::{resolve_version_in, DistTags, PackageIdentifier, Packument, PackumentVersion};
+use crate::policies::PackageStorage;
+
+pub fn migrations() -> Migrator<Schema> {
+    // Tarballs may be written before the packument listing them, so they don't reference it.
+    Migrator::new("package database").migration("create the package tables", |schema| {
+        schema.execute(
+            "CREATE TABLE packages (
+                name TEXT PRIMARY KEY,
+                rev TEXT,
+                document JSONB NOT NULL DEFAULT '{}',
+                modified TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+            CREATE TABLE package_versions (
+                package TEXT NOT NULL REFERENCES packages (name) ON DELETE CASCADE,
+                version TEXT NOT NULL,
+                manifest JSONB NOT NULL,
+                PRIMARY KEY (package, version)
+            );
+            CREATE TABLE dist_tags (
+                package TEXT NOT NULL REFERENCES packages (name) ON DELETE CASCADE,
+                tag TEXT NOT NULL,
+                version TEXT NOT NULL,
+                PRIMARY KEY (package, tag)
+            );
+            CREATE TABLE maintainers (
+                package TEXT NOT NULL REFERENCES packages (name) ON DELETE CASCADE,
+                position INTEGER NOT NULL,
+                name TEXT,
+                email TEXT,
+                entry JSONB NOT NULL,
+                PRIMARY KEY (package, position)
+            );
+            CREATE INDEX maintainers_by_name ON maintainers (name);
+            CREATE TABLE tarballs (
+                package TEXT NOT NULL,
+                version TEXT NOT NULL,
+                data BYTEA NOT NULL,
+                PRIMARY KEY (package, version)
+            );
+            CREATE TABLE attestations (
+                package TEXT NOT NULL,
+                version TEXT NOT NULL,
+                document JSONB NOT NULL,
+                PRIMARY KEY (package, version)
+            );",
+        )
+    })
+}
+
+/// A connection as a [`Migrator`] drives it, one blocking statement at a time. Only for use
+/// off the async runtime's worker threads, as [`Postgres::connect`] does.
+pub struct Schema {
+    client: Client,
+}
+
+impl Schema {
+    fn execute(&self, sql: &str) -> anyhow::Result<()> {
+        futures::executor::block_on(self.client.batch_execute(sql))?;
+        Ok(())
+    }
+}
+
+impl SchemaStamp for Schema {
+    fn schema_version(&self) -> anyhow::Result<u32> {
+        let query = self
+            .client
+            .query_opt("SELECT version FROM registry_schema", &[]);
+        match futures::executor::block_on(query) {
+            Ok(row) => Ok(row.map_or(0, |row| row.get::<_, i32>(0) as u32)),
+            Err(e) if e.code() == Some(&SqlState::UNDEFINED_TABLE) => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set_schema_version(&self, version: u32) -> anyhow::Result<()> {
+        self.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS registry_schema (version INTEGER NOT NULL);
+                DELETE FROM registry_schema;
+                INSERT INTO registry_schema (version) VALUES ({});",
+                version
+            )
+            .as_str(),
+        )
+    }
+
+    fn atomically(&self, f: &dyn Fn() -> anyhow::Result<()>) -> anyhow::Result<()> {
+        self.execute("BEGIN")?;
+        match f() {
+            Ok(()) => self.execute("COMMIT"),
+            Err(e) => {
+                self.execute("ROLLBACK")?;
+                Err(e)
+            }
+        }
+    }
+}
+
+// Another writer inserting the same new package is a conflict; a dropped connection is i/o.
+fn database_error(error: tokio_postgres::Error) -> anyhow::Error {
+    if error.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+        StorageError::Conflict.into()
+    } else if error.is_closed() {
+        StorageError::Io(std::io::Error::new(std::io::ErrorKind::BrokenPipe, error)).into()
+    } else {
+        error.into()
+    }
+}
+
+fn dist_tags(mut tags: BTreeMap<String, String>) -> Option<DistTags> {
+    if tags.is_empty() {
+        return None;
+    }
+
+    Some(DistTags {
+        latest: tags.remove("latest"),
+        tags,
+    })
+}
+
+/// A packument taken apart into the rows it's stored as.
+#[derive(Debug, Default, PartialEq)]
+struct Rows {
+    rev: Option<String>,
+    // Everything but the `_rev`, versions, dist-tags and maintainers.
+    document: serde_json::Value,
+    versions: Vec<(String, serde_json::Value)>,
+    dist_tags: BTreeMap<String, String>,
+    // Name and email, for querying, and the entry as it was given.
+    maintainers: Vec<(Option<String>, Option<String>, serde_json::Value)>,
+}
+
+impl Rows {
+    fn split(packument: &Packument) -> anyhow::Result<Self> {
+        let mut document = serde_json::to_value(packument)?;
+        if let Some(fields) = document.as_object_mut() {
+            for field in ["_rev", "versions", "dist-tags", "maintainers"] {
+                fields.remove(field);
+            }
+        }
+
+        let mut versions = Vec::new();
+        for (number, version) in packument.versions.iter().flatten() {
+            versions.push((number.clone(), serde_json::to_value(version)?));
+        }
+
+        let mut tags = BTreeMap::new();
+        if let Some(dist_tags) = &packument.dist_tags {
+            tags.extend(dist_tags.tags.clone());
+            if let Some(latest) = &dist_tags.latest {
+                tags.insert("latest".to_string(), latest.clone());
+            }
+        }
+
+        let mut maintainers = Vec::new();
+        for maintainer in packument.maintainers.iter().flatten() {
+            let object = maintainer.clone().into_object();
+            maintainers.push((object.name, object.email, serde_json::to_value(maintainer)?));
+        }
+
+        Ok(Self {
+            rev: packument.rev.clone(),
+            document,
+            versions,
+            dist_tags: tags,
+            maintainers,
+        })
+    }
+
+    fn assemble(self) -> Result<Packument, serde_json::Error> {
+        let mut document = self.document;
+        if let Some(fields) = document.as_object_mut() {
+            if let Some(rev) = self.rev {
+                fields.insert("_rev".to_string(), rev.into());
+            }
+            if !self.versions.is_empty() {
+                let versions: serde_json::Map<_, _> = self.versions.into_iter().collect();
+                fields.insert("versions".to_string(), versions.into());
+            }
+            if let Some(tags) = dist_tags(self.dist_tags) {
+                fields.insert("dist-tags".to_string(), serde_json::to_value(tags)?);
+            }
+            if !self.maintainers.is_empty() {
+                let entries: Vec<_> = self.maintainers.into_iter().map(|(_, _, e)| e).collect();
+                fields.insert("maintainers".to_string(), entries.into());
+            }
+        }
+        serde_json::from_value(document)
+    }
+
+    async fn read(client: &Client, package: &str) -> anyhow::Result<Self> {
+        let row = client
+            .query_opt(
+                "SELECT rev, document FROM packages WHERE name = $1",
+                &[&package],
+            )
+            .await
+            .map_err(database_error)?
+            .ok_or(StorageError::NotFound)?;
+        let mut rows = Self {
+            rev: row.get(0),
+            document: row.get(1),
+            ..Self::default()
+        };
+
+        for row in client
+            .query(
+                "SELECT version, manifest FROM package_versions WHERE package = $1",
+                &[&package],
+            )
+            .await
+            .map_err(database_error)?
+        {
+            rows.versions.push((row.get(0), row.get(1)));
+        }
+
+        for row in client
+            .query(
+                "SELECT tag, version FROM dist_tags WHERE package = $1",
+                &[&package],
+            )
+            .await
+            .map_err(database_error)?
+        {
+            rows.dist_tags.insert(row.get(0), row.get(1));
+        }
+
+        for row in client
+            .query(
+                "SELECT name, email, entry FROM maintainers WHERE package = $1 ORDER BY position",
+                &[&package],
+            )
+            .await
+            .map_err(database_error)?
+        {
+            rows.maintainers.push((row.get(0), row.get(1), row.get(2)));
+        }
+
+        Ok(rows)
+    }
+
+    // Replaces whatever was stored for the package, within the caller's transaction.
+    async fn write(self, transaction: &Transaction<'_>, package: &str) -> anyhow::Result<()> {
+        transaction
+            .execute(
+                "INSERT INTO packages (name, rev, document) VALUES ($1, $2, $3)
+                ON CONFLICT (name) DO UPDATE
+                SET rev = EXCLUDED.rev, document = EXCLUDED.document, modified = now()",
+                &[&package, &self.rev, &self.document],
+            )
+            .await
+            .map_err(database_error)?;
+
+        for table in ["package_versions", "dist_tags", "maintainers"] {
+            transaction
+                .execute(
+                    format!("DELETE FROM {} WHERE package = $1", table).as_str(),
+                    &[&package],
+                )
+                .await
+                .map_err(database_error)?;
+        }
+
+        for (version, manifest) in &self.versions {
+            transaction
+                .execute(
+                    "INSERT INTO package_versions (package, version, manifest)
+                    VALUES ($1, $2, $3)",
+                    &[&package, version, manifest],
+                )
+                .await
+                .map_err(database_error)?;
+        }
+
+        for (tag, version) in &self.dist_tags {
+            transaction
+                .execute(
+                    "INSERT INTO dist_tags (package, tag, version) VALUES ($1, $2, $3)",
+                    &[&package, tag, version],
+                )
+                .await
+                .map_err(database_error)?;
+        }
+
+        for (position, (name, email, entry)) in self.maintainers.iter().enumerate() {
+            transaction
+                .execute(
+                    "INSERT INTO maintainers (package, position, name, email, entry)
+                    VALUES ($1, $2, $3, $4, $5)",
+                    &[&package, &i32::try_from(position)?, name, email, entry],
+                )
+                .await
+                .map_err(database_error)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// One connection, shared through a lock; a publish holds it for the length of its
+/// transaction.
+#[derive(Clone)]
+pub struct Postgres {
+    client: Arc<Mutex<Client>>,
+}
+
+impl std::fmt::Debug for Postgres {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Postgres").finish_non_exhaustive()
+    }
+}
+
+impl Postgres {
+    /// Connect with a libpq-style connection string (`host=... user=... dbname=...`) or URL,
+    /// and bring the schema up to date.
+    pub async fn connect(config: &str) -> anyhow::Result<Self> {
+        let (client, connection) = tokio_postgres::connect(config, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::error!(error = ?e, "postgres connection failed");
+            }
+        });
+
+        let client = tokio::task::spawn_blocking(move || {
+            let schema = Schema { client };
+            migrations().run(&schema, false)?;
+            anyhow::Ok(schema.client)
+        })
+        .await??;
+
+        Ok(Self {
+            client: Arc::new(Mutex::new(client)),
+        })
+    }
+
+    async fn read_bytes(
+        &self,
+        query: &str,
+        package: &str,
+        version: &str,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, std::io::Error>>> {
+        let client = self.client.lock().await;
+        let row = client
+            .query_opt(query, &[&package, &version])
+            .await
+            .map_err(database_error)?
+            .ok_or(StorageError::NotFound)?;
+        let data = Bytes::from(row.get::<_, Vec<u8>>(0));
+        Ok(futures::stream::once(async move { Ok(data) }).boxed())
+    }
+}
+
+#[async_trait::async_trait]
+impl PackageStorage for Postgres {
+    type Error = std::io::Error;
+
+    async fn fetch_packument(&self, name: &PackageIdentifier) -> anyhow::Result<Packument> {
+        let client = self.client.lock().await;
+        Rows::read(&client, name.to_string().as_str())
+            .await?
+            .assemble()
+            .map_err(|e| StorageError::Corrupt(format!("packument for {}: {}", name, e)).into())
+    }
+
+    async fn stream_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        let data = Bytes::from(serde_json::to_vec(&self.fetch_packument(name).await?)?);
+        Ok(futures::stream::once(async move { Ok(data) }).boxed())
+    }
+
+    // Resolved against the version numbers and dist-tags; only the chosen manifest is read.
+    async fn resolve_version(
+        &self,
+        name: &PackageIdentifier,
+        spec: &str,
+    ) -> anyhow::Result<Option<PackumentVersion>> {
+        let package = name.to_string();
+        let client = self.client.lock().await;
+        client
+            .query_opt("SELECT 1 FROM packages WHERE name = $1", &[&package])
+            .await
+            .map_err(database_error)?
+            .ok_or(StorageError::NotFound)?;
+
+        // Keyed and valued by version number, so that resolving gives the number to read.
+        let numbers: BTreeMap<String, String> = client
+            .query(
+                "SELECT version FROM package_versions WHERE package = $1",
+                &[&package],
+            )
+            .await
+            .map_err(database_error)?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(0)))
+            .collect();
+        let tags = client
+            .query(
+                "SELECT tag, version FROM dist_tags WHERE package = $1",
+                &[&package],
+            )
+            .await
+            .map_err(database_error)?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+        let Some(number) = resolve_version_in(&numbers, dist_tags(tags).as_ref(), spec) else {
+            return Ok(None);
+        };
+
+        let row = client
+            .query_one(
+                "SELECT manifest FROM package_versions WHERE package = $1 AND version = $2",
+                &[&package, number],
+            )
+            .await
+            .map_err(database_error)?;
+        let manifest = serde_json::from_value(row.get(0))
+            .map_err(|e| StorageError::Corrupt(format!("{}@{}: {}", name, number, e)))?;
+        Ok(Some(manifest))
+    }
+
+    async fn stream_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.read_bytes(
+            "SELECT data FROM tarballs WHERE package = $1 AND version = $2",
+            name.to_string().as_str(),
+            version,
+        )
+        .await
+    }
+
+    // Sliced by the database, so only the range asked for is sent over the connection.
+    async fn stream_tarball_range(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        range: ByteRange,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        let query = format!(
+            "SELECT substring(data FROM {} FOR {}) FROM tarballs
+            WHERE package = $1 AND version = $2",
+            range.start + 1,
+            range.len()
+        );
+        self.read_bytes(query.as_str(), name.to_string().as_str(), version)
+            .await
+    }
+
+    async fn put_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &Packument,
+    ) -> anyhow::Result<()> {
+        let rows = Rows::split(packument)?;
+        let mut client = self.client.lock().await;
+        let transaction = client.transaction().await.map_err(database_error)?;
+        rows.write(&transaction, name.to_string().as_str()).await?;
+        transaction.commit().await.map_err(database_error)?;
+        Ok(())
+    }
+
+    // The stored revision is locked while it's checked, so the check and the write are one
+    // step. A package that doesn't exist yet is claimed with a bare insert, which fails for
+    // whoever inserts it second.
+    async fn update_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &mut Packument,
+    ) -> anyhow::Result<()> {
+        let package = name.to_string();
+        let mut client = self.client.lock().await;
+        let transaction = client.transaction().await.map_err(database_error)?;
+        let stored = transaction
+            .query_opt(
+                "SELECT rev FROM packages WHERE name = $1 FOR UPDATE",
+                &[&package],
+            )
+            .await
+            .map_err(database_error)?;
+
+        match stored {
+            Some(row) if row.get::<_, Option<String>>(0) != packument.rev => {
+                return Err(StorageError::Conflict.into());
+            }
+            Some(_) => {}
+            None if packument.rev.is_some() => return Err(StorageError::Conflict.into()),
+            None => {
+                transaction
+                    .execute("INSERT INTO packages (name) VALUES ($1)", &[&package])
+                    .await
+                    .map_err(database_error)?;
+            }
+        }
+
+        packument.advance_rev()?;
+        Rows::split(packument)?
+            .write(&transaction, package.as_str())
+            .await?;
+        transaction.commit().await.map_err(database_error)?;
+        Ok(())
+    }
+
+    async fn put_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: Bytes,
+    ) -> anyhow::Result<()> {
+        let client = self.client.lock().await;
+        client
+            .execute(
+                "INSERT INTO tarballs (package, version, data) VALUES ($1, $2, $3)
+                ON CONFLICT (package, version) DO UPDATE SET data = EXCLUDED.data",
+                &[&name.to_string(), &version, &tarball.as_ref()],
+            )
+            .await
+            .map_err(database_error)?;
+        Ok(())
+    }
+
+    async fn delete_tarball(&self, name: &PackageIdentifier, version: &str) -> anyhow::Result<()> {
+        let package = name.to_string();
+        let client = self.client.lock().await;
+        for table in ["tarballs", "attestations"] {
+            client
+                .execute(
+                    format!("DELETE FROM {} WHERE package = $1 AND version = $2", table).as_str(),
+                    &[&package, &version],
+                )
+                .await
+                .map_err(database_error)?;
+        }
+        Ok(())
+    }
+
+    async fn fetch_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<serde_json::Value> {
+        let client = self.client.lock().await;
+        let row = client
+            .query_opt(
+                "SELECT document FROM attestations WHERE package = $1 AND version = $2",
+                &[&name.to_string(), &version],
+            )
+            .await
+            .map_err(database_error)?
+            .ok_or(StorageError::NotFound)?;
+        Ok(row.get(0))
+    }
+
+    async fn put_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        attestations: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        let client = self.client.lock().await;
+        client
+            .execute(
+                "INSERT INTO attestations (package, version, document) VALUES ($1, $2, $3)
+                ON CONFLICT (package, version) DO UPDATE SET document = EXCLUDED.document",
+                &[&name.to_string(), &version, attestations],
+            )
+            .await
+            .map_err(database_error)?;
+        Ok(())
+    }
+
+    async fn list_packages(&self) -> anyhow::Result<Vec<PackageIdentifier>> {
+        let client = self.client.lock().await;
+        let rows = client
+            .query("SELECT name FROM packages ORDER BY name", &[])
+            .await
+            .map_err(database_error)?;
+        rows.into_iter()
+            .map(|row| row.get::<_, String>(0).parse().map_err(Into::into))
+            .collect()
+    }
+
+    // Matches names and descriptions containing the text, and exact keywords; an exact name
+    // comes first, the rest by name. There's nothing to score them on, so every score is 1.
+    async fn search(&self, query: &SearchQuery) -> anyhow::Result<serde_json::Value> {
+        let text = query.text.trim();
+        let pattern = like_pattern(text);
+        let (from, size) = query.page();
+        let (from, size) = (i64::from(from), i64::from(size));
+        let matches = "p.name ILIKE $1 OR p.document->>'description' ILIKE $1
+            OR p.document->'keywords' ? $2";
+
+        let client = self.client.lock().await;
+        let total: i64 = client
+            .query_one(
+                format!("SELECT count(*) FROM packages p WHERE {}", matches).as_str(),
+                &[&pattern, &text],
+            )
+            .await
+            .map_err(database_error)?
+            .get(0);
+        let rows = client
+            .query(
+                format!(
+                    "SELECT p.name, p.document, t.version FROM packages p
+                    LEFT JOIN dist_tags t ON t.package = p.name AND t.tag = 'latest'
+                    WHERE {}
+                    ORDER BY p.name = $2 DESC, p.name
+                    LIMIT $3 OFFSET $4",
+                    matches
+                )
+                .as_str(),
+                &[&pattern, &text, &size, &from],
+            )
+            .await
+            .map_err(database_error)?;
+
+        let matches = rows
+            .into_iter()
+            .map(|row| (row.get(0), row.get(2), row.get(1)))
+            .collect();
+        Ok(search_results(matches, total as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rows_round_trip() {
+        let packument: Packument = serde_json::from_value(serde_json::json!({
+            "_id": "left-pad",
+            "_rev": "2-abc",
+            "name": "left-pad",
+            "description": "pads strings",
+            "dist-tags": { "latest": "1.0.0", "beta": "1.1.0-0" },
+            "versions": {
+                "1.0.0": {
+                    "_id": "left-pad@1.0.0", "name": "left-pad", "version": "1.0.0",
+                    "dist": { "tarball": "", "shasum": "" }
+                },
+                "1.1.0-0": {
+                    "_id": "left-pad@1.1.0-0", "name": "left-pad", "version": "1.1.0-0",
+                    "dist": { "tarball": "", "shasum": "" }
+                }
+            },
+            "maintainers": [
+                "Ada <ada@example.com>",
+                { "name": "grace", "email": "grace@example.com" }
+            ]
+        }))
+        .unwrap();
+
+        let rows = Rows::split(&packument).unwrap();
+        assert_eq!(rows.rev.as_deref(), Some("2-abc"));
+        assert!(rows.document.get("versions").is_none());
+        assert_eq!(rows.versions.len(), 2);
+        assert_eq!(rows.dist_tags["latest"], "1.0.0");
+        assert_eq!(rows.maintainers[0].1.as_deref(), Some("ada@example.com"));
+        assert_eq!(rows.maintainers[1].0.as_deref(), Some("grace"));
+        assert_eq!(rows.assemble().unwrap(), packument);
+    }
+}
@@ -1,15 +1,331 @@
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::models::PackageIdentifier;
+use crate::hashing::{Algorithm, Digest, Hasher, Integrity};
+use crate::migrations::{Migrator, StampFile};
+use crate::models::{Dist, PackageIdentifier, Packument, PackumentVersion};
 use crate::policies::PackageStorage;
+use crate::signing::PublicKey;
+use crate::tasks::TaskRegistry;
+
+use super::indexed::{self, IndexedPackument};
+use super::{
+    collect_stream, put_if_unchanged, ContentEncoding, ContentMetadata, GarbageCollection,
+    SearchQuery, StorageError, TarballStats,
+};
 use axum::body::Bytes;
 use futures::stream::BoxStream;
-use futures_util::{pin_mut, StreamExt};
+use futures_util::{pin_mut, StreamExt, TryStreamExt};
+use tokio::sync::{broadcast, Mutex};
+
+// Only as much of an abbreviated packument as checking a tarball needs.
+#[derive(serde::Deserialize)]
+struct AdvertisedDists {
+    #[serde(default)]
+    versions: HashMap<String, AdvertisedDist>,
+}
+
+#[derive(serde::Deserialize)]
+struct AdvertisedDist {
+    dist: Dist,
+}
+
+/// Migrations for a cache directory. Version 1 is the cacache layout as first shipped, with
+/// abbreviated packuments stored under `corgi:` keys.
+pub fn migrations() -> Migrator<StampFile> {
+    Migrator::new("package cache").migration("stamp the initial cache layout", |_| Ok(()))
+}
+
+// How long to wait before trying a failed fill again, when the upstream didn't say.
+const FILL_RETRY_DELAY: Duration = Duration::from_millis(250);
+// The longest a request waits on a rate-limited upstream before failing instead.
+const FILL_RETRY_MAX_WAIT: Duration = Duration::from_secs(2);
+// How many refilled names are kept for subscribers that haven't caught up.
+const REFILLS_CAPACITY: usize = 256;
+// A publish writes its tarball before the packument that lists it, and cacache writes content
+// before indexing it; anything younger than this may be mid-write, and is left for next time.
+const GC_GRACE: Duration = Duration::from_secs(60 * 60);
+
+// Compressed once per revision and served many times over, so worth the slowest settings
+// that stay quick on a packument of several megabytes.
+async fn compress(encoding: ContentEncoding, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    use async_compression::tokio::write::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+    use async_compression::Level;
+    use tokio::io::AsyncWriteExt;
+
+    match encoding {
+        ContentEncoding::Gzip => {
+            let mut encoder = GzipEncoder::with_quality(Vec::new(), Level::Best);
+            encoder.write_all(data).await?;
+            encoder.shutdown().await?;
+            Ok(encoder.into_inner())
+        }
+        ContentEncoding::Brotli => {
+            let mut encoder = BrotliEncoder::with_quality(Vec::new(), Level::Precise(9));
+            encoder.write_all(data).await?;
+            encoder.shutdown().await?;
+            Ok(encoder.into_inner())
+        }
+        ContentEncoding::Zstd => {
+            let mut encoder = ZstdEncoder::with_quality(Vec::new(), Level::Precise(19));
+            encoder.write_all(data).await?;
+            encoder.shutdown().await?;
+            Ok(encoder.into_inner())
+        }
+    }
+}
+
+// Damaged entries are corrupt; anything else cacache fails with is i/o.
+fn cache_error(error: cacache::Error) -> anyhow::Error {
+    match error {
+        cacache::Error::EntryNotFound(_, _) => StorageError::NotFound.into(),
+        cacache::Error::IoError(e, _) => StorageError::Io(e).into(),
+        cacache::Error::IntegrityError(e) => StorageError::Corrupt(e.to_string()).into(),
+        cacache::Error::SizeMismatch(wanted, actual) => {
+            StorageError::Corrupt(format!("expected {} bytes, found {}", wanted, actual)).into()
+        }
+        cacache::Error::SerdeError(e, context) => {
+            StorageError::Corrupt(format!("{}: {}", context, e)).into()
+        }
+    }
+}
+
+fn upstream_error<E: Into<axum::BoxError>>(error: E) -> anyhow::Error {
+    let error: axum::BoxError = error.into();
+    StorageError::Upstream(error.to_string()).into()
+}
 
+// An entry being written as its content arrives. Only a document read to the end, and
+// matching what it was expected to hash to, is committed; one cut off partway or that
+// hashes to something else is never cached. The content is written before it's indexed,
+// since cacache only records the size of an entry it's told up front.
+struct CacheWrite {
+    cache_dir: PathBuf,
+    key: String,
+    algorithm: Algorithm,
+    writer: cacache::Writer,
+    size: usize,
+    expected: Option<(Digest, Hasher)>,
+    metadata: Option<serde_json::Value>,
+    references: Arc<Mutex<Option<References>>>,
+}
+
+impl CacheWrite {
+    async fn write(&mut self, chunk: Bytes) -> anyhow::Result<Bytes> {
+        use tokio::io::AsyncWriteExt;
+        self.writer
+            .write_all(chunk.as_ref())
+            .await
+            .map_err(StorageError::from)?;
+        self.size += chunk.len();
+        if let Some((_, hasher)) = self.expected.as_mut() {
+            hasher.update(chunk.as_ref())?;
+        }
+        Ok(chunk)
+    }
+
+    async fn commit(self) -> anyhow::Result<()> {
+        if let Some((expected, hasher)) = self.expected {
+            let actual = hasher.finish()?;
+            if actual != expected {
+                let key = self.key.as_str();
+                tracing::warn!(key, %expected, %actual, "refusing to cache a mismatched document");
+                return Err(StorageError::Corrupt(format!(
+                    "{} hashed to {}, not {}",
+                    key, actual, expected
+                ))
+                .into());
+            }
+        }
+        let integrity = self.writer.commit().await.map_err(cache_error)?;
+        let mut entry = cacache::WriteOpts::new()
+            .algorithm(self.algorithm.into())
+            .integrity(integrity.clone())
+            .size(self.size);
+        if let Some(metadata) = self.metadata {
+            entry = entry.metadata(metadata);
+        }
+        cacache::index::insert_async(self.cache_dir.as_path(), self.key.as_str(), entry)
+            .await
+            .map_err(cache_error)?;
+        record_reference(&self.references, self.key.as_str(), &integrity).await;
+        Ok(())
+    }
+
+    // Each chunk goes to the cache, then on to the client. A client that hangs up early
+    // leaves the entry unwritten; one that reads to the end of a document that fails to
+    // verify gets an error in place of the end of the body, so it never installs it.
+    fn tee<E>(
+        self,
+        stream: BoxStream<'static, Result<Bytes, E>>,
+    ) -> BoxStream<'static, Result<Bytes, std::io::Error>>
+    where
+        E: Into<axum::BoxError> + Send + 'static,
+    {
+        futures::stream::try_unfold((stream, self), |(mut stream, mut write)| async move {
+            match stream.next().await {
+                Some(chunk) => {
+                    let chunk = write.write(chunk.map_err(upstream_error)?).await?;
+                    Ok(Some((chunk, (stream, write))))
+                }
+                None => {
+                    write.commit().await?;
+                    Ok(None)
+                }
+            }
+        })
+        .map_err(|e: anyhow::Error| match e.downcast::<StorageError>() {
+            Ok(e) => std::io::Error::other(e),
+            Err(e) => std::io::Error::other(e),
+        })
+        .boxed()
+    }
+}
+
+// Which content each key in the index holds, and how many keys hold each, so that evicting
+// an entry needn't read the whole index to learn whether its content is shared. Built from
+// the index when first needed and again after each collection; until then, writes leave it
+// be, since building it will find them.
+#[derive(Debug, Default)]
+struct References {
+    keys: HashMap<String, String>,
+    counts: HashMap<String, usize>,
+}
+
+impl References {
+    fn of(entries: &[cacache::Metadata]) -> Self {
+        let mut references = Self::default();
+        for entry in entries {
+            references.insert(entry.key.as_str(), &entry.integrity);
+        }
+        references
+    }
+
+    // Writing over a key lets go of whatever it held before.
+    fn insert(&mut self, key: &str, integrity: &impl std::fmt::Display) {
+        let integrity = integrity.to_string();
+        match self.keys.insert(key.to_string(), integrity.clone()) {
+            Some(previous) if previous == integrity => return,
+            Some(previous) => {
+                self.release(previous.as_str());
+            }
+            None => {}
+        }
+        *self.counts.entry(integrity).or_default() += 1;
+    }
+
+    // Whether any other key still holds `key`'s content, once it's gone. A key it never
+    // saw may hold anything, so that content is kept.
+    fn remove(&mut self, key: &str) -> bool {
+        match self.keys.remove(key) {
+            Some(integrity) => self.release(integrity.as_str()),
+            None => true,
+        }
+    }
+
+    fn release(&mut self, integrity: &str) -> bool {
+        let Some(count) = self.counts.get_mut(integrity) else {
+            return false;
+        };
+        *count -= 1;
+        if *count > 0 {
+            return true;
+        }
+        self.counts.remove(integrity);
+        false
+    }
+}
+
+async fn record_reference(
+    references: &Mutex<Option<References>>,
+    key: &str,
+    integrity: &impl std::fmt::Display,
+) {
+    if let Some(references) = references.lock().await.as_mut() {
+        references.insert(key, integrity);
+    }
+}
+
+async fn forget_reference(references: &Mutex<Option<References>>, key: &str) {
+    if let Some(references) = references.lock().await.as_mut() {
+        references.remove(key);
+    }
+}
+
+// Where cacache keeps an entry's content: `content-v2/<algorithm>/` and then the hex digest,
+// split after its second and fourth characters.
+fn content_path(cache_dir: &Path, entry: &cacache::Metadata) -> PathBuf {
+    let (algorithm, hex) = entry.integrity.to_hex();
+    cache_dir
+        .join("content-v2")
+        .join(algorithm.to_string())
+        .join(&hex[0..2])
+        .join(&hex[2..4])
+        .join(&hex[4..])
+}
+
+// Every file under `dir`, with its size and when it was last written.
+fn content_files(dir: &Path) -> std::io::Result<Vec<(PathBuf, u64, std::time::SystemTime)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let listing = match std::fs::read_dir(&dir) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            listing => listing?,
+        };
+        for file in listing {
+            let file = file?;
+            let metadata = file.metadata()?;
+            if metadata.is_dir() {
+                dirs.push(file.path());
+            } else {
+                files.push((file.path(), metadata.len(), metadata.modified()?));
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn now_millis() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// What one [`ReadThrough::evict_to_budget`] pass found and removed.
+#[derive(Clone, Debug, Default)]
+pub struct CacheEviction {
+    /// Bytes held by evictable entries before the pass.
+    pub size: u64,
+    pub evicted: usize,
+    pub freed: u64,
+}
+
+/// Caches what the inner storage serves in a cacache directory, and keeps what's published
+/// here there too. Documents are stored by the digest of their content and looked up through
+/// keys naming the package (`packument:left-pad`, `tarball:left-pad:1.3.0`), so a version
+/// republished unchanged, or one artifact published under several names, is stored once.
 #[derive(Clone, Debug)]
 pub struct ReadThrough<R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static> {
     cache_dir: PathBuf,
     inner: R,
+    algorithm: Algorithm,
+    encodings: Vec<ContentEncoding>,
+    max_age: Option<Duration>,
+    // Set when expired entries are served at once and revalidated in the background.
+    revalidate_in: Option<TaskRegistry>,
+    // Keys being revalidated in the background, so each has at most one refill in flight.
+    revalidating: Arc<std::sync::Mutex<HashSet<String>>>,
+    max_size: Option<u64>,
+    // When each key was last read, in milliseconds since the epoch; kept under a size budget.
+    last_used: Arc<std::sync::Mutex<HashMap<String, u128>>>,
+    // Held across the check and write of an update, so two can't both pass the check.
+    updates: Arc<Mutex<()>>,
+    references: Arc<Mutex<Option<References>>>,
+    refills: broadcast::Sender<PackageIdentifier>,
 }
 
 impl<R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static> ReadThrough<R> {
@@ -17,7 +333,534 @@ impl<R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static> ReadTh
         Self {
             cache_dir: PathBuf::from(cache_dir.as_ref()),
             inner,
+            algorithm: Algorithm::default(),
+            encodings: vec![ContentEncoding::Gzip],
+            max_age: None,
+            revalidate_in: None,
+            revalidating: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            max_size: None,
+            last_used: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            updates: Arc::new(Mutex::new(())),
+            references: Arc::new(Mutex::new(None)),
+            refills: broadcast::channel(REFILLS_CAPACITY).0,
+        }
+    }
+
+    /// Choose the algorithm used to content-address cached entries.
+    pub fn with_integrity_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Choose the encodings packuments are kept compressed with, alongside the uncompressed
+    /// copy, and served in to clients that accept them. Gzip by default.
+    pub fn with_encodings(mut self, encodings: impl IntoIterator<Item = ContentEncoding>) -> Self {
+        self.encodings = encodings.into_iter().collect();
+        self
+    }
+
+    /// Check packuments cached from the inner storage against it again once they're older
+    /// than `max_age`, before serving them. Packuments written through this storage are the
+    /// only copy and never expire. `None`, the default, keeps every entry until it's written
+    /// over.
+    pub fn with_max_age(mut self, max_age: Option<Duration>) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Serve expired packuments as they are, revalidating them in the background under
+    /// `tasks` rather than making the request wait on the inner storage.
+    pub fn with_stale_while_revalidate(mut self, tasks: TaskRegistry) -> Self {
+        self.revalidate_in = Some(tasks);
+        self
+    }
+
+    /// Names of packages whose packuments are refilled from the inner storage from now on, on
+    /// a request or in the background, so that copies held elsewhere can be dropped.
+    pub fn subscribe_refills(&self) -> broadcast::Receiver<PackageIdentifier> {
+        self.refills.subscribe()
+    }
+
+    // Only entries filled from the inner storage expire; a write marks its entry as ours.
+    async fn is_expired(&self, key: &str, max_age: Option<Duration>) -> anyhow::Result<bool> {
+        let Some(max_age) = max_age else {
+            return Ok(false);
+        };
+        let Some(entry) = cacache::metadata(&self.cache_dir, key)
+            .await
+            .map_err(cache_error)?
+        else {
+            return Ok(false);
+        };
+        if entry.metadata["written"].as_bool() == Some(true) {
+            return Ok(false);
+        }
+
+        Ok(now_millis().saturating_sub(entry.time) > max_age.as_millis())
+    }
+
+    /// Hold entries cached from the inner storage to `max_size` bytes, evicting the least
+    /// recently used first whenever [`ReadThrough::evict_to_budget`] runs. `None`, the
+    /// default, lets the cache grow without limit.
+    pub fn with_max_size(mut self, max_size: Option<u64>) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    async fn entries(&self) -> anyhow::Result<Vec<cacache::Metadata>> {
+        let cache_dir = self.cache_dir.clone();
+        tokio::task::spawn_blocking(move || {
+            cacache::list_sync(cache_dir).collect::<Result<Vec<_>, _>>()
+        })
+        .await?
+        .map_err(cache_error)
+    }
+
+    // Held while an entry is removed and its content perhaps with it, so that the count it
+    // goes by can't change underneath it.
+    async fn references(&self) -> anyhow::Result<tokio::sync::MappedMutexGuard<'_, References>> {
+        let mut references = self.references.lock().await;
+        if references.is_none() {
+            *references = Some(References::of(self.entries().await?.as_slice()));
         }
+        Ok(tokio::sync::MutexGuard::map(references, |references| {
+            references.get_or_insert_with(References::default)
+        }))
+    }
+
+    async fn rebuild_references(&self) -> anyhow::Result<()> {
+        let mut references = self.references.lock().await;
+        *references = Some(References::of(self.entries().await?.as_slice()));
+        Ok(())
+    }
+
+    fn touch(&self, key: &str) {
+        if self.max_size.is_some() {
+            self.last_used
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), now_millis());
+        }
+    }
+
+    /// Evict the least recently used entries until those cached from the inner storage fit
+    /// the size budget. Entries written here don't count against it and are never evicted.
+    /// Reads are only tracked in memory, so after a restart an entry not yet read again
+    /// ranks by when it was filled.
+    pub async fn evict_to_budget(&self) -> anyhow::Result<CacheEviction> {
+        let Some(max_size) = self.max_size else {
+            return Ok(CacheEviction::default());
+        };
+        // Identical documents share their content; it goes with the last entry using it.
+        let mut references = self.references.lock().await;
+        let entries = self.entries().await?;
+        let references = references.insert(References::of(entries.as_slice()));
+
+        let mut evictable: Vec<_> = entries
+            .into_iter()
+            .filter(|entry| entry.metadata["written"].as_bool() != Some(true))
+            .collect();
+        let size: u64 = evictable.iter().map(|entry| entry.size as u64).sum();
+        let mut eviction = CacheEviction {
+            size,
+            ..CacheEviction::default()
+        };
+        if size <= max_size {
+            return Ok(eviction);
+        }
+
+        {
+            let last_used = self.last_used.lock().unwrap();
+            evictable.sort_by_key(|entry| last_used.get(&entry.key).copied().unwrap_or(entry.time));
+        }
+        for entry in evictable {
+            if size - eviction.freed <= max_size {
+                break;
+            }
+
+            cacache::remove(&self.cache_dir, &entry.key)
+                .await
+                .map_err(cache_error)?;
+            if !references.remove(entry.key.as_str()) {
+                cacache::remove_hash(&self.cache_dir, &entry.integrity)
+                    .await
+                    .map_err(cache_error)?;
+            }
+            self.last_used.lock().unwrap().remove(&entry.key);
+            eviction.evicted += 1;
+            eviction.freed += entry.size as u64;
+        }
+        Ok(eviction)
+    }
+
+    // The versions `name`'s cached packument lists, or `None` if none is cached.
+    async fn listed_versions(&self, name: &str) -> anyhow::Result<Option<HashSet<String>>> {
+        let key = format!("packument:{}", name);
+        if cacache::metadata(&self.cache_dir, &key)
+            .await
+            .map_err(cache_error)?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        let data = cacache::read(&self.cache_dir, &key)
+            .await
+            .map_err(cache_error)?;
+        let packument: serde_json::Value = serde_json::from_slice(data.as_slice())?;
+        Ok(Some(
+            packument["versions"]
+                .as_object()
+                .map(|versions| versions.keys().cloned().collect())
+                .unwrap_or_default(),
+        ))
+    }
+
+    // A tarball is orphaned once its packument no longer lists it. One filled from the inner
+    // storage whose packument isn't cached may still be listed there, so it's kept; one written
+    // here has no packument anywhere else.
+    async fn is_orphaned(
+        &self,
+        entry: &cacache::Metadata,
+        listed: &mut HashMap<String, Option<HashSet<String>>>,
+    ) -> anyhow::Result<bool> {
+        let Some((name, version)) = entry
+            .key
+            .strip_prefix("tarball:")
+            .and_then(|tarball| tarball.rsplit_once(':'))
+        else {
+            return Ok(false);
+        };
+
+        if !listed.contains_key(name) {
+            let versions = self.listed_versions(name).await?;
+            listed.insert(name.to_string(), versions);
+        }
+        Ok(match &listed[name] {
+            Some(versions) => !versions.contains(version),
+            None => entry.metadata["written"].as_bool() == Some(true),
+        })
+    }
+
+    // Orphaned tarballs and entries whose content is missing are dropped from the index, and
+    // then any content no remaining entry refers to is deleted. Nothing younger than `grace`
+    // is touched.
+    async fn collect_older_than(&self, grace: Duration) -> anyhow::Result<GarbageCollection> {
+        let entries = self.entries().await?;
+        let cutoff = now_millis().saturating_sub(grace.as_millis());
+        let mut collected = GarbageCollection::default();
+
+        let mut live = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let path = content_path(&self.cache_dir, &entry);
+            if entry.time <= cutoff && !tokio::fs::try_exists(&path).await.unwrap_or(true) {
+                cacache::remove(&self.cache_dir, &entry.key)
+                    .await
+                    .map_err(cache_error)?;
+                collected.dangling_entries += 1;
+            } else {
+                live.push(entry);
+            }
+        }
+
+        let mut listed = HashMap::new();
+        let mut referenced = HashSet::new();
+        let mut orphaned = HashSet::new();
+        for entry in live {
+            let path = content_path(&self.cache_dir, &entry);
+            if entry.time <= cutoff && self.is_orphaned(&entry, &mut listed).await? {
+                cacache::remove(&self.cache_dir, &entry.key)
+                    .await
+                    .map_err(cache_error)?;
+                self.last_used.lock().unwrap().remove(&entry.key);
+                collected.orphaned_tarballs += 1;
+                orphaned.insert(path);
+            } else {
+                referenced.insert(path);
+            }
+        }
+
+        let content_dir = self.cache_dir.join("content-v2");
+        let files = tokio::task::spawn_blocking(move || content_files(&content_dir)).await??;
+        let cutoff = std::time::SystemTime::now() - grace;
+        for (path, size, modified) in files {
+            if referenced.contains(&path) || modified > cutoff {
+                continue;
+            }
+            tokio::fs::remove_file(&path)
+                .await
+                .map_err(StorageError::from)?;
+            if !orphaned.contains(&path) {
+                collected.dangling_entries += 1;
+            }
+            collected.reclaimed += size;
+        }
+        self.rebuild_references().await?;
+        Ok(collected)
+    }
+
+    // An expired entry is filled again, before it's served or in the background; if that
+    // fails the entry is kept, since a stale packument is better than none.
+    async fn revalidate<F, Fut>(
+        &self,
+        name: &PackageIdentifier,
+        key: &str,
+        refill: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnOnce(Self) -> Fut + Send + 'static,
+        Fut: std::future::Future<
+                Output = anyhow::Result<BoxStream<'static, Result<Bytes, R::Error>>>,
+            > + Send
+            + 'static,
+    {
+        if !self.is_expired(key, self.max_age).await? {
+            return Ok(());
+        }
+
+        let Some(tasks) = &self.revalidate_in else {
+            match self.fill(key, None, refill(self.clone())).await {
+                Ok(()) => {
+                    tracing::debug!(key, "revalidated cache entry");
+                    let _ = self.refills.send(name.clone());
+                }
+                Err(e) => tracing::warn!(key, error = ?e, "could not revalidate; serving stale"),
+            }
+            return Ok(());
+        };
+
+        if !self.revalidating.lock().unwrap().insert(key.to_string()) {
+            return Ok(());
+        }
+        let this = self.clone();
+        let name = name.clone();
+        let key = key.to_string();
+        tasks.spawn("cache:revalidate", async move {
+            let result = this.fill(key.as_str(), None, refill(this.clone())).await;
+            this.revalidating.lock().unwrap().remove(&key);
+            if result.is_ok() {
+                let _ = this.refills.send(name);
+            }
+            result
+        });
+        Ok(())
+    }
+
+    // A fill that fails to start in a way that might not last is tried once more, unless the
+    // upstream asks us to wait longer than a client would. What it must hash to is only looked
+    // up on a miss. A miss is streamed to the client as it's written to the cache, rather
+    // than read back once it's all there.
+    async fn open_or_fill<F, Fut>(
+        &self,
+        key: String,
+        expected: impl std::future::Future<Output = anyhow::Result<Option<Integrity>>>,
+        fill: F,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, std::io::Error>>>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<
+            Output = anyhow::Result<BoxStream<'static, Result<Bytes, R::Error>>>,
+        >,
+    {
+        self.touch(key.as_str());
+        match cacache::Reader::open(&self.cache_dir, &key).await {
+            Ok(reader) => return Ok(tokio_util::io::ReaderStream::new(reader).boxed()),
+            Err(cacache::Error::EntryNotFound(_, _)) => {}
+            Err(e) => return Err(cache_error(e)),
+        }
+
+        let expected = expected.await?;
+        let stream = match fill().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                let retry = StorageError::of(&e)
+                    .filter(|e| e.is_retryable())
+                    .map(|e| e.retry_after().unwrap_or(FILL_RETRY_DELAY))
+                    .filter(|wait| *wait <= FILL_RETRY_MAX_WAIT);
+                let Some(wait) = retry else {
+                    return Err(e);
+                };
+                tracing::debug!(key, error = ?e, ?wait, "retrying cache fill");
+                tokio::time::sleep(wait).await;
+                fill().await?
+            }
+        };
+
+        let write = self.begin_write(key.as_str(), expected.as_ref()).await?;
+        Ok(write.tee(stream))
+    }
+
+    async fn fill(
+        &self,
+        key: &str,
+        expected: Option<&Integrity>,
+        fill: impl std::future::Future<
+            Output = anyhow::Result<BoxStream<'static, Result<Bytes, R::Error>>>,
+        >,
+    ) -> anyhow::Result<()> {
+        let stream = fill.await?;
+        let mut write = self.begin_write(key, expected).await?;
+        pin_mut!(stream);
+        while let Some(chunk) = stream.next().await {
+            write.write(chunk.map_err(upstream_error)?).await?;
+        }
+        write.commit().await
+    }
+
+    async fn begin_write(
+        &self,
+        key: &str,
+        expected: Option<&Integrity>,
+    ) -> anyhow::Result<CacheWrite> {
+        let writer = cacache::WriteOpts::new()
+            .algorithm(self.algorithm.into())
+            .open_hash(self.cache_dir.as_path())
+            .await
+            .map_err(cache_error)?;
+        let expected = expected.and_then(Integrity::strongest).cloned();
+        let hasher = expected
+            .as_ref()
+            .map(|digest| Hasher::new(digest.algorithm))
+            .transpose()?;
+        Ok(CacheWrite {
+            cache_dir: self.cache_dir.clone(),
+            key: key.to_string(),
+            algorithm: self.algorithm,
+            writer,
+            size: 0,
+            expected: expected.zip(hasher),
+            metadata: None,
+            references: self.references.clone(),
+        })
+    }
+
+    // Packages published here have no abbreviated form upstream; theirs is derived from the
+    // full packument instead. So is one the upstream is rate limiting us on, when the full
+    // packument is already cached.
+    async fn fill_abbreviated(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, R::Error>>>
+    where
+        R::Error: std::error::Error + Send + Sync + 'static,
+    {
+        match self.inner.stream_abbreviated_packument(name).await {
+            Err(e)
+                if matches!(
+                    StorageError::of(&e),
+                    Some(StorageError::NotFound | StorageError::RateLimited { .. })
+                ) =>
+            {
+                let packument = self.fetch_packument(name).await?;
+                let data = Bytes::from(serde_json::to_vec(&packument.abbreviated()?)?);
+                Ok(futures::stream::once(async move { Ok(data) }).boxed())
+            }
+            result => result,
+        }
+    }
+
+    // Read from the abbreviated packument, which an install has just fetched, rather than the
+    // full one, which it never needs and which may be many times the size.
+    async fn advertised_integrity(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<Option<Integrity>>
+    where
+        R::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let document = collect_stream(self.stream_abbreviated_packument(name).await?).await?;
+        let mut dists: AdvertisedDists = serde_json::from_slice(document.as_slice())?;
+        Ok(dists
+            .versions
+            .remove(version)
+            .and_then(|version| version.dist.advertised_integrity()))
+    }
+
+    // Marked as written here: the only copy, never revalidated or evicted.
+    async fn write_ours(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        use tokio::io::AsyncWriteExt;
+        let mut writer = cacache::WriteOpts::new()
+            .algorithm(self.algorithm.into())
+            .metadata(serde_json::json!({ "written": true }))
+            .size(data.len())
+            .open(&self.cache_dir, key)
+            .await
+            .map_err(cache_error)?;
+        writer.write_all(data).await.map_err(StorageError::from)?;
+        let integrity = writer.commit().await.map_err(cache_error)?;
+        record_reference(&self.references, key, &integrity).await;
+        Ok(())
+    }
+
+    // Removing the index entry alone would leave the content on disk until the cache is
+    // verified, so the content goes too, unless another entry holds the same bytes: a tarball
+    // of the same artifact, or the abbreviated form of a packument no upstream abbreviated.
+    // With `keep_ours`, entries written here stay.
+    async fn evict(&self, key: &str, keep_ours: bool) -> anyhow::Result<()> {
+        let entry = cacache::metadata(&self.cache_dir, key)
+            .await
+            .map_err(cache_error)?;
+        let Some(entry) = entry else {
+            return Ok(());
+        };
+        if keep_ours && entry.metadata["written"].as_bool() == Some(true) {
+            return Ok(());
+        }
+
+        let mut references = self.references().await?;
+        cacache::remove(&self.cache_dir, key)
+            .await
+            .map_err(cache_error)?;
+        if references.remove(key) {
+            return Ok(());
+        }
+        cacache::remove_hash(&self.cache_dir, &entry.integrity)
+            .await
+            .map_err(cache_error)?;
+        Ok(())
+    }
+
+    async fn cached_metadata(&self, key: &str) -> anyhow::Result<Option<ContentMetadata>> {
+        let Some(metadata) = cacache::metadata(&self.cache_dir, key)
+            .await
+            .map_err(cache_error)?
+        else {
+            return Ok(None);
+        };
+
+        let integrity: Integrity = metadata.integrity.to_string().parse()?;
+        let Some(digest) = integrity.strongest() else {
+            return Ok(None);
+        };
+
+        Ok(Some(ContentMetadata {
+            size: metadata.size as u64,
+            digest: digest.clone(),
+        }))
+    }
+
+    // cacache already knows the size and integrity of anything it holds; on a miss, filling
+    // the entry computes them. An expired entry is revalidated first, so that HEAD agrees
+    // with the GET that follows it.
+    async fn metadata_or_fill(
+        &self,
+        key: String,
+        max_age: Option<Duration>,
+        fill: impl std::future::Future<
+            Output = anyhow::Result<BoxStream<'static, Result<Bytes, std::io::Error>>>,
+        >,
+    ) -> anyhow::Result<ContentMetadata> {
+        if !self.is_expired(key.as_str(), max_age).await? {
+            if let Some(metadata) = self.cached_metadata(key.as_str()).await? {
+                return Ok(metadata);
+            }
+        }
+
+        // Drained, since a miss is only cached as it's read.
+        fill.await?.try_for_each(|_| async { Ok(()) }).await?;
+        self.cached_metadata(key.as_str())
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("{} was not cached after filling", key))
     }
 }
 
@@ -33,55 +876,1004 @@ where
         name: &PackageIdentifier,
     ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
         let key = format!("packument:{}", name);
-        match cacache::Reader::open(&self.cache_dir, &key).await {
-            Ok(reader) => Ok(tokio_util::io::ReaderStream::new(reader).boxed()),
-
-            Err(cacache::Error::EntryNotFound(_, _)) => {
-                use tokio::io::AsyncWriteExt;
-                let stream = self.inner.stream_packument(name).await?;
-                let mut writer =
-                    cacache::Writer::create(self.cache_dir.as_path(), key.as_str()).await?;
-                pin_mut!(stream);
-                while let Some(chunk) = stream.next().await {
-                    let Ok(chunk) = chunk else {
-                        break;
-                    };
-                    writer.write_all(chunk.as_ref()).await?;
+        let refill = name.clone();
+        self.revalidate(name, key.as_str(), move |this| async move {
+            this.inner.stream_packument(&refill).await
+        })
+        .await?;
+        self.open_or_fill(key, async { Ok(None) }, || {
+            self.inner.stream_packument(name)
+        })
+        .await
+    }
+
+    // Looked up in an index of the packument, kept under its own key. An index built from an
+    // older revision, or one that won't parse, is rebuilt on first use, so writes needn't
+    // touch it.
+    async fn resolve_version(
+        &self,
+        name: &PackageIdentifier,
+        spec: &str,
+    ) -> anyhow::Result<Option<PackumentVersion>> {
+        let source = self.packument_metadata(name).await?.digest.to_string();
+        let key = format!("indexed:{}", name);
+        self.touch(key.as_str());
+        match cacache::read(&self.cache_dir, &key).await {
+            Ok(data) => match IndexedPackument::parse(data.as_slice()) {
+                Ok(indexed) if indexed.source() == source => {
+                    return Ok(indexed.resolve_version(spec)?);
                 }
-                writer.commit().await?;
+                _ => {}
+            },
+            Err(cacache::Error::EntryNotFound(_, _)) => {}
+            Err(e) => return Err(cache_error(e)),
+        }
+
+        let packument = self.fetch_packument(name).await?;
+        let data = indexed::encode(&packument, source.as_str())?;
+        let integrity =
+            cacache::write_with_algo(self.algorithm.into(), &self.cache_dir, key.as_str(), data)
+                .await
+                .map_err(cache_error)?;
+        record_reference(&self.references, key.as_str(), &integrity).await;
+        Ok(packument.resolve_version(spec).cloned())
+    }
 
-                return self.stream_packument(name).await;
+    // Each compressed copy is kept under its own key, recording the digest of the document it
+    // was made from. Like the index, one left behind by a write is remade on first use.
+    async fn stream_encoded_packument(
+        &self,
+        name: &PackageIdentifier,
+        abbreviated: bool,
+        encoding: ContentEncoding,
+    ) -> anyhow::Result<Option<BoxStream<'static, Result<Bytes, Self::Error>>>> {
+        use tokio::io::AsyncWriteExt;
+        if !self.encodings.contains(&encoding) {
+            return Ok(None);
+        }
+
+        let (form, source) = if abbreviated {
+            ("corgi", self.abbreviated_packument_metadata(name).await?)
+        } else {
+            ("packument", self.packument_metadata(name).await?)
+        };
+        let source = source.digest.to_string();
+        let key = format!("{}.{}:{}", form, encoding.as_str(), name);
+        self.touch(key.as_str());
+        let current = cacache::metadata(&self.cache_dir, &key)
+            .await
+            .map_err(cache_error)?
+            .is_some_and(|entry| entry.metadata["source"].as_str() == Some(source.as_str()));
+        if current {
+            match cacache::Reader::open(&self.cache_dir, &key).await {
+                Ok(reader) => return Ok(Some(tokio_util::io::ReaderStream::new(reader).boxed())),
+                Err(cacache::Error::EntryNotFound(_, _)) => {}
+                Err(e) => return Err(cache_error(e)),
             }
-            Err(e) => return Err(e.into()),
         }
+
+        let document = cacache::read(&self.cache_dir, format!("{}:{}", form, name))
+            .await
+            .map_err(cache_error)?;
+        let compressed = compress(encoding, document.as_slice())
+            .await
+            .map_err(StorageError::from)?;
+        let mut writer = cacache::WriteOpts::new()
+            .algorithm(self.algorithm.into())
+            .metadata(serde_json::json!({ "source": source }))
+            .open(&self.cache_dir, &key)
+            .await
+            .map_err(cache_error)?;
+        writer
+            .write_all(compressed.as_slice())
+            .await
+            .map_err(StorageError::from)?;
+        let integrity = writer.commit().await.map_err(cache_error)?;
+        record_reference(&self.references, key.as_str(), &integrity).await;
+        Ok(Some(
+            futures::stream::once(async move { Ok(Bytes::from(compressed)) }).boxed(),
+        ))
     }
 
+    // Cached under its own key: an abbreviated document must never be served to a client
+    // that asked for the full packument.
+    async fn stream_abbreviated_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        let key = format!("corgi:{}", name);
+        let refill = name.clone();
+        self.revalidate(name, key.as_str(), move |this| async move {
+            this.fill_abbreviated(&refill).await
+        })
+        .await?;
+        self.open_or_fill(key, async { Ok(None) }, || self.fill_abbreviated(name))
+            .await
+    }
+
+    // A published tarball never changes, so never expires. It's only cached if it matches
+    // what its version's `dist` advertises.
     async fn stream_tarball(
         &self,
         name: &PackageIdentifier,
         version: &str,
     ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        let expected = self.advertised_integrity(name, version);
+        self.open_or_fill(format!("tarball:{}:{}", name, version), expected, || {
+            self.inner.stream_tarball(name, version)
+        })
+        .await
+    }
+
+    async fn packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        self.metadata_or_fill(
+            format!("packument:{}", name),
+            self.max_age,
+            self.stream_packument(name),
+        )
+        .await
+    }
+
+    async fn abbreviated_packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        self.metadata_or_fill(
+            format!("corgi:{}", name),
+            self.max_age,
+            self.stream_abbreviated_packument(name),
+        )
+        .await
+    }
+
+    async fn tarball_metadata(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<ContentMetadata> {
+        self.metadata_or_fill(
+            format!("tarball:{}:{}", name, version),
+            None,
+            self.stream_tarball(name, version),
+        )
+        .await
+    }
+
+    // Marked as written here, so that it's never revalidated against an upstream that
+    // doesn't have it, or has an older one.
+    async fn put_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &Packument,
+    ) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(packument)?;
+        self.write_ours(format!("packument:{}", name).as_str(), data.as_slice())
+            .await?;
+
+        // The abbreviated form is refetched from upstream on next use; it would otherwise
+        // keep advertising the old versions and tags.
+        let corgi = format!("corgi:{}", name);
+        cacache::remove(&self.cache_dir, corgi.as_str())
+            .await
+            .map_err(cache_error)?;
+        forget_reference(&self.references, corgi.as_str()).await;
+        Ok(())
+    }
+
+    async fn update_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &mut Packument,
+    ) -> anyhow::Result<()> {
+        let _update = self.updates.lock().await;
+        put_if_unchanged(self, name, packument).await
+    }
+
+    async fn put_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: Bytes,
+    ) -> anyhow::Result<()> {
+        self.write_ours(
+            format!("tarball:{}:{}", name, version).as_str(),
+            tarball.as_ref(),
+        )
+        .await
+    }
+
+    async fn delete_tarball(&self, name: &PackageIdentifier, version: &str) -> anyhow::Result<()> {
+        self.evict(format!("tarball:{}:{}", name, version).as_str(), false)
+            .await?;
+        self.evict(format!("attestations:{}@{}", name, version).as_str(), false)
+            .await
+    }
+
+    // Written a chunk at a time, as a fill is, and marked as written here as put_tarball is.
+    async fn put_tarball_stream(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        mut tarball: BoxStream<'static, Result<Bytes, std::io::Error>>,
+    ) -> anyhow::Result<()> {
         let key = format!("tarball:{}:{}", name, version);
-        match cacache::Reader::open(&self.cache_dir, &key).await {
-            Ok(reader) => Ok(tokio_util::io::ReaderStream::new(reader).boxed()),
-
-            Err(cacache::Error::EntryNotFound(_, _)) => {
-                use tokio::io::AsyncWriteExt;
-                let stream = self.inner.stream_tarball(name, version).await?;
-                let mut writer =
-                    cacache::Writer::create(self.cache_dir.as_path(), key.as_str()).await?;
-                pin_mut!(stream);
-                while let Some(chunk) = stream.next().await {
-                    let Ok(chunk) = chunk else {
-                        break;
-                    };
-                    writer.write_all(chunk.as_ref()).await?;
+        let mut write = self.begin_write(key.as_str(), None).await?;
+        write.metadata = Some(serde_json::json!({ "written": true }));
+        while let Some(chunk) = tarball.next().await {
+            write.write(chunk.map_err(StorageError::from)?).await?;
+        }
+        write.commit().await
+    }
+
+    // Everything cached of the package goes, whoever wrote it; the upstream may still have it.
+    async fn delete_package(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        let tarballs = format!("tarball:{}:", name);
+        let attestations = format!("attestations:{}@", name);
+        for entry in self.entries().await? {
+            if entry.key.starts_with(tarballs.as_str())
+                || entry.key.starts_with(attestations.as_str())
+            {
+                self.evict(entry.key.as_str(), false).await?;
+            }
+        }
+        self.evict(format!("packument:{}", name).as_str(), false)
+            .await?;
+        self.invalidate(name).await
+    }
+
+    // What's derived from the packument goes whoever wrote it; it's remade on next use.
+    async fn invalidate(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        self.inner.invalidate(name).await?;
+        self.evict(format!("packument:{}", name).as_str(), true)
+            .await?;
+        self.evict(format!("corgi:{}", name).as_str(), false)
+            .await?;
+        self.evict(format!("indexed:{}", name).as_str(), false)
+            .await?;
+        for form in ["packument", "corgi"] {
+            for encoding in ContentEncoding::ALL {
+                self.evict(
+                    format!("{}.{}:{}", form, encoding.as_str(), name).as_str(),
+                    false,
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn invalidate_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<()> {
+        self.inner.invalidate_tarball(name, version).await?;
+        self.evict(format!("tarball:{}:{}", name, version).as_str(), true)
+            .await?;
+        self.evict(format!("attestations:{}@{}", name, version).as_str(), true)
+            .await
+    }
+
+    // Ours are written on publish; anything else is the upstream's, cached once fetched.
+    async fn fetch_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<serde_json::Value> {
+        let key = format!("attestations:{}@{}", name, version);
+        self.touch(key.as_str());
+        match cacache::read(&self.cache_dir, &key).await {
+            Ok(data) => return Ok(serde_json::from_slice(data.as_slice())?),
+            Err(cacache::Error::EntryNotFound(_, _)) => {}
+            Err(e) => return Err(cache_error(e)),
+        }
+
+        let attestations = self.inner.fetch_attestations(name, version).await?;
+        let data = serde_json::to_vec(&attestations)?;
+        let integrity =
+            cacache::write_with_algo(self.algorithm.into(), &self.cache_dir, key.as_str(), data)
+                .await
+                .map_err(cache_error)?;
+        record_reference(&self.references, key.as_str(), &integrity).await;
+        Ok(attestations)
+    }
+
+    async fn put_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        attestations: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(attestations)?;
+        self.write_ours(
+            format!("attestations:{}@{}", name, version).as_str(),
+            data.as_slice(),
+        )
+        .await
+    }
+
+    async fn collect_garbage(&self) -> anyhow::Result<GarbageCollection> {
+        self.collect_older_than(GC_GRACE).await
+    }
+
+    async fn tarball_stats(&self) -> anyhow::Result<TarballStats> {
+        let mut stats = TarballStats::default();
+        let mut blobs = HashSet::new();
+        for entry in self.entries().await? {
+            if !entry.key.starts_with("tarball:") {
+                continue;
+            }
+            stats.tarballs += 1;
+            stats.size += entry.size as u64;
+            if blobs.insert(entry.integrity.to_string()) {
+                stats.stored += entry.size as u64;
+            }
+        }
+        stats.blobs = blobs.len();
+        Ok(stats)
+    }
+
+    async fn list_packages(&self) -> anyhow::Result<Vec<PackageIdentifier>> {
+        let cache_dir = self.cache_dir.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut names = Vec::new();
+            for entry in cacache::list_sync(cache_dir) {
+                let entry = entry?;
+                if let Some(name) = entry.key.strip_prefix("packument:") {
+                    names.push(name.parse()?);
+                }
+            }
+            Ok(names)
+        })
+        .await?
+    }
+
+    // Only the upstream keeps a search index; what's cached here is a subset of it.
+    async fn search(&self, query: &SearchQuery) -> anyhow::Result<serde_json::Value> {
+        self.inner.search(query).await
+    }
+
+    async fn public_keys(&self) -> anyhow::Result<Vec<PublicKey>> {
+        self.inner.public_keys().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policies::package_storage::in_memory::InMemoryPackageStorage;
+    use crate::policies::package_storage::{collect_stream, TestDir};
+
+    fn packument(name: &str, latest: &str) -> Packument {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "dist-tags": { "latest": latest }
+        }))
+        .unwrap()
+    }
+
+    // Holds each packument read until a permit is added, to keep a refill in flight.
+    #[derive(Clone, Debug)]
+    struct Gated {
+        inner: InMemoryPackageStorage,
+        permits: Arc<tokio::sync::Semaphore>,
+    }
+
+    #[async_trait::async_trait]
+    impl PackageStorage for Gated {
+        type Error = std::io::Error;
+
+        async fn stream_packument(
+            &self,
+            name: &PackageIdentifier,
+        ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+            self.permits.acquire().await?.forget();
+            self.inner.stream_packument(name).await
+        }
+
+        async fn stream_tarball(
+            &self,
+            name: &PackageIdentifier,
+            version: &str,
+        ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+            self.inner.stream_tarball(name, version).await
+        }
+    }
+
+    // Counts the full packuments read, for checking which reads a fill makes.
+    #[derive(Clone, Debug)]
+    struct Counted {
+        inner: InMemoryPackageStorage,
+        packuments: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl PackageStorage for Counted {
+        type Error = std::io::Error;
+
+        async fn stream_packument(
+            &self,
+            name: &PackageIdentifier,
+        ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+            self.packuments
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.inner.stream_packument(name).await
+        }
+
+        async fn stream_abbreviated_packument(
+            &self,
+            name: &PackageIdentifier,
+        ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+            self.inner.stream_abbreviated_packument(name).await
+        }
+
+        async fn stream_tarball(
+            &self,
+            name: &PackageIdentifier,
+            version: &str,
+        ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+            self.inner.stream_tarball(name, version).await
+        }
+    }
+
+    // Sends the first half of each tarball at once, and the rest once a permit is added.
+    #[derive(Clone, Debug)]
+    struct Trickled {
+        inner: InMemoryPackageStorage,
+        permits: Arc<tokio::sync::Semaphore>,
+    }
+
+    #[async_trait::async_trait]
+    impl PackageStorage for Trickled {
+        type Error = std::io::Error;
+
+        async fn stream_packument(
+            &self,
+            name: &PackageIdentifier,
+        ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+            self.inner.stream_packument(name).await
+        }
+
+        async fn stream_tarball(
+            &self,
+            name: &PackageIdentifier,
+            version: &str,
+        ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+            let mut data =
+                Bytes::from(collect_stream(self.inner.stream_tarball(name, version).await?).await?);
+            let first = data.split_to(data.len() / 2);
+            let permits = self.permits.clone();
+            let rest = async move {
+                permits
+                    .acquire()
+                    .await
+                    .map_err(std::io::Error::other)?
+                    .forget();
+                Ok(data)
+            };
+            Ok(futures::stream::once(async move { Ok(first) })
+                .chain(futures::stream::once(rest))
+                .boxed())
+        }
+    }
+
+    fn latest(packument: &Packument) -> Option<&str> {
+        packument.dist_tags.as_ref()?.latest.as_deref()
+    }
+
+    #[tokio::test]
+    async fn test_expired_packuments_are_revalidated() {
+        let cache_dir = TestDir::new("registry-read-through");
+        let upstream = InMemoryPackageStorage::new();
+        let storage = ReadThrough::new(&cache_dir, upstream.clone())
+            .with_max_age(Some(Duration::from_millis(10)));
+        let name: PackageIdentifier = "left-pad".parse().unwrap();
+        let ours: PackageIdentifier = "ours".parse().unwrap();
+
+        upstream
+            .put_packument(&name, &packument("left-pad", "1.0.0"))
+            .await
+            .unwrap();
+        storage
+            .put_packument(&ours, &packument("ours", "1.0.0"))
+            .await
+            .unwrap();
+        upstream
+            .put_packument(&ours, &packument("ours", "2.0.0"))
+            .await
+            .unwrap();
+        let cached = storage.fetch_packument(&name).await.unwrap();
+        assert_eq!(latest(&cached), Some("1.0.0"));
+
+        upstream
+            .put_packument(&name, &packument("left-pad", "1.1.0"))
+            .await
+            .unwrap();
+        let fresh = storage.fetch_packument(&name).await.unwrap();
+        assert_eq!(latest(&fresh), Some("1.0.0"));
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let revalidated = storage.fetch_packument(&name).await.unwrap();
+        assert_eq!(latest(&revalidated), Some("1.1.0"));
+
+        // Written here, so never replaced by the upstream's copy.
+        let written = storage.fetch_packument(&ours).await.unwrap();
+        assert_eq!(latest(&written), Some("1.0.0"));
+    }
+
+    #[tokio::test]
+    async fn test_invalidate() {
+        let cache_dir = TestDir::new("registry-read-through");
+        let upstream = InMemoryPackageStorage::new();
+        let storage = ReadThrough::new(&cache_dir, upstream.clone());
+        let name: PackageIdentifier = "left-pad".parse().unwrap();
+        let ours: PackageIdentifier = "ours".parse().unwrap();
+
+        upstream
+            .put_packument(&name, &packument("left-pad", "1.0.0"))
+            .await
+            .unwrap();
+        upstream
+            .put_tarball(&name, "1.0.0", Bytes::from_static(b"poisoned"))
+            .await
+            .unwrap();
+        storage.fetch_packument(&name).await.unwrap();
+        storage.tarball_metadata(&name, "1.0.0").await.unwrap();
+        storage
+            .put_packument(&ours, &packument("ours", "1.0.0"))
+            .await
+            .unwrap();
+
+        upstream
+            .put_packument(&name, &packument("left-pad", "1.1.0"))
+            .await
+            .unwrap();
+        upstream
+            .put_tarball(&name, "1.0.0", Bytes::from_static(b"tarball"))
+            .await
+            .unwrap();
+        storage.invalidate(&name).await.unwrap();
+        storage.invalidate_tarball(&name, "1.0.0").await.unwrap();
+        storage.invalidate(&ours).await.unwrap();
+
+        let refetched = storage.fetch_packument(&name).await.unwrap();
+        assert_eq!(latest(&refetched), Some("1.1.0"));
+        let tarball = storage.stream_tarball(&name, "1.0.0").await.unwrap();
+        assert_eq!(collect_stream(tarball).await.unwrap(), b"tarball");
+        let kept = storage.fetch_packument(&ours).await.unwrap();
+        assert_eq!(latest(&kept), Some("1.0.0"));
+    }
+
+    #[tokio::test]
+    async fn test_write_and_delete_package() {
+        let cache_dir = TestDir::new("registry-read-through");
+        let storage = ReadThrough::new(&cache_dir, InMemoryPackageStorage::new());
+        let name: PackageIdentifier = "@corp/app".parse().unwrap();
+        storage
+            .put_packument(&name, &packument("@corp/app", "1.0.0"))
+            .await
+            .unwrap();
+        let chunks = futures::stream::iter([
+            Ok(Bytes::from_static(b"tar")),
+            Ok(Bytes::from_static(b"ball")),
+        ]);
+        storage
+            .put_tarball_stream(&name, "1.0.0", chunks.boxed())
+            .await
+            .unwrap();
+        storage
+            .put_attestations(&name, "1.0.0", &serde_json::json!([]))
+            .await
+            .unwrap();
+
+        // Streamed in, it's as much ours as a tarball put whole.
+        let entry = cacache::metadata(&cache_dir, "tarball:@corp/app:1.0.0")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (entry.size, entry.metadata["written"].as_bool()),
+            (7, Some(true))
+        );
+        storage.invalidate_tarball(&name, "1.0.0").await.unwrap();
+        let tarball = storage.stream_tarball(&name, "1.0.0").await.unwrap();
+        assert_eq!(collect_stream(tarball).await.unwrap(), b"tarball");
+
+        storage.delete_package(&name).await.unwrap();
+        assert!(storage.entries().await.unwrap().is_empty());
+        assert!(matches!(
+            StorageError::of(&storage.fetch_packument(&name).await.unwrap_err()),
+            Some(StorageError::NotFound)
+        ));
+        assert!(storage.stream_tarball(&name, "1.0.0").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_evict_to_budget() {
+        let cache_dir = TestDir::new("registry-read-through");
+        let upstream = InMemoryPackageStorage::new();
+        let storage = ReadThrough::new(&cache_dir, upstream.clone()).with_max_size(Some(2000));
+        let name: PackageIdentifier = "left-pad".parse().unwrap();
+        upstream
+            .put_packument(&name, &packument("left-pad", "1.0.2"))
+            .await
+            .unwrap();
+
+        // The abbreviated packument is read to check each tarball filled; reading 1.0.0 and
+        // 1.0.2 again leaves it and 1.0.1 the least recently used.
+        for version in ["1.0.0", "1.0.1", "1.0.2"] {
+            let tarball = Bytes::from(format!("{:>1000}", version));
+            upstream.put_tarball(&name, version, tarball).await.unwrap();
+            storage.tarball_metadata(&name, version).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        storage
+            .put_tarball(&name, "2.0.0", Bytes::from(vec![0; 10000]))
+            .await
+            .unwrap();
+        for version in ["1.0.0", "1.0.2"] {
+            drop(storage.stream_tarball(&name, version).await.unwrap());
+        }
+
+        let eviction = storage.evict_to_budget().await.unwrap();
+        assert!(eviction.size > 3000 && eviction.size < 3500);
+        assert_eq!(eviction.evicted, 2);
+        assert!(cacache::metadata(&cache_dir, "tarball:left-pad:1.0.1")
+            .await
+            .unwrap()
+            .is_none());
+        for version in ["1.0.0", "1.0.2", "2.0.0"] {
+            let key = format!("tarball:left-pad:{}", version);
+            assert!(cacache::metadata(&cache_dir, key).await.unwrap().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collect_garbage() {
+        let cache_dir = TestDir::new("registry-read-through");
+        let upstream = InMemoryPackageStorage::new();
+        let storage = ReadThrough::new(&cache_dir, upstream.clone());
+        let listing = |name: &str, version: &str| -> Packument {
+            serde_json::from_value(serde_json::json!({
+                "name": name,
+                "versions": {
+                    version: {
+                        "_id": format!("{}@{}", name, version),
+                        "name": name,
+                        "version": version,
+                        "dist": { "tarball": "", "shasum": "" }
+                    }
+                }
+            }))
+            .unwrap()
+        };
+        let left_pad: PackageIdentifier = "left-pad".parse().unwrap();
+        let ours: PackageIdentifier = "ours".parse().unwrap();
+        let gone: PackageIdentifier = "gone".parse().unwrap();
+        let uncached: PackageIdentifier = "uncached".parse().unwrap();
+
+        // Unpublished upstream after it was cached, and published here but since removed.
+        upstream
+            .put_packument(&left_pad, &listing("left-pad", "1.0.0"))
+            .await
+            .unwrap();
+        storage.fetch_packument(&left_pad).await.unwrap();
+        for version in ["0.9.0", "1.0.0"] {
+            let tarball = Bytes::from(format!("left-pad {}", version));
+            upstream
+                .put_tarball(&left_pad, version, tarball)
+                .await
+                .unwrap();
+            storage.tarball_metadata(&left_pad, version).await.unwrap();
+        }
+        storage
+            .put_packument(&ours, &listing("ours", "1.0.0"))
+            .await
+            .unwrap();
+        for version in ["1.0.0", "2.0.0"] {
+            let tarball = Bytes::from(format!("ours {}", version));
+            storage.put_tarball(&ours, version, tarball).await.unwrap();
+        }
+        storage
+            .put_tarball(&gone, "1.0.0", Bytes::from_static(b"gone"))
+            .await
+            .unwrap();
+        upstream
+            .put_packument(&uncached, &listing("uncached", "1.0.0"))
+            .await
+            .unwrap();
+        upstream
+            .put_tarball(&uncached, "1.0.0", Bytes::from_static(b"uncached"))
+            .await
+            .unwrap();
+        storage.tarball_metadata(&uncached, "1.0.0").await.unwrap();
+        storage.invalidate(&uncached).await.unwrap();
+
+        // An entry whose content was lost, and content whose entry was.
+        let lost = cacache::write(&cache_dir, "lost", b"lost").await.unwrap();
+        cacache::remove_hash(&cache_dir, &lost).await.unwrap();
+        cacache::write(&cache_dir, "unindexed", b"unindexed")
+            .await
+            .unwrap();
+        cacache::remove(&cache_dir, "unindexed").await.unwrap();
+
+        let young = storage.collect_garbage().await.unwrap();
+        assert_eq!(young, GarbageCollection::default());
+
+        let collected = storage.collect_older_than(Duration::ZERO).await.unwrap();
+        assert_eq!(collected.orphaned_tarballs, 3);
+        assert_eq!(collected.dangling_entries, 2);
+        assert_eq!(collected.reclaimed, 14 + 10 + 4 + 9);
+        for key in [
+            "tarball:left-pad:0.9.0",
+            "tarball:ours:2.0.0",
+            "tarball:gone:1.0.0",
+        ] {
+            assert!(cacache::metadata(&cache_dir, key).await.unwrap().is_none());
+        }
+        for key in [
+            "tarball:left-pad:1.0.0",
+            "tarball:ours:1.0.0",
+            "tarball:uncached:1.0.0",
+        ] {
+            assert!(cacache::read(&cache_dir, key).await.is_ok());
+        }
+        assert!(cacache::metadata(&cache_dir, "lost")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tarball_dedup() {
+        let cache_dir = TestDir::new("registry-read-through");
+        let storage = ReadThrough::new(&cache_dir, InMemoryPackageStorage::new());
+        let left_pad: PackageIdentifier = "left-pad".parse().unwrap();
+        let fork: PackageIdentifier = "@corp/left-pad".parse().unwrap();
+        let tarball = Bytes::from(vec![7; 1000]);
+
+        // Republished unchanged, and published again under another name.
+        for version in ["1.0.0", "1.0.1"] {
+            storage
+                .put_tarball(&left_pad, version, tarball.clone())
+                .await
+                .unwrap();
+        }
+        storage
+            .put_tarball(&fork, "1.0.0", tarball.clone())
+            .await
+            .unwrap();
+        storage
+            .put_tarball(&left_pad, "2.0.0", Bytes::from(vec![8; 500]))
+            .await
+            .unwrap();
+
+        let stats = storage.tarball_stats().await.unwrap();
+        assert_eq!((stats.tarballs, stats.blobs), (4, 2));
+        assert_eq!(
+            (stats.size, stats.stored, stats.saved()),
+            (3500, 1500, 2000)
+        );
+
+        // The others still point at the content a deleted version shared.
+        storage.delete_tarball(&left_pad, "1.0.0").await.unwrap();
+        storage.invalidate_tarball(&fork, "1.0.0").await.unwrap();
+        let kept = storage.stream_tarball(&left_pad, "1.0.1").await.unwrap();
+        assert_eq!(collect_stream(kept).await.unwrap(), tarball);
+        let stats = storage.tarball_stats().await.unwrap();
+        assert_eq!((stats.tarballs, stats.blobs, stats.saved()), (3, 2, 1000));
+
+        storage.delete_tarball(&fork, "1.0.0").await.unwrap();
+        storage.delete_tarball(&left_pad, "1.0.1").await.unwrap();
+        let content = content_files(&cache_dir.join("content-v2")).unwrap();
+        assert_eq!(
+            content.iter().map(|(_, size, _)| *size).collect::<Vec<_>>(),
+            vec![500]
+        );
+
+        // A version written over no longer holds what it did.
+        storage
+            .put_tarball(&left_pad, "3.0.0", Bytes::from(vec![8; 500]))
+            .await
+            .unwrap();
+        storage
+            .put_tarball(&left_pad, "2.0.0", Bytes::from(vec![9; 250]))
+            .await
+            .unwrap();
+        storage.delete_tarball(&left_pad, "3.0.0").await.unwrap();
+        let content = content_files(&cache_dir.join("content-v2")).unwrap();
+        assert_eq!(
+            content.iter().map(|(_, size, _)| *size).collect::<Vec<_>>(),
+            vec![250]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tarball_integrity() {
+        let cache_dir = TestDir::new("registry-read-through");
+        let upstream = InMemoryPackageStorage::new();
+        let storage = ReadThrough::new(&cache_dir, upstream.clone());
+        let name: PackageIdentifier = "left-pad".parse().unwrap();
+        let integrity = crate::hashing::Digest::compute(Algorithm::Sha512, b"tarball").unwrap();
+        let published: Packument = serde_json::from_value(serde_json::json!({
+            "name": "left-pad",
+            "versions": {
+                "1.0.0": {
+                    "_id": "left-pad@1.0.0",
+                    "name": "left-pad",
+                    "version": "1.0.0",
+                    "dist": { "tarball": "", "shasum": "", "integrity": integrity.to_string() }
                 }
-                writer.commit().await?;
+            }
+        }))
+        .unwrap();
+        upstream.put_packument(&name, &published).await.unwrap();
+
+        upstream
+            .put_tarball(&name, "1.0.0", Bytes::from_static(b"tampered"))
+            .await
+            .unwrap();
+        let tampered = storage.stream_tarball(&name, "1.0.0").await.unwrap();
+        let refused = collect_stream(tampered).await.unwrap_err();
+        assert!(matches!(
+            StorageError::of(&refused),
+            Some(StorageError::Corrupt(_))
+        ));
+        assert!(cacache::metadata(&cache_dir, "tarball:left-pad:1.0.0")
+            .await
+            .unwrap()
+            .is_none());
+
+        upstream
+            .put_tarball(&name, "1.0.0", Bytes::from_static(b"tarball"))
+            .await
+            .unwrap();
+        // Streamed from the upstream as it's cached, so only cached once it's been read.
+        let tarball = storage.stream_tarball(&name, "1.0.0").await.unwrap();
+        assert!(cacache::metadata(&cache_dir, "tarball:left-pad:1.0.0")
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(collect_stream(tarball).await.unwrap(), b"tarball");
+        let cached = storage.tarball_metadata(&name, "1.0.0").await.unwrap();
+        assert_eq!(cached.size, 7);
+    }
 
-                return self.stream_tarball(name, version).await;
+    #[tokio::test]
+    async fn test_cold_fills_stream() {
+        let cache_dir = TestDir::new("registry-read-through");
+        let upstream = Trickled {
+            inner: InMemoryPackageStorage::new(),
+            permits: Arc::new(tokio::sync::Semaphore::new(0)),
+        };
+        let storage = ReadThrough::new(&cache_dir, upstream.clone());
+        let name: PackageIdentifier = "left-pad".parse().unwrap();
+        let integrity = crate::hashing::Digest::compute(Algorithm::Sha512, b"tarball").unwrap();
+        let published: Packument = serde_json::from_value(serde_json::json!({
+            "name": "left-pad",
+            "versions": {
+                "1.0.0": {
+                    "_id": "left-pad@1.0.0",
+                    "name": "left-pad",
+                    "version": "1.0.0",
+                    "dist": { "tarball": "", "shasum": "", "integrity": integrity.to_string() }
+                }
             }
-            Err(e) => return Err(e.into()),
+        }))
+        .unwrap();
+        upstream
+            .inner
+            .put_packument(&name, &published)
+            .await
+            .unwrap();
+        upstream
+            .inner
+            .put_tarball(&name, "1.0.0", Bytes::from_static(b"tarball"))
+            .await
+            .unwrap();
+        let cached = || cacache::metadata(&cache_dir, "tarball:left-pad:1.0.0");
+
+        // What the upstream has sent is passed on before the rest arrives. A client that
+        // hangs up then leaves nothing cached.
+        let mut tarball = storage.stream_tarball(&name, "1.0.0").await.unwrap();
+        let first = tarball.next().await.unwrap().unwrap();
+        assert_eq!(first.as_ref(), b"tar");
+        assert!(cached().await.unwrap().is_none());
+        drop(tarball);
+        assert!(cached().await.unwrap().is_none());
+
+        upstream.permits.add_permits(1);
+        let tarball = storage.stream_tarball(&name, "1.0.0").await.unwrap();
+        assert_eq!(collect_stream(tarball).await.unwrap(), b"tarball");
+        assert_eq!(cached().await.unwrap().unwrap().size, 7);
+    }
+
+    #[tokio::test]
+    async fn test_tarball_fill_reads_abbreviated_packument() {
+        let cache_dir = TestDir::new("registry-read-through");
+        let upstream = Counted {
+            inner: InMemoryPackageStorage::new(),
+            packuments: Default::default(),
+        };
+        let storage = ReadThrough::new(&cache_dir, upstream.clone());
+        let name: PackageIdentifier = "left-pad".parse().unwrap();
+        let integrity = crate::hashing::Digest::compute(Algorithm::Sha512, b"tarball").unwrap();
+        let published: Packument = serde_json::from_value(serde_json::json!({
+            "name": "left-pad",
+            "versions": {
+                "1.0.0": {
+                    "_id": "left-pad@1.0.0",
+                    "name": "left-pad",
+                    "version": "1.0.0",
+                    "dist": { "tarball": "", "shasum": "", "integrity": integrity.to_string() }
+                }
+            }
+        }))
+        .unwrap();
+        upstream
+            .inner
+            .put_packument(&name, &published)
+            .await
+            .unwrap();
+        upstream
+            .inner
+            .put_tarball(&name, "1.0.0", Bytes::from_static(b"tarball"))
+            .await
+            .unwrap();
+
+        let tarball = storage.stream_tarball(&name, "1.0.0").await.unwrap();
+        assert_eq!(collect_stream(tarball).await.unwrap(), b"tarball");
+        assert_eq!(
+            upstream
+                .packuments
+                .load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+        assert!(cacache::metadata(&cache_dir, "packument:left-pad")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stale_while_revalidate() {
+        let cache_dir = TestDir::new("registry-read-through");
+        let upstream = InMemoryPackageStorage::new();
+        let permits = Arc::new(tokio::sync::Semaphore::new(1));
+        let gated = Gated {
+            inner: upstream.clone(),
+            permits: permits.clone(),
+        };
+        let tasks = TaskRegistry::new();
+        let storage = ReadThrough::new(&cache_dir, gated)
+            .with_max_age(Some(Duration::from_millis(10)))
+            .with_stale_while_revalidate(tasks.clone());
+        let name: PackageIdentifier = "left-pad".parse().unwrap();
+
+        upstream
+            .put_packument(&name, &packument("left-pad", "1.0.0"))
+            .await
+            .unwrap();
+        storage.fetch_packument(&name).await.unwrap();
+        upstream
+            .put_packument(&name, &packument("left-pad", "1.1.0"))
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let stale = storage.fetch_packument(&name).await.unwrap();
+        assert_eq!(latest(&stale), Some("1.0.0"));
+        storage.fetch_packument(&name).await.unwrap();
+        assert_eq!(tasks.running("cache:revalidate"), 1);
+
+        permits.add_permits(1);
+        while tasks.running("cache:revalidate") > 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
         }
+        let revalidated = storage.fetch_packument(&name).await.unwrap();
+        assert_eq!(latest(&revalidated), Some("1.1.0"));
     }
 }
@@ -0,0 +1,470 @@
+use std::time::Duration;
+
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+use futures_util::{StreamExt, TryStreamExt};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+
+use crate::models::{PackageIdentifier, Packument, PackumentVersion};
+use crate::policies::PackageStorage;
+use crate::signing::PublicKey;
+
+use super::{
+    ByteRange, ContentEncoding, ContentMetadata, GarbageCollection, PackageChange, SearchQuery,
+    TarballStats,
+};
+
+const DEFAULT_PREFIX: &str = "registry:";
+const DEFAULT_PACKUMENT_TTL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_TARBALL_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_MAX_TARBALL_SIZE: usize = 4 * 1024 * 1024;
+
+/// Caches packuments and tarballs from another storage in Redis, so that several registry
+/// instances can share one hot cache in front of their common storage or upstream.
+///
+/// Every entry expires after the TTL for its kind; packuments written through this storage
+/// are dropped from the cache at once, but ones changed behind its back are served stale
+/// until they expire. Redis is only ever a cache: when it can't be reached, requests go
+/// straight to the inner storage.
+#[derive(Clone)]
+pub struct RedisCache<R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static> {
+    inner: R,
+    connection: ConnectionManager,
+    prefix: String,
+    packument_ttl: Duration,
+    tarball_ttl: Duration,
+    max_tarball_size: usize,
+}
+
+impl<R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static> std::fmt::Debug
+    for RedisCache<R>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisCache")
+            .field("inner", &self.inner)
+            .field("prefix", &self.prefix)
+            .field("packument_ttl", &self.packument_ttl)
+            .field("tarball_ttl", &self.tarball_ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R> RedisCache<R>
+where
+    R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static,
+    <R as PackageStorage>::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Connect to the Redis server at `url` (`redis://host:port/db`). The connection is
+    /// re-established on its own if it drops.
+    pub async fn connect(inner: R, url: &str) -> anyhow::Result<Self> {
+        let client = redis::Client::open(url)?;
+        Ok(Self {
+            inner,
+            connection: ConnectionManager::new(client).await?,
+            prefix: DEFAULT_PREFIX.to_string(),
+            packument_ttl: DEFAULT_PACKUMENT_TTL,
+            tarball_ttl: DEFAULT_TARBALL_TTL,
+            max_tarball_size: DEFAULT_MAX_TARBALL_SIZE,
+        })
+    }
+
+    /// Prefix every key with `prefix`, so that registries sharing a Redis server don't share
+    /// entries. `registry:` by default.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// How long packuments (full and abbreviated) stay cached. Five minutes by default.
+    pub fn with_packument_ttl(mut self, ttl: Duration) -> Self {
+        self.packument_ttl = ttl;
+        self
+    }
+
+    /// How long tarballs stay cached. A published tarball never changes, so a day by default.
+    pub fn with_tarball_ttl(mut self, ttl: Duration) -> Self {
+        self.tarball_ttl = ttl;
+        self
+    }
+
+    /// Tarballs larger than this are served from the inner storage every time. 4MiB by
+    /// default.
+    pub fn with_max_tarball_size(mut self, size: usize) -> Self {
+        self.max_tarball_size = size;
+        self
+    }
+
+    fn key(&self, kind: &str, name: &PackageIdentifier, version: Option<&str>) -> String {
+        match version {
+            Some(version) => format!("{}{}:{}:{}", self.prefix, kind, name, version),
+            None => format!("{}{}:{}", self.prefix, kind, name),
+        }
+    }
+
+    // A failure to read the cache is a miss.
+    async fn cached(&self, key: &str) -> Option<Bytes> {
+        let mut connection = self.connection.clone();
+        match connection.get::<_, Option<Vec<u8>>>(key).await {
+            Ok(data) => data.map(Bytes::from),
+            Err(e) => {
+                tracing::warn!(key, error = ?e, "could not read from the redis cache");
+                None
+            }
+        }
+    }
+
+    async fn store(&self, key: &str, data: &[u8], ttl: Duration) {
+        let mut connection = self.connection.clone();
+        let seconds = ttl.as_secs().max(1) as usize;
+        if let Err(e) = connection.set_ex::<_, _, ()>(key, data, seconds).await {
+            tracing::warn!(key, error = ?e, "could not write to the redis cache");
+        }
+    }
+
+    // Entries that can't be dropped are left to expire.
+    async fn forget(&self, keys: Vec<String>) {
+        let mut connection = self.connection.clone();
+        if let Err(e) = connection.del::<_, ()>(keys.as_slice()).await {
+            tracing::warn!(?keys, error = ?e, "could not drop entries from the redis cache");
+        }
+    }
+
+    async fn read_through(
+        &self,
+        key: String,
+        ttl: Duration,
+        max_size: usize,
+        fill: impl std::future::Future<
+            Output = anyhow::Result<BoxStream<'static, Result<Bytes, R::Error>>>,
+        >,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, R::Error>>> {
+        let data = match self.cached(key.as_str()).await {
+            Some(data) => data,
+            None => {
+                let chunks: Vec<Bytes> = fill.await?.try_collect().await?;
+                let data = Bytes::from(chunks.concat());
+                if data.len() <= max_size {
+                    self.store(key.as_str(), data.as_ref(), ttl).await;
+                }
+                data
+            }
+        };
+
+        Ok(futures::stream::once(async move { Ok(data) }).boxed())
+    }
+
+    async fn forget_packument(&self, name: &PackageIdentifier) {
+        self.forget(vec![
+            self.key("packument", name, None),
+            self.key("corgi", name, None),
+        ])
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl<R> PackageStorage for RedisCache<R>
+where
+    R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static,
+    <R as PackageStorage>::Error: std::error::Error + Send + Sync + 'static,
+{
+    type Error = R::Error;
+
+    async fn stream_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.read_through(
+            self.key("packument", name, None),
+            self.packument_ttl,
+            usize::MAX,
+            self.inner.stream_packument(name),
+        )
+        .await
+    }
+
+    async fn resolve_version(
+        &self,
+        name: &PackageIdentifier,
+        spec: &str,
+    ) -> anyhow::Result<Option<PackumentVersion>> {
+        self.inner.resolve_version(name, spec).await
+    }
+
+    async fn stream_encoded_packument(
+        &self,
+        name: &PackageIdentifier,
+        abbreviated: bool,
+        encoding: ContentEncoding,
+    ) -> anyhow::Result<Option<BoxStream<'static, Result<Bytes, Self::Error>>>> {
+        self.inner
+            .stream_encoded_packument(name, abbreviated, encoding)
+            .await
+    }
+
+    async fn stream_abbreviated_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.read_through(
+            self.key("corgi", name, None),
+            self.packument_ttl,
+            usize::MAX,
+            self.inner.stream_abbreviated_packument(name),
+        )
+        .await
+    }
+
+    async fn stream_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.read_through(
+            self.key("tarball", name, Some(version)),
+            self.tarball_ttl,
+            self.max_tarball_size,
+            self.inner.stream_tarball(name, version),
+        )
+        .await
+    }
+
+    // Delegated, so that HEAD and GET agree with the inner storage's digest.
+    async fn packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        self.inner.packument_metadata(name).await
+    }
+
+    async fn abbreviated_packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        self.inner.abbreviated_packument_metadata(name).await
+    }
+
+    async fn tarball_metadata(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<ContentMetadata> {
+        self.inner.tarball_metadata(name, version).await
+    }
+
+    async fn stream_tarball_range(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        range: ByteRange,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.inner.stream_tarball_range(name, version, range).await
+    }
+
+    // Dropped after the write, so that a read racing it can't cache the old document again.
+    async fn put_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &Packument,
+    ) -> anyhow::Result<()> {
+        self.inner.put_packument(name, packument).await?;
+        self.forget_packument(name).await;
+        Ok(())
+    }
+
+    async fn update_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &mut Packument,
+    ) -> anyhow::Result<()> {
+        self.inner.update_packument(name, packument).await?;
+        self.forget_packument(name).await;
+        Ok(())
+    }
+
+    async fn put_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: Bytes,
+    ) -> anyhow::Result<()> {
+        self.inner.put_tarball(name, version, tarball).await
+    }
+
+    async fn delete_tarball(&self, name: &PackageIdentifier, version: &str) -> anyhow::Result<()> {
+        self.inner.delete_tarball(name, version).await?;
+        self.forget(vec![self.key("tarball", name, Some(version))])
+            .await;
+        Ok(())
+    }
+
+    async fn put_tarball_stream(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: BoxStream<'static, Result<Bytes, std::io::Error>>,
+    ) -> anyhow::Result<()> {
+        self.inner.put_tarball_stream(name, version, tarball).await
+    }
+
+    // The cached tarballs are found through the packument, read before it's gone.
+    async fn delete_package(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        let versions = match self.inner.fetch_packument(name).await {
+            Ok(packument) => packument.versions.unwrap_or_default().into_keys().collect(),
+            Err(_) => Vec::new(),
+        };
+        self.inner.delete_package(name).await?;
+        self.forget_packument(name).await;
+        let tarballs = versions
+            .iter()
+            .map(|version: &String| self.key("tarball", name, Some(version)))
+            .collect::<Vec<_>>();
+        if !tarballs.is_empty() {
+            self.forget(tarballs).await;
+        }
+        Ok(())
+    }
+
+    async fn invalidate(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        self.inner.invalidate(name).await?;
+        self.forget_packument(name).await;
+        Ok(())
+    }
+
+    async fn invalidate_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<()> {
+        self.inner.invalidate_tarball(name, version).await?;
+        self.forget(vec![self.key("tarball", name, Some(version))])
+            .await;
+        Ok(())
+    }
+
+    async fn collect_garbage(&self) -> anyhow::Result<GarbageCollection> {
+        self.inner.collect_garbage().await
+    }
+
+    async fn tarball_stats(&self) -> anyhow::Result<TarballStats> {
+        self.inner.tarball_stats().await
+    }
+
+    async fn fetch_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<serde_json::Value> {
+        self.inner.fetch_attestations(name, version).await
+    }
+
+    async fn put_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        attestations: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        self.inner
+            .put_attestations(name, version, attestations)
+            .await
+    }
+
+    async fn list_packages(&self) -> anyhow::Result<Vec<PackageIdentifier>> {
+        self.inner.list_packages().await
+    }
+
+    async fn changes_since(&self, since: u64, limit: usize) -> anyhow::Result<Vec<PackageChange>> {
+        self.inner.changes_since(since, limit).await
+    }
+
+    async fn starred_by(&self, username: &str) -> anyhow::Result<Vec<String>> {
+        self.inner.starred_by(username).await
+    }
+
+    async fn search(&self, query: &SearchQuery) -> anyhow::Result<serde_json::Value> {
+        self.inner.search(query).await
+    }
+
+    async fn public_keys(&self) -> anyhow::Result<Vec<PublicKey>> {
+        self.inner.public_keys().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policies::package_storage::collect_stream;
+    use crate::policies::package_storage::in_memory::InMemoryPackageStorage;
+
+    fn packument(latest: &str) -> Packument {
+        serde_json::from_value(serde_json::json!({
+            "name": "left-pad",
+            "dist-tags": { "latest": latest }
+        }))
+        .unwrap()
+    }
+
+    fn latest(packument: &Packument) -> Option<&str> {
+        packument.dist_tags.as_ref()?.latest.as_deref()
+    }
+
+    // Needs a Redis server, named by `REGISTRY_TEST_REDIS_URL`; passes without one. Each run
+    // keys its entries under a fresh prefix, so runs can share a server.
+    #[tokio::test]
+    async fn test_redis_cache() {
+        let Ok(url) = std::env::var("REGISTRY_TEST_REDIS_URL") else {
+            return;
+        };
+        let inner = InMemoryPackageStorage::new();
+        let cache = RedisCache::connect(inner.clone(), url.as_str())
+            .await
+            .unwrap()
+            .with_prefix(format!("registry-test-{}:", uuid::Uuid::new_v4()))
+            .with_max_tarball_size(8);
+        let name: PackageIdentifier = "left-pad".parse().unwrap();
+
+        // Changed behind the cache's back, the packument is served stale; written through it,
+        // it's dropped at once.
+        inner
+            .put_packument(&name, &packument("1.0.0"))
+            .await
+            .unwrap();
+        assert_eq!(
+            latest(&cache.fetch_packument(&name).await.unwrap()),
+            Some("1.0.0")
+        );
+        inner
+            .put_packument(&name, &packument("1.1.0"))
+            .await
+            .unwrap();
+        assert_eq!(
+            latest(&cache.fetch_packument(&name).await.unwrap()),
+            Some("1.0.0")
+        );
+        cache
+            .put_packument(&name, &packument("1.2.0"))
+            .await
+            .unwrap();
+        assert_eq!(
+            latest(&cache.fetch_packument(&name).await.unwrap()),
+            Some("1.2.0")
+        );
+
+        // Tarballs over the size limit are read from the inner storage every time.
+        for (version, data) in [("1.0.0", "small"), ("1.1.0", "larger than 8")] {
+            inner
+                .put_tarball(&name, version, Bytes::from(data))
+                .await
+                .unwrap();
+            let tarball = collect_stream(cache.stream_tarball(&name, version).await.unwrap());
+            assert_eq!(tarball.await.unwrap(), data.as_bytes());
+            inner.delete_tarball(&name, version).await.unwrap();
+        }
+        assert!(cache.stream_tarball(&name, "1.0.0").await.is_ok());
+        assert!(cache.stream_tarball(&name, "1.1.0").await.is_err());
+
+        cache.delete_package(&name).await.unwrap();
+        assert!(cache.fetch_packument(&name).await.is_err());
+    }
+}
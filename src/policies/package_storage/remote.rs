@@ -1,22 +1,271 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use crate::models::PackageIdentifier;
-use crate::policies::PackageStorage;
+use crate::policies::configurator::{default_user_agent, DEFAULT_UPSTREAM_RETRIES};
+use crate::policies::{Configurator, PackageStorage};
+use crate::signing::PublicKey;
+
+use super::{Offline, SearchQuery, StorageError};
 use axum::body::Bytes;
+use chrono::{DateTime, Utc};
 use futures::stream::BoxStream;
 use futures_util::StreamExt;
+use rand::Rng;
+use reqwest::header::{HeaderMap, HeaderValue};
+use tokio::sync::RwLock;
+
+/// What npm sends when it only needs install metadata; registries that don't support the
+/// abbreviated form fall through to plain JSON.
+pub(crate) const ABBREVIATED_ACCEPT: &str =
+    "application/vnd.npm.install-v1+json; q=1.0, application/json; q=0.8, */*";
+
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(200);
+// The longest a request waits before any one retry; a Retry-After asking for longer fails it.
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(2);
 
 #[derive(Clone, Debug)]
 pub struct RemoteRegistry {
     registry: String,
+    user_agent: String,
+    headers: HeaderMap,
+    client: reqwest::Client,
+    offline: bool,
+    retries: u32,
+    backoff: Duration,
+    // Set when the registry rate limits us with a Retry-After; until then, requests fail here
+    // rather than adding to the load. Shared by every clone.
+    paused_until: Arc<RwLock<Option<Instant>>>,
 }
 
-impl Default for RemoteRegistry {
-    fn default() -> Self {
+fn build_client(user_agent: &str, headers: &HeaderMap) -> reqwest::Client {
+    reqwest::Client::builder()
+        .user_agent(user_agent)
+        .default_headers(headers.clone())
+        .build()
+        .expect("reqwest client with a user agent checked by RemoteRegistry::with_user_agent")
+}
+
+impl RemoteRegistry {
+    pub fn new(registry: impl Into<String>) -> Self {
+        let user_agent = default_user_agent("unknown");
+        let headers = HeaderMap::new();
+        Self {
+            registry: registry.into().trim_end_matches('/').to_string(),
+            client: build_client(user_agent.as_str(), &headers),
+            user_agent,
+            headers,
+            offline: false,
+            retries: DEFAULT_UPSTREAM_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            paused_until: Default::default(),
+        }
+    }
+
+    /// A user agent that can't be sent as a header is ignored, keeping the one already set.
+    pub fn with_user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        let user_agent = user_agent.into();
+        if HeaderValue::from_str(user_agent.as_str()).is_err() {
+            tracing::warn!(user_agent, "ignoring invalid user agent");
+            return self;
+        }
+
+        self.user_agent = user_agent;
+        self.client = build_client(self.user_agent.as_str(), &self.headers);
+        self
+    }
+
+    /// Headers sent with every request, e.g. contact details or an upstream API key.
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self.client = build_client(self.user_agent.as_str(), &self.headers);
+        self
+    }
+
+    /// Retry a request that fails in a way that might not last (it couldn't connect, timed
+    /// out, or was answered with a 429, 502, 503 or 504) up to `retries` times. The wait
+    /// before each starts around `backoff`, doubles with each retry after it and is jittered
+    /// so that many requests failing together don't retry together. Two retries from 200ms
+    /// by default; zero turns retrying off.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Take the User-Agent, extra headers and retries from a [`Configurator`].
+    pub fn with_configurator(self, configurator: &impl Configurator) -> Self {
+        self.with_user_agent(configurator.upstream_user_agent())
+            .with_headers(configurator.upstream_headers())
+            .with_retries(configurator.upstream_retries(), DEFAULT_BACKOFF)
+    }
+
+    /// Never contact the registry, and report every package as missing, with [`Offline`] as
+    /// context; for deployments that may only serve what they already hold.
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    pub fn registry(&self) -> &str {
+        self.registry.as_str()
+    }
+
+    // A retry waits out the upstream's Retry-After when it's longer than the backoff.
+    async fn send(&self, request: reqwest::RequestBuilder) -> anyhow::Result<reqwest::Response> {
+        let mut backoff = self.backoff;
+        for _ in 0..self.retries {
+            let Some(attempt) = request.try_clone() else {
+                break;
+            };
+            let e = match self.send_once(attempt).await {
+                Ok(response) => return Ok(response),
+                Err(Failure {
+                    transient: false,
+                    error,
+                }) => return Err(error),
+                Err(Failure { error, .. }) => error,
+            };
+
+            let asked = StorageError::of(&e).and_then(StorageError::retry_after);
+            let wait = jitter(backoff).max(asked.unwrap_or_default());
+            if wait > MAX_RETRY_WAIT {
+                return Err(e);
+            }
+            tracing::debug!(registry = self.registry, error = ?e, ?wait, "retrying upstream request");
+            tokio::time::sleep(wait).await;
+            backoff = (backoff * 2).min(MAX_RETRY_WAIT);
+        }
+
+        self.send_once(request)
+            .await
+            .map_err(|failure| failure.error)
+    }
+
+    // npm answers a missing package with a 404 and an error document; that must not be
+    // streamed on (or cached) as if it were the package.
+    async fn send_once(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, Failure> {
+        if self.offline {
+            return Err(Failure {
+                error: anyhow::Error::new(StorageError::NotFound).context(Offline),
+                transient: false,
+            });
+        }
+
+        if let Some(wait) = self.pause_remaining().await {
+            return Err(Failure::transient(StorageError::RateLimited {
+                retry_after: Some(wait),
+            }));
+        }
+
+        let response = request.send().await.map_err(|e| {
+            let error = StorageError::Upstream(e.to_string());
+            if e.is_connect() || e.is_timeout() {
+                Failure::transient(error)
+            } else {
+                Failure::permanent(error)
+            }
+        })?;
+
+        match response.status() {
+            status if status.is_success() => Ok(response),
+            reqwest::StatusCode::NOT_FOUND => Err(Failure::permanent(StorageError::NotFound)),
+            reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                let wait = retry_after(response.headers(), Utc::now());
+                if let Some(wait) = wait {
+                    tracing::warn!(registry = self.registry, ?wait, "upstream rate limited us");
+                    *self.paused_until.write().await = Some(Instant::now() + wait);
+                }
+                Err(Failure::transient(StorageError::RateLimited {
+                    retry_after: wait,
+                }))
+            }
+            status => {
+                let error =
+                    StorageError::Upstream(format!("{} responded {}", self.registry, status));
+                match status {
+                    reqwest::StatusCode::BAD_GATEWAY
+                    | reqwest::StatusCode::SERVICE_UNAVAILABLE
+                    | reqwest::StatusCode::GATEWAY_TIMEOUT => Err(Failure::transient(error)),
+                    _ => Err(Failure::permanent(error)),
+                }
+            }
+        }
+    }
+
+    // How much longer the registry asked us to wait, if it's rate limiting us.
+    async fn pause_remaining(&self) -> Option<Duration> {
+        let until = (*self.paused_until.read().await)?;
+        let remaining = until.saturating_duration_since(Instant::now());
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    // A document that came back whole but won't parse isn't worth asking for again.
+    async fn json<T: serde::de::DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> anyhow::Result<T> {
+        let data = self
+            .send(request)
+            .await?
+            .bytes()
+            .await
+            .map_err(|e| StorageError::Upstream(e.to_string()))?;
+        serde_json::from_slice(data.as_ref())
+            .map_err(|e| StorageError::Corrupt(format!("{} sent {}", self.registry, e)).into())
+    }
+}
+
+// One failed request, and whether the same request might succeed if made again shortly.
+struct Failure {
+    error: anyhow::Error,
+    transient: bool,
+}
+
+impl Failure {
+    fn transient(error: StorageError) -> Self {
+        Self {
+            error: error.into(),
+            transient: true,
+        }
+    }
+
+    fn permanent(error: StorageError) -> Self {
         Self {
-            registry: "https://registry.npmjs.org".to_string(),
+            error: error.into(),
+            transient: false,
         }
     }
 }
 
+// Somewhere between half of `backoff` and all of it.
+fn jitter(backoff: Duration) -> Duration {
+    rand::thread_rng().gen_range(backoff / 2..=backoff)
+}
+
+/// How long a `Retry-After` header asks us to wait, given as seconds or as an HTTP date.
+fn retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    let value = headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((at.with_timezone(&Utc) - now).to_std().unwrap_or_default())
+}
+
+impl Default for RemoteRegistry {
+    fn default() -> Self {
+        Self::new("https://registry.npmjs.org")
+    }
+}
+
 #[async_trait::async_trait]
 impl PackageStorage for RemoteRegistry {
     type Error = reqwest::Error;
@@ -24,12 +273,24 @@ impl PackageStorage for RemoteRegistry {
         &self,
         name: &PackageIdentifier,
     ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
-        Ok(reqwest::get(format!("{}/{}", self.registry, name))
+        Ok(self
+            .send(self.client.get(format!("{}/{}", self.registry, name)))
             .await?
             .bytes_stream()
             .boxed())
     }
 
+    async fn stream_abbreviated_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        let request = self
+            .client
+            .get(format!("{}/{}", self.registry, name))
+            .header(reqwest::header::ACCEPT, ABBREVIATED_ACCEPT);
+        Ok(self.send(request).await?.bytes_stream().boxed())
+    }
+
     async fn stream_tarball(
         &self,
         pkg: &PackageIdentifier,
@@ -47,6 +308,94 @@ impl PackageStorage for RemoteRegistry {
             )
         };
 
-        Ok(reqwest::get(url).await?.bytes_stream().boxed())
+        Ok(self
+            .send(self.client.get(url))
+            .await?
+            .bytes_stream()
+            .boxed())
+    }
+
+    async fn fetch_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<serde_json::Value> {
+        let url = name.attestations_url(self.registry.as_str(), version);
+        self.json(self.client.get(url)).await
+    }
+
+    async fn search(&self, query: &SearchQuery) -> anyhow::Result<serde_json::Value> {
+        let request = self
+            .client
+            .get(format!("{}/-/v1/search", self.registry))
+            .query(query);
+        self.json(request).await
+    }
+
+    // Registries that don't sign anything have no keys to give.
+    async fn public_keys(&self) -> anyhow::Result<Vec<PublicKey>> {
+        #[derive(serde::Deserialize)]
+        struct Keys {
+            keys: Vec<PublicKey>,
+        }
+
+        let request = self.client.get(format!("{}/-/npm/v1/keys", self.registry));
+        match self.json::<Keys>(request).await {
+            Ok(keys) => Ok(keys.keys),
+            Err(e) if matches!(StorageError::of(&e), Some(StorageError::NotFound)) => {
+                Ok(Vec::new())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_after() {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(reqwest::header::RETRY_AFTER, value.parse().unwrap());
+            headers
+        };
+
+        assert_eq!(
+            retry_after(&headers("120"), now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            retry_after(&headers("Wed, 21 Oct 2015 07:28:30 GMT"), now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            retry_after(&headers("Wed, 21 Oct 2015 07:00:00 GMT"), now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after(&headers("soon"), now), None);
+        assert_eq!(retry_after(&HeaderMap::new(), now), None);
+    }
+
+    #[test]
+    fn test_invalid_user_agent() {
+        let remote = RemoteRegistry::new("https://registry.npmjs.org")
+            .with_user_agent("registry/1.0")
+            .with_user_agent("registry\n/2.0");
+        assert_eq!(remote.user_agent, "registry/1.0");
+    }
+
+    #[test]
+    fn test_jitter() {
+        let backoff = Duration::from_millis(200);
+        for _ in 0..100 {
+            let wait = jitter(backoff);
+            assert!(wait >= backoff / 2 && wait <= backoff);
+        }
+        assert_eq!(jitter(Duration::ZERO), Duration::ZERO);
     }
 }
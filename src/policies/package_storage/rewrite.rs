@@ -0,0 +1,478 @@
+use std::collections::HashMap;
+
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::models::{PackageIdentifier, Packument, PackumentVersion};
+use crate::policies::PackageStorage;
+use crate::signing::PublicKey;
+
+use super::{
+    collect_stream, ByteRange, ContentEncoding, ContentMetadata, GarbageCollection, PackageChange,
+    SearchQuery, StorageError, TarballStats,
+};
+
+const DEPENDENCY_FIELDS: &[&str] = &[
+    "dependencies",
+    "devDependencies",
+    "optionalDependencies",
+    "peerDependencies",
+];
+
+// Specifiers that mean "whatever is newest", which an air-gapped mirror can't promise to have.
+const FLOATING_SPECIFIERS: &[&str] = &["latest", "*", "x", ""];
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum RewriteRule {
+    /// Replace a specifier prefix, e.g. `git+https://github.com/` with a mirror's tarball URL.
+    Prefix { from: String, to: String },
+    /// Replace floating specifiers with the dependency's current `latest` version.
+    PinLatest,
+}
+
+/// A rewrite applied to the packuments of one scope, or of every package when `scope` is
+/// absent.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DependencyRewrite {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(flatten)]
+    pub rule: RewriteRule,
+}
+
+impl DependencyRewrite {
+    fn applies_to(&self, name: &PackageIdentifier) -> bool {
+        self.scope.is_none() || self.scope == name.scope
+    }
+}
+
+/// Rewrites dependency specifiers in the packuments served from another storage, so that
+/// installs through a mirror never need to reach outside it. With a tarball base, each
+/// version's `dist.tarball` is pointed at this registry too; otherwise clients would fetch
+/// tarballs from wherever the packument came from.
+///
+/// Only served documents are rewritten: [`PackageStorage::fetch_packument`] returns what's
+/// stored, so modifications never persist a rewrite.
+#[derive(Clone, Debug)]
+pub struct RewriteDependencies<R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static>
+{
+    inner: R,
+    rewrites: Vec<DependencyRewrite>,
+    tarball_base: Option<String>,
+}
+
+impl<R> RewriteDependencies<R>
+where
+    R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static,
+    <R as PackageStorage>::Error: std::error::Error + Send + Sync + 'static,
+{
+    pub fn new(inner: R, rewrites: Vec<DependencyRewrite>) -> Self {
+        Self {
+            inner,
+            rewrites,
+            tarball_base: None,
+        }
+    }
+
+    /// Point served tarball URLs at `base`, e.g. [`Configurator::fqdn`].
+    ///
+    /// [`Configurator::fqdn`]: crate::policies::Configurator::fqdn
+    pub fn with_tarball_base(mut self, base: impl Into<String>) -> Self {
+        self.tarball_base = Some(base.into());
+        self
+    }
+
+    fn rewrites(&self, name: &PackageIdentifier) -> bool {
+        self.tarball_base.is_some() || !self.rules_for(name).is_empty()
+    }
+
+    fn rules_for(&self, name: &PackageIdentifier) -> Vec<&RewriteRule> {
+        self.rewrites
+            .iter()
+            .filter(|rewrite| rewrite.applies_to(name))
+            .map(|rewrite| &rewrite.rule)
+            .collect()
+    }
+
+    async fn latest_version(
+        &self,
+        dependency: &str,
+        known: &mut HashMap<String, Option<String>>,
+    ) -> Option<String> {
+        if let Some(latest) = known.get(dependency) {
+            return latest.clone();
+        }
+
+        let latest = match dependency.parse() {
+            Ok(name) => self
+                .inner
+                .fetch_packument(&name)
+                .await
+                .ok()
+                .and_then(|packument| packument.dist_tags)
+                .and_then(|dist_tags| dist_tags.latest),
+            Err(_) => None,
+        };
+        known.insert(dependency.to_string(), latest.clone());
+        latest
+    }
+
+    async fn rewrite_specifier(
+        &self,
+        rules: &[&RewriteRule],
+        dependency: &str,
+        specifier: &str,
+        known: &mut HashMap<String, Option<String>>,
+    ) -> Option<String> {
+        for rule in rules {
+            match rule {
+                RewriteRule::Prefix { from, to } => {
+                    if let Some(rest) = specifier.strip_prefix(from.as_str()) {
+                        return Some(format!("{}{}", to, rest));
+                    }
+                }
+                RewriteRule::PinLatest => {
+                    if FLOATING_SPECIFIERS.contains(&specifier.trim()) {
+                        if let Some(latest) = self.latest_version(dependency, known).await {
+                            return Some(latest);
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    async fn rewrite(&self, name: &PackageIdentifier, document: Vec<u8>) -> anyhow::Result<Bytes> {
+        let rules = self.rules_for(name);
+        let mut document: Value = serde_json::from_slice(document.as_slice())
+            .map_err(|e| StorageError::Corrupt(format!("packument for {}: {}", name, e)))?;
+        let mut known = HashMap::new();
+
+        let versions = document
+            .get_mut("versions")
+            .and_then(Value::as_object_mut)
+            .into_iter()
+            .flat_map(|versions| versions.iter_mut());
+        for (number, version) in versions {
+            if let Some(ref base) = self.tarball_base {
+                if let Some(tarball) = version.pointer_mut("/dist/tarball") {
+                    *tarball = Value::String(name.tarball_url(base, number));
+                }
+            }
+
+            for field in DEPENDENCY_FIELDS {
+                let Some(dependencies) = version.get_mut(*field).and_then(Value::as_object_mut)
+                else {
+                    continue;
+                };
+
+                for (dependency, specifier) in dependencies.iter_mut() {
+                    let Some(current) = specifier.as_str() else {
+                        continue;
+                    };
+                    if let Some(rewritten) = self
+                        .rewrite_specifier(rules.as_slice(), dependency, current, &mut known)
+                        .await
+                    {
+                        *specifier = Value::String(rewritten);
+                    }
+                }
+            }
+        }
+
+        Ok(Bytes::from(serde_json::to_vec(&document)?))
+    }
+
+    async fn rewrite_stream(
+        &self,
+        name: &PackageIdentifier,
+        stream: BoxStream<'static, Result<Bytes, R::Error>>,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, R::Error>>> {
+        if !self.rewrites(name) {
+            return Ok(stream);
+        }
+
+        let rewritten = self.rewrite(name, collect_stream(stream).await?).await?;
+        Ok(futures::stream::once(async move { Ok(rewritten) }).boxed())
+    }
+}
+
+#[async_trait::async_trait]
+impl<R> PackageStorage for RewriteDependencies<R>
+where
+    R: PackageStorage + Clone + std::fmt::Debug + Send + Sync + 'static,
+    <R as PackageStorage>::Error: std::error::Error + Send + Sync + 'static,
+{
+    type Error = R::Error;
+
+    async fn fetch_packument(&self, name: &PackageIdentifier) -> anyhow::Result<Packument> {
+        self.inner.fetch_packument(name).await
+    }
+
+    // As stored, like fetch_packument.
+    async fn resolve_version(
+        &self,
+        name: &PackageIdentifier,
+        spec: &str,
+    ) -> anyhow::Result<Option<PackumentVersion>> {
+        self.inner.resolve_version(name, spec).await
+    }
+
+    // Stored copies are compressed before any rewrite, so only unrewritten packuments can use
+    // them.
+    async fn stream_encoded_packument(
+        &self,
+        name: &PackageIdentifier,
+        abbreviated: bool,
+        encoding: ContentEncoding,
+    ) -> anyhow::Result<Option<BoxStream<'static, Result<Bytes, Self::Error>>>> {
+        if self.rewrites(name) {
+            return Ok(None);
+        }
+        self.inner
+            .stream_encoded_packument(name, abbreviated, encoding)
+            .await
+    }
+
+    async fn stream_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        let stream = self.inner.stream_packument(name).await?;
+        self.rewrite_stream(name, stream).await
+    }
+
+    async fn stream_abbreviated_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        let stream = self.inner.stream_abbreviated_packument(name).await?;
+        self.rewrite_stream(name, stream).await
+    }
+
+    async fn stream_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.inner.stream_tarball(name, version).await
+    }
+
+    // Rewritten documents differ from the stored ones, so their metadata must be computed
+    // from what's actually served.
+    async fn packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        if !self.rewrites(name) {
+            return self.inner.packument_metadata(name).await;
+        }
+        let stream = self.stream_packument(name).await?;
+        ContentMetadata::of(collect_stream(stream).await?.as_slice())
+    }
+
+    async fn abbreviated_packument_metadata(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<ContentMetadata> {
+        if !self.rewrites(name) {
+            return self.inner.abbreviated_packument_metadata(name).await;
+        }
+        let stream = self.stream_abbreviated_packument(name).await?;
+        ContentMetadata::of(collect_stream(stream).await?.as_slice())
+    }
+
+    async fn tarball_metadata(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<ContentMetadata> {
+        self.inner.tarball_metadata(name, version).await
+    }
+
+    async fn stream_tarball_range(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        range: ByteRange,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.inner.stream_tarball_range(name, version, range).await
+    }
+
+    async fn put_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &Packument,
+    ) -> anyhow::Result<()> {
+        self.inner.put_packument(name, packument).await
+    }
+
+    async fn update_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &mut Packument,
+    ) -> anyhow::Result<()> {
+        self.inner.update_packument(name, packument).await
+    }
+
+    async fn put_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: Bytes,
+    ) -> anyhow::Result<()> {
+        self.inner.put_tarball(name, version, tarball).await
+    }
+
+    async fn delete_tarball(&self, name: &PackageIdentifier, version: &str) -> anyhow::Result<()> {
+        self.inner.delete_tarball(name, version).await
+    }
+
+    async fn put_tarball_stream(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: BoxStream<'static, Result<Bytes, std::io::Error>>,
+    ) -> anyhow::Result<()> {
+        self.inner.put_tarball_stream(name, version, tarball).await
+    }
+
+    async fn delete_package(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        self.inner.delete_package(name).await
+    }
+
+    async fn invalidate(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        self.inner.invalidate(name).await
+    }
+
+    async fn invalidate_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<()> {
+        self.inner.invalidate_tarball(name, version).await
+    }
+
+    async fn collect_garbage(&self) -> anyhow::Result<GarbageCollection> {
+        self.inner.collect_garbage().await
+    }
+
+    async fn tarball_stats(&self) -> anyhow::Result<TarballStats> {
+        self.inner.tarball_stats().await
+    }
+
+    async fn fetch_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<serde_json::Value> {
+        self.inner.fetch_attestations(name, version).await
+    }
+
+    async fn put_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        attestations: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        self.inner
+            .put_attestations(name, version, attestations)
+            .await
+    }
+
+    async fn list_packages(&self) -> anyhow::Result<Vec<PackageIdentifier>> {
+        self.inner.list_packages().await
+    }
+
+    async fn changes_since(&self, since: u64, limit: usize) -> anyhow::Result<Vec<PackageChange>> {
+        self.inner.changes_since(since, limit).await
+    }
+
+    async fn starred_by(&self, username: &str) -> anyhow::Result<Vec<String>> {
+        self.inner.starred_by(username).await
+    }
+
+    async fn search(&self, query: &SearchQuery) -> anyhow::Result<serde_json::Value> {
+        self.inner.search(query).await
+    }
+
+    async fn public_keys(&self) -> anyhow::Result<Vec<PublicKey>> {
+        self.inner.public_keys().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policies::package_storage::remote::RemoteRegistry;
+
+    #[test]
+    fn test_rewrite_rules_deserialize() {
+        let rewrites: Vec<DependencyRewrite> = serde_json::from_str(
+            r#"[
+                {"scope": "corp", "type": "prefix", "from": "git+https://github.com/", "to": "https://mirror.corp/git/"},
+                {"type": "pin-latest"}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            rewrites,
+            vec![
+                DependencyRewrite {
+                    scope: Some("corp".to_string()),
+                    rule: RewriteRule::Prefix {
+                        from: "git+https://github.com/".to_string(),
+                        to: "https://mirror.corp/git/".to_string(),
+                    },
+                },
+                DependencyRewrite {
+                    scope: None,
+                    rule: RewriteRule::PinLatest,
+                },
+            ]
+        );
+
+        let scoped: PackageIdentifier = "@corp/app".parse().unwrap();
+        let unscoped: PackageIdentifier = "left-pad".parse().unwrap();
+        assert!(rewrites[0].applies_to(&scoped));
+        assert!(!rewrites[0].applies_to(&unscoped));
+        assert!(rewrites[1].applies_to(&unscoped));
+    }
+
+    #[tokio::test]
+    async fn test_tarball_urls_point_at_base() {
+        let storage = RewriteDependencies::new(RemoteRegistry::default().offline(true), Vec::new())
+            .with_tarball_base("https://registry.corp/");
+        let name: PackageIdentifier = "@corp/app".parse().unwrap();
+        let document = serde_json::json!({
+            "name": "@corp/app",
+            "versions": {
+                "1.0.0": {
+                    "dist": {
+                        "tarball": "https://registry.npmjs.org/@corp/app/-/app-1.0.0.tgz",
+                        "shasum": "abc"
+                    }
+                }
+            }
+        });
+
+        let rewritten = storage
+            .rewrite(&name, serde_json::to_vec(&document).unwrap())
+            .await
+            .unwrap();
+        let rewritten: Value = serde_json::from_slice(rewritten.as_ref()).unwrap();
+        assert_eq!(
+            rewritten["versions"]["1.0.0"]["dist"],
+            serde_json::json!({
+                "tarball": "https://registry.corp/@corp/app/-/app-1.0.0.tgz",
+                "shasum": "abc"
+            })
+        );
+    }
+}
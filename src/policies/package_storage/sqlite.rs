@@ -0,0 +1,440 @@
+//! Packages kept in a single SQLite database, so that a private registry needs nothing but
+//! a file to run. Packuments are stored whole, as JSON text, next to their `_rev`; tarballs
+//! and attestations are rows keyed by package and version.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+use futures_util::StreamExt;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{like_pattern, search_results, ByteRange, SearchQuery, StorageError};
+use crate::migrations::Migrator;
+use crate::models::{PackageIdentifier, Packument};
+use crate::policies::PackageStorage;
+
+pub fn migrations() -> Migrator<Connection> {
+    Migrator::new("package database").migration("create the package tables", |connection| {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS packuments (
+                name TEXT PRIMARY KEY,
+                rev TEXT,
+                document TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS tarballs (
+                package TEXT NOT NULL,
+                version TEXT NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (package, version)
+            );
+            CREATE TABLE IF NOT EXISTS attestations (
+                package TEXT NOT NULL,
+                version TEXT NOT NULL,
+                document TEXT NOT NULL,
+                PRIMARY KEY (package, version)
+            );",
+        )?;
+        Ok(())
+    })
+}
+
+// Packages matching a search: by name or description, or by an exact keyword.
+const SEARCH_MATCHES: &str = "name LIKE ?1 ESCAPE '\\'
+    OR json_extract(document, '$.description') LIKE ?1 ESCAPE '\\'
+    OR EXISTS (SELECT 1 FROM json_each(document, '$.keywords') WHERE value = ?2)";
+
+/// Package storage in a SQLite database. Every statement runs under one lock on one
+/// connection, so an update's check and write can't interleave with another's.
+#[derive(Clone)]
+pub struct SqlitePackageStorage {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl std::fmt::Debug for SqlitePackageStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqlitePackageStorage")
+            .finish_non_exhaustive()
+    }
+}
+
+impl SqlitePackageStorage {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    pub fn in_memory() -> anyhow::Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(connection: Connection) -> anyhow::Result<Self> {
+        migrations().run(&connection, false)?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    async fn with_connection<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let connection = connection.lock().unwrap_or_else(|e| e.into_inner());
+            Ok(f(&connection)?)
+        })
+        .await?
+    }
+
+    // All of a tarball, or with `range`, only the bytes SQLite slices out for it.
+    async fn read_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        range: Option<ByteRange>,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, std::io::Error>>> {
+        let package = name.to_string();
+        let version = version.to_string();
+        let range = match range {
+            Some(range) => Some((i64::try_from(range.start + 1)?, i64::try_from(range.len())?)),
+            None => None,
+        };
+        let data: Option<Vec<u8>> = self
+            .with_connection(move |connection| {
+                match range {
+                    Some((start, length)) => connection.query_row(
+                        "SELECT substr(data, ?3, ?4) FROM tarballs
+                         WHERE package = ?1 AND version = ?2",
+                        params![package, version, start, length],
+                        |row| row.get(0),
+                    ),
+                    None => connection.query_row(
+                        "SELECT data FROM tarballs WHERE package = ?1 AND version = ?2",
+                        params![package, version],
+                        |row| row.get(0),
+                    ),
+                }
+                .optional()
+            })
+            .await?;
+        let data = Bytes::from(data.ok_or(StorageError::NotFound)?);
+        Ok(futures::stream::once(async move { Ok(data) }).boxed())
+    }
+}
+
+#[async_trait::async_trait]
+impl PackageStorage for SqlitePackageStorage {
+    type Error = std::io::Error;
+
+    async fn stream_packument(
+        &self,
+        name: &PackageIdentifier,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        let package = name.to_string();
+        let document: Option<String> = self
+            .with_connection(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT document FROM packuments WHERE name = ?1",
+                        params![package],
+                        |row| row.get(0),
+                    )
+                    .optional()
+            })
+            .await?;
+        let data = Bytes::from(document.ok_or(StorageError::NotFound)?);
+        Ok(futures::stream::once(async move { Ok(data) }).boxed())
+    }
+
+    async fn stream_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.read_tarball(name, version, None).await
+    }
+
+    async fn stream_tarball_range(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        range: ByteRange,
+    ) -> anyhow::Result<BoxStream<'static, Result<Bytes, Self::Error>>> {
+        self.read_tarball(name, version, Some(range)).await
+    }
+
+    async fn put_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &Packument,
+    ) -> anyhow::Result<()> {
+        let package = name.to_string();
+        let rev = packument.rev.clone();
+        let document = serde_json::to_string(packument)?;
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO packuments (name, rev, document) VALUES (?1, ?2, ?3)",
+                params![package, rev, document],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    // The stored revision is checked and the new document written under the same lock.
+    async fn update_packument(
+        &self,
+        name: &PackageIdentifier,
+        packument: &mut Packument,
+    ) -> anyhow::Result<()> {
+        let package = name.to_string();
+        let expected = packument.rev.clone();
+        packument.advance_rev()?;
+        let rev = packument.rev.clone();
+        let document = serde_json::to_string(packument)?;
+
+        let checked = expected.clone();
+        let written = self
+            .with_connection(move |connection| {
+                let stored: Option<Option<String>> = connection
+                    .query_row(
+                        "SELECT rev FROM packuments WHERE name = ?1",
+                        params![package],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                if stored.flatten() != checked {
+                    return Ok(false);
+                }
+
+                connection.execute(
+                    "INSERT OR REPLACE INTO packuments (name, rev, document) VALUES (?1, ?2, ?3)",
+                    params![package, rev, document],
+                )?;
+                Ok(true)
+            })
+            .await?;
+
+        if !written {
+            packument.rev = expected;
+            return Err(StorageError::Conflict.into());
+        }
+        Ok(())
+    }
+
+    async fn put_tarball(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        tarball: Bytes,
+    ) -> anyhow::Result<()> {
+        let package = name.to_string();
+        let version = version.to_string();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO tarballs (package, version, data) VALUES (?1, ?2, ?3)",
+                params![package, version, tarball.as_ref()],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_tarball(&self, name: &PackageIdentifier, version: &str) -> anyhow::Result<()> {
+        let package = name.to_string();
+        let version = version.to_string();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "DELETE FROM tarballs WHERE package = ?1 AND version = ?2",
+                params![package, version],
+            )?;
+            connection.execute(
+                "DELETE FROM attestations WHERE package = ?1 AND version = ?2",
+                params![package, version],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    // The packument goes first, so that nothing is left advertising a deleted tarball.
+    async fn delete_package(&self, name: &PackageIdentifier) -> anyhow::Result<()> {
+        let package = name.to_string();
+        self.with_connection(move |connection| {
+            connection.execute("DELETE FROM packuments WHERE name = ?1", params![package])?;
+            connection.execute("DELETE FROM tarballs WHERE package = ?1", params![package])?;
+            connection.execute(
+                "DELETE FROM attestations WHERE package = ?1",
+                params![package],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn fetch_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+    ) -> anyhow::Result<serde_json::Value> {
+        let package = name.to_string();
+        let version = version.to_string();
+        let document: Option<String> = self
+            .with_connection(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT document FROM attestations WHERE package = ?1 AND version = ?2",
+                        params![package, version],
+                        |row| row.get(0),
+                    )
+                    .optional()
+            })
+            .await?;
+        Ok(serde_json::from_str(
+            document.ok_or(StorageError::NotFound)?.as_str(),
+        )?)
+    }
+
+    async fn put_attestations(
+        &self,
+        name: &PackageIdentifier,
+        version: &str,
+        attestations: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        let package = name.to_string();
+        let version = version.to_string();
+        let document = serde_json::to_string(attestations)?;
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO attestations (package, version, document)
+                 VALUES (?1, ?2, ?3)",
+                params![package, version, document],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn list_packages(&self) -> anyhow::Result<Vec<PackageIdentifier>> {
+        let names = self
+            .with_connection(|connection| {
+                let mut statement =
+                    connection.prepare("SELECT name FROM packuments ORDER BY name")?;
+                let names = statement
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok(names)
+            })
+            .await?;
+
+        names
+            .into_iter()
+            .map(|name| name.parse().map_err(Into::into))
+            .collect()
+    }
+
+    // An exact name comes first, the rest by name.
+    async fn search(&self, query: &SearchQuery) -> anyhow::Result<serde_json::Value> {
+        let text = query.text.trim().to_string();
+        let pattern = like_pattern(text.as_str());
+        let (from, size) = query.page();
+        let (total, rows) = self
+            .with_connection(move |connection| {
+                let total: u64 = connection.query_row(
+                    format!("SELECT count(*) FROM packuments WHERE {}", SEARCH_MATCHES).as_str(),
+                    params![pattern, text],
+                    |row| row.get(0),
+                )?;
+                let mut statement = connection.prepare(
+                    format!(
+                        "SELECT name, document FROM packuments WHERE {}
+                         ORDER BY name = ?2 DESC, name LIMIT ?3 OFFSET ?4",
+                        SEARCH_MATCHES
+                    )
+                    .as_str(),
+                )?;
+                let rows = statement
+                    .query_map(params![pattern, text, size, from], |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok((total, rows))
+            })
+            .await?;
+
+        let mut matches = Vec::with_capacity(rows.len());
+        for (name, document) in rows {
+            let document: serde_json::Value = serde_json::from_str(document.as_str())
+                .map_err(|e| StorageError::Corrupt(format!("packument for {}: {}", name, e)))?;
+            let latest = document["dist-tags"]["latest"].as_str().map(String::from);
+            matches.push((name, latest, document));
+        }
+        Ok(search_results(matches, total))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policies::package_storage::collect_stream;
+
+    #[tokio::test]
+    async fn test_sqlite_package_storage() {
+        let storage = SqlitePackageStorage::in_memory().unwrap();
+        let name: PackageIdentifier = "@corp/left-pad".parse().unwrap();
+        let mut packument: Packument = serde_json::from_value(serde_json::json!({
+            "name": "@corp/left-pad",
+            "description": "pads strings on the left",
+            "dist-tags": { "latest": "1.0.0" }
+        }))
+        .unwrap();
+
+        storage
+            .update_packument(&name, &mut packument)
+            .await
+            .unwrap();
+        assert_eq!(storage.fetch_packument(&name).await.unwrap(), packument);
+        assert_eq!(storage.list_packages().await.unwrap().len(), 1);
+
+        let mut stale: Packument =
+            serde_json::from_value(serde_json::json!({ "name": "@corp/left-pad" })).unwrap();
+        let conflict = storage
+            .update_packument(&name, &mut stale)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            StorageError::of(&conflict),
+            Some(StorageError::Conflict)
+        ));
+        assert_eq!(stale.rev, None);
+
+        storage
+            .put_tarball(&name, "1.0.0", Bytes::from_static(b"tarball"))
+            .await
+            .unwrap();
+        let range = ByteRange { start: 1, end: 3 };
+        let stream = storage
+            .stream_tarball_range(&name, "1.0.0", range)
+            .await
+            .unwrap();
+        assert_eq!(collect_stream(stream).await.unwrap(), b"arb");
+
+        let query = SearchQuery {
+            text: "pads".to_string(),
+            ..SearchQuery::default()
+        };
+        let results = storage.search(&query).await.unwrap();
+        assert_eq!(results["total"], 1);
+        assert_eq!(results["objects"][0]["package"]["version"], "1.0.0");
+
+        storage.delete_tarball(&name, "1.0.0").await.unwrap();
+        let missing = storage.stream_tarball(&name, "1.0.0").await.err().unwrap();
+        assert!(matches!(
+            StorageError::of(&missing),
+            Some(StorageError::NotFound)
+        ));
+    }
+}
@@ -1,6 +1,11 @@
 use super::configurator::env::EnvConfigurator;
 use super::not_implemented::NotImplemented;
+use std::sync::Arc;
+
 use super::*;
+use crate::events::EventBus;
+use crate::extractors::SeenNonces;
+use crate::tasks::TaskRegistry;
 
 pub trait PolicyHolder {
     type Authenticator: Authenticator + Send + Sync;
@@ -8,33 +13,71 @@ pub trait PolicyHolder {
     type UserStorage: UserStorage + Send + Sync;
     type PackageStorage: PackageStorage + Send + Sync;
     type Configurator: Configurator + Send + Sync;
+    type Advisories: Advisories + Send + Sync;
+    type AccessControl: AccessControl + Send + Sync;
+    type Webhooks: Webhooks + Send + Sync;
+    type DownloadCounts: DownloadCounts + Send + Sync;
+    type Geolocator: Geolocator + Send + Sync;
+    type Moderation: Moderation + Send + Sync;
 
     fn as_authenticator(&self) -> &Self::Authenticator;
     fn as_token_authorizer(&self) -> &Self::TokenAuthorizer;
     fn as_user_storage(&self) -> &Self::UserStorage;
     fn as_package_storage(&self) -> &Self::PackageStorage;
     fn as_configurator(&self) -> &Self::Configurator;
+    fn as_advisories(&self) -> &Self::Advisories;
+    fn as_access_control(&self) -> &Self::AccessControl;
+    fn as_webhooks(&self) -> &Self::Webhooks;
+    fn as_download_counts(&self) -> &Self::DownloadCounts;
+    fn as_geolocator(&self) -> &Self::Geolocator;
+    fn as_moderation(&self) -> &Self::Moderation;
+    fn as_tasks(&self) -> &TaskRegistry;
+    fn as_events(&self) -> &EventBus;
+    fn as_seen_nonces(&self) -> &SeenNonces;
+    fn as_packument_transforms(&self) -> &[Arc<dyn PackumentTransform>];
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct Policy<
     AuthImpl = NotImplemented,
     TokenAuthzImpl = NotImplemented,
     UserStorageImpl = NotImplemented,
     PackageStorageImpl = NotImplemented,
     ConfiguratorImpl = EnvConfigurator,
+    AdvisoriesImpl = NotImplemented,
+    AccessControlImpl = NotImplemented,
+    WebhooksImpl = NotImplemented,
+    DownloadCountsImpl = NotImplemented,
+    GeolocatorImpl = NotImplemented,
+    ModerationImpl = NotImplemented,
 > where
     AuthImpl: Authenticator + Send + Sync,
     TokenAuthzImpl: TokenAuthorizer + Send + Sync,
     UserStorageImpl: UserStorage + Send + Sync,
     PackageStorageImpl: PackageStorage + Send + Sync,
     ConfiguratorImpl: Configurator + Send + Sync,
+    AdvisoriesImpl: Advisories + Send + Sync,
+    AccessControlImpl: AccessControl + Send + Sync,
+    WebhooksImpl: Webhooks + Send + Sync,
+    DownloadCountsImpl: DownloadCounts + Send + Sync,
+    GeolocatorImpl: Geolocator + Send + Sync,
+    ModerationImpl: Moderation + Send + Sync,
 {
     auth: AuthImpl,
     token_authz: TokenAuthzImpl,
     user_storage: UserStorageImpl,
     package_storage: PackageStorageImpl,
     configurator: ConfiguratorImpl,
+    advisories: AdvisoriesImpl,
+    access_control: AccessControlImpl,
+    webhooks: WebhooksImpl,
+    download_counts: DownloadCountsImpl,
+    geolocator: GeolocatorImpl,
+    moderation: ModerationImpl,
+    tasks: TaskRegistry,
+    events: EventBus,
+    nonces: SeenNonces,
+    transforms: Vec<Arc<dyn PackumentTransform>>,
 }
 
 impl Policy {
@@ -45,6 +88,16 @@ impl Policy {
             auth: NotImplemented,
             token_authz: NotImplemented,
             configurator: EnvConfigurator::new(),
+            advisories: NotImplemented,
+            access_control: NotImplemented,
+            webhooks: NotImplemented,
+            download_counts: NotImplemented,
+            geolocator: NotImplemented,
+            moderation: NotImplemented,
+            tasks: TaskRegistry::new(),
+            events: EventBus::default(),
+            nonces: SeenNonces::default(),
+            transforms: Vec::new(),
         }
     }
 }
@@ -55,13 +108,19 @@ impl Default for Policy {
     }
 }
 
-impl<A, T, U, P, C> PolicyHolder for Policy<A, T, U, P, C>
+impl<A, T, U, P, C, Adv, AC, W, D, G, M> PolicyHolder for Policy<A, T, U, P, C, Adv, AC, W, D, G, M>
 where
     A: Authenticator + Send + Sync,
     T: TokenAuthorizer + Send + Sync,
     U: UserStorage + Send + Sync,
     P: PackageStorage + Send + Sync,
     C: Configurator + Send + Sync,
+    Adv: Advisories + Send + Sync,
+    AC: AccessControl + Send + Sync,
+    W: Webhooks + Send + Sync,
+    D: DownloadCounts + Send + Sync,
+    G: Geolocator + Send + Sync,
+    M: Moderation + Send + Sync,
 {
     type Authenticator = A;
 
@@ -73,6 +132,18 @@ where
 
     type Configurator = C;
 
+    type Advisories = Adv;
+
+    type AccessControl = AC;
+
+    type Webhooks = W;
+
+    type DownloadCounts = D;
+
+    type Geolocator = G;
+
+    type Moderation = M;
+
     fn as_authenticator(&self) -> &Self::Authenticator {
         &self.auth
     }
@@ -92,65 +163,331 @@ where
     fn as_configurator(&self) -> &Self::Configurator {
         &self.configurator
     }
+
+    fn as_advisories(&self) -> &Self::Advisories {
+        &self.advisories
+    }
+
+    fn as_access_control(&self) -> &Self::AccessControl {
+        &self.access_control
+    }
+
+    fn as_webhooks(&self) -> &Self::Webhooks {
+        &self.webhooks
+    }
+
+    fn as_download_counts(&self) -> &Self::DownloadCounts {
+        &self.download_counts
+    }
+
+    fn as_geolocator(&self) -> &Self::Geolocator {
+        &self.geolocator
+    }
+
+    fn as_moderation(&self) -> &Self::Moderation {
+        &self.moderation
+    }
+
+    fn as_tasks(&self) -> &TaskRegistry {
+        &self.tasks
+    }
+
+    fn as_events(&self) -> &EventBus {
+        &self.events
+    }
+
+    fn as_seen_nonces(&self) -> &SeenNonces {
+        &self.nonces
+    }
+
+    fn as_packument_transforms(&self) -> &[Arc<dyn PackumentTransform>] {
+        self.transforms.as_slice()
+    }
 }
 
-impl<A, T, U, P, C> Policy<A, T, U, P, C>
+impl<A, T, U, P, C, Adv, AC, W, D, G, M> Policy<A, T, U, P, C, Adv, AC, W, D, G, M>
 where
     A: Authenticator + Send + Sync,
     T: TokenAuthorizer + Send + Sync,
     U: UserStorage + Send + Sync,
     P: PackageStorage + Send + Sync,
     C: Configurator + Send + Sync,
+    Adv: Advisories + Send + Sync,
+    AC: AccessControl + Send + Sync,
+    W: Webhooks + Send + Sync,
+    D: DownloadCounts + Send + Sync,
+    G: Geolocator + Send + Sync,
+    M: Moderation + Send + Sync,
 {
     pub fn with_authenticator<A1: Authenticator + Send + Sync>(
         self,
         auth: A1,
-    ) -> Policy<A1, T, U, P, C> {
+    ) -> Policy<A1, T, U, P, C, Adv, AC, W, D, G, M> {
         Policy {
             auth,
             token_authz: self.token_authz,
             package_storage: self.package_storage,
             user_storage: self.user_storage,
             configurator: self.configurator,
+            advisories: self.advisories,
+            access_control: self.access_control,
+            webhooks: self.webhooks,
+            download_counts: self.download_counts,
+            geolocator: self.geolocator,
+            moderation: self.moderation,
+            tasks: self.tasks,
+            events: self.events,
+            nonces: self.nonces,
+            transforms: self.transforms,
         }
     }
 
     pub fn with_package_storage<P1: PackageStorage + Send + Sync>(
         self,
         package_storage: P1,
-    ) -> Policy<A, T, U, P1, C> {
+    ) -> Policy<A, T, U, P1, C, Adv, AC, W, D, G, M> {
         Policy {
             auth: self.auth,
             token_authz: self.token_authz,
             configurator: self.configurator,
             user_storage: self.user_storage,
             package_storage,
+            advisories: self.advisories,
+            access_control: self.access_control,
+            webhooks: self.webhooks,
+            download_counts: self.download_counts,
+            geolocator: self.geolocator,
+            moderation: self.moderation,
+            tasks: self.tasks,
+            events: self.events,
+            nonces: self.nonces,
+            transforms: self.transforms,
         }
     }
 
     pub fn with_user_storage<U1: UserStorage + Send + Sync>(
         self,
         user_storage: U1,
-    ) -> Policy<A, T, U1, P, C> {
+    ) -> Policy<A, T, U1, P, C, Adv, AC, W, D, G, M> {
         Policy {
             auth: self.auth,
             token_authz: self.token_authz,
             configurator: self.configurator,
             user_storage,
             package_storage: self.package_storage,
+            advisories: self.advisories,
+            access_control: self.access_control,
+            webhooks: self.webhooks,
+            download_counts: self.download_counts,
+            geolocator: self.geolocator,
+            moderation: self.moderation,
+            tasks: self.tasks,
+            events: self.events,
+            nonces: self.nonces,
+            transforms: self.transforms,
         }
     }
 
     pub fn with_token_authorizer<T1: TokenAuthorizer + Send + Sync>(
         self,
         token_authz: T1,
-    ) -> Policy<A, T1, U, P, C> {
+    ) -> Policy<A, T1, U, P, C, Adv, AC, W, D, G, M> {
         Policy {
             auth: self.auth,
             token_authz,
             configurator: self.configurator,
             user_storage: self.user_storage,
             package_storage: self.package_storage,
+            advisories: self.advisories,
+            access_control: self.access_control,
+            webhooks: self.webhooks,
+            download_counts: self.download_counts,
+            geolocator: self.geolocator,
+            moderation: self.moderation,
+            tasks: self.tasks,
+            events: self.events,
+            nonces: self.nonces,
+            transforms: self.transforms,
+        }
+    }
+
+    pub fn with_configurator<C1: Configurator + Send + Sync>(
+        self,
+        configurator: C1,
+    ) -> Policy<A, T, U, P, C1, Adv, AC, W, D, G, M> {
+        Policy {
+            auth: self.auth,
+            token_authz: self.token_authz,
+            configurator,
+            user_storage: self.user_storage,
+            package_storage: self.package_storage,
+            advisories: self.advisories,
+            access_control: self.access_control,
+            webhooks: self.webhooks,
+            download_counts: self.download_counts,
+            geolocator: self.geolocator,
+            moderation: self.moderation,
+            tasks: self.tasks,
+            events: self.events,
+            nonces: self.nonces,
+            transforms: self.transforms,
+        }
+    }
+
+    pub fn with_advisories<Adv1: Advisories + Send + Sync>(
+        self,
+        advisories: Adv1,
+    ) -> Policy<A, T, U, P, C, Adv1, AC, W, D, G, M> {
+        Policy {
+            auth: self.auth,
+            token_authz: self.token_authz,
+            configurator: self.configurator,
+            user_storage: self.user_storage,
+            package_storage: self.package_storage,
+            advisories,
+            access_control: self.access_control,
+            webhooks: self.webhooks,
+            download_counts: self.download_counts,
+            geolocator: self.geolocator,
+            moderation: self.moderation,
+            tasks: self.tasks,
+            events: self.events,
+            nonces: self.nonces,
+            transforms: self.transforms,
+        }
+    }
+
+    pub fn with_access_control<AC1: AccessControl + Send + Sync>(
+        self,
+        access_control: AC1,
+    ) -> Policy<A, T, U, P, C, Adv, AC1, W, D, G, M> {
+        Policy {
+            auth: self.auth,
+            token_authz: self.token_authz,
+            configurator: self.configurator,
+            user_storage: self.user_storage,
+            package_storage: self.package_storage,
+            advisories: self.advisories,
+            access_control,
+            webhooks: self.webhooks,
+            download_counts: self.download_counts,
+            geolocator: self.geolocator,
+            moderation: self.moderation,
+            tasks: self.tasks,
+            events: self.events,
+            nonces: self.nonces,
+            transforms: self.transforms,
+        }
+    }
+
+    pub fn with_webhooks<W1: Webhooks + Send + Sync>(
+        self,
+        webhooks: W1,
+    ) -> Policy<A, T, U, P, C, Adv, AC, W1, D, G, M> {
+        Policy {
+            auth: self.auth,
+            token_authz: self.token_authz,
+            configurator: self.configurator,
+            user_storage: self.user_storage,
+            package_storage: self.package_storage,
+            advisories: self.advisories,
+            access_control: self.access_control,
+            webhooks,
+            download_counts: self.download_counts,
+            geolocator: self.geolocator,
+            moderation: self.moderation,
+            tasks: self.tasks,
+            events: self.events,
+            nonces: self.nonces,
+            transforms: self.transforms,
         }
     }
+
+    pub fn with_download_counts<D1: DownloadCounts + Send + Sync>(
+        self,
+        download_counts: D1,
+    ) -> Policy<A, T, U, P, C, Adv, AC, W, D1, G, M> {
+        Policy {
+            auth: self.auth,
+            token_authz: self.token_authz,
+            configurator: self.configurator,
+            user_storage: self.user_storage,
+            package_storage: self.package_storage,
+            advisories: self.advisories,
+            access_control: self.access_control,
+            webhooks: self.webhooks,
+            download_counts,
+            geolocator: self.geolocator,
+            moderation: self.moderation,
+            tasks: self.tasks,
+            events: self.events,
+            nonces: self.nonces,
+            transforms: self.transforms,
+        }
+    }
+
+    pub fn with_geolocator<G1: Geolocator + Send + Sync>(
+        self,
+        geolocator: G1,
+    ) -> Policy<A, T, U, P, C, Adv, AC, W, D, G1, M> {
+        Policy {
+            auth: self.auth,
+            token_authz: self.token_authz,
+            configurator: self.configurator,
+            user_storage: self.user_storage,
+            package_storage: self.package_storage,
+            advisories: self.advisories,
+            access_control: self.access_control,
+            webhooks: self.webhooks,
+            download_counts: self.download_counts,
+            geolocator,
+            moderation: self.moderation,
+            tasks: self.tasks,
+            events: self.events,
+            nonces: self.nonces,
+            transforms: self.transforms,
+        }
+    }
+
+    pub fn with_moderation<M1: Moderation + Send + Sync>(
+        self,
+        moderation: M1,
+    ) -> Policy<A, T, U, P, C, Adv, AC, W, D, G, M1> {
+        Policy {
+            auth: self.auth,
+            token_authz: self.token_authz,
+            configurator: self.configurator,
+            user_storage: self.user_storage,
+            package_storage: self.package_storage,
+            advisories: self.advisories,
+            access_control: self.access_control,
+            webhooks: self.webhooks,
+            download_counts: self.download_counts,
+            geolocator: self.geolocator,
+            moderation,
+            tasks: self.tasks,
+            events: self.events,
+            nonces: self.nonces,
+            transforms: self.transforms,
+        }
+    }
+
+    /// Use `tasks` for background work, so the caller can shut it down with the server.
+    pub fn with_tasks(self, tasks: TaskRegistry) -> Self {
+        Policy { tasks, ..self }
+    }
+
+    /// Publish package events on `events`, e.g. to share one bus between several policies.
+    pub fn with_events(self, events: EventBus) -> Self {
+        Policy { events, ..self }
+    }
+
+    /// Transform served packuments with `transform`, after any registered before it.
+    pub fn with_packument_transform(
+        mut self,
+        transform: impl PackumentTransform + 'static,
+    ) -> Self {
+        self.transforms.push(Arc::new(transform));
+        self
+    }
 }
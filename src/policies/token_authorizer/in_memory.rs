@@ -1,23 +1,23 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 use crate::models::User;
 use crate::policies::TokenAuthorizer;
 
 use chrono::Utc;
 
-use futures_util::StreamExt;
-
-use tokio::sync::RwLock;
-
 use uuid::Uuid;
 
-use super::TokenSession;
+use super::{LoginEvent, TokenMetadata, TokenOptions, TokenSession};
+
+// Logins remembered per user; older ones are forgotten.
+const LOGIN_HISTORY_LIMIT: usize = 50;
 
 #[derive(Clone)]
 pub struct InMemoryTokenAuthorizer {
     token_sessions: Arc<RwLock<HashMap<Uuid, TokenSession>>>,
+    logins: Arc<RwLock<HashMap<String, VecDeque<LoginEvent>>>>,
 }
 
 impl std::fmt::Debug for InMemoryTokenAuthorizer {
@@ -34,6 +34,7 @@ impl InMemoryTokenAuthorizer {
     pub fn new() -> Self {
         Self {
             token_sessions: Arc::new(RwLock::new(HashMap::new())),
+            logins: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 }
@@ -44,29 +45,149 @@ impl Default for InMemoryTokenAuthorizer {
     }
 }
 
+fn to_metadata(token: &Uuid, session: &TokenSession) -> TokenMetadata {
+    let token = token.to_string();
+    TokenMetadata {
+        key: session.key.clone(),
+        token: format!("{}…", &token[..6]),
+        readonly: session.readonly,
+        cidr_whitelist: session.cidr_whitelist.clone(),
+        created: session.initialized_at,
+        updated: session.initialized_at,
+        expires: session.expires,
+        preview: session.preview,
+    }
+}
+
 #[async_trait::async_trait]
 impl TokenAuthorizer for InMemoryTokenAuthorizer {
     type TokenSessionId = Uuid;
     async fn start_session(&self, user: User) -> anyhow::Result<Self::TokenSessionId> {
-        let key = Uuid::new_v4();
-        self.token_sessions.write().await.insert(
-            key,
-            TokenSession {
-                initialized_at: Utc::now(),
-                user,
-            },
-        );
-
+        let (key, _) = self.create_token(user, TokenOptions::default()).await?;
         Ok(key)
     }
 
+    async fn create_token(
+        &self,
+        user: User,
+        options: TokenOptions,
+    ) -> anyhow::Result<(Self::TokenSessionId, TokenMetadata)> {
+        let token = Uuid::new_v4();
+        let session = TokenSession {
+            key: Uuid::new_v4().simple().to_string(),
+            initialized_at: Utc::now(),
+            user,
+            readonly: options.readonly,
+            cidr_whitelist: options.cidr_whitelist,
+            expires: options.expires,
+            preview: options.preview,
+        };
+        let metadata = to_metadata(&token, &session);
+        self.token_sessions.write().unwrap().insert(token, session);
+
+        Ok((token, metadata))
+    }
+
+    async fn list_tokens(&self, username: &str) -> anyhow::Result<Vec<TokenMetadata>> {
+        let sessions = self.token_sessions.read().unwrap();
+        let mut tokens: Vec<_> = sessions
+            .iter()
+            .filter(|(_, session)| session.user.name == username && !session.is_expired())
+            .map(|(token, session)| to_metadata(token, session))
+            .collect();
+        tokens.sort_by_key(|token| token.created);
+
+        Ok(tokens)
+    }
+
+    async fn revoke_token(&self, username: &str, key: &str) -> anyhow::Result<bool> {
+        let mut sessions = self.token_sessions.write().unwrap();
+        let Some(token) = sessions
+            .iter()
+            .find(|(token, session)| {
+                session.user.name == username && (session.key == key || token.to_string() == key)
+            })
+            .map(|(token, _)| *token)
+        else {
+            return Ok(false);
+        };
+
+        sessions.remove(&token);
+        Ok(true)
+    }
+
+    async fn token_key(&self, bearer: &Self::TokenSessionId) -> anyhow::Result<Option<String>> {
+        let sessions = self.token_sessions.read().unwrap();
+        Ok(sessions
+            .get(bearer)
+            .filter(|session| !session.is_expired())
+            .map(|session| session.key.clone()))
+    }
+
+    async fn token_options(
+        &self,
+        bearer: &Self::TokenSessionId,
+    ) -> anyhow::Result<Option<TokenOptions>> {
+        let sessions = self.token_sessions.read().unwrap();
+        Ok(sessions
+            .get(bearer)
+            .filter(|session| !session.is_expired())
+            .map(|session| TokenOptions {
+                readonly: session.readonly,
+                cidr_whitelist: session.cidr_whitelist.clone(),
+                expires: session.expires,
+                preview: session.preview,
+            }))
+    }
+
+    async fn record_login(&self, login: LoginEvent) -> anyhow::Result<()> {
+        let mut logins = self.logins.write().unwrap();
+        let history = logins.entry(login.username.clone()).or_default();
+        history.push_front(login);
+        history.truncate(LOGIN_HISTORY_LIMIT);
+        Ok(())
+    }
+
+    async fn list_logins(&self, username: &str) -> anyhow::Result<Vec<LoginEvent>> {
+        let logins = self.logins.read().unwrap();
+        Ok(logins
+            .get(username)
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn token_holders(&self) -> anyhow::Result<Vec<String>> {
+        let mut holders: Vec<_> = self
+            .token_sessions
+            .read()
+            .unwrap()
+            .values()
+            .map(|session| session.user.name.clone())
+            .collect();
+        holders.sort();
+        holders.dedup();
+        Ok(holders)
+    }
+
+    async fn revoke_user_tokens(&self, username: &str) -> anyhow::Result<usize> {
+        let mut sessions = self.token_sessions.write().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, session| session.user.name != username);
+        Ok(before - sessions.len())
+    }
+
     async fn authenticate_session_bearer(
         &self,
         token: Self::TokenSessionId,
     ) -> anyhow::Result<Option<User>> {
-        let sessions = self.token_sessions.read().await;
-        let session = sessions.get(&token);
+        let session = self.token_sessions.read().unwrap().get(&token).cloned();
+
+        // Expired tokens are forgotten the first time they're presented afterwards.
+        if session.as_ref().is_some_and(TokenSession::is_expired) {
+            self.token_sessions.write().unwrap().remove(&token);
+            return Ok(None);
+        }
 
-        Ok(session.map(|sess| sess.user.clone()))
+        Ok(session.map(|sess| sess.user))
     }
 }
@@ -1,16 +1,82 @@
 use std::{fmt::Display, hash::Hash, str::FromStr};
 
 use axum::http::request::Parts;
+use axum::http::HeaderMap;
 use chrono::{DateTime, Utc};
+use serde::Serialize;
 
 use crate::models::User;
+use crate::policies::authenticator::IdentityStatus;
+use crate::policies::Authenticator;
 
 pub(crate) mod in_memory;
 
 #[derive(Clone, Debug)]
 pub(crate) struct TokenSession {
+    key: String,
     initialized_at: DateTime<Utc>,
     user: User,
+    readonly: bool,
+    cidr_whitelist: Option<Vec<String>>,
+    expires: Option<DateTime<Utc>>,
+    preview: bool,
+}
+
+impl TokenSession {
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= Utc::now())
+    }
+}
+
+/// Options accepted by `npm token create`, and those only the registry sets.
+#[derive(Clone, Debug, Default)]
+pub struct TokenOptions {
+    pub readonly: bool,
+    pub cidr_whitelist: Option<Vec<String>>,
+    /// When the token stops working; `None` lasts until it's revoked.
+    pub expires: Option<DateTime<Utc>>,
+    /// Limits the token to publishing prereleases under the configured preview scope.
+    pub preview: bool,
+}
+
+/// A token as presented by `npm token list`. The `token` field only ever holds a redacted
+/// prefix of the bearer token; `key` is the stable identifier used for revocation.
+#[derive(Clone, Debug, Serialize)]
+pub struct TokenMetadata {
+    pub key: String,
+    pub token: String,
+    pub readonly: bool,
+    pub cidr_whitelist: Option<Vec<String>>,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub preview: bool,
+}
+
+/// A login, as listed in the user's login history. `key` identifies the token session it
+/// started, which outlives the login only until it's revoked.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginEvent {
+    pub username: String,
+    pub key: String,
+    pub provider: String,
+    pub ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    pub user_agent: Option<String>,
+    pub time: DateTime<Utc>,
+}
+
+/// The bearer token in a request's `Authorization` header, if there is one.
+pub(crate) fn bearer_token<T: FromStr>(headers: &HeaderMap) -> Option<T> {
+    let authentication = headers.get("authorization")?.to_str().ok()?;
+    let authentication = authentication
+        .strip_prefix("Bearer ")
+        .or_else(|| authentication.strip_prefix("bearer "))?;
+    authentication.trim().parse().ok()
 }
 
 #[async_trait::async_trait]
@@ -26,23 +92,172 @@ pub trait TokenAuthorizer {
 
     async fn start_session(&self, user: User) -> anyhow::Result<Self::TokenSessionId>;
 
-    async fn authenticate_session(&self, req: &Parts) -> anyhow::Result<Option<User>> {
-        let Some(authentication) = req.headers.get("authorization") else {
-            return Ok(None);
-        };
+    async fn create_token(
+        &self,
+        _user: User,
+        _options: TokenOptions,
+    ) -> anyhow::Result<(Self::TokenSessionId, TokenMetadata)> {
+        Err(anyhow::anyhow!("token creation is not supported"))
+    }
 
-        let Ok(authentication) = authentication.to_str() else {
-            return Ok(None);
-        };
+    async fn list_tokens(&self, _username: &str) -> anyhow::Result<Vec<TokenMetadata>> {
+        Ok(Vec::new())
+    }
 
-        let Some(authentication) = authentication.strip_prefix("Bearer ").or_else(|| authentication.strip_prefix("bearer ")) else {
-            return Ok(None);
-        };
+    /// Revoke the token identified by `key` (or by a bearer token) belonging to `username`.
+    /// Returns `false` if no such token exists.
+    async fn revoke_token(&self, _username: &str, _key: &str) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    /// The revocation key of the token `bearer`, if it's still valid.
+    async fn token_key(&self, _bearer: &Self::TokenSessionId) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// The options the token `bearer` was created with, if it's still valid.
+    async fn token_options(
+        &self,
+        _bearer: &Self::TokenSessionId,
+    ) -> anyhow::Result<Option<TokenOptions>> {
+        Ok(None)
+    }
 
-        let Ok(token): Result<Self::TokenSessionId, _> = authentication.trim().parse() else {
+    async fn record_login(&self, _login: LoginEvent) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// `username`'s recent logins, newest first.
+    async fn list_logins(&self, _username: &str) -> anyhow::Result<Vec<LoginEvent>> {
+        Ok(Vec::new())
+    }
+
+    /// Names of users holding at least one token.
+    async fn token_holders(&self) -> anyhow::Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Revoke every token belonging to `username`, returning how many there were.
+    async fn revoke_user_tokens(&self, _username: &str) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+
+    /// Check each token holder with the identity provider and revoke the tokens of those it
+    /// reports deactivated, returning their names. Run periodically, this carries offboarding
+    /// at the provider through to the registry.
+    async fn revalidate_sessions<A: Authenticator>(
+        &self,
+        authenticator: &A,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut revoked = Vec::new();
+        for username in self.token_holders().await? {
+            match authenticator.identity_status(username.as_str()).await {
+                Ok(IdentityStatus::Deactivated) => {
+                    let tokens = self.revoke_user_tokens(username.as_str()).await?;
+                    tracing::info!(
+                        target: "audit",
+                        action = "token.revoke_deactivated",
+                        user = username,
+                        tokens
+                    );
+                    revoked.push(username);
+                }
+                Ok(IdentityStatus::Active | IdentityStatus::Unknown) => {}
+                Err(e) => {
+                    tracing::warn!(user = username, error = ?e, "could not check identity status");
+                }
+            }
+        }
+        Ok(revoked)
+    }
+
+    async fn authenticate_session(&self, req: &Parts) -> anyhow::Result<Option<User>> {
+        let Some(token) = bearer_token::<Self::TokenSessionId>(&req.headers) else {
             return Ok(None);
         };
 
         self.authenticate_session_bearer(token).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::{body::Body, http::Request};
+
+    use super::in_memory::InMemoryTokenAuthorizer;
+    use super::*;
+    use crate::policies::{Configurator, UserStorage};
+
+    fn user(name: &str) -> User {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "email": format!("{}@example.com", name)
+        }))
+        .unwrap()
+    }
+
+    // Answers `identity_status` from a table; `None` stands for a provider that failed.
+    struct FakeAuthenticator(HashMap<&'static str, Option<IdentityStatus>>);
+
+    #[async_trait::async_trait]
+    impl Authenticator for FakeAuthenticator {
+        type SessionId = String;
+        type Response = String;
+        type User = User;
+
+        async fn start_login_session(&self, _req: Request<Body>) -> anyhow::Result<String> {
+            unimplemented!()
+        }
+
+        async fn poll_login_session<C: Configurator + Send + Sync>(
+            &self,
+            _config: &C,
+            _session: String,
+        ) -> anyhow::Result<Option<User>> {
+            unimplemented!()
+        }
+
+        async fn complete_login_session<
+            C: Configurator + Send + Sync,
+            U: UserStorage + Send + Sync,
+        >(
+            &self,
+            _config: &C,
+            _user_storage: &U,
+            _req: Request<Body>,
+            _session: Option<String>,
+        ) -> anyhow::Result<String> {
+            unimplemented!()
+        }
+
+        async fn identity_status(&self, username: &str) -> anyhow::Result<IdentityStatus> {
+            self.0[username].ok_or_else(|| anyhow::anyhow!("provider unavailable"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_revalidate_sessions() {
+        let authenticator = FakeAuthenticator(HashMap::from([
+            ("ada", Some(IdentityStatus::Active)),
+            ("grace", Some(IdentityStatus::Unknown)),
+            ("mallory", Some(IdentityStatus::Deactivated)),
+            ("linus", None),
+        ]));
+        let tokens = InMemoryTokenAuthorizer::new();
+        for name in ["ada", "grace", "mallory", "linus"] {
+            tokens.start_session(user(name)).await.unwrap();
+            tokens.start_session(user(name)).await.unwrap();
+        }
+
+        let revoked = tokens.revalidate_sessions(&authenticator).await.unwrap();
+        assert_eq!(revoked, vec!["mallory".to_string()]);
+        assert_eq!(
+            tokens.token_holders().await.unwrap(),
+            vec!["ada".to_string(), "grace".to_string(), "linus".to_string()]
+        );
+        assert!(tokens.list_tokens("mallory").await.unwrap().is_empty());
+        assert_eq!(tokens.list_tokens("linus").await.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,177 @@
+//! Deployment-specific changes to the packuments we serve: injecting fields (an internal
+//! quality score, an owning team), stripping others, adding a banner to the readme.
+//!
+//! Transforms are registered with [`Policy::with_packument_transform`] and run in the order
+//! they were registered, each on the whole parsed document. Packuments no transform applies to
+//! are streamed from storage untouched; the rest are buffered, transformed and served with an
+//! ETag computed from what was actually sent, so revalidation keeps working.
+//!
+//! A transform that fails fails the request rather than serving the document unchanged, so
+//! that one which strips fields can't be bypassed by making it error. Only served documents
+//! change: what's stored, and what publishes and modifications start from, is left alone.
+//!
+//! [`Policy::with_packument_transform`]: crate::Policy::with_packument_transform
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::models::{PackageIdentifier, User};
+
+/// The request a packument is being served for.
+#[derive(Clone, Copy, Debug)]
+pub struct ServedPackument<'a> {
+    pub name: &'a PackageIdentifier,
+    /// Whether this is the abbreviated form npm installs from.
+    pub abbreviated: bool,
+    /// Who asked, if they sent a token.
+    pub user: Option<&'a User>,
+    /// Whether the client asked for the whole document with `?full=true`. Transforms that
+    /// leave parts of it out should let this one through untouched.
+    pub full: bool,
+}
+
+#[async_trait::async_trait]
+pub trait PackumentTransform: Send + Sync + std::fmt::Debug {
+    /// Whether this transform has anything to do for `packument`. Returning false for most
+    /// packages keeps them streaming straight from storage.
+    fn applies_to(&self, _packument: &ServedPackument<'_>) -> bool {
+        true
+    }
+
+    async fn transform(
+        &self,
+        packument: &ServedPackument<'_>,
+        document: &mut serde_json::Value,
+    ) -> anyhow::Result<()>;
+}
+
+/// Serves only the newest `max` versions of each release line, so that packuments with
+/// thousands of versions stay quick to download and parse.
+///
+/// A release line is a major version; its prereleases count as a line of their own, so a
+/// stream of nightlies can't crowd out the releases. Versions a dist-tag points at are always
+/// kept, and `time` is left whole, so the publish history stays complete. Anything that isn't
+/// semver is kept as well.
+#[derive(Clone, Copy, Debug)]
+pub struct TrimVersions {
+    max: usize,
+}
+
+impl TrimVersions {
+    pub fn new(max: usize) -> Self {
+        Self { max }
+    }
+
+    fn kept<'a>(
+        &self,
+        versions: impl Iterator<Item = &'a String>,
+        tagged: &HashSet<&str>,
+    ) -> HashSet<String> {
+        let mut kept = HashSet::new();
+        let mut lines: BTreeMap<(u64, bool), Vec<semver::Version>> = BTreeMap::new();
+        for number in versions {
+            match semver::Version::parse(number) {
+                Ok(version) if !tagged.contains(number.as_str()) => lines
+                    .entry((version.major, !version.pre.is_empty()))
+                    .or_default()
+                    .push(version),
+                _ => {
+                    kept.insert(number.clone());
+                }
+            }
+        }
+
+        for mut line in lines.into_values() {
+            line.sort_unstable_by(|a, b| b.cmp(a));
+            kept.extend(
+                line.iter()
+                    .take(self.max)
+                    .map(|version| version.to_string()),
+            );
+        }
+        kept
+    }
+}
+
+#[async_trait::async_trait]
+impl PackumentTransform for TrimVersions {
+    fn applies_to(&self, packument: &ServedPackument<'_>) -> bool {
+        !packument.full
+    }
+
+    async fn transform(
+        &self,
+        _packument: &ServedPackument<'_>,
+        document: &mut serde_json::Value,
+    ) -> anyhow::Result<()> {
+        let tagged: HashSet<&str> = document
+            .get("dist-tags")
+            .and_then(|tags| tags.as_object())
+            .map(|tags| tags.values().filter_map(|tag| tag.as_str()).collect())
+            .unwrap_or_default();
+        let Some(versions) = document.get("versions").and_then(|v| v.as_object()) else {
+            return Ok(());
+        };
+        if versions.len() <= self.max {
+            return Ok(());
+        }
+
+        let kept = self.kept(versions.keys(), &tagged);
+        if let Some(versions) = document
+            .get_mut("versions")
+            .and_then(|versions| versions.as_object_mut())
+        {
+            versions.retain(|number, _| kept.contains(number));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_trim_versions() {
+        let name: PackageIdentifier = "left-pad".parse().unwrap();
+        let served = ServedPackument {
+            name: &name,
+            abbreviated: false,
+            user: None,
+            full: false,
+        };
+        let versions: serde_json::Map<_, _> =
+            "1.0.0 1.1.0 1.2.0 2.0.0-rc.1 2.0.0-rc.2 2.0.0 2.1.0 2.2.0 3.0.0-rc.1 3.0.0-rc.2 legacy"
+                .split(' ')
+                .map(|number| (number.to_string(), serde_json::json!({})))
+                .collect();
+        let mut document = serde_json::json!({
+            "dist-tags": { "latest": "2.2.0", "old": "1.0.0" },
+            "versions": versions,
+            "time": { "1.1.0": "2020-01-01T00:00:00.000Z" }
+        });
+
+        TrimVersions::new(1)
+            .transform(&served, &mut document)
+            .await
+            .unwrap();
+        let mut kept: Vec<_> = document["versions"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        kept.sort_unstable();
+        assert_eq!(
+            kept.join(" "),
+            "1.0.0 1.2.0 2.0.0-rc.2 2.1.0 2.2.0 3.0.0-rc.2 legacy"
+        );
+        assert_eq!(document["time"]["1.1.0"], "2020-01-01T00:00:00.000Z");
+
+        let mut untouched = serde_json::json!({ "versions": { "1.0.0": {} } });
+        TrimVersions::new(2)
+            .transform(&served, &mut untouched)
+            .await
+            .unwrap();
+        assert_eq!(untouched["versions"].as_object().unwrap().len(), 1);
+    }
+}
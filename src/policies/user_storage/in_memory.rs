@@ -1,21 +1,37 @@
-use std::{collections::HashMap, fmt::Debug, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Debug,
+    sync::{Arc, RwLock},
+};
 
 use serde::Serialize;
-use tokio::sync::RwLock;
 
-use crate::models::User;
+use crate::models::{DistTagPolicy, OrgRole, ProfileUpdate, User};
 
-use super::UserStorage;
+use super::{ExportedUser, UserExport, UserStorage};
 
 #[derive(Clone)]
 pub struct InMemoryUserStorage {
     users: Arc<RwLock<HashMap<String, User>>>,
+    orgs: Arc<RwLock<HashMap<String, BTreeMap<String, OrgRole>>>>,
+    teams: Arc<RwLock<Teams>>,
+    dist_tag_policies: Arc<RwLock<HashMap<String, DistTagPolicy>>>,
+    deactivated: Arc<RwLock<BTreeSet<String>>>,
+    verified_scopes: Arc<RwLock<HashMap<String, Vec<String>>>>,
 }
 
+// Members of each team, keyed by (org, team).
+type Teams = BTreeMap<(String, String), BTreeSet<String>>;
+
 impl InMemoryUserStorage {
     pub fn new() -> Self {
         Self {
             users: Arc::new(RwLock::new(HashMap::new())),
+            orgs: Arc::new(RwLock::new(HashMap::new())),
+            teams: Arc::new(RwLock::new(BTreeMap::new())),
+            dist_tag_policies: Arc::new(RwLock::new(HashMap::new())),
+            deactivated: Arc::new(RwLock::new(BTreeSet::new())),
+            verified_scopes: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 }
@@ -32,6 +48,18 @@ impl Debug for InMemoryUserStorage {
         if let Ok(users) = self.users.try_read() {
             formatter.field("users", &users);
         }
+        if let Ok(orgs) = self.orgs.try_read() {
+            formatter.field("orgs", &orgs);
+        }
+        if let Ok(teams) = self.teams.try_read() {
+            formatter.field("teams", &teams);
+        }
+        if let Ok(policies) = self.dist_tag_policies.try_read() {
+            formatter.field("dist_tag_policies", &policies);
+        }
+        if let Ok(deactivated) = self.deactivated.try_read() {
+            formatter.field("deactivated", &deactivated);
+        }
         formatter.finish()
     }
 }
@@ -43,13 +71,22 @@ impl UserStorage for InMemoryUserStorage {
         user: U,
     ) -> anyhow::Result<User> {
         let user = user.into();
-        let mut users = self.users.write().await;
+        let mut users = self.users.write().unwrap();
+
+        // Returning users keep whatever they've set via `npm profile set`.
+        let user = match users.remove(&user.name) {
+            Some(mut existing) => {
+                existing.full_name = existing.full_name.or(user.full_name);
+                existing
+            }
+            None => user,
+        };
         users.insert(user.name.clone(), user.clone());
         Ok(user)
     }
 
     async fn get_user(&self, username: &str) -> anyhow::Result<User> {
-        let users = self.users.read().await;
+        let users = self.users.read().unwrap();
         users
             .get(username)
             .cloned()
@@ -57,6 +94,327 @@ impl UserStorage for InMemoryUserStorage {
     }
 
     async fn list_users(&self) -> anyhow::Result<Vec<User>> {
-        todo!()
+        let mut users: Vec<_> = self.users.read().unwrap().values().cloned().collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(users)
+    }
+
+    async fn update_user(&self, username: &str, update: ProfileUpdate) -> anyhow::Result<User> {
+        let mut users = self.users.write().unwrap();
+        let Some(user) = users.get_mut(username) else {
+            anyhow::bail!("no such user");
+        };
+
+        user.apply_profile_update(update);
+        Ok(user.clone())
+    }
+
+    async fn remove_user(&self, username: &str) -> anyhow::Result<bool> {
+        let removed = self.users.write().unwrap().remove(username).is_some();
+
+        let mut orgs = self.orgs.write().unwrap();
+        for members in orgs.values_mut() {
+            members.remove(username);
+        }
+        orgs.retain(|_, members| !members.is_empty());
+
+        for team in self.teams.write().unwrap().values_mut() {
+            team.remove(username);
+        }
+        self.deactivated.write().unwrap().remove(username);
+        self.verified_scopes.write().unwrap().remove(username);
+        Ok(removed)
+    }
+
+    async fn is_active(&self, username: &str) -> anyhow::Result<bool> {
+        Ok(!self.deactivated.read().unwrap().contains(username))
+    }
+
+    async fn set_active(&self, username: &str, active: bool) -> anyhow::Result<()> {
+        if !self.users.read().unwrap().contains_key(username) {
+            anyhow::bail!("no such user");
+        }
+
+        let mut deactivated = self.deactivated.write().unwrap();
+        if active {
+            deactivated.remove(username);
+        } else {
+            deactivated.insert(username.to_string());
+        }
+        Ok(())
+    }
+
+    async fn set_org_member(&self, org: &str, username: &str, role: OrgRole) -> anyhow::Result<()> {
+        self.orgs
+            .write()
+            .unwrap()
+            .entry(org.to_string())
+            .or_default()
+            .insert(username.to_string(), role);
+        Ok(())
+    }
+
+    async fn claim_org(&self, org: &str, username: &str) -> anyhow::Result<bool> {
+        let mut orgs = self.orgs.write().unwrap();
+        let members = orgs.entry(org.to_string()).or_default();
+        if !members.is_empty() {
+            return Ok(false);
+        }
+
+        members.insert(username.to_string(), OrgRole::Owner);
+        Ok(true)
+    }
+
+    async fn remove_org_member(&self, org: &str, username: &str) -> anyhow::Result<bool> {
+        let mut orgs = self.orgs.write().unwrap();
+        let Some(members) = orgs.get_mut(org) else {
+            return Ok(false);
+        };
+
+        let removed = members.remove(username).is_some();
+        if members.is_empty() {
+            orgs.remove(org);
+        }
+
+        // Leaving an org means leaving its teams.
+        for ((team_org, _), team) in self.teams.write().unwrap().iter_mut() {
+            if team_org == org {
+                team.remove(username);
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn list_org_members(&self, org: &str) -> anyhow::Result<BTreeMap<String, OrgRole>> {
+        Ok(self
+            .orgs
+            .read()
+            .unwrap()
+            .get(org)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn list_orgs(&self) -> anyhow::Result<Vec<String>> {
+        // An org whose members have all left may still have teams.
+        let mut orgs: BTreeSet<_> = self.orgs.read().unwrap().keys().cloned().collect();
+        orgs.extend(
+            self.teams
+                .read()
+                .unwrap()
+                .keys()
+                .map(|(org, _)| org.clone()),
+        );
+        Ok(orgs.into_iter().collect())
+    }
+
+    async fn orgs_for_user(&self, username: &str) -> anyhow::Result<Vec<String>> {
+        let mut orgs: Vec<_> = self
+            .orgs
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, members)| members.contains_key(username))
+            .map(|(org, _)| org.clone())
+            .collect();
+        orgs.sort();
+        Ok(orgs)
+    }
+
+    async fn set_verified_scopes(&self, username: &str, scopes: Vec<String>) -> anyhow::Result<()> {
+        self.verified_scopes
+            .write()
+            .unwrap()
+            .insert(username.to_string(), scopes);
+        Ok(())
+    }
+
+    async fn verified_scopes(&self, username: &str) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .verified_scopes
+            .read()
+            .unwrap()
+            .get(username)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn org_dist_tag_policy(&self, org: &str) -> anyhow::Result<Option<DistTagPolicy>> {
+        Ok(self.dist_tag_policies.read().unwrap().get(org).cloned())
+    }
+
+    async fn set_org_dist_tag_policy(
+        &self,
+        org: &str,
+        policy: DistTagPolicy,
+    ) -> anyhow::Result<()> {
+        self.dist_tag_policies
+            .write()
+            .unwrap()
+            .insert(org.to_string(), policy);
+        Ok(())
+    }
+
+    async fn create_team(
+        &self,
+        org: &str,
+        team: &str,
+        _description: Option<String>,
+    ) -> anyhow::Result<bool> {
+        let mut teams = self.teams.write().unwrap();
+        let key = (org.to_string(), team.to_string());
+        if teams.contains_key(&key) {
+            return Ok(false);
+        }
+
+        teams.insert(key, BTreeSet::new());
+        Ok(true)
+    }
+
+    async fn delete_team(&self, org: &str, team: &str) -> anyhow::Result<bool> {
+        Ok(self
+            .teams
+            .write()
+            .unwrap()
+            .remove(&(org.to_string(), team.to_string()))
+            .is_some())
+    }
+
+    async fn list_teams(&self, org: &str) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .teams
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|(team_org, _)| team_org == org)
+            .map(|(team_org, team)| format!("{}:{}", team_org, team))
+            .collect())
+    }
+
+    async fn add_team_member(&self, org: &str, team: &str, username: &str) -> anyhow::Result<bool> {
+        let mut teams = self.teams.write().unwrap();
+        let Some(team) = teams.get_mut(&(org.to_string(), team.to_string())) else {
+            return Ok(false);
+        };
+
+        team.insert(username.to_string());
+        Ok(true)
+    }
+
+    async fn remove_team_member(
+        &self,
+        org: &str,
+        team: &str,
+        username: &str,
+    ) -> anyhow::Result<bool> {
+        let mut teams = self.teams.write().unwrap();
+        let Some(team) = teams.get_mut(&(org.to_string(), team.to_string())) else {
+            return Ok(false);
+        };
+
+        Ok(team.remove(username))
+    }
+
+    async fn list_team_members(
+        &self,
+        org: &str,
+        team: &str,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        Ok(self
+            .teams
+            .read()
+            .unwrap()
+            .get(&(org.to_string(), team.to_string()))
+            .map(|members| members.iter().cloned().collect()))
+    }
+
+    async fn teams_for_user(&self, username: &str) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .teams
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, members)| members.contains(username))
+            .map(|((org, team), _)| format!("{}:{}", org, team))
+            .collect())
+    }
+
+    async fn export_users(&self) -> anyhow::Result<UserExport> {
+        let mut users: Vec<_> = {
+            let users = self.users.read().unwrap();
+            let deactivated = self.deactivated.read().unwrap();
+            users
+                .values()
+                .map(|user| ExportedUser {
+                    user: user.clone(),
+                    active: !deactivated.contains(&user.name),
+                })
+                .collect()
+        };
+        users.sort_by(|a, b| a.user.name.cmp(&b.user.name));
+
+        let orgs = self.orgs.read().unwrap();
+        let teams = self.teams.read().unwrap();
+        let dist_tag_policies = self.dist_tag_policies.read().unwrap();
+        Ok(UserExport {
+            users,
+            orgs: orgs
+                .iter()
+                .map(|(org, members)| (org.clone(), members.clone()))
+                .collect(),
+            teams: teams
+                .iter()
+                .map(|((org, team), members)| {
+                    (
+                        format!("{}:{}", org, team),
+                        members.iter().cloned().collect(),
+                    )
+                })
+                .collect(),
+            dist_tag_policies: dist_tag_policies
+                .iter()
+                .map(|(org, policy)| (org.clone(), policy.clone()))
+                .collect(),
+        })
+    }
+
+    async fn import_users(&self, export: UserExport) -> anyhow::Result<()> {
+        // Checked before anything is written, so a bad export changes nothing.
+        let mut imported_teams = Vec::with_capacity(export.teams.len());
+        for (key, members) in export.teams {
+            let Some((org, team)) = key.split_once(':') else {
+                anyhow::bail!("{} is not an org:team", key);
+            };
+            imported_teams.push(((org.to_string(), team.to_string()), members));
+        }
+
+        {
+            let mut users = self.users.write().unwrap();
+            let mut deactivated = self.deactivated.write().unwrap();
+            for ExportedUser { user, active } in export.users {
+                if active {
+                    deactivated.remove(&user.name);
+                } else {
+                    deactivated.insert(user.name.clone());
+                }
+                users.insert(user.name.clone(), user);
+            }
+        }
+
+        let mut orgs = self.orgs.write().unwrap();
+        for (org, members) in export.orgs {
+            orgs.entry(org).or_default().extend(members);
+        }
+
+        let mut teams = self.teams.write().unwrap();
+        for (key, members) in imported_teams {
+            teams.insert(key, members.into_iter().collect());
+        }
+
+        self.dist_tag_policies
+            .write()
+            .unwrap()
+            .extend(export.dist_tag_policies);
+        Ok(())
     }
 }
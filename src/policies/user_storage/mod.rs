@@ -1,8 +1,39 @@
-use serde::Serialize;
+use std::collections::BTreeMap;
 
-use crate::models::User;
+use serde::{Deserialize, Serialize};
+
+use crate::models::{DistTagPolicy, OrgRole, ProfileUpdate, User};
 
 pub(crate) mod in_memory;
+#[cfg(feature = "tokio-backends")]
+pub(crate) mod sqlite;
+
+/// Everything a user storage knows about its users, in a form one registry can export and
+/// another import: for migrations, and for restoring a lost instance.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UserExport {
+    pub users: Vec<ExportedUser>,
+    /// Members of each org, with their roles.
+    #[serde(default)]
+    pub orgs: BTreeMap<String, BTreeMap<String, OrgRole>>,
+    /// Members of each team, keyed by `org:team`.
+    #[serde(default)]
+    pub teams: BTreeMap<String, Vec<String>>,
+    #[serde(default, rename = "distTagPolicies")]
+    pub dist_tag_policies: BTreeMap<String, DistTagPolicy>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExportedUser {
+    #[serde(flatten)]
+    pub user: User,
+    #[serde(default = "active")]
+    pub active: bool,
+}
+
+fn active() -> bool {
+    true
+}
 
 #[async_trait::async_trait]
 pub trait UserStorage: Send + Sync {
@@ -12,4 +43,149 @@ pub trait UserStorage: Send + Sync {
     ) -> anyhow::Result<User>;
     async fn get_user(&self, username: &str) -> anyhow::Result<User>;
     async fn list_users(&self) -> anyhow::Result<Vec<User>>;
+    async fn update_user(&self, username: &str, update: ProfileUpdate) -> anyhow::Result<User>;
+
+    /// Forget `username` altogether, along with their org and team memberships. Returns false
+    /// if there was no such user.
+    async fn remove_user(&self, _username: &str) -> anyhow::Result<bool> {
+        anyhow::bail!("this user storage does not support removing users")
+    }
+
+    /// Deactivated users keep their record but may not authenticate.
+    async fn is_active(&self, _username: &str) -> anyhow::Result<bool> {
+        Ok(true)
+    }
+
+    async fn set_active(&self, _username: &str, _active: bool) -> anyhow::Result<()> {
+        anyhow::bail!("this user storage does not support deactivating users")
+    }
+
+    /// Add `username` to `org`, or change their role if they're already a member.
+    async fn set_org_member(
+        &self,
+        _org: &str,
+        _username: &str,
+        _role: OrgRole,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("this user storage does not support organizations")
+    }
+
+    /// Make `username` the owner of `org` if, and only if, it has no members yet. Returns false
+    /// if someone else got there first.
+    async fn claim_org(&self, _org: &str, _username: &str) -> anyhow::Result<bool> {
+        anyhow::bail!("this user storage does not support organizations")
+    }
+
+    /// Returns false if `username` wasn't a member.
+    async fn remove_org_member(&self, _org: &str, _username: &str) -> anyhow::Result<bool> {
+        anyhow::bail!("this user storage does not support organizations")
+    }
+
+    /// Members of `org` and their roles. An unknown org has no members.
+    async fn list_org_members(&self, _org: &str) -> anyhow::Result<BTreeMap<String, OrgRole>> {
+        anyhow::bail!("this user storage does not support organizations")
+    }
+
+    /// Every org with members or teams.
+    async fn list_orgs(&self) -> anyhow::Result<Vec<String>> {
+        anyhow::bail!("this user storage does not support organizations")
+    }
+
+    /// Record the scope names the identity provider vouched for at `username`'s latest login
+    /// (for GitHub, the organizations they belong to.)
+    async fn set_verified_scopes(
+        &self,
+        _username: &str,
+        _scopes: Vec<String>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Scope names recorded by [`UserStorage::set_verified_scopes`]. These may be claimed
+    /// without an admin.
+    async fn verified_scopes(&self, _username: &str) -> anyhow::Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Every org `username` is a member of.
+    async fn orgs_for_user(&self, _username: &str) -> anyhow::Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// The rules for dist-tags on `org`'s packages, if it has set any.
+    async fn org_dist_tag_policy(&self, _org: &str) -> anyhow::Result<Option<DistTagPolicy>> {
+        Ok(None)
+    }
+
+    async fn set_org_dist_tag_policy(
+        &self,
+        _org: &str,
+        _policy: DistTagPolicy,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("this user storage does not support organizations")
+    }
+
+    /// Create `org:team`. Returns false if it already exists.
+    async fn create_team(
+        &self,
+        _org: &str,
+        _team: &str,
+        _description: Option<String>,
+    ) -> anyhow::Result<bool> {
+        anyhow::bail!("this user storage does not support teams")
+    }
+
+    /// Returns false if there was no such team.
+    async fn delete_team(&self, _org: &str, _team: &str) -> anyhow::Result<bool> {
+        anyhow::bail!("this user storage does not support teams")
+    }
+
+    async fn list_teams(&self, _org: &str) -> anyhow::Result<Vec<String>> {
+        anyhow::bail!("this user storage does not support teams")
+    }
+
+    /// Returns false if there is no such team.
+    async fn add_team_member(
+        &self,
+        _org: &str,
+        _team: &str,
+        _username: &str,
+    ) -> anyhow::Result<bool> {
+        anyhow::bail!("this user storage does not support teams")
+    }
+
+    /// Returns false if there is no such team, or `username` wasn't on it.
+    async fn remove_team_member(
+        &self,
+        _org: &str,
+        _team: &str,
+        _username: &str,
+    ) -> anyhow::Result<bool> {
+        anyhow::bail!("this user storage does not support teams")
+    }
+
+    /// `None` if there is no such team.
+    async fn list_team_members(
+        &self,
+        _org: &str,
+        _team: &str,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        anyhow::bail!("this user storage does not support teams")
+    }
+
+    /// Every team `username` belongs to, as `org:team` grantees.
+    async fn teams_for_user(&self, _username: &str) -> anyhow::Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Every user, org membership, team and org dist-tag policy, read in one go.
+    async fn export_users(&self) -> anyhow::Result<UserExport> {
+        anyhow::bail!("this user storage does not support exporting users")
+    }
+
+    /// Add everything in `export`. Users, roles and teams it names replace any already here,
+    /// and anything it doesn't name is left alone. Team keys must be `org:team`.
+    async fn import_users(&self, _export: UserExport) -> anyhow::Result<()> {
+        anyhow::bail!("this user storage does not support importing users")
+    }
 }
@@ -0,0 +1,703 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::migrations::Migrator;
+use crate::models::{DistTagPolicy, OrgRole, ProfileUpdate, User};
+
+use super::{ExportedUser, UserExport, UserStorage};
+
+pub fn migrations() -> Migrator<Connection> {
+    Migrator::new("user database").migration("create the user tables", |connection| {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS users (
+                name TEXT PRIMARY KEY,
+                profile TEXT NOT NULL,
+                active INTEGER NOT NULL DEFAULT 1
+            );
+            CREATE TABLE IF NOT EXISTS org_members (
+                org TEXT NOT NULL,
+                username TEXT NOT NULL,
+                role TEXT NOT NULL,
+                PRIMARY KEY (org, username)
+            );
+            CREATE TABLE IF NOT EXISTS teams (
+                org TEXT NOT NULL,
+                team TEXT NOT NULL,
+                description TEXT,
+                PRIMARY KEY (org, team)
+            );
+            CREATE TABLE IF NOT EXISTS team_members (
+                org TEXT NOT NULL,
+                team TEXT NOT NULL,
+                username TEXT NOT NULL,
+                PRIMARY KEY (org, team, username)
+            );
+            CREATE TABLE IF NOT EXISTS dist_tag_policies (
+                org TEXT PRIMARY KEY,
+                policy TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS verified_scopes (
+                username TEXT NOT NULL,
+                scope TEXT NOT NULL,
+                PRIMARY KEY (username, scope)
+            );",
+        )?;
+        Ok(())
+    })
+}
+
+// Roles are stored by the names npm uses for them.
+fn role_name(role: OrgRole) -> anyhow::Result<String> {
+    match serde_json::to_value(role)? {
+        serde_json::Value::String(name) => Ok(name),
+        other => anyhow::bail!("unexpected role {}", other),
+    }
+}
+
+fn parse_role(name: String) -> anyhow::Result<OrgRole> {
+    Ok(serde_json::from_value(serde_json::Value::String(name))?)
+}
+
+/// Users, orgs and teams in a SQLite database, kept with the same rules as the in-memory
+/// storage.
+#[derive(Clone)]
+pub struct SqliteUserStorage {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl std::fmt::Debug for SqliteUserStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqliteUserStorage").finish_non_exhaustive()
+    }
+}
+
+impl SqliteUserStorage {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    pub fn in_memory() -> anyhow::Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(connection: Connection) -> anyhow::Result<Self> {
+        migrations().run(&connection, false)?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    async fn with_connection<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> anyhow::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let connection = connection.lock().unwrap_or_else(|e| e.into_inner());
+            f(&connection)
+        })
+        .await?
+    }
+
+    async fn strings<P>(&self, query: &'static str, params: P) -> anyhow::Result<Vec<String>>
+    where
+        P: rusqlite::Params + Send + 'static,
+    {
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare_cached(query)?;
+            let strings = statement
+                .query_map(params, |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(strings)
+        })
+        .await
+    }
+}
+
+fn read_user(connection: &Connection, username: &str) -> anyhow::Result<Option<User>> {
+    let profile: Option<String> = connection
+        .query_row(
+            "SELECT profile FROM users WHERE name = ?1",
+            params![username],
+            |row| row.get(0),
+        )
+        .optional()?;
+    profile
+        .map(|profile| Ok(serde_json::from_str(profile.as_str())?))
+        .transpose()
+}
+
+fn write_user(connection: &Connection, user: &User) -> anyhow::Result<()> {
+    connection.execute(
+        "INSERT INTO users (name, profile) VALUES (?1, ?2)
+         ON CONFLICT (name) DO UPDATE SET profile = excluded.profile",
+        params![user.name, serde_json::to_string(user)?],
+    )?;
+    Ok(())
+}
+
+fn org_members(connection: &Connection, org: &str) -> anyhow::Result<BTreeMap<String, OrgRole>> {
+    let mut statement =
+        connection.prepare_cached("SELECT username, role FROM org_members WHERE org = ?1")?;
+    let rows = statement
+        .query_map(params![org], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+    rows.into_iter()
+        .map(|(username, role)| Ok((username, parse_role(role)?)))
+        .collect()
+}
+
+fn team_exists(connection: &Connection, org: &str, team: &str) -> anyhow::Result<bool> {
+    Ok(connection
+        .query_row(
+            "SELECT 1 FROM teams WHERE org = ?1 AND team = ?2",
+            params![org, team],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+#[async_trait::async_trait]
+impl UserStorage for SqliteUserStorage {
+    async fn register_user<U: Into<User> + Serialize + Send + Sync>(
+        &self,
+        user: U,
+    ) -> anyhow::Result<User> {
+        let user = user.into();
+        self.with_connection(move |connection| {
+            // Returning users keep whatever they've set via `npm profile set`.
+            let user = match read_user(connection, user.name.as_str())? {
+                Some(mut existing) => {
+                    existing.full_name = existing.full_name.or(user.full_name);
+                    existing
+                }
+                None => user,
+            };
+            write_user(connection, &user)?;
+            Ok(user)
+        })
+        .await
+    }
+
+    async fn get_user(&self, username: &str) -> anyhow::Result<User> {
+        let username = username.to_string();
+        self.with_connection(move |connection| {
+            read_user(connection, username.as_str())?.ok_or_else(|| anyhow::anyhow!("no such user"))
+        })
+        .await
+    }
+
+    async fn list_users(&self) -> anyhow::Result<Vec<User>> {
+        self.strings("SELECT profile FROM users ORDER BY name", [])
+            .await?
+            .into_iter()
+            .map(|profile| Ok(serde_json::from_str(profile.as_str())?))
+            .collect()
+    }
+
+    async fn update_user(&self, username: &str, update: ProfileUpdate) -> anyhow::Result<User> {
+        let username = username.to_string();
+        self.with_connection(move |connection| {
+            let Some(mut user) = read_user(connection, username.as_str())? else {
+                anyhow::bail!("no such user");
+            };
+
+            user.apply_profile_update(update);
+            write_user(connection, &user)?;
+            Ok(user)
+        })
+        .await
+    }
+
+    async fn remove_user(&self, username: &str) -> anyhow::Result<bool> {
+        let username = username.to_string();
+        self.with_connection(move |connection| {
+            let transaction = connection.unchecked_transaction()?;
+            let removed =
+                transaction.execute("DELETE FROM users WHERE name = ?1", params![username])? > 0;
+            transaction.execute(
+                "DELETE FROM org_members WHERE username = ?1",
+                params![username],
+            )?;
+            transaction.execute(
+                "DELETE FROM team_members WHERE username = ?1",
+                params![username],
+            )?;
+            transaction.execute(
+                "DELETE FROM verified_scopes WHERE username = ?1",
+                params![username],
+            )?;
+            transaction.commit()?;
+            Ok(removed)
+        })
+        .await
+    }
+
+    async fn is_active(&self, username: &str) -> anyhow::Result<bool> {
+        let username = username.to_string();
+        self.with_connection(move |connection| {
+            let active: Option<bool> = connection
+                .query_row(
+                    "SELECT active FROM users WHERE name = ?1",
+                    params![username],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(active.unwrap_or(true))
+        })
+        .await
+    }
+
+    async fn set_active(&self, username: &str, active: bool) -> anyhow::Result<()> {
+        let username = username.to_string();
+        self.with_connection(move |connection| {
+            let updated = connection.execute(
+                "UPDATE users SET active = ?2 WHERE name = ?1",
+                params![username, active],
+            )?;
+            if updated == 0 {
+                anyhow::bail!("no such user");
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn set_org_member(&self, org: &str, username: &str, role: OrgRole) -> anyhow::Result<()> {
+        let (org, username, role) = (org.to_string(), username.to_string(), role_name(role)?);
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO org_members (org, username, role) VALUES (?1, ?2, ?3)",
+                params![org, username, role],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn claim_org(&self, org: &str, username: &str) -> anyhow::Result<bool> {
+        let (org, username, role) = (
+            org.to_string(),
+            username.to_string(),
+            role_name(OrgRole::Owner)?,
+        );
+        self.with_connection(move |connection| {
+            let claimed = connection.execute(
+                "INSERT INTO org_members (org, username, role) SELECT ?1, ?2, ?3
+                 WHERE NOT EXISTS (SELECT 1 FROM org_members WHERE org = ?1)",
+                params![org, username, role],
+            )?;
+            Ok(claimed > 0)
+        })
+        .await
+    }
+
+    async fn remove_org_member(&self, org: &str, username: &str) -> anyhow::Result<bool> {
+        let (org, username) = (org.to_string(), username.to_string());
+        self.with_connection(move |connection| {
+            let transaction = connection.unchecked_transaction()?;
+            let removed = transaction.execute(
+                "DELETE FROM org_members WHERE org = ?1 AND username = ?2",
+                params![org, username],
+            )? > 0;
+
+            // Leaving an org means leaving its teams.
+            transaction.execute(
+                "DELETE FROM team_members WHERE org = ?1 AND username = ?2",
+                params![org, username],
+            )?;
+            transaction.commit()?;
+            Ok(removed)
+        })
+        .await
+    }
+
+    async fn list_org_members(&self, org: &str) -> anyhow::Result<BTreeMap<String, OrgRole>> {
+        let org = org.to_string();
+        self.with_connection(move |connection| org_members(connection, org.as_str()))
+            .await
+    }
+
+    async fn list_orgs(&self) -> anyhow::Result<Vec<String>> {
+        // An org whose members have all left may still have teams.
+        self.strings(
+            "SELECT org FROM org_members UNION SELECT org FROM teams ORDER BY org",
+            [],
+        )
+        .await
+    }
+
+    async fn orgs_for_user(&self, username: &str) -> anyhow::Result<Vec<String>> {
+        self.strings(
+            "SELECT org FROM org_members WHERE username = ?1 ORDER BY org",
+            [username.to_string()],
+        )
+        .await
+    }
+
+    async fn set_verified_scopes(&self, username: &str, scopes: Vec<String>) -> anyhow::Result<()> {
+        let username = username.to_string();
+        self.with_connection(move |connection| {
+            let transaction = connection.unchecked_transaction()?;
+            transaction.execute(
+                "DELETE FROM verified_scopes WHERE username = ?1",
+                params![username],
+            )?;
+            for scope in scopes {
+                transaction.execute(
+                    "INSERT OR IGNORE INTO verified_scopes (username, scope) VALUES (?1, ?2)",
+                    params![username, scope],
+                )?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn verified_scopes(&self, username: &str) -> anyhow::Result<Vec<String>> {
+        self.strings(
+            "SELECT scope FROM verified_scopes WHERE username = ?1 ORDER BY scope",
+            [username.to_string()],
+        )
+        .await
+    }
+
+    async fn org_dist_tag_policy(&self, org: &str) -> anyhow::Result<Option<DistTagPolicy>> {
+        let policy = self
+            .strings(
+                "SELECT policy FROM dist_tag_policies WHERE org = ?1",
+                [org.to_string()],
+            )
+            .await?;
+        policy
+            .first()
+            .map(|policy| Ok(serde_json::from_str(policy.as_str())?))
+            .transpose()
+    }
+
+    async fn set_org_dist_tag_policy(
+        &self,
+        org: &str,
+        policy: DistTagPolicy,
+    ) -> anyhow::Result<()> {
+        let (org, policy) = (org.to_string(), serde_json::to_string(&policy)?);
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO dist_tag_policies (org, policy) VALUES (?1, ?2)",
+                params![org, policy],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn create_team(
+        &self,
+        org: &str,
+        team: &str,
+        description: Option<String>,
+    ) -> anyhow::Result<bool> {
+        let (org, team) = (org.to_string(), team.to_string());
+        self.with_connection(move |connection| {
+            let created = connection.execute(
+                "INSERT OR IGNORE INTO teams (org, team, description) VALUES (?1, ?2, ?3)",
+                params![org, team, description],
+            )?;
+            Ok(created > 0)
+        })
+        .await
+    }
+
+    async fn delete_team(&self, org: &str, team: &str) -> anyhow::Result<bool> {
+        let (org, team) = (org.to_string(), team.to_string());
+        self.with_connection(move |connection| {
+            let transaction = connection.unchecked_transaction()?;
+            let deleted = transaction.execute(
+                "DELETE FROM teams WHERE org = ?1 AND team = ?2",
+                params![org, team],
+            )? > 0;
+            transaction.execute(
+                "DELETE FROM team_members WHERE org = ?1 AND team = ?2",
+                params![org, team],
+            )?;
+            transaction.commit()?;
+            Ok(deleted)
+        })
+        .await
+    }
+
+    async fn list_teams(&self, org: &str) -> anyhow::Result<Vec<String>> {
+        self.strings(
+            "SELECT org || ':' || team FROM teams WHERE org = ?1 ORDER BY team",
+            [org.to_string()],
+        )
+        .await
+    }
+
+    async fn add_team_member(&self, org: &str, team: &str, username: &str) -> anyhow::Result<bool> {
+        let (org, team, username) = (org.to_string(), team.to_string(), username.to_string());
+        self.with_connection(move |connection| {
+            if !team_exists(connection, org.as_str(), team.as_str())? {
+                return Ok(false);
+            }
+
+            connection.execute(
+                "INSERT OR IGNORE INTO team_members (org, team, username) VALUES (?1, ?2, ?3)",
+                params![org, team, username],
+            )?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn remove_team_member(
+        &self,
+        org: &str,
+        team: &str,
+        username: &str,
+    ) -> anyhow::Result<bool> {
+        let (org, team, username) = (org.to_string(), team.to_string(), username.to_string());
+        self.with_connection(move |connection| {
+            let removed = connection.execute(
+                "DELETE FROM team_members WHERE org = ?1 AND team = ?2 AND username = ?3",
+                params![org, team, username],
+            )?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    async fn list_team_members(
+        &self,
+        org: &str,
+        team: &str,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        let (org, team) = (org.to_string(), team.to_string());
+        self.with_connection(move |connection| {
+            if !team_exists(connection, org.as_str(), team.as_str())? {
+                return Ok(None);
+            }
+
+            let mut statement = connection.prepare_cached(
+                "SELECT username FROM team_members WHERE org = ?1 AND team = ?2
+                 ORDER BY username",
+            )?;
+            let members = statement
+                .query_map(params![org, team], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(Some(members))
+        })
+        .await
+    }
+
+    async fn teams_for_user(&self, username: &str) -> anyhow::Result<Vec<String>> {
+        self.strings(
+            "SELECT org || ':' || team FROM team_members WHERE username = ?1 ORDER BY org, team",
+            [username.to_string()],
+        )
+        .await
+    }
+
+    async fn export_users(&self) -> anyhow::Result<UserExport> {
+        self.with_connection(|connection| {
+            let mut export = UserExport::default();
+
+            let mut statement =
+                connection.prepare("SELECT profile, active FROM users ORDER BY name")?;
+            let users = statement
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<Vec<(String, bool)>>>()?;
+            for (profile, active) in users {
+                export.users.push(ExportedUser {
+                    user: serde_json::from_str(profile.as_str())?,
+                    active,
+                });
+            }
+
+            let mut statement = connection.prepare("SELECT DISTINCT org FROM org_members")?;
+            let orgs = statement
+                .query_map([], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            for org in orgs {
+                let members = org_members(connection, org.as_str())?;
+                export.orgs.insert(org, members);
+            }
+
+            let mut statement = connection.prepare("SELECT org, team FROM teams")?;
+            let teams = statement
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+            for (org, team) in teams {
+                let mut statement = connection.prepare_cached(
+                    "SELECT username FROM team_members WHERE org = ?1 AND team = ?2
+                     ORDER BY username",
+                )?;
+                let members = statement
+                    .query_map(params![org, team], |row| row.get(0))?
+                    .collect::<rusqlite::Result<Vec<String>>>()?;
+                export.teams.insert(format!("{}:{}", org, team), members);
+            }
+
+            let mut statement = connection.prepare("SELECT org, policy FROM dist_tag_policies")?;
+            let policies = statement
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+            for (org, policy) in policies {
+                export
+                    .dist_tag_policies
+                    .insert(org, serde_json::from_str(policy.as_str())?);
+            }
+
+            Ok(export)
+        })
+        .await
+    }
+
+    // One transaction, so a bad export changes nothing.
+    async fn import_users(&self, export: UserExport) -> anyhow::Result<()> {
+        self.with_connection(move |connection| {
+            let transaction = connection.unchecked_transaction()?;
+            for ExportedUser { user, active } in export.users {
+                write_user(&transaction, &user)?;
+                transaction.execute(
+                    "UPDATE users SET active = ?2 WHERE name = ?1",
+                    params![user.name, active],
+                )?;
+            }
+
+            for (org, members) in export.orgs {
+                for (username, role) in members {
+                    transaction.execute(
+                        "INSERT OR REPLACE INTO org_members (org, username, role)
+                         VALUES (?1, ?2, ?3)",
+                        params![org, username, role_name(role)?],
+                    )?;
+                }
+            }
+
+            for (key, members) in export.teams {
+                let Some((org, team)) = key.split_once(':') else {
+                    anyhow::bail!("{} is not an org:team", key);
+                };
+                transaction.execute(
+                    "INSERT OR IGNORE INTO teams (org, team) VALUES (?1, ?2)",
+                    params![org, team],
+                )?;
+                transaction.execute(
+                    "DELETE FROM team_members WHERE org = ?1 AND team = ?2",
+                    params![org, team],
+                )?;
+                for username in members {
+                    transaction.execute(
+                        "INSERT OR IGNORE INTO team_members (org, team, username)
+                         VALUES (?1, ?2, ?3)",
+                        params![org, team, username],
+                    )?;
+                }
+            }
+
+            for (org, policy) in export.dist_tag_policies {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO dist_tag_policies (org, policy) VALUES (?1, ?2)",
+                    params![org, serde_json::to_string(&policy)?],
+                )?;
+            }
+
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str) -> User {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "email": format!("{}@example.com", name)
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_user_storage() {
+        let storage = SqliteUserStorage::in_memory().unwrap();
+        storage.register_user(user("ada")).await.unwrap();
+        storage.register_user(user("grace")).await.unwrap();
+        assert_eq!(storage.list_users().await.unwrap().len(), 2);
+        assert!(storage.get_user("nobody").await.is_err());
+
+        storage
+            .set_org_member("corp", "ada", OrgRole::Owner)
+            .await
+            .unwrap();
+        assert!(storage.create_team("corp", "devs", None).await.unwrap());
+        assert!(!storage.create_team("corp", "devs", None).await.unwrap());
+        assert!(storage
+            .add_team_member("corp", "devs", "ada")
+            .await
+            .unwrap());
+        assert!(!storage
+            .add_team_member("corp", "nope", "ada")
+            .await
+            .unwrap());
+        assert_eq!(
+            storage.teams_for_user("ada").await.unwrap(),
+            vec!["corp:devs".to_string()]
+        );
+
+        storage.set_active("grace", false).await.unwrap();
+        assert!(!storage.is_active("grace").await.unwrap());
+        assert!(storage.set_active("nobody", false).await.is_err());
+
+        let export = storage.export_users().await.unwrap();
+        assert_eq!(export.orgs["corp"]["ada"], OrgRole::Owner);
+        assert_eq!(export.teams["corp:devs"], vec!["ada".to_string()]);
+
+        assert!(storage.remove_org_member("corp", "ada").await.unwrap());
+        assert_eq!(
+            storage.list_team_members("corp", "devs").await.unwrap(),
+            Some(Vec::new())
+        );
+        assert_eq!(storage.list_orgs().await.unwrap(), vec!["corp".to_string()]);
+
+        let restored = SqliteUserStorage::in_memory().unwrap();
+        restored.import_users(export).await.unwrap();
+        assert!(!restored.is_active("grace").await.unwrap());
+        assert_eq!(
+            restored.list_org_members("corp").await.unwrap()["ada"],
+            OrgRole::Owner
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_claim_org() {
+        let storage = SqliteUserStorage::in_memory().unwrap();
+        storage
+            .set_verified_scopes("ada", vec!["corp".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.verified_scopes("ada").await.unwrap(),
+            vec!["corp".to_string()]
+        );
+
+        assert!(storage.claim_org("corp", "ada").await.unwrap());
+        assert!(!storage.claim_org("corp", "grace").await.unwrap());
+        assert_eq!(
+            storage.list_org_members("corp").await.unwrap(),
+            BTreeMap::from([("ada".to_string(), OrgRole::Owner)])
+        );
+    }
+}
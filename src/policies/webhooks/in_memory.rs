@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::policies::Webhooks;
+
+use super::{
+    delivery_signature, Delivery, DeliveryStatus, Hook, HookEvent, HookUpdate, NewHook,
+    SIGNATURE_HEADER,
+};
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_ATTEMPTS: u32 = 4;
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Keeps hooks and their delivery history in memory, delivering events with a signed POST
+/// (see [`delivery_signature`]).
+///
+/// A delivery that fails in a way that may pass (no response, a 5xx, a 408 or a 429) is
+/// retried, waiting twice as long before each retry as before the last. Replays are attempted
+/// once.
+#[derive(Clone)]
+pub struct InMemoryWebhooks {
+    hooks: Arc<RwLock<HashMap<String, Hook>>>,
+    deliveries: Arc<RwLock<HashMap<String, Vec<Delivery>>>>,
+    client: reqwest::Client,
+    attempts: u32,
+    backoff: Duration,
+}
+
+impl std::fmt::Debug for InMemoryWebhooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut formatter = f.debug_struct("InMemoryWebhooks");
+        if let Ok(hooks) = self.hooks.try_read() {
+            formatter.field("hooks", &hooks.len());
+        }
+        formatter.finish()
+    }
+}
+
+impl InMemoryWebhooks {
+    pub fn new() -> Self {
+        Self {
+            hooks: Arc::new(RwLock::new(HashMap::new())),
+            deliveries: Arc::new(RwLock::new(HashMap::new())),
+            client: reqwest::Client::new(),
+            attempts: DEFAULT_ATTEMPTS,
+            backoff: DEFAULT_BACKOFF,
+        }
+    }
+
+    /// Attempt each delivery up to `attempts` times, waiting `backoff` before the first retry.
+    pub fn with_retries(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.attempts = attempts.max(1);
+        self.backoff = backoff;
+        self
+    }
+
+    async fn send(&self, hook: &Hook, event: &HookEvent) -> anyhow::Result<reqwest::Response> {
+        let body = serde_json::to_vec(event)?;
+        let signature = delivery_signature(hook.secret.as_str(), body.as_slice())?;
+        Ok(self
+            .client
+            .post(hook.endpoint.as_str())
+            .timeout(DELIVERY_TIMEOUT)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, signature)
+            .body(body)
+            .send()
+            .await?)
+    }
+
+    async fn deliver_with_retries(&self, hook: &Hook, mut delivery: Delivery) -> Delivery {
+        let mut backoff = self.backoff;
+        loop {
+            delivery = self.deliver(hook, delivery).await;
+            let transient = match delivery.response_code {
+                None => true,
+                Some(code) => code >= 500 || code == 408 || code == 429,
+            };
+            if delivery.status == DeliveryStatus::Delivered
+                || !transient
+                || delivery.attempts >= self.attempts
+            {
+                return delivery;
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    async fn deliver(&self, hook: &Hook, mut delivery: Delivery) -> Delivery {
+        delivery.attempts += 1;
+        delivery.last_attempt = Some(Utc::now());
+
+        match self.send(hook, &delivery.event).await {
+            Ok(response) => {
+                let status = response.status();
+                delivery.response_code = Some(status.as_u16());
+                if status.is_success() {
+                    delivery.status = DeliveryStatus::Delivered;
+                    delivery.error = None;
+                } else {
+                    delivery.status = DeliveryStatus::Failed;
+                    delivery.error = Some(format!("endpoint responded with {}", status));
+                }
+            }
+            Err(e) => {
+                delivery.status = DeliveryStatus::Failed;
+                delivery.response_code = None;
+                delivery.error = Some(e.to_string());
+            }
+        }
+
+        if let Some(hook) = self.hooks.write().unwrap().get_mut(&hook.id) {
+            hook.last_delivery = delivery.last_attempt;
+            hook.response_code = delivery.response_code;
+            hook.delivered |= delivery.status == DeliveryStatus::Delivered;
+        }
+
+        let mut deliveries = self.deliveries.write().unwrap();
+        let history = deliveries.entry(hook.id.clone()).or_default();
+        match history.iter_mut().find(|prior| prior.id == delivery.id) {
+            Some(prior) => *prior = delivery.clone(),
+            None => history.push(delivery.clone()),
+        }
+
+        delivery
+    }
+}
+
+impl Default for InMemoryWebhooks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait::async_trait]
+impl Webhooks for InMemoryWebhooks {
+    async fn create_hook(&self, username: &str, hook: NewHook) -> anyhow::Result<Hook> {
+        let now = Utc::now();
+        let hook = Hook {
+            id: Uuid::new_v4().simple().to_string(),
+            username: username.to_string(),
+            hook_type: hook.hook_type,
+            name: hook.name,
+            endpoint: hook.endpoint,
+            secret: hook.secret,
+            events: hook.events,
+            created: now,
+            updated: now,
+            deleted: false,
+            delivered: false,
+            last_delivery: None,
+            response_code: None,
+            status: "active".to_string(),
+        };
+
+        self.hooks
+            .write()
+            .unwrap()
+            .insert(hook.id.clone(), hook.clone());
+        Ok(hook)
+    }
+
+    async fn list_hooks(&self, username: &str, name: Option<&str>) -> anyhow::Result<Vec<Hook>> {
+        let mut hooks: Vec<_> = self
+            .hooks
+            .read()
+            .unwrap()
+            .values()
+            .filter(|hook| hook.username == username)
+            .filter(|hook| name.is_none_or(|name| hook.name == name))
+            .cloned()
+            .collect();
+        hooks.sort_by_key(|hook| hook.created);
+        Ok(hooks)
+    }
+
+    async fn get_hook(&self, username: &str, id: &str) -> anyhow::Result<Option<Hook>> {
+        Ok(self
+            .hooks
+            .read()
+            .unwrap()
+            .get(id)
+            .filter(|hook| hook.username == username)
+            .cloned())
+    }
+
+    async fn update_hook(
+        &self,
+        username: &str,
+        id: &str,
+        update: HookUpdate,
+    ) -> anyhow::Result<Option<Hook>> {
+        let mut hooks = self.hooks.write().unwrap();
+        let Some(hook) = hooks.get_mut(id).filter(|hook| hook.username == username) else {
+            return Ok(None);
+        };
+
+        if let Some(endpoint) = update.endpoint {
+            hook.endpoint = endpoint;
+        }
+        if let Some(secret) = update.secret {
+            hook.secret = secret;
+        }
+        if let Some(events) = update.events {
+            hook.events = events;
+        }
+        hook.updated = Utc::now();
+        Ok(Some(hook.clone()))
+    }
+
+    async fn delete_hook(&self, username: &str, id: &str) -> anyhow::Result<Option<Hook>> {
+        let mut hooks = self.hooks.write().unwrap();
+        if hooks.get(id).is_none_or(|hook| hook.username != username) {
+            return Ok(None);
+        }
+
+        let mut hook = hooks.remove(id);
+        if let Some(ref mut hook) = hook {
+            hook.deleted = true;
+        }
+        self.deliveries.write().unwrap().remove(id);
+        Ok(hook)
+    }
+
+    async fn list_deliveries(
+        &self,
+        username: &str,
+        hook_id: &str,
+    ) -> anyhow::Result<Option<Vec<Delivery>>> {
+        if self.get_hook(username, hook_id).await?.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            self.deliveries
+                .read()
+                .unwrap()
+                .get(hook_id)
+                .cloned()
+                .unwrap_or_default(),
+        ))
+    }
+
+    async fn replay_delivery(
+        &self,
+        username: &str,
+        hook_id: &str,
+        delivery_id: &str,
+    ) -> anyhow::Result<Option<Delivery>> {
+        let Some(hook) = self.get_hook(username, hook_id).await? else {
+            return Ok(None);
+        };
+
+        let delivery = self
+            .deliveries
+            .read()
+            .unwrap()
+            .get(hook_id)
+            .and_then(|history| history.iter().find(|delivery| delivery.id == delivery_id))
+            .cloned();
+
+        match delivery {
+            Some(delivery) => Ok(Some(self.deliver(&hook, delivery).await)),
+            None => Ok(None),
+        }
+    }
+
+    async fn dispatch(&self, event: HookEvent, owners: &[String]) -> anyhow::Result<Vec<Delivery>> {
+        let hooks: Vec<_> = self
+            .hooks
+            .read()
+            .unwrap()
+            .values()
+            .filter(|hook| hook.matches(&event, owners))
+            .cloned()
+            .collect();
+
+        // Sent to every hook at once, so one retrying endpoint doesn't hold up the rest.
+        let deliveries = hooks.iter().map(|hook| {
+            let delivery = Delivery {
+                id: Uuid::new_v4().simple().to_string(),
+                hook_id: hook.id.clone(),
+                event: event.clone(),
+                status: DeliveryStatus::Pending,
+                attempts: 0,
+                last_attempt: None,
+                response_code: None,
+                error: None,
+            };
+            self.deliver_with_retries(hook, delivery)
+        });
+
+        Ok(futures::future::join_all(deliveries).await)
+    }
+}
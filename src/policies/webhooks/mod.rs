@@ -0,0 +1,228 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::hashing::{self, HashError};
+use crate::models::PackageIdentifier;
+
+pub(crate) mod in_memory;
+
+/// The header a delivery's signature is sent in, as npm's own hooks send it.
+pub const SIGNATURE_HEADER: &str = "x-npm-signature";
+
+/// The signature of a delivery with body `body`, for [`SIGNATURE_HEADER`]:
+/// `sha256=<hex HMAC-SHA256 of the body, keyed with the hook's secret>`. Receivers compute
+/// the same over the raw body to check the event came from us.
+pub fn delivery_signature(secret: &str, body: &[u8]) -> Result<String, HashError> {
+    let mac = hashing::hmac_sha256(secret.as_bytes(), body)?;
+    Ok(format!("sha256={}", hex::encode(mac)))
+}
+
+/// What a hook is attached to, as in `npm hook add <pkg|@scope|~owner>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookType {
+    Package,
+    Scope,
+    Owner,
+}
+
+/// A webhook subscription, in the shape `npm hook ls` prints.
+#[derive(Clone, Debug, Serialize)]
+pub struct Hook {
+    pub id: String,
+    pub username: String,
+    #[serde(rename = "type")]
+    pub hook_type: HookType,
+    pub name: String,
+    pub endpoint: String,
+    pub secret: String,
+    /// Event types to deliver (`package:publish`, ...). Empty means every event.
+    pub events: Vec<String>,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    pub deleted: bool,
+    pub delivered: bool,
+    pub last_delivery: Option<DateTime<Utc>>,
+    pub response_code: Option<u16>,
+    pub status: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct NewHook {
+    #[serde(rename = "type")]
+    pub hook_type: HookType,
+    pub name: String,
+    pub endpoint: String,
+    pub secret: String,
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct HookUpdate {
+    pub endpoint: Option<String>,
+    pub secret: Option<String>,
+    pub events: Option<Vec<String>>,
+}
+
+/// Something that happened to a package, delivered as the body of a webhook POST.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HookEvent {
+    pub event: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub hook_type: HookType,
+    pub version: Option<String>,
+    pub time: DateTime<Utc>,
+    pub change: serde_json::Value,
+}
+
+impl HookEvent {
+    pub fn new(event: impl Into<String>, package: &PackageIdentifier) -> Self {
+        Self {
+            event: event.into(),
+            name: package.to_string(),
+            hook_type: HookType::Package,
+            version: None,
+            time: Utc::now(),
+            change: serde_json::Value::Null,
+        }
+    }
+
+    pub fn with_change(mut self, change: serde_json::Value) -> Self {
+        self.change = change;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Delivery {
+    pub id: String,
+    pub hook_id: String,
+    pub event: HookEvent,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub response_code: Option<u16>,
+    pub error: Option<String>,
+}
+
+impl Hook {
+    /// Whether `event` (on a package maintained by `owners`) should be sent to this hook.
+    pub fn matches(&self, event: &HookEvent, owners: &[String]) -> bool {
+        if self.deleted {
+            return false;
+        }
+
+        if !self.events.is_empty() && !self.events.contains(&event.event) {
+            return false;
+        }
+
+        match self.hook_type {
+            HookType::Package => self.name == event.name,
+            HookType::Scope => {
+                let scope = self.name.trim_start_matches('@');
+                event
+                    .name
+                    .strip_prefix('@')
+                    .and_then(|name| name.split_once('/'))
+                    .is_some_and(|(event_scope, _)| event_scope == scope)
+            }
+            HookType::Owner => owners.contains(&self.name),
+        }
+    }
+}
+
+/// Webhook subscriptions and their delivery history.
+///
+/// Hooks are owned by the user that created them; every operation other than
+/// [`Webhooks::dispatch`] is scoped to that user.
+#[async_trait::async_trait]
+pub trait Webhooks: Send + Sync {
+    async fn create_hook(&self, username: &str, hook: NewHook) -> anyhow::Result<Hook>;
+
+    /// A user's hooks, optionally only those attached to `name`.
+    async fn list_hooks(&self, username: &str, name: Option<&str>) -> anyhow::Result<Vec<Hook>>;
+    async fn get_hook(&self, username: &str, id: &str) -> anyhow::Result<Option<Hook>>;
+    async fn update_hook(
+        &self,
+        username: &str,
+        id: &str,
+        update: HookUpdate,
+    ) -> anyhow::Result<Option<Hook>>;
+    async fn delete_hook(&self, username: &str, id: &str) -> anyhow::Result<Option<Hook>>;
+
+    async fn list_deliveries(
+        &self,
+        username: &str,
+        hook_id: &str,
+    ) -> anyhow::Result<Option<Vec<Delivery>>>;
+
+    /// Deliver a previous event again, returning the new attempt's outcome.
+    async fn replay_delivery(
+        &self,
+        username: &str,
+        hook_id: &str,
+        delivery_id: &str,
+    ) -> anyhow::Result<Option<Delivery>>;
+
+    /// Deliver `event` to every matching hook.
+    async fn dispatch(&self, event: HookEvent, owners: &[String]) -> anyhow::Result<Vec<Delivery>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hook(hook_type: HookType, name: &str, events: &[&str]) -> Hook {
+        Hook {
+            id: "1".to_string(),
+            username: "alice".to_string(),
+            hook_type,
+            name: name.to_string(),
+            endpoint: "https://example.com/hook".to_string(),
+            secret: "shh".to_string(),
+            events: events.iter().map(|event| event.to_string()).collect(),
+            created: Utc::now(),
+            updated: Utc::now(),
+            deleted: false,
+            delivered: false,
+            last_delivery: None,
+            response_code: None,
+            status: "active".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_delivery_signature() {
+        assert_eq!(
+            delivery_signature("Jefe", b"what do ya want for nothing?").unwrap(),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_hook_matches() {
+        let package: PackageIdentifier = "@acme/widgets".parse().unwrap();
+        let event = HookEvent::new("package:star", &package);
+        let owners = vec!["bob".to_string()];
+
+        assert!(hook(HookType::Package, "@acme/widgets", &[]).matches(&event, &owners));
+        assert!(!hook(HookType::Package, "widgets", &[]).matches(&event, &owners));
+        assert!(hook(HookType::Scope, "@acme", &[]).matches(&event, &owners));
+        assert!(hook(HookType::Scope, "acme", &[]).matches(&event, &owners));
+        assert!(!hook(HookType::Scope, "@other", &[]).matches(&event, &owners));
+        assert!(hook(HookType::Owner, "bob", &[]).matches(&event, &owners));
+        assert!(!hook(HookType::Owner, "alice", &[]).matches(&event, &owners));
+        assert!(hook(HookType::Scope, "@acme", &["package:star"]).matches(&event, &owners));
+        assert!(!hook(HookType::Scope, "@acme", &["package:publish"]).matches(&event, &owners));
+    }
+}
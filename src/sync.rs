@@ -0,0 +1,231 @@
+//! Differential sync from a primary registry into local package storage.
+//!
+//! A secondary follows the primary's CouchDB-style `_changes` feed and, for each changed
+//! package, copies only the versions it doesn't already have. Every tarball is checked against
+//! the primary's `dist.integrity` / `dist.shasum` before it's stored, so a secondary never
+//! serves bytes the primary didn't vouch for.
+
+use anyhow::Context;
+use axum::body::Bytes;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::client::Client;
+use crate::models::{PackageIdentifier, Packument};
+use crate::policies::PackageStorage;
+
+const DEFAULT_BATCH_SIZE: usize = 100;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Change {
+    pub seq: Value,
+    pub id: String,
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Changes {
+    pub results: Vec<Change>,
+    pub last_seq: Value,
+}
+
+/// The outcome of one pass over the primary's changes.
+#[derive(Clone, Debug, Default)]
+pub struct SyncReport {
+    /// Where the next pass should resume from.
+    pub last_seq: Option<Value>,
+    pub packages: usize,
+    pub versions: usize,
+    /// Package or `package@version` paired with the reason it wasn't synced.
+    pub failures: Vec<(String, String)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RegistrySync<S: PackageStorage> {
+    primary: Client,
+    local: S,
+    batch_size: usize,
+}
+
+impl<S> RegistrySync<S>
+where
+    S: PackageStorage,
+    S::Error: std::error::Error,
+{
+    pub fn new(primary: Client, local: S) -> Self {
+        Self {
+            primary,
+            local,
+            batch_size: DEFAULT_BATCH_SIZE,
+        }
+    }
+
+    /// How many changes to request from the primary per pass.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Fail early if the primary says it has no changes feed. Registries that predate the
+    /// capabilities endpoint (CouchDB-backed ones, for instance) are assumed to have one.
+    pub async fn check_primary(&self) -> anyhow::Result<()> {
+        let response = self
+            .primary
+            .request(reqwest::Method::GET, "-/capabilities")
+            .send()
+            .await?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+
+        let capabilities: Value = response.error_for_status()?.json().await?;
+        let has_changes = capabilities
+            .get("features")
+            .and_then(Value::as_array)
+            .is_some_and(|features| features.iter().any(|feature| feature == "changes"));
+
+        if !has_changes {
+            anyhow::bail!("{} does not serve a changes feed", self.primary.registry());
+        }
+        Ok(())
+    }
+
+    pub async fn changes(&self, since: Option<&Value>) -> anyhow::Result<Changes> {
+        changes(&self.primary, since, self.batch_size).await
+    }
+
+    /// Sync one batch of changes after `since`. Call repeatedly with the returned
+    /// `last_seq` to follow the primary.
+    pub async fn sync_since(&self, since: Option<&Value>) -> anyhow::Result<SyncReport> {
+        let changes = self.changes(since).await?;
+        let mut report = SyncReport {
+            last_seq: Some(changes.last_seq),
+            ..Default::default()
+        };
+
+        for change in changes.results {
+            // Unpublishes aren't propagated yet; the secondary keeps what it has.
+            if change.deleted || change.id.starts_with("_design/") {
+                continue;
+            }
+
+            let name: PackageIdentifier = match change.id.parse() {
+                Ok(name) => name,
+                Err(e) => {
+                    report.failures.push((change.id, e.to_string()));
+                    continue;
+                }
+            };
+
+            match self.sync_package(&name, &mut report).await {
+                Ok(()) => report.packages += 1,
+                Err(e) => report.failures.push((change.id, e.to_string())),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Copy the versions of `name` the local storage is missing, then store the primary's
+    /// packument minus any versions whose tarballs couldn't be verified.
+    pub async fn sync_package(
+        &self,
+        name: &PackageIdentifier,
+        report: &mut SyncReport,
+    ) -> anyhow::Result<()> {
+        let mut packument = self
+            .primary
+            .packument(name)
+            .await
+            .with_context(|| format!("fetching {} from the primary", name))?;
+        let local = self.local.fetch_packument(name).await.ok();
+
+        if local.as_ref() == Some(&packument) {
+            return Ok(());
+        }
+
+        let have = local.and_then(|local| local.versions).unwrap_or_default();
+        let missing: Vec<_> = packument
+            .versions
+            .iter()
+            .flatten()
+            .filter(|(version, _)| !have.contains_key(*version))
+            .map(|(version, manifest)| (version.clone(), manifest.dist.clone()))
+            .collect();
+
+        let mut unverified = Vec::new();
+        for (version, dist) in missing {
+            let copied = async {
+                let tarball = self.primary.tarball_bytes(name, version.as_str()).await?;
+                dist.verify(tarball.as_slice())?;
+                self.local
+                    .put_tarball(name, version.as_str(), Bytes::from(tarball))
+                    .await
+            }
+            .await;
+
+            match copied {
+                Ok(()) => report.versions += 1,
+                Err(e) => {
+                    report
+                        .failures
+                        .push((format!("{}@{}", name, version), e.to_string()));
+                    unverified.push(version);
+                }
+            }
+        }
+
+        drop_versions(&mut packument, unverified.as_slice());
+        self.local.put_packument(name, &packument).await
+    }
+}
+
+/// Up to `limit` changes from `registry`'s `_changes` feed after `since`, or from its start.
+pub(crate) async fn changes(
+    registry: &Client,
+    since: Option<&Value>,
+    limit: usize,
+) -> anyhow::Result<Changes> {
+    let mut query = vec![("limit".to_string(), limit.to_string())];
+    if let Some(since) = since {
+        let since = match since {
+            Value::String(since) => since.clone(),
+            since => since.to_string(),
+        };
+        query.push(("since".to_string(), since));
+    }
+
+    Ok(registry
+        .request(reqwest::Method::GET, "_changes")
+        .query(&query)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+// Remove versions (and the tags pointing at them) so that the stored packument only
+// advertises tarballs we actually hold.
+fn drop_versions(packument: &mut Packument, versions: &[String]) {
+    if versions.is_empty() {
+        return;
+    }
+
+    if let Some(ref mut known) = packument.versions {
+        known.retain(|version, _| !versions.contains(version));
+    }
+
+    if let Some(ref mut tags) = packument.dist_tags {
+        if tags
+            .latest
+            .as_ref()
+            .is_some_and(|latest| versions.contains(latest))
+        {
+            tags.latest = None;
+        }
+        tags.tags.retain(|_, version| !versions.contains(version));
+    }
+}
@@ -0,0 +1,219 @@
+//! Named, tracked background work.
+//!
+//! Everything the server runs outside of a request goes through a [`TaskRegistry`], so that it
+//! shows up at `/-/admin/tasks` and is waited for at shutdown instead of being dropped mid-write
+//! when the runtime exits.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// What the registry knows about every task spawned under one name.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskStatus {
+    pub name: String,
+    /// How many tasks under this name are in flight.
+    pub running: usize,
+    pub runs: u64,
+    pub failures: u64,
+    pub first_started: DateTime<Utc>,
+    pub last_started: DateTime<Utc>,
+    pub last_finished: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    /// False when the most recent run failed.
+    pub healthy: bool,
+}
+
+impl TaskStatus {
+    fn new(name: &str) -> Self {
+        let now = Utc::now();
+        Self {
+            name: name.to_string(),
+            running: 0,
+            runs: 0,
+            failures: 0,
+            first_started: now,
+            last_started: now,
+            last_finished: None,
+            last_error: None,
+            healthy: true,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    tasks: Mutex<BTreeMap<String, TaskStatus>>,
+    handles: Mutex<Vec<(String, JoinHandle<()>)>>,
+    shutdown: CancellationToken,
+}
+
+/// Spawns and keeps track of background tasks.
+///
+/// Long-running tasks should stop when [`TaskRegistry::cancelled`] resolves; short ones are
+/// simply waited for. Either way, [`TaskRegistry::shutdown`] aborts whatever is left once its
+/// grace period runs out.
+#[derive(Clone, Debug, Default)]
+pub struct TaskRegistry {
+    inner: Arc<Inner>,
+}
+
+impl TaskRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `task` in the background under `name`. Tasks spawned once shutdown has begun are
+    /// dropped, since nothing would wait for them.
+    pub fn spawn<F>(&self, name: &str, task: F)
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        if self.is_shutting_down() {
+            tracing::warn!(task = name, "not spawning task during shutdown");
+            return;
+        }
+
+        {
+            let mut tasks = self.inner.tasks.lock().unwrap();
+            let status = tasks
+                .entry(name.to_string())
+                .or_insert_with(|| TaskStatus::new(name));
+            status.running += 1;
+            status.last_started = Utc::now();
+        }
+
+        let registry = self.clone();
+        let task_name = name.to_string();
+        let handle = tokio::spawn(async move {
+            let result = task.await;
+            if let Err(ref e) = result {
+                tracing::warn!(task = task_name, error = ?e, "background task failed");
+            }
+            registry.finish(task_name.as_str(), result);
+        });
+
+        let mut handles = self.inner.handles.lock().unwrap();
+        handles.retain(|(_, handle)| !handle.is_finished());
+        handles.push((name.to_string(), handle));
+    }
+
+    /// Record one pass of a task that loops, without waiting for it to exit.
+    pub fn record_run(&self, name: &str, result: &anyhow::Result<()>) {
+        let mut tasks = self.inner.tasks.lock().unwrap();
+        let status = tasks
+            .entry(name.to_string())
+            .or_insert_with(|| TaskStatus::new(name));
+        record(status, result);
+    }
+
+    fn finish(&self, name: &str, result: anyhow::Result<()>) {
+        let mut tasks = self.inner.tasks.lock().unwrap();
+        if let Some(status) = tasks.get_mut(name) {
+            status.running = status.running.saturating_sub(1);
+            record(status, &result);
+        }
+    }
+
+    /// How many tasks under `name` are in flight.
+    pub fn running(&self, name: &str) -> usize {
+        self.inner
+            .tasks
+            .lock()
+            .unwrap()
+            .get(name)
+            .map_or(0, |status| status.running)
+    }
+
+    pub fn snapshot(&self) -> Vec<TaskStatus> {
+        self.inner.tasks.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.inner.shutdown.is_cancelled()
+    }
+
+    /// Resolves once shutdown begins.
+    pub async fn cancelled(&self) {
+        self.inner.shutdown.cancelled().await
+    }
+
+    /// Signal every task to stop, wait up to `grace` for them, then abort the stragglers.
+    pub async fn shutdown(&self, grace: Duration) {
+        self.inner.shutdown.cancel();
+
+        let handles = std::mem::take(&mut *self.inner.handles.lock().unwrap());
+        let deadline = tokio::time::Instant::now() + grace;
+        for (name, mut handle) in handles {
+            if tokio::time::timeout_at(deadline, &mut handle)
+                .await
+                .is_err()
+            {
+                tracing::warn!(task = name, "aborting background task at shutdown");
+                handle.abort();
+                self.finish(name.as_str(), Err(anyhow::anyhow!("aborted at shutdown")));
+            }
+        }
+    }
+}
+
+fn record(status: &mut TaskStatus, result: &anyhow::Result<()>) {
+    status.runs += 1;
+    status.last_finished = Some(Utc::now());
+    match result {
+        Ok(()) => {
+            status.healthy = true;
+        }
+        Err(e) => {
+            status.failures += 1;
+            status.healthy = false;
+            status.last_error = Some(e.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_waits_then_aborts() {
+        let tasks = TaskRegistry::new();
+        tasks.spawn("quick", async { Ok(()) });
+        tasks.spawn("failing", async { anyhow::bail!("nope") });
+        tasks.spawn("stubborn", async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        });
+        let registry = tasks.clone();
+        tasks.spawn("polite", async move {
+            registry.cancelled().await;
+            Ok(())
+        });
+
+        tasks.shutdown(Duration::from_millis(50)).await;
+        tasks.spawn("late", async { Ok(()) });
+
+        let statuses: BTreeMap<_, _> = tasks
+            .snapshot()
+            .into_iter()
+            .map(|status| (status.name.clone(), status))
+            .collect();
+
+        assert_eq!(statuses["quick"].runs, 1);
+        assert!(statuses["quick"].healthy);
+        assert_eq!(statuses["failing"].failures, 1);
+        assert_eq!(statuses["failing"].last_error.as_deref(), Some("nope"));
+        assert_eq!(statuses["polite"].running, 0);
+        assert_eq!(statuses["stubborn"].running, 0);
+        assert!(!statuses["stubborn"].healthy);
+        assert!(!statuses.contains_key("late"));
+    }
+}